@@ -0,0 +1,70 @@
+//! Ansible tasks reproducing the installs of a session
+//!
+//! Infrastructure teams codify machines as Ansible roles. The `ansible`
+//! format of `export_session` renders the recorded installs as a list of
+//! tasks, for `tasks/main.yml` or a playbook's `tasks`: the repositories the
+//! packages came from are added first, then the packages are installed with
+//! `ansible.builtin.apt` or `community.general.apk`, pinned to the versions
+//! the session installed.
+
+use crate::cloudinit::{push_block, yaml_scalar};
+
+/// Value of a module argument
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaskArgument {
+    Text(String),
+    Flag(bool),
+    List(Vec<String>),
+    /// Multi-line text, such as a key
+    Block(String),
+}
+
+/// An Ansible task calling one module
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnsibleTask {
+    pub name: String,
+    /// Fully qualified module name, e.g. `ansible.builtin.apt`
+    pub module: String,
+    pub arguments: Vec<(String, TaskArgument)>,
+}
+
+impl AnsibleTask {
+    pub fn new(name: impl Into<String>, module: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            module: module.into(),
+            arguments: Vec::new(),
+        }
+    }
+
+    /// Add the module argument `key`
+    pub fn argument(mut self, key: impl Into<String>, value: TaskArgument) -> Self {
+        self.arguments.push((key.into(), value));
+        self
+    }
+}
+
+/// The tasks as an Ansible YAML task list
+pub fn render_tasks(tasks: &[AnsibleTask]) -> String {
+    let mut yaml = "---\n".to_string();
+    for task in tasks {
+        yaml.push_str(&format!("- name: {}\n", yaml_scalar(&task.name)));
+        yaml.push_str(&format!("  {}:\n", task.module));
+        for (key, value) in &task.arguments {
+            match value {
+                TaskArgument::Text(text) => {
+                    yaml.push_str(&format!("    {key}: {}\n", yaml_scalar(text)));
+                }
+                TaskArgument::Flag(flag) => yaml.push_str(&format!("    {key}: {flag}\n")),
+                TaskArgument::List(items) => {
+                    yaml.push_str(&format!("    {key}:\n"));
+                    for item in items {
+                        yaml.push_str(&format!("      - {}\n", yaml_scalar(item)));
+                    }
+                }
+                TaskArgument::Block(text) => push_block(&mut yaml, "    ", key, text),
+            }
+        }
+    }
+    yaml
+}
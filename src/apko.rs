@@ -0,0 +1,123 @@
+//! apko image configurations for the packages of an APK system
+//!
+//! apko builds Alpine and Wolfi images from a declarative list of packages
+//! rather than a Dockerfile. The `export_apko_config` tool describes the
+//! packages installed in a session, or every package installed on the
+//! system, as the `contents` and `archs` of an apko configuration, so a
+//! sandbox provisioned interactively can become a reproducible image build.
+//!
+//! Keys of the Alpine and Wolfi projects are referenced by the URLs they are
+//! published at; other keys by their path on the managed system, which the
+//! image build must be able to read.
+
+use serde::Serialize;
+
+use crate::backend::PinnedPackage;
+
+/// Where the public keys of the Alpine project are published
+const ALPINE_KEYS_URL: &str = "https://alpinelinux.org/keys";
+
+/// Where the public key of the Wolfi project is published
+const WOLFI_KEYS_URL: &str = "https://packages.wolfi.dev/os";
+
+/// The parts of an apko configuration describing the image's packages
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ApkoConfig {
+    /// Repository URLs or paths, in the order apk searches them
+    pub repositories: Vec<String>,
+    /// URLs or paths of the keys verifying the repositories
+    pub keyring: Vec<String>,
+    /// `name=version` of each package
+    pub packages: Vec<String>,
+    /// Architectures to build the image for
+    pub archs: Vec<String>,
+}
+
+impl ApkoConfig {
+    /// Configuration installing `packages` at their pinned versions
+    ///
+    /// The repositories packages were installed from are added after
+    /// `repositories`. apko selects architectures per image rather than per
+    /// package, so the architecture of the packages is ignored.
+    pub fn new(
+        packages: &[PinnedPackage],
+        repositories: impl IntoIterator<Item = String>,
+        arch: impl Into<String>,
+    ) -> Self {
+        let mut config = Self {
+            archs: vec![arch.into()],
+            ..Self::default()
+        };
+        for repository in repositories.into_iter().chain(
+            packages
+                .iter()
+                .flat_map(PinnedPackage::custom_repositories)
+                .map(str::to_string),
+        ) {
+            if !config.repositories.contains(&repository) {
+                config.repositories.push(repository);
+            }
+        }
+        for package in packages {
+            let pinned = format!("{}={}", package.name, package.version);
+            if !config.packages.contains(&pinned) {
+                config.packages.push(pinned);
+            }
+        }
+        config
+    }
+
+    /// Add the key file `key` found in `keys_dir`
+    pub fn with_key(mut self, keys_dir: &str, key: &str) -> Self {
+        let entry = if key.starts_with("alpine-devel@lists.alpinelinux.org-") {
+            format!("{ALPINE_KEYS_URL}/{key}")
+        } else if key == "wolfi-signing.rsa.pub" {
+            format!("{WOLFI_KEYS_URL}/{key}")
+        } else {
+            format!("{}/{key}", keys_dir.trim_end_matches('/'))
+        };
+        if !self.keyring.contains(&entry) {
+            self.keyring.push(entry);
+        }
+        self
+    }
+
+    /// The configuration as apko YAML
+    pub fn to_yaml(&self) -> String {
+        let mut yaml = "contents:\n".to_string();
+        for (key, values) in [
+            ("repositories", &self.repositories),
+            ("keyring", &self.keyring),
+            ("packages", &self.packages),
+        ] {
+            push_list(&mut yaml, "  ", key, values);
+        }
+        push_list(&mut yaml, "", "archs", &self.archs);
+        yaml
+    }
+}
+
+/// Append the YAML sequence `key` indented by `indent`, nothing when empty
+fn push_list(yaml: &mut String, indent: &str, key: &str, values: &[String]) {
+    if values.is_empty() {
+        return;
+    }
+    yaml.push_str(&format!("{indent}{key}:\n"));
+    for value in values {
+        yaml.push_str(&format!("{indent}  - {}\n", yaml_scalar(value)));
+    }
+}
+
+/// `value` as a YAML scalar, double-quoted when plain style would change it
+fn yaml_scalar(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value.starts_with(['-', '?', ':', '@', '`', '!', '&', '*', '|', '>', '%', ' '])
+        && !value.ends_with([' ', ':'])
+        && !value.contains(": ")
+        && !value.contains(['"', '\'', '{', '}', '[', ']', ',', '#', '\n', '\t']);
+    if plain {
+        value.to_string()
+    } else {
+        serde_json::Value::from(value).to_string()
+    }
+}
@@ -0,0 +1,428 @@
+//! Append-only record of the operations requested through the server
+//!
+//! Security reviews need to know what agents asked the server to do, not
+//! only what changed: a rejected attempt to install a package from a local
+//! file says as much about an agent as a successful install. [`AuditLog`]
+//! appends one JSON line per tool call to a file, with the operation, its
+//! outcome and the untrusted source it used, if any.
+//!
+//! The log is tamper-evident: each line carries its `sequence` number, the
+//! `previous_hash` of the line before it and its own `hash`, the SHA-256 of
+//! the line without its `hash` and `signature`. Editing, inserting or
+//! removing a line breaks the chain from there on. With an [`AuditSigner`],
+//! each hash is also signed with the server's Ed25519 key, so that a chain
+//! rewritten from scratch is detected as well by anyone holding the public
+//! key. [`verify_chain`] checks a log, as the `export_audit_log` tool does
+//! before returning its entries; truncating the end of the log is only
+//! detected by comparing the `head` hash with one recorded earlier.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use ring::digest::{SHA256, digest};
+use ring::signature::{ED25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+
+use crate::identity::ClientIdentity;
+use crate::operation::Operation;
+
+/// Outcome of an audited operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Succeeded,
+    Failed,
+    /// The server's policy refused to run the operation, e.g. an install
+    /// from an untrusted source
+    Rejected,
+}
+
+impl std::fmt::Display for AuditOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::Rejected => "rejected",
+        })
+    }
+}
+
+/// One line of the audit log
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub tool: &'static str,
+    pub operation: Operation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    /// Person or agent the tool call came from, when identified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<ClientIdentity>,
+    /// Local file, URL or custom repository the operation installs from,
+    /// the first of `untrusted_sources`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub untrusted_source: Option<String>,
+    /// Every local file, URL and custom repository the operation installs
+    /// from
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub untrusted_sources: Vec<String>,
+    /// Whether the call asked to skip the vulnerability gate
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub override_vulnerabilities: bool,
+    pub outcome: AuditOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Ed25519 key signing the hashes of the audit log
+pub struct AuditSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl AuditSigner {
+    /// Key derived from a 32-byte seed written in hex, as generated by
+    /// `openssl rand -hex 32`
+    pub fn from_seed_hex(seed: &str) -> Result<Self, String> {
+        let seed = decode_hex(seed.trim())
+            .filter(|seed| seed.len() == 32)
+            .ok_or("the audit signing key must be 32 bytes written as 64 hex digits")?;
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|_| "the audit signing key is not a valid Ed25519 seed".to_string())?;
+        Ok(Self { key_pair })
+    }
+
+    /// Key read from a file holding its hex seed
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let seed = std::fs::read_to_string(path)?;
+        Self::from_seed_hex(&seed).map_err(std::io::Error::other)
+    }
+
+    /// Public key verifying the signatures, in hex
+    pub fn public_key(&self) -> String {
+        encode_hex(self.key_pair.public_key().as_ref())
+    }
+
+    fn sign(&self, hash: &[u8]) -> String {
+        encode_hex(self.key_pair.sign(hash).as_ref())
+    }
+}
+
+/// Position of the last line of the log
+#[derive(Default)]
+struct ChainHead {
+    sequence: u64,
+    hash: Option<String>,
+}
+
+impl ChainHead {
+    /// Head after the last line of `contents`
+    fn of(contents: &str) -> Self {
+        let Some(line) = contents.lines().rev().find(|line| !line.trim().is_empty()) else {
+            return Self::default();
+        };
+        let line: Value = serde_json::from_str(line).unwrap_or_default();
+        match (line["sequence"].as_u64(), line["hash"].as_str()) {
+            (Some(sequence), Some(hash)) => Self {
+                sequence,
+                hash: Some(hash.to_string()),
+            },
+            _ => {
+                tracing::warn!(
+                    "The audit log does not end with a chained entry, starting a new chain"
+                );
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Line of the audit log before its hash and signature are added
+#[derive(Serialize)]
+struct ChainedEntry<'a> {
+    sequence: u64,
+    #[serde(flatten)]
+    entry: &'a AuditEntry,
+    previous_hash: Option<&'a str>,
+}
+
+/// Why a line of the log breaks the chain
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum ChainProblem {
+    /// The line is not a JSON object with a `hash`
+    Malformed,
+    /// A line is missing or was inserted before this one
+    SequenceGap { expected: u64, found: Option<u64> },
+    /// The line does not follow the one before it
+    BrokenLink,
+    /// The line was modified after being written
+    HashMismatch,
+    /// The line is not signed although a public key was given
+    MissingSignature,
+    /// The signature was not made by the key
+    InvalidSignature,
+}
+
+impl std::fmt::Display for ChainProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "not a chained entry"),
+            Self::SequenceGap {
+                expected,
+                found: Some(found),
+            } => write!(f, "sequence {found} where {expected} was expected"),
+            Self::SequenceGap { expected, .. } => {
+                write!(f, "no sequence where {expected} was expected")
+            }
+            Self::BrokenLink => write!(f, "previous hash does not match the entry before"),
+            Self::HashMismatch => write!(f, "entry modified after being written"),
+            Self::MissingSignature => write!(f, "entry not signed"),
+            Self::InvalidSignature => write!(f, "signature not made by the key"),
+        }
+    }
+}
+
+/// First line breaking the chain
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChainBreak {
+    /// Line number in the file, starting at 1
+    pub line: usize,
+    #[serde(flatten)]
+    pub problem: ChainProblem,
+}
+
+/// Result of checking an audit log
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditVerification {
+    /// Whether every line is chained, and signed when a public key was given
+    pub valid: bool,
+    /// Number of lines verified before the chain broke
+    pub entries: u64,
+    /// Hash of the last verified line
+    pub head: Option<String>,
+    /// Public key the signatures were checked with, in hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broken_at: Option<ChainBreak>,
+}
+
+/// Check the hash chain of the audit log `contents`, and its signatures when
+/// `public_key` (in hex) is given
+///
+/// Every line must then be signed: a log written without a key does not
+/// verify against one.
+pub fn verify_chain(contents: &str, public_key: Option<&str>) -> AuditVerification {
+    let mut verification = AuditVerification {
+        valid: true,
+        entries: 0,
+        head: None,
+        public_key: public_key.map(str::to_string),
+        broken_at: None,
+    };
+    let key =
+        public_key.map(|key| UnparsedPublicKey::new(&ED25519, decode_hex(key).unwrap_or_default()));
+
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Err(problem) = verify_line(line, &verification, key.as_ref()) {
+            verification.valid = false;
+            verification.broken_at = Some(ChainBreak {
+                line: index + 1,
+                problem,
+            });
+            break;
+        }
+        verification.entries += 1;
+        let line: Value = serde_json::from_str(line).unwrap_or_default();
+        verification.head = line["hash"].as_str().map(str::to_string);
+    }
+    verification
+}
+
+fn verify_line(
+    line: &str,
+    chain: &AuditVerification,
+    key: Option<&UnparsedPublicKey<Vec<u8>>>,
+) -> Result<(), ChainProblem> {
+    let Ok(Value::Object(mut fields)) = serde_json::from_str(line) else {
+        return Err(ChainProblem::Malformed);
+    };
+    let Some(Value::String(hash)) = fields.remove("hash") else {
+        return Err(ChainProblem::Malformed);
+    };
+    let signature = fields.remove("signature");
+
+    let expected = chain.entries + 1;
+    let found = fields.get("sequence").and_then(Value::as_u64);
+    if found != Some(expected) {
+        return Err(ChainProblem::SequenceGap { expected, found });
+    }
+    if fields.get("previous_hash").and_then(Value::as_str) != chain.head.as_deref() {
+        return Err(ChainProblem::BrokenLink);
+    }
+    let digest = hash_line(&Value::Object(fields));
+    if encode_hex(&digest) != hash {
+        return Err(ChainProblem::HashMismatch);
+    }
+    if let Some(key) = key {
+        let signature = signature
+            .as_ref()
+            .and_then(Value::as_str)
+            .ok_or(ChainProblem::MissingSignature)?;
+        let signature = decode_hex(signature).ok_or(ChainProblem::InvalidSignature)?;
+        key.verify(&digest, &signature)
+            .map_err(|_| ChainProblem::InvalidSignature)?;
+    }
+    Ok(())
+}
+
+/// SHA-256 of a line without its `hash` and `signature`
+///
+/// Object keys serialize in sorted order, so the hash does not depend on the
+/// order of the fields in the file.
+fn hash_line(line: &Value) -> Vec<u8> {
+    let bytes = serde_json::to_vec(line).unwrap_or_default();
+    digest(&SHA256, &bytes).as_ref().to_vec()
+}
+
+/// Entries of the audit log with the verification of its chain
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditExport {
+    pub verification: AuditVerification,
+    pub entries: Vec<Value>,
+}
+
+/// File being appended to and the head of its chain
+struct Writer {
+    file: tokio::fs::File,
+    head: ChainHead,
+}
+
+/// JSON Lines file receiving an [`AuditEntry`] per tool call
+pub struct AuditLog {
+    path: PathBuf,
+    writer: tokio::sync::Mutex<Writer>,
+    signer: Option<AuditSigner>,
+}
+
+impl AuditLog {
+    /// Append entries to the file at `path`, creating it if needed
+    ///
+    /// New entries continue the chain of the entries already in the file.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+        let head = ChainHead::of(&std::io::read_to_string(&file)?);
+        Ok(Self {
+            path,
+            writer: tokio::sync::Mutex::new(Writer {
+                file: tokio::fs::File::from_std(file),
+                head,
+            }),
+            signer: None,
+        })
+    }
+
+    /// Sign the hash of every new entry with `signer`
+    pub fn with_signer(mut self, signer: AuditSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Public key verifying the entries' signatures, in hex
+    pub fn public_key(&self) -> Option<String> {
+        self.signer.as_ref().map(AuditSigner::public_key)
+    }
+
+    /// Verify the chain of the log and return its entries, starting at
+    /// sequence number `from_sequence` if given
+    pub async fn export(&self, from_sequence: Option<u64>) -> std::io::Result<AuditExport> {
+        // Holding the writer keeps entries from being appended meanwhile
+        let _writer = self.writer.lock().await;
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+        let verification = verify_chain(&contents, self.public_key().as_deref());
+        let entries = contents
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter(|entry| {
+                from_sequence.is_none_or(|from| entry["sequence"].as_u64() >= Some(from))
+            })
+            .collect();
+        Ok(AuditExport {
+            verification,
+            entries,
+        })
+    }
+
+    /// Append `entry` to the log
+    ///
+    /// Failures to write are logged rather than failing the operation, which
+    /// already ran.
+    pub async fn record(&self, entry: &AuditEntry) {
+        let mut writer = self.writer.lock().await;
+        let sequence = writer.head.sequence + 1;
+        let chained = ChainedEntry {
+            sequence,
+            entry,
+            previous_hash: writer.head.hash.as_deref(),
+        };
+        let mut line = match serde_json::to_value(&chained) {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::warn!("Failed to serialize audit entry: {err}");
+                return;
+            }
+        };
+        let digest = hash_line(&line);
+        let hash = encode_hex(&digest);
+        line["hash"] = Value::String(hash.clone());
+        if let Some(signer) = &self.signer {
+            line["signature"] = Value::String(signer.sign(&digest));
+        }
+
+        let mut line = line.to_string().into_bytes();
+        line.push(b'\n');
+        let written = match writer.file.write_all(&line).await {
+            Ok(()) => writer.file.flush().await,
+            Err(err) => Err(err),
+        };
+        match written {
+            Ok(()) => {
+                writer.head = ChainHead {
+                    sequence,
+                    hash: Some(hash),
+                }
+            }
+            Err(err) => {
+                tracing::warn!("Failed to write audit log {}: {err}", self.path.display())
+            }
+        }
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,105 @@
+//! Allowlist of the repositories tool calls may use
+//!
+//! Regulated environments only let machines pull packages from approved
+//! internal mirrors. With a [`RepositoryAllowlist`], the `repository`
+//! argument of a tool call is only accepted when every URL it names is below
+//! an approved URL prefix (`https://mirror.example.com/alpine/`) or on an
+//! approved domain or one of its subdomains (`example.com`).
+//!
+//! Repositories without a URL, such as the path of an APT sources file or of
+//! a local APK repository, are rejected: the URLs they lead to cannot be
+//! checked.
+
+use crate::error::PackageManagerError;
+
+/// Approved URL prefixes and domains
+#[derive(Clone, Debug, Default)]
+pub struct RepositoryAllowlist {
+    entries: Vec<String>,
+}
+
+impl RepositoryAllowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Approve repositories below the URL prefix or on the domain `entry`
+    pub fn allow(mut self, entry: impl Into<String>) -> Self {
+        self.push(entry);
+        self
+    }
+
+    pub fn push(&mut self, entry: impl Into<String>) {
+        self.entries.push(entry.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Whether `url` is below an approved prefix or on an approved domain
+    ///
+    /// Prefixes only match at a path boundary, so `https://mirror.example.com`
+    /// does not approve `https://mirror.example.com.evil.net`.
+    pub fn allows(&self, url: &str) -> bool {
+        self.entries.iter().any(|entry| {
+            if entry.contains("://") {
+                url.strip_prefix(entry.as_str()).is_some_and(|rest| {
+                    entry.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?', '#'])
+                })
+            } else {
+                host(url).is_some_and(|host| {
+                    let domain = entry.trim_start_matches('.').to_ascii_lowercase();
+                    host == domain || host.ends_with(&format!(".{domain}"))
+                })
+            }
+        })
+    }
+
+    /// Reject `repository` unless every URL it names is approved
+    ///
+    /// An empty allowlist approves every repository.
+    pub fn check(&self, repository: &str) -> Result<(), PackageManagerError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let urls = repository_urls(repository);
+        let message = match urls.iter().find(|url| !self.allows(url)) {
+            Some(url) => format!("repository {url} is not on the server's repository allowlist"),
+            None if urls.is_empty() => format!(
+                "repository {} is not a URL, and only URLs on the server's repository allowlist are accepted",
+                repository.trim()
+            ),
+            None => return Ok(()),
+        };
+        Err(PackageManagerError::PermissionDenied { message })
+    }
+}
+
+/// URLs named by a repository definition: a URL, a one-line APT entry or a
+/// deb822 stanza
+pub(crate) fn repository_urls(repository: &str) -> Vec<&str> {
+    repository
+        .split_whitespace()
+        .map(|token| token.trim_matches(|c| c == '[' || c == ']'))
+        .filter(|token| token.contains("://"))
+        .collect()
+}
+
+/// Lowercase host of `url`, without credentials or port
+fn host(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
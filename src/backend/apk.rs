@@ -1,38 +1,1050 @@
-use rmcp::ErrorData as McpError;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
-use super::{ExecResult, InstallOptions, InstallVersionOptions, PackageManager, SearchOptions};
+use async_trait::async_trait;
+use tokio::sync::OnceCell;
 
-/// List of repositories to search across
-const SEARCH_REPOSITORIES: &[&str] = &[
-    "https://dl-cdn.alpinelinux.org/alpine/edge/main",
-    "https://dl-cdn.alpinelinux.org/alpine/edge/community",
+use crate::ansible::{AnsibleTask, TaskArgument};
+use crate::apko::ApkoConfig;
+use crate::cloudinit::CloudConfig;
+use crate::error::PackageManagerError;
+use crate::version::VersionScheme;
+
+use super::apkindex::{IndexReader, IndexSource};
+use super::credentials::{RedactingRunner, RepositoryCredentials};
+use super::doctor::{self, HealthCheck};
+use super::drift;
+use super::exit_status::{self, ExitMeaning};
+use super::freshness;
+use super::local::{apk_directory_provides, index_apk_directory, local_directory};
+use super::mirrors::MirrorList;
+use super::offline::OfflineMirrors;
+use super::parse::apk::{
+    parse_alpine_release, parse_install_output, parse_installed_checksums,
+    parse_installed_licenses, parse_installed_list, parse_installed_origins, parse_policy,
+    parse_removed_packages, parse_repositories, parse_required_space, parse_search,
+    parse_signing_key, parse_unreachable_repositories, split_package_tag, split_repository_tag,
+};
+use super::parse::{parse_os_release_name, parse_version_line};
+use super::pins;
+use super::pkgs::PackagesSite;
+use super::privileges::{self, PrivilegeReport};
+use super::runner::{Command, CommandRunner, LocalRunner, shell_quote};
+use super::signing::UnsignedRepository;
+use super::validation::{validate_package_name, validate_version};
+use super::{
+    Capabilities, ExecResult, FetchedPackage, InstallFlags, InstallOptions, InstallOutcome,
+    InstallVersionOptions, InstalledPackage, LockedPackage, PackageLicense, PackageManager,
+    PinnedPackage, Recommends, Repository, ResolvedVersion, SearchMode, SearchOptions,
+    SearchResult, SpaceRequirement, SystemInfo, command, lock_packages_with, resolve_among,
+    validate_arch, validate_root,
+};
+
+/// Repositories configured on the system
+const REPOSITORIES_FILE: &str = "/etc/apk/repositories";
+
+/// Installed package database, locked and written by installs
+const DATABASE_DIR: &str = "/lib/apk/db";
+
+/// Lock apk take while changing the package database
+const LOCK_FILE: &str = "/lib/apk/db/lock";
+
+/// Directory downloaded packages are kept in
+const CACHE_DIR: &str = "/var/cache/apk";
+
+/// Installed packages of the database, with their checksums
+const INSTALLED_DATABASE: &str = "/lib/apk/db/installed";
+
+/// Packages explicitly requested, with the repository tags they are pinned to
+const WORLD_FILE: &str = "/etc/apk/world";
+
+/// Mirrors of the Alpine repositories tried by default
+pub const DEFAULT_MIRRORS: &[&str] = &["https://dl-cdn.alpinelinux.org/alpine"];
+
+/// Release of the running system
+const ALPINE_RELEASE_FILE: &str = "/etc/alpine-release";
+
+/// Description of the running system's distribution
+const OS_RELEASE_FILE: &str = "/etc/os-release";
+
+/// File holding the architecture of the packages the system installs
+const ARCH_FILE: &str = "/etc/apk/arch";
+
+/// Directory of the public keys verifying repository indexes
+const KEYS_DIR: &str = "/etc/apk/keys";
+
+/// How long cached repository indexes are used before being downloaded again
+pub const DEFAULT_INDEX_CACHE_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Older stable branches searched besides the running system's branch
+pub const DEFAULT_OLDER_BRANCHES: usize = 3;
+
+/// Repositories searched when the Alpine release cannot be detected,
+/// relative to a mirror
+pub const DEFAULT_SEARCH_REPOSITORIES: &[&str] = &[
+    "edge/main",
+    "edge/community",
     // Current version
-    "https://dl-cdn.alpinelinux.org/alpine/v3.22/main",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.22/community",
+    "v3.22/main",
+    "v3.22/community",
     // Older versions
-    "https://dl-cdn.alpinelinux.org/alpine/v3.21/main",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.21/community",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.20/main",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.20/community",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.19/main",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.19/community",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.18/main",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.18/community",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.17/main",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.17/community",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.16/main",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.16/community",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.15/main",
-    "https://dl-cdn.alpinelinux.org/alpine/v3.15/community",
+    "v3.21/main",
+    "v3.21/community",
+    "v3.20/main",
+    "v3.20/community",
+    "v3.19/main",
+    "v3.19/community",
+    "v3.18/main",
+    "v3.18/community",
+    "v3.17/main",
+    "v3.17/community",
+    "v3.16/main",
+    "v3.16/community",
+    "v3.15/main",
+    "v3.15/community",
 ];
 
 /// Alpine Linux APK package manager backend
 #[derive(Clone)]
-pub struct Apk;
+pub struct Apk {
+    runner: Arc<dyn CommandRunner>,
+    mirrors: Arc<MirrorList>,
+    /// Configured search repositories
+    search_repositories: Option<Arc<[String]>>,
+    /// Search repositories derived from the system on first use
+    derived_search_repositories: Arc<OnceCell<Arc<[String]>>>,
+    older_branches: usize,
+    /// Root filesystem managed instead of `/` (`apk --root`)
+    root: Option<String>,
+    /// Directory keeping the indexes of searched repositories between searches
+    index_cache: Option<Arc<IndexCache>>,
+    search_provider: SearchProvider,
+    /// Credentials added to the URLs of private repositories
+    credentials: Option<Arc<RepositoryCredentials>>,
+    /// Internal mirrors replacing public repositories in offline mode
+    offline: Option<Arc<OfflineMirrors>>,
+    /// Whether every install skips the maintainer scripts
+    no_scripts: bool,
+}
+
+/// Where searches look packages up
+#[derive(Clone)]
+enum SearchProvider {
+    /// `apk search`
+    Apk,
+    /// Indexes downloaded and parsed by the server
+    Index(Arc<IndexReader>),
+    /// The Alpine packages site
+    PackagesSite(Arc<PackagesSite>),
+}
+
+/// Persistent cache of repository indexes used by searches
+struct IndexCache {
+    dir: String,
+    max_age: Duration,
+    /// Whether the directory could be created, checked on first use
+    ready: OnceCell<bool>,
+}
 
 impl Apk {
     pub fn new() -> Self {
-        Self
+        Self::with_runner(Arc::new(LocalRunner))
+    }
+
+    /// Run `apk` through the given runner, e.g. in a chroot or container
+    pub fn with_runner(runner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            runner,
+            mirrors: Arc::new(MirrorList::new(DEFAULT_MIRRORS.iter().copied())),
+            search_repositories: None,
+            derived_search_repositories: Arc::new(OnceCell::new()),
+            older_branches: DEFAULT_OLDER_BRANCHES,
+            root: None,
+            index_cache: None,
+            search_provider: SearchProvider::Apk,
+            credentials: None,
+            offline: None,
+            no_scripts: false,
+        }
+    }
+
+    /// Fail over between these mirrors instead of [`DEFAULT_MIRRORS`]
+    ///
+    /// Mirror health is shared by clones of the backend.
+    pub fn with_mirrors(mut self, mirrors: MirrorList) -> Self {
+        self.mirrors = Arc::new(mirrors);
+        self
+    }
+
+    /// Search these repositories instead of deriving them from the system
+    ///
+    /// Paths such as `v3.22/main` are resolved against the mirrors and fail
+    /// over with them. URLs and local paths, e.g. a private repository, are
+    /// used as they are.
+    pub fn with_search_repositories<I>(mut self, repositories: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let repositories = repositories
+            .into_iter()
+            .map(|repository| repository.into().trim_end_matches('/').to_string())
+            .collect();
+        self.search_repositories = Some(repositories);
+        self
+    }
+
+    /// Search this many stable branches older than the system's instead of
+    /// [`DEFAULT_OLDER_BRANCHES`]
+    pub fn with_older_branches(mut self, count: usize) -> Self {
+        self.older_branches = count;
+        self
+    }
+
+    /// Keep the indexes of searched repositories in `dir` instead of
+    /// downloading them for every search
+    ///
+    /// Searches and versioned installs pass `--cache-dir` and
+    /// `--cache-max-age` to apk, which downloads an index again once it is
+    /// older than `max_age` (rounded up to whole minutes). Without a cache,
+    /// searches run with `--no-cache`. The directory is created on the
+    /// runner's system on first use; searches fall back to `--no-cache` when
+    /// that fails.
+    pub fn with_index_cache(mut self, dir: impl Into<String>, max_age: Duration) -> Self {
+        self.index_cache = Some(Arc::new(IndexCache {
+            dir: dir.into(),
+            max_age,
+            ready: OnceCell::new(),
+        }));
+        self
+    }
+
+    /// Search by downloading and parsing the repository indexes instead of
+    /// running `apk search`
+    ///
+    /// Parsed indexes are reused for `max_age`, so repeated searches and
+    /// version lookups do not download them again, and searches work where
+    /// `apk` is not installed. Local repository paths are read from the
+    /// server's file system. Installs still run `apk`.
+    pub fn with_native_index(mut self, max_age: Duration) -> Self {
+        let mut reader = IndexReader::new(max_age);
+        if let Some(credentials) = &self.credentials {
+            reader = reader.with_credentials(credentials.clone());
+        }
+        self.search_provider = SearchProvider::Index(Arc::new(reader));
+        self
+    }
+
+    /// Search through the Alpine packages site instead of running
+    /// `apk search`
+    ///
+    /// The site is queried for the system's branch unless the provider
+    /// selects one, or for the branch of the requested repository when it is
+    /// on the mirrors. Searches of other repositories still run `apk`.
+    pub fn with_packages_site(mut self, site: PackagesSite) -> Self {
+        self.search_provider = SearchProvider::PackagesSite(Arc::new(site));
+        self
+    }
+
+    /// Authenticate to private repositories with `credentials`
+    ///
+    /// Repositories passed to `apk` with `--repository` carry the credentials
+    /// of their URL prefix in the URL, and native index downloads use basic
+    /// authentication. Secrets are redacted from the output of every command.
+    pub fn with_credentials(mut self, credentials: RepositoryCredentials) -> Self {
+        let credentials = Arc::new(credentials);
+        self.runner = Arc::new(RedactingRunner::new(self.runner, credentials.clone()));
+        if let SearchProvider::Index(reader) = &self.search_provider {
+            let reader = IndexReader::new(reader.max_age()).with_credentials(credentials.clone());
+            self.search_provider = SearchProvider::Index(Arc::new(reader));
+        }
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Run every install with `--no-scripts`, for environments forbidding
+    /// maintainer scripts
+    pub fn with_no_scripts(mut self) -> Self {
+        self.no_scripts = true;
+        self
+    }
+
+    /// Only reach the internal mirrors of `offline`
+    ///
+    /// The mirrors failed over between are moved onto their internal
+    /// mirrors (every internal mirror when none of them is mirrored), and
+    /// commands reaching the network read the system's repositories moved
+    /// onto the internal mirrors instead of `/etc/apk/repositories`.
+    pub fn with_offline_mirrors(mut self, offline: OfflineMirrors) -> Self {
+        let mut mirrors: Vec<String> = Vec::new();
+        for mirror in self.mirrors.mirrors() {
+            if let Some(mirror) = offline.rewrite(mirror)
+                && !mirrors.contains(&mirror)
+            {
+                mirrors.push(mirror);
+            }
+        }
+        if mirrors.is_empty() {
+            mirrors = offline.internal_urls().map(str::to_string).collect();
+        }
+        self.mirrors = Arc::new(MirrorList::new(mirrors));
+        self.offline = Some(Arc::new(offline));
+        self
+    }
+
+    /// Branch of the managed system, from `/etc/alpine-release`
+    async fn system_branch(&self) -> Option<String> {
+        let file = self.system_file(ALPINE_RELEASE_FILE);
+        let release = self.runner.read_file(&file).await.ok().flatten()?;
+        Some(parse_alpine_release(&release)?.branch())
+    }
+
+    /// Search the Alpine packages site
+    ///
+    /// Returns `None` when the requested repositories are not a single one
+    /// on the mirrors, so the site does not know them, and for description
+    /// searches, which the site does not support.
+    async fn search_packages_site(
+        &self,
+        site: &PackagesSite,
+        options: &SearchOptions,
+    ) -> Option<Result<Vec<SearchResult>, PackageManagerError>> {
+        if options.mode == SearchMode::Description
+            || !options.repositories.is_empty()
+            || !options.only_repositories.is_empty()
+        {
+            return None;
+        }
+        let (branch, repository) = match &options.repository {
+            Some(repository) => {
+                let path = self.mirrors.relative_path(repository).unwrap_or(repository);
+                let (branch, repository) = path.split_once('/')?;
+                if path.contains("://") || branch.is_empty() || repository.contains('/') {
+                    return None;
+                }
+                (Some(branch.to_string()), Some(repository))
+            }
+            None => match site.branch() {
+                Some(branch) => (Some(branch.to_string()), None),
+                None => (self.system_branch().await, None),
+            },
+        };
+        let arch = match &options.arch {
+            Some(arch) => arch.clone(),
+            None => self.system_arch().await,
+        };
+
+        let results = site
+            .search(
+                &options.query,
+                options.mode,
+                branch.as_deref(),
+                repository,
+                &arch,
+            )
+            .await
+            .map_err(|err| {
+                PackageManagerError::io(
+                    format!(
+                        "there was an error searching for packages with query {}",
+                        options.query
+                    ),
+                    std::io::Error::other(err),
+                )
+            });
+        let mirror = self
+            .mirrors
+            .candidates()
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        Some(results.map(|results| {
+            results
+                .into_iter()
+                .map(|package| SearchResult {
+                    repository: package.repository.map(|path| format!("{mirror}/{path}")),
+                    ..package
+                })
+                .collect()
+        }))
+    }
+
+    /// Architecture of the managed system, from `/etc/apk/arch`
+    ///
+    /// Falls back to the architecture the server was built for.
+    async fn system_arch(&self) -> String {
+        let file = self.system_file(ARCH_FILE);
+        match self.runner.read_file(&file).await {
+            Ok(Some(arch)) if !arch.trim().is_empty() => arch.trim().to_string(),
+            _ => match std::env::consts::ARCH {
+                "arm" => "armv7".to_string(),
+                arch => arch.to_string(),
+            },
+        }
+    }
+
+    /// Index the local repository at `dir` for `arch` (default: the
+    /// system's), returning the directory of its packages
+    async fn index_local_repository(
+        &self,
+        dir: &str,
+        arch: Option<&str>,
+    ) -> Result<String, PackageManagerError> {
+        let arch = match arch {
+            Some(arch) => arch.to_string(),
+            None => self.system_arch().await,
+        };
+        let packages = format!("{dir}/{arch}");
+        index_apk_directory(&*self.runner, &packages).await?;
+        Ok(packages)
+    }
+
+    /// Whether one of the local package directories `local` has a package
+    /// requested by `specs` (`name`, `name=version`, `name@tag`...)
+    ///
+    /// `--allow-untrusted` applies to every repository of a transaction, so
+    /// it is only passed when a package comes from a local directory.
+    async fn installs_local(
+        &self,
+        local: &[String],
+        specs: &[&str],
+    ) -> Result<bool, PackageManagerError> {
+        let names: Vec<&str> = specs
+            .iter()
+            .filter_map(|spec| spec.split(['=', '<', '>', '~', '@']).next())
+            .collect();
+        for dir in local {
+            if apk_directory_provides(&*self.runner, dir, &names).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Where to download the index of a search repository from
+    fn index_source(&self, repository: &str) -> IndexSource {
+        // Searches list the packages of tagged repositories like the others
+        let (_, repository) = split_repository_tag(repository);
+        if let Some(path) = self.mirrors.relative_path(repository) {
+            IndexSource::Mirrored(path.to_string())
+        } else if repository.contains("://") || repository.starts_with('/') {
+            IndexSource::Direct(repository.to_string())
+        } else {
+            IndexSource::Mirrored(repository.to_string())
+        }
+    }
+
+    /// Search the downloaded indexes of the search repositories
+    ///
+    /// Repositories whose index cannot be loaded are skipped with a warning,
+    /// like `apk search` does; the search fails when none can be loaded.
+    async fn search_indexes(
+        &self,
+        reader: &Arc<IndexReader>,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        let arch = match &options.arch {
+            Some(arch) => arch.clone(),
+            None => self.system_arch().await,
+        };
+        let added = options.added_repositories();
+        let sources: Vec<_> = match added.as_slice() {
+            [] if !options.only_repositories.is_empty() => options
+                .only_repositories
+                .iter()
+                .map(|repository| self.index_source(repository))
+                .collect(),
+            [] => self
+                .search_repository_list()
+                .await
+                .iter()
+                .map(|repository| self.index_source(repository))
+                .collect(),
+            added => added
+                .iter()
+                .map(|repository| self.index_source(repository))
+                .collect(),
+        };
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for (position, source) in sources.into_iter().enumerate() {
+            let reader = reader.clone();
+            let mirrors = self.mirrors.clone();
+            let arch = arch.clone();
+            tasks.spawn(async move { (position, reader.load(&mirrors, &source, &arch).await) });
+        }
+        let mut loaded = tasks.join_all().await;
+        loaded.sort_by_key(|(position, _)| *position);
+
+        let repositories = loaded.len();
+        let matches = options.mode.matcher(&options.query);
+        let mut results = Vec::new();
+        let mut errors = Vec::new();
+        for (_, index) in loaded {
+            match index {
+                Ok(index) => results.extend(
+                    index
+                        .iter()
+                        .filter(|package| matches(&package.name, package.description.as_deref()))
+                        .cloned(),
+                ),
+                Err(err) => {
+                    tracing::warn!("Skipping repository index: {err}");
+                    errors.push(err.to_string());
+                }
+            }
+        }
+        if repositories > 0 && errors.len() == repositories {
+            return Err(PackageManagerError::io(
+                format!(
+                    "there was an error searching for packages with query {}",
+                    options.query
+                ),
+                std::io::Error::other(errors.join("; ")),
+            ));
+        }
+        Ok(results)
+    }
+
+    /// Available versions of the package of a versioned install, with the
+    /// repositories holding them
+    ///
+    /// Index and packages site searches report the repository of each
+    /// version. `apk search` does not, so `apk policy` is asked instead; it
+    /// reads the same indexes of the search repositories.
+    async fn locate_versions(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        if !matches!(self.search_provider, SearchProvider::Apk) {
+            let search = SearchOptions {
+                query: options.package.clone(),
+                repository: None,
+                repositories: Vec::new(),
+                only_repositories: Vec::new(),
+                arch: options.arch.clone(),
+                mode: SearchMode::Exact,
+            };
+            return self.search_package(&search).await;
+        }
+
+        let output = self
+            .policy(&[options.package.as_str()], options.arch.as_deref())
+            .await
+            .map_err(|err| {
+                PackageManagerError::io(
+                    format!(
+                        "there was an error checking versions for package {}",
+                        options.package
+                    ),
+                    err,
+                )
+            })?;
+
+        let exec_result = output.success()?;
+        Ok(parse_policy(
+            exec_result.stdout.as_deref().unwrap_or_default(),
+        ))
+    }
+
+    /// `apk policy` of `packages` in the search repositories
+    async fn policy(&self, packages: &[&str], arch: Option<&str>) -> std::io::Result<ExecResult> {
+        let cache_args = self.index_cache_args().await;
+        let offline_args = self.offline_args().await;
+        let repositories = self.search_repository_list().await;
+        self.run_with_failover(|mirror| {
+            let mut command = self.apk();
+            command.args(&cache_args).args(&offline_args);
+            if let Some(arch) = arch {
+                command.arg("--arch");
+                command.arg(arch);
+            }
+            for repository in Self::search_urls(&repositories, mirror) {
+                command.arg("--repository");
+                command.arg(self.repository_arg(&repository));
+            }
+            command.arg("policy");
+            command.args(packages);
+            command
+        })
+        .await
+    }
+
+    /// Newest version of `options.package` matching `options.version`, with
+    /// the candidates it was picked among
+    async fn resolve_candidates(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<(ResolvedVersion, Vec<SearchResult>), PackageManagerError> {
+        // Validate inputs to prevent command injection
+        validate_package_name(&options.package, Some(VersionScheme::Apk))?;
+        validate_version(&options.version, Some(VersionScheme::Apk))?;
+
+        let candidates = self.locate_versions(options).await?;
+        match resolve_among(Some(VersionScheme::Apk), &candidates, options) {
+            Ok(resolved) => Ok((resolved, candidates)),
+            Err(PackageManagerError::NotFound { package, .. }) => {
+                Err(PackageManagerError::NotFound {
+                    package,
+                    searched_repositories: self
+                        .list_search_repositories()
+                        .await?
+                        .into_iter()
+                        .map(|repository| repository.url)
+                        .collect(),
+                })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Newest version of `options.package` matching `options.version`, with
+    /// the repositories to install it from
+    async fn resolve_install(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<(String, Arc<[String]>), PackageManagerError> {
+        let (resolved, candidates) = self.resolve_candidates(options).await?;
+        let repositories = self
+            .install_repositories(&candidates, &options.package, &resolved.version)
+            .await;
+        Ok((resolved.version, repositories))
+    }
+
+    /// Repositories added to the system's to install `version` of `package`
+    ///
+    /// Only the repositories `version` was found in are added, so `apk` does
+    /// not download every search repository's index again and cannot pick
+    /// dependencies from another branch. When the repository is unknown,
+    /// every search repository is added.
+    async fn install_repositories(
+        &self,
+        candidates: &[SearchResult],
+        package: &str,
+        version: &str,
+    ) -> Arc<[String]> {
+        let mut located: Vec<String> = Vec::new();
+        for repository in candidates
+            .iter()
+            .filter(|candidate| {
+                candidate.name == package && candidate.version.as_deref() == Some(version)
+            })
+            .filter_map(|candidate| candidate.repository.as_deref())
+        {
+            // Repositories on a mirror stay relative to it so they fail over
+            let repository = self
+                .mirrors
+                .relative_path(repository)
+                .unwrap_or(repository.trim_end_matches('/'))
+                .to_string();
+            if !located.contains(&repository) {
+                located.push(repository);
+            }
+        }
+
+        if located.is_empty() {
+            self.search_repository_list().await
+        } else {
+            located.into()
+        }
+    }
+
+    /// Repositories of a command reaching the network in offline mode
+    ///
+    /// The system's repositories file names public hosts, so it is replaced
+    /// by its enabled repositories moved onto the internal mirrors.
+    /// Repositories without a mirror are left out.
+    async fn offline_args(&self) -> Vec<String> {
+        let Some(offline) = &self.offline else {
+            return Vec::new();
+        };
+        let file = self.system_file(REPOSITORIES_FILE);
+        let content = self.runner.read_file(&file).await.ok().flatten();
+        let mut args = vec!["--repositories-file".to_string(), "/dev/null".to_string()];
+        for repository in parse_repositories(&content.unwrap_or_default())
+            .iter()
+            .filter(|repository| repository.enabled)
+        {
+            match offline.rewrite(repository.url.trim_end_matches('/')) {
+                Some(url) => {
+                    args.push("--repository".to_string());
+                    args.push(self.repository_arg(&tagged(repository.tag.as_deref(), &url)));
+                }
+                None => tracing::warn!(
+                    "Skipping repository {} without an internal mirror",
+                    repository.url
+                ),
+            }
+        }
+        args
+    }
+
+    /// Repositories of a call replacing the configured ones
+    ///
+    /// The system's repositories file is ignored. Local directories are
+    /// indexed first; their package directories are returned with the
+    /// arguments, as the generated indexes are not signed.
+    async fn override_args(
+        &self,
+        repositories: &[String],
+        arch: Option<&str>,
+    ) -> Result<(Vec<String>, Vec<String>), PackageManagerError> {
+        let repositories: Vec<&str> = repositories.iter().map(String::as_str).collect();
+        let (repositories, local) = self.prepare_repositories(&repositories, arch).await?;
+        let mut args = vec!["--repositories-file".to_string(), "/dev/null".to_string()];
+        for repository in &repositories {
+            args.push("--repository".to_string());
+            args.push(self.repository_arg(repository));
+        }
+        Ok((args, local))
+    }
+
+    /// Custom repositories of a call, with their local directories indexed
+    ///
+    /// Also returns the package directories of the local ones, as the
+    /// generated indexes are not signed.
+    async fn prepare_repositories(
+        &self,
+        repositories: &[&str],
+        arch: Option<&str>,
+    ) -> Result<(Vec<String>, Vec<String>), PackageManagerError> {
+        let mut prepared = Vec::new();
+        let mut local = Vec::new();
+        for repository in repositories {
+            prepared.push(match local_directory(repository) {
+                Some(dir) => {
+                    local.push(self.index_local_repository(dir, arch).await?);
+                    dir.to_string()
+                }
+                None => repository.to_string(),
+            });
+        }
+        Ok((prepared, local))
+    }
+
+    /// Options selecting the index cache for a search
+    async fn index_cache_args(&self) -> Vec<String> {
+        let Some(cache) = &self.index_cache else {
+            return vec!["--no-cache".to_string()];
+        };
+        let ready = *cache
+            .ready
+            .get_or_init(|| async {
+                let created = self
+                    .runner
+                    .run(command("mkdir").args(["-p", "--", &cache.dir]))
+                    .await;
+                match created {
+                    Ok(result) if result.status == 0 => true,
+                    Ok(result) => {
+                        tracing::warn!(
+                            "Searching without index cache, {} could not be created: {}",
+                            cache.dir,
+                            result.stderr.unwrap_or_default().trim()
+                        );
+                        false
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "Searching without index cache, {} could not be created: {err}",
+                            cache.dir
+                        );
+                        false
+                    }
+                }
+            })
+            .await;
+        if !ready {
+            return vec!["--no-cache".to_string()];
+        }
+
+        let minutes = cache.max_age.as_secs().div_ceil(60).max(1);
+        vec![
+            "--cache-dir".to_string(),
+            cache.dir.clone(),
+            "--cache-max-age".to_string(),
+            minutes.to_string(),
+        ]
+    }
+
+    /// Manage the packages of the root filesystem at `root` instead of `/`
+    ///
+    /// Every `apk` command gets `--root`, and the system's release and
+    /// repositories are read below `root`.
+    pub fn at_root(mut self, root: impl Into<String>) -> Self {
+        self.root = Some(root.into());
+        // Derived repositories depend on the release installed in the root
+        self.derived_search_repositories = Arc::new(OnceCell::new());
+        self
+    }
+
+    /// Start an `apk` command targeting the managed root
+    fn apk(&self) -> Command {
+        let mut command = command("apk");
+        if let Some(root) = &self.root {
+            command.arg("--root");
+            command.arg(root);
+        }
+        command
+    }
+
+    /// `apk add` options of `flags`, with `--no-scripts` whenever the backend
+    /// skips maintainer scripts
+    fn add_args(&self, flags: InstallFlags) -> Vec<&'static str> {
+        let mut args = Vec::new();
+        if flags.no_scripts || self.no_scripts {
+            args.push("--no-scripts");
+        }
+        if flags.no_cache {
+            args.push("--no-cache");
+        }
+        if flags.update_cache {
+            args.push("--update-cache");
+        }
+        args
+    }
+
+    /// `--repository` argument of `repository`, moved onto its internal
+    /// mirror in offline mode and with its credentials, keeping its tag
+    fn repository_arg(&self, repository: &str) -> String {
+        let (tag, repository) = split_repository_tag(repository);
+        let repository = self
+            .offline
+            .as_ref()
+            .and_then(|offline| offline.rewrite(repository))
+            .unwrap_or_else(|| repository.to_string());
+        let repository = match &self.credentials {
+            Some(credentials) => credentials.authenticate_url(&repository),
+            None => repository,
+        };
+        tagged(tag, &repository)
+    }
+
+    /// Add the tagged repositories among `repositories` to
+    /// `/etc/apk/repositories`, unless they are configured already,
+    /// returning the file's previous content when it was changed
+    ///
+    /// apk records `package@tag` in its world file, and every later command
+    /// fails to resolve it unless a configured repository carries the tag, so
+    /// the tags are registered before installing.
+    async fn register_tagged_repositories(
+        &self,
+        repositories: &[&str],
+    ) -> Result<Option<String>, PackageManagerError> {
+        let tagged: Vec<(&str, &str)> = repositories
+            .iter()
+            .filter_map(|repository| match split_repository_tag(repository) {
+                (Some(tag), url) => Some((tag, url.trim_end_matches('/'))),
+                (None, _) => None,
+            })
+            .collect();
+        if tagged.is_empty() {
+            return Ok(None);
+        }
+
+        let file = self.system_file(REPOSITORIES_FILE);
+        let original = self
+            .runner
+            .read_file(&file)
+            .await
+            .map_err(|err| {
+                PackageManagerError::io(format!("there was an error reading {file}"), err)
+            })?
+            .unwrap_or_default();
+        let mut content = original.clone();
+        for (tag, url) in tagged {
+            let configured = parse_repositories(&content).into_iter().any(|repository| {
+                repository.enabled
+                    && repository.tag.as_deref() == Some(tag)
+                    && repository.url.trim_end_matches('/') == url
+            });
+            if configured {
+                continue;
+            }
+            if !content.is_empty() && !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(&format!("@{tag} {url}\n"));
+        }
+        if content == original {
+            return Ok(None);
+        }
+        self.write_system_file(&file, &content).await?;
+        Ok(Some(original))
+    }
+
+    /// Replace the system file `file` with `content`, as root
+    async fn write_system_file(
+        &self,
+        file: &str,
+        content: &str,
+    ) -> Result<(), PackageManagerError> {
+        let mut command = command("sh");
+        command
+            .privileged()
+            .args(["-c", "cat > \"$1\"", "sh", file])
+            .stdin(content);
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(format!("there was an error writing {file}"), err)
+        })?;
+        output.success().map(|_| ())
+    }
+
+    /// Path of a system file in the managed root
+    fn system_file(&self, path: &str) -> String {
+        match &self.root {
+            Some(root) => format!("{}{path}", root.trim_end_matches('/')),
+            None => path.to_string(),
+        }
+    }
+
+    /// Name and content of the keys in `/etc/apk/keys` that are not the
+    /// Alpine project's, for repositories added elsewhere
+    async fn custom_keys(&self) -> Result<Vec<(String, String)>, PackageManagerError> {
+        let keys_dir = self.system_file(KEYS_DIR);
+        let keys = self.runner.list_dir(&keys_dir).await.map_err(|err| {
+            PackageManagerError::io(format!("there was an error reading {keys_dir}"), err)
+        })?;
+        let mut custom = Vec::new();
+        for key in keys.into_iter().filter(|key| {
+            key.ends_with(".pub") && !key.starts_with("alpine-devel@lists.alpinelinux.org-")
+        }) {
+            let path = format!("{keys_dir}/{key}");
+            let content = self
+                .runner
+                .read_file(&path)
+                .await
+                .map_err(|err| {
+                    PackageManagerError::io(format!("there was an error reading {path}"), err)
+                })?
+                .unwrap_or_default();
+            custom.push((key, content));
+        }
+        Ok(custom)
+    }
+
+    /// Repositories searched when no repository is given
+    async fn search_repository_list(&self) -> Arc<[String]> {
+        if let Some(repositories) = &self.search_repositories {
+            return repositories.clone();
+        }
+        self.derived_search_repositories
+            .get_or_init(|| self.derive_search_repositories())
+            .await
+            .clone()
+    }
+
+    /// Build the search repositories from the running system
+    ///
+    /// The list holds the `main` and `community` repositories of the
+    /// system's branch, the enabled repositories of `/etc/apk/repositories`
+    /// and the same repositories of older stable branches, so new Alpine
+    /// releases are searched without a crate update. Repositories on a
+    /// known mirror are kept relative to it so they fail over. Falls back to
+    /// [`DEFAULT_SEARCH_REPOSITORIES`] when the release cannot be read.
+    async fn derive_search_repositories(&self) -> Arc<[String]> {
+        let read = |path| async move {
+            let path = self.system_file(path);
+            self.runner.read_file(&path).await.ok().flatten()
+        };
+        let Some(release) = read(ALPINE_RELEASE_FILE)
+            .await
+            .as_deref()
+            .and_then(parse_alpine_release)
+        else {
+            return DEFAULT_SEARCH_REPOSITORIES
+                .iter()
+                .map(|repository| repository.to_string())
+                .collect();
+        };
+        let configured = parse_repositories(&read(REPOSITORIES_FILE).await.unwrap_or_default());
+
+        let branch_repositories = |branch: String| {
+            ["main", "community"].map(|repository| format!("{branch}/{repository}"))
+        };
+        let mut repositories: Vec<String> = branch_repositories(release.branch()).into();
+        for repository in configured.iter().filter(|repository| repository.enabled) {
+            let url = repository.url.trim_end_matches('/');
+            let url = match &self.offline {
+                Some(offline) => match offline.rewrite(url) {
+                    Some(url) => url,
+                    None => continue,
+                },
+                None => url.to_string(),
+            };
+            repositories.push(self.mirrors.relative_path(&url).unwrap_or(&url).to_string());
+        }
+        for branch in release.older_branches(self.older_branches) {
+            repositories.extend(branch_repositories(branch));
+        }
+
+        let mut unique = Vec::with_capacity(repositories.len());
+        for repository in repositories {
+            if !unique.contains(&repository) {
+                unique.push(repository);
+            }
+        }
+        unique.into()
+    }
+
+    /// URLs of the search repositories, on the given mirror
+    fn search_urls(repositories: &[String], mirror: &str) -> Vec<String> {
+        repositories
+            .iter()
+            .map(|repository| {
+                if repository.contains("://") || repository.starts_with('/') {
+                    repository.clone()
+                } else {
+                    format!("{mirror}/{repository}")
+                }
+            })
+            .collect()
+    }
+
+    /// URL of the repository a version was located in, on `mirror` when
+    /// relative to it
+    ///
+    /// `None` when the version was found in several repositories or its
+    /// repository was unknown and every search repository was added.
+    fn located_url(repositories: &[String], mirror: &str) -> Option<String> {
+        match repositories {
+            [_] => Self::search_urls(repositories, mirror).pop(),
+            _ => None,
+        }
+    }
+
+    /// Run a command built for a mirror, failing over to the next mirror
+    /// while `apk` reports the repositories of the current one unreachable
+    ///
+    /// When every mirror fails, the result of the last attempt is returned.
+    async fn run_with_failover(
+        &self,
+        build: impl Fn(&str) -> Command,
+    ) -> std::io::Result<ExecResult> {
+        let mut last = None;
+        for mirror in self.mirrors.candidates() {
+            let result = self.runner.run(&build(&mirror)).await?;
+
+            let diagnostics = format!(
+                "{}\n{}",
+                result.stdout.as_deref().unwrap_or_default(),
+                result.stderr.as_deref().unwrap_or_default()
+            );
+            let unreachable = parse_unreachable_repositories(&diagnostics)
+                .iter()
+                .any(|url| MirrorList::serves(&mirror, url));
+            if !unreachable {
+                self.mirrors.mark_healthy(&mirror);
+                return Ok(result);
+            }
+
+            tracing::warn!("Alpine mirror {mirror} is unreachable, trying the next mirror");
+            self.mirrors.mark_unhealthy(&mirror);
+            last = Some(result);
+        }
+
+        last.ok_or_else(|| std::io::Error::other("no Alpine mirrors are configured"))
     }
 }
 
@@ -42,6 +1054,7 @@ impl Default for Apk {
     }
 }
 
+#[async_trait]
 impl PackageManager for Apk {
     fn name(&self) -> &'static str {
         "APK"
@@ -51,282 +1064,1103 @@ impl PackageManager for Apk {
         "Alpine Linux"
     }
 
-    fn install_package(&self, options: &InstallOptions) -> Result<ExecResult, McpError> {
-        let mut command = std::process::Command::new("apk");
-        command.arg("add");
-
-        if let Some(repository) = &options.repository {
-            command.arg("--repository");
-            command.arg(repository);
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            search_repository_listing: true,
+            alternate_root: true,
+            privilege_check: true,
+            install_commands: true,
+            batch_install: true,
+            vulnerability_check: true,
+            license_report: true,
+            lockfile: true,
+            locked_install: true,
+            batch_version_install: true,
+            signature_check: true,
+            artifact_verification: true,
+            apko_config: true,
+            cloud_init: true,
+            ansible_tasks: true,
+            drift_detection: true,
+            disk_space_check: true,
+            install_flags: true,
+            repository_override: true,
+            multiple_repositories: true,
+            ..Capabilities::default()
         }
+    }
+
+    /// `apk --version`, the lock and the free space of the root and
+    /// package cache file systems
+    async fn health_checks(&self) -> Result<Vec<HealthCheck>, PackageManagerError> {
+        let runner = &*self.runner;
+        let mut checks = vec![
+            doctor::check_program(runner, self.apk().arg("--version"), "apk-tools").await,
+            doctor::check_lock(runner, &self.system_file(LOCK_FILE)).await,
+        ];
+        let paths = [self.system_file("/"), self.system_file(CACHE_DIR)];
+        checks.extend(doctor::check_disk_space(runner, &paths).await);
+        Ok(checks)
+    }
+
+    /// Modification time of the package cache directory, which `apk update`
+    /// writes the `APKINDEX` files to
+    async fn indexes_refreshed_at(
+        &self,
+    ) -> Result<Option<std::time::SystemTime>, PackageManagerError> {
+        Ok(freshness::modified_at(&*self.runner, &self.system_file(CACHE_DIR)).await)
+    }
 
-        command.arg(&options.package);
+    fn exit_meaning(&self, status: i32, _output: &str) -> Option<ExitMeaning> {
+        exit_status::apk(status)
+    }
+
+    /// Size `apk add --simulate --verbose` reports for the unpacked packages,
+    /// on the root file system; apk streams packages without caching them
+    /// unless a cache is configured
+    async fn required_space(
+        &self,
+        packages: &[String],
+        arch: Option<&str>,
+        _recommends: Option<Recommends>,
+    ) -> Result<Vec<SpaceRequirement>, PackageManagerError> {
+        validate_arch(arch)?;
 
-        let output = command.output().map_err(|err| {
-            McpError::internal_error(
+        let mut command = self.apk();
+        command.privileged().args(self.offline_args().await).args([
+            "add",
+            "--simulate",
+            "--verbose",
+        ]);
+        if let Some(arch) = arch {
+            command.arg("--arch");
+            command.arg(arch);
+        }
+        command.args(packages);
+
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
                 format!(
-                    "there was an error installing package {}: {}",
-                    &options.package, err
+                    "there was an error simulating the install of {}",
+                    packages.join(" ")
                 ),
-                None,
+                err,
             )
         })?;
+        let exec_result = output.success()?;
+        let Some(installed) =
+            parse_required_space(exec_result.stdout.as_deref().unwrap_or_default())
+        else {
+            return Ok(Vec::new());
+        };
+        doctor::space_requirements(&*self.runner, &[(self.system_file("/"), installed)])
+            .await
+            .map_err(|err| PackageManagerError::io("failed to read the free disk space", err))
+    }
 
-        Ok(ExecResult {
-            stdout: if !output.stdout.is_empty() {
-                Some(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                None
-            },
-            stderr: if !output.stderr.is_empty() {
-                Some(String::from_utf8_lossy(&output.stderr).to_string())
-            } else {
-                None
-            },
-            status: output.status.code().unwrap_or(-1),
+    /// `apk --version` and the `PRETTY_NAME` of `/etc/os-release`
+    async fn system_info(&self) -> Result<SystemInfo, PackageManagerError> {
+        let version = self.runner.run(self.apk().arg("--version")).await.ok();
+        let os_release = self
+            .runner
+            .read_file(&self.system_file(OS_RELEASE_FILE))
+            .await
+            .ok()
+            .flatten();
+        Ok(SystemInfo {
+            package_manager_version: version
+                .and_then(|output| output.success().ok())
+                .and_then(|result| parse_version_line(result.stdout.as_deref()?)),
+            distro_release: os_release.as_deref().and_then(parse_os_release_name),
         })
     }
 
-    fn install_package_with_version(
+    /// `Alpine:v<major>.<minor>`; OSV has no ecosystem for edge snapshots
+    async fn osv_ecosystem(&self) -> Result<String, PackageManagerError> {
+        let file = self.system_file(ALPINE_RELEASE_FILE);
+        let release = self
+            .runner
+            .read_file(&file)
+            .await
+            .map_err(|err| PackageManagerError::io(format!("failed to read {file}"), err))?
+            .as_deref()
+            .and_then(parse_alpine_release)
+            .filter(|release| !release.edge)
+            .ok_or(PackageManagerError::Unsupported {
+                operation: "check_vulnerabilities",
+            })?;
+        Ok(format!("Alpine:{}", release.branch()))
+    }
+
+    /// The `License` field of every installed package, from `apk list -I`
+    async fn package_licenses(&self) -> Result<Vec<PackageLicense>, PackageManagerError> {
+        let output = self
+            .runner
+            .run(self.apk().arg("list").arg("-I"))
+            .await
+            .map_err(|err| {
+                PackageManagerError::io("there was an error listing installed packages", err)
+            })?;
+
+        let exec_result = output.success()?;
+        Ok(parse_installed_licenses(
+            exec_result.stdout.as_deref().unwrap_or_default(),
+        ))
+    }
+
+    async fn source_packages(&self) -> Result<HashMap<String, String>, PackageManagerError> {
+        let output = self
+            .runner
+            .run(self.apk().arg("list").arg("-I"))
+            .await
+            .map_err(|err| {
+                PackageManagerError::io("there was an error listing installed packages", err)
+            })?;
+
+        let exec_result = output.success()?;
+        Ok(parse_installed_origins(
+            exec_result.stdout.as_deref().unwrap_or_default(),
+        ))
+    }
+
+    async fn install_commands(
         &self,
-        options: &InstallVersionOptions,
-    ) -> Result<ExecResult, McpError> {
-        // Validate inputs to prevent command injection
-        if !validate_package_version_input(&options.package) {
-            return Err(McpError::internal_error(
-                format!(
-                    "Invalid package name '{}': only alphanumeric characters, dots, hyphens, underscores, and plus signs are allowed",
-                    options.package
-                ),
-                Some(serde_json::json!({
-                    "package_name": options.package,
-                    "error_type": "validation_error"
-                })),
-            ));
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        Ok(pinned_installs(packages)
+            .into_iter()
+            .map(|(command, names)| format!("{command} {}", names.join(" ")))
+            .collect())
+    }
+
+    /// The enabled repositories, the keys in `/etc/apk/keys` and the
+    /// system's architecture
+    async fn apko_config(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<ApkoConfig, PackageManagerError> {
+        let repositories = self
+            .list_repositories()
+            .await?
+            .into_iter()
+            .filter(|repository| repository.enabled)
+            .map(|repository| repository.url);
+        let mut config = ApkoConfig::new(packages, repositories, self.system_arch().await);
+
+        let keys_dir = self.system_file(KEYS_DIR);
+        let keys = self.runner.list_dir(&keys_dir).await.map_err(|err| {
+            PackageManagerError::io(format!("there was an error reading {keys_dir}"), err)
+        })?;
+        for key in keys.iter().filter(|key| key.ends_with(".pub")) {
+            config = config.with_key(KEYS_DIR, key);
         }
+        Ok(config)
+    }
 
-        if !validate_package_version_input(&options.version) {
-            return Err(McpError::internal_error(
-                format!(
-                    "Invalid version string '{}': only alphanumeric characters, dots, hyphens, underscores, and plus signs are allowed",
-                    options.version
-                ),
-                Some(serde_json::json!({
-                    "version": options.version,
-                    "error_type": "validation_error"
-                })),
-            ));
+    /// Custom repositories appended to `/etc/apk/repositories`, with the keys
+    /// in `/etc/apk/keys` that are not the Alpine project's
+    async fn cloud_config(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<CloudConfig, PackageManagerError> {
+        let custom = custom_repositories(packages);
+        let mut config = custom
+            .iter()
+            .fold(CloudConfig::new(packages), |config, repository| {
+                config.with_apk_repository(repository)
+            });
+        if custom.is_empty() {
+            return Ok(config);
+        }
+        for (name, content) in self.custom_keys().await?.iter().rev() {
+            config = config.with_apk_key(name, content);
         }
+        Ok(config)
+    }
 
-        // Reuse the search_package function to find available versions
-        let search_options = SearchOptions {
-            query: options.package.clone(),
-            repository: None, // Search across all repositories
-        };
+    /// Tasks copying the keys and adding the repositories like
+    /// `cloud_config`, then one `community.general.apk` task
+    ///
+    /// The module's `repository` argument replaces the system repositories,
+    /// so custom repositories are added to `/etc/apk/repositories` instead.
+    async fn ansible_tasks(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<AnsibleTask>, PackageManagerError> {
+        let custom = custom_repositories(packages);
+        let mut tasks = Vec::new();
+        if !custom.is_empty() {
+            for (name, content) in self.custom_keys().await? {
+                tasks.push(
+                    AnsibleTask::new(format!("Install APK key {name}"), "ansible.builtin.copy")
+                        .argument("dest", TaskArgument::Text(format!("{KEYS_DIR}/{name}")))
+                        .argument("content", TaskArgument::Block(content))
+                        .argument("mode", TaskArgument::Text("0644".to_string())),
+                );
+            }
+        }
+        for repository in &custom {
+            tasks.push(
+                AnsibleTask::new(
+                    format!("Add APK repository {repository}"),
+                    "ansible.builtin.lineinfile",
+                )
+                .argument("path", TaskArgument::Text(REPOSITORIES_FILE.to_string()))
+                .argument("line", TaskArgument::Text(repository.to_string())),
+            );
+        }
 
-        let search_result = self.search_package(&search_options)?;
+        let mut names = Vec::new();
+        for package in packages {
+            let pinned = format!("{}={}", package.name, package.version);
+            if !names.contains(&pinned) {
+                names.push(pinned);
+            }
+        }
+        tasks.push(
+            AnsibleTask::new("Install packages", "community.general.apk")
+                .argument("name", TaskArgument::List(names))
+                .argument("update_cache", TaskArgument::Flag(true)),
+        );
+        Ok(tasks)
+    }
 
-        // Parse the search output to find available versions
-        let mut found_versions: Vec<String> = Vec::new();
-        let mut version_found = false;
+    /// One `RUN apk add --no-cache` per architecture and repository, a
+    /// package per line
+    async fn dockerfile_instructions(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        Ok(pinned_installs(packages)
+            .into_iter()
+            .map(|(command, names)| format!("RUN {command} \\\n    {}", names.join(" \\\n    ")))
+            .collect())
+    }
 
-        if let Some(stdout) = &search_result.stdout {
-            for line in stdout.lines() {
-                // Skip fetch messages and empty lines
-                if line.starts_with("fetch ") || line.trim().is_empty() {
-                    continue;
-                }
+    /// Checksums from the installed database, sources from `apk policy` of
+    /// the search repositories
+    async fn lock_packages(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<LockedPackage>, PackageManagerError> {
+        let file = self.system_file(INSTALLED_DATABASE);
+        let checksums = self
+            .runner
+            .read_file(&file)
+            .await
+            .map_err(|err| PackageManagerError::io(format!("failed to read {file}"), err))?
+            .map(|content| parse_installed_checksums(&content))
+            .unwrap_or_default();
 
-                // Parse package-version from output
-                // Format is typically: package-name-version
-                if let Some(version_str) = line.strip_prefix(&format!("{}-", options.package)) {
-                    found_versions.push(version_str.to_string());
+        let names: Vec<&str> = packages
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect();
+        let output = self.policy(&names, None).await.map_err(|err| {
+            PackageManagerError::io(
+                "there was an error looking up the repositories of installed packages",
+                err,
+            )
+        })?;
+        let exec_result = output.success()?;
+        let sources = parse_policy(exec_result.stdout.as_deref().unwrap_or_default());
+
+        Ok(lock_packages_with(packages, &checksums, &sources))
+    }
+
+    /// One `apk add` of every `name=version`, adding the custom repository
+    /// or source of each package
+    async fn install_locked(
+        &self,
+        packages: &[LockedPackage],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        // `apk add` installs for one architecture
+        let mut arches: Vec<&str> = packages
+            .iter()
+            .filter_map(|package| package.package.arch.as_deref())
+            .collect();
+        arches.sort_unstable();
+        arches.dedup();
+        if arches.len() > 1 {
+            return Err(PackageManagerError::Validation {
+                field: "lockfile",
+                value: arches.join(", "),
+                reason: "APK installs the packages of a lockfile for a single architecture"
+                    .to_string(),
+            });
+        }
+        let arch = arches.first().copied();
+        validate_arch(arch)?;
 
-                    // Check for exact version match
-                    if version_str == options.version {
-                        version_found = true;
+        let mut repositories: Vec<String> = Vec::new();
+        let mut local = false;
+        for package in packages {
+            let custom: Vec<&str> = package.package.custom_repositories().collect();
+            let mut used = Vec::new();
+            for repository in &custom {
+                used.push(match local_directory(repository) {
+                    Some(dir) => {
+                        // Locked packages of local directories are installed
+                        // from them
+                        local = true;
+                        self.index_local_repository(dir, arch).await?;
+                        dir.to_string()
                     }
+                    None => repository.to_string(),
+                });
+            }
+            if custom.is_empty() {
+                match &package.source {
+                    Some(source) if source.contains("://") => used.push(source.clone()),
+                    _ => continue,
+                }
+            }
+            for repository in used {
+                if !repositories.contains(&repository) {
+                    repositories.push(repository);
                 }
             }
         }
 
-        // If exact version match found, install it
-        if version_found {
-            let mut install_cmd = std::process::Command::new("apk");
-            install_cmd.arg("add");
+        let mut command = self.apk();
+        command
+            .privileged()
+            .args(self.offline_args().await)
+            .arg("add")
+            .args(self.add_args(InstallFlags::default()));
+        if let Some(arch) = arch {
+            command.arg("--arch");
+            command.arg(arch);
+        }
+        // Generated indexes are not signed
+        if local {
+            command.arg("--allow-untrusted");
+        }
+        for repository in &repositories {
+            command.arg("--repository");
+            command.arg(self.repository_arg(repository));
+        }
+        for package in packages {
+            command.arg(format!(
+                "{}={}",
+                package.package.name, package.package.version
+            ));
+        }
+
+        let names: Vec<String> = packages
+            .iter()
+            .map(|package| package.package.name.clone())
+            .collect();
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!("there was an error installing packages {}", names.join(" ")),
+                err,
+            )
+        })?;
+
+        let exec_result = output.success()?;
+        Ok(install_outcomes(&names, exec_result))
+    }
+
+    fn with_root(&self, root: &str) -> Result<Arc<dyn PackageManager>, PackageManagerError> {
+        validate_root(root)?;
+        Ok(Arc::new(self.clone().at_root(root)))
+    }
+
+    fn version_scheme(&self) -> Option<VersionScheme> {
+        Some(VersionScheme::Apk)
+    }
+
+    async fn install_package(
+        &self,
+        options: &InstallOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        validate_arch(options.arch.as_deref())?;
 
-            // Add all repositories - apk will find the right one
-            for repo in SEARCH_REPOSITORIES {
-                install_cmd.arg("--repository");
-                install_cmd.arg(repo);
+        let (repositories, mut local) = self
+            .prepare_repositories(&options.added_repositories(), options.arch.as_deref())
+            .await?;
+        let repository_args = match options.only_repositories.as_slice() {
+            [] => self.offline_args().await,
+            repositories => {
+                let (args, only_local) = self
+                    .override_args(repositories, options.arch.as_deref())
+                    .await?;
+                local.extend(only_local);
+                args
+            }
+        };
+        let untrusted = self.installs_local(&local, &[&options.package]).await?;
+        let mut registered = options.added_repositories();
+        registered.extend(options.only_repositories.iter().map(String::as_str));
+        let previous = self.register_tagged_repositories(&registered).await?;
+        let build = |repositories: &[String]| {
+            let mut command = self.apk();
+            command
+                .privileged()
+                .args(&repository_args)
+                .arg("add")
+                .args(self.add_args(options.flags));
+            if let Some(arch) = &options.arch {
+                command.arg("--arch");
+                command.arg(arch);
+            }
+            // Generated indexes are not signed
+            if untrusted {
+                command.arg("--allow-untrusted");
             }
+            for repository in repositories {
+                command.arg("--repository");
+                command.arg(self.repository_arg(repository));
+            }
+            command.arg(&options.package);
+            command
+        };
 
-            install_cmd.arg(format!("{}={}", options.package, options.version));
+        // A single repository on a known mirror fails over to the same path
+        // on the other mirrors
+        let mirrored = match repositories.as_slice() {
+            [repository] => self.mirrors.relative_path(repository),
+            _ => None,
+        };
+        let installed = match mirrored {
+            Some(path) => {
+                self.run_with_failover(|mirror| build(&[format!("{mirror}/{path}")]))
+                    .await
+            }
+            None => self.runner.run(&build(&repositories)).await,
+        }
+        .map_err(|err| {
+            PackageManagerError::io(
+                format!("there was an error installing package {}", options.package),
+                err,
+            )
+        })
+        .and_then(|output| output.success());
+
+        // Failed installs leave the repositories as they were
+        if installed.is_err()
+            && let Some(previous) = previous
+        {
+            let file = self.system_file(REPOSITORIES_FILE);
+            if let Err(err) = self.write_system_file(&file, &previous).await {
+                tracing::warn!("Could not restore {file} after a failed install: {err}");
+            }
+        }
+        Ok(install_outcome(&options.package, installed?))
+    }
+
+    async fn install_packages(
+        &self,
+        packages: &[String],
+        arch: Option<&str>,
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        validate_arch(arch)?;
 
-            let output = install_cmd.output().map_err(|err| {
-                McpError::internal_error(
+        let mut command = self.apk();
+        command
+            .privileged()
+            .args(self.offline_args().await)
+            .arg("add")
+            .args(self.add_args(InstallFlags::default()));
+        if let Some(arch) = arch {
+            command.arg("--arch");
+            command.arg(arch);
+        }
+        command.args(packages);
+
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!(
+                    "there was an error installing packages {}",
+                    packages.join(" ")
+                ),
+                err,
+            )
+        })?;
+
+        let exec_result = output.success()?;
+        Ok(install_outcomes(packages, exec_result))
+    }
+
+    async fn install_package_with_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        validate_arch(options.arch.as_deref())?;
+        let (version, repositories) = self.resolve_install(options).await?;
+
+        let offline_args = self.offline_args().await;
+        let used_mirror = std::sync::Mutex::new(String::new());
+        let output = self
+            .run_with_failover(|mirror| {
+                *used_mirror.lock().unwrap_or_else(|err| err.into_inner()) = mirror.to_string();
+                let mut install_cmd = self.apk();
+                install_cmd
+                    .privileged()
+                    .args(&offline_args)
+                    .arg("add")
+                    .args(self.add_args(options.flags));
+                if let Some(arch) = &options.arch {
+                    install_cmd.arg("--arch");
+                    install_cmd.arg(arch);
+                }
+
+                for repo in Self::search_urls(&repositories, mirror) {
+                    install_cmd.arg("--repository");
+                    install_cmd.arg(self.repository_arg(&repo));
+                }
+
+                install_cmd.arg(format!("{}={}", options.package, version));
+                install_cmd
+            })
+            .await
+            .map_err(|err| {
+                PackageManagerError::io(
                     format!(
-                        "there was an error installing package {}={}: {}",
-                        options.package, options.version, err
+                        "there was an error installing package {}={}",
+                        options.package, version
                     ),
-                    None,
+                    err,
                 )
             })?;
 
-            return Ok(ExecResult {
-                stdout: if !output.stdout.is_empty() {
-                    Some(String::from_utf8_lossy(&output.stdout).to_string())
-                } else {
-                    None
-                },
-                stderr: if !output.stderr.is_empty() {
-                    Some(String::from_utf8_lossy(&output.stderr).to_string())
-                } else {
-                    None
-                },
-                status: output.status.code().unwrap_or(-1),
+        let exec_result = output.success()?;
+        let mut outcome = install_outcome(&options.package, exec_result);
+        outcome.repository = Self::located_url(
+            &repositories,
+            &used_mirror
+                .into_inner()
+                .unwrap_or_else(|err| err.into_inner()),
+        );
+        Ok(outcome)
+    }
+
+    async fn resolve_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<ResolvedVersion, PackageManagerError> {
+        validate_arch(options.arch.as_deref())?;
+        Ok(self.resolve_candidates(options).await?.0)
+    }
+
+    /// Resolves every version first, then installs them with one `apk add`
+    async fn install_packages_with_versions(
+        &self,
+        packages: &[InstallVersionOptions],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        // `apk add` installs for one architecture
+        let mut arches: Vec<&str> = packages
+            .iter()
+            .filter_map(|package| package.arch.as_deref())
+            .collect();
+        arches.sort_unstable();
+        arches.dedup();
+        if arches.len() > 1 {
+            return Err(PackageManagerError::Validation {
+                field: "packages",
+                value: arches.join(", "),
+                reason: "APK installs the packages of one call for a single architecture"
+                    .to_string(),
             });
         }
+        let arch = arches.first().copied();
+        validate_arch(arch)?;
 
-        // Version not found - return error with available versions
-        if found_versions.is_empty() {
-            return Err(McpError::internal_error(
-                format!(
-                    "Package '{}' not found in any searched repository",
-                    options.package
-                ),
-                Some(serde_json::json!({
-                    "package_name": options.package,
-                    "requested_version": options.version,
-                    "error_type": "package_not_found",
-                    "searched_repositories": SEARCH_REPOSITORIES
-                })),
-            ));
+        let mut specs = Vec::new();
+        let mut repositories: Vec<String> = Vec::new();
+        let mut locations = Vec::new();
+        for options in packages {
+            let (version, located) = self.resolve_install(options).await?;
+            specs.push(format!("{}={}", options.package, version));
+            for repository in located.iter() {
+                if !repositories.contains(repository) {
+                    repositories.push(repository.clone());
+                }
+            }
+            locations.push(located);
         }
 
-        // Remove duplicates and sort available versions
-        found_versions.sort();
-        found_versions.dedup();
-
-        Err(McpError::internal_error(
-            format!(
-                "Version '{}' of package '{}' not found. Available versions: {}",
-                options.version,
-                options.package,
-                found_versions.join(", ")
-            ),
-            Some(serde_json::json!({
-                "package_name": options.package,
-                "requested_version": options.version,
-                "available_versions": found_versions,
-                "error_type": "version_not_found"
-            })),
-        ))
+        let flags = packages
+            .first()
+            .map(|options| options.flags)
+            .unwrap_or_default();
+        let offline_args = self.offline_args().await;
+        let used_mirror = std::sync::Mutex::new(String::new());
+        let output = self
+            .run_with_failover(|mirror| {
+                *used_mirror.lock().unwrap_or_else(|err| err.into_inner()) = mirror.to_string();
+                let mut install_cmd = self.apk();
+                install_cmd
+                    .privileged()
+                    .args(&offline_args)
+                    .arg("add")
+                    .args(self.add_args(flags));
+                if let Some(arch) = arch {
+                    install_cmd.arg("--arch");
+                    install_cmd.arg(arch);
+                }
+
+                for repo in Self::search_urls(&repositories, mirror) {
+                    install_cmd.arg("--repository");
+                    install_cmd.arg(self.repository_arg(&repo));
+                }
+
+                install_cmd.args(&specs);
+                install_cmd
+            })
+            .await
+            .map_err(|err| {
+                PackageManagerError::io(
+                    format!("there was an error installing packages {}", specs.join(" ")),
+                    err,
+                )
+            })?;
+
+        let exec_result = output.success()?;
+        let names: Vec<String> = packages
+            .iter()
+            .map(|options| options.package.clone())
+            .collect();
+        let used_mirror = used_mirror
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner());
+        let mut outcomes = install_outcomes(&names, exec_result);
+        for (outcome, located) in outcomes.iter_mut().zip(&locations) {
+            outcome.repository = Self::located_url(located, &used_mirror);
+        }
+        Ok(outcomes)
     }
 
-    fn search_package(&self, options: &SearchOptions) -> Result<ExecResult, McpError> {
-        let mut command = std::process::Command::new("apk");
-        command.arg("--no-cache");
+    async fn search_package(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        validate_arch(options.arch.as_deref())?;
 
-        // Add repositories: use provided repository or search all
-        if let Some(repository) = &options.repository {
-            command.arg("--repository");
-            command.arg(repository);
-        } else {
-            // Search across all repositories
-            for repo in SEARCH_REPOSITORIES {
-                command.arg("--repository");
-                command.arg(repo);
+        match &self.search_provider {
+            SearchProvider::Apk => {}
+            SearchProvider::Index(reader) => return self.search_indexes(reader, options).await,
+            SearchProvider::PackagesSite(site) => {
+                if let Some(results) = self.search_packages_site(site, options).await {
+                    return results;
+                }
             }
         }
 
-        command.arg("search");
-        command.arg("--exact");
-        command.arg("--all");
-        command.arg(&options.query);
+        let (repositories, mut local) = self
+            .prepare_repositories(&options.added_repositories(), options.arch.as_deref())
+            .await?;
+        let cache_args = self.index_cache_args().await;
+        let repository_args = match options.only_repositories.as_slice() {
+            [] => self.offline_args().await,
+            repositories => {
+                let (args, only_local) = self
+                    .override_args(repositories, options.arch.as_deref())
+                    .await?;
+                local.extend(only_local);
+                args
+            }
+        };
+        let build = |repositories: &mut dyn Iterator<Item = String>| {
+            let mut command = self.apk();
+            command.args(&cache_args).args(&repository_args);
+            if let Some(arch) = &options.arch {
+                command.arg("--arch");
+                command.arg(arch);
+            }
+            // Searches only read the indexes, so local directories are
+            // listed even though theirs are not signed
+            if !local.is_empty() {
+                command.arg("--allow-untrusted");
+            }
+            for repository in repositories {
+                command.arg("--repository");
+                command.arg(self.repository_arg(&repository));
+            }
+            command.arg("search");
+            match options.mode {
+                SearchMode::Exact => {
+                    command.arg("--exact");
+                    command.arg("--all");
+                    command.arg(&options.query);
+                }
+                SearchMode::Description => {
+                    command.arg("--description");
+                    command.arg("--all");
+                    command.arg(&options.query);
+                }
+                mode => {
+                    command.arg("--all");
+                    command.arg(mode.name_glob(&options.query));
+                }
+            }
+            command
+        };
 
-        let output = command.output().map_err(|err| {
-            McpError::internal_error(
+        // Add repositories: use the provided ones or search all. A single
+        // repository on a known mirror fails over with the mirrors.
+        let mirrored = match repositories.as_slice() {
+            [repository] => self.mirrors.relative_path(repository),
+            _ => None,
+        };
+        let output = match mirrored {
+            Some(path) => {
+                self.run_with_failover(|mirror| {
+                    build(&mut std::iter::once(format!("{mirror}/{path}")))
+                })
+                .await
+            }
+            // Also when the call's repositories replace the configured ones
+            None if !repositories.is_empty() || !options.only_repositories.is_empty() => {
+                self.runner
+                    .run(&build(&mut repositories.iter().cloned()))
+                    .await
+            }
+            // Search across all repositories
+            None => {
+                let searched = self.search_repository_list().await;
+                self.run_with_failover(|mirror| {
+                    build(&mut Self::search_urls(&searched, mirror).into_iter())
+                })
+                .await
+            }
+        }
+        .map_err(|err| {
+            PackageManagerError::io(
                 format!(
-                    "there was an error searching for packages with query {}: {}",
-                    &options.query, err
+                    "there was an error searching for packages with query {}",
+                    options.query
                 ),
-                None,
+                err,
             )
         })?;
 
-        Ok(ExecResult {
-            stdout: if !output.stdout.is_empty() {
-                Some(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                None
-            },
-            stderr: if !output.stderr.is_empty() {
-                Some(String::from_utf8_lossy(&output.stderr).to_string())
-            } else {
-                None
-            },
-            status: output.status.code().unwrap_or(-1),
-        })
+        let exec_result = output.success()?;
+        Ok(parse_search(
+            exec_result.stdout.as_deref().unwrap_or_default(),
+        ))
     }
 
-    fn list_installed_packages(&self) -> Result<ExecResult, McpError> {
-        let output = std::process::Command::new("apk")
-            .arg("list")
-            .arg("-I")
-            .output()
+    async fn list_installed_packages(&self) -> Result<Vec<InstalledPackage>, PackageManagerError> {
+        let output = self
+            .runner
+            .run(self.apk().arg("list").arg("-I"))
+            .await
             .map_err(|err| {
-                McpError::internal_error(
-                    format!("there was an error listing installed packages: {err}"),
-                    None,
-                )
+                PackageManagerError::io("there was an error listing installed packages", err)
             })?;
 
-        Ok(ExecResult {
-            stdout: if !output.stdout.is_empty() {
-                Some(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                None
-            },
-            stderr: if !output.stderr.is_empty() {
-                Some(String::from_utf8_lossy(&output.stderr).to_string())
-            } else {
-                None
-            },
-            status: output.status.code().unwrap_or(-1),
-        })
+        let exec_result = output.success()?;
+        // Without the world file, the reason of each package is unknown
+        let world = self
+            .runner
+            .read_file(&self.system_file(WORLD_FILE))
+            .await
+            .ok()
+            .flatten();
+        Ok(parse_installed_list(
+            exec_result.stdout.as_deref().unwrap_or_default(),
+            world.as_deref(),
+        ))
+    }
+
+    async fn refresh_repositories(&self) -> Result<ExecResult, PackageManagerError> {
+        let output = self
+            .runner
+            .run(
+                self.apk()
+                    .privileged()
+                    .args(self.offline_args().await)
+                    .arg("update"),
+            )
+            .await
+            .map_err(|err| {
+                PackageManagerError::io("there was an error refreshing repositories", err)
+            })?;
+
+        output.success()
     }
 
-    fn refresh_repositories(&self) -> Result<ExecResult, McpError> {
-        let output = std::process::Command::new("apk")
-            .arg("update")
-            .output()
+    async fn list_search_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        let mirror = self
+            .mirrors
+            .candidates()
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        Ok(
+            Self::search_urls(&self.search_repository_list().await, &mirror)
+                .into_iter()
+                .map(Repository::new)
+                .collect(),
+        )
+    }
+
+    async fn check_privileges(&self) -> Result<PrivilegeReport, PackageManagerError> {
+        Ok(privileges::check(&*self.runner, &self.system_file(DATABASE_DIR)).await)
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        let file = self.system_file(REPOSITORIES_FILE);
+        let content = self
+            .runner
+            .read_file(&file)
+            .await
             .map_err(|err| {
-                McpError::internal_error(
-                    format!("there was an error refreshing repositories: {err}"),
-                    None,
+                PackageManagerError::io(format!("there was an error reading {file}"), err)
+            })?
+            .unwrap_or_default();
+
+        Ok(parse_repositories(&content)
+            .into_iter()
+            .map(|repository| Repository {
+                file: Some(file.clone()),
+                ..repository
+            })
+            .collect())
+    }
+
+    /// `apk fetch` of the exact version into a private directory, with the
+    /// SHA-256 of the file and the key its signature names
+    async fn fetch_package(
+        &self,
+        package: &PinnedPackage,
+    ) -> Result<FetchedPackage, PackageManagerError> {
+        validate_arch(package.arch.as_deref())?;
+        let local = package.repository.as_deref().and_then(local_directory);
+        if let Some(dir) = local {
+            self.index_local_repository(dir, package.arch.as_deref())
+                .await?;
+        }
+        let dir = pins::create_fetch_directory(&*self.runner).await?;
+
+        let mut fetch = self.apk();
+        fetch.args(self.offline_args().await).arg("fetch");
+        if let Some(arch) = &package.arch {
+            fetch.arg("--arch");
+            fetch.arg(arch);
+        }
+        if local.is_some() {
+            fetch.arg("--allow-untrusted");
+        }
+        if let Some(repository) = local.or(package.repository.as_deref()) {
+            fetch.arg("--repository");
+            fetch.arg(self.repository_arg(repository));
+        }
+        fetch
+            .args(["--output", &dir])
+            .arg(format!("{}={}", package.name, package.version));
+
+        let fetched = async {
+            let output = self.runner.run(&fetch).await.map_err(|err| {
+                PackageManagerError::io(
+                    format!("there was an error downloading package {}", package.name),
+                    err,
                 )
             })?;
+            output.success()?;
+            let path = pins::fetched_file(&*self.runner, &dir, ".apk").await?;
+            let sha256 = pins::file_sha256(&*self.runner, &path).await?;
+            // The signature is the first entry of the first gzip stream
+            let listing = self
+                .runner
+                .run(command("tar").args(["-tzf", &path]))
+                .await
+                .map_err(|err| PackageManagerError::io(format!("failed to list {path}"), err))?;
+            Ok(FetchedPackage {
+                package: package.clone(),
+                signing_key: parse_signing_key(listing.stdout.as_deref().unwrap_or_default()),
+                path,
+                sha256,
+            })
+        }
+        .await;
+        if fetched.is_err() {
+            let _ = pins::remove_fetch_directory(&*self.runner, &dir).await;
+        }
+        fetched
+    }
 
-        Ok(ExecResult {
-            stdout: if !output.stdout.is_empty() {
-                Some(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                None
-            },
-            stderr: if !output.stderr.is_empty() {
-                Some(String::from_utf8_lossy(&output.stderr).to_string())
-            } else {
-                None
-            },
-            status: output.status.code().unwrap_or(-1),
+    /// `apk add` of the downloaded file, which apk checks against the
+    /// signing keys like packages from repositories
+    async fn install_fetched(
+        &self,
+        package: &FetchedPackage,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        let pinned = &package.package;
+        let local = pinned.repository.as_deref().and_then(local_directory);
+        let mut command = self.apk();
+        command
+            .privileged()
+            .args(self.offline_args().await)
+            .arg("add")
+            .args(self.add_args(InstallFlags::default()));
+        if let Some(arch) = &pinned.arch {
+            command.arg("--arch");
+            command.arg(arch);
+        }
+        // Packages of local directories are not signed
+        if local.is_some() {
+            command.arg("--allow-untrusted");
+        }
+        if let Some(repository) = local.or(pinned.repository.as_deref()) {
+            command.arg("--repository");
+            command.arg(self.repository_arg(repository));
+        }
+        command.arg(&package.path);
+
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!("there was an error installing package {}", pinned.name),
+                err,
+            )
+        })?;
+        let exec_result = output.success()?;
+        Ok(install_outcome(&pinned.name, exec_result))
+    }
+
+    async fn discard_fetched(&self, package: &FetchedPackage) -> Result<(), PackageManagerError> {
+        match package.path.rsplit_once('/') {
+            Some((dir, _)) => pins::remove_fetch_directory(&*self.runner, dir).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Every repository when there are no signing keys, and local
+    /// directories
+    ///
+    /// apk checks every index against the keys in `/etc/apk/keys`, except
+    /// when told to allow untrusted indexes as for local directories.
+    /// Digest of the installed database, `/lib/apk/db/installed`, rewritten by every change
+    async fn database_fingerprint(&self) -> Result<String, PackageManagerError> {
+        let file = self.system_file(INSTALLED_DATABASE);
+        let content = self
+            .runner
+            .read_file(&file)
+            .await
+            .map_err(|err| PackageManagerError::io(format!("failed to read {file}"), err))?;
+        Ok(drift::fingerprint(content.unwrap_or_default().as_bytes()))
+    }
+
+    async fn unsigned_repositories(
+        &self,
+        repository: Option<&str>,
+    ) -> Result<Vec<UnsignedRepository>, PackageManagerError> {
+        let mut problems = Vec::new();
+        if let Some(dir) = repository.and_then(local_directory) {
+            problems.push(UnsignedRepository::new(
+                dir,
+                "the index generated for a local directory is not signed",
+            ));
+        }
+
+        let keys_dir = self.system_file(KEYS_DIR);
+        let keys = self.runner.list_dir(&keys_dir).await.map_err(|err| {
+            PackageManagerError::io(format!("there was an error reading {keys_dir}"), err)
+        })?;
+        if !keys.iter().any(|key| key.ends_with(".pub")) {
+            let reason = format!("there are no signing keys in {keys_dir} to verify its index");
+            let custom = repository
+                .filter(|repository| local_directory(repository).is_none())
+                .map(|repository| Repository::new(repository.trim()));
+            let configured = self.list_repositories().await?.into_iter();
+            for repository in configured
+                .filter(|repository| repository.enabled)
+                .chain(custom)
+            {
+                problems.push(
+                    UnsignedRepository::new(repository.url, reason.clone())
+                        .in_file(repository.file),
+                );
+            }
+        }
+        Ok(problems)
+    }
+}
+
+/// Custom repositories packages were installed from, in order of first use
+///
+/// Local directories do not exist on other machines and are left out.
+fn custom_repositories(packages: &[PinnedPackage]) -> Vec<&str> {
+    let mut repositories = Vec::new();
+    for repository in packages
+        .iter()
+        .flat_map(PinnedPackage::custom_repositories)
+        .filter(|repository| local_directory(repository).is_none())
+    {
+        if !repositories.contains(&repository) {
+            repositories.push(repository);
+        }
+    }
+    repositories
+}
+
+/// `apk add` commands installing pinned packages, one per architecture and
+/// set of repositories in order of first use, with the quoted
+/// `name=version` of their packages
+fn pinned_installs(packages: &[PinnedPackage]) -> Vec<(String, Vec<String>)> {
+    let mut groups: Vec<(Option<&str>, Vec<&str>, Vec<String>)> = Vec::new();
+    for package in packages {
+        let arch = package.arch.as_deref();
+        let repositories: Vec<&str> = package.custom_repositories().collect();
+        let pinned = shell_quote(&format!("{}={}", package.name, package.version));
+        match groups
+            .iter_mut()
+            .find(|(group_arch, group_repositories, _)| {
+                *group_arch == arch && *group_repositories == repositories
+            }) {
+            Some((_, _, names)) => names.push(pinned),
+            None => groups.push((arch, repositories, vec![pinned])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(arch, repositories, names)| {
+            let mut command = "apk add --no-cache".to_string();
+            if let Some(arch) = arch {
+                command.push_str(&format!(" --arch {}", shell_quote(arch)));
+            }
+            for repository in repositories {
+                command.push_str(&format!(" --repository {}", shell_quote(repository)));
+            }
+            (command, names)
         })
+        .collect()
+}
+
+/// `repository` with its `@tag`, if any
+fn tagged(tag: Option<&str>, repository: &str) -> String {
+    match tag {
+        Some(tag) => format!("@{tag} {repository}"),
+        None => repository.to_string(),
     }
 }
 
-fn validate_package_version_input(input: &str) -> bool {
-    // Allow alphanumeric, dots, hyphens, underscores, and plus signs (common in version strings)
-    input
-        .chars()
-        .all(|c| c.is_alphanumeric() || c == '.' || c == '-' || c == '_' || c == '+')
+/// Outcome of installing `package`, from the output of `apk add`
+fn install_outcome(package: &str, exec_result: ExecResult) -> InstallOutcome {
+    let mut outcomes = install_outcomes(&[package.to_string()], exec_result);
+    outcomes.remove(0)
+}
+
+/// Outcomes of installing `packages` with one `apk add`
+///
+/// apk does not report the size of its downloads.
+fn install_outcomes(packages: &[String], exec_result: ExecResult) -> Vec<InstallOutcome> {
+    let stdout = exec_result.stdout.clone().unwrap_or_default();
+    // apk reports the packages of `package@tag` under their plain name
+    let names: Vec<String> = packages
+        .iter()
+        .map(|package| split_package_tag(package).0.to_string())
+        .collect();
+    let mut outcomes = InstallOutcome::batch(&names, parse_install_output(&stdout), exec_result);
+    for (outcome, package) in outcomes.iter_mut().zip(packages) {
+        outcome.package = package.clone();
+    }
+    // Counted once, with the first package
+    if let Some(first) = outcomes.first_mut() {
+        first.removed = parse_removed_packages(&stdout);
+    }
+    outcomes
 }
@@ -0,0 +1,208 @@
+//! Native reader of APK repository indexes
+//!
+//! `apk search` downloads the index of every search repository again unless a
+//! cache directory is configured, and needs `apk` on the managed system. The
+//! APK backend can instead download each repository's `APKINDEX.tar.gz`
+//! itself and parse it with [`parse_apkindex`]. Parsed indexes are kept in
+//! memory until they are older than the reader's maximum age, searches work
+//! where `apk` is not installed (e.g. a host managing a root filesystem), and
+//! results carry the repository, architecture and license of each package.
+//!
+//! The signature of a downloaded index is not verified, so search results are
+//! only as trustworthy as the connection to the repository. Installs still run
+//! `apk`, which checks the index and the packages against the signing keys.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use flate2::read::MultiGzDecoder;
+
+use super::SearchResult;
+use super::credentials::RepositoryCredentials;
+use super::mirrors::MirrorList;
+use super::parse::apk::parse_apkindex;
+
+/// How long downloading one index may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Name of the index file in the `APKINDEX.tar.gz` archive
+const INDEX_FILE: &str = "APKINDEX";
+
+/// Why an index could not be loaded
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum IndexError {
+    /// The server could not be reached, another mirror may serve the index
+    #[error("{0}")]
+    Unreachable(String),
+    /// The index is missing or cannot be read
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// Where the index of a search repository is downloaded from
+#[derive(Clone, Debug)]
+pub(crate) enum IndexSource {
+    /// A path on the mirrors, e.g. `v3.22/main`, failing over between them
+    Mirrored(String),
+    /// A URL or a local path on the server, used as it is
+    Direct(String),
+}
+
+/// A parsed index and when it was downloaded
+type CachedIndex = (Instant, Arc<[SearchResult]>);
+
+/// Downloaded and parsed repository indexes, shared by clones of the backend
+pub(crate) struct IndexReader {
+    client: reqwest::Client,
+    max_age: Duration,
+    indexes: Mutex<HashMap<String, CachedIndex>>,
+    /// Basic authentication of private repositories
+    credentials: Option<Arc<RepositoryCredentials>>,
+}
+
+impl IndexReader {
+    pub(crate) fn new(max_age: Duration) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            max_age,
+            indexes: Mutex::default(),
+            credentials: None,
+        }
+    }
+
+    /// Authenticate downloads from private repositories
+    pub(crate) fn with_credentials(mut self, credentials: Arc<RepositoryCredentials>) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub(crate) fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Packages of the repository at `repository` for `arch`
+    ///
+    /// Every package has its `repository` set to `repository`.
+    pub(crate) async fn index(
+        &self,
+        repository: &str,
+        arch: &str,
+    ) -> Result<Arc<[SearchResult]>, IndexError> {
+        let url = format!("{repository}/{arch}/APKINDEX.tar.gz");
+        if let Some((fetched, index)) = self.lock_indexes().get(&url)
+            && fetched.elapsed() < self.max_age
+        {
+            return Ok(index.clone());
+        }
+
+        let archive = self.fetch(&url).await?;
+        let repository = repository.to_string();
+        let index: Arc<[SearchResult]> = tokio::task::spawn_blocking(move || {
+            let content = read_index_archive(&archive)?;
+            Ok::<_, std::io::Error>(
+                parse_apkindex(&content)
+                    .into_iter()
+                    .map(|package| SearchResult {
+                        repository: Some(repository.clone()),
+                        ..package
+                    })
+                    .collect(),
+            )
+        })
+        .await
+        .map_err(|err| IndexError::Invalid(format!("{url}: {err}")))?
+        .map_err(|err| IndexError::Invalid(format!("{url}: {err}")))?;
+
+        self.lock_indexes()
+            .insert(url, (Instant::now(), index.clone()));
+        Ok(index)
+    }
+
+    /// Download the archive at `url`, or read it when `url` is a local path
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, IndexError> {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        if !path.contains("://") {
+            return tokio::fs::read(path)
+                .await
+                .map_err(|err| IndexError::Invalid(format!("{path}: {err}")));
+        }
+
+        let unreachable = |err: reqwest::Error| IndexError::Unreachable(format!("{url}: {err}"));
+        let mut request = self.client.get(url).timeout(FETCH_TIMEOUT);
+        if let Some((username, password)) = self
+            .credentials
+            .as_ref()
+            .and_then(|credentials| credentials.basic_auth(url))
+        {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send().await.map_err(unreachable)?;
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(IndexError::Unreachable(format!("{url}: {status}")));
+        }
+        if !status.is_success() {
+            return Err(IndexError::Invalid(format!("{url}: {status}")));
+        }
+        Ok(response.bytes().await.map_err(unreachable)?.to_vec())
+    }
+
+    /// Packages of the repository at `source`, trying every mirror in turn
+    /// for mirrored repositories
+    pub(crate) async fn load(
+        &self,
+        mirrors: &MirrorList,
+        source: &IndexSource,
+        arch: &str,
+    ) -> Result<Arc<[SearchResult]>, IndexError> {
+        let path = match source {
+            IndexSource::Direct(repository) => return self.index(repository, arch).await,
+            IndexSource::Mirrored(path) => path,
+        };
+
+        let mut last = None;
+        for mirror in mirrors.candidates() {
+            match self.index(&format!("{mirror}/{path}"), arch).await {
+                Ok(index) => {
+                    mirrors.mark_healthy(&mirror);
+                    return Ok(index);
+                }
+                Err(IndexError::Unreachable(err)) => {
+                    tracing::warn!("Alpine mirror {mirror} is unreachable, trying the next mirror");
+                    mirrors.mark_unhealthy(&mirror);
+                    last = Some(IndexError::Unreachable(err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last.unwrap_or_else(|| {
+            IndexError::Unreachable("no Alpine mirrors are configured".to_string())
+        }))
+    }
+
+    fn lock_indexes(&self) -> std::sync::MutexGuard<'_, HashMap<String, CachedIndex>> {
+        self.indexes.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Extract the `APKINDEX` file from an `APKINDEX.tar.gz` archive
+///
+/// Signed indexes are a gzip stream with the signature followed by another
+/// with the index, so every stream is decompressed.
+fn read_index_archive(archive: &[u8]) -> std::io::Result<String> {
+    let mut entries = tar::Archive::new(MultiGzDecoder::new(archive));
+    for entry in entries.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_os_str() == INDEX_FILE {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            return Ok(content);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "the archive has no APKINDEX file",
+    ))
+}
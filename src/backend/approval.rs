@@ -0,0 +1,137 @@
+//! External approval of mutating operations
+//!
+//! Change-management systems want a say before anything is installed on a
+//! machine they track. With an [`ApprovalWebhook`], the handler `POST`s every
+//! mutating operation to a URL before running it and only proceeds when the
+//! response approves it:
+//!
+//! ```json
+//! {"decision": "approve"}
+//! {"decision": "deny", "reason": "outside the change window"}
+//! ```
+//!
+//! A webhook that does not answer in time, answers with an error status or
+//! with anything else fails closed by default, rejecting the operation;
+//! [`ApprovalWebhook::fail_open`] lets operations run instead.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::PackageManagerError;
+use crate::identity::ClientIdentity;
+use crate::operation::Operation;
+
+/// How long the webhook may take to decide by default
+pub const DEFAULT_APPROVAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Webhook approving or denying mutating operations
+#[derive(Clone, Debug)]
+pub struct ApprovalWebhook {
+    url: String,
+    timeout: Duration,
+    fail_open: bool,
+    client: reqwest::Client,
+}
+
+/// Details of the operation sent to the webhook
+#[derive(Debug, Serialize)]
+pub struct ApprovalRequest<'a> {
+    pub tool: &'static str,
+    pub operation: &'a Operation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<&'a str>,
+    /// Client requesting the operation, when identified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<&'a ClientIdentity>,
+    pub requested_at: DateTime<Utc>,
+}
+
+/// Decision returned by the webhook
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Approve,
+    Deny,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApprovalResponse {
+    decision: Decision,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+impl ApprovalWebhook {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            timeout: DEFAULT_APPROVAL_TIMEOUT,
+            fail_open: false,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Wait at most `timeout` for a decision
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run operations when the webhook cannot decide, instead of rejecting
+    /// them
+    pub fn fail_open(mut self) -> Self {
+        self.fail_open = true;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Ask the webhook whether `request` may run
+    pub async fn approve(&self, request: &ApprovalRequest<'_>) -> Result<(), PackageManagerError> {
+        let tool = request.tool;
+        match self.decide(request).await {
+            Ok(ApprovalResponse {
+                decision: Decision::Approve,
+                ..
+            }) => Ok(()),
+            Ok(ApprovalResponse {
+                decision: Decision::Deny,
+                reason,
+            }) => Err(PackageManagerError::PermissionDenied {
+                message: match reason {
+                    Some(reason) => format!("the approval webhook denied {tool}: {reason}"),
+                    None => format!("the approval webhook denied {tool}"),
+                },
+            }),
+            Err(err) if self.fail_open => {
+                tracing::warn!(
+                    "Approval webhook {} failed, running {tool} anyway: {err}",
+                    self.url
+                );
+                Ok(())
+            }
+            Err(err) => Err(PackageManagerError::PermissionDenied {
+                message: format!("the approval webhook could not approve {tool}: {err}"),
+            }),
+        }
+    }
+
+    async fn decide(
+        &self,
+        request: &ApprovalRequest<'_>,
+    ) -> Result<ApprovalResponse, reqwest::Error> {
+        self.client
+            .post(&self.url)
+            .timeout(self.timeout)
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
@@ -1,14 +1,682 @@
-use rmcp::ErrorData as McpError;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-use super::{ExecResult, InstallOptions, InstallVersionOptions, PackageManager, SearchOptions};
+use async_trait::async_trait;
+use tokio::sync::OnceCell;
+
+use crate::ansible::{AnsibleTask, TaskArgument};
+use crate::cloudinit::{CloudConfig, host_name};
+use crate::error::PackageManagerError;
+use crate::version::{VersionConstraint, VersionScheme};
+
+use super::credentials::{RedactingRunner, RepositoryCredentials};
+use super::doctor::{self, HealthCheck};
+use super::drift;
+use super::exit_status::{self, ExitMeaning};
+use super::freshness;
+use super::local::{index_deb_directory, local_directory};
+use super::offline::OfflineMirrors;
+use super::parse::apt::{
+    parse_copyright_licenses, parse_deb822_sources, parse_fetched_bytes, parse_install_output,
+    parse_installed_list, parse_madison, parse_osv_ecosystem, parse_removed_packages,
+    parse_required_space, parse_search, parse_show_checksums, parse_source_packages,
+    parse_sources_list,
+};
+use super::parse::{parse_os_release_name, parse_version_line, versions_of};
+use super::pins;
+use super::private_dir::PrivateDir;
+use super::privileges::{self, PrivilegeReport};
+use super::runner::{Command, CommandRunner, LocalRunner, shell_quote};
+use super::signing::UnsignedRepository;
+use super::snapshot::SnapshotArchive;
+use super::validation::{validate_package_name, validate_version};
+use super::{
+    Capabilities, ExecResult, FetchedPackage, InstallOptions, InstallOutcome,
+    InstallVersionOptions, InstalledPackage, LockedPackage, PackageLicense, PackageManager,
+    PinnedPackage, Recommends, Repository, ResolvedVersion, SearchMode, SearchOptions,
+    SearchResult, SpaceRequirement, SystemInfo, command, lock_packages_with, resolve_among,
+    validate_arch, validate_root,
+};
+
+/// Main one-line style sources file
+const SOURCES_LIST: &str = "/etc/apt/sources.list";
+
+/// Directory of additional `.list` and deb822 `.sources` files
+const SOURCES_PARTS: &str = "/etc/apt/sources.list.d";
+
+/// Package documentation, holding each package's `copyright` file
+const DOC_DIR: &str = "/usr/share/doc";
+
+/// dpkg database, locked and written by installs
+const DATABASE_DIR: &str = "/var/lib/dpkg";
+
+/// Lock APT front ends take while changing the package database
+const LOCK_FILE: &str = "/var/lib/dpkg/lock-frontend";
+
+/// Directory downloaded packages are kept in
+const CACHE_DIR: &str = "/var/cache/apt/archives";
+
+/// Directory `apt-get update` writes the package indexes to
+const LISTS_DIR: &str = "/var/lib/apt/lists";
+
+/// dpkg's record of the installed packages
+const STATUS_FILE: &str = "/var/lib/dpkg/status";
+
+/// Operating system identification, holding the release codename
+const OS_RELEASE_FILE: &str = "/etc/os-release";
+
+/// Snapshot Release files are long expired, APT must accept them anyway
+const IGNORE_VALID_UNTIL: &str = "Acquire::Check-Valid-Until=false";
+
+/// Most packages looked up by one `apt-cache madison`, keeping broad
+/// searches within the command line length limit
+const MADISON_BATCH: usize = 256;
+
+/// Counter keeping generated source file names unique within the process
+static NEXT_SOURCE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Entries `apt-cache madison` reported, by managed root and package
+type MadisonCache = HashMap<(Option<String>, String), Vec<SearchResult>>;
 
 /// Debian/Debian-derivative APT package manager backend
 #[derive(Clone)]
-pub struct Apt;
+pub struct Apt {
+    runner: Arc<dyn CommandRunner>,
+    /// Root filesystem managed instead of `/`
+    root: Option<String>,
+    /// Archive historical versions are installed from
+    snapshot: Option<Arc<SnapshotArchive>>,
+    /// Available versions of the packages looked up since the last refresh,
+    /// shared with the backends created by `with_root`
+    madison: Arc<Mutex<MadisonCache>>,
+    /// Credentials of private repositories, written to [`Apt::auth_file`]
+    /// before the commands reading them
+    credentials: Option<Arc<RepositoryCredentials>>,
+    /// Private directory of the files APT reads: the credentials and the
+    /// generated sources
+    private_dir: Arc<PrivateDir>,
+    /// Sources read instead of the system's in offline mode
+    offline: Option<Arc<OfflineSources>>,
+    /// Weaker dependencies installed when the call does not choose
+    install_recommends: Option<Recommends>,
+}
+
+/// Sources file of offline mode: the system's sources moved onto the
+/// internal mirrors, written on first use
+struct OfflineSources {
+    mirrors: Arc<OfflineMirrors>,
+    path: String,
+    written: OnceCell<()>,
+}
+
+impl OfflineSources {
+    /// Sources written in `dir`
+    fn new(mirrors: Arc<OfflineMirrors>, dir: &PrivateDir) -> Self {
+        Self {
+            mirrors,
+            path: dir.file(&format!(
+                "offline-{}.sources",
+                NEXT_SOURCE_ID.fetch_add(1, Ordering::Relaxed)
+            )),
+            written: OnceCell::new(),
+        }
+    }
+}
 
 impl Apt {
     pub fn new() -> Self {
-        Self
+        Self::with_runner(Arc::new(LocalRunner))
+    }
+
+    /// Run APT through the given runner, e.g. in a chroot or container
+    pub fn with_runner(runner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            runner,
+            root: None,
+            snapshot: None,
+            madison: Arc::default(),
+            credentials: None,
+            private_dir: Arc::new(PrivateDir::new("apt")),
+            offline: None,
+            install_recommends: None,
+        }
+    }
+
+    /// Install versions missing from the configured suites from the snapshot
+    /// archive
+    ///
+    /// When `install_package_with_version` finds no matching version with
+    /// `apt-cache madison`, the archive is asked for every version of the
+    /// package. The newest matching one is installed from the archive as it
+    /// was when the version was first seen, through a temporary source for
+    /// the system's suite.
+    pub fn with_snapshot_archive(mut self, archive: SnapshotArchive) -> Self {
+        self.snapshot = Some(Arc::new(archive));
+        self
+    }
+
+    /// Authenticate to private repositories with `credentials`
+    ///
+    /// The credentials are written to an `auth.conf` file readable by the
+    /// server's user only, which APT reads besides `auth.conf.d`
+    /// (`-o Dir::Etc::netrc=`). Secrets are redacted from command output.
+    pub fn with_credentials(mut self, credentials: RepositoryCredentials) -> Self {
+        let credentials = Arc::new(credentials);
+        self.runner = Arc::new(RedactingRunner::new(self.runner, credentials.clone()));
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Install the weaker dependencies of `recommends` unless the call
+    /// chooses, instead of following APT's configuration
+    pub fn with_install_recommends(mut self, recommends: Recommends) -> Self {
+        self.install_recommends = Some(recommends);
+        self
+    }
+
+    /// Only reach the internal mirrors of `offline`
+    ///
+    /// APT commands read the system's enabled sources moved onto the
+    /// internal mirrors (`-o Dir::Etc::sourcelist=`) instead of
+    /// `sources.list` and `sources.list.d`; sources without a mirror are left
+    /// out. The file is written on first use and again on every refresh.
+    pub fn with_offline_mirrors(mut self, offline: OfflineMirrors) -> Self {
+        self.offline = Some(Arc::new(OfflineSources::new(
+            Arc::new(offline),
+            &self.private_dir,
+        )));
+        self
+    }
+
+    /// Manage the packages of the root filesystem at `root` instead of `/`
+    ///
+    /// APT reads its configuration, sources and state below `root`
+    /// (`-o Dir=`) and dpkg runs chrooted into it
+    /// (`-o DPkg::Chroot-Directory=`).
+    pub fn at_root(mut self, root: impl Into<String>) -> Self {
+        self.root = Some(root.into());
+        // The root has sources of its own
+        self.offline = self.offline.map(|offline| {
+            Arc::new(OfflineSources::new(
+                offline.mirrors.clone(),
+                &self.private_dir,
+            ))
+        });
+        self
+    }
+
+    /// Start an APT command targeting the managed root
+    ///
+    /// Progress indicators and colors are turned off, so the output only
+    /// holds the lines the parsers read.
+    fn apt(&self, program: &str) -> Command {
+        let mut command = command(program);
+        command.args(["-q", "-o", "APT::Color=0"]);
+        if let Some(root) = &self.root {
+            command.arg("-o");
+            command.arg(format!("Dir={root}"));
+            command.arg("-o");
+            command.arg(format!("DPkg::Chroot-Directory={root}"));
+        }
+        if self.credentials.is_some() {
+            command.arg("-o");
+            command.arg(format!("Dir::Etc::netrc={}", self.auth_file()));
+        }
+        if let Some(offline) = &self.offline {
+            command.arg("-o");
+            command.arg(format!("Dir::Etc::sourcelist={}", offline.path));
+            command.arg("-o");
+            command.arg("Dir::Etc::sourceparts=-");
+        }
+        command
+    }
+
+    /// Start a noninteractive `apt-get install`, adding the weaker
+    /// dependencies of `recommends` or else of the backend's default, and
+    /// preferring the packages of `target_release` when given
+    fn install_command(
+        &self,
+        recommends: Option<Recommends>,
+        target_release: Option<&str>,
+    ) -> Command {
+        let mut command = self.apt("apt-get");
+        command.privileged();
+        command.env("DEBIAN_FRONTEND", "noninteractive");
+        command.arg("install");
+        command.arg("-y");
+        command.args(recommends_args(recommends.or(self.install_recommends)));
+        if let Some(release) = target_release {
+            command.arg("-t");
+            command.arg(release);
+        }
+        command
+    }
+
+    /// Write the files APT commands read: the repository credentials and, in
+    /// offline mode, the sources
+    async fn prepare(&self) -> Result<(), PackageManagerError> {
+        self.write_auth_file().await?;
+        if let Some(offline) = &self.offline {
+            offline
+                .written
+                .get_or_try_init(|| self.write_offline_sources(offline))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The system's enabled sources, moved onto the internal mirrors
+    async fn offline_repositories(
+        &self,
+        mirrors: &OfflineMirrors,
+    ) -> Result<Vec<Repository>, PackageManagerError> {
+        let mut repositories = Vec::new();
+        for repository in self.list_repositories().await? {
+            if !repository.enabled {
+                continue;
+            }
+            match mirrors.rewrite(&repository.url) {
+                Some(url) => repositories.push(Repository {
+                    url,
+                    file: None,
+                    ..repository
+                }),
+                None => tracing::warn!(
+                    "Skipping repository {} without an internal mirror",
+                    repository.url
+                ),
+            }
+        }
+        Ok(repositories)
+    }
+
+    async fn write_offline_sources(
+        &self,
+        offline: &OfflineSources,
+    ) -> Result<(), PackageManagerError> {
+        self.private_dir.create(&self.runner).await?;
+        let content = self
+            .offline_repositories(&offline.mirrors)
+            .await?
+            .iter()
+            .map(deb822_stanza)
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.runner
+            .write_file(&offline.path, &content)
+            .await
+            .map_err(|err| {
+                PackageManagerError::io("there was an error writing the offline APT sources", err)
+            })
+    }
+
+    /// Path of the `auth.conf` file holding the repository credentials, in
+    /// the backend's private directory
+    ///
+    /// The path is absolute, so APT does not resolve it below an alternate
+    /// root's `Dir`.
+    fn auth_file(&self) -> String {
+        self.private_dir.file("auth.conf")
+    }
+
+    /// Write the repository credentials for APT
+    ///
+    /// The file is written again before every command set reading it, so it
+    /// is restored if it was removed. The secrets are passed on standard
+    /// input, never as arguments, and the file is created without
+    /// permissions for other users in a directory only the server's user
+    /// can write to.
+    async fn write_auth_file(&self) -> Result<(), PackageManagerError> {
+        let Some(credentials) = &self.credentials else {
+            return Ok(());
+        };
+        self.private_dir.create(&self.runner).await?;
+        let path = self.auth_file();
+        let mut command = command("sh");
+        command
+            .args(["-c", "umask 077 && cat > \"$1\"", "sh", path.as_str()])
+            .stdin(credentials.apt_auth_conf());
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io("there was an error writing the repository credentials", err)
+        })?;
+        output.success().map(|_| ())
+    }
+
+    /// Path of a system file in the managed root
+    fn system_file(&self, path: &str) -> String {
+        match &self.root {
+            Some(root) => format!("{}{path}", root.trim_end_matches('/')),
+            None => path.to_string(),
+        }
+    }
+
+    /// Keyring files named by a `Signed-By` value that do not exist
+    ///
+    /// The value is an inline key, or keyring paths and key fingerprints
+    /// separated by commas or spaces; fingerprints refer to the keyrings
+    /// trusted by APT and are not checked.
+    async fn missing_keyrings(&self, signed_by: &str) -> Result<Vec<String>, PackageManagerError> {
+        if signed_by.contains("BEGIN PGP PUBLIC KEY BLOCK") {
+            return Ok(Vec::new());
+        }
+        let mut missing = Vec::new();
+        for keyring in signed_by
+            .split([',', ' '])
+            .filter(|keyring| keyring.starts_with('/'))
+        {
+            let path = self.system_file(keyring);
+            let (dir, name) = path.rsplit_once('/').unwrap_or_default();
+            let entries = self.runner.list_dir(dir).await.map_err(|err| {
+                PackageManagerError::io(format!("there was an error reading {dir}"), err)
+            })?;
+            if !entries.iter().any(|entry| entry == name) {
+                missing.push(keyring.to_string());
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Enabled repositories of a custom source given as a one-line entry, a
+    /// deb822 stanza or the path of a `.list` or `.sources` file
+    ///
+    /// Local directories, which only exist on the managed system, have none.
+    async fn source_definition(
+        &self,
+        definition: &str,
+    ) -> Result<Vec<Repository>, PackageManagerError> {
+        let definition = definition.trim();
+        let repositories = if definition.starts_with("deb ") || definition.starts_with("deb-src ") {
+            parse_sources_list(definition)
+        } else if definition.lines().any(|line| {
+            line.split_once(':')
+                .is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case("uris"))
+        }) {
+            parse_deb822_sources(definition)
+        } else if definition.ends_with(".list") || definition.ends_with(".sources") {
+            let file = self.system_file(definition);
+            let content = self
+                .runner
+                .read_file(&file)
+                .await
+                .map_err(|err| {
+                    PackageManagerError::io(format!("there was an error reading {file}"), err)
+                })?
+                .unwrap_or_default();
+            if definition.ends_with(".list") {
+                parse_sources_list(&content)
+            } else {
+                parse_deb822_sources(&content)
+            }
+        } else {
+            Vec::new()
+        };
+        Ok(repositories
+            .into_iter()
+            .filter(|repository| repository.enabled && !repository.url.starts_with("file:"))
+            .collect())
+    }
+
+    /// ASCII-armored key of a `Signed-By` value: the inline key, or the
+    /// first keyring file holding an armored key
+    ///
+    /// Binary keyrings cannot be exported as text and give none.
+    async fn armored_key(&self, signed_by: &str) -> Option<String> {
+        if signed_by.contains("BEGIN PGP PUBLIC KEY BLOCK") {
+            return Some(signed_by.to_string());
+        }
+        for keyring in signed_by
+            .split([',', ' '])
+            .filter(|keyring| keyring.starts_with('/'))
+        {
+            let content = self
+                .runner
+                .read_file(&self.system_file(keyring))
+                .await
+                .ok()
+                .flatten();
+            if let Some(key) = content.filter(|key| key.contains("BEGIN PGP PUBLIC KEY BLOCK")) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    /// Codename of the managed system's release, e.g. `bookworm`
+    async fn release_codename(&self) -> Option<String> {
+        let file = self.system_file(OS_RELEASE_FILE);
+        let content = self.runner.read_file(&file).await.ok().flatten()?;
+        content.lines().find_map(|line| {
+            let codename = line.strip_prefix("VERSION_CODENAME=")?;
+            let codename = codename.trim().trim_matches('"');
+            (!codename.is_empty()).then(|| codename.to_string())
+        })
+    }
+
+    /// Architecture packages are installed for by default
+    async fn native_arch(&self) -> Option<String> {
+        let mut dpkg = command("dpkg");
+        if let Some(root) = &self.root {
+            dpkg.arg(format!("--root={root}"));
+        }
+        let output = self
+            .runner
+            .run(dpkg.arg("--print-architecture"))
+            .await
+            .ok()?;
+        let arch = output.stdout?.trim().to_string();
+        (output.status == 0 && !arch.is_empty()).then_some(arch)
+    }
+
+    /// Versions of `package` in the configured suites, those of
+    /// `target_release` when given
+    async fn available_versions(
+        &self,
+        package: &str,
+        name: &str,
+        target_release: Option<&str>,
+    ) -> Result<Vec<String>, PackageManagerError> {
+        let entries = in_release(self.madison(package, name).await?, target_release)?;
+        Ok(versions_of(&entries, name))
+    }
+
+    /// Versions of `package` in the configured suites, with the archive of
+    /// each as its repository
+    ///
+    /// `apt-cache madison` only runs the first time a package is looked up;
+    /// the entries are reused until the repositories are refreshed. Failed
+    /// lookups are not cached and report no entries.
+    async fn madison(
+        &self,
+        package: &str,
+        name: &str,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        let key = (self.root.clone(), package.to_string());
+        if let Some(entries) = self.lock_madison().get(&key) {
+            return Ok(entries.clone());
+        }
+
+        self.prepare().await?;
+        let output = self
+            .runner
+            .run(self.apt("apt-cache").arg("madison").arg(package))
+            .await
+            .map_err(|err| {
+                PackageManagerError::io(
+                    format!("there was an error checking versions for package {name}"),
+                    err,
+                )
+            })?;
+        if output.status != 0 {
+            return Ok(Vec::new());
+        }
+
+        let entries: Vec<SearchResult> =
+            parse_madison(output.stdout.as_deref().unwrap_or_default())
+                .into_iter()
+                .filter(|entry| entry.name == name)
+                .collect();
+        self.lock_madison().insert(key, entries.clone());
+        Ok(entries)
+    }
+
+    /// Versions of each of `packages`, like [`Self::madison`], with one
+    /// `apt-cache madison` per [`MADISON_BATCH`] packages not looked up yet
+    async fn madison_all(
+        &self,
+        packages: &[&str],
+    ) -> Result<HashMap<String, Vec<SearchResult>>, PackageManagerError> {
+        let mut found = HashMap::new();
+        let mut missing = Vec::new();
+        {
+            let cache = self.lock_madison();
+            for package in packages {
+                match cache.get(&(self.root.clone(), package.to_string())) {
+                    Some(entries) => {
+                        found.insert(package.to_string(), entries.clone());
+                    }
+                    None => missing.push(*package),
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(found);
+        }
+
+        self.prepare().await?;
+        for batch in missing.chunks(MADISON_BATCH) {
+            let output = self
+                .runner
+                .run(self.apt("apt-cache").arg("madison").args(batch))
+                .await
+                .map_err(|err| {
+                    PackageManagerError::io("there was an error checking package versions", err)
+                })?;
+            if output.status != 0 {
+                continue;
+            }
+
+            let mut entries: HashMap<String, Vec<SearchResult>> = batch
+                .iter()
+                .map(|package| (package.to_string(), Vec::new()))
+                .collect();
+            for entry in parse_madison(output.stdout.as_deref().unwrap_or_default()) {
+                if let Some(versions) = entries.get_mut(&entry.name) {
+                    versions.push(entry);
+                }
+            }
+            let mut cache = self.lock_madison();
+            for (package, versions) in entries {
+                cache.insert((self.root.clone(), package.clone()), versions.clone());
+                found.insert(package, versions);
+            }
+        }
+        Ok(found)
+    }
+
+    fn lock_madison(&self) -> std::sync::MutexGuard<'_, MadisonCache> {
+        self.madison.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Install the newest version matching `constraint` from the snapshot
+    /// archive
+    ///
+    /// Returns `None` when the archive does not have a matching version or
+    /// cannot be queried, so the caller reports the versions of the suites.
+    async fn install_from_snapshot(
+        &self,
+        snapshot: &SnapshotArchive,
+        options: &InstallVersionOptions,
+        constraint: &VersionConstraint,
+    ) -> Result<Option<InstallOutcome>, PackageManagerError> {
+        let versions = match snapshot.versions(&options.package).await {
+            Ok(versions) => versions,
+            Err(err) => {
+                tracing::warn!(
+                    "Could not list the archived versions of {}: {err}",
+                    options.package
+                );
+                return Ok(None);
+            }
+        };
+        let Some(version) = VersionScheme::Deb
+            .latest_matching(versions.iter().map(String::as_str), constraint)
+            .map(str::to_string)
+        else {
+            return Ok(None);
+        };
+
+        let arch = match &options.arch {
+            Some(arch) => Some(arch.clone()),
+            None => self.native_arch().await,
+        };
+        let location = match snapshot
+            .locate(&options.package, &version, arch.as_deref())
+            .await
+        {
+            Ok(Some(location)) => location,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                tracing::warn!(
+                    "Could not locate {}={version} in the snapshot archive: {err}",
+                    options.package
+                );
+                return Ok(None);
+            }
+        };
+
+        let codename = match snapshot.suite() {
+            Some(suite) => suite.to_string(),
+            None => self.release_codename().await.ok_or_else(|| {
+                PackageManagerError::io(
+                    format!(
+                        "there was an error installing package {}={version} from the snapshot archive",
+                        options.package
+                    ),
+                    std::io::Error::other(
+                        "the release codename is unknown, configure the snapshot suite",
+                    ),
+                )
+            })?,
+        };
+        // Security updates are published in their own suite
+        let suite = match location.archive.as_str() {
+            "debian-security" => format!("{codename}-security"),
+            _ => codename,
+        };
+
+        let source = CustomSource::prepare(self, &snapshot.source_entry(&location, &suite)).await?;
+        source.update(&[IGNORE_VALID_UNTIL]).await?;
+
+        let package = with_arch(&options.package, options.arch.as_deref());
+        let mut command = self.install_command(options.install_recommends, None);
+        command.args(["-o", IGNORE_VALID_UNTIL, "-o"]);
+        command.arg(source.sourcelist_option());
+        command.arg(format!("{package}={version}"));
+
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!(
+                    "there was an error installing package {}={version}",
+                    options.package
+                ),
+                err,
+            )
+        })?;
+
+        let exec_result = output.success()?;
+        Ok(Some(install_outcome(&options.package, exec_result)))
+    }
+}
+
+/// `apt-get install` options installing the weaker dependencies of
+/// `recommends`, none to follow APT's configuration
+fn recommends_args(recommends: Option<Recommends>) -> &'static [&'static str] {
+    match recommends {
+        Some(Recommends::Skip) => &["--no-install-recommends"],
+        Some(Recommends::Install) => &["--install-recommends"],
+        Some(Recommends::WithSuggests) => &["--install-recommends", "--install-suggests"],
+        None => &[],
     }
 }
 
@@ -18,6 +686,7 @@ impl Default for Apt {
     }
 }
 
+#[async_trait]
 impl PackageManager for Apt {
     fn name(&self) -> &'static str {
         "APT"
@@ -27,250 +696,1425 @@ impl PackageManager for Apt {
         "Debian/Debian-derivative"
     }
 
-    fn install_package(&self, options: &InstallOptions) -> Result<ExecResult, McpError> {
-        let mut command = std::process::Command::new("apt-get");
-        command.env("DEBIAN_FRONTEND", "noninteractive");
-        command.arg("install");
-        command.arg("-y");
-
-        if let Some(repository) = &options.repository {
-            command.arg("-o");
-            command.arg(format!("Dir::Etc::sourcelist={repository}"));
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            alternate_root: true,
+            privilege_check: true,
+            install_commands: true,
+            batch_install: true,
+            vulnerability_check: true,
+            license_report: true,
+            lockfile: true,
+            locked_install: true,
+            batch_version_install: true,
+            signature_check: true,
+            artifact_verification: true,
+            cloud_init: true,
+            ansible_tasks: true,
+            drift_detection: true,
+            disk_space_check: true,
+            debconf_preseed: true,
+            recommends_selection: true,
+            target_release_selection: true,
+            repository_override: true,
+            multiple_repositories: true,
+            ..Capabilities::default()
         }
+    }
+
+    /// `apt-get --version`, the lock and the free space of the root and
+    /// package cache file systems
+    async fn health_checks(&self) -> Result<Vec<HealthCheck>, PackageManagerError> {
+        let runner = &*self.runner;
+        let mut checks = vec![
+            doctor::check_program(runner, self.apt("apt-get").arg("--version"), "apt").await,
+            doctor::check_fcntl_lock(runner, &self.system_file(LOCK_FILE)).await,
+        ];
+        let paths = [self.system_file("/"), self.system_file(CACHE_DIR)];
+        checks.extend(doctor::check_disk_space(runner, &paths).await);
+        Ok(checks)
+    }
 
-        command.arg(&options.package);
+    /// Feed the selections to `debconf-set-selections`, chrooted into the
+    /// managed root if any
+    async fn preseed(&self, selections: &[String]) -> Result<(), PackageManagerError> {
+        let mut command = match &self.root {
+            Some(root) => {
+                let mut command = command("chroot");
+                command.args([root.as_str(), "debconf-set-selections"]);
+                command
+            }
+            None => command("debconf-set-selections"),
+        };
+        command
+            .privileged()
+            .stdin(format!("{}\n", selections.join("\n")));
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io("there was an error setting debconf selections", err)
+        })?;
+        output.success().map(|_| ())
+    }
 
-        let output = command.output().map_err(|err| {
-            McpError::internal_error(
+    /// Modification time of the package lists directory
+    async fn indexes_refreshed_at(
+        &self,
+    ) -> Result<Option<std::time::SystemTime>, PackageManagerError> {
+        Ok(freshness::modified_at(&*self.runner, &self.system_file(LISTS_DIR)).await)
+    }
+
+    fn exit_meaning(&self, status: i32, output: &str) -> Option<ExitMeaning> {
+        exit_status::apt(status, output)
+    }
+
+    /// Sizes `apt-get install --assume-no` reports, with the weaker
+    /// dependencies the install selects: the archives it would download go
+    /// to the package cache, the unpacked packages to the root file system
+    async fn required_space(
+        &self,
+        packages: &[String],
+        arch: Option<&str>,
+        recommends: Option<Recommends>,
+    ) -> Result<Vec<SpaceRequirement>, PackageManagerError> {
+        validate_arch(arch)?;
+        self.prepare().await?;
+
+        let mut command = self.apt("apt-get");
+        command.privileged();
+        command.env("DEBIAN_FRONTEND", "noninteractive");
+        command.args(["install", "--assume-no"]);
+        command.args(recommends_args(recommends.or(self.install_recommends)));
+        for package in packages {
+            command.arg(match package.split_once('=') {
+                Some((name, version)) => format!("{}={version}", with_arch(name, arch)),
+                None => with_arch(package, arch),
+            });
+        }
+
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
                 format!(
-                    "there was an error installing package {}: {}",
-                    &options.package, err
+                    "there was an error simulating the install of {}",
+                    packages.join(" ")
                 ),
-                None,
+                err,
             )
         })?;
+        // Answering no to the confirmation prompt exits with status 1
+        if output.status > 1 {
+            return Err(PackageManagerError::CommandFailed(output));
+        }
+        let (download, installed) =
+            parse_required_space(output.stdout.as_deref().unwrap_or_default());
+        let needs = [
+            (self.system_file(CACHE_DIR), download),
+            (self.system_file("/"), installed),
+        ];
+        doctor::space_requirements(&*self.runner, &needs)
+            .await
+            .map_err(|err| PackageManagerError::io("failed to read the free disk space", err))
+    }
 
-        Ok(ExecResult {
-            stdout: if !output.stdout.is_empty() {
-                Some(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                None
-            },
-            stderr: if !output.stderr.is_empty() {
-                Some(String::from_utf8_lossy(&output.stderr).to_string())
-            } else {
-                None
-            },
-            status: output.status.code().unwrap_or(-1),
+    /// `apt-get --version` and the `PRETTY_NAME` of `/etc/os-release`
+    async fn system_info(&self) -> Result<SystemInfo, PackageManagerError> {
+        let version = self
+            .runner
+            .run(self.apt("apt-get").arg("--version"))
+            .await
+            .ok();
+        let os_release = self
+            .runner
+            .read_file(&self.system_file(OS_RELEASE_FILE))
+            .await
+            .ok()
+            .flatten();
+        Ok(SystemInfo {
+            package_manager_version: version
+                .and_then(|output| output.success().ok())
+                .and_then(|result| parse_version_line(result.stdout.as_deref()?)),
+            distro_release: os_release.as_deref().and_then(parse_os_release_name),
         })
     }
 
-    fn install_package_with_version(
+    /// `Debian:<version>` or `Ubuntu:<version>`, from `/etc/os-release`
+    async fn osv_ecosystem(&self) -> Result<String, PackageManagerError> {
+        let file = self.system_file(OS_RELEASE_FILE);
+        self.runner
+            .read_file(&file)
+            .await
+            .map_err(|err| PackageManagerError::io(format!("failed to read {file}"), err))?
+            .as_deref()
+            .and_then(parse_osv_ecosystem)
+            .ok_or(PackageManagerError::Unsupported {
+                operation: "check_vulnerabilities",
+            })
+    }
+
+    /// The `License:` paragraphs of the packages' machine-readable
+    /// `/usr/share/doc/<package>/copyright` files
+    async fn package_licenses(&self) -> Result<Vec<PackageLicense>, PackageManagerError> {
+        let installed = self.list_installed_packages().await?;
+        let output = self
+            .runner
+            .run(
+                command("grep")
+                    .arg("-R")
+                    .arg("-H")
+                    .arg("--include=copyright")
+                    .arg("^License:")
+                    .arg(self.system_file(DOC_DIR)),
+            )
+            .await
+            .map_err(|err| {
+                PackageManagerError::io("there was an error reading copyright files", err)
+            })?;
+        // grep exits with 1 when nothing matched, and with 2 when some file
+        // could not be read even though others matched
+        if output.status > 1 && output.stdout.as_deref().is_none_or(str::is_empty) {
+            return Err(PackageManagerError::CommandFailed(output));
+        }
+
+        let mut licenses = parse_copyright_licenses(output.stdout.as_deref().unwrap_or_default());
+        Ok(installed
+            .into_iter()
+            .map(|package| PackageLicense {
+                licenses: licenses.remove(&package.name).unwrap_or_default(),
+                name: package.name,
+                version: package.version,
+            })
+            .collect())
+    }
+
+    async fn source_packages(&self) -> Result<HashMap<String, String>, PackageManagerError> {
+        let mut dpkg_query = command("dpkg-query");
+        if let Some(root) = &self.root {
+            dpkg_query.arg(format!("--root={root}"));
+        }
+        let output = self
+            .runner
+            .run(
+                dpkg_query
+                    .arg("-W")
+                    .arg("-f")
+                    .arg("${Package}\t${source:Package}\n"),
+            )
+            .await
+            .map_err(|err| {
+                PackageManagerError::io("there was an error listing source packages", err)
+            })?;
+
+        let exec_result = output.success()?;
+        Ok(parse_source_packages(
+            exec_result.stdout.as_deref().unwrap_or_default(),
+        ))
+    }
+
+    /// Custom sources are not recreated: they must be configured in the image
+    /// before these commands run
+    async fn install_commands(
         &self,
-        options: &InstallVersionOptions,
-    ) -> Result<ExecResult, McpError> {
-        // Validate inputs to prevent command injection
-        if !validate_package_version_input(&options.package) {
-            return Err(McpError::internal_error(
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        let pinned = pinned_names(packages);
+        Ok(vec![
+            "apt-get update".to_string(),
+            format!(
+                "DEBIAN_FRONTEND=noninteractive apt-get install -y {}",
+                pinned.join(" ")
+            ),
+        ])
+    }
+
+    /// The custom sources packages were installed from, with the
+    /// ASCII-armored key of their `Signed-By`
+    async fn cloud_config(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<CloudConfig, PackageManagerError> {
+        let mut config = CloudConfig::new(packages);
+        for definition in packages.iter().flat_map(PinnedPackage::custom_repositories) {
+            for repository in self.source_definition(definition).await? {
+                let key = match repository.signed_by.as_deref() {
+                    Some(signed_by) => self.armored_key(signed_by).await,
+                    None => None,
+                };
+                config = config.with_apt_source(&repository, key);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Tasks writing the armored keys and adding the custom sources, then
+    /// one `ansible.builtin.apt` task
+    ///
+    /// Keys are written where the source's `Signed-By` expects them, inline
+    /// keys to `/etc/apt/keyrings/<host>.asc`.
+    async fn ansible_tasks(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<AnsibleTask>, PackageManagerError> {
+        let mut tasks = Vec::new();
+        let mut sources = Vec::new();
+        for definition in packages.iter().flat_map(PinnedPackage::custom_repositories) {
+            for mut repository in self.source_definition(definition).await? {
+                let name = host_name(&repository.url);
+                if let Some(signed_by) = repository.signed_by.clone()
+                    && let Some(key) = self.armored_key(&signed_by).await
+                {
+                    let dest = if signed_by.contains("BEGIN PGP PUBLIC KEY BLOCK") {
+                        format!("/etc/apt/keyrings/{name}.asc")
+                    } else {
+                        signed_by
+                            .split([',', ' '])
+                            .find(|keyring| keyring.starts_with('/'))
+                            .unwrap_or_default()
+                            .to_string()
+                    };
+                    repository.signed_by = Some(dest.clone());
+                    tasks.push(
+                        AnsibleTask::new(format!("Install APT key {dest}"), "ansible.builtin.copy")
+                            .argument("dest", TaskArgument::Text(dest))
+                            .argument("content", TaskArgument::Block(key))
+                            .argument("mode", TaskArgument::Text("0644".to_string())),
+                    );
+                }
+                for line in repository.source_lines() {
+                    if !sources.contains(&line) {
+                        sources.push(line.clone());
+                        tasks.push(
+                            AnsibleTask::new(
+                                format!("Add APT source {}", repository.url),
+                                "ansible.builtin.apt_repository",
+                            )
+                            .argument("repo", TaskArgument::Text(line))
+                            .argument("filename", TaskArgument::Text(name.clone()))
+                            .argument("update_cache", TaskArgument::Flag(false)),
+                        );
+                    }
+                }
+            }
+        }
+
+        let names = packages
+            .iter()
+            .map(|package| {
                 format!(
-                    "Invalid package name '{}': only alphanumeric characters, dots, hyphens, underscores, plus signs, and colons are allowed",
-                    options.package
-                ),
-                Some(serde_json::json!({
-                    "package_name": options.package,
-                    "error_type": "validation_error"
-                })),
+                    "{}={}",
+                    with_arch(&package.name, package.arch.as_deref()),
+                    package.version
+                )
+            })
+            .collect();
+        tasks.push(
+            AnsibleTask::new("Install packages", "ansible.builtin.apt")
+                .argument("name", TaskArgument::List(names))
+                .argument("update_cache", TaskArgument::Flag(true)),
+        );
+        Ok(tasks)
+    }
+
+    /// One `RUN` refreshing the indexes, installing without recommended
+    /// packages and removing the indexes again, so the layer stays small
+    async fn dockerfile_instructions(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        Ok(vec![format!(
+            "RUN apt-get update \\\n    && DEBIAN_FRONTEND=noninteractive apt-get install -y --no-install-recommends \\\n        {} \\\n    && rm -rf /var/lib/apt/lists/*",
+            pinned_names(packages).join(" \\\n        ")
+        )])
+    }
+
+    fn version_scheme(&self) -> Option<VersionScheme> {
+        Some(VersionScheme::Deb)
+    }
+
+    /// Checksums from `apt-cache show`, sources from `apt-cache madison`
+    ///
+    /// Versions no longer in the configured suites only have their dpkg
+    /// status record, without a checksum or source.
+    async fn lock_packages(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<LockedPackage>, PackageManagerError> {
+        self.prepare().await?;
+        let lookup_error = |err| {
+            PackageManagerError::io(
+                "there was an error looking up the checksums of installed packages",
+                err,
+            )
+        };
+
+        let mut show = self.apt("apt-cache");
+        show.arg("show");
+        for package in packages {
+            let arch = package.arch.as_deref().filter(|arch| *arch != "all");
+            show.arg(format!(
+                "{}={}",
+                with_arch(&package.name, arch),
+                package.version
             ));
         }
+        let output = self.runner.run(&show).await.map_err(lookup_error)?;
+        // apt-cache exits with 100 when some version is unknown, after
+        // showing the others
+        if output.status != 0 && output.stdout.as_deref().is_none_or(str::is_empty) {
+            return Err(PackageManagerError::CommandFailed(output));
+        }
+        let checksums = parse_show_checksums(output.stdout.as_deref().unwrap_or_default());
 
-        if !validate_package_version_input(&options.version) {
-            return Err(McpError::internal_error(
-                format!(
-                    "Invalid version string '{}': only alphanumeric characters, dots, hyphens, underscores, plus signs, colons, and tildes are allowed",
-                    options.version
-                ),
-                Some(serde_json::json!({
-                    "version": options.version,
-                    "error_type": "validation_error"
-                })),
+        let mut madison = self.apt("apt-cache");
+        madison.arg("madison");
+        for package in packages {
+            madison.arg(&package.name);
+        }
+        let output = self.runner.run(&madison).await.map_err(lookup_error)?;
+        let sources = parse_madison(output.stdout.as_deref().unwrap_or_default());
+
+        Ok(lock_packages_with(packages, &checksums, &sources))
+    }
+
+    /// One `apt-get install` of every `name:arch=version`
+    ///
+    /// Custom repositories of the packages are not added; their sources must
+    /// already be configured.
+    async fn install_locked(
+        &self,
+        packages: &[LockedPackage],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        for package in packages {
+            validate_arch(package.package.arch.as_deref())?;
+        }
+        self.prepare().await?;
+
+        let mut command = self.install_command(None, None);
+        // Pinned versions may be older than the installed ones
+        command.arg("--allow-downgrades");
+        for package in packages {
+            let arch = package
+                .package
+                .arch
+                .as_deref()
+                .filter(|arch| *arch != "all");
+            command.arg(format!(
+                "{}={}",
+                with_arch(&package.package.name, arch),
+                package.package.version
             ));
         }
 
+        let names: Vec<String> = packages
+            .iter()
+            .map(|package| package.package.name.clone())
+            .collect();
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!("there was an error installing packages {}", names.join(" ")),
+                err,
+            )
+        })?;
+
+        let exec_result = output.success()?;
+        Ok(install_outcomes(&names, exec_result))
+    }
+
+    fn with_root(&self, root: &str) -> Result<Arc<dyn PackageManager>, PackageManagerError> {
+        validate_root(root)?;
+        Ok(Arc::new(self.clone().at_root(root)))
+    }
+
+    async fn install_package(
+        &self,
+        options: &InstallOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        validate_arch(options.arch.as_deref())?;
+        self.prepare().await?;
+
+        let mut command = self.install_command(
+            options.install_recommends,
+            options.target_release.as_deref(),
+        );
+
+        let added = options.added_repositories();
+        let source = match (added.as_slice(), options.only_repositories.as_slice()) {
+            ([], []) => None,
+            ([], repositories) => Some(CustomSource::replacing(self, repositories).await?),
+            (added, _) => Some(CustomSource::adding(self, added).await?),
+        };
+        if let Some(source) = &source {
+            source.update(&[]).await?;
+            command.args(source.options());
+        }
+
+        command.arg(with_arch(&options.package, options.arch.as_deref()));
+
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!("there was an error installing package {}", options.package),
+                err,
+            )
+        })?;
+
+        let exec_result = output.success()?;
+        Ok(install_outcome(&options.package, exec_result))
+    }
+
+    async fn install_packages(
+        &self,
+        packages: &[String],
+        arch: Option<&str>,
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        validate_arch(arch)?;
+        self.prepare().await?;
+
+        let mut command = self.install_command(None, None);
+        for package in packages {
+            command.arg(with_arch(package, arch));
+        }
+
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!(
+                    "there was an error installing packages {}",
+                    packages.join(" ")
+                ),
+                err,
+            )
+        })?;
+
+        let exec_result = output.success()?;
+        Ok(install_outcomes(packages, exec_result))
+    }
+
+    async fn install_package_with_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        // Validate inputs to prevent command injection
+        validate_package_name(&options.package, Some(VersionScheme::Deb))?;
+        validate_version(&options.version, Some(VersionScheme::Deb))?;
+
+        validate_arch(options.arch.as_deref())?;
+        self.prepare().await?;
+        let package = with_arch(&options.package, options.arch.as_deref());
+
+        let constraint = VersionConstraint::parse(&options.version);
+
         // First, check available versions using apt-cache madison
-        let madison_output = std::process::Command::new("apt-cache")
-            .arg("madison")
-            .arg(&options.package)
-            .output()
-            .map_err(|err| {
-                McpError::internal_error(
+        let mut found_versions = self
+            .available_versions(
+                &package,
+                &options.package,
+                options.target_release.as_deref(),
+            )
+            .await?;
+        let resolved = VersionScheme::Deb
+            .latest_matching(found_versions.iter().map(String::as_str), &constraint)
+            .map(str::to_string);
+        if resolved.is_none()
+            && let Some(snapshot) = &self.snapshot
+            && let Some(outcome) = self
+                .install_from_snapshot(snapshot, options, &constraint)
+                .await?
+        {
+            return Ok(outcome);
+        }
+        let resolved = resolved
+            // If we couldn't verify the versions, let apt-get resolve an exact version
+            .or_else(|| {
+                (found_versions.is_empty() && constraint.is_exact())
+                    .then(|| constraint.version.clone())
+            });
+
+        // If a matching version was found, install the newest one
+        if let Some(version) = resolved {
+            let mut command = self.install_command(
+                options.install_recommends,
+                options.target_release.as_deref(),
+            );
+            command.arg(format!("{package}={version}"));
+
+            let output = self.runner.run(&command).await.map_err(|err| {
+                PackageManagerError::io(
                     format!(
-                        "there was an error checking versions for package {}: {}",
-                        options.package, err
+                        "there was an error installing package {}={}",
+                        options.package, version
                     ),
-                    None,
+                    err,
                 )
             })?;
 
-        let mut found_versions: Vec<String> = Vec::new();
-        let mut version_found = false;
-
-        if madison_output.status.success() {
-            let stdout = String::from_utf8_lossy(&madison_output.stdout);
-            for line in stdout.lines() {
-                // apt-cache madison output format: package | version | source
-                let parts: Vec<&str> = line.split('|').collect();
-                if parts.len() >= 2 {
-                    let version = parts[1].trim().to_string();
-                    if version == options.version {
-                        version_found = true;
-                    }
-                    if !found_versions.contains(&version) {
-                        found_versions.push(version);
-                    }
+            let exec_result = output.success()?;
+            return Ok(install_outcome(&options.package, exec_result));
+        }
+
+        if found_versions.is_empty() {
+            return Err(PackageManagerError::NotFound {
+                package: options.package.clone(),
+                searched_repositories: Vec::new(),
+            });
+        }
+
+        // Version not found - return error with available versions
+        VersionScheme::Deb.sort(&mut found_versions);
+        Err(PackageManagerError::VersionNotFound {
+            package: options.package.clone(),
+            version: options.version.clone(),
+            available_versions: found_versions,
+        })
+    }
+
+    /// Resolves among the versions `apt-cache madison` reports; versions
+    /// only the snapshot archive has are not found
+    async fn resolve_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<ResolvedVersion, PackageManagerError> {
+        // Validate inputs to prevent command injection
+        validate_package_name(&options.package, Some(VersionScheme::Deb))?;
+        validate_version(&options.version, Some(VersionScheme::Deb))?;
+        validate_arch(options.arch.as_deref())?;
+        self.prepare().await?;
+
+        let package = with_arch(&options.package, options.arch.as_deref());
+        let entries = in_release(
+            self.madison(&package, &options.package).await?,
+            options.target_release.as_deref(),
+        )?;
+        resolve_among(Some(VersionScheme::Deb), &entries, options)
+    }
+
+    /// Resolves every version first, then installs them with one
+    /// `apt-get install`
+    ///
+    /// Unlike single installs, a version missing from the repositories is
+    /// not looked up in the snapshot archive.
+    async fn install_packages_with_versions(
+        &self,
+        packages: &[InstallVersionOptions],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        for options in packages {
+            // Validate inputs to prevent command injection
+            validate_package_name(&options.package, Some(VersionScheme::Deb))?;
+            validate_version(&options.version, Some(VersionScheme::Deb))?;
+            validate_arch(options.arch.as_deref())?;
+        }
+        // `-t` applies to the whole transaction, so it takes one release
+        let mut releases = packages
+            .iter()
+            .filter_map(|options| options.target_release.as_deref());
+        let release = releases.next();
+        if let Some(other) = releases.find(|other| Some(*other) != release) {
+            return Err(PackageManagerError::Validation {
+                field: "target_release",
+                value: other.to_string(),
+                reason: format!(
+                    "packages installed together must target one release, not also {}",
+                    release.unwrap_or_default()
+                ),
+            });
+        }
+        self.prepare().await?;
+
+        let mut specs = Vec::new();
+        for options in packages {
+            let package = with_arch(&options.package, options.arch.as_deref());
+            let constraint = VersionConstraint::parse(&options.version);
+            let target_release = options.target_release.as_deref();
+            let mut found_versions = self
+                .available_versions(&package, &options.package, target_release)
+                .await?;
+            let resolved = VersionScheme::Deb
+                .latest_matching(found_versions.iter().map(String::as_str), &constraint)
+                .map(str::to_string)
+                // If we couldn't verify the versions, let apt-get resolve an exact version
+                .or_else(|| {
+                    (found_versions.is_empty() && constraint.is_exact())
+                        .then(|| constraint.version.clone())
+                });
+            match resolved {
+                Some(version) => specs.push(format!("{package}={version}")),
+                None if found_versions.is_empty() => {
+                    return Err(PackageManagerError::NotFound {
+                        package: options.package.clone(),
+                        searched_repositories: Vec::new(),
+                    });
+                }
+                None => {
+                    VersionScheme::Deb.sort(&mut found_versions);
+                    return Err(PackageManagerError::VersionNotFound {
+                        package: options.package.clone(),
+                        version: options.version.clone(),
+                        available_versions: found_versions,
+                    });
                 }
             }
         }
 
-        // If exact version match found (or we couldn't verify), try to install it
-        if version_found || found_versions.is_empty() {
-            let mut command = std::process::Command::new("apt-get");
-            command.env("DEBIAN_FRONTEND", "noninteractive");
-            command.arg("install");
-            command.arg("-y");
-            command.arg(format!("{}={}", options.package, options.version));
+        let recommends = packages
+            .iter()
+            .find_map(|options| options.install_recommends);
+        let mut command = self.install_command(recommends, release);
+        command.args(&specs);
 
-            let output = command.output().map_err(|err| {
-                McpError::internal_error(
-                    format!(
-                        "there was an error installing package {}={}: {}",
-                        options.package, options.version, err
-                    ),
-                    None,
-                )
-            })?;
+        let names: Vec<String> = packages
+            .iter()
+            .map(|options| options.package.clone())
+            .collect();
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!("there was an error installing packages {}", names.join(" ")),
+                err,
+            )
+        })?;
 
-            return Ok(ExecResult {
-                stdout: if !output.stdout.is_empty() {
-                    Some(String::from_utf8_lossy(&output.stdout).to_string())
-                } else {
-                    None
-                },
-                stderr: if !output.stderr.is_empty() {
-                    Some(String::from_utf8_lossy(&output.stderr).to_string())
-                } else {
-                    None
-                },
-                status: output.status.code().unwrap_or(-1),
-            });
+        let exec_result = output.success()?;
+        Ok(install_outcomes(&names, exec_result))
+    }
+
+    async fn search_package(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        // Note: APT searches ignore a custom `repository`, using the system
+        // sources plus the added `repositories` unless `only_repositories`
+        // replaces them, and list the packages of every enabled architecture
+        validate_arch(options.arch.as_deref())?;
+        self.prepare().await?;
+        let added: Vec<&str> = options.repositories.iter().map(String::as_str).collect();
+        let source = match (added.as_slice(), options.only_repositories.as_slice()) {
+            ([], []) => None,
+            ([], repositories) => Some(CustomSource::replacing(self, repositories).await?),
+            (added, _) => Some(CustomSource::adding(self, added).await?),
+        };
+        let mut command = self.apt("apt-cache");
+        if let Some(source) = &source {
+            source.update(&[]).await?;
+            command.args(source.options());
         }
+        command.arg("search");
+        // `apt-cache search` matches a regular expression against names and
+        // descriptions
+        match options.mode {
+            SearchMode::Description => command.arg(SearchMode::description_regex(&options.query)),
+            mode => command
+                .arg("--names-only")
+                .arg(mode.name_regex(&options.query)),
+        };
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!(
+                    "there was an error searching for packages with query {}",
+                    options.query
+                ),
+                err,
+            )
+        })?;
 
-        // Version not found - return error with available versions
-        Err(McpError::internal_error(
-            format!(
-                "Version '{}' of package '{}' not found. Available versions: {}",
-                options.version,
-                options.package,
-                found_versions.join(", ")
-            ),
-            Some(serde_json::json!({
-                "package_name": options.package,
-                "requested_version": options.version,
-                "available_versions": found_versions,
-                "error_type": "version_not_found"
-            })),
+        let exec_result = output.success()?;
+        Ok(parse_search(
+            exec_result.stdout.as_deref().unwrap_or_default(),
         ))
     }
 
-    fn search_package(&self, options: &SearchOptions) -> Result<ExecResult, McpError> {
-        // Note: APT doesn't support custom repository for search, uses system sources
-        let output = std::process::Command::new("apt-cache")
-            .arg("search")
-            .arg(&options.query)
-            .output()
+    /// `apt-cache search` names no versions or suites: the packages are
+    /// looked up together with `apt-cache madison`
+    async fn locate_search_results(
+        &self,
+        results: Vec<SearchResult>,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        let names: Vec<&str> = results
+            .iter()
+            .filter(|result| result.repository.is_none())
+            .map(|result| result.name.as_str())
+            .collect();
+        let versions = self.madison_all(&names).await?;
+
+        let mut located = Vec::with_capacity(results.len());
+        for result in results {
+            let entries = match &result.repository {
+                None => versions.get(&result.name).cloned().unwrap_or_default(),
+                Some(_) => Vec::new(),
+            };
+            if entries.is_empty() {
+                located.push(result);
+                continue;
+            }
+            located.extend(entries.into_iter().map(|entry| SearchResult {
+                description: result.description.clone(),
+                ..entry
+            }));
+        }
+        Ok(located)
+    }
+
+    async fn list_installed_packages(&self) -> Result<Vec<InstalledPackage>, PackageManagerError> {
+        self.prepare().await?;
+        let output = self
+            .runner
+            .run(self.apt("apt").arg("list").arg("--installed"))
+            .await
             .map_err(|err| {
-                McpError::internal_error(
-                    format!(
-                        "there was an error searching for packages with query {}: {}",
-                        &options.query, err
-                    ),
-                    None,
-                )
+                PackageManagerError::io("there was an error listing installed packages", err)
             })?;
 
-        Ok(ExecResult {
-            stdout: if !output.stdout.is_empty() {
-                Some(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                None
-            },
-            stderr: if !output.stderr.is_empty() {
-                Some(String::from_utf8_lossy(&output.stderr).to_string())
-            } else {
-                None
-            },
-            status: output.status.code().unwrap_or(-1),
-        })
+        let exec_result = output.success()?;
+        Ok(parse_installed_list(
+            exec_result.stdout.as_deref().unwrap_or_default(),
+        ))
     }
 
-    fn list_installed_packages(&self) -> Result<ExecResult, McpError> {
-        let output = std::process::Command::new("apt")
-            .arg("list")
-            .arg("--installed")
-            .output()
+    async fn refresh_repositories(&self) -> Result<ExecResult, PackageManagerError> {
+        self.write_auth_file().await?;
+        // The system's sources may have changed since they were moved onto
+        // the mirrors
+        if let Some(offline) = &self.offline {
+            self.write_offline_sources(offline).await?;
+            let _ = offline.written.set(());
+        }
+        let output = self
+            .runner
+            .run(
+                self.apt("apt-get")
+                    .privileged()
+                    .env("DEBIAN_FRONTEND", "noninteractive")
+                    .arg("update"),
+            )
+            .await
             .map_err(|err| {
-                McpError::internal_error(
-                    format!("there was an error listing installed packages: {err}"),
-                    None,
-                )
+                PackageManagerError::io("there was an error refreshing repositories", err)
             })?;
 
-        Ok(ExecResult {
-            stdout: if !output.stdout.is_empty() {
-                Some(String::from_utf8_lossy(&output.stdout).to_string())
+        let result = output.success()?;
+        // The indexes changed, so may the available versions
+        self.lock_madison().clear();
+        Ok(result)
+    }
+
+    async fn check_privileges(&self) -> Result<PrivilegeReport, PackageManagerError> {
+        Ok(privileges::check(&*self.runner, &self.system_file(DATABASE_DIR)).await)
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        let list_error =
+            |err| PackageManagerError::io("there was an error reading the APT sources", err);
+
+        let sources_parts = self.system_file(SOURCES_PARTS);
+        let mut parts: Vec<String> = self
+            .runner
+            .list_dir(&sources_parts)
+            .await
+            .map_err(list_error)?
+            .into_iter()
+            .map(|name| format!("{sources_parts}/{name}"))
+            .collect();
+        // APT reads the parts in alphabetical order after the main list
+        parts.sort();
+
+        let mut repositories = Vec::new();
+        for file in std::iter::once(self.system_file(SOURCES_LIST)).chain(parts) {
+            let parse: fn(&str) -> Vec<Repository> = if file.ends_with(".list") {
+                parse_sources_list
+            } else if file.ends_with(".sources") {
+                parse_deb822_sources
             } else {
-                None
-            },
-            stderr: if !output.stderr.is_empty() {
-                Some(String::from_utf8_lossy(&output.stderr).to_string())
+                continue;
+            };
+            let Some(content) = self.runner.read_file(&file).await.map_err(list_error)? else {
+                continue;
+            };
+            repositories.extend(parse(&content).into_iter().map(|repository| Repository {
+                file: Some(file.clone()),
+                ..repository
+            }));
+        }
+
+        Ok(repositories)
+    }
+
+    /// Sources marked `trusted=yes`, without a `Signed-By` keyring or whose
+    /// keyring is missing, and local directories
+    /// `apt-get download` of the exact version into a private directory,
+    /// with the SHA-256 of the `.deb`
+    ///
+    /// Debian packages carry no signature of their own, so no signing key is
+    /// reported.
+    async fn fetch_package(
+        &self,
+        package: &PinnedPackage,
+    ) -> Result<FetchedPackage, PackageManagerError> {
+        validate_arch(package.arch.as_deref())?;
+        self.prepare().await?;
+
+        let mut download = self.apt("apt-get");
+        download.arg("download");
+        let source = match &package.repository {
+            Some(repository) => Some(CustomSource::prepare(self, repository).await?),
+            None => None,
+        };
+        if let Some(source) = &source {
+            source.update(&[]).await?;
+            download.arg("-o");
+            download.arg(source.sourcelist_option());
+        }
+        download.arg(format!(
+            "{}={}",
+            with_arch(&package.name, package.arch.as_deref()),
+            package.version
+        ));
+
+        let dir = pins::create_fetch_directory(&*self.runner).await?;
+        // apt-get download saves into the working directory
+        let mut fetch = command("sh");
+        fetch
+            .args(["-c", "cd \"$1\" && shift && exec \"$@\"", "sh", &dir])
+            .args(download.argv());
+        fetch.env = download.env;
+
+        let fetched = async {
+            let output = self.runner.run(&fetch).await.map_err(|err| {
+                PackageManagerError::io(
+                    format!("there was an error downloading package {}", package.name),
+                    err,
+                )
+            })?;
+            output.success()?;
+            let path = pins::fetched_file(&*self.runner, &dir, ".deb").await?;
+            Ok(FetchedPackage {
+                package: package.clone(),
+                sha256: pins::file_sha256(&*self.runner, &path).await?,
+                path,
+                signing_key: None,
+            })
+        }
+        .await;
+        if fetched.is_err() {
+            let _ = pins::remove_fetch_directory(&*self.runner, &dir).await;
+        }
+        fetched
+    }
+
+    /// `apt-get install` of the downloaded `.deb`, with the dependencies
+    /// from the configured sources and the custom one it came from
+    async fn install_fetched(
+        &self,
+        package: &FetchedPackage,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        let pinned = &package.package;
+        self.prepare().await?;
+
+        let mut command = self.install_command(None, None);
+        let source = match &pinned.repository {
+            Some(repository) => Some(CustomSource::prepare(self, repository).await?),
+            None => None,
+        };
+        if let Some(source) = &source {
+            source.update(&[]).await?;
+            command.arg("-o");
+            command.arg(source.sourcelist_option());
+        }
+        command.arg(&package.path);
+
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(
+                format!("there was an error installing package {}", pinned.name),
+                err,
+            )
+        })?;
+        let exec_result = output.success()?;
+        Ok(install_outcome(&pinned.name, exec_result))
+    }
+
+    async fn discard_fetched(&self, package: &FetchedPackage) -> Result<(), PackageManagerError> {
+        match package.path.rsplit_once('/') {
+            Some((dir, _)) => pins::remove_fetch_directory(&*self.runner, dir).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Digest of the dpkg status file, rewritten by every change
+    async fn database_fingerprint(&self) -> Result<String, PackageManagerError> {
+        let file = self.system_file(STATUS_FILE);
+        let content = self
+            .runner
+            .read_file(&file)
+            .await
+            .map_err(|err| PackageManagerError::io(format!("failed to read {file}"), err))?;
+        Ok(drift::fingerprint(content.unwrap_or_default().as_bytes()))
+    }
+
+    async fn unsigned_repositories(
+        &self,
+        repository: Option<&str>,
+    ) -> Result<Vec<UnsignedRepository>, PackageManagerError> {
+        let mut repositories = self.list_repositories().await?;
+        let mut problems = Vec::new();
+        if let Some(definition) = repository.map(str::trim) {
+            if let Some(dir) = local_directory(definition) {
+                problems.push(UnsignedRepository::new(
+                    dir,
+                    "the index generated for a local directory is not signed",
+                ));
+            } else if definition.starts_with("deb ") || definition.starts_with("deb-src ") {
+                repositories.extend(parse_sources_list(definition));
+            } else if definition.ends_with(".list") || definition.ends_with(".sources") {
+                let content = self
+                    .runner
+                    .read_file(definition)
+                    .await
+                    .map_err(|err| {
+                        PackageManagerError::io(
+                            format!("there was an error reading {definition}"),
+                            err,
+                        )
+                    })?
+                    .unwrap_or_default();
+                let parse = if definition.ends_with(".list") {
+                    parse_sources_list
+                } else {
+                    parse_deb822_sources
+                };
+                repositories.extend(parse(&content).into_iter().map(|repository| Repository {
+                    file: Some(definition.to_string()),
+                    ..repository
+                }));
             } else {
-                None
-            },
-            status: output.status.code().unwrap_or(-1),
+                repositories.extend(parse_deb822_sources(definition));
+            }
+        }
+
+        for repository in repositories.iter().filter(|repository| repository.enabled) {
+            let problem = |reason: String| {
+                UnsignedRepository::new(&repository.url, reason).in_file(repository.file.clone())
+            };
+            let mut found = Vec::new();
+            if repository.trusted {
+                found.push(problem(
+                    "marked trusted, so its signatures are not checked".to_string(),
+                ));
+            }
+            match &repository.signed_by {
+                None => found.push(problem(
+                    "no Signed-By keyring, so any key trusted by APT is accepted".to_string(),
+                )),
+                Some(signed_by) => {
+                    for keyring in self.missing_keyrings(signed_by).await? {
+                        found.push(problem(format!(
+                            "Signed-By keyring {keyring} does not exist"
+                        )));
+                    }
+                }
+            }
+            // deb and deb-src entries of a repository share their problems
+            for problem in found {
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+        }
+        Ok(problems)
+    }
+}
+
+/// Sources file used by a single installation from a custom `repository`
+///
+/// The repository may be the path, on the runner's target, of an existing `.list` or deb822
+/// `.sources` file (APT picks the format from the extension), a one-line
+/// entry (`deb [signed-by=...] https://example.org/debian bookworm main`) or
+/// a deb822 stanza. Inline definitions are normalized into a temporary
+/// `.sources` file that only carries the known fields, so options like
+/// `Trusted: yes` cannot be smuggled in, and that is removed on drop.
+///
+/// An exclusive source replaces every configured source, for calls passing
+/// `only_repositories`.
+struct CustomSource {
+    path: String,
+    generated: bool,
+    exclusive: bool,
+    apt: Apt,
+}
+
+/// Entries of a custom source definition
+enum SourceEntries {
+    /// Path of an existing sources file
+    File(String),
+    /// Inline entries, with whether they index a local directory
+    Inline {
+        repositories: Vec<Repository>,
+        local: bool,
+    },
+}
+
+impl SourceEntries {
+    /// Parse the source definition `repository` of the parameter `field`,
+    /// indexing it first when it is a local directory
+    async fn parse(
+        apt: &Apt,
+        field: &'static str,
+        repository: &str,
+    ) -> Result<Self, PackageManagerError> {
+        let definition = repository.trim();
+        let invalid = |reason: &str| PackageManagerError::Validation {
+            field,
+            value: repository.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let mut local = false;
+        let mut repositories = if definition.starts_with("deb ")
+            || definition.starts_with("deb-src ")
+        {
+            parse_sources_list(definition)
+        } else if definition.lines().any(|line| {
+            line.split_once(':')
+                .is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case("uris"))
+        }) {
+            parse_deb822_sources(definition)
+        } else if let Some(dir) = local_directory(definition) {
+            index_deb_directory(&*apt.runner, dir).await?;
+            local = true;
+            // A flat repository: the index is at the directory's root
+            vec![Repository {
+                suites: vec!["./".to_string()],
+                ..Repository::new(format!("file://{dir}"))
+            }]
+        } else if let Some(offline) = &apt.offline {
+            return Err(PackageManagerError::PermissionDenied {
+                message: format!(
+                    "the server is offline and only accepts repositories given as source entries on its internal mirrors ({})",
+                    offline
+                        .mirrors
+                        .internal_urls()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            });
+        } else if definition.contains("://") {
+            return Err(invalid(
+                "APT repositories need a suite: pass a one-line entry such as 'deb https://example.org/debian bookworm main', a deb822 stanza, the path of a .list or .sources file or a directory of packages",
+            ));
+        } else {
+            return Ok(Self::File(definition.to_string()));
+        };
+
+        if repositories.is_empty()
+            || repositories
+                .iter()
+                .any(|repository| repository.suites.is_empty())
+        {
+            return Err(invalid(
+                "not a valid APT source definition, every entry needs a URI and a suite",
+            ));
+        }
+
+        if let Some(offline) = &apt.offline {
+            for repository in &mut repositories {
+                repository.url = offline.mirrors.rewrite(&repository.url).ok_or_else(|| {
+                    PackageManagerError::PermissionDenied {
+                        message: format!(
+                            "the server is offline and {} is not on one of its internal mirrors",
+                            repository.url
+                        ),
+                    }
+                })?;
+            }
+        }
+        Ok(Self::Inline {
+            repositories,
+            local,
         })
     }
+}
 
-    fn refresh_repositories(&self) -> Result<ExecResult, McpError> {
-        let output = std::process::Command::new("apt-get")
-            .env("DEBIAN_FRONTEND", "noninteractive")
-            .arg("update")
-            .output()
+impl CustomSource {
+    async fn prepare(apt: &Apt, repository: &str) -> Result<Self, PackageManagerError> {
+        Self::adding(apt, &[repository]).await
+    }
+
+    /// Source of `repositories`, added to the configured sources
+    ///
+    /// A sources file is used as it is, so it cannot be combined with other
+    /// repositories.
+    async fn adding(apt: &Apt, repositories: &[&str]) -> Result<Self, PackageManagerError> {
+        let mut stanzas = Vec::new();
+        for repository in repositories {
+            match SourceEntries::parse(apt, "repository", repository).await? {
+                SourceEntries::File(path) if repositories.len() == 1 => {
+                    return Ok(Self {
+                        path,
+                        generated: false,
+                        exclusive: false,
+                        apt: apt.clone(),
+                    });
+                }
+                SourceEntries::File(_) => {
+                    return Err(PackageManagerError::Validation {
+                        field: "repositories",
+                        value: repository.to_string(),
+                        reason: "a sources file cannot be combined with other repositories"
+                            .to_string(),
+                    });
+                }
+                SourceEntries::Inline {
+                    repositories,
+                    local,
+                } => {
+                    for repository in &repositories {
+                        let mut stanza = deb822_stanza(repository);
+                        // The generated index of a local directory is not
+                        // signed
+                        if local {
+                            stanza.push_str("Trusted: yes\n");
+                        }
+                        stanzas.push(stanza);
+                    }
+                }
+            }
+        }
+        // Offline, the source replaces the offline sources, so it carries
+        // them too for dependencies to keep resolving
+        if let Some(offline) = &apt.offline {
+            let offline_repositories = apt.offline_repositories(&offline.mirrors).await?;
+            stanzas.extend(offline_repositories.iter().map(deb822_stanza));
+        }
+        Self::write(apt, &stanzas, false).await
+    }
+
+    /// Source of `repositories`, replacing every configured source
+    ///
+    /// Files are not accepted, since their entries could not be checked
+    /// against the offline mirrors.
+    async fn replacing(apt: &Apt, repositories: &[String]) -> Result<Self, PackageManagerError> {
+        let mut stanzas = Vec::new();
+        for repository in repositories {
+            match SourceEntries::parse(apt, "only_repositories", repository).await? {
+                SourceEntries::File(_) => {
+                    return Err(PackageManagerError::Validation {
+                        field: "only_repositories",
+                        value: repository.clone(),
+                        reason: "sources replacing the configured ones are given as source entries or directories of packages, not as files".to_string(),
+                    });
+                }
+                SourceEntries::Inline {
+                    repositories,
+                    local,
+                } => {
+                    for repository in &repositories {
+                        let mut stanza = deb822_stanza(repository);
+                        // The generated index of a local directory is not
+                        // signed
+                        if local {
+                            stanza.push_str("Trusted: yes\n");
+                        }
+                        stanzas.push(stanza);
+                    }
+                }
+            }
+        }
+        Self::write(apt, &stanzas, true).await
+    }
+
+    /// Write `stanzas` to a temporary sources file in the backend's private
+    /// directory
+    async fn write(
+        apt: &Apt,
+        stanzas: &[String],
+        exclusive: bool,
+    ) -> Result<Self, PackageManagerError> {
+        apt.private_dir.create(&apt.runner).await?;
+        let path = apt.private_dir.file(&format!(
+            "source-{}.sources",
+            NEXT_SOURCE_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        apt.runner
+            .write_file(&path, &stanzas.join("\n"))
+            .await
             .map_err(|err| {
-                McpError::internal_error(
-                    format!("there was an error refreshing repositories: {err}"),
-                    None,
-                )
+                PackageManagerError::io("there was an error writing the custom APT source", err)
             })?;
 
-        Ok(ExecResult {
-            stdout: if !output.stdout.is_empty() {
-                Some(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                None
-            },
-            stderr: if !output.stderr.is_empty() {
-                Some(String::from_utf8_lossy(&output.stderr).to_string())
-            } else {
-                None
-            },
-            status: output.status.code().unwrap_or(-1),
+        Ok(Self {
+            path,
+            generated: true,
+            exclusive,
+            apt: apt.clone(),
         })
     }
+
+    /// `-o` options of the commands using this source
+    ///
+    /// An exclusive source also disables `sources.list.d`.
+    fn options(&self) -> Vec<String> {
+        let mut options = vec!["-o".to_string(), self.sourcelist_option()];
+        if self.exclusive {
+            options.push("-o".to_string());
+            options.push("Dir::Etc::sourceparts=-".to_string());
+        }
+        options
+    }
+
+    /// `apt-get` option reading this source instead of `/etc/apt/sources.list`
+    ///
+    /// `sources.list.d` is still read, so dependencies from the system
+    /// repositories keep resolving. The path is absolute, so APT does not
+    /// resolve it below an alternate root's `Dir`.
+    fn sourcelist_option(&self) -> String {
+        format!("Dir::Etc::sourcelist={}", self.path)
+    }
+
+    /// Download the package index of this source only, with additional
+    /// `-o` configuration options
+    async fn update(&self, options: &[&str]) -> Result<ExecResult, PackageManagerError> {
+        let mut command = self.apt.apt("apt-get");
+        command
+            .privileged()
+            .env("DEBIAN_FRONTEND", "noninteractive")
+            .arg("update")
+            .arg("-o")
+            .arg(self.sourcelist_option())
+            .arg("-o")
+            .arg("Dir::Etc::sourceparts=-")
+            .arg("-o")
+            .arg("APT::Get::List-Cleanup=0");
+        for option in options {
+            command.arg("-o");
+            command.arg(option);
+        }
+        let output = self.apt.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io("there was an error refreshing the custom repository", err)
+        })?;
+
+        output.success()
+    }
+}
+
+impl Drop for CustomSource {
+    fn drop(&mut self) {
+        if !self.generated {
+            return;
+        }
+        // The file lives on the runner's target, so removing it is async
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let runner = self.apt.runner.clone();
+            let path = std::mem::take(&mut self.path);
+            runtime.spawn(async move {
+                let _ = runner.remove_file(&path).await;
+            });
+        }
+    }
+}
+
+/// Suite of an archive line of `apt-cache madison`, e.g. `bookworm-backports`
+/// in `http://deb.debian.org/debian bookworm-backports/main amd64 Packages`
+fn suite_of(archive: &str) -> Option<&str> {
+    archive.split_whitespace().nth(1)?.split('/').next()
+}
+
+/// The `apt-cache madison` entries of `release`, every entry without one
+///
+/// A release no suite offering the package is named after is rejected rather
+/// than ignored, including archive names such as `stable`: the entries only
+/// name the suites as the sources do.
+fn in_release(
+    entries: Vec<SearchResult>,
+    release: Option<&str>,
+) -> Result<Vec<SearchResult>, PackageManagerError> {
+    let Some(release) = release else {
+        return Ok(entries);
+    };
+    // Unknown packages are reported as not found
+    if entries.is_empty() {
+        return Ok(entries);
+    }
+    let matching: Vec<SearchResult> = entries
+        .into_iter()
+        .filter(|entry| entry.repository.as_deref().and_then(suite_of) == Some(release))
+        .collect();
+    if matching.is_empty() {
+        return Err(PackageManagerError::Validation {
+            field: "target_release",
+            value: release.to_string(),
+            reason: "no configured suite of that name offers the package".to_string(),
+        });
+    }
+    Ok(matching)
+}
+
+/// Outcome of installing `package`, from the output of `apt-get install`
+fn install_outcome(package: &str, exec_result: ExecResult) -> InstallOutcome {
+    let mut outcomes = install_outcomes(&[package.to_string()], exec_result);
+    outcomes.remove(0)
+}
+
+/// Outcomes of installing `packages` with one `apt-get install`
+fn install_outcomes(packages: &[String], exec_result: ExecResult) -> Vec<InstallOutcome> {
+    let stdout = exec_result.stdout.clone().unwrap_or_default();
+    let mut outcomes = InstallOutcome::batch(packages, parse_install_output(&stdout), exec_result);
+    // Counted once, with the first package
+    if let Some(first) = outcomes.first_mut() {
+        first.removed = parse_removed_packages(&stdout);
+        first.downloaded_bytes = parse_fetched_bytes(&stdout);
+    }
+    outcomes
 }
 
-fn validate_package_version_input(input: &str) -> bool {
-    // Allow alphanumeric, dots, hyphens, underscores, plus signs, colons, and tildes
-    // (colons are common in Debian package names like "package:amd64", tildes in versions like "1.0~beta")
-    input.chars().all(|c| {
-        c.is_alphanumeric() || c == '.' || c == '-' || c == '_' || c == '+' || c == ':' || c == '~'
-    })
+/// Render a repository as a deb822 stanza
+fn deb822_stanza(repository: &Repository) -> String {
+    let mut stanza = String::new();
+    let mut field = |name: &str, value: &str| {
+        stanza.push_str(name);
+        stanza.push(':');
+        if value.contains('\n') {
+            // Multi-line values (inline keys) start on an indented line, with
+            // empty lines written as " ."
+            for line in value.lines().map(str::trim) {
+                stanza.push_str(if line.is_empty() { "\n ." } else { "\n " });
+                stanza.push_str(line);
+            }
+        } else {
+            stanza.push(' ');
+            stanza.push_str(value.trim());
+        }
+        stanza.push('\n');
+    };
+
+    if repository.types.is_empty() {
+        field("Types", "deb");
+    } else {
+        field("Types", &repository.types.join(" "));
+    }
+    field("URIs", &repository.url);
+    field("Suites", &repository.suites.join(" "));
+    if !repository.components.is_empty() {
+        field("Components", &repository.components.join(" "));
+    }
+    if let Some(signed_by) = &repository.signed_by {
+        field("Signed-By", signed_by);
+    }
+    if !repository.enabled {
+        field("Enabled", "no");
+    }
+
+    stanza
+}
+
+/// Quoted `name:arch=version` of pinned packages
+fn pinned_names(packages: &[PinnedPackage]) -> Vec<String> {
+    packages
+        .iter()
+        .map(|package| {
+            shell_quote(&format!(
+                "{}={}",
+                with_arch(&package.name, package.arch.as_deref()),
+                package.version
+            ))
+        })
+        .collect()
+}
+
+/// Qualify a package name with an architecture (`curl:arm64`)
+fn with_arch(package: &str, arch: Option<&str>) -> String {
+    match arch {
+        Some(arch) => format!("{package}:{arch}"),
+        None => package.to_string(),
+    }
 }
@@ -0,0 +1,136 @@
+//! Coalescing of installs requested close together
+//!
+//! Agents following a plan such as "install these 15 tools" call
+//! `install_package` once per tool, and every call resolves dependencies and
+//! reads the package database again. With batching enabled, the first
+//! install of a session waits for a short window, every install requested
+//! meanwhile joins it, and the whole batch runs as one `apk add` or
+//! `apt-get install` through [`PackageManager::install_packages`].
+//!
+//! A failing batch does not tell which package broke it, so its packages are
+//! then installed one by one and each call gets its own result. Batches run
+//! under the handler's mutation lock, like every other mutating call.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use super::exec;
+use super::{InstallFlags, InstallOptions, InstallOutcome, PackageManager};
+use crate::error::PackageManagerError;
+
+type Reply = oneshot::Sender<Result<InstallOutcome, PackageManagerError>>;
+
+/// Installs waiting for their batch to run, by architecture
+type Queues = HashMap<Option<String>, Vec<(String, Reply)>>;
+
+/// Install queue of one session
+pub(crate) struct InstallBatcher {
+    window: Duration,
+    queues: Mutex<Queues>,
+    /// Handler's lock serializing mutating calls, held while a batch runs
+    mutation_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl InstallBatcher {
+    pub(crate) fn new(window: Duration, mutation_lock: Arc<tokio::sync::Mutex<()>>) -> Self {
+        Self {
+            window,
+            queues: Mutex::default(),
+            mutation_lock,
+        }
+    }
+
+    /// How long the first install of a batch waits for others
+    pub(crate) fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Install the package of `options` with the other packages requested
+    /// for the same architecture within the batching window
+    ///
+    /// Only installs without a custom repository can be batched. The batch
+    /// runs in its own task, so cancelling one of its calls does not affect
+    /// the others; its output is streamed to the call that started it.
+    pub(crate) async fn install(
+        self: &Arc<Self>,
+        backend: &Arc<dyn PackageManager>,
+        options: &InstallOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        let (reply, result) = oneshot::channel();
+        let starts_batch = {
+            let mut queues = self.lock_queues();
+            let queue = queues.entry(options.arch.clone()).or_default();
+            queue.push((options.package.clone(), reply));
+            queue.len() == 1
+        };
+
+        if starts_batch {
+            let batcher = self.clone();
+            let backend = backend.clone();
+            let arch = options.arch.clone();
+            let run = async move {
+                tokio::time::sleep(batcher.window).await;
+                // Installs requested while waiting for the lock join the batch
+                let _mutating = batcher.mutation_lock.lock().await;
+                let batch = batcher.lock_queues().remove(&arch).unwrap_or_default();
+                run_batch(&backend, arch.as_deref(), batch).await;
+            };
+            match exec::current_sink() {
+                Some(sink) => tokio::spawn(exec::stream_output(sink, run)),
+                None => tokio::spawn(run),
+            };
+        }
+
+        result.await.unwrap_or_else(|_| {
+            Err(PackageManagerError::io(
+                format!("the install of package {} was abandoned", options.package),
+                std::io::Error::other("the batch task ended without a result"),
+            ))
+        })
+    }
+
+    fn lock_queues(&self) -> std::sync::MutexGuard<'_, Queues> {
+        self.queues.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+async fn run_batch(
+    backend: &Arc<dyn PackageManager>,
+    arch: Option<&str>,
+    batch: Vec<(String, Reply)>,
+) {
+    let packages: Vec<String> = batch.iter().map(|(package, _)| package.clone()).collect();
+    if packages.len() > 1 {
+        match backend.install_packages(&packages, arch).await {
+            Ok(outcomes) if outcomes.len() == batch.len() => {
+                for ((_, reply), outcome) in batch.into_iter().zip(outcomes) {
+                    let _ = reply.send(Ok(outcome));
+                }
+                return;
+            }
+            Ok(_) => tracing::warn!("Batched install returned the wrong number of outcomes"),
+            Err(err) => tracing::warn!(
+                "Batched install of {} failed, installing one by one: {err}",
+                packages.join(" ")
+            ),
+        }
+    }
+
+    for (package, reply) in batch {
+        let options = InstallOptions {
+            package,
+            repository: None,
+            repositories: Vec::new(),
+            only_repositories: Vec::new(),
+            arch: arch.map(str::to_string),
+            preseed: Vec::new(),
+            install_recommends: None,
+            target_release: None,
+            flags: InstallFlags::default(),
+        };
+        let _ = reply.send(backend.install_package(&options).await);
+    }
+}
@@ -0,0 +1,466 @@
+//! Time-limited caches of search results and installed packages
+//!
+//! Agents often search for the same package several times while deciding on a
+//! version, and searches can be slow: the APK backend queries every search
+//! repository over the network. [`CachingBackend`] wraps another backend and
+//! answers repeated searches from a [`SearchCache`] until their entry is older
+//! than the cache's time to live. Refreshing the repositories clears the
+//! cache, since the indexes the results came from changed.
+//!
+//! The cache can be persisted to a JSON file, so it survives restarts.
+//!
+//! Agents also list the installed packages before and after almost every
+//! step. The handler keeps those listings in an [`InstalledCache`] until a
+//! mutating tool succeeds or they expire.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::doctor::HealthCheck;
+use super::exit_status::ExitMeaning;
+use super::privileges::PrivilegeReport;
+use super::signing::UnsignedRepository;
+use super::{
+    Capabilities, ExecResult, FetchedPackage, InstallOptions, InstallOutcome,
+    InstallVersionOptions, InstalledPackage, LockedPackage, PackageLicense, PackageManager,
+    PinnedPackage, Recommends, Repository, ResolvedVersion, SearchMode, SearchOptions,
+    SearchResult, SpaceRequirement, SystemInfo,
+};
+use crate::ansible::AnsibleTask;
+use crate::apko::ApkoConfig;
+use crate::cloudinit::CloudConfig;
+use crate::error::PackageManagerError;
+use crate::version::VersionScheme;
+
+/// Search results are reused this long by default
+pub const DEFAULT_SEARCH_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// What a cached search was run with
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct SearchKey {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repository: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    repositories: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    only_repositories: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arch: Option<String>,
+    mode: SearchMode,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedSearch {
+    key: SearchKey,
+    cached_at: DateTime<Utc>,
+    results: Vec<SearchResult>,
+}
+
+/// Search results shared by the backends wrapped with it
+#[derive(Clone)]
+pub struct SearchCache {
+    ttl: Duration,
+    path: Option<PathBuf>,
+    entries: Arc<Mutex<HashMap<SearchKey, CachedSearch>>>,
+}
+
+impl SearchCache {
+    /// Keep search results in memory for `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            path: None,
+            entries: Arc::default(),
+        }
+    }
+
+    /// Load the results saved in the JSON file at `path` and save new ones to it
+    ///
+    /// The cache is only an optimization: a missing or unreadable file starts
+    /// an empty cache, and failures to save are logged.
+    pub fn persist_to(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<Vec<CachedSearch>>(&contents) {
+                Ok(saved) => {
+                    let mut entries = self.lock_entries();
+                    for entry in saved {
+                        entries.insert(entry.key.clone(), entry);
+                    }
+                }
+                Err(err) => tracing::warn!("Ignoring search cache {}: {err}", path.display()),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => tracing::warn!("Ignoring search cache {}: {err}", path.display()),
+        }
+        self.path = Some(path);
+        self
+    }
+
+    fn get(&self, key: &SearchKey) -> Option<Vec<SearchResult>> {
+        let mut entries = self.lock_entries();
+        let entry = entries.get(key)?;
+        if self.is_fresh(entry) {
+            Some(entry.results.clone())
+        } else {
+            entries.remove(key);
+            None
+        }
+    }
+
+    async fn insert(&self, key: SearchKey, results: Vec<SearchResult>) {
+        let entry = CachedSearch {
+            key: key.clone(),
+            cached_at: Utc::now(),
+            results,
+        };
+        self.lock_entries().insert(key, entry);
+        self.save().await;
+    }
+
+    async fn clear(&self) {
+        self.lock_entries().clear();
+        self.save().await;
+    }
+
+    fn is_fresh(&self, entry: &CachedSearch) -> bool {
+        (Utc::now() - entry.cached_at)
+            .to_std()
+            .is_ok_and(|age| age < self.ttl)
+    }
+
+    /// Write the unexpired entries to the cache file, if any
+    async fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let contents = {
+            let mut entries = self.lock_entries();
+            entries.retain(|_, entry| self.is_fresh(entry));
+            serde_json::to_string(&entries.values().collect::<Vec<_>>())
+        };
+        let saved = match contents {
+            Ok(contents) => tokio::fs::write(path, contents).await,
+            Err(err) => Err(std::io::Error::other(err)),
+        };
+        if let Err(err) = saved {
+            tracing::warn!("Failed to save search cache {}: {err}", path.display());
+        }
+    }
+
+    fn lock_entries(&self) -> std::sync::MutexGuard<'_, HashMap<SearchKey, CachedSearch>> {
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Default for SearchCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEARCH_CACHE_TTL)
+    }
+}
+
+/// A listing of installed packages and when it was made
+type CachedListing = (Instant, Arc<[InstalledPackage]>);
+
+/// Installed packages listed by the handler, by managed root
+pub(crate) struct InstalledCache {
+    ttl: Duration,
+    listings: Mutex<HashMap<Option<String>, CachedListing>>,
+}
+
+impl InstalledCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            listings: Mutex::default(),
+        }
+    }
+
+    /// Packages installed in `root`, when listed less than the TTL ago
+    pub(crate) fn get(&self, root: Option<&str>) -> Option<Arc<[InstalledPackage]>> {
+        let listings = self.lock_listings();
+        let (listed, packages) = listings.get(&root.map(str::to_string))?;
+        (listed.elapsed() < self.ttl).then(|| packages.clone())
+    }
+
+    pub(crate) fn insert(&self, root: Option<&str>, packages: Arc<[InstalledPackage]>) {
+        self.lock_listings()
+            .insert(root.map(str::to_string), (Instant::now(), packages));
+    }
+
+    /// Forget every listing, e.g. after an install
+    ///
+    /// Installs on targets can change the server's own system too (`local`
+    /// targets), so every root is cleared.
+    pub(crate) fn clear(&self) {
+        self.lock_listings().clear();
+    }
+
+    fn lock_listings(&self) -> std::sync::MutexGuard<'_, HashMap<Option<String>, CachedListing>> {
+        self.listings.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Backend answering repeated searches from a [`SearchCache`]
+pub struct CachingBackend {
+    inner: Arc<dyn PackageManager>,
+    cache: SearchCache,
+    /// Root the inner backend was moved to with [`PackageManager::with_root`]
+    root: Option<String>,
+}
+
+impl CachingBackend {
+    pub fn new(inner: Arc<dyn PackageManager>, cache: SearchCache) -> Self {
+        Self {
+            inner,
+            cache,
+            root: None,
+        }
+    }
+}
+
+#[async_trait]
+impl PackageManager for CachingBackend {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn os_name(&self) -> &'static str {
+        self.inner.os_name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn version_scheme(&self) -> Option<VersionScheme> {
+        self.inner.version_scheme()
+    }
+
+    async fn install_package(
+        &self,
+        options: &InstallOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        self.inner.install_package(options).await
+    }
+
+    async fn install_package_with_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        self.inner.install_package_with_version(options).await
+    }
+
+    async fn install_packages(
+        &self,
+        packages: &[String],
+        arch: Option<&str>,
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        self.inner.install_packages(packages, arch).await
+    }
+
+    async fn install_packages_with_versions(
+        &self,
+        packages: &[InstallVersionOptions],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        self.inner.install_packages_with_versions(packages).await
+    }
+
+    async fn resolve_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<ResolvedVersion, PackageManagerError> {
+        self.inner.resolve_version(options).await
+    }
+
+    async fn search_package(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        let key = SearchKey {
+            root: self.root.clone(),
+            query: options.query.clone(),
+            repository: options.repository.clone(),
+            repositories: options.repositories.clone(),
+            only_repositories: options.only_repositories.clone(),
+            arch: options.arch.clone(),
+            mode: options.mode,
+        };
+        if let Some(results) = self.cache.get(&key) {
+            return Ok(results);
+        }
+
+        let results = self.inner.search_package(options).await?;
+        self.cache.insert(key, results.clone()).await;
+        Ok(results)
+    }
+
+    async fn locate_search_results(
+        &self,
+        results: Vec<SearchResult>,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        self.inner.locate_search_results(results).await
+    }
+
+    async fn list_installed_packages(&self) -> Result<Vec<InstalledPackage>, PackageManagerError> {
+        self.inner.list_installed_packages().await
+    }
+
+    async fn package_licenses(&self) -> Result<Vec<PackageLicense>, PackageManagerError> {
+        self.inner.package_licenses().await
+    }
+
+    async fn osv_ecosystem(&self) -> Result<String, PackageManagerError> {
+        self.inner.osv_ecosystem().await
+    }
+
+    async fn health_checks(&self) -> Result<Vec<HealthCheck>, PackageManagerError> {
+        self.inner.health_checks().await
+    }
+
+    async fn system_info(&self) -> Result<SystemInfo, PackageManagerError> {
+        self.inner.system_info().await
+    }
+
+    async fn indexes_refreshed_at(
+        &self,
+    ) -> Result<Option<std::time::SystemTime>, PackageManagerError> {
+        self.inner.indexes_refreshed_at().await
+    }
+
+    async fn required_space(
+        &self,
+        packages: &[String],
+        arch: Option<&str>,
+        recommends: Option<Recommends>,
+    ) -> Result<Vec<SpaceRequirement>, PackageManagerError> {
+        self.inner.required_space(packages, arch, recommends).await
+    }
+
+    fn exit_meaning(&self, status: i32, output: &str) -> Option<ExitMeaning> {
+        self.inner.exit_meaning(status, output)
+    }
+
+    async fn preseed(&self, selections: &[String]) -> Result<(), PackageManagerError> {
+        self.inner.preseed(selections).await
+    }
+
+    async fn source_packages(&self) -> Result<HashMap<String, String>, PackageManagerError> {
+        self.inner.source_packages().await
+    }
+
+    async fn refresh_repositories(&self) -> Result<ExecResult, PackageManagerError> {
+        let result = self.inner.refresh_repositories().await;
+        if result.is_ok() {
+            self.cache.clear().await;
+        }
+        result
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        self.inner.list_repositories().await
+    }
+
+    async fn list_search_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        self.inner.list_search_repositories().await
+    }
+
+    async fn check_privileges(&self) -> Result<PrivilegeReport, PackageManagerError> {
+        self.inner.check_privileges().await
+    }
+
+    async fn install_commands(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        self.inner.install_commands(packages).await
+    }
+
+    async fn ansible_tasks(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<AnsibleTask>, PackageManagerError> {
+        self.inner.ansible_tasks(packages).await
+    }
+
+    async fn apko_config(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<ApkoConfig, PackageManagerError> {
+        self.inner.apko_config(packages).await
+    }
+
+    async fn cloud_config(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<CloudConfig, PackageManagerError> {
+        self.inner.cloud_config(packages).await
+    }
+
+    async fn dockerfile_instructions(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        self.inner.dockerfile_instructions(packages).await
+    }
+
+    async fn lock_packages(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<LockedPackage>, PackageManagerError> {
+        self.inner.lock_packages(packages).await
+    }
+
+    async fn install_locked(
+        &self,
+        packages: &[LockedPackage],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        self.inner.install_locked(packages).await
+    }
+
+    async fn database_fingerprint(&self) -> Result<String, PackageManagerError> {
+        self.inner.database_fingerprint().await
+    }
+
+    async fn fetch_package(
+        &self,
+        package: &PinnedPackage,
+    ) -> Result<FetchedPackage, PackageManagerError> {
+        self.inner.fetch_package(package).await
+    }
+
+    async fn install_fetched(
+        &self,
+        package: &FetchedPackage,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        self.inner.install_fetched(package).await
+    }
+
+    async fn discard_fetched(&self, package: &FetchedPackage) -> Result<(), PackageManagerError> {
+        self.inner.discard_fetched(package).await
+    }
+
+    async fn unsigned_repositories(
+        &self,
+        repository: Option<&str>,
+    ) -> Result<Vec<UnsignedRepository>, PackageManagerError> {
+        self.inner.unsigned_repositories(repository).await
+    }
+
+    fn with_root(&self, root: &str) -> Result<Arc<dyn PackageManager>, PackageManagerError> {
+        Ok(Arc::new(Self {
+            inner: self.inner.with_root(root)?,
+            cache: self.cache.clone(),
+            root: Some(root.to_string()),
+        }))
+    }
+}
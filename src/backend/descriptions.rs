@@ -0,0 +1,133 @@
+//! Operator overrides of tool descriptions and server instructions
+//!
+//! The tool descriptions and the `instructions` of the server info are what
+//! agents read to plan their calls, so they are where site-specific policy
+//! belongs: which internal repositories exist, which packages need approval,
+//! what to try before adding a repository. A [`DescriptionOverrides`] file
+//! replaces the generated text or appends to it:
+//!
+//! ```toml
+//! [instructions]
+//! append = "Packages are mirrored at https://mirror.example.com; do not add public repositories."
+//!
+//! [tools.install_package]
+//! append = "Installs of packages outside the base image are reviewed weekly."
+//!
+//! [tools.search_package]
+//! replace = "Search the internal mirror for packages."
+//! ```
+//!
+//! Tools are named by their built-in names, also when they are exposed under
+//! a prefix or an alias.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::operation::Operation;
+
+/// Errors loading an overrides file
+#[derive(Debug, thiserror::Error)]
+pub enum DescriptionsError {
+    #[error("failed to read descriptions file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid descriptions file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("'{0}' is not a tool of this server")]
+    UnknownTool(String),
+}
+
+/// Replacement of, or addition to, a generated text
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextOverride {
+    /// Text used instead of the generated one
+    #[serde(default)]
+    pub replace: Option<String>,
+    /// Paragraph added after the generated or replaced text
+    #[serde(default)]
+    pub append: Option<String>,
+}
+
+/// Overrides of the server instructions and of tool descriptions
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DescriptionOverrides {
+    #[serde(default)]
+    instructions: Option<TextOverride>,
+    /// Overrides by built-in tool name
+    #[serde(default)]
+    tools: BTreeMap<String, TextOverride>,
+}
+
+impl TextOverride {
+    /// `generated` with the override applied
+    pub fn apply(&self, generated: &str) -> String {
+        let text = self.replace.as_deref().unwrap_or(generated);
+        match &self.append {
+            Some(append) => format!("{text}\n\n{append}"),
+            None => text.to_string(),
+        }
+    }
+}
+
+impl DescriptionOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Override the server instructions
+    pub fn instructions(mut self, text: TextOverride) -> Self {
+        self.instructions = Some(text);
+        self
+    }
+
+    /// Override the description of the built-in tool `name`
+    pub fn tool(mut self, name: impl Into<String>, text: TextOverride) -> Self {
+        self.tools.insert(name.into(), text);
+        self
+    }
+
+    /// Load overrides from the TOML file at `path`
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, DescriptionsError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|source| DescriptionsError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_toml(&contents)
+    }
+
+    /// Parse overrides from TOML
+    pub fn from_toml(contents: &str) -> Result<Self, DescriptionsError> {
+        let overrides: Self = toml::from_str(contents)?;
+        if let Some(name) = overrides
+            .tools
+            .keys()
+            .find(|name| !Operation::TOOL_NAMES.contains(&name.as_str()))
+        {
+            return Err(DescriptionsError::UnknownTool(name.clone()));
+        }
+        Ok(overrides)
+    }
+
+    /// Server instructions, given the generated ones
+    pub fn apply_to_instructions(&self, generated: &str) -> String {
+        match &self.instructions {
+            Some(text) => text.apply(generated),
+            None => generated.to_string(),
+        }
+    }
+
+    /// Description of the built-in tool `name`, given the generated one
+    pub fn apply_to_tool(&self, name: &str, generated: &str) -> String {
+        match self.tools.get(name) {
+            Some(text) => text.apply(generated),
+            None => generated.to_string(),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Self-diagnostics of the server for the `server_info` tool
+//!
+//! Agents plan differently on an Alpine edge container without privileges
+//! than on a Debian host whose installs are gated by approvals and
+//! vulnerability checks. A [`ServerReport`] tells them which backend and
+//! release they talk to, whether installs can succeed, where packages come
+//! from and which policies may reject their calls, so they do not have to
+//! learn it from failures.
+
+use serde::Serialize;
+
+use super::osv::Severity;
+use super::privileges::PrivilegeReport;
+use super::{Capabilities, Repository, SystemInfo};
+
+/// Environment and configuration of the server
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerReport {
+    /// Version of this server
+    pub server_version: String,
+    /// Package manager of the backend, e.g. `APK`
+    pub backend: String,
+    /// Distribution family of the backend, e.g. `Alpine Linux`
+    pub os: String,
+    #[serde(flatten)]
+    pub system: SystemInfo,
+    /// Root filesystem of the call, when not the server's target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    /// Architecture used when calls do not pass `arch`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_arch: Option<String>,
+    /// Configured repositories, when the backend can list them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repositories: Option<Vec<Repository>>,
+    /// Result of a privilege check, when the backend supports one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privileges: Option<PrivilegeReport>,
+    /// Whether installs and repository refreshes are offered
+    pub can_mutate: bool,
+    /// Optional features available through the tools
+    pub capabilities: Capabilities,
+    pub policies: PolicyReport,
+}
+
+/// Policies that can reject or change tool calls
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PolicyReport {
+    /// Whether installs may use package files, URLs and custom repositories
+    pub untrusted_sources: bool,
+    /// Whether installs may skip maintainer scripts
+    pub no_scripts: bool,
+    /// Repositories the calling client may use, any when empty
+    pub allowed_repositories: Vec<String>,
+    /// Whether repositories must be on internal mirrors
+    pub offline: bool,
+    /// Whether operations are refused while a repository is unsigned
+    pub signed_repositories_only: bool,
+    /// Least severity of the vulnerabilities blocking installs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocked_vulnerabilities: Option<Severity>,
+    /// Whether blocked installs may be retried with `override_vulnerabilities`
+    pub vulnerability_override: bool,
+    /// Whether mutating calls wait for an approval webhook
+    pub approval_required: bool,
+    /// Whether some packages must match pinned artifacts
+    pub package_pins: bool,
+    /// Whether sessions have install quotas
+    pub quotas: bool,
+    /// Whether tool calls are written to an audit log
+    pub audit_log: bool,
+    /// Whether mutating tools accept an `idempotency_key`
+    pub idempotency_keys: bool,
+    /// Whether the server refreshes stale or incomplete indexes on its own
+    pub auto_refresh: bool,
+}
+
+impl ServerReport {
+    /// Short text description of the report
+    pub fn summary(&self) -> String {
+        let mut lines = Vec::new();
+        let mut system = format!("{} on {}", self.backend, self.os);
+        if let Some(release) = &self.system.distro_release {
+            system.push_str(&format!(" ({release})"));
+        }
+        if let Some(version) = &self.system.package_manager_version {
+            system.push_str(&format!(", {version}"));
+        }
+        if let Some(root) = &self.root {
+            system.push_str(&format!(", root {root}"));
+        }
+        lines.push(system);
+
+        let problems = self
+            .privileges
+            .as_ref()
+            .map(|report| report.problems.as_slice())
+            .unwrap_or_default();
+        lines.push(match (self.can_mutate, problems) {
+            (true, _) => "Installs and repository refreshes are available.".to_string(),
+            (false, []) => "Installs and repository refreshes are unavailable.".to_string(),
+            (false, problems) => format!(
+                "Installs and repository refreshes are unavailable: {}",
+                problems.join("; ")
+            ),
+        });
+
+        if let Some(repositories) = &self.repositories {
+            let urls: Vec<&str> = repositories
+                .iter()
+                .map(|repository| repository.url.as_str())
+                .collect();
+            lines.push(format!("Repositories: {}", urls.join(", ")));
+        }
+
+        let policies = self.policies.enabled();
+        if !policies.is_empty() {
+            lines.push(format!("Policies: {}", policies.join(", ")));
+        }
+        lines.join("\n")
+    }
+}
+
+impl PolicyReport {
+    /// Descriptions of the policies in effect
+    fn enabled(&self) -> Vec<String> {
+        let mut policies = Vec::new();
+        if !self.untrusted_sources {
+            policies.push("no package files, URLs or custom repositories".to_string());
+        }
+        if !self.allowed_repositories.is_empty() {
+            policies.push(format!(
+                "repositories limited to {}",
+                self.allowed_repositories.join(", ")
+            ));
+        }
+        if self.offline {
+            policies.push("offline mirrors only".to_string());
+        }
+        if self.signed_repositories_only {
+            policies.push("signed repositories only".to_string());
+        }
+        if let Some(severity) = self.blocked_vulnerabilities {
+            let overridable = if self.vulnerability_override {
+                ", overridable"
+            } else {
+                ""
+            };
+            policies.push(format!(
+                "installs with {severity} or more severe vulnerabilities blocked{overridable}"
+            ));
+        }
+        if self.approval_required {
+            policies.push("mutating calls need approval".to_string());
+        }
+        if self.package_pins {
+            policies.push("pinned package artifacts".to_string());
+        }
+        if self.quotas {
+            policies.push("session quotas".to_string());
+        }
+        if self.audit_log {
+            policies.push("audit log".to_string());
+        }
+        if self.auto_refresh {
+            policies.push("automatic index refresh".to_string());
+        }
+        policies
+    }
+}
@@ -0,0 +1,429 @@
+//! Preflight checks of the environment for the `doctor` tool
+//!
+//! When every install fails, the error of a single call rarely tells why:
+//! `apk add` reports a missing package when the repository is unreachable,
+//! `apt-get` a lock error when another process is upgrading. The `doctor`
+//! tool runs a battery of [`HealthCheck`]s (package manager present,
+//! privileges, package database lock, disk space, repositories reachable and
+//! signed, proxy configuration) and reports each as passed, a warning or a
+//! failure, with a hint on how to fix it.
+//!
+//! Backends contribute the checks that need their commands and files through
+//! `PackageManager::health_checks`; the handler adds the ones built on other
+//! backend methods and on the server's own network.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::parse::{parse_df, parse_lslocks, parse_version_line};
+use super::runner::{Command, CommandRunner};
+use super::{Repository, SpaceRequirement, command};
+
+/// How long a repository may take to answer
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Free space below which installs may fail
+const LOW_DISK_SPACE: u64 = 500 * 1024 * 1024;
+
+/// Free space below which installs most likely fail
+const MIN_DISK_SPACE: u64 = 50 * 1024 * 1024;
+
+/// Outcome of a check, ordered from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Result of one check
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    /// What was checked, e.g. `disk_space`
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+    /// How to fix a warning or failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remediation: Option<String>,
+}
+
+/// Results of every check, with the worst status among them
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DoctorReport {
+    pub status: CheckStatus,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthCheck {
+    pub fn new(name: impl Into<String>, status: CheckStatus, message: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            message: message.into(),
+            remediation: None,
+        }
+    }
+
+    pub fn pass(name: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Pass, message)
+    }
+
+    pub fn warn(name: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Warn, message)
+    }
+
+    pub fn fail(name: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Fail, message)
+    }
+
+    pub fn with_remediation(mut self, remediation: impl Into<String>) -> Self {
+        self.remediation = Some(remediation.into());
+        self
+    }
+}
+
+impl DoctorReport {
+    pub fn new(checks: Vec<HealthCheck>) -> Self {
+        let status = checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(CheckStatus::Pass);
+        Self { status, checks }
+    }
+
+    /// One line per check, failures and warnings with their remediation
+    pub fn summary(&self) -> String {
+        let mut lines = vec![match self.status {
+            CheckStatus::Pass => "Every check passed.".to_string(),
+            CheckStatus::Warn => "Some checks raised warnings:".to_string(),
+            CheckStatus::Fail => "Some checks failed:".to_string(),
+        }];
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Pass => "pass",
+                CheckStatus::Warn => "WARN",
+                CheckStatus::Fail => "FAIL",
+            };
+            let mut line = format!("[{status}] {}: {}", check.name, check.message);
+            if let Some(remediation) = &check.remediation
+                && check.status != CheckStatus::Pass
+            {
+                line.push_str(&format!(" Fix: {remediation}"));
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+/// Whether `command`, printing the package manager's version, runs
+pub(crate) async fn check_program(
+    runner: &dyn CommandRunner,
+    command: &Command,
+    package: &str,
+) -> HealthCheck {
+    let name = "package_manager";
+    let program = &command.program;
+    match runner.run(command).await {
+        Ok(result) if result.status == 0 => HealthCheck::pass(
+            name,
+            result
+                .stdout
+                .as_deref()
+                .and_then(parse_version_line)
+                .unwrap_or_else(|| format!("{program} runs")),
+        ),
+        Ok(result) => HealthCheck::fail(
+            name,
+            format!("{program} exited with status {}", result.status),
+        )
+        .with_remediation(format!(
+            "Check that the {package} package is installed and intact."
+        )),
+        Err(err) => HealthCheck::fail(name, format!("{program} could not be run: {err}"))
+            .with_remediation(format!(
+                "Install the {package} package or add {program} to the PATH of the server."
+            )),
+    }
+}
+
+/// Free space of the file systems holding `paths`, one check per file
+/// system
+pub(crate) async fn check_disk_space(
+    runner: &dyn CommandRunner,
+    paths: &[String],
+) -> Vec<HealthCheck> {
+    let name = "disk_space";
+    let filesystems = match free_space(runner, paths).await {
+        Ok(filesystems) if !filesystems.is_empty() => filesystems,
+        Ok(_) => {
+            return vec![HealthCheck::warn(
+                name,
+                format!("the free space of {} is unknown", paths.join(", ")),
+            )];
+        }
+        Err(err) => {
+            return vec![HealthCheck::warn(
+                name,
+                format!("df could not be run: {err}"),
+            )];
+        }
+    };
+
+    filesystems
+        .into_iter()
+        .map(|(mount, available)| {
+            let message = format!("{} free on {mount}", format_bytes(available));
+            let remediation =
+                format!("Free space on {mount}, e.g. by removing package caches or old logs.");
+            if available < MIN_DISK_SPACE {
+                HealthCheck::fail(name, message).with_remediation(remediation)
+            } else if available < LOW_DISK_SPACE {
+                HealthCheck::warn(name, message).with_remediation(remediation)
+            } else {
+                HealthCheck::pass(name, message)
+            }
+        })
+        .collect()
+}
+
+/// Mount point and available bytes of the file systems holding `paths`,
+/// each listed once
+///
+/// Paths that do not exist are left out.
+pub(crate) async fn free_space(
+    runner: &dyn CommandRunner,
+    paths: &[String],
+) -> std::io::Result<Vec<(String, u64)>> {
+    let result = runner.run(command("df").arg("-Pk").args(paths)).await?;
+    let mut filesystems: Vec<(String, u64)> = Vec::new();
+    for (mount, available) in parse_df(result.stdout.as_deref().unwrap_or_default()) {
+        if !filesystems.iter().any(|(listed, _)| *listed == mount) {
+            filesystems.push((mount, available));
+        }
+    }
+    Ok(filesystems)
+}
+
+/// Space free on the file systems holding the paths of `needs`, each giving
+/// the bytes an install writes under a path
+///
+/// Needs of paths on the same file system add up. Paths whose file system
+/// is unknown are left out.
+pub(crate) async fn space_requirements(
+    runner: &dyn CommandRunner,
+    needs: &[(String, u64)],
+) -> std::io::Result<Vec<SpaceRequirement>> {
+    let mut requirements: Vec<SpaceRequirement> = Vec::new();
+    for (path, needed) in needs {
+        let Some((mount, available)) = free_space(runner, std::slice::from_ref(path))
+            .await?
+            .into_iter()
+            .next()
+        else {
+            continue;
+        };
+        match requirements
+            .iter_mut()
+            .find(|requirement| requirement.mount == mount)
+        {
+            Some(requirement) => requirement.needed += needed,
+            None => requirements.push(SpaceRequirement {
+                mount,
+                needed: *needed,
+                available,
+            }),
+        }
+    }
+    Ok(requirements)
+}
+
+/// Whether another process holds the package database lock `lock_file`,
+/// which the package manager takes with `flock(2)` (APK)
+///
+/// Takes the lock with `flock` without waiting and releases it at once.
+pub(crate) async fn check_lock(runner: &dyn CommandRunner, lock_file: &str) -> HealthCheck {
+    let name = "lock";
+    let result = runner
+        .run(
+            command("flock")
+                .privileged()
+                .args(["--nonblock", lock_file, "true"]),
+        )
+        .await;
+    match result {
+        Ok(result) if result.status == 0 => HealthCheck::pass(name, format!("{lock_file} is free")),
+        Ok(result)
+            if result.status == 1
+                && result
+                    .stderr
+                    .as_deref()
+                    .is_none_or(|stderr| stderr.trim().is_empty()) =>
+        {
+            HealthCheck::fail(name, format!("another process holds {lock_file}")).with_remediation(
+                "Wait for the running package manager (e.g. unattended upgrades) to finish, \
+                    or stop it if it is stuck.",
+            )
+        }
+        Ok(result) => HealthCheck::warn(
+            name,
+            format!(
+                "{lock_file} could not be checked: {}",
+                result.stderr.as_deref().unwrap_or_default().trim()
+            ),
+        ),
+        Err(err) => HealthCheck::warn(
+            name,
+            format!("{lock_file} could not be checked: flock could not be run: {err}"),
+        ),
+    }
+}
+
+/// Whether another process holds the package database lock `lock_file`,
+/// which the package manager takes with `fcntl(2)` (dpkg and APT)
+///
+/// `flock` cannot see `fcntl` locks, and taking the lock would create the
+/// file, so the locks the kernel holds are listed with `lslocks` instead.
+/// It runs privileged to resolve the paths of other users' locks.
+pub(crate) async fn check_fcntl_lock(runner: &dyn CommandRunner, lock_file: &str) -> HealthCheck {
+    let name = "lock";
+    let result = runner
+        .run(command("lslocks").privileged().args([
+            "--noheadings",
+            "--raw",
+            "--output",
+            "PID,COMMAND,PATH",
+        ]))
+        .await;
+    match result {
+        Ok(result) if result.status == 0 => {
+            let locks = parse_lslocks(result.stdout.as_deref().unwrap_or_default());
+            match locks.iter().find(|(_, _, path)| path == lock_file) {
+                Some((pid, holder, _)) => HealthCheck::fail(
+                    name,
+                    format!("process {pid} ({holder}) holds {lock_file}"),
+                )
+                .with_remediation(
+                    "Wait for the running package manager (e.g. unattended upgrades) to finish, \
+                        or stop it if it is stuck.",
+                ),
+                None => HealthCheck::pass(name, format!("{lock_file} is free")),
+            }
+        }
+        Ok(result) => HealthCheck::warn(
+            name,
+            format!(
+                "{lock_file} could not be checked: {}",
+                result.stderr.as_deref().unwrap_or_default().trim()
+            ),
+        ),
+        Err(err) => HealthCheck::warn(
+            name,
+            format!("{lock_file} could not be checked: lslocks could not be run: {err}"),
+        ),
+    }
+}
+
+/// Whether the HTTP(S) repositories answer requests from the server
+///
+/// Any HTTP response counts: repository roots often answer 403 or 404.
+pub(crate) async fn check_reachability(repositories: &[Repository]) -> Vec<HealthCheck> {
+    let name = "repository_reachable";
+    let client = match reqwest::Client::builder()
+        .timeout(REACHABILITY_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            return vec![HealthCheck::warn(
+                name,
+                format!("no HTTP client is available: {err}"),
+            )];
+        }
+    };
+
+    let mut tasks = tokio::task::JoinSet::new();
+    let urls = repositories
+        .iter()
+        .map(|repository| repository.url.clone())
+        .filter(|url| url.starts_with("http://") || url.starts_with("https://"));
+    for (position, url) in urls.enumerate() {
+        let client = client.clone();
+        tasks.spawn(async move {
+            let response = client.head(&url).send().await;
+            (position, url, response)
+        });
+    }
+    let mut responses = tasks.join_all().await;
+    responses.sort_by_key(|(position, _, _)| *position);
+
+    responses
+        .into_iter()
+        .map(|(_, url, response)| match response {
+            Ok(response) => HealthCheck::pass(
+                name,
+                format!("{url} answered with HTTP {}", response.status().as_u16()),
+            ),
+            Err(err) => HealthCheck::fail(name, format!("{url} is unreachable: {err}"))
+                .with_remediation(
+                    "Check the network and DNS of the server, set HTTPS_PROXY if it needs a proxy, \
+                    or replace the repository with a reachable mirror.",
+                ),
+        })
+        .collect()
+}
+
+/// Proxy the server's requests go through, from its environment
+pub(crate) fn check_proxy() -> HealthCheck {
+    let name = "proxy";
+    let variable = |names: [&str; 2]| {
+        names
+            .into_iter()
+            .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+            .map(|value| redact_userinfo(&value))
+    };
+    let http = variable(["http_proxy", "HTTP_PROXY"]);
+    let https = variable(["https_proxy", "HTTPS_PROXY"]);
+    match (http, https) {
+        (None, None) => HealthCheck::pass(name, "no proxy is configured"),
+        (Some(http), None) => HealthCheck::warn(
+            name,
+            format!("HTTP_PROXY is {http} but HTTPS_PROXY is not set"),
+        )
+        .with_remediation("Set HTTPS_PROXY too, so requests to HTTPS repositories use the proxy."),
+        (None, Some(https)) => {
+            HealthCheck::pass(name, format!("HTTPS requests go through {https}"))
+        }
+        (Some(http), Some(https)) => HealthCheck::pass(
+            name,
+            format!("HTTP requests go through {http}, HTTPS requests through {https}"),
+        ),
+    }
+}
+
+/// `url` without the password of its userinfo
+fn redact_userinfo(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    match rest.split_once('@') {
+        Some((userinfo, host)) if !userinfo.contains('/') => match userinfo.split_once(':') {
+            Some((user, _)) => format!("{scheme}://{user}:***@{host}"),
+            None => url.to_string(),
+        },
+        _ => url.to_string(),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const MIB: u64 = 1024 * 1024;
+    if bytes >= 1024 * MIB {
+        format!("{:.1} GiB", bytes as f64 / (1024 * MIB) as f64)
+    } else {
+        format!("{} MiB", bytes / MIB)
+    }
+}
@@ -0,0 +1,104 @@
+//! Detection of changes to the installed packages, including those made
+//! outside the server
+//!
+//! Clients subscribing to the `state://installed-manifest` resource want to
+//! know when the installed set drifts, whether an agent installed a package
+//! through the server or someone ran `apk add` in a shell. Package managers
+//! offer no change notifications, so a [`DriftWatcher`] polls the backend's
+//! [`database_fingerprint`](super::PackageManager::database_fingerprint), a
+//! digest of its installed database (`/lib/apk/db/installed`,
+//! `/var/lib/dpkg/status`), and notifies the subscribed client whenever it
+//! changes.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ring::digest::{SHA256, digest};
+use rmcp::{Peer, RoleServer, model::ResourceUpdatedNotificationParam};
+
+use super::PackageManager;
+use super::cache::InstalledCache;
+use crate::error::PackageManagerError;
+
+/// How often the installed database is checked by default
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Polling task of one session's subscription
+#[derive(Debug, Default)]
+pub(crate) struct DriftWatcher {
+    task: Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+impl DriftWatcher {
+    /// Notify `peer` of updates of the resource `uri` whenever the
+    /// fingerprint of `backend`'s database changes, replacing the previous
+    /// subscription
+    ///
+    /// The current fingerprint is taken before returning, so changes made
+    /// after the subscription are never missed. Changes also clear the
+    /// `installed` listings, which no longer describe the system.
+    pub(crate) async fn watch(
+        &self,
+        backend: Arc<dyn PackageManager>,
+        installed: Option<Arc<InstalledCache>>,
+        peer: Peer<RoleServer>,
+        uri: String,
+        interval: Duration,
+    ) -> Result<(), PackageManagerError> {
+        let mut fingerprint = backend.database_fingerprint().await?;
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let current = match backend.database_fingerprint().await {
+                    Ok(current) => current,
+                    Err(err) => {
+                        tracing::warn!("Failed to check the installed packages for changes: {err}");
+                        continue;
+                    }
+                };
+                if current == fingerprint {
+                    continue;
+                }
+                fingerprint = current;
+                if let Some(installed) = &installed {
+                    installed.clear();
+                }
+                let notification = ResourceUpdatedNotificationParam { uri: uri.clone() };
+                if peer.notify_resource_updated(notification).await.is_err() {
+                    // The session is gone
+                    break;
+                }
+            }
+        });
+        if let Some(previous) = self.lock_task().replace(task) {
+            previous.abort();
+        }
+        Ok(())
+    }
+
+    /// Stop notifying the client
+    pub(crate) fn stop(&self) {
+        if let Some(task) = self.lock_task().take() {
+            task.abort();
+        }
+    }
+
+    fn lock_task(&self) -> std::sync::MutexGuard<'_, Option<tokio::task::JoinHandle<()>>> {
+        self.task.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Drop for DriftWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Fingerprint of a package database with the given content
+pub(crate) fn fingerprint(content: &[u8]) -> String {
+    digest(&SHA256, content)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
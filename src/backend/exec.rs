@@ -0,0 +1,238 @@
+//! Command execution with line-by-line output streaming
+//!
+//! [`LocalRunner`](super::runner::LocalRunner) runs commands through
+//! [`output`], which captures stdout and stderr like
+//! [`tokio::process::Command::output`] but also forwards every line to the
+//! output sink installed with [`stream_output`] while the command runs. Other
+//! runners forward their output with [`emit`].
+//!
+//! At most [`output_limit`] bytes of each stream are kept: a command dumping
+//! gigabytes keeps the start and the end of its output, with a marker in
+//! place of the rest, and its result is flagged as truncated.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+
+use super::ExecResult;
+
+/// Bytes of each output stream kept by default
+pub const DEFAULT_OUTPUT_LIMIT: usize = 8 * 1024 * 1024;
+
+/// Longest line forwarded to the output sink, longer lines are cut
+const MAX_LINE: usize = 64 * 1024;
+
+static OUTPUT_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_OUTPUT_LIMIT);
+
+/// Keep at most `bytes` of the stdout and of the stderr of every command
+///
+/// Applies to the commands started afterwards, in the whole process.
+pub fn set_output_limit(bytes: usize) {
+    OUTPUT_LIMIT.store(bytes.max(2), Ordering::Relaxed);
+}
+
+/// Bytes of each output stream kept, see [`set_output_limit`]
+pub fn output_limit() -> usize {
+    OUTPUT_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Stream a line of output was written to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A line of output produced by a running command
+#[derive(Clone, Debug)]
+pub struct OutputLine {
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+/// Sending half of the channel output lines are forwarded to
+pub type OutputSender = mpsc::UnboundedSender<OutputLine>;
+
+tokio::task_local! {
+    static OUTPUT_SINK: OutputSender;
+}
+
+/// Run a future with the output of every command it spawns forwarded to `sender`
+///
+/// The sender is dropped once the future completes, closing the channel.
+pub async fn stream_output<F: Future>(sender: OutputSender, future: F) -> F::Output {
+    OUTPUT_SINK.scope(sender, future).await
+}
+
+/// Forward a line to the current output sink, if any
+///
+/// Used for output that does not come from a command run through [`output`].
+pub fn emit(line: OutputLine) {
+    let _ = OUTPUT_SINK.try_with(|sink| sink.send(line));
+}
+
+/// The current output sink, for forwarding output from spawned tasks
+pub(crate) fn current_sink() -> Option<OutputSender> {
+    OUTPUT_SINK.try_with(|sink| sink.clone()).ok()
+}
+
+/// Run the command to completion, capturing its output
+///
+/// `input` is written to the command's stdin, which is closed otherwise.
+/// Lines are forwarded to the current output sink, if any, as they are
+/// produced instead of once the command exits.
+pub(crate) async fn output(
+    command: &mut tokio::process::Command,
+    input: Option<&str>,
+) -> std::io::Result<ExecResult> {
+    command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = command.spawn()?;
+    let sink = current_sink();
+
+    let stdin = child.stdin.take();
+    let write_input = async move {
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            stdin.write_all(input.as_bytes()).await?;
+            // Dropping stdin closes it so the command sees end of input
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| std::io::Error::other("child stdout was not captured"))?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| std::io::Error::other("child stderr was not captured"))?;
+
+    let limit = output_limit();
+    let ((), stdout, stderr, status) = tokio::try_join!(
+        write_input,
+        read_lines(stdout, OutputStream::Stdout, sink.as_ref(), limit),
+        read_lines(stderr, OutputStream::Stderr, sink.as_ref(), limit),
+        child.wait(),
+    )?;
+
+    let truncated = stdout.is_truncated() || stderr.is_truncated();
+    let mut result = ExecResult::new(
+        &stdout.into_bytes(),
+        &stderr.into_bytes(),
+        status.code().unwrap_or(-1),
+    );
+    result.truncated = truncated;
+    Ok(result)
+}
+
+/// Read the stream to its end, forwarding its lines to the sink
+///
+/// The stream is read in chunks rather than lines, so a command writing a
+/// huge amount of data without a newline cannot exhaust the memory either.
+async fn read_lines(
+    reader: impl AsyncRead + Unpin,
+    stream: OutputStream,
+    sink: Option<&OutputSender>,
+    limit: usize,
+) -> std::io::Result<BoundedOutput> {
+    let mut reader = BufReader::new(reader);
+    let mut output = BoundedOutput::new(limit);
+    let mut line = Vec::new();
+    let forward = |line: &[u8]| {
+        if let Some(sink) = sink {
+            // The receiver going away must not interrupt the command
+            let _ = sink.send(OutputLine {
+                stream,
+                line: String::from_utf8_lossy(line).trim_end().to_string(),
+            });
+        }
+    };
+
+    loop {
+        let chunk = reader.fill_buf().await?;
+        if chunk.is_empty() {
+            break;
+        }
+        let (chunk, ends_line) = match chunk.iter().position(|&byte| byte == b'\n') {
+            Some(end) => (&chunk[..=end], true),
+            None => (chunk, false),
+        };
+
+        output.push(chunk);
+        let room = MAX_LINE.saturating_sub(line.len());
+        line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        if ends_line {
+            forward(&line);
+            line.clear();
+        }
+
+        let read = chunk.len();
+        reader.consume(read);
+    }
+    if !line.is_empty() {
+        forward(&line);
+    }
+
+    Ok(output)
+}
+
+/// Output of a stream, keeping its start and end when it exceeds the limit
+struct BoundedOutput {
+    limit: usize,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    /// Bytes dropped between the head and the tail
+    dropped: usize,
+}
+
+impl BoundedOutput {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, mut bytes: &[u8]) {
+        let head_room = (self.limit / 2).saturating_sub(self.head.len());
+        let (head, rest) = bytes.split_at(bytes.len().min(head_room));
+        self.head.extend_from_slice(head);
+        bytes = rest;
+
+        self.tail.extend(bytes);
+        let excess = self.tail.len().saturating_sub(self.limit - self.limit / 2);
+        self.tail.drain(..excess);
+        self.dropped += excess;
+    }
+
+    fn is_truncated(&self) -> bool {
+        self.dropped > 0
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.head;
+        if self.dropped > 0 {
+            bytes.extend_from_slice(
+                format!("\n[... {} bytes of output truncated ...]\n", self.dropped).as_bytes(),
+            );
+        }
+        bytes.extend(self.tail);
+        bytes
+    }
+}
@@ -0,0 +1,163 @@
+//! Meaning of the exit statuses of package manager commands
+//!
+//! The raw `exit_code` of a failed command means different things on
+//! different distributions: `apt-get` exits with 100 on any error, `dpkg`
+//! with 1 or 2, and `apk` with the number of errors it ran into. Each
+//! backend translates the statuses of its commands into an [`ExitMeaning`]
+//! through `PackageManager::exit_meaning`, and the handler adds it to the
+//! data of failed calls next to the `exit_code`, with the failure class it
+//! implies when the output did not give one.
+
+use rmcp::ErrorData as McpError;
+use serde::Serialize;
+
+use super::PackageManager;
+use super::parse::classify_failure;
+use crate::error::ErrorCode;
+
+/// `apt-get` output when the `dpkg` run it started failed
+const DPKG_SUBPROCESS_FAILED: &str = "Sub-process /usr/bin/dpkg returned an error code";
+
+/// Prefix of the messages of `dpkg`
+const DPKG_MESSAGE: &str = "dpkg: ";
+
+/// What the exit status of a failed command means
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ExitMeaning {
+    /// What happened, e.g. `apt-get reported an error`
+    pub meaning: String,
+    /// Class of the failure, when the status alone tells it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+    /// What to do about it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl ExitMeaning {
+    fn new(meaning: impl Into<String>) -> Self {
+        Self {
+            meaning: meaning.into(),
+            error_code: None,
+            hint: None,
+        }
+    }
+
+    fn with_error_code(mut self, error_code: ErrorCode) -> Self {
+        self.error_code = Some(error_code);
+        self
+    }
+
+    fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Meaning of the statuses every command shares: those set by the shell
+/// and by signals
+pub fn common(status: i32) -> Option<ExitMeaning> {
+    let meaning = match status {
+        // No exit code: the local process was killed by a signal
+        -1 => ExitMeaning::new("the command was killed by a signal"),
+        126 => ExitMeaning::new("the command could not be executed")
+            .with_error_code(ErrorCode::PermissionDenied)
+            .with_hint("Check that the package manager is executable by the server's user."),
+        127 => ExitMeaning::new("the command was not found")
+            .with_hint("Install the package manager or add it to the PATH of the server."),
+        137 => ExitMeaning::new("the command was killed (SIGKILL)").with_hint(
+            "The process may have run out of memory or hit a timeout: check the memory \
+            limits of the container and retry.",
+        ),
+        143 => ExitMeaning::new("the command was terminated (SIGTERM)")
+            .with_hint("Check whether the server or its container is shutting down, and retry."),
+        129..=192 => ExitMeaning::new(format!("the command was killed by signal {}", status - 128)),
+        _ => return None,
+    };
+    Some(meaning)
+}
+
+/// Meaning of the statuses of `apt-get` and the `dpkg` runs it starts
+pub fn apt(status: i32, output: &str) -> Option<ExitMeaning> {
+    let meaning = match status {
+        100 if output.contains(DPKG_SUBPROCESS_FAILED) => {
+            ExitMeaning::new("dpkg failed while unpacking or configuring packages")
+                .with_error_code(ErrorCode::BrokenState)
+                .with_hint(
+                    "Read the dpkg errors in the output, run 'dpkg --configure -a' and \
+                    'apt-get --fix-broken install', then retry.",
+                )
+        }
+        100 => ExitMeaning::new("apt-get reported an error")
+            .with_hint("The lines starting with 'E:' in the output give the cause."),
+        1 if output.contains(DPKG_MESSAGE) => {
+            ExitMeaning::new("dpkg could not perform the requested action").with_hint(
+                "The lines starting with 'dpkg:' in the output give the cause, often a \
+                missing dependency or a package in a broken state.",
+            )
+        }
+        2 if output.contains(DPKG_MESSAGE) => ExitMeaning::new("dpkg hit a fatal error").with_hint(
+            "Check that the package file is intact and that the dpkg database under \
+                /var/lib/dpkg is readable.",
+        ),
+        _ => return common(status),
+    };
+    Some(meaning)
+}
+
+/// Meaning of the statuses of `apk`, which exits with the number of errors
+/// it ran into
+pub fn apk(status: i32) -> Option<ExitMeaning> {
+    let meaning = match status {
+        1 => "apk reported an error".to_string(),
+        2..=125 => format!("apk reported {status} errors"),
+        _ => return common(status),
+    };
+    Some(
+        ExitMeaning::new(meaning)
+            .with_hint("The lines starting with 'ERROR:' in the output give the cause."),
+    )
+}
+
+/// Add the meaning of the `exit_code` in the data of `err`, if any, as
+/// `backend` reports it
+///
+/// Sets `exit_meaning` and, unless the error has them, a `suggestion` from
+/// the hint and an `error_code` from the status when the output does not
+/// classify the failure.
+pub(crate) fn explain(backend: &dyn PackageManager, mut err: McpError) -> McpError {
+    let Some(serde_json::Value::Object(data)) = &mut err.data else {
+        return err;
+    };
+    let Some(status) = data
+        .get("exit_code")
+        .and_then(serde_json::Value::as_i64)
+        .and_then(|status| i32::try_from(status).ok())
+    else {
+        return err;
+    };
+    let output = ["stderr", "stdout"]
+        .into_iter()
+        .filter_map(|stream| data.get(stream).and_then(serde_json::Value::as_str))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let Some(meaning) = backend.exit_meaning(status, &output) else {
+        return err;
+    };
+
+    data.insert("exit_meaning".to_string(), meaning.meaning.into());
+    if let Some(hint) = meaning.hint {
+        data.entry("suggestion").or_insert(hint.into());
+    }
+    let classified = data
+        .get("error_code")
+        .and_then(serde_json::Value::as_str)
+        .is_some_and(|code| code != ErrorCode::Unknown.as_str())
+        || classify_failure(&output).is_some();
+    if let Some(code) = meaning.error_code
+        && !classified
+    {
+        data.insert("error_code".to_string(), serde_json::json!(code));
+    }
+    err
+}
@@ -0,0 +1,248 @@
+//! Automatic refresh of stale repository indexes
+//!
+//! Installs and searches only see the packages of the indexes the package
+//! manager downloaded last: a package published since then is "not found",
+//! and a container image built months ago offers versions the mirrors
+//! already deleted. Agents otherwise have to learn to call
+//! `refresh_repositories` and retry. With [`AutoRefresh`], the handler
+//! refreshes the indexes before an install or search when they are older
+//! than a maximum age, and refreshes and retries once when one fails because
+//! a package or version is missing, reporting in the result that it did.
+//! Searches that legitimately find nothing would otherwise refresh on every
+//! call, so a miss only refreshes when the last refresh is older than a
+//! minimum interval. Automatic refreshes are audited like calls of
+//! `refresh_repositories`.
+//!
+//! The time of the last refresh is the later of the refreshes made through
+//! the server, per root, and the modification time of the backend's index
+//! files, which also covers refreshes made outside the server.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use rmcp::ErrorData as McpError;
+use rmcp::model::CallToolResult;
+use serde::Serialize;
+
+use super::PackageManager;
+use super::command;
+use super::runner::CommandRunner;
+use crate::error::{ErrorCode, PackageManagerError};
+
+/// Time a missing package waits for since the last refresh before it
+/// refreshes the indexes again
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// When the handler refreshes repository indexes on its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AutoRefresh {
+    max_age: Option<Duration>,
+    min_interval: Duration,
+}
+
+impl Default for AutoRefresh {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            min_interval: DEFAULT_MIN_INTERVAL,
+        }
+    }
+}
+
+impl AutoRefresh {
+    /// Refresh and retry once when an install or search misses a package
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only refresh for a missing package when the indexes were last
+    /// refreshed more than `min_interval` ago
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    /// Also refresh before installs and searches when the indexes are older
+    /// than `max_age`, or their age is unknown
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age
+    }
+}
+
+/// Why the indexes were refreshed before a call ran
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum RefreshReason {
+    /// The indexes were older than the maximum age, or of unknown age
+    Stale {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        age_secs: Option<u64>,
+    },
+    /// The call failed because a package or version was missing, and was
+    /// retried
+    NotFound,
+}
+
+impl RefreshReason {
+    /// Note added to the result of the call
+    pub fn note(&self) -> String {
+        match self {
+            Self::Stale {
+                age_secs: Some(age),
+            } => format!(
+                "The repository indexes were refreshed first, since they were {age} seconds old."
+            ),
+            Self::Stale { age_secs: None } => {
+                "The repository indexes were refreshed first, since their age is unknown."
+                    .to_string()
+            }
+            Self::NotFound => "The package was missing from the repository indexes, so they were \
+                refreshed and the call retried."
+                .to_string(),
+        }
+    }
+}
+
+/// What [`IndexFreshness::refresh_unless_since`] did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MissRefresh {
+    /// The indexes were refreshed
+    Refreshed,
+    /// Another call refreshed them since the call started
+    RefreshedConcurrently,
+    /// They were refreshed less than the minimum interval ago
+    TooRecent,
+}
+
+/// Times the indexes were refreshed through the server, by root
+#[derive(Debug, Default)]
+pub(crate) struct IndexFreshness {
+    refreshed: Mutex<HashMap<Option<String>, SystemTime>>,
+    /// Held while a refresh runs, so concurrent calls refresh once
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl IndexFreshness {
+    /// Record a successful refresh of the indexes of `root`
+    pub(crate) fn record(&self, root: Option<&str>) {
+        self.lock_refreshed()
+            .insert(root.map(str::to_string), SystemTime::now());
+    }
+
+    /// When the indexes of `root` were last refreshed, if known
+    pub(crate) async fn refreshed_at(
+        &self,
+        backend: &dyn PackageManager,
+        root: Option<&str>,
+    ) -> Option<SystemTime> {
+        let recorded = self
+            .lock_refreshed()
+            .get(&root.map(str::to_string))
+            .copied();
+        let reported = backend.indexes_refreshed_at().await.ok().flatten();
+        recorded.max(reported)
+    }
+
+    /// Refresh the indexes of `root` if they are older than `max_age`
+    ///
+    /// Returns why they were refreshed, `None` when they were fresh.
+    pub(crate) async fn refresh_if_stale(
+        &self,
+        backend: &dyn PackageManager,
+        root: Option<&str>,
+        max_age: Duration,
+    ) -> Result<Option<RefreshReason>, PackageManagerError> {
+        let _refreshing = self.refreshing.lock().await;
+        let age = self
+            .refreshed_at(backend, root)
+            .await
+            .map(|at| at.elapsed().unwrap_or_default());
+        if age.is_some_and(|age| age <= max_age) {
+            return Ok(None);
+        }
+        backend.refresh_repositories().await?;
+        self.record(root);
+        Ok(Some(RefreshReason::Stale {
+            age_secs: age.map(|age| age.as_secs()),
+        }))
+    }
+
+    /// Refresh the indexes of `root` unless they were refreshed since
+    /// `since`, e.g. by a concurrent call, or less than `min_interval` ago
+    pub(crate) async fn refresh_unless_since(
+        &self,
+        backend: &dyn PackageManager,
+        root: Option<&str>,
+        since: SystemTime,
+        min_interval: Duration,
+    ) -> Result<MissRefresh, PackageManagerError> {
+        let _refreshing = self.refreshing.lock().await;
+        let recorded = self
+            .lock_refreshed()
+            .get(&root.map(str::to_string))
+            .copied();
+        if recorded.is_some_and(|at| at >= since) {
+            return Ok(MissRefresh::RefreshedConcurrently);
+        }
+        let last = self.refreshed_at(backend, root).await;
+        if last.is_some_and(|at| at.elapsed().unwrap_or_default() < min_interval) {
+            return Ok(MissRefresh::TooRecent);
+        }
+        backend.refresh_repositories().await?;
+        self.record(root);
+        Ok(MissRefresh::Refreshed)
+    }
+
+    fn lock_refreshed(&self) -> std::sync::MutexGuard<'_, HashMap<Option<String>, SystemTime>> {
+        self.refreshed.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Whether a call failed because a package or version is missing from the
+/// indexes, or a search found nothing
+pub(crate) fn missing_package(result: &Result<CallToolResult, McpError>, search: bool) -> bool {
+    match result {
+        Err(err) => {
+            let code = err
+                .data
+                .as_ref()
+                .and_then(|data| data["error_code"].as_str());
+            match code {
+                Some(code) => code == ErrorCode::NotFound.as_str(),
+                None => ErrorCode::of(err) == ErrorCode::NotFound,
+            }
+        }
+        // Searches finding nothing only have their text message
+        Ok(result) => {
+            search
+                && result.is_error != Some(true)
+                && !result.content.iter().any(|content| {
+                    content.as_text().is_some_and(|text| {
+                        serde_json::from_str::<serde_json::Value>(&text.text).is_ok()
+                    })
+                })
+        }
+    }
+}
+
+/// Modification time of `path`, from `stat`
+pub(crate) async fn modified_at(runner: &dyn CommandRunner, path: &str) -> Option<SystemTime> {
+    let result = runner
+        .run(command("stat").args(["-c", "%Y", path]))
+        .await
+        .ok()?
+        .success()
+        .ok()?;
+    let seconds: u64 = result.stdout.as_deref()?.trim().parse().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
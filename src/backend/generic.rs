@@ -0,0 +1,476 @@
+//! Backend driven entirely by a TOML configuration file
+//!
+//! Every operation is described by a command template (an argument vector,
+//! never passed through a shell) and, where the operation returns data, a
+//! regular expression applied to each line of stdout. Placeholders of the
+//! form `{name}` in template arguments are replaced with the request values:
+//!
+//! ```toml
+//! name = "XBPS"
+//! os_name = "Void Linux"
+//!
+//! [install]
+//! command = ["xbps-install", "-y", "{package}"]
+//! repository_args = ["--repository", "{repository}"]
+//! pattern = '^(?P<name>\S+)-(?P<version>[^-\s]+_\d+): installed successfully'
+//!
+//! [install_version]
+//! command = ["xbps-install", "-y", "{package}-{version}"]
+//!
+//! [search]
+//! command = ["xbps-query", "-Rs", "{query}"]
+//! pattern = '^\[.\] (?P<name>\S+)-(?P<version>[^-\s]+_\d+)\s+(?P<description>.*)$'
+//!
+//! [list]
+//! command = ["xbps-query", "-l"]
+//! pattern = '^ii (?P<name>\S+)-(?P<version>[^-\s]+_\d+)'
+//!
+//! [refresh]
+//! command = ["xbps-install", "-S"]
+//! ```
+//!
+//! `repository_args` and `arch_args` are appended to the command only when a
+//! call passes a `repository` or an `arch`, and the matching tool parameters
+//! are only advertised when an operation configures them. `root_args` (with a
+//! `{root}` placeholder) are appended when the backend targets an alternate
+//! root filesystem, which is only supported when every configured operation
+//! defines them.
+//!
+//! Patterns use named capture groups: `name` and `version` for installed
+//! packages (plus optional `architecture`, `previous_version` and
+//! `repository`), and `name` with optional `version`, `repository` and
+//! `description` for search results. Operations without a section are reported as unsupported.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+use super::parse::search_entry;
+use super::runner::{CommandRunner, LocalRunner};
+use super::{
+    Capabilities, ExecResult, InstallOptions, InstallOutcome, InstallVersionOptions,
+    InstalledPackage, PackageManager, SearchOptions, SearchResult, command, validate_root,
+};
+use crate::error::PackageManagerError;
+
+/// Errors raised while loading a backend configuration file
+#[derive(Debug, thiserror::Error)]
+pub enum BackendConfigError {
+    #[error("Failed to read backend configuration {path}: {source}")]
+    Read {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Invalid backend configuration: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Command template for '{operation}' is empty")]
+    EmptyCommand { operation: &'static str },
+    #[error("Invalid pattern for '{operation}': {source}")]
+    Pattern {
+        operation: &'static str,
+        #[source]
+        source: regex::Error,
+    },
+}
+
+#[derive(Deserialize)]
+struct GenericConfig {
+    name: String,
+    os_name: String,
+    install: Option<OperationConfig>,
+    install_version: Option<OperationConfig>,
+    search: Option<OperationConfig>,
+    list: Option<OperationConfig>,
+    refresh: Option<OperationConfig>,
+}
+
+#[derive(Deserialize)]
+struct OperationConfig {
+    command: Vec<String>,
+    #[serde(default)]
+    repository_args: Vec<String>,
+    #[serde(default)]
+    arch_args: Vec<String>,
+    #[serde(default)]
+    root_args: Vec<String>,
+    pattern: Option<String>,
+}
+
+#[derive(Clone)]
+struct CommandTemplate {
+    command: Vec<String>,
+    repository_args: Vec<String>,
+    arch_args: Vec<String>,
+    root_args: Vec<String>,
+    pattern: Option<Regex>,
+    /// Whether the operation modifies the system
+    privileged: bool,
+}
+
+impl CommandTemplate {
+    fn compile(
+        operation: &'static str,
+        config: Option<OperationConfig>,
+    ) -> Result<Option<Self>, BackendConfigError> {
+        let Some(config) = config else {
+            return Ok(None);
+        };
+        if config.command.is_empty() {
+            return Err(BackendConfigError::EmptyCommand { operation });
+        }
+        let pattern = config
+            .pattern
+            .map(|pattern| Regex::new(&pattern))
+            .transpose()
+            .map_err(|source| BackendConfigError::Pattern { operation, source })?;
+
+        Ok(Some(Self {
+            command: config.command,
+            repository_args: config.repository_args,
+            arch_args: config.arch_args,
+            root_args: config.root_args,
+            pattern,
+            privileged: matches!(operation, "install" | "install_version" | "refresh"),
+        }))
+    }
+
+    /// Substitute placeholders and build the argument vector
+    fn render(&self, values: &HashMap<&str, &str>) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .command
+            .iter()
+            .map(|arg| substitute(arg, values))
+            .collect();
+        for (placeholder, extra_args) in [
+            ("repository", &self.repository_args),
+            ("arch", &self.arch_args),
+            ("root", &self.root_args),
+        ] {
+            if values.contains_key(placeholder) {
+                args.extend(extra_args.iter().map(|arg| substitute(arg, values)));
+            }
+        }
+        args
+    }
+
+    /// Captures of the pattern for every matching stdout line
+    fn captures<'a>(&'a self, stdout: &'a str) -> impl Iterator<Item = Captures<'a>> + 'a {
+        stdout
+            .lines()
+            .filter_map(|line| self.pattern.as_ref()?.captures(line))
+    }
+}
+
+/// Package manager backend defined by a TOML configuration
+#[derive(Clone)]
+pub struct GenericBackend {
+    name: &'static str,
+    os_name: &'static str,
+    install: Option<CommandTemplate>,
+    install_version: Option<CommandTemplate>,
+    search: Option<CommandTemplate>,
+    list: Option<CommandTemplate>,
+    refresh: Option<CommandTemplate>,
+    runner: Arc<dyn CommandRunner>,
+    root: Option<String>,
+}
+
+impl GenericBackend {
+    /// Load the backend from a TOML configuration file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, BackendConfigError> {
+        let path = path.as_ref();
+        let contents =
+            std::fs::read_to_string(path).map_err(|source| BackendConfigError::Read {
+                path: path.display().to_string(),
+                source,
+            })?;
+        Self::from_toml(&contents)
+    }
+
+    /// Build the backend from TOML configuration contents
+    pub fn from_toml(contents: &str) -> Result<Self, BackendConfigError> {
+        let config: GenericConfig = toml::from_str(contents)?;
+
+        Ok(Self {
+            // The backend lives for the lifetime of the server, so leaking the
+            // configured names is the simplest way to satisfy the trait.
+            name: Box::leak(config.name.into_boxed_str()),
+            os_name: Box::leak(config.os_name.into_boxed_str()),
+            install: CommandTemplate::compile("install", config.install)?,
+            install_version: CommandTemplate::compile("install_version", config.install_version)?,
+            search: CommandTemplate::compile("search", config.search)?,
+            list: CommandTemplate::compile("list", config.list)?,
+            refresh: CommandTemplate::compile("refresh", config.refresh)?,
+            runner: Arc::new(LocalRunner),
+            root: None,
+        })
+    }
+
+    /// Run the configured commands through the given runner
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Configured operations
+    fn templates(&self) -> impl Iterator<Item = &CommandTemplate> {
+        [
+            &self.install,
+            &self.install_version,
+            &self.search,
+            &self.list,
+            &self.refresh,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    async fn run(
+        &self,
+        template: &CommandTemplate,
+        values: &HashMap<&str, &str>,
+    ) -> Result<ExecResult, PackageManagerError> {
+        let mut values = values.clone();
+        if let Some(root) = &self.root {
+            values.insert("root", root.as_str());
+        }
+        let args = template.render(&values);
+
+        let mut command = command(&args[0]);
+        command.args(&args[1..]);
+        if template.privileged {
+            command.privileged();
+        }
+        let output = self.runner.run(&command).await.map_err(|err| {
+            PackageManagerError::io(format!("Failed to execute {}", args[0]), err)
+        })?;
+
+        output.success()
+    }
+}
+
+#[async_trait]
+impl PackageManager for GenericBackend {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn os_name(&self) -> &'static str {
+        self.os_name
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let has_repository_args = |template: &Option<CommandTemplate>| {
+            template
+                .as_ref()
+                .is_some_and(|template| !template.repository_args.is_empty())
+        };
+        let has_arch_args = |template: &Option<CommandTemplate>| {
+            template
+                .as_ref()
+                .is_some_and(|template| !template.arch_args.is_empty())
+        };
+
+        Capabilities {
+            version_install: self.install_version.is_some(),
+            custom_repositories: has_repository_args(&self.install)
+                || has_repository_args(&self.search),
+            mutating: self.install.is_some() || self.refresh.is_some(),
+            repository_listing: false,
+            search_repository_listing: false,
+            architecture_selection: has_arch_args(&self.install)
+                || has_arch_args(&self.install_version)
+                || has_arch_args(&self.search),
+            privilege_check: false,
+            install_commands: false,
+            batch_install: false,
+            batch_version_install: false,
+            vulnerability_check: false,
+            license_report: false,
+            lockfile: false,
+            locked_install: false,
+            audit_log: false,
+            cross_distro_search: false,
+            signature_check: false,
+            artifact_verification: false,
+            apko_config: false,
+            cloud_init: false,
+            drift_detection: false,
+            disk_space_check: false,
+            debconf_preseed: false,
+            recommends_selection: false,
+            install_flags: false,
+            repository_override: false,
+            multiple_repositories: false,
+            target_release_selection: false,
+            ansible_tasks: false,
+            alternate_root: self
+                .templates()
+                .all(|template| !template.root_args.is_empty()),
+        }
+    }
+
+    fn with_root(&self, root: &str) -> Result<Arc<dyn PackageManager>, PackageManagerError> {
+        if !self.capabilities().alternate_root {
+            return Err(PackageManagerError::Unsupported { operation: "root" });
+        }
+        validate_root(root)?;
+        Ok(Arc::new(Self {
+            root: Some(root.to_string()),
+            ..self.clone()
+        }))
+    }
+
+    async fn install_package(
+        &self,
+        options: &InstallOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        let mut values = HashMap::from([("package", options.package.as_str())]);
+        if let Some(repository) = &options.repository {
+            values.insert("repository", repository.as_str());
+        }
+        if let Some(arch) = &options.arch {
+            values.insert("arch", arch.as_str());
+        }
+
+        let template = supported("install", &self.install)?;
+        let exec_result = self.run(template, &values).await?;
+        let installed = parse_installed(template, &exec_result);
+        Ok(InstallOutcome::new(
+            &options.package,
+            installed,
+            exec_result,
+        ))
+    }
+
+    async fn install_package_with_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        let mut values = HashMap::from([
+            ("package", options.package.as_str()),
+            ("version", options.version.as_str()),
+        ]);
+        if let Some(arch) = &options.arch {
+            values.insert("arch", arch.as_str());
+        }
+
+        let template = supported("install_version", &self.install_version)?;
+        let exec_result = self.run(template, &values).await?;
+        let installed = parse_installed(template, &exec_result);
+        Ok(InstallOutcome::new(
+            &options.package,
+            installed,
+            exec_result,
+        ))
+    }
+
+    async fn search_package(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        let mut values = HashMap::from([("query", options.query.as_str())]);
+        if let Some(repository) = &options.repository {
+            values.insert("repository", repository.as_str());
+        }
+        if let Some(arch) = &options.arch {
+            values.insert("arch", arch.as_str());
+        }
+
+        let template = supported("search", &self.search)?;
+        let exec_result = self.run(template, &values).await?;
+
+        let matches = options.mode.matcher(&options.query);
+        Ok(template
+            .captures(exec_result.stdout.as_deref().unwrap_or_default())
+            .filter_map(|captures| {
+                let field = |name| captures.name(name).map(|group| group.as_str());
+                search_entry(
+                    field("name")?,
+                    field("version"),
+                    field("repository"),
+                    field("description"),
+                )
+            })
+            .filter(|result| matches(&result.name, result.description.as_deref()))
+            .collect())
+    }
+
+    async fn list_installed_packages(&self) -> Result<Vec<InstalledPackage>, PackageManagerError> {
+        let template = supported("list", &self.list)?;
+        let exec_result = self.run(template, &HashMap::new()).await?;
+        Ok(parse_installed(template, &exec_result))
+    }
+
+    async fn refresh_repositories(&self) -> Result<ExecResult, PackageManagerError> {
+        let template = supported("refresh", &self.refresh)?;
+        self.run(template, &HashMap::new()).await
+    }
+}
+
+/// Replace every `{name}` placeholder with its value
+///
+/// Substitution is done in a single pass so values containing braces are
+/// never expanded again. Unknown placeholders are kept verbatim.
+fn substitute(arg: &str, values: &HashMap<&str, &str>) -> String {
+    let mut result = String::with_capacity(arg.len());
+    let mut rest = arg;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        match placeholder
+            .find('}')
+            .and_then(|end| Some((end, *values.get(&placeholder[1..end])?)))
+        {
+            Some((end, value)) => {
+                result.push_str(value);
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+fn group(captures: &Captures, name: &str) -> Option<String> {
+    captures
+        .name(name)
+        .map(|group| group.as_str().trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn supported<'a>(
+    operation: &'static str,
+    template: &'a Option<CommandTemplate>,
+) -> Result<&'a CommandTemplate, PackageManagerError> {
+    template
+        .as_ref()
+        .ok_or(PackageManagerError::Unsupported { operation })
+}
+
+fn parse_installed(template: &CommandTemplate, exec_result: &ExecResult) -> Vec<InstalledPackage> {
+    template
+        .captures(exec_result.stdout.as_deref().unwrap_or_default())
+        .filter_map(|captures| {
+            Some(InstalledPackage {
+                name: group(&captures, "name")?,
+                version: group(&captures, "version")?,
+                architecture: group(&captures, "architecture"),
+                previous_version: group(&captures, "previous_version"),
+                repository: group(&captures, "repository"),
+                reason: None,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,157 @@
+//! Replay of mutating tool calls retried with the same idempotency key
+//!
+//! Agents retry calls whose response they never received: the transport
+//! dropped, the client reconnected, a timeout fired while `apt-get` was still
+//! downloading. Without a way to recognize the retry, the server installs
+//! again, which is slow at best. Mutating tools take an optional
+//! `idempotency_key`; the first successful result of a key is stored and
+//! returned to every later call with that key instead of running the package
+//! manager again. A retry arriving while the original call still runs waits
+//! for it.
+//!
+//! Failed calls are not stored, so they can be retried with the same key.
+//! Keys are scoped to the identified client, shared by its sessions, or to
+//! the session of anonymous calls, so that anonymous agents cannot replay
+//! each other's results; they expire after a TTL. Reusing a key for a call
+//! with other arguments is an error rather than a silent replay of another
+//! call.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rmcp::model::{CallToolRequestParam, CallToolResult};
+use tokio::sync::OnceCell;
+
+use crate::error::PackageManagerError;
+
+/// How long results are kept by default
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most keys kept at once; the oldest are forgotten first
+const MAX_KEYS: usize = 10_000;
+
+/// Longest key accepted
+const MAX_KEY_LENGTH: usize = 256;
+
+/// Argument carrying the key
+pub const IDEMPOTENCY_KEY_ARGUMENT: &str = "idempotency_key";
+
+/// Result slot of a key: filled once by the first successful call
+pub(crate) type ResultSlot = Arc<OnceCell<CallToolResult>>;
+
+/// Stored results of keyed calls, shared by every session
+#[derive(Debug)]
+pub(crate) struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<(Owner, String), Entry>>,
+}
+
+/// Calls sharing keys, and background jobs
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Owner {
+    /// Every session of an identified client
+    Client(String),
+    /// One session of anonymous calls
+    Session(String),
+    /// Anonymous calls without a session
+    Anonymous,
+}
+
+impl Owner {
+    /// Owner of the calls of `client`, or of the `session` of anonymous
+    /// calls
+    pub(crate) fn new(client: Option<&str>, session: Option<&str>) -> Self {
+        match (client, session) {
+            (Some(client), _) => Self::Client(client.to_string()),
+            (None, Some(session)) => Self::Session(session.to_string()),
+            (None, None) => Self::Anonymous,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    created: Instant,
+    /// Tool and arguments of the call that used the key first
+    call: String,
+    result: ResultSlot,
+}
+
+impl IdempotencyStore {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Result slot of the key the request carries, if any
+    ///
+    /// Fails when the argument is not a valid key, or when `client`, or the
+    /// `session` of anonymous calls, used the key for another call.
+    pub(crate) fn slot(
+        &self,
+        request: &CallToolRequestParam,
+        client: Option<&str>,
+        session: Option<&str>,
+    ) -> Result<Option<ResultSlot>, PackageManagerError> {
+        let Some(key) = request
+            .arguments
+            .as_ref()
+            .and_then(|arguments| arguments.get(IDEMPOTENCY_KEY_ARGUMENT))
+        else {
+            return Ok(None);
+        };
+        let key = key
+            .as_str()
+            .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LENGTH)
+            .ok_or_else(|| PackageManagerError::Validation {
+                field: IDEMPOTENCY_KEY_ARGUMENT,
+                value: key.to_string(),
+                reason: format!("must be a non-empty string of at most {MAX_KEY_LENGTH} bytes"),
+            })?;
+
+        let mut arguments = request.arguments.clone().unwrap_or_default();
+        arguments.remove(IDEMPOTENCY_KEY_ARGUMENT);
+        let call = format!("{} {}", request.name, serde_json::Value::Object(arguments));
+
+        let mut entries = self.lock_entries();
+        let now = Instant::now();
+        entries.retain(|_, entry| now.duration_since(entry.created) < self.ttl);
+        let id = (Owner::new(client, session), key.to_string());
+        if let Some(entry) = entries.get(&id) {
+            if entry.call != call {
+                return Err(PackageManagerError::Validation {
+                    field: IDEMPOTENCY_KEY_ARGUMENT,
+                    value: key.to_string(),
+                    reason: "was already used for a call with other arguments".to_string(),
+                });
+            }
+            return Ok(Some(entry.result.clone()));
+        }
+
+        if entries.len() >= MAX_KEYS
+            && let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.created)
+                .map(|(id, _)| id.clone())
+        {
+            entries.remove(&oldest);
+        }
+        let result = ResultSlot::default();
+        entries.insert(
+            id,
+            Entry {
+                created: now,
+                call,
+                result: result.clone(),
+            },
+        );
+        Ok(Some(result))
+    }
+
+    fn lock_entries(&self) -> std::sync::MutexGuard<'_, HashMap<(Owner, String), Entry>> {
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
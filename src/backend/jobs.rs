@@ -0,0 +1,419 @@
+//! Background jobs for long-running mutating calls
+//!
+//! A `dist-upgrade` or the install of a texlive-sized package set runs for
+//! longer than most MCP clients wait for a tool call. Mutating tools take an
+//! optional `async` argument: the call is then started as a job and its id
+//! returned at once. The `get_job_status`, `get_job_output` and `cancel_job`
+//! tools follow the job, read the output of the package manager while it
+//! runs, and stop it.
+//!
+//! Jobs run the call exactly as a synchronous call would, with its checks,
+//! hooks, audit entry and idempotency key. The jobs of an MCP session form a
+//! FIFO queue: an agent can enqueue several installs at once, and each
+//! starts once the previous ones finished, in the order they were started.
+//! Like every mutating call, they then run under the handler's mutation
+//! lock. Queued jobs can be cancelled, which takes them out of the queue;
+//! running ones cannot, since dropping the call only kills the local
+//! process of `sudo`, `docker exec`, `kubectl exec` or `ssh`, which can
+//! leave the package manager running while the next mutation starts. Like
+//! idempotency keys, jobs are scoped to the identified client and shared by
+//! its sessions, or to the session of anonymous calls; the most recent
+//! finished jobs are kept.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use rmcp::ErrorData as McpError;
+use rmcp::model::CallToolResult;
+use serde::Serialize;
+use tokio::sync::{Notify, mpsc};
+
+use super::exec;
+use super::idempotency::Owner;
+use crate::error::ErrorCode;
+
+/// Argument starting a mutating call as a job
+pub const ASYNC_ARGUMENT: &str = "async";
+
+/// Finished jobs kept at once; the oldest are forgotten first
+const MAX_FINISHED_JOBS: usize = 1000;
+
+/// Output lines kept per job; the oldest are dropped first
+const MAX_OUTPUT_LINES: usize = 10_000;
+
+/// Most output lines returned by one `get_job_output` call
+pub const MAX_OUTPUT_PAGE: usize = 1000;
+
+/// State of a job
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for the earlier jobs of its session to finish
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, Self::Queued | Self::Running)
+    }
+}
+
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        })
+    }
+}
+
+/// What `get_job_status` reports about a job
+#[derive(Clone, Debug, Serialize)]
+pub struct JobReport {
+    pub job_id: String,
+    /// Tool the job runs
+    pub tool: String,
+    pub status: JobStatus,
+    /// Jobs of the same session to run before a queued job
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Output lines the package manager printed so far
+    pub output_lines: usize,
+    /// Message and data of the error of a failed job
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+/// Output lines of a job from an offset
+#[derive(Clone, Debug, Serialize)]
+pub struct JobOutput {
+    pub job_id: String,
+    pub status: JobStatus,
+    /// Offset of the first line returned
+    pub offset: usize,
+    /// Offset to pass to read the lines that follow
+    pub next_offset: usize,
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Job {
+    /// Client, or session of anonymous calls, that started the job
+    owner: Owner,
+    /// MCP session the job is queued in
+    session: Option<String>,
+    tool: String,
+    status: JobStatus,
+    started_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    output: VecDeque<String>,
+    /// Lines dropped from the start of `output`
+    dropped_lines: usize,
+    result: Option<Result<CallToolResult, McpError>>,
+    task: Option<tokio::task::AbortHandle>,
+}
+
+impl Job {
+    fn report(&self, job_id: &str, queue_position: Option<usize>) -> JobReport {
+        let error = match &self.result {
+            Some(Err(err)) => Some(serde_json::json!({
+                "message": err.message,
+                "data": err.data,
+            })),
+            _ => None,
+        };
+        JobReport {
+            job_id: job_id.to_string(),
+            tool: self.tool.clone(),
+            status: self.status,
+            queue_position: queue_position.filter(|_| self.status == JobStatus::Queued),
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            output_lines: self.dropped_lines + self.output.len(),
+            error,
+        }
+    }
+
+    fn finish(&mut self, status: JobStatus) {
+        self.status = status;
+        self.finished_at = Some(Utc::now());
+        self.task = None;
+    }
+}
+
+/// Jobs of every session
+#[derive(Debug, Default)]
+pub(crate) struct JobStore {
+    next_id: AtomicU64,
+    state: Mutex<JobState>,
+    /// Notified whenever a job leaves its queue
+    dequeued: Notify,
+}
+
+#[derive(Debug, Default)]
+struct JobState {
+    jobs: HashMap<String, Job>,
+    /// Ids of the unfinished jobs of each session, in the order they run
+    queues: HashMap<Option<String>, VecDeque<String>>,
+}
+
+impl JobState {
+    /// Jobs of the session of `job` ahead of it in the queue
+    fn queue_position(&self, job_id: &str, job: &Job) -> Option<usize> {
+        self.queues
+            .get(&job.session)?
+            .iter()
+            .position(|queued| queued == job_id)
+    }
+
+    fn report(&self, job_id: &str, job: &Job) -> JobReport {
+        job.report(job_id, self.queue_position(job_id, job))
+    }
+
+    /// Take `job_id` out of its session's queue
+    fn dequeue(&mut self, job_id: &str) {
+        let Some(session) = self.jobs.get(job_id).map(|job| job.session.clone()) else {
+            return;
+        };
+        if let Some(queue) = self.queues.get_mut(&session) {
+            queue.retain(|queued| queued != job_id);
+            if queue.is_empty() {
+                self.queues.remove(&session);
+            }
+        }
+    }
+}
+
+impl JobStore {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `call` of `tool` for `owner` behind the jobs of `session`,
+    /// returning the id of its job and the jobs ahead of it
+    pub(crate) fn start<F>(
+        self: &Arc<Self>,
+        tool: &str,
+        owner: Owner,
+        session: Option<&str>,
+        call: F,
+    ) -> (String, usize)
+    where
+        F: Future<Output = Result<CallToolResult, McpError>> + Send + 'static,
+    {
+        let job_id = format!("job-{}", self.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+        let mut state = self.lock_state();
+        let finished = state
+            .jobs
+            .values()
+            .filter(|job| job.status.is_finished())
+            .count();
+        if finished >= MAX_FINISHED_JOBS
+            && let Some(oldest) = state
+                .jobs
+                .iter()
+                .filter(|(_, job)| job.status.is_finished())
+                .min_by_key(|(_, job)| job.finished_at)
+                .map(|(id, _)| id.clone())
+        {
+            state.jobs.remove(&oldest);
+        }
+
+        let store = self.clone();
+        let id = job_id.clone();
+        let task = tokio::spawn(async move {
+            store.wait_for_turn(&id).await;
+            let (sender, mut receiver) = mpsc::unbounded_channel::<exec::OutputLine>();
+            let mut call = std::pin::pin!(exec::stream_output(sender, call));
+            let result = loop {
+                tokio::select! {
+                    result = &mut call => break result,
+                    Some(line) = receiver.recv() => store.push_output(&id, line.line),
+                }
+            };
+            while let Ok(line) = receiver.try_recv() {
+                store.push_output(&id, line.line);
+            }
+            store.complete(&id, result);
+        });
+        let queue = state.queues.entry(session.map(str::to_string)).or_default();
+        let ahead = queue.len();
+        queue.push_back(job_id.clone());
+        state.jobs.insert(
+            job_id.clone(),
+            Job {
+                owner,
+                session: session.map(str::to_string),
+                tool: tool.to_string(),
+                status: if ahead == 0 {
+                    JobStatus::Running
+                } else {
+                    JobStatus::Queued
+                },
+                started_at: Utc::now(),
+                finished_at: None,
+                output: VecDeque::new(),
+                dropped_lines: 0,
+                result: None,
+                task: Some(task.abort_handle()),
+            },
+        );
+        (job_id, ahead)
+    }
+
+    /// Wait until `job_id` is first in its session's queue, then mark it
+    /// running
+    async fn wait_for_turn(&self, job_id: &str) {
+        loop {
+            let dequeued = self.dequeued.notified();
+            tokio::pin!(dequeued);
+            dequeued.as_mut().enable();
+            {
+                let mut state = self.lock_state();
+                let Some(job) = state.jobs.get(job_id) else {
+                    return;
+                };
+                if state.queue_position(job_id, job).unwrap_or_default() == 0 {
+                    if let Some(job) = state.jobs.get_mut(job_id) {
+                        job.status = JobStatus::Running;
+                    }
+                    return;
+                }
+            }
+            dequeued.await;
+        }
+    }
+
+    /// Report of a job of `owner`, with the result of the call once it
+    /// succeeded
+    pub(crate) fn status(
+        &self,
+        job_id: &str,
+        owner: &Owner,
+    ) -> Result<(JobReport, Option<CallToolResult>), McpError> {
+        let state = self.lock_state();
+        let job = Self::find(&state, job_id, owner)?;
+        let result = match &job.result {
+            Some(Ok(result)) => Some(result.clone()),
+            _ => None,
+        };
+        Ok((state.report(job_id, job), result))
+    }
+
+    /// At most `limit` output lines of a job of `owner`, from `offset`
+    ///
+    /// Lines dropped since are skipped.
+    pub(crate) fn output(
+        &self,
+        job_id: &str,
+        owner: &Owner,
+        offset: usize,
+        limit: usize,
+    ) -> Result<JobOutput, McpError> {
+        let state = self.lock_state();
+        let job = Self::find(&state, job_id, owner)?;
+        let offset = offset.max(job.dropped_lines);
+        let lines: Vec<String> = job
+            .output
+            .iter()
+            .skip(offset - job.dropped_lines)
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok(JobOutput {
+            job_id: job_id.to_string(),
+            status: job.status,
+            offset,
+            next_offset: offset + lines.len(),
+            lines,
+        })
+    }
+
+    /// Take a queued job of `owner` out of its queue
+    ///
+    /// Running jobs cannot be cancelled; finished jobs are left as they are.
+    pub(crate) fn cancel(&self, job_id: &str, owner: &Owner) -> Result<JobReport, McpError> {
+        let mut state = self.lock_state();
+        if Self::find(&state, job_id, owner)?.status == JobStatus::Running {
+            return Err(McpError::invalid_params(
+                format!("Job '{job_id}' is already running, only queued jobs can be cancelled"),
+                Some(serde_json::json!({
+                    "error_type": "job_running",
+                    "error_code": ErrorCode::Unsupported,
+                    "job_id": job_id,
+                })),
+            ));
+        }
+        state.dequeue(job_id);
+        let job = state.jobs.get_mut(job_id).expect("the job was found");
+        if let Some(task) = job.task.take() {
+            task.abort();
+            job.finish(JobStatus::Cancelled);
+        }
+        let report = job.report(job_id, None);
+        drop(state);
+        self.dequeued.notify_waiters();
+        Ok(report)
+    }
+
+    fn find<'a>(state: &'a JobState, job_id: &str, owner: &Owner) -> Result<&'a Job, McpError> {
+        state
+            .jobs
+            .get(job_id)
+            .filter(|job| job.owner == *owner)
+            .ok_or_else(|| {
+                McpError::invalid_params(
+                    format!("Job '{job_id}' not found"),
+                    Some(serde_json::json!({
+                        "error_type": "job_not_found",
+                        "error_code": ErrorCode::NotFound,
+                        "job_id": job_id,
+                    })),
+                )
+            })
+    }
+
+    fn push_output(&self, job_id: &str, line: String) {
+        if let Some(job) = self.lock_state().jobs.get_mut(job_id) {
+            if job.output.len() >= MAX_OUTPUT_LINES {
+                job.output.pop_front();
+                job.dropped_lines += 1;
+            }
+            job.output.push_back(line);
+        }
+    }
+
+    fn complete(&self, job_id: &str, result: Result<CallToolResult, McpError>) {
+        let mut state = self.lock_state();
+        state.dequeue(job_id);
+        if let Some(job) = state.jobs.get_mut(job_id)
+            && !job.status.is_finished()
+        {
+            let status = match &result {
+                Ok(result) if result.is_error != Some(true) => JobStatus::Succeeded,
+                _ => JobStatus::Failed,
+            };
+            job.result = Some(result);
+            job.finish(status);
+        }
+        drop(state);
+        self.dequeued.notify_waiters();
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, JobState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
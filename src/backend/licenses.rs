@@ -0,0 +1,88 @@
+//! License reports of installed packages
+//!
+//! Compliance reviews need to know which licenses ship in an image before it
+//! is distributed. The `license_report` tool collects the licenses each
+//! installed package declares (the `License` field of APK packages, the
+//! machine-readable `copyright` files of Debian packages) into a
+//! [`LicenseReport`], flagging the packages whose licenses match the
+//! server's deny-list, e.g. `AGPL`.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::PackageLicense;
+
+/// A package in a license report
+#[derive(Clone, Debug, Serialize)]
+pub struct LicenseFinding {
+    #[serde(flatten)]
+    pub package: PackageLicense,
+    /// Deny-list patterns matching one of the package's licenses
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub denied: Vec<String>,
+}
+
+/// Licenses of the installed packages, with those needing review flagged
+#[derive(Clone, Debug, Serialize)]
+pub struct LicenseReport {
+    pub packages: Vec<LicenseFinding>,
+    /// Number of packages declaring each license
+    pub licenses: BTreeMap<String, usize>,
+    /// Packages without license information
+    pub unknown: usize,
+    /// Packages with a license on the deny-list
+    pub flagged: usize,
+}
+
+impl LicenseReport {
+    /// Report `packages`, flagging licenses containing one of `deny_list`
+    ///
+    /// Patterns match case-insensitively anywhere in a license, so `AGPL`
+    /// flags `AGPL-3.0-or-later` as well as `GPL-2.0-only OR AGPL-3.0-only`.
+    pub fn new(packages: Vec<PackageLicense>, deny_list: &[String]) -> Self {
+        let mut licenses = BTreeMap::new();
+        let mut unknown = 0;
+        let mut flagged = 0;
+        let packages = packages
+            .into_iter()
+            .map(|package| {
+                if package.licenses.is_empty() {
+                    unknown += 1;
+                }
+                for license in &package.licenses {
+                    *licenses.entry(license.clone()).or_insert(0) += 1;
+                }
+                let denied: Vec<String> = deny_list
+                    .iter()
+                    .filter(|pattern| {
+                        let pattern = pattern.to_lowercase();
+                        package
+                            .licenses
+                            .iter()
+                            .any(|license| license.to_lowercase().contains(&pattern))
+                    })
+                    .cloned()
+                    .collect();
+                if !denied.is_empty() {
+                    flagged += 1;
+                }
+                LicenseFinding { package, denied }
+            })
+            .collect();
+
+        Self {
+            packages,
+            licenses,
+            unknown,
+            flagged,
+        }
+    }
+
+    /// Packages with a license on the deny-list
+    pub fn flagged_packages(&self) -> impl Iterator<Item = &LicenseFinding> {
+        self.packages
+            .iter()
+            .filter(|finding| !finding.denied.is_empty())
+    }
+}
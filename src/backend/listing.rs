@@ -0,0 +1,82 @@
+//! Snapshots of paged listings
+//!
+//! Listing the installed packages runs the package manager, and installs
+//! between two pages would shift the entries under an offset. The first
+//! page of a paged listing keeps the whole listing in a [`ListingSnapshots`]
+//! entry, and the cursors it hands out name the snapshot and an offset in
+//! it, so the following pages read the same listing without running the
+//! command again. Snapshots expire after [`SNAPSHOT_TTL`]; only the newest
+//! [`MAX_SNAPSHOTS`] of a session are kept.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::InstalledPackage;
+
+/// How long the pages of a listing can be fetched
+pub const SNAPSHOT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Most listings paged through at once
+pub const MAX_SNAPSHOTS: usize = 16;
+
+/// A listing kept for its next pages
+#[derive(Debug)]
+struct Snapshot {
+    created: Instant,
+    packages: Arc<[InstalledPackage]>,
+}
+
+/// Listings being paged through, by snapshot id
+#[derive(Debug, Default)]
+pub(crate) struct ListingSnapshots {
+    snapshots: Mutex<HashMap<String, Snapshot>>,
+}
+
+impl ListingSnapshots {
+    /// Keep `packages` for their next pages, returning the snapshot's id
+    pub(crate) fn store(&self, packages: Arc<[InstalledPackage]>) -> String {
+        let mut id = [0u8; 8];
+        let _ = SystemRandom::new().fill(&mut id);
+        let mut snapshots = self.lock();
+        let mut id: String = id.iter().map(|byte| format!("{byte:02x}")).collect();
+        // Without randomness, ids are kept unique by lengthening them
+        while snapshots.contains_key(&id) {
+            id.push('0');
+        }
+
+        snapshots.retain(|_, snapshot| snapshot.created.elapsed() < SNAPSHOT_TTL);
+        while snapshots.len() >= MAX_SNAPSHOTS {
+            let Some(oldest) = snapshots
+                .iter()
+                .min_by_key(|(_, snapshot)| snapshot.created)
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            snapshots.remove(&oldest);
+        }
+        snapshots.insert(
+            id.clone(),
+            Snapshot {
+                created: Instant::now(),
+                packages,
+            },
+        );
+        id
+    }
+
+    /// The listing of snapshot `id`, unless it expired
+    pub(crate) fn get(&self, id: &str) -> Option<Arc<[InstalledPackage]>> {
+        self.lock()
+            .get(id)
+            .filter(|snapshot| snapshot.created.elapsed() < SNAPSHOT_TTL)
+            .map(|snapshot| snapshot.packages.clone())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Snapshot>> {
+        self.snapshots.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
@@ -0,0 +1,108 @@
+//! Repositories that are local directories of packages
+//!
+//! Teams vendoring packages next to their build context can pass the
+//! directory holding them as the `repository` of a tool call, as a path or a
+//! `file://` URL. The package manager needs an index of the directory, so the
+//! backends generate it on the runner's target before using the directory,
+//! and regenerate it whenever a package is newer than the index:
+//!
+//! - APK repositories keep their packages in a directory per architecture
+//!   (`/srv/packages/x86_64/*.apk`), indexed with `apk index`;
+//! - APT repositories are flat directories of `.deb` files, indexed with
+//!   `dpkg-scanpackages`.
+//!
+//! Generated indexes are not signed, so the package managers are told to
+//! trust them. APK trusts a whole transaction at once, so installs only
+//! allow untrusted packages when one of the requested packages is in a local
+//! directory. Installs from local directories are untrusted sources, which
+//! the server rejects unless they are allowed.
+
+use super::ExecResult;
+use super::runner::{Command, CommandRunner};
+use crate::error::PackageManagerError;
+
+/// Index `$1/*.apk` into `$1/APKINDEX.tar.gz` unless it is up to date
+const APK_INDEX_SCRIPT: &str = r#"cd "$1" || exit 1
+if [ -e APKINDEX.tar.gz ] && [ -z "$(find . -maxdepth 1 -name '*.apk' -newer APKINDEX.tar.gz)" ]; then
+    exit 0
+fi
+exec apk index --allow-untrusted -o APKINDEX.tar.gz ./*.apk"#;
+
+/// Index the `.deb` files below `$1` into `$1/Packages` unless it is up to
+/// date
+const DEB_INDEX_SCRIPT: &str = r#"cd "$1" || exit 1
+if [ -e Packages ] && [ -z "$(find . -name '*.deb' -newer Packages)" ]; then
+    exit 0
+fi
+dpkg-scanpackages --multiversion . > Packages.new && mv Packages.new Packages"#;
+
+/// Directory named by `repository`, when it is a local directory
+///
+/// `file://` URLs and absolute paths are directories, except the paths of
+/// APT sources files (`.list`, `.sources`).
+pub(crate) fn local_directory(repository: &str) -> Option<&str> {
+    let repository = repository.trim();
+    let path = repository.strip_prefix("file://").unwrap_or(repository);
+    if !path.starts_with('/') || path.ends_with(".list") || path.ends_with(".sources") {
+        return None;
+    }
+    match path.trim_end_matches('/') {
+        "" => Some("/"),
+        path => Some(path),
+    }
+}
+
+/// Generate the APK index of the packages in `dir` when it is missing or
+/// older than one of them
+pub(crate) async fn index_apk_directory(
+    runner: &dyn CommandRunner,
+    dir: &str,
+) -> Result<ExecResult, PackageManagerError> {
+    index(runner, APK_INDEX_SCRIPT, dir).await
+}
+
+/// Whether the APK packages directory `dir` has a package named one of
+/// `names`
+///
+/// Package files are named `{name}-{version}-r{release}.apk`.
+pub(crate) async fn apk_directory_provides(
+    runner: &dyn CommandRunner,
+    dir: &str,
+    names: &[&str],
+) -> Result<bool, PackageManagerError> {
+    let files = runner
+        .list_dir(dir)
+        .await
+        .map_err(|err| PackageManagerError::io(format!("failed to list {dir}"), err))?;
+    Ok(files.iter().any(|file| {
+        file.strip_suffix(".apk")
+            .and_then(|file| file.rsplitn(3, '-').nth(2))
+            .is_some_and(|name| names.contains(&name))
+    }))
+}
+
+/// Generate the APT `Packages` index of the packages below `dir` when it is
+/// missing or older than one of them
+pub(crate) async fn index_deb_directory(
+    runner: &dyn CommandRunner,
+    dir: &str,
+) -> Result<ExecResult, PackageManagerError> {
+    index(runner, DEB_INDEX_SCRIPT, dir).await
+}
+
+async fn index(
+    runner: &dyn CommandRunner,
+    script: &str,
+    dir: &str,
+) -> Result<ExecResult, PackageManagerError> {
+    let output = runner
+        .run(Command::new("sh").args(["-c", script, "sh", dir]))
+        .await
+        .map_err(|err| {
+            PackageManagerError::io(
+                format!("there was an error indexing local repository {dir}"),
+                err,
+            )
+        })?;
+    output.success()
+}
@@ -0,0 +1,115 @@
+//! Repository mirrors with health tracking
+//!
+//! A [`MirrorList`] holds interchangeable base URLs serving the same
+//! repository tree, e.g. `https://dl-cdn.alpinelinux.org/alpine` and
+//! `https://uk.alpinelinux.org/alpine`. Backends try the mirrors in order and
+//! mark the ones whose repositories could not be reached as unhealthy, so
+//! later operations go to a working mirror first. Unhealthy mirrors are
+//! retried once their cooldown has passed, or as a last resort when every
+//! mirror is unhealthy.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a mirror is skipped after it failed
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(300);
+
+/// Ordered mirror base URLs and the ones currently known to be unreachable
+#[derive(Debug)]
+pub struct MirrorList {
+    mirrors: Vec<String>,
+    cooldown: Duration,
+    unhealthy: Mutex<HashMap<String, Instant>>,
+}
+
+impl MirrorList {
+    /// Create a list trying the mirrors in the given order
+    pub fn new<I>(mirrors: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        Self {
+            mirrors: mirrors
+                .into_iter()
+                .map(|mirror| mirror.into().trim_end_matches('/').to_string())
+                .collect(),
+            cooldown: DEFAULT_COOLDOWN,
+            unhealthy: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Skip failed mirrors for this long instead of [`DEFAULT_COOLDOWN`]
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Configured mirrors, in order of preference
+    pub fn mirrors(&self) -> &[String] {
+        &self.mirrors
+    }
+
+    /// Mirrors in the order they should be tried
+    ///
+    /// Healthy mirrors come first in configured order, followed by the
+    /// unhealthy ones, least recently failed first.
+    pub fn candidates(&self) -> Vec<String> {
+        let unhealthy = self.lock_unhealthy();
+        let (mut healthy, mut failed): (Vec<_>, Vec<_>) = self
+            .mirrors
+            .iter()
+            .map(|mirror| (mirror, unhealthy.get(mirror).copied()))
+            .partition(|(_, failed_at)| failed_at.is_none());
+        failed.sort_by_key(|(_, failed_at)| *failed_at);
+        healthy.extend(failed);
+        healthy
+            .into_iter()
+            .map(|(mirror, _)| mirror.clone())
+            .collect()
+    }
+
+    /// Mirrors currently skipped because they recently failed
+    pub fn unhealthy(&self) -> Vec<String> {
+        let unhealthy = self.lock_unhealthy();
+        self.mirrors
+            .iter()
+            .filter(|mirror| unhealthy.contains_key(*mirror))
+            .cloned()
+            .collect()
+    }
+
+    /// Record that the mirror could not be reached
+    pub fn mark_unhealthy(&self, mirror: &str) {
+        self.lock_unhealthy()
+            .insert(mirror.to_string(), Instant::now());
+    }
+
+    /// Record that the mirror served a request
+    pub fn mark_healthy(&self, mirror: &str) {
+        self.lock_unhealthy().remove(mirror);
+    }
+
+    /// Path of a URL below one of the mirrors, e.g. `v3.22/main`
+    pub fn relative_path<'a>(&self, url: &'a str) -> Option<&'a str> {
+        self.mirrors.iter().find_map(|mirror| {
+            url.trim_end_matches('/')
+                .strip_prefix(mirror.as_str())?
+                .strip_prefix('/')
+        })
+    }
+
+    /// Whether the URL points below the given mirror
+    pub fn serves(mirror: &str, url: &str) -> bool {
+        url.strip_prefix(mirror)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Unhealthy mirrors, with the ones whose cooldown passed forgotten
+    fn lock_unhealthy(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        let mut unhealthy = self.unhealthy.lock().unwrap_or_else(|err| err.into_inner());
+        unhealthy.retain(|_, failed_at| failed_at.elapsed() < self.cooldown);
+        unhealthy
+    }
+}
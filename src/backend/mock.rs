@@ -0,0 +1,1178 @@
+//! In-memory backend for tests and demos
+//!
+//! [`MockBackend`] keeps a fake package database in memory, so every tool can
+//! be exercised without a real distribution or root privileges. Calls can be
+//! slowed down with configurable latencies and made to fail on demand to
+//! exercise client error paths.
+//!
+//! The backend is selected with `--backend mock`. An optional TOML file passed
+//! with `--backend-config` replaces the default package database:
+//!
+//! ```toml
+//! latency_ms = 200
+//! # Reject installs and refreshes like a server without root privileges
+//! read_only = false
+//! # Bytes free on the root file system, checked against package sizes
+//! free_space = 1073741824
+//! # Seconds since the indexes were last refreshed
+//! index_age_secs = 86400
+//! # Repositories reported as unsigned in signed-repositories-only mode
+//! unsigned_repositories = ["mock://unsigned"]
+//!
+//! [[packages]]
+//! name = "curl"
+//! version = "8.14.1-r1"
+//! description = "URL retrieval utility and library"
+//! depends = ["ca-certificates"]
+//! checksum = "Q1hEw9wdKBn2zrEYGA+kHYdeyTOxY="
+//! signing_key = "alpine-devel@lists.alpinelinux.org-6165ee59.rsa.pub"
+//!
+//! [[packages]]
+//! name = "jq"
+//! version = "1.8.0-r0"
+//! # Only offered once the repositories were refreshed
+//! unindexed = true
+//!
+//! [latencies]
+//! install_package = 2000
+//!
+//! [failures]
+//! refresh_repositories = { kind = "network_failure" }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use ring::digest::{SHA256, digest};
+use serde::Deserialize;
+
+use super::doctor::HealthCheck;
+use super::drift;
+use super::exec::{self, OutputLine, OutputStream};
+use super::generic::BackendConfigError;
+use super::privileges::PrivilegeReport;
+use super::signing::UnsignedRepository;
+use super::{
+    Capabilities, ExecResult, FetchedPackage, InstallFlags, InstallOptions, InstallOutcome,
+    InstallReason, InstallVersionOptions, InstalledPackage, LockedPackage, PackageLicense,
+    PackageManager, PinnedPackage, Recommends, Repository, ResolvedVersion, SearchOptions,
+    SearchResult, SpaceRequirement, SystemInfo,
+};
+use crate::ansible::AnsibleTask;
+use crate::apko::ApkoConfig;
+use crate::cloudinit::CloudConfig;
+use crate::error::PackageManagerError;
+use crate::version::{VersionConstraint, VersionScheme};
+
+/// Repository reported for packages without an explicit one
+const DEFAULT_REPOSITORY: &str = "mock://main";
+
+/// A package available in the mock repositories
+#[derive(Clone, Debug, Deserialize)]
+pub struct MockPackage {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub repository: Option<String>,
+    /// License expression reported by `license_report` once installed
+    #[serde(default)]
+    pub license: Option<String>,
+    /// Packages installed along with this one when missing
+    #[serde(default)]
+    pub depends: Vec<String>,
+    /// Checksum recorded by `generate_lockfile`
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// Installed packages removed when this one is installed
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    /// Bytes reported as downloaded when installing the package, and needed
+    /// on the disk to install it
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// SHA-256 of the package file, derived from the name and version when
+    /// not set
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Key the package file is signed with
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    /// Only offered once the repositories were refreshed, like a package
+    /// published after the indexes were last downloaded
+    #[serde(default)]
+    pub unindexed: bool,
+}
+
+impl MockPackage {
+    pub fn new(name: &str, version: &str, description: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            description: Some(description.to_string()),
+            repository: None,
+            license: None,
+            depends: Vec::new(),
+            checksum: None,
+            conflicts: Vec::new(),
+            size: None,
+            sha256: None,
+            signing_key: None,
+            unindexed: false,
+        }
+    }
+
+    pub fn with_license(mut self, license: &str) -> Self {
+        self.license = Some(license.to_string());
+        self
+    }
+
+    pub fn with_dependency(mut self, name: &str) -> Self {
+        self.depends.push(name.to_string());
+        self
+    }
+
+    pub fn with_checksum(mut self, checksum: &str) -> Self {
+        self.checksum = Some(checksum.to_string());
+        self
+    }
+
+    pub fn with_conflict(mut self, name: &str) -> Self {
+        self.conflicts.push(name.to_string());
+        self
+    }
+
+    pub fn with_size(mut self, bytes: u64) -> Self {
+        self.size = Some(bytes);
+        self
+    }
+
+    pub fn with_signing_key(mut self, key: &str) -> Self {
+        self.signing_key = Some(key.to_string());
+        self
+    }
+
+    /// Only offer the package once the repositories were refreshed
+    pub fn unindexed(mut self) -> Self {
+        self.unindexed = true;
+        self
+    }
+
+    /// SHA-256 reported for the package file
+    pub fn sha256(&self) -> String {
+        self.sha256.clone().unwrap_or_else(|| {
+            let file = format!("{}-{}.apk", self.name, self.version);
+            digest(&SHA256, file.as_bytes())
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect()
+        })
+    }
+
+    fn repository(&self) -> &str {
+        self.repository.as_deref().unwrap_or(DEFAULT_REPOSITORY)
+    }
+}
+
+/// Failure returned by an operation instead of its normal result
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MockFailure {
+    PermissionDenied,
+    NetworkFailure,
+    LockHeld,
+    CommandFailed {
+        #[serde(default = "default_exit_code")]
+        exit_code: i32,
+    },
+}
+
+fn default_exit_code() -> i32 {
+    1
+}
+
+impl MockFailure {
+    fn into_error(self, operation: &str) -> PackageManagerError {
+        match self {
+            Self::PermissionDenied => PackageManagerError::PermissionDenied {
+                message: format!("{operation} requires root privileges"),
+            },
+            Self::NetworkFailure => PackageManagerError::NetworkFailure {
+                message: format!("{operation} could not reach {DEFAULT_REPOSITORY}"),
+            },
+            Self::LockHeld => PackageManagerError::LockHeld {
+                message: "mock database is locked".to_string(),
+            },
+            Self::CommandFailed { exit_code } => PackageManagerError::CommandFailed(ExecResult {
+                stdout: None,
+                stderr: Some(format!("{operation} failed (injected)")),
+                status: exit_code,
+                truncated: false,
+                command: None,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MockConfig {
+    #[serde(default)]
+    latency_ms: u64,
+    #[serde(default)]
+    latencies: HashMap<String, u64>,
+    #[serde(default)]
+    packages: Vec<MockPackage>,
+    #[serde(default)]
+    installed: Vec<MockPackage>,
+    #[serde(default)]
+    failures: HashMap<String, MockFailure>,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    free_space: Option<u64>,
+    #[serde(default)]
+    index_age_secs: Option<u64>,
+    #[serde(default)]
+    unsigned_repositories: Vec<String>,
+}
+
+/// Package manager backend backed by an in-memory package database
+pub struct MockBackend {
+    available: Vec<MockPackage>,
+    installed: Mutex<BTreeMap<String, InstalledPackage>>,
+    /// License of each installed package declaring one
+    licenses: Mutex<HashMap<String, String>>,
+    latency: Duration,
+    latencies: HashMap<String, Duration>,
+    failures: Mutex<HashMap<String, MockFailure>>,
+    read_only: bool,
+    /// Bytes free on the root file system, unknown when not set
+    free_space: Option<u64>,
+    /// When the indexes were last refreshed
+    refreshed_at: Mutex<SystemTime>,
+    /// Whether `refresh_repositories` ran, offering unindexed packages
+    refreshed: AtomicBool,
+    unsigned: Vec<String>,
+}
+
+impl MockBackend {
+    /// Create a backend with a small default package database
+    pub fn new() -> Self {
+        Self::empty()
+            .with_package(
+                MockPackage::new("curl", "8.12.1-r0", "URL retrieval utility and library")
+                    .with_license("curl"),
+            )
+            .with_package(
+                MockPackage::new("curl", "8.14.1-r1", "URL retrieval utility and library")
+                    .with_license("curl"),
+            )
+            .with_package(
+                MockPackage::new("git", "2.49.1-r0", "Distributed version control system")
+                    .with_license("GPL-2.0-only"),
+            )
+            .with_package(
+                MockPackage::new("python3", "3.12.11-r0", "High-level scripting language")
+                    .with_license("PSF-2.0"),
+            )
+            .with_package(
+                MockPackage::new("python3", "3.13.5-r0", "High-level scripting language")
+                    .with_license("PSF-2.0"),
+            )
+            .with_installed(
+                MockPackage::new("musl", "1.2.5-r10", "the musl c library").with_license("MIT"),
+            )
+            .with_installed(
+                MockPackage::new(
+                    "busybox",
+                    "1.37.0-r18",
+                    "Size optimized toolbox of many common UNIX utilities",
+                )
+                .with_license("GPL-2.0-only"),
+            )
+    }
+
+    /// Create a backend without any packages
+    pub fn empty() -> Self {
+        Self {
+            available: Vec::new(),
+            installed: Mutex::new(BTreeMap::new()),
+            licenses: Mutex::default(),
+            latency: Duration::ZERO,
+            latencies: HashMap::new(),
+            failures: Mutex::new(HashMap::new()),
+            read_only: false,
+            free_space: None,
+            refreshed_at: Mutex::new(SystemTime::now()),
+            refreshed: AtomicBool::new(false),
+            unsigned: Vec::new(),
+        }
+    }
+
+    /// Reject installs and refreshes like a server without root privileges
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Report `bytes` free on the root file system, so installs of packages
+    /// whose sizes add up to more fail the disk space check
+    pub fn with_free_space(mut self, bytes: u64) -> Self {
+        self.free_space = Some(bytes);
+        self
+    }
+
+    /// Report the indexes as last refreshed `age` ago
+    pub fn with_index_age(self, age: Duration) -> Self {
+        *self.lock_refreshed_at() = SystemTime::now() - age;
+        self
+    }
+
+    /// Load the backend from a TOML configuration file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, BackendConfigError> {
+        let path = path.as_ref();
+        let contents =
+            std::fs::read_to_string(path).map_err(|source| BackendConfigError::Read {
+                path: path.display().to_string(),
+                source,
+            })?;
+        let config: MockConfig = toml::from_str(&contents)?;
+
+        let mut backend = Self::empty().with_latency(Duration::from_millis(config.latency_ms));
+        for (operation, latency_ms) in config.latencies {
+            backend = backend.with_operation_latency(operation, Duration::from_millis(latency_ms));
+        }
+        for package in config.packages {
+            backend = backend.with_package(package);
+        }
+        for package in config.installed {
+            backend = backend.with_installed(package);
+        }
+        for (operation, failure) in config.failures {
+            backend.inject_failure(operation, failure);
+        }
+        backend.read_only = config.read_only;
+        backend.free_space = config.free_space;
+        if let Some(age) = config.index_age_secs {
+            backend = backend.with_index_age(Duration::from_secs(age));
+        }
+        backend.unsigned = config.unsigned_repositories;
+        Ok(backend)
+    }
+
+    /// Report the repository as unsigned, whether it holds packages or is
+    /// passed as a custom `repository`
+    pub fn with_unsigned_repository(mut self, repository: impl Into<String>) -> Self {
+        self.unsigned.push(repository.into());
+        self
+    }
+
+    /// Make a package available for installation
+    pub fn with_package(mut self, package: MockPackage) -> Self {
+        self.available.push(package);
+        self
+    }
+
+    /// Mark a package as already installed
+    pub fn with_installed(self, package: MockPackage) -> Self {
+        self.mark_installed(package);
+        self
+    }
+
+    /// Mark a package as installed while the mock is in use, like a change
+    /// made outside the server
+    pub fn mark_installed(&self, package: MockPackage) {
+        self.record_license(&package);
+        self.lock_installed().insert(
+            package.name.clone(),
+            InstalledPackage {
+                repository: Some(package.repository().to_string()),
+                name: package.name,
+                version: package.version,
+                architecture: Some(std::env::consts::ARCH.to_string()),
+                previous_version: None,
+                reason: None,
+            },
+        );
+    }
+
+    /// Delay applied to every operation
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Delay applied to the operation of the given tool, overriding the default
+    pub fn with_operation_latency(
+        mut self,
+        operation: impl Into<String>,
+        latency: Duration,
+    ) -> Self {
+        self.latencies.insert(operation.into(), latency);
+        self
+    }
+
+    /// Fail every call of the given tool's operation until cleared
+    pub fn with_failure(self, operation: impl Into<String>, failure: MockFailure) -> Self {
+        self.inject_failure(operation, failure);
+        self
+    }
+
+    /// Fail every call of the given tool's operation until cleared
+    pub fn inject_failure(&self, operation: impl Into<String>, failure: MockFailure) {
+        self.lock_failures().insert(operation.into(), failure);
+    }
+
+    /// Remove every injected failure
+    pub fn clear_failures(&self) {
+        self.lock_failures().clear();
+    }
+
+    fn lock_installed(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, InstalledPackage>> {
+        self.installed.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn lock_refreshed_at(&self) -> std::sync::MutexGuard<'_, SystemTime> {
+        self.refreshed_at
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Whether the indexes offer `package`
+    fn indexed(&self, package: &MockPackage) -> bool {
+        !package.unindexed || self.refreshed.load(Ordering::Relaxed)
+    }
+
+    fn lock_failures(&self) -> std::sync::MutexGuard<'_, HashMap<String, MockFailure>> {
+        self.failures.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Repositories of the available packages, starting with the default one
+    fn repositories(&self) -> Vec<Repository> {
+        let mut repositories = vec![DEFAULT_REPOSITORY];
+        for package in &self.available {
+            if !repositories.contains(&package.repository()) {
+                repositories.push(package.repository());
+            }
+        }
+        repositories.into_iter().map(Repository::new).collect()
+    }
+
+    /// Simulate the latency of the operation and return its injected failure
+    async fn begin(&self, operation: &str) -> Result<(), PackageManagerError> {
+        let latency = self
+            .latencies
+            .get(operation)
+            .copied()
+            .unwrap_or(self.latency);
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        if self.read_only
+            && matches!(
+                operation,
+                "install_package"
+                    | "install_package_with_version"
+                    | "install_from_lockfile"
+                    | "refresh_repositories"
+            )
+        {
+            return Err(MockFailure::PermissionDenied.into_error(operation));
+        }
+
+        match self.lock_failures().get(operation).cloned() {
+            Some(failure) => Err(failure.into_error(operation)),
+            None => Ok(()),
+        }
+    }
+
+    fn versions(&self, name: &str, repository: Option<&str>) -> Vec<&MockPackage> {
+        self.available
+            .iter()
+            .filter(|package| package.name == name && self.indexed(package))
+            .filter(|package| {
+                repository.is_none_or(|repository| package.repository() == repository)
+            })
+            .collect()
+    }
+
+    /// The available package `pinned` names
+    fn available_version(
+        &self,
+        pinned: &PinnedPackage,
+    ) -> Result<&MockPackage, PackageManagerError> {
+        let versions = self.versions(&pinned.name, pinned.repository.as_deref());
+        versions
+            .iter()
+            .find(|package| package.version == pinned.version)
+            .copied()
+            .ok_or_else(|| PackageManagerError::VersionNotFound {
+                package: pinned.name.clone(),
+                version: pinned.version.clone(),
+                available_versions: versions
+                    .iter()
+                    .map(|package| package.version.clone())
+                    .collect(),
+            })
+    }
+
+    fn record_license(&self, package: &MockPackage) {
+        let mut licenses = self.licenses.lock().unwrap_or_else(|err| err.into_inner());
+        match &package.license {
+            Some(license) => licenses.insert(package.name.clone(), license.clone()),
+            None => licenses.remove(&package.name),
+        };
+    }
+
+    /// Latest available version of `name`
+    fn latest(&self, name: &str) -> Option<&MockPackage> {
+        self.versions(name, None)
+            .into_iter()
+            .max_by(|a, b| VersionScheme::Apk.compare(&a.version, &b.version))
+    }
+
+    /// Add the dependencies of `package` that are neither installed nor in
+    /// `changes` to it, transitively
+    fn add_missing_dependencies<'a>(
+        &'a self,
+        package: &MockPackage,
+        changes: &mut Vec<&'a MockPackage>,
+    ) {
+        for name in &package.depends {
+            if self.lock_installed().contains_key(name)
+                || changes.iter().any(|change| change.name == *name)
+            {
+                continue;
+            }
+            if let Some(dependency) = self.latest(name) {
+                changes.push(dependency);
+                self.add_missing_dependencies(dependency, changes);
+            }
+        }
+    }
+
+    /// Install `package` after its missing dependencies
+    fn install(&self, package: &MockPackage, arch: Option<&str>) -> InstallOutcome {
+        let mut changes = vec![package];
+        self.add_missing_dependencies(package, &mut changes);
+        // Dependencies are installed before the packages needing them
+        changes.reverse();
+
+        let removed: Vec<InstalledPackage> = {
+            let mut db = self.lock_installed();
+            changes
+                .iter()
+                .flat_map(|change| &change.conflicts)
+                .filter_map(|name| db.remove(name))
+                .collect()
+        };
+        let downloaded_bytes = changes
+            .iter()
+            .filter_map(|change| change.size)
+            .reduce(|total, size| total + size);
+
+        let count = removed.len() + changes.len();
+        let mut lines = Vec::new();
+        for (index, package) in removed.iter().enumerate() {
+            let line = format!(
+                "({}/{count}) Purging {} ({})",
+                index + 1,
+                package.name,
+                package.version
+            );
+            exec::emit(OutputLine {
+                stream: OutputStream::Stdout,
+                line: line.clone(),
+            });
+            lines.push(line);
+        }
+        let mut installed = Vec::new();
+        for (index, change) in changes.into_iter().enumerate() {
+            let step = removed.len() + index + 1;
+            let reason = if change.name == package.name {
+                InstallReason::Explicit
+            } else {
+                InstallReason::Dependency
+            };
+            let (line, entry) = self.install_one(change, arch, reason, (step, count));
+            lines.push(line);
+            installed.push(entry);
+        }
+
+        let mut outcome = InstallOutcome::new(
+            &package.name,
+            installed,
+            ExecResult::new(lines.join("\n").as_bytes(), b"", 0),
+        );
+        outcome.removed = removed;
+        outcome.downloaded_bytes = downloaded_bytes;
+        outcome
+    }
+
+    /// Newest available version of `options.package` matching
+    /// `options.version`
+    fn matching_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<&MockPackage, PackageManagerError> {
+        let versions = self.versions(&options.package, None);
+        if versions.is_empty() {
+            return Err(PackageManagerError::NotFound {
+                package: options.package.clone(),
+                searched_repositories: vec![DEFAULT_REPOSITORY.to_string()],
+            });
+        }
+
+        let constraint = VersionConstraint::parse(&options.version);
+        let resolved = VersionScheme::Apk.latest_matching(
+            versions.iter().map(|package| package.version.as_str()),
+            &constraint,
+        );
+        match versions
+            .iter()
+            .find(|package| Some(package.version.as_str()) == resolved)
+        {
+            Some(package) => Ok(*package),
+            None => {
+                let mut available_versions: Vec<String> = versions
+                    .iter()
+                    .map(|package| package.version.clone())
+                    .collect();
+                VersionScheme::Apk.sort(&mut available_versions);
+                Err(PackageManagerError::VersionNotFound {
+                    package: options.package.clone(),
+                    version: options.version.clone(),
+                    available_versions,
+                })
+            }
+        }
+    }
+
+    /// Install `package` as change `step` of a transaction
+    fn install_one(
+        &self,
+        package: &MockPackage,
+        arch: Option<&str>,
+        reason: InstallReason,
+        (step, count): (usize, usize),
+    ) -> (String, InstalledPackage) {
+        self.record_license(package);
+        let previous_version = self
+            .lock_installed()
+            .get(&package.name)
+            .map(|installed| installed.version.clone())
+            .filter(|version| *version != package.version);
+
+        let line = match &previous_version {
+            Some(previous) => format!(
+                "({step}/{count}) {} {} ({} -> {})",
+                if VersionScheme::Apk
+                    .compare(&package.version, previous)
+                    .is_lt()
+                {
+                    "Downgrading"
+                } else {
+                    "Upgrading"
+                },
+                package.name,
+                previous,
+                package.version
+            ),
+            None => format!(
+                "({step}/{count}) Installing {} ({})",
+                package.name, package.version
+            ),
+        };
+        exec::emit(OutputLine {
+            stream: OutputStream::Stdout,
+            line: line.clone(),
+        });
+
+        let installed = InstalledPackage {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            architecture: Some(arch.unwrap_or(std::env::consts::ARCH).to_string()),
+            previous_version,
+            repository: Some(package.repository().to_string()),
+            reason: Some(reason),
+        };
+        self.lock_installed()
+            .insert(package.name.clone(), installed.clone());
+        (line, installed)
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl PackageManager for MockBackend {
+    fn name(&self) -> &'static str {
+        "MOCK"
+    }
+
+    fn os_name(&self) -> &'static str {
+        "Mock OS"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            search_repository_listing: true,
+            privilege_check: true,
+            install_commands: true,
+            vulnerability_check: true,
+            license_report: true,
+            lockfile: true,
+            locked_install: true,
+            signature_check: true,
+            artifact_verification: true,
+            apko_config: true,
+            cloud_init: true,
+            ansible_tasks: true,
+            drift_detection: true,
+            batch_version_install: true,
+            disk_space_check: self.free_space.is_some(),
+            ..Capabilities::default()
+        }
+    }
+
+    async fn package_licenses(&self) -> Result<Vec<PackageLicense>, PackageManagerError> {
+        self.begin("license_report").await?;
+        let licenses = self.licenses.lock().unwrap_or_else(|err| err.into_inner());
+        Ok(self
+            .lock_installed()
+            .values()
+            .map(|package| PackageLicense {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                licenses: licenses.get(&package.name).cloned().into_iter().collect(),
+            })
+            .collect())
+    }
+
+    /// The Alpine release the mock imitates
+    async fn osv_ecosystem(&self) -> Result<String, PackageManagerError> {
+        Ok("Alpine:v3.22".to_string())
+    }
+
+    async fn health_checks(&self) -> Result<Vec<HealthCheck>, PackageManagerError> {
+        let mut checks = vec![HealthCheck::pass(
+            "package_manager",
+            format!("mock {}", env!("CARGO_PKG_VERSION")),
+        )];
+        checks.push(if self.read_only {
+            HealthCheck::fail("lock", "the mock database is read-only")
+        } else {
+            HealthCheck::pass("lock", "the mock database is free")
+        });
+        Ok(checks)
+    }
+
+    async fn system_info(&self) -> Result<SystemInfo, PackageManagerError> {
+        Ok(SystemInfo {
+            package_manager_version: Some(format!("mock {}", env!("CARGO_PKG_VERSION"))),
+            distro_release: Some("Alpine Linux v3.22".to_string()),
+        })
+    }
+
+    async fn indexes_refreshed_at(&self) -> Result<Option<SystemTime>, PackageManagerError> {
+        Ok(Some(*self.lock_refreshed_at()))
+    }
+
+    /// Sizes of the packages and their missing dependencies, on `/`
+    async fn required_space(
+        &self,
+        packages: &[String],
+        arch: Option<&str>,
+        _recommends: Option<Recommends>,
+    ) -> Result<Vec<SpaceRequirement>, PackageManagerError> {
+        self.begin("required_space").await?;
+        let Some(available) = self.free_space else {
+            return Err(PackageManagerError::Unsupported {
+                operation: "required_space",
+            });
+        };
+        let mut changes = Vec::new();
+        for package in packages {
+            let package = match package.split_once('=') {
+                Some((name, version)) => self.matching_version(&InstallVersionOptions {
+                    package: name.to_string(),
+                    version: version.to_string(),
+                    arch: arch.map(str::to_string),
+                    preseed: Vec::new(),
+                    install_recommends: None,
+                    target_release: None,
+                    flags: InstallFlags::default(),
+                })?,
+                None => self
+                    .latest(package)
+                    .ok_or_else(|| PackageManagerError::NotFound {
+                        package: package.clone(),
+                        searched_repositories: vec![DEFAULT_REPOSITORY.to_string()],
+                    })?,
+            };
+            if !changes
+                .iter()
+                .any(|change: &&MockPackage| change.name == package.name)
+            {
+                changes.push(package);
+                self.add_missing_dependencies(package, &mut changes);
+            }
+        }
+        Ok(vec![SpaceRequirement {
+            mount: "/".to_string(),
+            needed: changes.iter().filter_map(|change| change.size).sum(),
+            available,
+        }])
+    }
+
+    /// Renders the commands of the Alpine database the mock imitates
+    async fn install_commands(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        super::apk::Apk::new().install_commands(packages).await
+    }
+
+    async fn ansible_tasks(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<AnsibleTask>, PackageManagerError> {
+        super::apk::Apk::new().ansible_tasks(packages).await
+    }
+
+    /// The repositories of the available packages, without keys, for the
+    /// architecture the server was built for
+    async fn apko_config(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<ApkoConfig, PackageManagerError> {
+        let repositories = self
+            .repositories()
+            .into_iter()
+            .map(|repository| repository.url);
+        Ok(ApkoConfig::new(
+            packages,
+            repositories,
+            std::env::consts::ARCH,
+        ))
+    }
+
+    /// The repositories packages were installed from, without keys
+    async fn cloud_config(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<CloudConfig, PackageManagerError> {
+        Ok(packages
+            .iter()
+            .flat_map(PinnedPackage::custom_repositories)
+            .fold(CloudConfig::new(packages), CloudConfig::with_apk_repository))
+    }
+
+    async fn dockerfile_instructions(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        super::apk::Apk::new()
+            .dockerfile_instructions(packages)
+            .await
+    }
+
+    fn version_scheme(&self) -> Option<VersionScheme> {
+        Some(VersionScheme::Apk)
+    }
+
+    /// Checksums and repositories of the available packages
+    async fn lock_packages(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<LockedPackage>, PackageManagerError> {
+        self.begin("generate_lockfile").await?;
+        Ok(packages
+            .iter()
+            .map(|pinned| {
+                let available = self.available.iter().find(|package| {
+                    package.name == pinned.name && package.version == pinned.version
+                });
+                LockedPackage {
+                    source: pinned
+                        .repository
+                        .clone()
+                        .or_else(|| available.map(|package| package.repository().to_string())),
+                    checksum: available.and_then(|package| package.checksum.clone()),
+                    ..LockedPackage::new(pinned.clone())
+                }
+            })
+            .collect())
+    }
+
+    /// Installs the exact versions in one transaction
+    async fn install_locked(
+        &self,
+        packages: &[LockedPackage],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        self.begin("install_from_lockfile").await?;
+
+        let mut changes = Vec::new();
+        for locked in packages {
+            let pinned = &locked.package;
+            let versions = self.versions(&pinned.name, pinned.repository.as_deref());
+            match versions
+                .iter()
+                .find(|package| package.version == pinned.version)
+            {
+                Some(package) => changes.push((*package, pinned.arch.as_deref())),
+                None => {
+                    let mut available_versions: Vec<String> = versions
+                        .iter()
+                        .map(|package| package.version.clone())
+                        .collect();
+                    VersionScheme::Apk.sort(&mut available_versions);
+                    return Err(PackageManagerError::VersionNotFound {
+                        package: pinned.name.clone(),
+                        version: pinned.version.clone(),
+                        available_versions,
+                    });
+                }
+            }
+        }
+
+        let count = changes.len();
+        let mut lines = Vec::new();
+        let mut installed = Vec::new();
+        for (index, (package, arch)) in changes.into_iter().enumerate() {
+            let (line, entry) =
+                self.install_one(package, arch, InstallReason::Explicit, (index + 1, count));
+            lines.push(line);
+            installed.push(entry);
+        }
+
+        let names: Vec<String> = packages
+            .iter()
+            .map(|package| package.package.name.clone())
+            .collect();
+        Ok(InstallOutcome::batch(
+            &names,
+            installed,
+            ExecResult::new(lines.join("\n").as_bytes(), b"", 0),
+        ))
+    }
+
+    async fn fetch_package(
+        &self,
+        package: &PinnedPackage,
+    ) -> Result<FetchedPackage, PackageManagerError> {
+        self.begin("fetch_package").await?;
+        let available = self.available_version(package)?;
+        Ok(FetchedPackage {
+            package: package.clone(),
+            path: format!("/var/cache/mock/{}-{}.apk", package.name, package.version),
+            sha256: available.sha256(),
+            signing_key: available.signing_key.clone(),
+        })
+    }
+
+    async fn install_fetched(
+        &self,
+        package: &FetchedPackage,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        self.begin("install_package").await?;
+        let available = self.available_version(&package.package)?;
+        Ok(self.install(available, package.package.arch.as_deref()))
+    }
+
+    async fn install_package(
+        &self,
+        options: &InstallOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        self.begin("install_package").await?;
+
+        let package = self
+            .versions(&options.package, options.repository.as_deref())
+            .into_iter()
+            .max_by(|a, b| VersionScheme::Apk.compare(&a.version, &b.version))
+            .ok_or_else(|| PackageManagerError::NotFound {
+                package: options.package.clone(),
+                searched_repositories: vec![
+                    options
+                        .repository
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_REPOSITORY.to_string()),
+                ],
+            })?;
+
+        Ok(self.install(package, options.arch.as_deref()))
+    }
+
+    async fn install_package_with_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        self.begin("install_package_with_version").await?;
+        let package = self.matching_version(options)?;
+        Ok(self.install(package, options.arch.as_deref()))
+    }
+
+    async fn resolve_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<ResolvedVersion, PackageManagerError> {
+        self.begin("resolve_version").await?;
+        let package = self.matching_version(options)?;
+        Ok(ResolvedVersion {
+            package: package.name.clone(),
+            version: package.version.clone(),
+            repositories: vec![package.repository().to_string()],
+        })
+    }
+
+    /// Installs the matching versions in one transaction, resolving them
+    /// all before installing any
+    async fn install_packages_with_versions(
+        &self,
+        packages: &[InstallVersionOptions],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        self.begin("install_package_with_version").await?;
+
+        let changes = packages
+            .iter()
+            .map(|options| Ok((self.matching_version(options)?, options.arch.as_deref())))
+            .collect::<Result<Vec<_>, PackageManagerError>>()?;
+
+        let count = changes.len();
+        let mut lines = Vec::new();
+        let mut installed = Vec::new();
+        for (index, (package, arch)) in changes.into_iter().enumerate() {
+            let (line, entry) =
+                self.install_one(package, arch, InstallReason::Explicit, (index + 1, count));
+            lines.push(line);
+            installed.push(entry);
+        }
+
+        let names: Vec<String> = packages
+            .iter()
+            .map(|options| options.package.clone())
+            .collect();
+        Ok(InstallOutcome::batch(
+            &names,
+            installed,
+            ExecResult::new(lines.join("\n").as_bytes(), b"", 0),
+        ))
+    }
+
+    async fn search_package(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        self.begin("search_package").await?;
+
+        let matches = options.mode.matcher(&options.query);
+        Ok(self
+            .available
+            .iter()
+            .filter(|package| self.indexed(package))
+            .filter(|package| matches(&package.name, package.description.as_deref()))
+            .filter(|package| {
+                options
+                    .repository
+                    .as_deref()
+                    .is_none_or(|repository| package.repository() == repository)
+            })
+            .map(|package| SearchResult {
+                name: package.name.clone(),
+                version: Some(package.version.clone()),
+                repository: Some(package.repository().to_string()),
+                description: package.description.clone(),
+                arch: None,
+                license: None,
+                maintainer: None,
+                build_date: None,
+            })
+            .collect())
+    }
+
+    async fn list_installed_packages(&self) -> Result<Vec<InstalledPackage>, PackageManagerError> {
+        self.begin("list_installed_packages").await?;
+
+        Ok(self
+            .lock_installed()
+            .values()
+            .map(|installed| InstalledPackage {
+                previous_version: None,
+                ..installed.clone()
+            })
+            .collect())
+    }
+
+    async fn refresh_repositories(&self) -> Result<ExecResult, PackageManagerError> {
+        self.begin("refresh_repositories").await?;
+        self.refreshed.store(true, Ordering::Relaxed);
+        *self.lock_refreshed_at() = SystemTime::now();
+
+        let line = format!(
+            "{DEFAULT_REPOSITORY} [OK: {} packages available]",
+            self.available.len()
+        );
+        exec::emit(OutputLine {
+            stream: OutputStream::Stdout,
+            line: line.clone(),
+        });
+        Ok(ExecResult::new(line.as_bytes(), b"", 0))
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        self.begin("list_repositories").await?;
+        Ok(self.repositories())
+    }
+
+    async fn list_search_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        // Searches cover every repository of the database
+        self.begin("list_search_repositories").await?;
+        Ok(self.repositories())
+    }
+
+    async fn database_fingerprint(&self) -> Result<String, PackageManagerError> {
+        let database: String = self
+            .lock_installed()
+            .values()
+            .map(|package| format!("{}={}\n", package.name, package.version))
+            .collect();
+        Ok(drift::fingerprint(database.as_bytes()))
+    }
+
+    async fn unsigned_repositories(
+        &self,
+        repository: Option<&str>,
+    ) -> Result<Vec<UnsignedRepository>, PackageManagerError> {
+        let repositories = self.repositories();
+        let configured = repositories
+            .iter()
+            .map(|repository| repository.url.as_str());
+        Ok(configured
+            .chain(repository)
+            .filter(|repository| self.unsigned.iter().any(|unsigned| unsigned == repository))
+            .map(|repository| UnsignedRepository::new(repository, "the repository is not signed"))
+            .collect())
+    }
+
+    async fn check_privileges(&self) -> Result<PrivilegeReport, PackageManagerError> {
+        self.begin("check_privileges").await?;
+        let mut report = PrivilegeReport::unrestricted("mock database");
+        if self.read_only {
+            report.database_writable = false;
+            report.can_mutate = false;
+            report.problems = vec!["the mock database is read-only".to_string()];
+        }
+        Ok(report)
+    }
+}
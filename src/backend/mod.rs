@@ -1,627 +1,6319 @@
+pub mod allowlist;
 pub mod apk;
+pub mod apkindex;
+pub mod approval;
 pub mod apt;
+pub mod batch;
+pub mod cache;
+pub mod credentials;
+pub mod descriptions;
+pub mod diagnostics;
+pub mod doctor;
+pub mod drift;
+pub mod exec;
+pub mod exit_status;
+pub mod freshness;
+pub mod generic;
+pub mod idempotency;
+pub mod jobs;
+pub mod licenses;
+pub mod listing;
+pub mod local;
+pub mod mirrors;
+pub mod mock;
+pub mod naming;
+pub mod normalize;
+pub mod notifications;
+pub mod offline;
+pub mod operation_log;
+pub mod osv;
+pub mod parse;
+pub mod pins;
+pub mod pkgs;
+pub mod plugin;
+pub mod private_dir;
+pub mod privileges;
+pub mod registry;
+pub mod repology;
+pub mod runner;
+pub mod signing;
+pub mod snapshot;
+pub mod targets;
+pub mod validation;
 
+use async_trait::async_trait;
 use rmcp::{
     ErrorData as McpError, RoleServer, ServerHandler, model::*, service::RequestContext,
     tool_router,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 
+use crate::ansible::AnsibleTask;
+use crate::apko::ApkoConfig;
+use crate::audit::{AuditEntry, AuditLog, AuditOutcome};
+use crate::cloudinit::CloudConfig;
+use crate::error::{PackageManagerError, with_error_code};
+use crate::events::EventBus;
+use crate::hooks::Hooks;
+use crate::identity::ClientIdentity;
+use crate::lockfile::{LockProblem, Lockfile};
+use crate::operation::{Operation, OutputFormat};
+use crate::quota::{QuotaLimits, QuotaReservation, QuotaUsage};
+use crate::session::{ExportFormat, ExportScope, SessionDelta, SessionLog};
+use crate::version::{VersionConstraint, VersionScheme};
+use doctor::HealthCheck;
+use exit_status::ExitMeaning;
+use privileges::PrivilegeReport;
+use runner::CommandRunner;
+use signing::UnsignedRepository;
+pub(crate) use validation::{validate_arch, validate_root};
+
 /// Result of executing a package manager command
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ExecResult {
     pub stdout: Option<String>,
     pub stderr: Option<String>,
     pub status: i32,
+    /// Whether output was dropped from the middle of stdout or stderr, see
+    /// [`exec::set_output_limit`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Command line that was run, as the runner executing it on the target
+    /// saw it, e.g. wrapped in `ssh` or `sudo`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
 }
 
-/// Options for installing a package
-pub struct InstallOptions {
-    pub package: String,
+impl From<std::process::Output> for ExecResult {
+    fn from(output: std::process::Output) -> Self {
+        Self::new(
+            &output.stdout,
+            &output.stderr,
+            output.status.code().unwrap_or(-1),
+        )
+    }
+}
+
+impl ExecResult {
+    /// Build a result from raw command output
+    pub fn new(stdout: &[u8], stderr: &[u8], status: i32) -> Self {
+        Self {
+            stdout: if !stdout.is_empty() {
+                Some(String::from_utf8_lossy(stdout).to_string())
+            } else {
+                None
+            },
+            stderr: if !stderr.is_empty() {
+                Some(String::from_utf8_lossy(stderr).to_string())
+            } else {
+                None
+            },
+            status,
+            truncated: false,
+            command: None,
+        }
+    }
+
+    /// Turn a non-zero exit status into a `CommandFailed` error, and
+    /// truncated output of a successful command into an `OutputTruncated`
+    /// one, since parsing it would silently miss what was dropped
+    pub fn success(self) -> Result<Self, PackageManagerError> {
+        if self.status != 0 {
+            Err(PackageManagerError::CommandFailed(self))
+        } else if self.truncated {
+            Err(PackageManagerError::OutputTruncated {
+                command: self.command,
+                limit: exec::output_limit(),
+            })
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+/// A package installed on the system
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+    /// Version replaced by this one, when the package was upgraded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_version: Option<String>,
+    /// Repository the package was installed from, when the listing reports
+    /// it: the APT archives (`stable`, `bookworm-updates`) or the APK
+    /// repository tag the package is pinned to (`edge`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub repository: Option<String>,
+    /// Why the package is installed, when the package manager records it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<InstallReason>,
 }
 
-/// Options for installing a package with a specific version
-pub struct InstallVersionOptions {
-    pub package: String,
+/// Why a package is installed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallReason {
+    /// Requested by name (APK world, APT manual installs)
+    Explicit,
+    /// Pulled in by another package and removable once nothing needs it
+    Dependency,
+}
+
+/// Licenses declared by an installed package
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageLicense {
+    pub name: String,
     pub version: String,
+    /// License expressions or names, empty when the package declares none
+    pub licenses: Vec<String>,
 }
 
-/// Options for searching packages
-pub struct SearchOptions {
-    pub query: String,
+/// A package to reinstall at the version it was installed at
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinnedPackage {
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// Custom repository the package was installed from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub repository: Option<String>,
+    /// Further custom repositories its install used, after `repository`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub repositories: Vec<String>,
 }
 
-/// Trait defining the interface for package manager backends
-pub trait PackageManager: Clone + Send + Sync + 'static {
-    /// Returns the name of the package manager (e.g., "APK", "APT")
-    fn name(&self) -> &'static str;
+impl PinnedPackage {
+    /// Every custom repository the install used, `repository` first
+    pub fn custom_repositories(&self) -> impl Iterator<Item = &str> {
+        self.repository
+            .iter()
+            .chain(&self.repositories)
+            .map(String::as_str)
+    }
+}
 
-    /// Returns the OS name (e.g., "Alpine Linux", "Debian/Debian-derivative")
-    fn os_name(&self) -> &'static str;
+/// A package pinned in a lockfile, with what identifies its exact build
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedPackage {
+    #[serde(flatten)]
+    pub package: PinnedPackage,
+    /// Repository the package manager finds this version in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Checksum in the package manager's own notation: `sha256:<hex>` of
+    /// the `.deb` for APT, the `Q1`-prefixed SHA-1 of the package's control
+    /// data for APK
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Whether the package was pulled in by another one rather than
+    /// requested
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dependency: bool,
+}
 
-    /// Install a package (latest version)
-    fn install_package(&self, options: &InstallOptions) -> Result<ExecResult, McpError>;
+impl LockedPackage {
+    /// `package` without a known source or checksum
+    pub fn new(package: PinnedPackage) -> Self {
+        Self {
+            package,
+            source: None,
+            checksum: None,
+            dependency: false,
+        }
+    }
+}
 
-    /// Install a package with a specific version
-    fn install_package_with_version(
-        &self,
-        options: &InstallVersionOptions,
-    ) -> Result<ExecResult, McpError>;
+/// A package file downloaded without installing it, to check it first
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FetchedPackage {
+    /// Package and version the file holds
+    pub package: PinnedPackage,
+    /// Path of the file on the managed system
+    pub path: String,
+    /// Lowercase hex SHA-256 of the file
+    pub sha256: String,
+    /// Name of the key the file is signed with, for formats signing
+    /// packages themselves
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+}
 
-    /// Search for packages
-    fn search_package(&self, options: &SearchOptions) -> Result<ExecResult, McpError>;
+/// Lock `packages` to the first checksum and source listed for their
+/// version (and architecture, when both sides name one)
+///
+/// Packages installed from a custom repository keep it as their source.
+pub(crate) fn lock_packages_with(
+    packages: &[PinnedPackage],
+    checksums: &[LockedPackage],
+    sources: &[SearchResult],
+) -> Vec<LockedPackage> {
+    packages
+        .iter()
+        .map(|package| {
+            let same_arch = |arch: Option<&str>| {
+                arch.zip(package.arch.as_deref())
+                    .is_none_or(|(arch, pinned)| arch == pinned)
+            };
+            let checksum = checksums
+                .iter()
+                .filter(|locked| {
+                    locked.package.name == package.name
+                        && locked.package.version == package.version
+                        && same_arch(locked.package.arch.as_deref())
+                })
+                .find_map(|locked| locked.checksum.clone());
+            let source = package.repository.clone().or_else(|| {
+                sources
+                    .iter()
+                    .filter(|source| {
+                        source.name == package.name
+                            && source.version.as_deref() == Some(package.version.as_str())
+                    })
+                    .find_map(|source| source.repository.clone())
+            });
+            LockedPackage {
+                source,
+                checksum,
+                ..LockedPackage::new(package.clone())
+            }
+        })
+        .collect()
+}
 
-    /// List installed packages
-    fn list_installed_packages(&self) -> Result<ExecResult, McpError>;
+/// A package matching a search query
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Architecture of the package, when the listing reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// License expression of the package, when the listing reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    /// Maintainer of the package, when the listing reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintainer: Option<String>,
+    /// When the package was built (RFC 3339), when the listing reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_date: Option<String>,
+}
 
-    /// Refresh repository indexes
-    fn refresh_repositories(&self) -> Result<ExecResult, McpError>;
+/// Version of the package manager and release of the system it manages
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemInfo {
+    /// First line the package manager prints for `--version`, e.g.
+    /// `apk-tools 2.14.9, compiled for x86_64.`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_manager_version: Option<String>,
+    /// Name of the distribution release, e.g. `Alpine Linux v3.22` or
+    /// `Debian GNU/Linux 12 (bookworm)`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distro_release: Option<String>,
 }
 
-/// Generic MCP handler that wraps any PackageManager implementation
-#[derive(Clone)]
-pub struct PackageManagerHandler<T: PackageManager> {
-    backend: T,
+/// Disk space an install needs on a file system, and the space free on it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpaceRequirement {
+    /// Mount point of the file system
+    pub mount: String,
+    /// Bytes the install writes to the file system
+    pub needed: u64,
+    /// Bytes free on the file system
+    pub available: u64,
 }
 
-#[tool_router]
-impl<T: PackageManager> PackageManagerHandler<T> {
-    pub fn new(backend: T) -> Self {
-        Self { backend }
-    }
+/// A package repository configured on the system
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Repository {
+    pub url: String,
+    /// Archive types (APT `deb`/`deb-src`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub types: Vec<String>,
+    /// Distribution suites (APT), e.g. `bookworm` and `bookworm-updates`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suites: Vec<String>,
+    /// Archive components (APT), e.g. `main` and `contrib`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<String>,
+    /// Keyring or key used to verify the repository (APT `Signed-By`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_by: Option<String>,
+    /// Whether the package manager skips verifying the repository's
+    /// signatures (APT `trusted=yes`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub trusted: bool,
+    /// File the repository is configured in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Tag of the repository (APK `@testing`), whose packages are only
+    /// installed when requested as `package@testing`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
 }
 
-impl<T: PackageManager> ServerHandler for PackageManagerHandler<T> {
-    fn get_info(&self) -> ServerInfo {
-        let instructions = format!(
-            "This MCP server provides {} package management capabilities through the {} package manager. \
-            Use this server to search for, install, update, list installed packages, and manage packages on {} systems. \
-            The server executes {} commands with appropriate error handling and provides detailed feedback on operations.",
-            self.backend.os_name(),
-            self.backend.name(),
-            self.backend.os_name(),
-            self.backend.name()
-        );
+fn enabled_by_default() -> bool {
+    true
+}
 
-        ServerInfo {
-            protocol_version: ProtocolVersion::V_2025_03_26,
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
-            server_info: Implementation::from_build_env(),
-            instructions: Some(instructions),
+impl Repository {
+    /// A repository identified only by its URL
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            types: Vec::new(),
+            suites: Vec::new(),
+            components: Vec::new(),
+            signed_by: None,
+            trusted: false,
+            file: None,
+            tag: None,
+            enabled: true,
         }
     }
 
-    async fn list_tools(
-        &self,
-        _request: Option<PaginatedRequestParam>,
-        _: RequestContext<RoleServer>,
-    ) -> Result<ListToolsResult, McpError> {
-        let pm_name = self.backend.name();
-        let os_name = self.backend.os_name();
-        let pm_lower = pm_name.to_lowercase();
-
-        Ok(ListToolsResult {
-            tools: vec![
-                Tool {
-                    name: "install_package".into(),
-                    description: Some(std::borrow::Cow::Owned(format!(
-                        "Install {} packages using the {} package manager. This tool executes '{}' commands with proper error handling. \
-                        Use this when you need to install the latest version of software packages, libraries, or development tools on {} systems. \
-                        If you need to install a specific version, use the install_package_with_version tool.",
-                        os_name, pm_name,
-                        if pm_lower == "apk" { "apk add" } else { "apt-get install" },
-                        os_name
-                    ))),
-                    input_schema: Arc::new(
-                        serde_json::from_value(serde_json::json!({
-                            "type": "object",
-                            "properties": {
-                                "package_name": {
-                                    "type": "string",
-                                    "description": format!(
-                                        "The exact name of the {} package to install (e.g., 'curl', 'python3', 'git'). \
-                                        Package names are case-sensitive and should match the official package names in {} repositories. \
-                                        Multiple packages can be specified by calling this tool multiple times.",
-                                        os_name, os_name
-                                    )
-                                },
-                                "repository": {
-                                    "type": "string",
-                                    "description": if pm_lower == "apk" {
-                                        "Optional: Custom repository URL to use for package installation. Use this when you need to install packages from non-standard repositories or specific Alpine mirrors. Format should be a valid APK repository URL (e.g., 'https://dl-cdn.alpinelinux.org/alpine/edge/testing'). If not provided, the system's default configured repositories will be used.".to_string()
-                                    } else {
-                                        "Optional: Path to a custom sources.list file to use for package installation. If not provided, the system's default configured repositories will be used.".to_string()
-                                    }
-                                },
-                            },
-                            "required": ["package_name"]
-                        })).map_err(|e| McpError::internal_error(format!("failed to parse install_package schema: {e}"), None))?,
-                    ),
-                    annotations: Some(ToolAnnotations {
-                        idempotent_hint: Some(true),
-                        open_world_hint: Some(true),
-                        ..Default::default()
-                    }),
-                },
-                Tool {
-                    name: "install_package_with_version".into(),
-                    description: Some(std::borrow::Cow::Owned(format!(
-                        "Install a specific version of a {os_name} package. This tool searches {os_name} repositories to find the requested package version, \
-                        then installs it using exact version matching. Use this when you need to install a specific version of a package rather than the latest available version."
-                    ))),
-                    input_schema: Arc::new(
-                        serde_json::from_value(serde_json::json!({
-                            "type": "object",
-                            "properties": {
-                                "package_name": {
-                                    "type": "string",
-                                    "description": format!(
-                                        "The exact name of the {} package to install (e.g., 'curl', 'python3', 'git'). \
-                                        Package names are case-sensitive and should match the official package names in {} repositories.",
-                                        os_name, os_name
-                                    )
-                                },
-                                "version": {
-                                    "type": "string",
-                                    "description": format!(
-                                        "The specific version of the package to install. The version string must match exactly as it appears in the repository. \
-                                        If no exact match is found, the tool will return a list of available versions."
-                                    )
-                                },
-                            },
-                            "required": ["package_name", "version"]
-                        })).map_err(|e| McpError::internal_error(format!("failed to parse install_package_with_version schema: {e}"), None))?,
-                    ),
-                    annotations: Some(ToolAnnotations {
-                        idempotent_hint: Some(true),
-                        open_world_hint: Some(true),
-                        ..Default::default()
-                    }),
-                },
-                Tool {
-                    name: "refresh_repositories".into(),
-                    description: Some(std::borrow::Cow::Owned(format!(
-                        "Refresh registered repository indexes using '{}'. This tool synchronizes the local package database with remote repositories, \
-                        ensuring you have access to the latest package information and versions. Use this before installing packages to get the most up-to-date package lists.",
-                        if pm_lower == "apk" { "apk update" } else { "apt-get update" }
-                    ))),
-                    input_schema: Arc::new(
-                        serde_json::from_value(serde_json::json!({
-                            "type": "object",
-                            "properties": {},
-                            "required": []
-                        })).map_err(|e| McpError::internal_error(format!("failed to parse refresh_repositories schema: {e}"), None))?,
-                    ),
-                    annotations: Some(ToolAnnotations {
-                        idempotent_hint: Some(true),
-                        open_world_hint: Some(true),
-                        ..Default::default()
-                    }),
-                },
-                Tool {
-                    name: "list_installed_packages".into(),
-                    description: Some(std::borrow::Cow::Owned(format!(
-                        "List all installed packages on {} using '{}'. This tool shows all packages currently installed on the system with their versions. \
-                        Use this to audit installed software, check package versions, or verify installations.",
-                        os_name,
-                        if pm_lower == "apk" { "apk list -I" } else { "apt list --installed" }
-                    ))),
-                    input_schema: Arc::new(
-                        serde_json::from_value(serde_json::json!({
-                            "type": "object",
-                            "properties": {},
-                            "required": []
-                        })).map_err(|e| McpError::internal_error(format!("failed to parse list_installed_packages schema: {e}"), None))?,
-                    ),
-                    annotations: Some(ToolAnnotations {
-                        idempotent_hint: Some(true),
-                        open_world_hint: Some(false),
-                        ..Default::default()
-                    }),
-                },
-                Tool {
-                    name: "search_package".into(),
-                    description: Some(std::borrow::Cow::Owned(format!(
-                        "Search for {} packages using the {} package manager. This tool executes '{}' commands to find packages matching your query. \
-                        Use this when you need to discover available packages, find package names, or explore what software is available.",
-                        os_name, pm_name,
-                        if pm_lower == "apk" { "apk search" } else { "apt-cache search" }
-                    ))),
-                    input_schema: Arc::new(
-                        serde_json::from_value(serde_json::json!({
-                            "type": "object",
-                            "properties": {
-                                "query": {
-                                    "type": "string",
-                                    "description": format!(
-                                        "Package name pattern to search for. Use exact package names (e.g., 'ruby', 'python3') or patterns to match multiple packages. \
-                                        If you don't know the package name, try with specific package names first to avoid excessive output."
-                                    )
-                                },
-                                "repository": {
-                                    "type": "string",
-                                    "description": if pm_lower == "apk" {
-                                        "Optional: Specific repository URL to search in. If not provided, the search will query across multiple Alpine repositories (edge, v3.22, v3.21, v3.20, etc.) to find all available versions of matching packages.".to_string()
-                                    } else {
-                                        "Optional: This parameter is not used for APT searches. APT searches use the system's configured repositories.".to_string()
-                                    }
-                                },
-                            },
-                            "required": ["query"]
-                        })).map_err(|e| McpError::internal_error(format!("failed to parse search_package schema: {e}"), None))?,
-                    ),
-                    annotations: Some(ToolAnnotations {
-                        idempotent_hint: Some(true),
-                        open_world_hint: Some(true),
-                        ..Default::default()
-                    }),
+    /// One-line APT entries of the repository, one per type and suite
+    ///
+    /// An inline `Signed-By` key cannot be written on one line and is left
+    /// out.
+    pub fn source_lines(&self) -> Vec<String> {
+        let options = match &self.signed_by {
+            Some(signed_by) if !signed_by.contains('\n') => format!("[signed-by={signed_by}] "),
+            _ => String::new(),
+        };
+        let kinds = if self.types.is_empty() {
+            vec!["deb".to_string()]
+        } else {
+            self.types.clone()
+        };
+        let mut lines = Vec::new();
+        for kind in &kinds {
+            for suite in &self.suites {
+                let mut line = format!("{kind} {options}{} {suite}", self.url);
+                for component in &self.components {
+                    line.push(' ');
+                    line.push_str(component);
                 }
-            ],
-            next_cursor: None,
-        })
+                lines.push(line);
+            }
+        }
+        lines
     }
+}
 
-    async fn call_tool(
-        &self,
-        request: CallToolRequestParam,
-        _context: RequestContext<RoleServer>,
-    ) -> Result<CallToolResult, McpError> {
-        let pm_name = self.backend.name();
-        let backend = self.backend.clone();
-
-        match request.name.as_ref() {
-            "install_package" => {
-                let package = request
-                    .arguments
-                    .as_ref()
-                    .and_then(|args| {
-                        args.get("package_name")
-                            .and_then(|package_name| package_name.as_str())
-                    })
-                    .ok_or_else(|| {
-                        McpError::invalid_params("missing required parameter: package_name", None)
-                    })?
-                    .to_string();
-
-                let repository = request
-                    .arguments
-                    .as_ref()
-                    .and_then(|args| {
-                        args.get("repository")
-                            .and_then(|repository| repository.as_str())
-                    })
-                    .map(|repository| repository.to_string());
-
-                let install_options = InstallOptions {
-                    package: package.clone(),
-                    repository: repository.clone(),
-                };
+/// Version a versioned install would pick
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolvedVersion {
+    pub package: String,
+    /// Newest available version matching the requested one
+    pub version: String,
+    /// Repositories offering the version, when the package manager reports
+    /// them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub repositories: Vec<String>,
+}
 
-                let package_installation =
-                    tokio::task::spawn_blocking(move || backend.install_package(&install_options))
-                        .await
-                        .map_err(|err| {
-                            McpError::internal_error(
-                                format!(
-                                    "there was an error spawning installation process for package {package}: {err:?}"
-                                ),
-                                None,
-                            )
-                        })?;
+/// Outcome of a successful package installation
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstallOutcome {
+    /// Requested package
+    pub package: String,
+    /// Installed version of the requested package, when reported by the package manager
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Every package the operation installed or upgraded, including dependencies
+    pub installed: Vec<InstalledPackage>,
+    /// Packages the operation removed, e.g. conflicting packages or
+    /// dependencies an upgrade dropped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<InstalledPackage>,
+    /// Bytes the package manager downloaded, when it reports them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloaded_bytes: Option<u64>,
+    /// Repository the requested version was found in and installed from,
+    /// when the backend added it to the configured ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    /// Raw command output, kept for diagnostics
+    #[serde(skip)]
+    pub diagnostics: ExecResult,
+}
 
-                match package_installation {
-                    Ok(exec_result) => {
-                        if exec_result.status == 0 {
-                            let success_message =
-                                format!("Package '{package}' was installed successfully.");
-                            Ok(CallToolResult::success(vec![Content::text(
-                                success_message,
-                            )]))
-                        } else {
-                            let error_message = format!(
-                                "Failed to install package '{package}' (exit code: {})",
-                                exec_result.status
-                            );
-                            let mut error_details = serde_json::json!({
-                                "package_name": package,
-                                "exit_code": exec_result.status,
-                                "package_manager": pm_name
-                            });
+impl InstallOutcome {
+    fn new(package: &str, installed: Vec<InstalledPackage>, diagnostics: ExecResult) -> Self {
+        let version = installed
+            .iter()
+            .find(|installed| installed.name == package)
+            .map(|installed| installed.version.clone());
 
-                            if let Some(stdout) = exec_result.stdout {
-                                error_details["stdout"] = serde_json::Value::String(stdout);
-                            }
-                            if let Some(stderr) = exec_result.stderr {
-                                error_details["stderr"] = serde_json::Value::String(stderr);
-                            }
+        Self {
+            package: package.to_string(),
+            version,
+            installed,
+            removed: Vec::new(),
+            downloaded_bytes: None,
+            repository: None,
+            diagnostics,
+        }
+    }
 
-                            Err(McpError::internal_error(error_message, Some(error_details)))
-                        }
-                    }
-                    Err(err) => Err(McpError::internal_error(
-                        format!(
-                            "System error while installing package '{package}': {err:?}. This may indicate {pm_name} is not available or there are permission issues."
-                        ),
-                        Some(serde_json::json!({
-                            "package_name": package,
-                            "error_type": "system_error",
-                            "suggestion": format!("Ensure {} package manager is installed and you have sufficient privileges", pm_name)
-                        })),
-                    )),
+    /// Outcomes of the requested `packages` installed by one command
+    ///
+    /// Each outcome lists its own package; dependencies and other changes are
+    /// listed by the first outcome, so every change is reported once.
+    fn batch(
+        packages: &[String],
+        installed: Vec<InstalledPackage>,
+        diagnostics: ExecResult,
+    ) -> Vec<Self> {
+        let mut outcomes: Vec<Self> = packages
+            .iter()
+            .map(|package| Self::new(package, Vec::new(), diagnostics.clone()))
+            .collect();
+        for change in installed {
+            let owner = outcomes
+                .iter_mut()
+                .position(|outcome| outcome.package == change.name)
+                .unwrap_or(0);
+            if let Some(outcome) = outcomes.get_mut(owner) {
+                if outcome.package == change.name {
+                    outcome.version = Some(change.version.clone());
                 }
+                outcome.installed.push(change);
             }
-            "install_package_with_version" => {
-                let package = request
-                    .arguments
-                    .as_ref()
-                    .and_then(|args| {
-                        args.get("package_name")
-                            .and_then(|package_name| package_name.as_str())
-                    })
-                    .ok_or_else(|| {
-                        McpError::invalid_params("missing required parameter: package_name", None)
-                    })?
-                    .to_string();
-
-                let version = request
-                    .arguments
-                    .as_ref()
-                    .and_then(|args| args.get("version").and_then(|version| version.as_str()))
-                    .ok_or_else(|| {
-                        McpError::invalid_params("missing required parameter: version", None)
-                    })?
-                    .to_string();
+        }
+        outcomes
+    }
+}
 
-                let install_version_options = InstallVersionOptions {
-                    package: package.clone(),
-                    version: version.clone(),
-                };
+/// Create a command for the given program, to be run by a [`CommandRunner`]
+pub(crate) fn command(program: &str) -> runner::Command {
+    runner::Command::new(program)
+}
 
-                let package_installation = tokio::task::spawn_blocking(move || {
-                    backend.install_package_with_version(&install_version_options)
-                })
-                .await
-                .map_err(|err| {
-                    McpError::internal_error(
-                        format!(
-                            "there was an error spawning installation process for package {package}={version}: {err:?}"
-                        ),
-                        None,
-                    )
-                })?;
+/// Serialize debconf selections with the values of `password` questions
+/// masked, keeping them out of the audit log, history, exports and approval
+/// requests; backends get the values through [`PackageManager::preseed`]
+fn serialize_preseed<S: serde::Serializer>(
+    selections: &[String],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        selections
+            .iter()
+            .map(|selection| validation::masked_selection(selection)),
+    )
+}
 
-                match package_installation {
-                    Ok(exec_result) => {
-                        if exec_result.status == 0 {
-                            let success_message = format!(
-                                "Package '{package}' version '{version}' was installed successfully."
-                            );
-                            Ok(CallToolResult::success(vec![Content::text(
-                                success_message,
-                            )]))
-                        } else {
-                            let error_message = format!(
-                                "Failed to install package '{package}' version '{version}' (exit code: {})",
-                                exec_result.status
-                            );
-                            let mut error_details = serde_json::json!({
-                                "package_name": package,
-                                "version": version,
-                                "exit_code": exec_result.status,
-                                "package_manager": pm_name
-                            });
+/// Options for installing a package
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct InstallOptions {
+    pub package: String,
+    pub repository: Option<String>,
+    /// Repositories added to the configured ones after `repository`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub repositories: Vec<String>,
+    /// Repositories used instead of the configured ones, when not empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub only_repositories: Vec<String>,
+    /// Architecture to install the package for, when not the host's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// debconf selections to set before installing, as
+    /// `package question type value` lines
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "serialize_preseed"
+    )]
+    pub preseed: Vec<String>,
+    /// Which weaker dependencies to install, when not the server's default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub install_recommends: Option<Recommends>,
+    /// Release whose packages are preferred, e.g. `bookworm-backports`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_release: Option<String>,
+    #[serde(flatten)]
+    pub flags: InstallFlags,
+}
 
-                            if let Some(stdout) = exec_result.stdout {
-                                error_details["stdout"] = serde_json::Value::String(stdout);
-                            }
-                            if let Some(stderr) = exec_result.stderr {
-                                error_details["stderr"] = serde_json::Value::String(stderr);
-                            }
+impl InstallOptions {
+    /// Custom repositories added to the configured ones: `repository`, then
+    /// `repositories`
+    pub fn added_repositories(&self) -> Vec<&str> {
+        self.repository
+            .iter()
+            .chain(&self.repositories)
+            .map(String::as_str)
+            .collect()
+    }
+}
 
-                            Err(McpError::internal_error(error_message, Some(error_details)))
-                        }
-                    }
-                    Err(err) => Err(err),
-                }
-            }
-            "refresh_repositories" => {
-                let repository_refresh = tokio::task::spawn_blocking(move || {
-                    backend.refresh_repositories()
-                })
-                .await
-                .map_err(|err| {
-                    McpError::internal_error(
-                        format!("there was an error spawning repository refresh process: {err:?}"),
-                        None,
-                    )
-                })?;
+/// Options for installing a package with a specific version
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct InstallVersionOptions {
+    pub package: String,
+    pub version: String,
+    /// Architecture to install the package for, when not the host's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// debconf selections to set before installing, as
+    /// `package question type value` lines
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "serialize_preseed"
+    )]
+    pub preseed: Vec<String>,
+    /// Which weaker dependencies to install, when not the server's default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub install_recommends: Option<Recommends>,
+    /// Release whose packages are preferred, e.g. `bookworm-backports`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_release: Option<String>,
+    #[serde(flatten)]
+    pub flags: InstallFlags,
+}
 
-                match repository_refresh {
-                    Ok(exec_result) => {
-                        if exec_result.status == 0 {
-                            let success_message =
-                                "All repositories were refreshed successfully.".to_string();
-                            Ok(CallToolResult::success(vec![Content::text(
-                                success_message,
-                            )]))
-                        } else {
-                            let error_message = format!(
-                                "Failed to refresh repositories (exit code: {})",
-                                exec_result.status
-                            );
-                            let mut error_details = serde_json::json!({
-                                "exit_code": exec_result.status,
-                                "package_manager": pm_name
-                            });
+/// Script and cache options of an install, as `apk add` takes them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct InstallFlags {
+    /// Skip the maintainer scripts of the packages (`--no-scripts`)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub no_scripts: bool,
+    /// Neither read nor fill the package cache (`--no-cache`)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub no_cache: bool,
+    /// Download the repository indexes first (`--update-cache`)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub update_cache: bool,
+}
 
-                            if let Some(stdout) = exec_result.stdout {
-                                error_details["stdout"] = serde_json::Value::String(stdout);
-                            }
-                            if let Some(stderr) = exec_result.stderr {
-                                error_details["stderr"] = serde_json::Value::String(stderr);
-                            }
+impl InstallFlags {
+    /// Whether no flag is set
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 
-                            Err(McpError::internal_error(error_message, Some(error_details)))
-                        }
-                    }
-                    Err(err) => Err(McpError::internal_error(
-                        format!(
-                            "System error while refreshing repositories: {err:?}. This may indicate {pm_name} is not available or there are permission issues."
-                        ),
-                        Some(serde_json::json!({
-                            "error_type": "system_error",
-                            "suggestion": format!("Ensure {} package manager is installed and you have sufficient privileges", pm_name)
-                        })),
-                    )),
-                }
-            }
-            "list_installed_packages" => {
-                let package_list =
-                    tokio::task::spawn_blocking(move || backend.list_installed_packages())
-                        .await
-                        .map_err(|err| {
-                            McpError::internal_error(
-                                format!(
-                                    "there was an error spawning package listing process: {err:?}"
-                                ),
-                                None,
-                            )
-                        })?;
+    /// Names of the parameters setting the flags that are set
+    pub fn parameters(&self) -> Vec<&'static str> {
+        [
+            ("no_scripts", self.no_scripts),
+            ("no_cache", self.no_cache),
+            ("update_cache", self.update_cache),
+        ]
+        .into_iter()
+        .filter_map(|(parameter, set)| set.then_some(parameter))
+        .collect()
+    }
+}
 
-                match package_list {
-                    Ok(exec_result) => {
-                        if exec_result.status == 0 {
-                            let packages = exec_result.stdout.unwrap_or_default();
-                            Ok(CallToolResult::success(vec![Content::text(format!(
-                                "Installed packages:\n{packages}"
-                            ))]))
-                        } else {
-                            let error_message = format!(
-                                "Failed to list installed packages (exit code: {})",
-                                exec_result.status
-                            );
-                            let mut error_details = serde_json::json!({
-                                "exit_code": exec_result.status,
-                                "package_manager": pm_name
-                            });
+/// Which packages an install adds besides the dependencies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Recommends {
+    /// No recommended packages (`--no-install-recommends`)
+    #[serde(rename = "none")]
+    Skip,
+    /// The recommended packages (`--install-recommends`)
+    #[serde(rename = "recommends")]
+    Install,
+    /// The recommended and suggested packages (`--install-suggests`)
+    #[serde(rename = "suggests")]
+    WithSuggests,
+}
 
-                            if let Some(stderr) = exec_result.stderr {
-                                error_details["stderr"] = serde_json::Value::String(stderr);
-                            }
+impl std::str::FromStr for Recommends {
+    type Err = String;
 
-                            Err(McpError::internal_error(error_message, Some(error_details)))
-                        }
-                    }
-                    Err(err) => Err(McpError::internal_error(
-                        format!("System error while listing packages: {err:?}"),
-                        Some(serde_json::json!({
-                            "error_type": "system_error",
-                            "suggestion": format!("Ensure {} package manager is available", pm_name)
-                        })),
-                    )),
-                }
-            }
-            "search_package" => {
-                let query = request
-                    .arguments
-                    .as_ref()
-                    .and_then(|args| args.get("query").and_then(|query| query.as_str()))
-                    .ok_or_else(|| {
-                        McpError::invalid_params("missing required parameter: query", None)
-                    })?
-                    .to_string();
+    fn from_str(recommends: &str) -> Result<Self, Self::Err> {
+        match recommends {
+            "none" => Ok(Self::Skip),
+            "recommends" => Ok(Self::Install),
+            "suggests" => Ok(Self::WithSuggests),
+            _ => Err(format!(
+                "unknown install_recommends '{recommends}', expected none, recommends or suggests"
+            )),
+        }
+    }
+}
 
-                let repository = request
-                    .arguments
-                    .as_ref()
-                    .and_then(|args| {
-                        args.get("repository")
-                            .and_then(|repository| repository.as_str())
-                    })
-                    .map(|repository| repository.to_string());
+/// Options for installing a package on several execution targets
+#[derive(Clone, Debug, Serialize)]
+pub struct TargetInstallOptions {
+    pub package: String,
+    /// Version to install, the latest one when not given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Architecture to install the package for, when not the targets'
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// Names of the targets to install on, every configured target when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+}
 
-                let search_options = SearchOptions {
-                    query: query.clone(),
-                    repository,
-                };
+/// Options for searching packages
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchOptions {
+    pub query: String,
+    pub repository: Option<String>,
+    /// Repositories searched after `repository`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub repositories: Vec<String>,
+    /// Repositories searched instead of the configured ones, when not empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub only_repositories: Vec<String>,
+    /// Architecture to search packages for, when not the host's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// How the query matches packages
+    pub mode: SearchMode,
+}
 
-                let package_search = tokio::task::spawn_blocking(move || {
-                    backend.search_package(&search_options)
-                })
-                .await
-                .map_err(|err| {
-                    McpError::internal_error(
-                        format!(
-                            "there was an error spawning search process for query {query}: {err:?}"
-                        ),
-                        None,
-                    )
-                })?;
+impl SearchOptions {
+    /// Custom repositories searched: `repository`, then `repositories`
+    pub fn added_repositories(&self) -> Vec<&str> {
+        self.repository
+            .iter()
+            .chain(&self.repositories)
+            .map(String::as_str)
+            .collect()
+    }
+}
 
-                match package_search {
-                    Ok(exec_result) => {
-                        if exec_result.status == 0 {
-                            let search_results = if let Some(stdout) = exec_result.stdout {
-                                if stdout.trim().is_empty() {
-                                    format!(
-                                        "Search completed for query '{query}' but no packages were found."
-                                    )
-                                } else {
-                                    // Clean up `fetch` lines from APK output
-                                    let cleaned_stdout = stdout
-                                        .lines()
-                                        .filter(|line| !line.starts_with("fetch "))
-                                        .collect::<Vec<&str>>()
-                                        .join("\n");
-
-                                    format!(
-                                        "Search results for query '{query}':\n\n{cleaned_stdout}"
-                                    )
-                                }
-                            } else {
-                                format!(
-                                    "Search completed for query '{query}' but no packages were found."
-                                )
-                            };
-                            Ok(CallToolResult::success(vec![Content::text(search_results)]))
-                        } else {
-                            let error_message = format!(
-                                "Failed to search for packages with query '{query}' (exit code: {})",
-                                exec_result.status
-                            );
-                            let mut error_details = serde_json::json!({
-                                "query": query,
-                                "exit_code": exec_result.status,
-                                "package_manager": pm_name
-                            });
+/// How a search query matches packages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// Names equal to the query
+    Exact,
+    /// Names starting with the query, or matching it as a wildcard pattern
+    /// when it contains `*` or `?`
+    #[default]
+    Prefix,
+    /// Names matching the query as a wildcard pattern, or containing it when
+    /// it has no wildcards
+    Wildcard,
+    /// Names or descriptions containing the query
+    Description,
+}
 
-                            if let Some(stdout) = exec_result.stdout {
-                                error_details["stdout"] = serde_json::Value::String(stdout);
-                            }
-                            if let Some(stderr) = exec_result.stderr {
-                                error_details["stderr"] = serde_json::Value::String(stderr);
-                            }
+impl std::str::FromStr for SearchMode {
+    type Err = String;
 
-                            Err(McpError::internal_error(error_message, Some(error_details)))
-                        }
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "exact" => Ok(Self::Exact),
+            "prefix" => Ok(Self::Prefix),
+            "wildcard" => Ok(Self::Wildcard),
+            "description" => Ok(Self::Description),
+            _ => Err(format!(
+                "unknown mode '{mode}', expected exact, prefix, wildcard or description"
+            )),
+        }
+    }
+}
+
+impl SearchMode {
+    /// Wildcard pattern of the names the mode matches, as `apk search`
+    /// understands it
+    ///
+    /// Description searches match names containing the query.
+    pub fn name_glob(self, query: &str) -> String {
+        let wildcards = query.contains(['*', '?']);
+        match self {
+            Self::Exact => query.to_string(),
+            Self::Prefix | Self::Wildcard if wildcards => query.to_string(),
+            Self::Prefix => format!("{query}*"),
+            Self::Wildcard | Self::Description => format!("*{query}*"),
+        }
+    }
+
+    /// Anchored POSIX extended regular expression of the names the mode
+    /// matches, which the `regex` crate also accepts
+    pub fn name_regex(self, query: &str) -> String {
+        let wildcards = self != Self::Exact;
+        let pattern: String = self
+            .name_glob(query)
+            .chars()
+            .map(|c| match c {
+                '*' if wildcards => ".*".to_string(),
+                '?' if wildcards => ".".to_string(),
+                c => regex_escaped(c),
+            })
+            .collect();
+        format!("^{pattern}$")
+    }
+
+    /// POSIX extended regular expression matching `query` anywhere in a
+    /// description, with no character taken as a metacharacter
+    pub fn description_regex(query: &str) -> String {
+        query.chars().map(regex_escaped).collect()
+    }
+
+    /// Predicate telling whether a package, given its name and description,
+    /// matches `query`
+    pub fn matcher(self, query: &str) -> impl Fn(&str, Option<&str>) -> bool + use<> {
+        let names = regex::Regex::new(&self.name_regex(query)).ok();
+        let description = (self == Self::Description).then(|| query.to_lowercase());
+        move |name, text| {
+            names.as_ref().is_some_and(|names| names.is_match(name))
+                || description.as_ref().is_some_and(|query| {
+                    text.is_some_and(|text| text.to_lowercase().contains(query))
+                })
+        }
+    }
+}
+
+/// `c` escaped for a POSIX extended regular expression
+fn regex_escaped(c: char) -> String {
+    match c {
+        '.' | '[' | ']' | '(' | ')' | '{' | '}' | '*' | '?' | '+' | '|' | '^' | '$' | '\\' => {
+            format!("\\{c}")
+        }
+        c => c.to_string(),
+    }
+}
+
+/// How the results of a search are listed, applied to the parsed results
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SearchListing {
+    /// Most results to list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Order of the results, the backend's when not given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<SearchSort>,
+    /// Alpine branch (`v3.22`, `edge`) or APT suite (`bookworm-updates`)
+    /// whose repositories the results must come from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+}
+
+/// Order of search results
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSort {
+    /// By name, the highest version of each package first
+    Name,
+    /// Highest versions first
+    Version,
+    /// Most recently built first, packages without a build date last
+    Newest,
+}
+
+impl std::str::FromStr for SearchSort {
+    type Err = String;
+
+    fn from_str(sort: &str) -> Result<Self, Self::Err> {
+        match sort {
+            "name" => Ok(Self::Name),
+            "version" => Ok(Self::Version),
+            "newest" => Ok(Self::Newest),
+            _ => Err(format!(
+                "unknown sort '{sort}', expected name, version or newest"
+            )),
+        }
+    }
+}
+
+impl SearchListing {
+    /// Whether the results need the version and repository of each package
+    pub fn needs_locations(&self) -> bool {
+        self.branch.is_some() || self.sort == Some(SearchSort::Version)
+    }
+
+    /// The results of a search to list, with how many there were before the
+    /// limit
+    pub fn apply(
+        &self,
+        scheme: Option<VersionScheme>,
+        mut results: Vec<SearchResult>,
+    ) -> (Vec<SearchResult>, usize) {
+        if let Some(branch) = &self.branch {
+            results.retain(|result| {
+                result
+                    .repository
+                    .as_deref()
+                    .is_some_and(|repository| in_branch(repository, branch))
+            });
+        }
+
+        let versions = |a: &SearchResult, b: &SearchResult| {
+            let (a, b) = (a.version.as_deref(), b.version.as_deref());
+            match (scheme, a, b) {
+                (Some(scheme), Some(a), Some(b)) => scheme.compare(b, a),
+                _ => b.cmp(&a),
+            }
+        };
+        match self.sort {
+            Some(SearchSort::Name) => {
+                results.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| versions(a, b)))
+            }
+            Some(SearchSort::Version) => {
+                results.sort_by(|a, b| versions(a, b).then_with(|| a.name.cmp(&b.name)))
+            }
+            Some(SearchSort::Newest) => {
+                results.sort_by(|a, b| b.build_date.cmp(&a.build_date).then_with(|| versions(a, b)))
+            }
+            None => {}
+        }
+
+        let found = results.len();
+        if let Some(limit) = self.limit {
+            results.truncate(limit);
+        }
+        (results, found)
+    }
+}
+
+/// Whether `repository` is one of `branch`, which is a path segment or word
+/// of its location: `…/alpine/v3.22/main`, `… bookworm-updates/main …`
+///
+/// Alpine branches may be given without their `v`.
+fn in_branch(repository: &str, branch: &str) -> bool {
+    repository
+        .split(['/', ' '])
+        .any(|part| part == branch || part.strip_prefix('v') == Some(branch))
+}
+
+/// Optional features supported by a backend
+///
+/// The handler only advertises tools and parameters the backend supports and
+/// rejects calls that need a missing capability.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Installing a specific package version
+    pub version_install: bool,
+    /// Custom repositories for installs and searches
+    pub custom_repositories: bool,
+    /// Tools that modify the system (installs and repository refreshes)
+    pub mutating: bool,
+    /// Listing the configured repositories
+    pub repository_listing: bool,
+    /// Listing the repositories searched when no repository is given, for
+    /// backends that search their own list rather than the system's
+    /// configuration (off by default)
+    pub search_repository_listing: bool,
+    /// Installing and searching packages for another architecture
+    pub architecture_selection: bool,
+    /// Managing the packages of another root filesystem than `/` (off by
+    /// default)
+    pub alternate_root: bool,
+    /// Checking whether the server can modify the system (off by default)
+    pub privilege_check: bool,
+    /// Rendering shell commands that reinstall pinned packages (off by
+    /// default)
+    pub install_commands: bool,
+    /// Installing several packages with one command (off by default)
+    pub batch_install: bool,
+    /// Installing several packages at requested versions with one command
+    /// (off by default)
+    pub batch_version_install: bool,
+    /// Naming the OSV ecosystem of the packages, for vulnerability lookups
+    /// (off by default)
+    pub vulnerability_check: bool,
+    /// Reporting the licenses of installed packages (off by default)
+    pub license_report: bool,
+    /// Locking installed packages to their checksums and source
+    /// repositories (off by default)
+    pub lockfile: bool,
+    /// Installing locked packages at their pinned versions in one
+    /// transaction (off by default)
+    pub locked_install: bool,
+    /// Exporting the server's audit log, set by the handler when it keeps
+    /// one (off by default)
+    pub audit_log: bool,
+    /// Looking up a project's packages in other distributions, set by the
+    /// handler when it has a Repology client (off by default)
+    pub cross_distro_search: bool,
+    /// Checking that the repositories are signed, for the
+    /// signed-repositories-only mode (off by default)
+    pub signature_check: bool,
+    /// Downloading package files to verify them before installing them,
+    /// for pinned packages (off by default)
+    pub artifact_verification: bool,
+    /// Describing packages as an apko image configuration (off by default)
+    pub apko_config: bool,
+    /// Describing packages and their repositories as cloud-init user data
+    /// (off by default)
+    pub cloud_init: bool,
+    /// Fingerprinting the installed package database, so subscribers of the
+    /// installed manifest are notified of changes (off by default)
+    pub drift_detection: bool,
+    /// Estimating the disk space installs need, so installs the disk cannot
+    /// hold fail before anything is unpacked (off by default)
+    pub disk_space_check: bool,
+    /// Setting debconf selections before installs, so packages asking
+    /// questions install unattended (off by default)
+    pub debconf_preseed: bool,
+    /// Choosing whether installs add recommended and suggested packages
+    /// (off by default)
+    pub recommends_selection: bool,
+    /// Skipping maintainer scripts and choosing how installs use the package
+    /// cache and indexes (off by default)
+    pub install_flags: bool,
+    /// Installing and searching with a set of repositories replacing the
+    /// configured ones (off by default)
+    pub repository_override: bool,
+    /// Installing from and searching several custom repositories at once
+    /// (off by default)
+    pub multiple_repositories: bool,
+    /// Preferring the packages of a release, such as backports, in installs
+    /// (off by default)
+    pub target_release_selection: bool,
+    /// Rendering pinned packages as Ansible tasks, the `ansible` format of
+    /// `export_session` (off by default)
+    pub ansible_tasks: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            version_install: true,
+            custom_repositories: true,
+            mutating: true,
+            repository_listing: true,
+            search_repository_listing: false,
+            architecture_selection: true,
+            alternate_root: false,
+            privilege_check: false,
+            install_commands: false,
+            batch_install: false,
+            batch_version_install: false,
+            vulnerability_check: false,
+            license_report: false,
+            lockfile: false,
+            locked_install: false,
+            audit_log: false,
+            cross_distro_search: false,
+            signature_check: false,
+            artifact_verification: false,
+            apko_config: false,
+            cloud_init: false,
+            drift_detection: false,
+            disk_space_check: false,
+            debconf_preseed: false,
+            recommends_selection: false,
+            install_flags: false,
+            repository_override: false,
+            multiple_repositories: false,
+            target_release_selection: false,
+            ansible_tasks: false,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Whether the tool with the given name is available
+    pub fn exposes_tool(&self, tool_name: &str) -> bool {
+        match tool_name {
+            "install_package" | "refresh_repositories" => self.mutating,
+            "install_package_with_version" => self.mutating && self.version_install,
+            "resolve_version" => self.version_install,
+            "list_repositories" => self.repository_listing,
+            "list_search_repositories" => self.search_repository_listing,
+            "check_privileges" => self.privilege_check,
+            "export_session" | "export_dockerfile_snippet" => self.install_commands,
+            "export_apko_config" => self.apko_config,
+            "export_cloud_init" => self.cloud_init,
+            "check_vulnerabilities" => self.vulnerability_check,
+            "license_report" => self.license_report,
+            "generate_lockfile" => self.lockfile,
+            "install_from_lockfile" => self.mutating && self.locked_install,
+            "export_audit_log" | "export_history" => self.audit_log,
+            "search_all_distros" => self.cross_distro_search,
+            _ => true,
+        }
+    }
+
+    /// Reject operations requiring a capability the backend lacks
+    pub fn check(&self, operation: &Operation) -> Result<(), PackageManagerError> {
+        if !self.exposes_tool(operation.tool_name()) {
+            return Err(PackageManagerError::Unsupported {
+                operation: operation.tool_name(),
+            });
+        }
+
+        if matches!(operation, Operation::InstallVersions { .. }) && !self.batch_version_install {
+            return Err(PackageManagerError::Unsupported {
+                operation: "packages",
+            });
+        }
+        if operation.repository().is_some() && !self.custom_repositories {
+            return Err(PackageManagerError::Unsupported {
+                operation: "repository",
+            });
+        }
+        if operation.arch().is_some() && !self.architecture_selection {
+            return Err(PackageManagerError::Unsupported { operation: "arch" });
+        }
+        if !operation.preseed().is_empty() && !self.debconf_preseed {
+            return Err(PackageManagerError::Unsupported {
+                operation: "preseed",
+            });
+        }
+        if operation.lists_repositories() && !self.multiple_repositories {
+            return Err(PackageManagerError::Unsupported {
+                operation: "repositories",
+            });
+        }
+        if operation.overrides_repositories() && !self.repository_override {
+            return Err(PackageManagerError::Unsupported {
+                operation: "only_repositories",
+            });
+        }
+        if operation.target_release().is_some() && !self.target_release_selection {
+            return Err(PackageManagerError::Unsupported {
+                operation: "target_release",
+            });
+        }
+        if operation.install_recommends().is_some() && !self.recommends_selection {
+            return Err(PackageManagerError::Unsupported {
+                operation: "install_recommends",
+            });
+        }
+        if let Some(parameter) = operation.install_flags().parameters().first()
+            && !self.install_flags
+        {
+            return Err(PackageManagerError::Unsupported {
+                operation: parameter,
+            });
+        }
+        if matches!(
+            operation,
+            Operation::ExportSession {
+                format: ExportFormat::Ansible
+            }
+        ) && !self.ansible_tasks
+        {
+            return Err(PackageManagerError::Unsupported {
+                operation: "ansible",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Trait defining the interface for package manager backends
+///
+/// The trait is object safe so backends can be selected at runtime and shared
+/// behind an `Arc<dyn PackageManager>`.
+#[async_trait]
+pub trait PackageManager: Send + Sync + 'static {
+    /// Returns the name of the package manager (e.g., "APK", "APT")
+    fn name(&self) -> &'static str;
+
+    /// Returns the OS name (e.g., "Alpine Linux", "Debian/Debian-derivative")
+    fn os_name(&self) -> &'static str;
+
+    /// Optional features supported by the backend
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Version ordering used by the backend's packages, if known
+    fn version_scheme(&self) -> Option<VersionScheme> {
+        None
+    }
+
+    /// Install a package (latest version)
+    async fn install_package(
+        &self,
+        options: &InstallOptions,
+    ) -> Result<InstallOutcome, PackageManagerError>;
+
+    /// Install a package with a specific version
+    async fn install_package_with_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<InstallOutcome, PackageManagerError>;
+
+    /// Search for packages
+    async fn search_package(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError>;
+
+    /// `results` with the version and repository of each package, for
+    /// backends whose searches do not report them
+    ///
+    /// Packages offered by several repositories give one result per
+    /// repository. The default returns the results as they are.
+    async fn locate_search_results(
+        &self,
+        results: Vec<SearchResult>,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        Ok(results)
+    }
+
+    /// List installed packages
+    async fn list_installed_packages(&self) -> Result<Vec<InstalledPackage>, PackageManagerError>;
+
+    /// Refresh repository indexes
+    async fn refresh_repositories(&self) -> Result<ExecResult, PackageManagerError>;
+
+    /// List the configured repositories
+    async fn list_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "list_repositories",
+        })
+    }
+
+    /// List the repositories searched when no repository is given
+    async fn list_search_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "list_search_repositories",
+        })
+    }
+
+    /// Check whether installs and repository refreshes can succeed
+    async fn check_privileges(&self) -> Result<PrivilegeReport, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "check_privileges",
+        })
+    }
+
+    /// Install several packages (latest versions) with one command, resolving
+    /// their dependencies once
+    ///
+    /// Returns one outcome per package, in order. Backends supporting it set
+    /// the `batch_install` capability.
+    async fn install_packages(
+        &self,
+        _packages: &[String],
+        _arch: Option<&str>,
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "install_packages",
+        })
+    }
+
+    /// Version [`install_package_with_version`](Self::install_package_with_version)
+    /// would install for `options`, without installing anything
+    ///
+    /// The default picks among the versions `search_package` reports, with
+    /// the backend's [`version_scheme`](Self::version_scheme).
+    async fn resolve_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<ResolvedVersion, PackageManagerError> {
+        let found = self
+            .search_package(&SearchOptions {
+                query: options.package.clone(),
+                repository: None,
+                repositories: Vec::new(),
+                only_repositories: Vec::new(),
+                arch: options.arch.clone(),
+                mode: SearchMode::Exact,
+            })
+            .await?;
+        resolve_among(self.version_scheme(), &found, options)
+    }
+
+    /// Install several packages at versions matching their constraints with
+    /// one command, resolving each version like
+    /// [`install_package_with_version`](Self::install_package_with_version)
+    ///
+    /// Nothing is installed unless every version resolves. Returns one
+    /// outcome per package, in order. Backends supporting it set the
+    /// `batch_version_install` capability.
+    async fn install_packages_with_versions(
+        &self,
+        _packages: &[InstallVersionOptions],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "install_packages_with_versions",
+        })
+    }
+
+    /// OSV ecosystem of the managed system's packages, e.g. `Alpine:v3.22`
+    /// or `Debian:12`
+    ///
+    /// Backends supporting it set the `vulnerability_check` capability.
+    async fn osv_ecosystem(&self) -> Result<String, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "check_vulnerabilities",
+        })
+    }
+
+    /// Version of the package manager and release of the managed system
+    ///
+    /// Fields the backend cannot determine are left unset; the default sets
+    /// none.
+    async fn system_info(&self) -> Result<SystemInfo, PackageManagerError> {
+        Ok(SystemInfo::default())
+    }
+
+    /// Checks of the package manager's own environment for the `doctor`
+    /// tool, e.g. whether it runs, its lock and the free disk space
+    ///
+    /// The default has none.
+    async fn health_checks(&self) -> Result<Vec<HealthCheck>, PackageManagerError> {
+        Ok(Vec::new())
+    }
+
+    /// Meaning of the exit status of a failed command of this backend,
+    /// given its output
+    ///
+    /// The default only knows the statuses set by the shell and by signals.
+    fn exit_meaning(&self, status: i32, _output: &str) -> Option<ExitMeaning> {
+        exit_status::common(status)
+    }
+
+    /// Set the debconf `selections`, `package question type value` lines,
+    /// so the next installs do not ask their questions
+    ///
+    /// Backends supporting it set the `debconf_preseed` capability.
+    async fn preseed(&self, _selections: &[String]) -> Result<(), PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "preseed",
+        })
+    }
+
+    /// When the repository indexes were last refreshed, also outside the
+    /// server, if known
+    ///
+    /// The default does not know.
+    async fn indexes_refreshed_at(
+        &self,
+    ) -> Result<Option<std::time::SystemTime>, PackageManagerError> {
+        Ok(None)
+    }
+
+    /// Disk space installing `packages` needs on each file system it writes
+    /// to, estimated without installing anything
+    ///
+    /// Packages are names, optionally followed by `=version`, installed
+    /// with the weaker dependencies `recommends` selects (default: the
+    /// backend's). Backends supporting it set the `disk_space_check`
+    /// capability.
+    async fn required_space(
+        &self,
+        _packages: &[String],
+        _arch: Option<&str>,
+        _recommends: Option<Recommends>,
+    ) -> Result<Vec<SpaceRequirement>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "required_space",
+        })
+    }
+
+    /// Source package of every installed package built from a source package
+    /// with another name, by package name
+    ///
+    /// Advisories are filed against source packages. Packages missing from
+    /// the map are looked up under their own name.
+    async fn source_packages(
+        &self,
+    ) -> Result<std::collections::HashMap<String, String>, PackageManagerError> {
+        Ok(std::collections::HashMap::new())
+    }
+
+    /// Licenses of the installed packages
+    ///
+    /// Backends supporting it set the `license_report` capability.
+    async fn package_licenses(&self) -> Result<Vec<PackageLicense>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "license_report",
+        })
+    }
+
+    /// Shell commands installing the packages at their pinned versions, in
+    /// order, e.g. for an image build
+    ///
+    /// The commands must work on a fresh system of the backend's
+    /// distribution and quote every argument taken from `packages`.
+    async fn install_commands(
+        &self,
+        _packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "export_session",
+        })
+    }
+
+    /// Ansible tasks adding the repositories the packages were installed
+    /// from and installing them at their pinned versions
+    ///
+    /// Backends supporting it set the `ansible_tasks` capability.
+    async fn ansible_tasks(
+        &self,
+        _packages: &[PinnedPackage],
+    ) -> Result<Vec<AnsibleTask>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "export_session",
+        })
+    }
+
+    /// apko configuration installing the packages at their pinned versions
+    ///
+    /// Backends supporting it set the `apko_config` capability.
+    async fn apko_config(
+        &self,
+        _packages: &[PinnedPackage],
+    ) -> Result<ApkoConfig, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "export_apko_config",
+        })
+    }
+
+    /// cloud-init configuration installing the packages at their pinned
+    /// versions, adding the repositories they were installed from
+    ///
+    /// Backends supporting it set the `cloud_init` capability.
+    async fn cloud_config(
+        &self,
+        _packages: &[PinnedPackage],
+    ) -> Result<CloudConfig, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "export_cloud_init",
+        })
+    }
+
+    /// Dockerfile instructions installing the packages at their pinned
+    /// versions, in order
+    ///
+    /// The default chains the [`install_commands`](Self::install_commands)
+    /// into a single `RUN`; backends override it to follow their
+    /// distribution's image conventions, such as not keeping caches in the
+    /// layer.
+    async fn dockerfile_instructions(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        let commands = self.install_commands(packages).await?;
+        Ok(vec![format!("RUN {}", commands.join(" \\\n    && "))])
+    }
+
+    /// The installed `packages` with their checksums and the repositories
+    /// holding them, in order, for a lockfile
+    ///
+    /// Packages the package manager knows nothing more about are returned
+    /// without a checksum or source. Backends supporting it set the
+    /// `lockfile` capability.
+    async fn lock_packages(
+        &self,
+        _packages: &[PinnedPackage],
+    ) -> Result<Vec<LockedPackage>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "generate_lockfile",
+        })
+    }
+
+    /// Install `packages` at exactly their pinned versions with one command
+    ///
+    /// Returns an outcome per package, in order. Packages may be downgraded.
+    /// Backends supporting it set the `locked_install` capability.
+    async fn install_locked(
+        &self,
+        _packages: &[LockedPackage],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "install_from_lockfile",
+        })
+    }
+
+    /// Repositories whose packages the package manager would not verify,
+    /// among the enabled ones and the custom `repository` if given
+    ///
+    /// Backends supporting it set the `signature_check` capability.
+    async fn unsigned_repositories(
+        &self,
+        _repository: Option<&str>,
+    ) -> Result<Vec<UnsignedRepository>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "signed_repositories_only",
+        })
+    }
+
+    /// Download the file of `package` at its version without installing it
+    ///
+    /// Backends supporting it set the `artifact_verification` capability and
+    /// implement [`install_fetched`](Self::install_fetched).
+    async fn fetch_package(
+        &self,
+        _package: &PinnedPackage,
+    ) -> Result<FetchedPackage, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "fetch_package",
+        })
+    }
+
+    /// Install a package from the file [`fetch_package`](Self::fetch_package)
+    /// downloaded, resolving its dependencies from the repositories
+    async fn install_fetched(
+        &self,
+        _package: &FetchedPackage,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "install_fetched",
+        })
+    }
+
+    /// Fingerprint of the installed package database, changing whenever a
+    /// package is installed, removed or upgraded, also outside the server
+    ///
+    /// Backends supporting it set the `drift_detection` capability.
+    async fn database_fingerprint(&self) -> Result<String, PackageManagerError> {
+        Err(PackageManagerError::Unsupported {
+            operation: "resources/subscribe",
+        })
+    }
+
+    /// Remove a file [`fetch_package`](Self::fetch_package) downloaded
+    async fn discard_fetched(&self, _package: &FetchedPackage) -> Result<(), PackageManagerError> {
+        Ok(())
+    }
+
+    /// The same backend managing the root filesystem at `root` instead of `/`
+    ///
+    /// Replaces any root the backend was already targeting.
+    fn with_root(&self, _root: &str) -> Result<Arc<dyn PackageManager>, PackageManagerError> {
+        Err(PackageManagerError::Unsupported { operation: "root" })
+    }
+}
+
+/// Available package manager backends
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    Apk,
+    Apt,
+}
+
+impl BackendKind {
+    /// Detect the backend for the running OS via file system markers
+    pub fn detect() -> Option<Self> {
+        if std::path::Path::new("/etc/alpine-release").exists() {
+            Some(Self::Apk)
+        } else if std::path::Path::new("/etc/debian_version").exists() {
+            Some(Self::Apt)
+        } else {
+            None
+        }
+    }
+
+    /// Detect the backend for the system the runner targets, or for the root
+    /// filesystem at `root` on it
+    pub async fn detect_on(
+        runner: &dyn CommandRunner,
+        root: Option<&str>,
+    ) -> std::io::Result<Option<Self>> {
+        let root = root.unwrap_or_default().trim_end_matches('/');
+        if runner
+            .read_file(&format!("{root}/etc/alpine-release"))
+            .await?
+            .is_some()
+        {
+            Ok(Some(Self::Apk))
+        } else if runner
+            .read_file(&format!("{root}/etc/debian_version"))
+            .await?
+            .is_some()
+        {
+            Ok(Some(Self::Apt))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Name the backend is registered under in the [`registry::BackendRegistry`]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Apk => "apk",
+            Self::Apt => "apt",
+        }
+    }
+
+    /// Create a new instance of the backend
+    pub fn create(self) -> Arc<dyn PackageManager> {
+        match self {
+            Self::Apk => Arc::new(apk::Apk::new()),
+            Self::Apt => Arc::new(apt::Apt::new()),
+        }
+    }
+
+    /// Create a new instance of the backend running its commands with `runner`
+    pub fn create_with_runner(self, runner: Arc<dyn CommandRunner>) -> Arc<dyn PackageManager> {
+        match self {
+            Self::Apk => Arc::new(apk::Apk::with_runner(runner)),
+            Self::Apt => Arc::new(apt::Apt::with_runner(runner)),
+        }
+    }
+}
+
+/// URI of the resource listing the packages a session changed
+pub const SESSION_DELTA_URI: &str = "state://session-delta";
+
+/// URI of the resource listing the installed packages
+pub const INSTALLED_MANIFEST_URI: &str = "state://installed-manifest";
+
+/// Header carrying the MCP session of streamable HTTP requests
+const MCP_SESSION_ID_HEADER: &str = "mcp-session-id";
+
+/// Bytes of each output stream of a command kept in verbose results
+const VERBOSE_OUTPUT_LIMIT: usize = 16 * 1024;
+
+/// Tool lists built by a handler, by the capabilities they were built for
+type ToolLists = std::sync::Mutex<Vec<(Capabilities, Arc<ListToolsResult>)>>;
+
+/// MCP handler that wraps any PackageManager implementation
+#[derive(Clone)]
+pub struct PackageManagerHandler {
+    backend: Arc<dyn PackageManager>,
+    hooks: Vec<Arc<dyn Hooks>>,
+    events: EventBus,
+    default_arch: Option<String>,
+    /// Entries per page of listings that do not pass a `limit`
+    page_size: Option<usize>,
+    /// Latest privilege check of the backend, shared by every session
+    privileges: Arc<std::sync::RwLock<Option<PrivilegeReport>>>,
+    /// Named execution targets for fan-out installs, in configuration order
+    targets: Vec<targets::NamedBackend>,
+    /// Targets on the server's own host, whose installs take the mutation
+    /// lock
+    local_targets: Vec<String>,
+    /// Packages installed by the current session, when recording
+    session: Option<Arc<SessionLog>>,
+    /// Packages the current session changed on the server's system
+    delta: Arc<std::sync::Mutex<SessionDelta>>,
+    /// Subscription of the current session to the installed manifest
+    watcher: Arc<drift::DriftWatcher>,
+    /// How often subscriptions check the installed packages for changes
+    watch_interval: std::time::Duration,
+    /// Latest installed package listings, shared by every session
+    installed: Option<Arc<cache::InstalledCache>>,
+    /// Installs of the current session waiting to run as one batch
+    batcher: Option<Arc<batch::InstallBatcher>>,
+    /// Advisory database queried by `check_vulnerabilities`
+    osv: Option<Arc<osv::OsvClient>>,
+    /// Package database queried by `search_all_distros`
+    repology: Option<Arc<repology::RepologyClient>>,
+    /// License patterns `license_report` flags for review
+    denied_licenses: Vec<String>,
+    /// Whether installs may use package files, URLs and custom repositories
+    allow_untrusted_sources: bool,
+    /// Whether installs may skip the maintainer scripts of the packages
+    allow_no_scripts: bool,
+    /// Repositories tool calls may use, any when empty
+    repository_allowlist: allowlist::RepositoryAllowlist,
+    /// Allowlists replacing the server-wide one for some clients
+    user_allowlists: HashMap<String, allowlist::RepositoryAllowlist>,
+    /// Internal mirrors every repository must be on in offline mode
+    offline: Option<offline::OfflineMirrors>,
+    /// Whether operations are refused while a repository is unsigned
+    signed_repositories_only: bool,
+    /// Whether installs are checked against the free disk space first
+    disk_space_check: bool,
+    /// When the handler refreshes the repository indexes on its own
+    auto_refresh: Option<freshness::AutoRefresh>,
+    /// Refreshes of the indexes made through the server, shared by every
+    /// session
+    index_freshness: Arc<freshness::IndexFreshness>,
+    /// Installed package listings being paged through by the session
+    listings: Arc<listing::ListingSnapshots>,
+    /// Log receiving an entry per tool call
+    audit: Option<Arc<AuditLog>>,
+    /// Usage of the current session against its quotas
+    quota: Option<Arc<QuotaUsage>>,
+    /// Webhook approving mutating operations before they run
+    approval: Option<approval::ApprovalWebhook>,
+    /// Webhooks notified of every install call
+    notification_sinks: Vec<notifications::NotificationSink>,
+    /// Policy blocking installs of vulnerable versions
+    vulnerability_gate: Option<osv::VulnerabilityGate>,
+    /// Expected artifacts of critical packages
+    package_pins: pins::PackagePins,
+    /// Tool lists built so far, shared by every session
+    tool_lists: Arc<ToolLists>,
+    /// Results of mutating calls by idempotency key, shared by every session
+    idempotency: Option<Arc<idempotency::IdempotencyStore>>,
+    /// Mutating calls started with `async`, shared by every session
+    jobs: Option<Arc<jobs::JobStore>>,
+    /// Complete output of mutating operations, kept on disk
+    operation_logs: Option<Arc<operation_log::OperationLogStore>>,
+    /// Held while a mutating call runs, so they run one at a time
+    mutation_lock: Arc<tokio::sync::Mutex<()>>,
+    /// Names the tools are exposed under
+    tool_names: naming::ToolNames,
+    /// Operator text replacing or extending the generated descriptions
+    descriptions: descriptions::DescriptionOverrides,
+}
+
+#[tool_router]
+impl PackageManagerHandler {
+    pub fn new(backend: impl PackageManager) -> Self {
+        Self::from_arc(Arc::new(backend))
+    }
+
+    /// Create a handler from an already shared backend
+    pub fn from_arc(backend: Arc<dyn PackageManager>) -> Self {
+        Self {
+            backend,
+            hooks: Vec::new(),
+            events: EventBus::new(),
+            default_arch: None,
+            page_size: None,
+            privileges: Arc::default(),
+            targets: Vec::new(),
+            local_targets: Vec::new(),
+            session: None,
+            delta: Arc::default(),
+            watcher: Arc::default(),
+            watch_interval: drift::DEFAULT_WATCH_INTERVAL,
+            installed: None,
+            batcher: None,
+            osv: None,
+            repology: None,
+            denied_licenses: Vec::new(),
+            allow_untrusted_sources: false,
+            allow_no_scripts: false,
+            repository_allowlist: allowlist::RepositoryAllowlist::new(),
+            user_allowlists: HashMap::new(),
+            offline: None,
+            signed_repositories_only: false,
+            disk_space_check: true,
+            auto_refresh: None,
+            index_freshness: Arc::default(),
+            listings: Arc::default(),
+            audit: None,
+            quota: None,
+            approval: None,
+            notification_sinks: Vec::new(),
+            vulnerability_gate: None,
+            package_pins: pins::PackagePins::new(),
+            tool_lists: Arc::default(),
+            idempotency: Some(Arc::new(idempotency::IdempotencyStore::new(
+                idempotency::DEFAULT_IDEMPOTENCY_TTL,
+            ))),
+            jobs: Some(Arc::new(jobs::JobStore::new())),
+            operation_logs: None,
+            mutation_lock: Arc::default(),
+            tool_names: naming::ToolNames::new(),
+            descriptions: descriptions::DescriptionOverrides::new(),
+        }
+    }
+
+    /// Publish package events to an existing bus instead of a private one
+    pub fn with_event_bus(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Event bus receiving every package change made through this handler
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Register hooks invoked before and after every package operation
+    pub fn with_hooks(mut self, hooks: impl Hooks) -> Self {
+        self.hooks.push(Arc::new(hooks));
+        self
+    }
+
+    /// Architecture used by installs and searches that do not pass `arch`
+    ///
+    /// Ignored when the backend cannot select architectures.
+    pub fn with_default_arch(mut self, arch: impl Into<String>) -> Self {
+        self.default_arch = Some(arch.into());
+        self
+    }
+
+    /// Split listings into pages of at most `size` entries
+    ///
+    /// Calls pass the `next_cursor` of a page to get the next one, and can
+    /// ask for other page sizes with `limit`. Without a page size, listings
+    /// are returned whole unless the call passes a `limit`.
+    pub fn with_page_size(mut self, size: usize) -> Self {
+        self.page_size = Some(size.max(1));
+        self
+    }
+
+    /// Start from the result of a privilege check, e.g. one run at startup
+    ///
+    /// While the report says the server cannot modify the system, mutating
+    /// tools are not advertised and calls to them are rejected, unless they
+    /// target another root. The `check_privileges` tool updates the report.
+    pub fn with_privileges(self, report: PrivilegeReport) -> Self {
+        self.set_privileges(report);
+        self
+    }
+
+    /// Add a named execution target for the `install_package_on_targets` tool
+    ///
+    /// The tool is only advertised once a target is configured. Targets are
+    /// independent of the handler's own backend: its capabilities and
+    /// privilege check do not apply to them.
+    pub fn with_target(
+        mut self,
+        name: impl Into<String>,
+        backend: Arc<dyn PackageManager>,
+    ) -> Self {
+        let name = name.into();
+        self.targets.retain(|(existing, _)| *existing != name);
+        self.local_targets.retain(|existing| *existing != name);
+        self.targets.push((name, backend));
+        // The targets are listed in the tool list
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Add a named execution target running on the server's own host
+    ///
+    /// Installs on it take the mutation lock, as they modify the same
+    /// package database as the handler's other mutating calls.
+    pub fn with_local_target(
+        self,
+        name: impl Into<String>,
+        backend: Arc<dyn PackageManager>,
+    ) -> Self {
+        let name = name.into();
+        let mut handler = self.with_target(name.clone(), backend);
+        handler.local_targets.push(name);
+        handler
+    }
+
+    /// Record the packages each session installs and offer `export_session`
+    /// and `generate_lockfile`
+    ///
+    /// Only installs on the handler's own system are recorded, not those
+    /// passing a `root` or made on targets.
+    pub fn with_session_recording(mut self) -> Self {
+        self.session = Some(Arc::default());
+        self
+    }
+
+    /// Answer `list_installed_packages` from the previous listing for `ttl`
+    ///
+    /// The listing is made again as soon as a mutating tool succeeds, so only
+    /// changes made outside the server can go unnoticed, for at most `ttl`.
+    pub fn with_installed_cache(mut self, ttl: std::time::Duration) -> Self {
+        self.installed = Some(Arc::new(cache::InstalledCache::new(ttl)));
+        self
+    }
+
+    /// Expose the tools under `names` instead of their built-in names
+    pub fn with_tool_names(mut self, names: naming::ToolNames) -> Self {
+        self.tool_names = names;
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Replace or extend the generated tool descriptions and server
+    /// instructions with `overrides`
+    pub fn with_description_overrides(
+        mut self,
+        overrides: descriptions::DescriptionOverrides,
+    ) -> Self {
+        self.descriptions = overrides;
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Replay the result of mutating calls retried with the same
+    /// `idempotency_key` for `ttl`, or never when `ttl` is zero
+    pub fn with_idempotency_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.idempotency =
+            (!ttl.is_zero()).then(|| Arc::new(idempotency::IdempotencyStore::new(ttl)));
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Run mutating calls to completion only, without the `async` argument
+    /// and the job tools
+    pub fn without_jobs(mut self) -> Self {
+        self.jobs = None;
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Run the installs a session requests within `window` of each other as
+    /// one command
+    ///
+    /// Only plain installs on the handler's own system are batched, when the
+    /// backend has the `batch_install` capability. The first install of a
+    /// batch is delayed by up to `window`.
+    pub fn with_install_batching(mut self, window: std::time::Duration) -> Self {
+        self.batcher = Some(Arc::new(batch::InstallBatcher::new(
+            window,
+            self.mutation_lock.clone(),
+        )));
+        self
+    }
+
+    /// Check the installed packages of subscribed sessions for changes every
+    /// `interval`
+    pub fn with_watch_interval(mut self, interval: std::time::Duration) -> Self {
+        self.watch_interval = interval;
+        self
+    }
+
+    /// Offer `check_vulnerabilities`, looking up advisories with `client`
+    ///
+    /// The tool is only advertised when the backend can name the OSV
+    /// ecosystem of its packages (the `vulnerability_check` capability).
+    pub fn with_vulnerability_database(mut self, client: osv::OsvClient) -> Self {
+        self.osv = Some(Arc::new(client));
+        self
+    }
+
+    /// Offer `search_all_distros`, looking up projects with `client`
+    pub fn with_repology(mut self, client: repology::RepologyClient) -> Self {
+        self.repology = Some(Arc::new(client));
+        self
+    }
+
+    /// Check the version `install_package` and `install_package_with_version`
+    /// are about to install against the vulnerability database, blocking it
+    /// as `gate` says
+    ///
+    /// Needs [`Self::with_vulnerability_database`]; without it, gated installs
+    /// are refused.
+    pub fn with_vulnerability_gate(mut self, gate: osv::VulnerabilityGate) -> Self {
+        self.vulnerability_gate = Some(gate);
+        // The override parameter is only offered when the gate allows it
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Verify the downloaded file of pinned packages before installing them
+    ///
+    /// Pinned packages are only installed by `install_package` and
+    /// `install_package_with_version`, on backends with the
+    /// `artifact_verification` capability; other installs of them are
+    /// refused.
+    pub fn with_package_pins(mut self, pins: pins::PackagePins) -> Self {
+        self.package_pins = pins;
+        self
+    }
+
+    /// Flag packages with a license containing `pattern` (case-insensitive)
+    /// in license reports, e.g. `AGPL`
+    pub fn with_denied_license(mut self, pattern: impl Into<String>) -> Self {
+        self.denied_licenses.push(pattern.into());
+        // The deny-list is shown in the tool description
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Allow installs from package files, URLs and custom repositories
+    ///
+    /// Such packages bypass the signatures of the configured repositories,
+    /// so calls using them are rejected unless allowed. Either way, the
+    /// attempt is recorded in the audit log.
+    pub fn with_untrusted_sources(mut self) -> Self {
+        self.allow_untrusted_sources = true;
+        self
+    }
+
+    /// Allow installs skipping the maintainer scripts of the packages
+    ///
+    /// Packages may depend on their scripts to create users, directories or
+    /// configuration, so `no_scripts` is only offered and accepted when
+    /// allowed.
+    pub fn with_no_scripts_allowed(mut self) -> Self {
+        self.allow_no_scripts = true;
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Only accept `repository` arguments below the URL prefix or on the
+    /// domain `entry`, e.g. `https://mirror.example.com/alpine/` or
+    /// `example.com`
+    ///
+    /// Once an entry is added, repositories without an approved URL are
+    /// rejected. Approved repositories are not untrusted sources, so they
+    /// can be installed from without [`Self::with_untrusted_sources`].
+    pub fn with_allowed_repository(mut self, entry: impl Into<String>) -> Self {
+        self.repository_allowlist.push(entry);
+        // The approved repositories are shown in the tool descriptions
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Only accept `repository` arguments below the URL prefix or on the
+    /// domain `entry` from the client identified as `subject`
+    ///
+    /// A client's own allowlist replaces the server-wide one, which still
+    /// applies to every other client.
+    pub fn with_allowed_repository_for(
+        mut self,
+        subject: impl Into<String>,
+        entry: impl Into<String>,
+    ) -> Self {
+        self.user_allowlists
+            .entry(subject.into())
+            .or_default()
+            .push(entry);
+        self
+    }
+
+    /// Allowlist applying to tool calls of `client`
+    fn repository_allowlist(
+        &self,
+        client: Option<&ClientIdentity>,
+    ) -> &allowlist::RepositoryAllowlist {
+        client
+            .and_then(|client| self.user_allowlists.get(&client.subject))
+            .unwrap_or(&self.repository_allowlist)
+    }
+
+    /// Reject `repository` arguments naming hosts other than the internal
+    /// mirrors of `offline`
+    ///
+    /// URLs below a mirrored public prefix are accepted, the backends move
+    /// them onto their mirror. The backends themselves are put in offline
+    /// mode with [`registry::BackendOptions::offline`].
+    pub fn with_offline_mirrors(mut self, offline: offline::OfflineMirrors) -> Self {
+        self.offline = Some(offline);
+        // The internal mirrors are shown in the tool descriptions
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Refuse operations using repositories while one of them is unsigned
+    ///
+    /// The configured repositories and the operation's custom repositories
+    /// are checked with [`PackageManager::unsigned_repositories`] before
+    /// every operation that installs, searches or refreshes. Backends
+    /// without the `signature_check` capability refuse them all.
+    pub fn with_signed_repositories_only(mut self) -> Self {
+        self.signed_repositories_only = true;
+        self
+    }
+
+    /// Let installs run without estimating the disk space they need first
+    ///
+    /// The estimate runs the package manager's simulation of the install,
+    /// which doubles its dependency resolution.
+    pub fn without_disk_space_check(mut self) -> Self {
+        self.disk_space_check = false;
+        self
+    }
+
+    /// Refresh the repository indexes before installs and searches when
+    /// they are stale, and retry once after a refresh when one misses a
+    /// package
+    pub fn with_auto_refresh(mut self, policy: freshness::AutoRefresh) -> Self {
+        self.auto_refresh = Some(policy);
+        self
+    }
+
+    /// Record every tool call, rejected ones included, in `log`
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(Arc::new(log));
+        self
+    }
+
+    /// Keep the output of every mutating operation in `logs`
+    pub fn with_operation_logs(mut self, logs: operation_log::OperationLogStore) -> Self {
+        self.operation_logs = Some(Arc::new(logs));
+        self.tool_lists = Arc::default();
+        self
+    }
+
+    /// Limit the installs, removed packages and downloads of every session
+    pub fn with_quotas(mut self, limits: QuotaLimits) -> Self {
+        self.quota = (!limits.is_empty()).then(|| Arc::new(QuotaUsage::new(limits)));
+        self
+    }
+
+    /// Ask `webhook` to approve every mutating operation before running it
+    pub fn with_approval_webhook(mut self, webhook: approval::ApprovalWebhook) -> Self {
+        self.approval = Some(webhook);
+        self
+    }
+
+    /// Notify `sink` after every install call, whatever its outcome
+    pub fn with_notification_sink(mut self, sink: notifications::NotificationSink) -> Self {
+        self.notification_sinks.push(sink);
+        self
+    }
+
+    /// Handler for a new MCP session, with an empty session recording,
+    /// delta, install queue and quota usage
+    pub fn for_new_session(&self) -> Self {
+        let mut handler = self.clone();
+        handler.delta = Arc::default();
+        handler.watcher = Arc::default();
+        handler.listings = Arc::default();
+        if handler.session.is_some() {
+            handler.session = Some(Arc::default());
+        }
+        if let Some(batcher) = &self.batcher {
+            handler.batcher = Some(Arc::new(batch::InstallBatcher::new(
+                batcher.window(),
+                handler.mutation_lock.clone(),
+            )));
+        }
+        if let Some(quota) = &self.quota {
+            handler.quota = Some(Arc::new(quota.fresh()));
+        }
+        handler
+    }
+
+    /// The batcher to hand the install of `options` to: plain installs of
+    /// packages that are not pinned, on the handler's own system
+    fn batcher_for(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        options: &InstallOptions,
+    ) -> Option<&Arc<batch::InstallBatcher>> {
+        self.batcher.as_ref().filter(|_| {
+            !self.package_pins.is_pinned(&options.package)
+                && options.added_repositories().is_empty()
+                && options.only_repositories.is_empty()
+                && options.preseed.is_empty()
+                && options.install_recommends.is_none()
+                && options.target_release.is_none()
+                && options.flags.is_empty()
+                && Arc::ptr_eq(backend, &self.backend)
+                && backend.capabilities().batch_install
+        })
+    }
+
+    /// Record an install made by `backend` from the custom `repositories`
+    /// if it targets the server's system
+    fn record_install(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        outcome: &InstallOutcome,
+        arch: Option<&str>,
+        repositories: &[&str],
+    ) {
+        if let Some(session) = &self.session
+            && Arc::ptr_eq(backend, &self.backend)
+        {
+            session.record(outcome, arch, repositories);
+        }
+    }
+
+    /// Record a package installed from a lockfile by `backend` if it targets
+    /// the server's system, keeping the lockfile's dependencies apart
+    fn record_locked_install(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        outcome: &InstallOutcome,
+        locked: &LockedPackage,
+    ) {
+        if !locked.dependency {
+            self.record_install(
+                backend,
+                outcome,
+                locked.package.arch.as_deref(),
+                &locked.package.custom_repositories().collect::<Vec<_>>(),
+            );
+        } else if let Some(session) = &self.session
+            && Arc::ptr_eq(backend, &self.backend)
+        {
+            session.record_dependency(locked.package.clone());
+        }
+    }
+
+    /// Targets selected by a fan-out install, every target when none are named
+    fn select_targets(
+        &self,
+        names: &[String],
+    ) -> Result<Vec<targets::NamedBackend>, PackageManagerError> {
+        if self.targets.is_empty() {
+            return Err(PackageManagerError::Unsupported {
+                operation: "install_package_on_targets",
+            });
+        }
+        if names.is_empty() {
+            return Ok(self.targets.clone());
+        }
+        names
+            .iter()
+            .map(|name| {
+                self.targets
+                    .iter()
+                    .find(|(target, _)| target == name)
+                    .cloned()
+                    .ok_or_else(|| PackageManagerError::Validation {
+                        field: "targets",
+                        value: name.clone(),
+                        reason: format!(
+                            "unknown target, configured targets: {}",
+                            self.target_names().join(", ")
+                        ),
+                    })
+            })
+            .collect()
+    }
+
+    fn target_names(&self) -> Vec<&str> {
+        self.targets.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    fn set_privileges(&self, report: PrivilegeReport) {
+        *self
+            .privileges
+            .write()
+            .unwrap_or_else(|err| err.into_inner()) = Some(report);
+    }
+
+    /// Problems preventing mutating operations, per the latest privilege check
+    fn mutation_problems(&self) -> Option<Vec<String>> {
+        self.privileges
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_ref()
+            .filter(|report| !report.can_mutate)
+            .map(|report| report.problems.clone())
+    }
+
+    /// Packages installed by `backend`, from the cache when it is fresh
+    async fn installed_packages(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        root: Option<&str>,
+    ) -> Result<Arc<[InstalledPackage]>, PackageManagerError> {
+        let Some(installed) = &self.installed else {
+            return Ok(backend.list_installed_packages().await?.into());
+        };
+        if let Some(packages) = installed.get(root) {
+            return Ok(packages);
+        }
+        let packages: Arc<[InstalledPackage]> = backend.list_installed_packages().await?.into();
+        installed.insert(root, packages.clone());
+        Ok(packages)
+    }
+
+    /// Capabilities of the backend, without mutating tools while the server
+    /// cannot modify the system
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = self.backend.capabilities();
+        if self.mutation_problems().is_some() {
+            capabilities.mutating = false;
+        }
+        if self.session.is_none() {
+            capabilities.install_commands = false;
+            capabilities.lockfile = false;
+            capabilities.cloud_init = false;
+        }
+        if self.osv.is_none() {
+            capabilities.vulnerability_check = false;
+        }
+        capabilities.audit_log = self.audit.is_some();
+        capabilities.cross_distro_search = self.repology.is_some();
+        capabilities
+    }
+}
+
+impl ServerHandler for PackageManagerHandler {
+    fn get_info(&self) -> ServerInfo {
+        let instructions = format!(
+            "This MCP server provides {} package management capabilities through the {} package manager. \
+            Use this server to search for, install, update, list installed packages, and manage packages on {} systems. \
+            The server executes {} commands with appropriate error handling and provides detailed feedback on operations.",
+            self.backend.os_name(),
+            self.backend.name(),
+            self.backend.os_name(),
+            self.backend.name()
+        );
+
+        ServerInfo {
+            protocol_version: ProtocolVersion::V_2025_03_26,
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .enable_resources_subscribe()
+                .build(),
+            server_info: Implementation::from_build_env(),
+            instructions: Some(self.descriptions.apply_to_instructions(&instructions)),
+        }
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        Ok(self.tool_list(self.capabilities())?.as_ref().clone())
+    }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, McpError> {
+        let delta = RawResource {
+            description: Some(
+                "Packages this session added, removed and changed (with their previous_version) \
+                on the system. Read it when the session ends to snapshot or persist what it \
+                changed."
+                    .to_string(),
+            ),
+            mime_type: Some("application/json".to_string()),
+            ..RawResource::new(SESSION_DELTA_URI, "session-delta")
+        };
+        let manifest = RawResource {
+            description: Some(
+                "Packages installed on the system. Subscribe to it to be notified when the \
+                installed packages change, also by commands run outside this server."
+                    .to_string(),
+            ),
+            mime_type: Some("application/json".to_string()),
+            ..RawResource::new(INSTALLED_MANIFEST_URI, "installed-manifest")
+        };
+        let mut resources = vec![manifest.no_annotation(), delta.no_annotation()];
+        if let Some(logs) = &self.operation_logs {
+            // Like reading them, listing logs is limited to the client's own
+            let client = context
+                .extensions
+                .get::<axum::http::request::Parts>()
+                .and_then(|parts| parts.extensions.get::<ClientIdentity>())
+                .map(|client| client.subject.as_str());
+            let ids = logs.recent(client).await.map_err(|err| {
+                PackageManagerError::io(
+                    format!(
+                        "there was an error listing operation logs in {}",
+                        logs.dir().display()
+                    ),
+                    err,
+                )
+            })?;
+            resources.extend(ids.into_iter().map(|id| {
+                RawResource {
+                    description: Some(format!(
+                        "Every line the package manager printed during operation {id}."
+                    )),
+                    mime_type: Some("application/json".to_string()),
+                    ..RawResource::new(
+                        format!("{}{id}", operation_log::OPERATION_LOG_URI_PREFIX),
+                        id,
+                    )
+                }
+                .no_annotation()
+            }));
+        }
+        Ok(ListResourcesResult {
+            resources,
+            next_cursor: None,
+        })
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        let content = match request.uri.as_str() {
+            INSTALLED_MANIFEST_URI => {
+                let installed = self.installed_packages(&self.backend, None).await?;
+                serde_json::to_string_pretty(&installed)
+            }
+            SESSION_DELTA_URI => {
+                let delta = self
+                    .delta
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .clone();
+                serde_json::to_string_pretty(&delta)
+            }
+            uri => {
+                let operation_id = uri.strip_prefix(operation_log::OPERATION_LOG_URI_PREFIX);
+                let (Some(logs), Some(operation_id)) = (&self.operation_logs, operation_id) else {
+                    return Err(unknown_resource(uri));
+                };
+                let client = context
+                    .extensions
+                    .get::<axum::http::request::Parts>()
+                    .and_then(|parts| parts.extensions.get::<ClientIdentity>())
+                    .map(|client| client.subject.as_str());
+                let log = logs.read(operation_id, client).await?;
+                serde_json::to_string_pretty(&log)
+            }
+        };
+        Ok(ReadResourceResult {
+            contents: vec![ResourceContents::TextResourceContents {
+                uri: request.uri,
+                mime_type: Some("application/json".to_string()),
+                text: content.map_err(|err| McpError::internal_error(err.to_string(), None))?,
+            }],
+        })
+    }
+
+    async fn subscribe(
+        &self,
+        request: SubscribeRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<(), McpError> {
+        if request.uri != INSTALLED_MANIFEST_URI {
+            return Err(unknown_resource(&request.uri));
+        }
+        if !self.capabilities().drift_detection {
+            return Err(PackageManagerError::Unsupported {
+                operation: "resources/subscribe",
+            }
+            .into());
+        }
+        self.watcher
+            .watch(
+                self.backend.clone(),
+                self.installed.clone(),
+                context.peer,
+                request.uri,
+                self.watch_interval,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn unsubscribe(
+        &self,
+        request: UnsubscribeRequestParam,
+        _: RequestContext<RoleServer>,
+    ) -> Result<(), McpError> {
+        if request.uri != INSTALLED_MANIFEST_URI {
+            return Err(unknown_resource(&request.uri));
+        }
+        self.watcher.stop();
+        Ok(())
+    }
+
+    async fn call_tool(
+        &self,
+        mut request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let tool_name = request.name.clone();
+        let Some(name) = self.tool_names.internal(&tool_name) else {
+            return Ok(self.unknown_tool(&tool_name));
+        };
+        request.name = name.into();
+        // Set on the HTTP request by the server's authentication and
+        // identity layers
+        let client = context
+            .extensions
+            .get::<axum::http::request::Parts>()
+            .and_then(|parts| parts.extensions.get::<ClientIdentity>())
+            .cloned();
+        let session = context
+            .extensions
+            .get::<axum::http::request::Parts>()
+            .and_then(|parts| parts.headers.get(MCP_SESSION_ID_HEADER))
+            .and_then(|session| session.to_str().ok())
+            .map(str::to_string);
+
+        if let Some(jobs) = &self.jobs
+            && Operation::is_mutating_tool(name)
+            && let Some(arguments) = &mut request.arguments
+            && let Some(run_async) = arguments.remove(jobs::ASYNC_ARGUMENT)
+        {
+            let Some(run_async) = run_async.as_bool() else {
+                return Err(with_error_code(McpError::invalid_params(
+                    format!("{} must be a boolean", jobs::ASYNC_ARGUMENT),
+                    None,
+                )));
+            };
+            if run_async {
+                let owner = idempotency::Owner::new(
+                    client.as_ref().map(|client| client.subject.as_str()),
+                    session.as_deref(),
+                );
+                let handler = self.clone();
+                let queue = session.clone();
+                let (job_id, ahead) = jobs.start(name, owner, queue.as_deref(), async move {
+                    handler
+                        .dispatch_tool_call(request, client, session)
+                        .await
+                        .map_err(with_error_code)
+                });
+                let (message, status) = match ahead {
+                    0 => (
+                        format!("Started job '{job_id}' running {tool_name}."),
+                        jobs::JobStatus::Running,
+                    ),
+                    ahead => (
+                        format!(
+                            "Queued job '{job_id}' running {tool_name} behind {ahead} earlier jobs of this session."
+                        ),
+                        jobs::JobStatus::Queued,
+                    ),
+                };
+                let mut report = serde_json::json!({
+                    "job_id": job_id,
+                    "status": status,
+                });
+                if ahead > 0 {
+                    report["queue_position"] = ahead.into();
+                }
+                return Ok(CallToolResult::success(vec![
+                    Content::text(format!(
+                        "{message} Follow it with get_job_status and get_job_output."
+                    )),
+                    Content::json(report)?,
+                ]));
+            }
+        }
+
+        let call = async {
+            match context.meta.get_progress_token() {
+                // Forward command output lines to the client as progress notifications
+                Some(progress_token) => {
+                    let (sender, mut receiver) =
+                        tokio::sync::mpsc::unbounded_channel::<exec::OutputLine>();
+                    let peer = context.peer.clone();
+                    tokio::spawn(async move {
+                        let mut progress = 0;
+                        while let Some(output_line) = receiver.recv().await {
+                            progress += 1;
+                            let _ = peer
+                                .notify_progress(ProgressNotificationParam {
+                                    progress_token: progress_token.clone(),
+                                    progress,
+                                    total: None,
+                                    message: Some(output_line.line),
+                                })
+                                .await;
+                        }
+                    });
+                    exec::stream_output(sender, self.dispatch_tool_call(request, client, session))
+                        .await
+                }
+                None => self.dispatch_tool_call(request, client, session).await,
+            }
+        };
+
+        // Dropping the in-flight call kills the spawned package manager process
+        let result = tokio::select! {
+            result = call => result,
+            _ = context.ct.cancelled() => Err(McpError::internal_error(
+                format!("Tool call '{tool_name}' was cancelled"),
+                Some(serde_json::json!({
+                    "error_type": "cancelled"
+                })),
+            )),
+        };
+        result.map_err(with_error_code)
+    }
+}
+
+/// Error for a resource URI the server does not offer
+fn unknown_resource(uri: &str) -> McpError {
+    McpError::resource_not_found(
+        format!("Unknown resource '{uri}'"),
+        Some(serde_json::json!({ "uri": uri })),
+    )
+}
+
+impl PackageManagerHandler {
+    /// Tools offered with `capabilities`, built on first use
+    ///
+    /// The list only changes with the capabilities (mutating tools are hidden
+    /// while the server cannot modify the system), so one list is kept per
+    /// capability set and shared by every session.
+    fn tool_list(&self, capabilities: Capabilities) -> Result<Arc<ListToolsResult>, McpError> {
+        let mut lists = self
+            .tool_lists
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Some((_, list)) = lists.iter().find(|(cached, _)| *cached == capabilities) {
+            return Ok(list.clone());
+        }
+        let list = Arc::new(self.build_tool_list(capabilities)?);
+        lists.push((capabilities, list.clone()));
+        Ok(list)
+    }
+
+    /// Build the tool list and check that every tool's schema is valid
+    ///
+    /// Called when a server is built, so a broken schema fails at startup
+    /// rather than on a client's first `tools/list`.
+    pub fn validate_tools(&self) -> Result<(), McpError> {
+        self.tool_list(self.capabilities()).map(|_| ())
+    }
+
+    fn build_tool_list(&self, capabilities: Capabilities) -> Result<ListToolsResult, McpError> {
+        let pm_name = self.backend.name();
+        let os_name = self.backend.os_name();
+        let pm_lower = pm_name.to_lowercase();
+        // Alpine has branches, APT suites; searches accept either name
+        let branch_parameter = if pm_lower == "apk" { "branch" } else { "suite" };
+        let arch_schema = serde_json::json!({
+            "type": "string",
+            "description": if pm_lower == "apk" {
+                "Optional: Architecture to resolve packages for when it differs from the host's, e.g. when preparing a root filesystem for another machine \
+                (e.g., 'aarch64', 'armv7', 'x86_64'). Passed to 'apk --arch'."
+            } else {
+                "Optional: Architecture to install packages for when it differs from the host's (e.g., 'arm64', 'armhf', 'i386'), using APT's 'package:arch' syntax. \
+                The architecture must be enabled with 'dpkg --add-architecture'. APT searches list packages of every enabled architecture, so searches ignore it."
+            }
+        });
+
+        let preseed_schema = serde_json::json!({
+            "type": "array",
+            "items": { "type": "string" },
+            "description": "Optional: debconf answers to set before installing, one 'package question type value' line each \
+                (e.g., 'tzdata tzdata/Areas select Europe'), for packages such as tzdata or mysql-server that ask questions while installing. \
+                Fed to debconf-set-selections."
+        });
+        let recommends_schema = serde_json::json!({
+            "type": "string",
+            "enum": ["none", "recommends", "suggests"],
+            "description": "Optional: Which packages to install besides the dependencies: 'none' skips the recommended packages \
+                to keep images small (--no-install-recommends), 'recommends' installs them and 'suggests' also installs the suggested ones \
+                (--install-suggests). Defaults to the server's setting."
+        });
+        let target_release_schema = serde_json::json!({
+            "type": "string",
+            "description": "Optional: Release to prefer packages and their dependencies from (apt-get install -t), e.g. 'bookworm-backports' \
+                for packages newer than the stable release. Versions are resolved among the packages of that release."
+        });
+        let no_scripts_schema = serde_json::json!({
+            "type": "boolean",
+            "default": false,
+            "description": "Optional: Skip the maintainer scripts of the packages (--no-scripts), for environments forbidding them. \
+                Packages relying on their scripts, e.g. to create users, may not work afterwards."
+        });
+        let no_cache_schema = serde_json::json!({
+            "type": "boolean",
+            "default": false,
+            "description": "Optional: Neither use nor fill the local package cache (--no-cache), e.g. to keep container image layers small."
+        });
+        let update_cache_schema = serde_json::json!({
+            "type": "boolean",
+            "default": false,
+            "description": "Optional: Download the repository indexes before installing (--update-cache)."
+        });
+
+        let repositories_schema = serde_json::json!({
+            "type": "array",
+            "items": { "type": "string" },
+            "description": "Optional: More custom repositories to use along with 'repository' and the configured ones, in the format of 'repository', \
+                e.g. a private repository and the public one its packages depend on."
+        });
+        let only_repositories_schema = serde_json::json!({
+            "type": "array",
+            "items": { "type": "string" },
+            "minItems": 1,
+            "description": if pm_lower == "apk" {
+                "Optional: Repositories to use instead of the configured ones for this call only, e.g. a private repository with the public \
+                branch it builds on. Each is a repository URL or a local directory of packages, in the format of 'repository'. Cannot be combined with 'repository'."
+            } else {
+                "Optional: APT sources to use instead of the configured ones for this call only, e.g. a private repository with the public \
+                suite it builds on. Each is a one-line entry, a deb822 stanza or a local directory of .deb files. Cannot be combined with 'repository'."
+            }
+        });
+
+        let mut tools = vec![
+            Tool {
+                name: "install_package".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Install {} packages using the {} package manager. This tool executes '{}' commands with proper error handling. \
+                    Use this when you need to install the latest version of software packages, libraries, or development tools on {} systems. \
+                    If you need to install a specific version, use the install_package_with_version tool.",
+                    os_name, pm_name,
+                    if pm_lower == "apk" { "apk add" } else { "apt-get install" },
+                    os_name
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "package_name": {
+                                "type": "string",
+                                "description": format!(
+                                    "The exact name of the {} package to install (e.g., 'curl', 'python3', 'git'). \
+                                    Package names are case-sensitive and should match the official package names in {} repositories. \
+                                    Multiple packages can be specified by calling this tool multiple times.",
+                                    os_name, os_name
+                                )
+                            },
+                            "repository": {
+                                "type": "string",
+                                "description": if pm_lower == "apk" {
+                                    "Optional: Custom repository URL to use for package installation. Use this when you need to install packages from non-standard repositories or specific Alpine mirrors. Format should be a valid APK repository URL (e.g., 'https://dl-cdn.alpinelinux.org/alpine/edge/testing'), or a local directory of packages as an absolute path or file:// URL (e.g., '/srv/packages', holding one subdirectory of .apk files per architecture), which is indexed when needed. A URL prefixed with a tag (e.g., '@testing https://dl-cdn.alpinelinux.org/alpine/edge/testing') is a tagged repository: only packages requested as 'package@testing' are installed from it, and it is added to /etc/apk/repositories once the install succeeds. Tags start with a letter or digit and only contain letters, digits, '_', '.' and '-'. If not provided, the system's default configured repositories will be used.".to_string()
+                                } else {
+                                    "Optional: Custom APT source to install from, in addition to the sources in /etc/apt/sources.list.d. Either a one-line entry \
+                                    (e.g., 'deb [signed-by=/etc/apt/keyrings/example.gpg] https://example.org/debian bookworm main'), a deb822 stanza with Types, URIs, Suites, \
+                                    Components and Signed-By fields, the path of a .list or .sources file, or a local directory of .deb files as an absolute path or file:// URL (e.g., '/srv/debs'), which is indexed when needed. If not provided, the system's default configured repositories will be used.".to_string()
+                                }
+                            },
+                            "repositories": repositories_schema,
+                            "only_repositories": only_repositories_schema,
+                            "arch": arch_schema,
+                            "preseed": preseed_schema,
+                            "install_recommends": recommends_schema,
+                            "target_release": target_release_schema,
+                            "no_scripts": no_scripts_schema,
+                            "no_cache": no_cache_schema,
+                            "update_cache": update_cache_schema,
+                        },
+                        "required": ["package_name"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse install_package schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "install_package_with_version".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Install a specific version of a {os_name} package. This tool searches {os_name} repositories to find the requested package version, \
+                    then installs it using exact version matching. Use this when you need to install a specific version of a package rather than the latest available version. \
+                    To install several packages at once, pass them in packages instead of package_name and version: they are installed together in one transaction, \
+                    or not at all if any version cannot be found."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "package_name": {
+                                "type": "string",
+                                "description": format!(
+                                    "The exact name of the {} package to install (e.g., 'curl', 'python3', 'git'). \
+                                    Package names are case-sensitive and should match the official package names in {} repositories.",
+                                    os_name, os_name
+                                )
+                            },
+                            "version": {
+                                "type": "string",
+                                "description": format!(
+                                    "The specific version of the package to install. The version string must match exactly as it appears in the repository, \
+                                    or be a constraint such as '>=1.2', '<2.0' or '~3.12' to install the newest matching version. \
+                                    If no matching version is found, the tool will return a list of available versions."
+                                )
+                            },
+                            "packages": {
+                                "type": "array",
+                                "description": "Optional: Packages to install together in one transaction, in place of package_name and version. \
+                                    The result reports the outcome of each package.",
+                                "items": {
+                                    "type": "object",
+                                    "properties": {
+                                        "package": { "type": "string", "description": "Name of the package" },
+                                        "version": { "type": "string", "description": "Version or version constraint to install" },
+                                        "arch": { "type": "string", "description": "Optional: Architecture of the package, defaulting to arch" },
+                                    },
+                                    "required": ["package", "version"]
+                                },
+                                "minItems": 1
+                            },
+                            "arch": arch_schema,
+                            "preseed": preseed_schema,
+                            "install_recommends": recommends_schema,
+                            "target_release": target_release_schema,
+                            "no_scripts": no_scripts_schema,
+                            "no_cache": no_cache_schema,
+                            "update_cache": update_cache_schema,
+                        },
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse install_package_with_version schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "resolve_version".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Find the {os_name} package version install_package_with_version would install for a version or constraint, and the repositories offering it, without installing anything. \
+                    Use this to plan version pins before modifying the system."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "package_name": {
+                                "type": "string",
+                                "description": format!("The exact name of the {os_name} package (e.g., 'curl', 'python3').")
+                            },
+                            "version": {
+                                "type": "string",
+                                "description": "Exact version, partial version such as '3.11', or constraint such as '>=1.2', '<2.0' or '~3.12'. \
+                                    If no version matches, the tool returns the available versions."
+                            },
+                            "arch": arch_schema,
+                            "target_release": target_release_schema,
+                        },
+                        "required": ["package_name", "version"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse resolve_version schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "refresh_repositories".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Refresh registered repository indexes using '{}'. This tool synchronizes the local package database with remote repositories, \
+                    ensuring you have access to the latest package information and versions. Use this before installing packages to get the most up-to-date package lists.",
+                    if pm_lower == "apk" { "apk update" } else { "apt-get update" }
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse refresh_repositories schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "list_installed_packages".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "List all installed packages on {} using '{}'. This tool shows all packages currently installed on the system with their versions. \
+                    Use this to audit installed software, check package versions, or verify installations.",
+                    os_name,
+                    if pm_lower == "apk" { "apk list -I" } else { "apt list --installed" }
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "cursor": {
+                                "type": "string",
+                                "description": "The next_cursor of the previous page, to list the next page"
+                            },
+                            "limit": {
+                                "type": "integer",
+                                "minimum": 1,
+                                "description": "Maximum number of packages in the page. When set (or when the server pages listings), the result ends with a {total, next_cursor} object, and next_cursor is null on the last page."
+                            }
+                        },
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse list_installed_packages schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "search_package".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Search for {} packages using the {} package manager. This tool executes '{}' commands to find packages matching your query. \
+                    Use this when you need to discover available packages, find package names, or explore what software is available.",
+                    os_name, pm_name,
+                    if pm_lower == "apk" { "apk search" } else { "apt-cache search" }
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "query": {
+                                "type": "string",
+                                "description": format!(
+                                    "Package name pattern to search for. Use exact package names (e.g., 'ruby', 'python3') or patterns to match multiple packages. \
+                                    If you don't know the package name, try with specific package names first to avoid excessive output."
+                                )
+                            },
+                            "mode": {
+                                "type": "string",
+                                "enum": ["exact", "prefix", "wildcard", "description"],
+                                "default": "prefix",
+                                "description": "Optional: How the query matches packages. 'exact' matches the package name exactly; \
+                                    'prefix' (the default) matches names starting with the query, or the query as a wildcard pattern when it contains '*' or '?'; \
+                                    'wildcard' matches names against the query as a wildcard pattern, or names containing it when it has no wildcards; \
+                                    'description' matches names or descriptions containing the query."
+                            },
+                            "search_descriptions": {
+                                "type": "boolean",
+                                "default": false,
+                                "description": "Optional: Whether to also match the query against package descriptions, the same as mode 'description'. \
+                                    Name searches are fast and precise; description searches find packages by what they do but return many more results."
+                            },
+                            "limit": {
+                                "type": "integer",
+                                "minimum": 1,
+                                "description": "Optional: Most results to return. Searches for a common prefix can match hundreds of packages."
+                            },
+                            "sort": {
+                                "type": "string",
+                                "enum": ["name", "version", "newest"],
+                                "description": "Optional: Order of the results: 'name' by package name, 'version' highest versions first, \
+                                    'newest' most recently built first. Results are in the package manager's order when not given."
+                            },
+                            branch_parameter: {
+                                "type": "string",
+                                "description": if pm_lower == "apk" {
+                                    "Optional: Alpine branch the results must come from, e.g. 'v3.22' or 'edge'."
+                                } else {
+                                    "Optional: Suite the results must come from, e.g. 'bookworm' or 'bookworm-updates'."
+                                }
+                            },
+                            "repository": {
+                                "type": "string",
+                                "description": if pm_lower == "apk" {
+                                    "Optional: Specific repository URL or local directory of packages to search in. If not provided, the search will query across multiple Alpine repositories (the system's branch, its configured repositories and older branches; see list_search_repositories) to find all available versions of matching packages.".to_string()
+                                } else {
+                                    "Optional: This parameter is not used for APT searches. APT searches use the system's configured repositories.".to_string()
+                                }
+                            },
+                            "repositories": repositories_schema,
+                            "only_repositories": only_repositories_schema,
+                            "arch": arch_schema,
+                        },
+                        "required": ["query"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse search_package schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "list_repositories".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "List the package repositories configured on {os_name}, with their suites, components and signing keys where the format has them. \
+                    Use this to check where packages will be installed from before installing packages or passing a custom repository."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse list_repositories schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "check_privileges".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Check whether the server can install packages and refresh repositories: the effective user, privilege escalation through sudo or doas \
+                    and write access to the {pm_name} database. Install tools are hidden while the server cannot modify the system; run this after fixing \
+                    the reported problems to make them available again."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse check_privileges schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "server_info".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Describe the environment this server manages: the {pm_name} version, the distribution release, the configured repositories, \
+                    whether installs and repository refreshes can succeed, the optional features available and the policies that may reject calls \
+                    (repository allowlists, signed repositories only, vulnerability blocking, approvals, quotas). \
+                    Call it first to adapt your plan to the actual system instead of learning it from failures."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse server_info schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "doctor".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Diagnose why installs fail: checks that {pm_name} runs, the privileges of the server, the package database lock, \
+                    the free disk space, that the repositories are reachable and signed, and the proxy configuration. \
+                    Returns pass, warn or fail for each check with a hint on how to fix it. \
+                    Use it when installs or refreshes keep failing for unclear reasons."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse doctor schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "export_session".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Export the packages installed during this session as the {pm_name} commands reinstalling them at the same versions, \
+                    either as a shell script, a Dockerfile RUN instruction or Ansible tasks, or describe them as an SPDX 2.3 JSON bill of materials. \
+                    Use this to turn what was set up interactively into a reproducible image build, an Ansible role or an SBOM for compliance tooling. \
+                    Each package appears once, pinned to the last version installed."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "format": {
+                                "type": "string",
+                                "enum": ["script", "dockerfile", "spdx", "ansible"],
+                                "description": "Optional: 'script' for a POSIX shell script (default), 'dockerfile' for a Dockerfile RUN instruction, 'spdx' for an SPDX 2.3 JSON document or 'ansible' for a list of Ansible tasks."
+                            }
+                        },
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse export_session schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "export_dockerfile_snippet".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Export the packages installed during this session as idiomatic Dockerfile instructions installing them with {pm_name} at the same versions, \
+                    following the distribution's image conventions such as not keeping package caches in the layer. \
+                    Use this to paste what was set up interactively into a Dockerfile. Each package appears once, pinned to the last version installed."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse export_dockerfile_snippet schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "export_cloud_init".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Export the packages installed during this session as cloud-init user data installing them with {pm_name} at the same versions, \
+                    together with the repositories and signing keys they were installed from. \
+                    Use this to provision VMs with what was set up interactively. Returns a #cloud-config YAML document."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse export_cloud_init schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "export_apko_config".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Describe {pm_name} packages as an apko image configuration: the repositories, signing keys, packages pinned to their installed versions and architecture. \
+                    Use this to turn a sandbox provisioned interactively into a declarative Alpine or Wolfi image build. \
+                    Returns the configuration as YAML, to merge into an apko configuration file."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "scope": {
+                                "type": "string",
+                                "enum": ["session", "installed"],
+                                "description": "Optional: 'session' for the packages installed during this session or 'installed' for every installed package. Defaults to 'session' when sessions are recorded and 'installed' otherwise."
+                            }
+                        },
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse export_apko_config schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "generate_lockfile".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Generate a lockfile of every {pm_name} package installed during this session, including the dependencies the installs pulled in, \
+                    with its exact version, checksum and the repository holding it. Use this to reproduce the environment exactly: \
+                    unlike export_session, the lockfile also pins the dependencies. Returns the lockfile as a JSON document."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse generate_lockfile schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "install_from_lockfile".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Install every package of a lockfile written by generate_lockfile at exactly its pinned version, in one {pm_name} transaction, \
+                    to reproduce an environment. Before installing, checks that each pinned version is still available and that its checksum matches the lockfile; \
+                    if not, nothing is installed and the error lists the differences. Checksums are checked again after the install."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "lockfile": {
+                                "type": ["object", "string"],
+                                "description": "The lockfile returned by generate_lockfile, as a JSON object or its text."
+                            }
+                        },
+                        "required": ["lockfile"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse install_from_lockfile schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "export_audit_log".into(),
+                description: Some(std::borrow::Cow::Borrowed(
+                    "Export the server's audit log, which records every tool call with its outcome, after verifying that it was not tampered with. \
+                    Each entry is chained to the one before it by its SHA-256 hash and, when the server has a signing key, signed with it. \
+                    Returns the verification of the whole chain (whether it is valid, the hash of its last entry and the first line breaking it) and the entries.",
+                )),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "from_sequence": {
+                                "type": "integer",
+                                "minimum": 1,
+                                "description": "Optional: sequence number of the first entry to return, to fetch only the entries recorded since a previous export. The whole chain is verified regardless."
+                            }
+                        },
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse export_audit_log schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "export_history".into(),
+                description: Some(std::borrow::Cow::Borrowed(
+                    "Export the server's operation history from its audit log as JSON Lines for analytics, e.g. to count which packages agents install most often. \
+                    Each line is one package named by one tool call, with the same fields on every line: sequence, timestamp, tool, outcome, package, version, arch, \
+                    repository, root, client, untrusted_source and error, null when they do not apply. Calls naming no package give one line without one.",
+                )),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "from_sequence": {
+                                "type": "integer",
+                                "minimum": 1,
+                                "description": "Optional: sequence number of the first audit entry to export, to fetch only the calls recorded since a previous export."
+                            }
+                        },
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse export_history schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "check_vulnerabilities".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Look up known vulnerabilities of {os_name} packages in the OSV.dev database, which aggregates the distribution's security advisories, \
+                    independently of any scanner installed on the system. Checks every installed package by default, or only the given packages, \
+                    e.g. a version about to be installed. Returns the advisories affecting each package with their severity and the versions fixing them."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "packages": {
+                                "type": "array",
+                                "items": { "type": "string" },
+                                "description": "Optional: Packages to check, as 'name' for the installed version or 'name=version' for any other version \
+                                (e.g., 'curl', 'openssl=3.5.1-r0'). Every installed package is checked when not provided."
+                            }
+                        },
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse check_vulnerabilities schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "search_all_distros".into(),
+                description: Some(std::borrow::Cow::Owned(
+                    "Look up a project in the Repology database, which tracks the packages of most Linux distributions, and report the package names \
+                    and newest version of the project in each major distribution release. Use this to find out what a project is called on another \
+                    distribution (e.g., that libcurl's headers are 'curl-dev' on Alpine and 'libcurl4-openssl-dev' on Debian) without trial-and-error searches."
+                        .to_string(),
+                )),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "project": {
+                                "type": "string",
+                                "description": "Repology project name, usually the upstream name in lowercase (e.g., 'curl', 'python', 'openssl')"
+                            },
+                            "distros": {
+                                "type": "array",
+                                "items": { "type": "string" },
+                                "description": "Optional: Repology repository families to report (e.g., 'alpine', 'debian', 'ubuntu', 'nix'). \
+                                Defaults to Alpine, Wolfi, Debian, Ubuntu, Fedora, CentOS Stream, openSUSE and Arch."
+                            }
+                        },
+                        "required": ["project"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse search_all_distros schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "license_report".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Report the licenses of every package installed on {os_name}, as declared by the packages, with the number of packages per license. \
+                    Packages whose license matches the deny-list are flagged for compliance review{}. \
+                    Use this before distributing an image or to find out why a package may not be shipped.",
+                    if self.denied_licenses.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" (server deny-list: {})", self.denied_licenses.join(", "))
+                    }
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "deny": {
+                                "type": "array",
+                                "items": { "type": "string" },
+                                "description": "Optional: Additional license patterns to flag, matched case-insensitively anywhere in a license (e.g., 'AGPL', 'SSPL'). \
+                                Added to the server's deny-list."
+                            }
+                        },
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse license_report schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            },
+            Tool {
+                name: "list_search_repositories".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "List the {pm_name} repositories that search_package and install_package_with_version query when no repository is given. \
+                    Use this to know which branches and mirrors a search covered before concluding that a package or version does not exist."
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {},
+                        "required": []
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse list_search_repositories schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(false),
+                    ..Default::default()
+                }),
+            }
+        ];
+
+        tools.retain(|tool| capabilities.exposes_tool(&tool.name));
+        let output_format_schema = serde_json::json!({
+            "type": "string",
+            "enum": ["text", "json", "summary"],
+            "description": "Optional: Shape of the result. 'text' (default) returns a human-readable text and the JSON data, \
+                'json' only the JSON data and 'summary' one short sentence. Pick 'summary' or 'json' to save tokens."
+        });
+        for tool in &mut tools {
+            if let Some(serde_json::Value::Object(properties)) =
+                Arc::make_mut(&mut tool.input_schema).get_mut("properties")
+            {
+                properties.insert("output_format".to_string(), output_format_schema.clone());
+            }
+        }
+        if capabilities.alternate_root {
+            let root_schema = serde_json::json!({
+                "type": "string",
+                "description": format!(
+                    "Optional: Absolute path of a root filesystem to operate on instead of the server's target, e.g. a staged image root. \
+                    {pm_name} reads its configuration and installed packages from this directory and installs packages into it."
+                )
+            });
+            for tool in &mut tools {
+                if let Some(serde_json::Value::Object(properties)) =
+                    Arc::make_mut(&mut tool.input_schema).get_mut("properties")
+                {
+                    properties.insert("root".to_string(), root_schema.clone());
+                }
+            }
+        }
+        let verbose_schema = serde_json::json!({
+            "type": "boolean",
+            "default": false,
+            "description": "Optional: Include the package manager's command line and output in the result, \
+                e.g. to read post-install script messages and warnings. Long output is cut to its end."
+        });
+        for tool in &mut tools {
+            if tool.name != "install_package" && tool.name != "install_package_with_version" {
+                continue;
+            }
+            if let Some(serde_json::Value::Object(properties)) =
+                Arc::make_mut(&mut tool.input_schema).get_mut("properties")
+            {
+                properties.insert("verbose".to_string(), verbose_schema.clone());
+            }
+        }
+        if self.idempotency.is_some() {
+            let idempotency_schema = serde_json::json!({
+                "type": "string",
+                "description": "Optional: Unique key of this call, e.g. a UUID. Retrying the call with the same key returns \
+                    the result of the first successful call instead of running the package manager again."
+            });
+            for tool in &mut tools {
+                if !Operation::is_mutating_tool(&tool.name) {
+                    continue;
+                }
+                if let Some(serde_json::Value::Object(properties)) =
+                    Arc::make_mut(&mut tool.input_schema).get_mut("properties")
+                {
+                    properties.insert(
+                        idempotency::IDEMPOTENCY_KEY_ARGUMENT.to_string(),
+                        idempotency_schema.clone(),
+                    );
+                }
+            }
+        }
+        if let Some(gate) = &self.vulnerability_gate
+            && gate.allows_override()
+        {
+            let override_schema = serde_json::json!({
+                "type": "boolean",
+                "description": format!(
+                    "Optional: Install even though the version has known {} or more severe vulnerabilities. \
+                    Only set this after the user accepted the risk of the advisories returned by a blocked install.",
+                    gate.min_severity()
+                )
+            });
+            for tool in &mut tools {
+                if tool.name != "install_package" && tool.name != "install_package_with_version" {
+                    continue;
+                }
+                if let Some(serde_json::Value::Object(properties)) =
+                    Arc::make_mut(&mut tool.input_schema).get_mut("properties")
+                {
+                    properties.insert(
+                        "override_vulnerabilities".to_string(),
+                        override_schema.clone(),
+                    );
+                }
+            }
+        }
+        for (parameter, supported) in [
+            ("repository", capabilities.custom_repositories),
+            ("repositories", capabilities.multiple_repositories),
+            ("only_repositories", capabilities.repository_override),
+            ("arch", capabilities.architecture_selection),
+            ("preseed", capabilities.debconf_preseed),
+            ("install_recommends", capabilities.recommends_selection),
+            ("target_release", capabilities.target_release_selection),
+            (
+                "no_scripts",
+                capabilities.install_flags && self.allow_no_scripts,
+            ),
+            ("no_cache", capabilities.install_flags),
+            ("update_cache", capabilities.install_flags),
+        ] {
+            if supported {
+                continue;
+            }
+            for tool in &mut tools {
+                if let Some(serde_json::Value::Object(properties)) =
+                    Arc::make_mut(&mut tool.input_schema).get_mut("properties")
+                {
+                    properties.remove(parameter);
+                }
+            }
+        }
+        if !capabilities.ansible_tasks {
+            for tool in &mut tools {
+                if tool.name != "export_session" {
+                    continue;
+                }
+                if let Some(serde_json::Value::Array(formats)) =
+                    Arc::make_mut(&mut tool.input_schema)
+                        .get_mut("properties")
+                        .and_then(|properties| properties.get_mut("format"))
+                        .and_then(|format| format.get_mut("enum"))
+                {
+                    formats.retain(|format| format != "ansible");
+                }
+            }
+        }
+        if !capabilities.batch_version_install {
+            for tool in &mut tools {
+                if tool.name != "install_package_with_version" {
+                    continue;
+                }
+                let schema = Arc::make_mut(&mut tool.input_schema);
+                if let Some(serde_json::Value::Object(properties)) = schema.get_mut("properties") {
+                    properties.remove("packages");
+                }
+                schema.insert(
+                    "required".to_string(),
+                    serde_json::json!(["package_name", "version"]),
+                );
+            }
+        }
+
+        let mut notes = Vec::new();
+        if !self.repository_allowlist.is_empty() {
+            notes.push(format!(
+                " Only repositories below these approved URLs or on these approved domains are accepted: {}.",
+                self.repository_allowlist.entries().join(", ")
+            ));
+        }
+        if let Some(offline) = &self.offline {
+            notes.push(format!(
+                " The server is offline: only repositories on its internal mirrors are reachable: {}.",
+                offline.internal_urls().collect::<Vec<_>>().join(", ")
+            ));
+        }
+        if !notes.is_empty() {
+            let note = notes.concat();
+            for tool in &mut tools {
+                if let Some(serde_json::Value::String(description)) =
+                    Arc::make_mut(&mut tool.input_schema)
+                        .get_mut("properties")
+                        .and_then(|properties| properties.get_mut("repository"))
+                        .and_then(|repository| repository.get_mut("description"))
+                {
+                    description.push_str(&note);
+                }
+            }
+        }
+
+        // Added last: the parameters stripped above depend on the server's
+        // backend, not on the targets
+        if !self.targets.is_empty() {
+            tools.push(Tool {
+                name: "install_package_on_targets".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Install the same package on several execution targets (containers or hosts) in parallel, e.g. to provision identical toolchains across \
+                    a fleet of sandboxes. Returns one result per target with the installed version or the error, so a failure on one target does not hide \
+                    the others. Configured targets: {}.",
+                    self.target_names().join(", ")
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "package_name": {
+                                "type": "string",
+                                "description": "The exact name of the package to install on every target (e.g., 'curl', 'python3', 'git')."
+                            },
+                            "version": {
+                                "type": "string",
+                                "description": "Optional: Version to install, as an exact version or a constraint such as '>=1.2'. \
+                                The latest version is installed when not provided."
+                            },
+                            "arch": {
+                                "type": "string",
+                                "description": "Optional: Architecture to install the package for when it differs from the targets' own."
+                            },
+                            "targets": {
+                                "type": "array",
+                                "items": {
+                                    "type": "string",
+                                    "enum": self.target_names()
+                                },
+                                "description": "Optional: Names of the targets to install on. Every configured target is used when not provided."
+                            }
+                        },
+                        "required": ["package_name"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse install_package_on_targets schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    idempotent_hint: Some(true),
+                    open_world_hint: Some(true),
+                    ..Default::default()
+                }),
+            });
+        }
+        if self.jobs.is_some() && capabilities.mutating {
+            let async_schema = serde_json::json!({
+                "type": "boolean",
+                "default": false,
+                "description": "Optional: Start the call as a background job and return its job_id at once instead of waiting for it. \
+                    Use it for large installs that may outlast the client's timeout, then follow the job with get_job_status."
+            });
+            for tool in &mut tools {
+                if !Operation::is_mutating_tool(&tool.name) {
+                    continue;
+                }
+                if let Some(serde_json::Value::Object(properties)) =
+                    Arc::make_mut(&mut tool.input_schema).get_mut("properties")
+                {
+                    properties.insert(jobs::ASYNC_ARGUMENT.to_string(), async_schema.clone());
+                }
+            }
+            let job_id_schema = serde_json::json!({
+                "type": "string",
+                "description": "Id of the job, as returned by a call with async"
+            });
+            tools.push(Tool {
+                name: "get_job_status".into(),
+                description: Some(std::borrow::Cow::Borrowed(
+                    "Get the status of a background job started with async: running, succeeded, failed or cancelled. \
+                    Once the job succeeded, the result of the call is returned after the status; once it failed, the status includes the error.",
+                )),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "job_id": job_id_schema
+                        },
+                        "required": ["job_id"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse get_job_status schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    ..Default::default()
+                }),
+            });
+            tools.push(Tool {
+                name: "get_job_output".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Read the output the package manager printed for a background job, from a line offset. \
+                    Returns at most {} lines and the next_offset to pass to read the lines that follow, also while the job runs.",
+                    jobs::MAX_OUTPUT_PAGE
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "job_id": job_id_schema,
+                            "offset": {
+                                "type": "integer",
+                                "minimum": 0,
+                                "description": "Optional: Line to start from, the next_offset of the previous call. Defaults to the first line."
+                            },
+                            "limit": {
+                                "type": "integer",
+                                "minimum": 1,
+                                "maximum": jobs::MAX_OUTPUT_PAGE,
+                                "description": "Optional: Most lines to return."
+                            }
+                        },
+                        "required": ["job_id"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse get_job_output schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    ..Default::default()
+                }),
+            });
+            tools.push(Tool {
+                name: "cancel_job".into(),
+                description: Some(std::borrow::Cow::Borrowed(
+                    "Cancel a background job still queued behind the earlier jobs of its session, so it never runs. \
+                    Running jobs cannot be cancelled; finished jobs are left as they are.",
+                )),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "job_id": job_id_schema
+                        },
+                        "required": ["job_id"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse cancel_job schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    destructive_hint: Some(false),
+                    idempotent_hint: Some(true),
+                    ..Default::default()
+                }),
+            });
+        }
+        if self.operation_logs.is_some() && capabilities.mutating {
+            tools.push(Tool {
+                name: "get_operation_log".into(),
+                description: Some(std::borrow::Cow::Owned(format!(
+                    "Read every line the package manager printed during an install or repository refresh, from a line offset, \
+                    also after the session that made it ended. Mutating calls return the operation_id of their log with their result or error. \
+                    Returns at most {} lines and the next_offset to pass to read the lines that follow.",
+                    operation_log::MAX_LOG_PAGE
+                ))),
+                input_schema: Arc::new(
+                    serde_json::from_value(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "operation_id": {
+                                "type": "string",
+                                "description": "Id of the operation, as returned with its result or error"
+                            },
+                            "offset": {
+                                "type": "integer",
+                                "minimum": 0,
+                                "description": "Optional: Line to start from, the next_offset of the previous call. Defaults to the first line."
+                            },
+                            "limit": {
+                                "type": "integer",
+                                "minimum": 1,
+                                "maximum": operation_log::MAX_LOG_PAGE,
+                                "description": "Optional: Most lines to return."
+                            }
+                        },
+                        "required": ["operation_id"]
+                    })).map_err(|e| McpError::internal_error(format!("failed to parse get_operation_log schema: {e}"), None))?,
+                ),
+                annotations: Some(ToolAnnotations {
+                    read_only_hint: Some(true),
+                    ..Default::default()
+                }),
+            });
+        }
+        for tool in &mut tools {
+            if let Some(description) = &tool.description {
+                tool.description = Some(
+                    self.descriptions
+                        .apply_to_tool(&tool.name, description)
+                        .into(),
+                );
+            }
+            tool.name = self.tool_names.exposed(&tool.name).into();
+        }
+
+        Ok(ListToolsResult {
+            tools,
+            next_cursor: None,
+        })
+    }
+
+    /// Result of a call of a tool the server does not have
+    fn unknown_tool(&self, name: &str) -> CallToolResult {
+        let available: Vec<String> = Operation::TOOL_NAMES
+            .iter()
+            .map(|name| self.tool_names.exposed(name))
+            .collect();
+        CallToolResult::error(vec![Content::text(format!(
+            "Unknown tool '{name}'. Available tools: {}",
+            available.join(", ")
+        ))])
+    }
+
+    /// Run a tool call, or replay the stored result of its idempotency key
+    async fn dispatch_tool_call(
+        &self,
+        request: CallToolRequestParam,
+        client: Option<ClientIdentity>,
+        session: Option<String>,
+    ) -> Result<CallToolResult, McpError> {
+        let slot = match &self.idempotency {
+            Some(store) if Operation::is_mutating_tool(&request.name) => store
+                .slot(
+                    &request,
+                    client.as_ref().map(|client| client.subject.as_str()),
+                    session.as_deref(),
+                )
+                .map_err(McpError::from)?,
+            _ => None,
+        };
+        let Some(slot) = slot else {
+            return self.run_tool_call(request, client, session).await;
+        };
+        let mut failure = None;
+        let result = slot
+            .get_or_try_init(|| async {
+                let result = self.run_tool_call(request, client, session).await;
+                match result {
+                    Ok(result) if result.is_error != Some(true) => Ok(result),
+                    result => {
+                        failure = Some(result);
+                        Err(())
+                    }
+                }
+            })
+            .await;
+        match (result, failure) {
+            (Ok(result), _) => Ok(result.clone()),
+            (Err(()), Some(failure)) => failure,
+            (Err(()), None) => unreachable!("failed calls return their result"),
+        }
+    }
+
+    async fn run_tool_call(
+        &self,
+        request: CallToolRequestParam,
+        client: Option<ClientIdentity>,
+        session: Option<String>,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(mut operation) = Operation::from_request(&request)? else {
+            return Ok(self.unknown_tool(&self.tool_names.exposed(&request.name)));
+        };
+        let output_format = OutputFormat::from_request(&request)?;
+
+        let root = request
+            .arguments
+            .as_ref()
+            .and_then(|arguments| arguments.get("root"))
+            .and_then(|root| root.as_str());
+        let override_vulnerabilities = request
+            .arguments
+            .as_ref()
+            .and_then(|arguments| arguments.get("override_vulnerabilities"))
+            .and_then(|flag| flag.as_bool())
+            .unwrap_or_default();
+        let verbose = request
+            .arguments
+            .as_ref()
+            .and_then(|arguments| arguments.get("verbose"))
+            .and_then(|flag| flag.as_bool())
+            .unwrap_or_default();
+
+        // Repositories on the allowlist are approved, not untrusted
+        let allowlist = self.repository_allowlist(client.as_ref());
+        let untrusted_sources: Vec<String> = operation
+            .untrusted_sources()
+            .into_iter()
+            .filter(|source| allowlist.is_empty() || !operation.repositories().contains(source))
+            .map(str::to_string)
+            .collect();
+        let (result, rejected) = match self
+            .check_operation(
+                &operation,
+                &untrusted_sources,
+                root,
+                client.as_ref(),
+                override_vulnerabilities,
+            )
+            .await
+        {
+            // The quota reservation lasts until the operation ends
+            Ok(_reservation) => (
+                self.run_logged_operation(
+                    &mut operation,
+                    root,
+                    client.as_ref(),
+                    session.as_deref(),
+                    verbose,
+                )
+                .await,
+                false,
+            ),
+            Err(err) => {
+                match &client {
+                    Some(client) => {
+                        tracing::warn!("Rejected {} for {client}: {err}", operation.tool_name())
+                    }
+                    None => tracing::warn!("Rejected {}: {err}", operation.tool_name()),
+                }
+                (Err(err.into()), true)
+            }
+        };
+
+        let (outcome, error) = match &result {
+            Err(err) if rejected => (AuditOutcome::Rejected, Some(err.message.to_string())),
+            Ok(result) if result.is_error != Some(true) => (AuditOutcome::Succeeded, None),
+            Ok(_) => (AuditOutcome::Failed, None),
+            Err(err) => (AuditOutcome::Failed, Some(err.message.to_string())),
+        };
+        if !self.notification_sinks.is_empty() {
+            let content = result.as_ref().ok().and_then(|result| {
+                result
+                    .content
+                    .iter()
+                    .filter_map(|content| content.as_text())
+                    .find_map(|text| serde_json::from_str(&text.text).ok())
+            });
+            if let Some(mut notification) = notifications::Notification::new(
+                &operation,
+                outcome,
+                content.as_ref(),
+                error.clone(),
+            ) {
+                notification.session = session;
+                notification.initiator = client.clone();
+                let sinks = self.notification_sinks.clone();
+                tokio::spawn(async move {
+                    for sink in &sinks {
+                        sink.deliver(&notification).await;
+                    }
+                });
+            }
+        }
+
+        if let Some(audit) = &self.audit {
+            audit
+                .record(&AuditEntry {
+                    timestamp: chrono::Utc::now(),
+                    tool: operation.tool_name(),
+                    root: root.map(str::to_string),
+                    client,
+                    untrusted_source: untrusted_sources.first().cloned(),
+                    untrusted_sources,
+                    override_vulnerabilities,
+                    outcome,
+                    error,
+                    operation,
+                })
+                .await;
+        }
+
+        result.map(|result| output_format.apply(result))
+    }
+
+    /// Run the operation, keeping the output of mutating ones in the
+    /// operation logs and returning the id of their log
+    async fn run_logged_operation(
+        &self,
+        operation: &mut Operation,
+        root: Option<&str>,
+        client: Option<&ClientIdentity>,
+        session: Option<&str>,
+        verbose: bool,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(logs) = self
+            .operation_logs
+            .as_ref()
+            .filter(|_| operation.is_mutating())
+        else {
+            return self
+                .run_operation(operation, root, client, session, verbose)
+                .await;
+        };
+        let tool = operation.tool_name();
+        let operation_id = logs.new_id();
+        let started_at = chrono::Utc::now();
+        let (result, dropped_lines, lines) =
+            operation_log::capture(self.run_operation(operation, root, client, session, verbose))
+                .await;
+        let (outcome, error) = match &result {
+            Ok(result) if result.is_error != Some(true) => (AuditOutcome::Succeeded, None),
+            Ok(_) => (AuditOutcome::Failed, None),
+            Err(err) => (AuditOutcome::Failed, Some(err.message.to_string())),
+        };
+        logs.write(&operation_log::OperationLog {
+            operation_id: operation_id.clone(),
+            tool: tool.to_string(),
+            client: client.map(|client| client.subject.clone()),
+            session: session.map(str::to_string),
+            started_at,
+            finished_at: chrono::Utc::now(),
+            outcome,
+            error,
+            dropped_lines,
+            lines,
+        })
+        .await;
+
+        match result {
+            Ok(mut result) => {
+                result.content.push(Content::text(format!(
+                    "The complete output is kept as operation log '{operation_id}', read it with get_operation_log."
+                )));
+                Ok(result)
+            }
+            Err(mut err) => {
+                match &mut err.data {
+                    Some(serde_json::Value::Object(data)) => {
+                        data.insert("operation_id".to_string(), operation_id.into());
+                    }
+                    data @ None => {
+                        *data = Some(serde_json::json!({ "operation_id": operation_id }));
+                    }
+                    Some(_) => {}
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Run every check an operation must pass before it runs, the approval
+    /// webhook last, returning the quota usage reserved for it
+    async fn check_operation(
+        &self,
+        operation: &Operation,
+        untrusted_sources: &[String],
+        root: Option<&str>,
+        client: Option<&ClientIdentity>,
+        override_vulnerabilities: bool,
+    ) -> Result<Option<QuotaReservation>, PackageManagerError> {
+        self.check_policy(operation, untrusted_sources, client)?;
+        self.check_package_pins(operation)?;
+        let reservation = self.check_quota(operation)?;
+        self.check_signed_repositories(operation, root).await?;
+        self.check_vulnerability_gate(operation, root, override_vulnerabilities)
+            .await?;
+        self.check_approval(operation, root, client).await?;
+        Ok(reservation)
+    }
+
+    /// Reject installs of a version with vulnerabilities the gate blocks,
+    /// unless the call overrides the gate and the gate allows it
+    async fn check_vulnerability_gate(
+        &self,
+        operation: &Operation,
+        root: Option<&str>,
+        override_vulnerabilities: bool,
+    ) -> Result<(), PackageManagerError> {
+        let Some(gate) = &self.vulnerability_gate else {
+            return Ok(());
+        };
+        let exact = |package: &String, arch: &Option<String>| SearchOptions {
+            query: package.clone(),
+            repository: None,
+            repositories: Vec::new(),
+            only_repositories: Vec::new(),
+            arch: arch.clone(),
+            mode: SearchMode::Exact,
+        };
+        // Versions to install, with the search listing the candidates
+        let installs = match operation {
+            // Package files are not in the repositories the database covers
+            Operation::Install(options)
+                if operation.untrusted_source() == Some(options.package.as_str()) =>
+            {
+                return Ok(());
+            }
+            Operation::Install(options) => {
+                vec![(
+                    None,
+                    SearchOptions {
+                        repository: options.repository.clone(),
+                        repositories: options.repositories.clone(),
+                        only_repositories: options.only_repositories.clone(),
+                        ..exact(&options.package, &options.arch)
+                    },
+                )]
+            }
+            Operation::InstallVersion(options) => vec![(
+                Some(options.version.as_str()),
+                exact(&options.package, &options.arch),
+            )],
+            Operation::InstallVersions { packages } => packages
+                .iter()
+                .map(|options| {
+                    (
+                        Some(options.version.as_str()),
+                        exact(&options.package, &options.arch),
+                    )
+                })
+                .collect(),
+            Operation::InstallOnTargets(options) => vec![(
+                options.version.as_deref(),
+                exact(&options.package, &options.arch),
+            )],
+            Operation::InstallFromLockfile(lockfile) => lockfile
+                .packages
+                .iter()
+                .map(|locked| {
+                    let package = &locked.package;
+                    (
+                        Some(package.version.as_str()),
+                        SearchOptions {
+                            repository: package.repository.clone(),
+                            ..exact(&package.name, &package.arch)
+                        },
+                    )
+                })
+                .collect(),
+            // Listed rather than matched with `_`, so new operations that
+            // install packages are not let through
+            Operation::ResolveVersion(_)
+            | Operation::Search { .. }
+            | Operation::ListInstalled(_)
+            | Operation::RefreshRepositories
+            | Operation::ListRepositories
+            | Operation::ListSearchRepositories
+            | Operation::CheckPrivileges
+            | Operation::ServerInfo
+            | Operation::Doctor
+            | Operation::ExportSession { .. }
+            | Operation::ExportDockerfileSnippet
+            | Operation::ExportCloudInit
+            | Operation::ExportApkoConfig { .. }
+            | Operation::CheckVulnerabilities { .. }
+            | Operation::SearchAllDistros { .. }
+            | Operation::LicenseReport { .. }
+            | Operation::GenerateLockfile
+            | Operation::ExportAuditLog { .. }
+            | Operation::ExportHistory { .. }
+            | Operation::GetJobStatus { .. }
+            | Operation::GetJobOutput { .. }
+            | Operation::CancelJob { .. }
+            | Operation::GetOperationLog { .. } => return Ok(()),
+        };
+        // Lockfiles pin the exact versions to install
+        let pinned = matches!(operation, Operation::InstallFromLockfile(_));
+        if override_vulnerabilities && gate.allows_override() {
+            for (_, search) in &installs {
+                tracing::warn!(
+                    "Installing {} without checking its vulnerabilities",
+                    search.query
+                );
+            }
+            return Ok(());
+        }
+        let Some(osv) = &self.osv else {
+            return Err(PackageManagerError::PermissionDenied {
+                message:
+                    "the vulnerability gate has no vulnerability database to check installs against"
+                        .to_string(),
+            });
+        };
+        // The backends the operation installs with; a root the gate cannot
+        // check is rejected rather than let through
+        let backends: Vec<Arc<dyn PackageManager>> = match (operation, root) {
+            (Operation::InstallOnTargets(options), _) => self
+                .select_targets(&options.targets)?
+                .into_iter()
+                .map(|(_, backend)| backend)
+                .collect(),
+            (_, Some(root)) => vec![self.backend.with_root(root)?],
+            (_, None) => vec![self.backend.clone()],
+        };
+
+        for backend in backends {
+            let scheme = backend.version_scheme();
+            validation::validate_operation(operation, scheme)?;
+            let ecosystem = backend.osv_ecosystem().await?;
+            let sources = backend.source_packages().await?;
+
+            for (version, search) in &installs {
+                let search = match scheme {
+                    Some(VersionScheme::Apk) => untagged_search(search.clone()),
+                    _ => search.clone(),
+                };
+                let package = &search.query;
+                let candidate = match version {
+                    Some(version) if pinned => Some(version.to_string()),
+                    _ => {
+                        let versions = available_versions(backend.as_ref(), &search).await?;
+                        candidate_version(scheme, &versions, *version).map(str::to_string)
+                    }
+                };
+                // Missing packages and versions are reported by the install
+                let Some(candidate) = candidate else {
+                    continue;
+                };
+
+                let source = sources
+                    .get(package)
+                    .cloned()
+                    .unwrap_or_else(|| package.clone());
+                let vulnerabilities = osv
+                    .vulnerabilities(&ecosystem, &[(source, candidate.clone())])
+                    .await
+                    .map_err(|err| PackageManagerError::NetworkFailure {
+                        message: format!(
+                            "the OSV vulnerability database could not be queried: {err}"
+                        ),
+                    })?
+                    .into_iter()
+                    .next()
+                    .unwrap_or_default();
+                let blocking = gate.blocking(vulnerabilities);
+                if !blocking.is_empty() {
+                    return Err(PackageManagerError::VulnerablePackage {
+                        package: package.clone(),
+                        version: candidate,
+                        vulnerabilities: blocking,
+                        overridable: gate.allows_override(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject mutating operations the approval webhook does not approve
+    async fn check_approval(
+        &self,
+        operation: &Operation,
+        root: Option<&str>,
+        client: Option<&ClientIdentity>,
+    ) -> Result<(), PackageManagerError> {
+        let Some(approval) = &self.approval else {
+            return Ok(());
+        };
+        if !operation.is_mutating() {
+            return Ok(());
+        }
+        approval
+            .approve(&approval::ApprovalRequest {
+                tool: operation.tool_name(),
+                operation,
+                root,
+                client,
+                requested_at: chrono::Utc::now(),
+            })
+            .await
+    }
+
+    /// Reject operations the server's policy does not allow: repositories
+    /// off the client's allowlist and untrusted sources, unless allowed
+    fn check_policy(
+        &self,
+        operation: &Operation,
+        untrusted_sources: &[String],
+        client: Option<&ClientIdentity>,
+    ) -> Result<(), PackageManagerError> {
+        for repository in operation.repositories() {
+            self.repository_allowlist(client).check(repository)?;
+            if let Some(offline) = &self.offline {
+                offline.check(repository)?;
+            }
+        }
+        if !untrusted_sources.is_empty() {
+            let source = untrusted_sources.join(", ");
+            if !self.allow_untrusted_sources {
+                return Err(PackageManagerError::PermissionDenied {
+                    message: format!(
+                        "{source} is not a configured repository and the server does not allow untrusted sources"
+                    ),
+                });
+            }
+            tracing::info!(
+                "Running {} from untrusted source {source}",
+                operation.tool_name()
+            );
+        }
+        if operation.install_flags().no_scripts && !self.allow_no_scripts {
+            return Err(PackageManagerError::PermissionDenied {
+                message: "the server does not allow installs skipping maintainer scripts"
+                    .to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Reject installs of pinned packages that cannot verify their artifact
+    /// before installing it
+    fn check_package_pins(&self, operation: &Operation) -> Result<(), PackageManagerError> {
+        if self.package_pins.is_empty() {
+            return Ok(());
+        }
+        let packages: Vec<&str> = match operation {
+            // The artifact is fetched from the call's repository, not from
+            // the repository an APK tag selects
+            Operation::Install(options) => {
+                let (name, tag) = parse::apk::split_package_tag(&options.package);
+                if !self.package_pins.is_pinned(name) {
+                    return Ok(());
+                }
+                if tag.is_some() {
+                    return Err(PackageManagerError::PermissionDenied {
+                        message: format!(
+                            "{name} is pinned, so it must be installed without a repository tag for its artifact to be verified"
+                        ),
+                    });
+                }
+                let mut parameters = Vec::new();
+                if !options.repositories.is_empty() {
+                    parameters.push("repositories");
+                }
+                if !options.only_repositories.is_empty() {
+                    parameters.push("only_repositories");
+                }
+                return check_pinned_options(
+                    name,
+                    parameters,
+                    options.install_recommends,
+                    options.target_release.as_deref(),
+                    options.flags,
+                );
+            }
+            Operation::InstallVersion(options) => {
+                if !self.package_pins.is_pinned(&options.package) {
+                    return Ok(());
+                }
+                return check_pinned_options(
+                    &options.package,
+                    Vec::new(),
+                    options.install_recommends,
+                    options.target_release.as_deref(),
+                    options.flags,
+                );
+            }
+            Operation::InstallOnTargets(options) => vec![options.package.as_str()],
+            Operation::InstallVersions { packages } => packages
+                .iter()
+                .map(|options| options.package.as_str())
+                .collect(),
+            Operation::InstallFromLockfile(lockfile) => lockfile
+                .packages
+                .iter()
+                .map(|locked| locked.package.name.as_str())
+                .collect(),
+            _ => return Ok(()),
+        };
+        let pinned: Vec<&str> = packages
+            .into_iter()
+            .filter(|package| self.package_pins.is_pinned(package))
+            .collect();
+        if pinned.is_empty() {
+            return Ok(());
+        }
+        Err(PackageManagerError::PermissionDenied {
+            message: format!(
+                "{} must be installed with install_package or install_package_with_version, which verify the artifact of pinned packages",
+                pinned.join(", ")
+            ),
+        })
+    }
+
+    /// Install a pinned package from its downloaded file, once the file
+    /// matches one of the package's pins, setting the debconf `preseed`
+    /// selections in between
+    async fn install_pinned(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        package: &str,
+        version: Option<&str>,
+        repository: Option<String>,
+        arch: Option<String>,
+        preseed: &[String],
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        if !backend.capabilities().artifact_verification {
+            return Err(PackageManagerError::PermissionDenied {
+                message: format!(
+                    "{package} is pinned, but the {} backend cannot download it to verify it before installing it",
+                    backend.name()
+                ),
+            });
+        }
+        let scheme = backend.version_scheme();
+        let search = SearchOptions {
+            query: package.to_string(),
+            repository: repository.clone(),
+            repositories: Vec::new(),
+            only_repositories: Vec::new(),
+            arch: arch.clone(),
+            mode: SearchMode::Exact,
+        };
+        let mut versions = available_versions(backend.as_ref(), &search).await?;
+        let Some(candidate) = candidate_version(scheme, &versions, version).map(str::to_string)
+        else {
+            return Err(match version {
+                Some(version) => {
+                    if let Some(scheme) = scheme {
+                        scheme.sort(&mut versions);
+                    }
+                    PackageManagerError::VersionNotFound {
+                        package: package.to_string(),
+                        version: version.to_string(),
+                        available_versions: versions,
+                    }
+                }
+                None => PackageManagerError::NotFound {
+                    package: package.to_string(),
+                    searched_repositories: repository.into_iter().collect(),
+                },
+            });
+        };
+
+        let fetched = backend
+            .fetch_package(&PinnedPackage {
+                name: package.to_string(),
+                version: candidate,
+                arch,
+                repository,
+                repositories: Vec::new(),
+            })
+            .await?;
+        let installation = match self.package_pins.verify(&fetched) {
+            Ok(()) => match set_selections(backend.as_ref(), preseed).await {
+                Ok(()) => backend.install_fetched(&fetched).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        if let Err(err) = backend.discard_fetched(&fetched).await {
+            tracing::warn!(
+                "Failed to remove the downloaded file {}: {err}",
+                fetched.path
+            );
+        }
+        installation
+    }
+
+    /// Reject installs once the session used up one of its quotas, and
+    /// reserve the usage of the others until they end
+    fn check_quota(
+        &self,
+        operation: &Operation,
+    ) -> Result<Option<QuotaReservation>, PackageManagerError> {
+        let Some(quota) = &self.quota else {
+            return Ok(None);
+        };
+        let installs = match operation {
+            Operation::Install(_) | Operation::InstallVersion(_) => 1,
+            Operation::InstallVersions { packages } => packages.len(),
+            Operation::InstallOnTargets(options) => self.select_targets(&options.targets)?.len(),
+            Operation::InstallFromLockfile(lockfile) => lockfile.packages.len(),
+            _ => return Ok(None),
+        };
+        quota.reserve(installs as u64).map(Some)
+    }
+
+    /// Add the changes of an install made by `backend` to the session's
+    /// delta if it targets the server's system
+    fn track_changes(&self, backend: &Arc<dyn PackageManager>, outcome: &InstallOutcome) {
+        if Arc::ptr_eq(backend, &self.backend) {
+            self.delta
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .record(outcome);
+        }
+    }
+
+    /// Charge a successful install to the session's quotas
+    fn charge_quota(&self, outcome: &InstallOutcome) {
+        if let Some(quota) = &self.quota {
+            quota.charge(outcome);
+        }
+    }
+
+    /// Reject operations using repositories while one of them is unsigned,
+    /// in signed-repositories-only mode
+    async fn check_signed_repositories(
+        &self,
+        operation: &Operation,
+        root: Option<&str>,
+    ) -> Result<(), PackageManagerError> {
+        if !self.signed_repositories_only || !operation.uses_repositories() {
+            return Ok(());
+        }
+        let backends = match (operation, root) {
+            (Operation::InstallOnTargets(options), _) => self
+                .select_targets(&options.targets)?
+                .into_iter()
+                .map(|(_, backend)| backend)
+                .collect(),
+            // Unsupported roots are reported when the operation runs
+            (_, Some(root)) => self.backend.with_root(root).into_iter().collect(),
+            (_, None) => vec![self.backend.clone()],
+        };
+        let mut repositories: Vec<Option<&str>> =
+            operation.repositories().into_iter().map(Some).collect();
+        if repositories.is_empty() {
+            repositories.push(None);
+        }
+
+        let mut problems = Vec::new();
+        for backend in backends {
+            for repository in &repositories {
+                for problem in backend.unsigned_repositories(*repository).await? {
+                    if !problems.contains(&problem) {
+                        problems.push(problem);
+                    }
+                }
+            }
+        }
+        signing::require_signed(&problems)
+    }
+
+    /// Check the operation against the capabilities and run it with the hooks
+    ///
+    /// Successful installs include the command line and output of the
+    /// package manager when `verbose`.
+    async fn run_operation(
+        &self,
+        operation: &mut Operation,
+        root: Option<&str>,
+        client: Option<&ClientIdentity>,
+        session: Option<&str>,
+        verbose: bool,
+    ) -> Result<CallToolResult, McpError> {
+        validation::validate_operation(operation, self.backend.version_scheme())?;
+
+        let mut capabilities = self.backend.capabilities();
+        if self.session.is_none() {
+            capabilities.install_commands = false;
+            capabilities.lockfile = false;
+            capabilities.cloud_init = false;
+        }
+        if self.osv.is_none() {
+            capabilities.vulnerability_check = false;
+        }
+        capabilities.audit_log = self.audit.is_some();
+        capabilities.cross_distro_search = self.repology.is_some();
+        if let Some(arch) = &self.default_arch
+            && capabilities.architecture_selection
+        {
+            operation.set_default_arch(arch);
+        }
+        if let Some(size) = self.page_size {
+            operation.set_default_page_size(size);
+        }
+        if let Operation::InstallOnTargets(options) = &operation {
+            // Each target is checked against its own backend's capabilities
+            if root.is_some() {
+                return Err(PackageManagerError::Unsupported { operation: "root" }.into());
+            }
+            self.select_targets(&options.targets)?;
+        } else {
+            // The privilege check only covers the server's own root
+            if root.is_none()
+                && operation.is_mutating()
+                && let Some(problems) = self.mutation_problems()
+            {
+                return Err(PackageManagerError::PermissionDenied {
+                    message: format!(
+                        "the server cannot modify the system: {}",
+                        problems.join("; ")
+                    ),
+                }
+                .into());
+            }
+            capabilities.check(operation)?;
+        }
+        let backend = match root {
+            Some(_) if !capabilities.alternate_root => {
+                return Err(PackageManagerError::Unsupported { operation: "root" }.into());
+            }
+            Some(root) => self.backend.with_root(root)?,
+            None => self.backend.clone(),
+        };
+        if self.disk_space_check {
+            check_disk_space(&backend, operation).await?;
+        }
+
+        for hook in &self.hooks {
+            hook.before_call(operation, client).await?;
+        }
+
+        // Installs on remote targets are coordinated by the target backends,
+        // and batched installs take the lock when their batch runs: holding
+        // it here would keep the batcher from coalescing them
+        let _mutating = match &*operation {
+            Operation::InstallOnTargets(options) if !self.selects_local_target(options)? => None,
+            Operation::Install(options) if self.batcher_for(&backend, options).is_some() => None,
+            _ if operation.is_mutating() => Some(self.mutation_lock.lock().await),
+            _ => None,
+        };
+        let result = match &*operation {
+            // Jobs belong to the client, or to the session of anonymous calls
+            Operation::GetJobStatus { .. }
+            | Operation::GetJobOutput { .. }
+            | Operation::CancelJob { .. } => self.execute_job(
+                operation,
+                &idempotency::Owner::new(client.map(|client| client.subject.as_str()), session),
+            ),
+            _ => self
+                .execute_refreshing(
+                    &backend,
+                    root,
+                    operation,
+                    client,
+                    verbose,
+                    _mutating.is_some(),
+                )
+                .await
+                .map_err(|err| exit_status::explain(&*backend, err)),
+        };
+
+        if let Some(installed) = &self.installed
+            && operation.is_mutating()
+            && result
+                .as_ref()
+                .is_ok_and(|result| result.is_error != Some(true))
+        {
+            installed.clear();
+        }
+
+        for hook in &self.hooks {
+            hook.after(operation, &result).await;
+        }
+
+        result
+    }
+
+    /// Execute the operation, refreshing the repository indexes first when
+    /// they are stale, and again before one retry when it misses a package
+    ///
+    /// Refreshes rewrite the indexes installs read, so they take the
+    /// mutation lock unless the call holds it already (`locked`). The result
+    /// notes the refresh.
+    async fn execute_refreshing(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        root: Option<&str>,
+        operation: &Operation,
+        client: Option<&ClientIdentity>,
+        verbose: bool,
+        locked: bool,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(policy) = self.auto_refresh_for(backend, root, operation) else {
+            return self
+                .execute(backend, root, operation, client, verbose)
+                .await;
+        };
+        let started = std::time::SystemTime::now();
+        let mut refreshed = None;
+        if let Some(max_age) = policy.max_age() {
+            let _mutating = self.lock_unless(locked).await;
+            match self
+                .index_freshness
+                .refresh_if_stale(backend.as_ref(), root, max_age)
+                .await
+            {
+                Ok(reason) => {
+                    if reason.is_some() {
+                        self.audit_auto_refresh(root, client, None).await;
+                    }
+                    refreshed = reason;
+                }
+                Err(err) => {
+                    tracing::warn!("Could not refresh stale repository indexes: {err}");
+                    self.audit_auto_refresh(root, client, Some(&err)).await;
+                }
+            }
+        }
+
+        let mut result = self
+            .execute(backend, root, operation, client, verbose)
+            .await;
+        let search = matches!(operation, Operation::Search { .. });
+        if refreshed.is_none() && freshness::missing_package(&result, search) {
+            let mutating = self.lock_unless(locked).await;
+            let refresh = self
+                .index_freshness
+                .refresh_unless_since(backend.as_ref(), root, started, policy.min_interval())
+                .await;
+            drop(mutating);
+            match refresh {
+                Ok(freshness::MissRefresh::TooRecent) => {}
+                Ok(refresh) => {
+                    if refresh == freshness::MissRefresh::Refreshed {
+                        self.audit_auto_refresh(root, client, None).await;
+                    }
+                    refreshed = Some(freshness::RefreshReason::NotFound);
+                    result = self
+                        .execute(backend, root, operation, client, verbose)
+                        .await;
+                }
+                Err(err) => {
+                    tracing::warn!("Could not refresh the repository indexes: {err}");
+                    self.audit_auto_refresh(root, client, Some(&err)).await;
+                }
+            }
+        }
+
+        if let Some(reason) = refreshed {
+            tracing::info!("{} {}", operation.tool_name(), reason.note());
+            match &mut result {
+                Ok(result) => result.content.push(Content::text(reason.note())),
+                Err(err) => {
+                    if let Some(data) = err.data.as_mut().and_then(|data| data.as_object_mut()) {
+                        data.insert("index_refresh".to_string(), serde_json::json!(reason));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// The mutation lock, unless the caller holds it already
+    async fn lock_unless(&self, locked: bool) -> Option<tokio::sync::MutexGuard<'_, ()>> {
+        if locked {
+            None
+        } else {
+            Some(self.mutation_lock.lock().await)
+        }
+    }
+
+    /// Record a refresh the handler made on behalf of `client` in the audit
+    /// log, like a call of `refresh_repositories`
+    async fn audit_auto_refresh(
+        &self,
+        root: Option<&str>,
+        client: Option<&ClientIdentity>,
+        error: Option<&PackageManagerError>,
+    ) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        audit
+            .record(&AuditEntry {
+                timestamp: chrono::Utc::now(),
+                tool: Operation::RefreshRepositories.tool_name(),
+                operation: Operation::RefreshRepositories,
+                root: root.map(str::to_string),
+                client: client.cloned(),
+                untrusted_source: None,
+                untrusted_sources: Vec::new(),
+                override_vulnerabilities: false,
+                outcome: match error {
+                    Some(_) => AuditOutcome::Failed,
+                    None => AuditOutcome::Succeeded,
+                },
+                error: error.map(ToString::to_string),
+            })
+            .await;
+    }
+
+    /// Auto-refresh policy applying to `operation` on `backend`, if any
+    fn auto_refresh_for(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        root: Option<&str>,
+        operation: &Operation,
+    ) -> Option<freshness::AutoRefresh> {
+        let policy = self.auto_refresh?;
+        let eligible = match operation {
+            // Custom repositories and package files have their own indexes
+            Operation::Install(options) => {
+                options.added_repositories().is_empty()
+                    && options.only_repositories.is_empty()
+                    && operation.untrusted_source().is_none()
+            }
+            Operation::InstallVersion(_) | Operation::InstallVersions { .. } => true,
+            // A refresh on behalf of a search would bypass the approval of
+            // mutating calls
+            Operation::Search { options, .. } => {
+                options.added_repositories().is_empty()
+                    && options.only_repositories.is_empty()
+                    && self.approval.is_none()
+            }
+            _ => false,
+        };
+        // Refreshing needs the privileges installs need
+        let can_refresh = backend.capabilities().mutating
+            && (root.is_some() || self.mutation_problems().is_none());
+        (eligible && can_refresh).then_some(policy)
+    }
+
+    /// Environment and configuration of the server, as seen by `client`
+    /// operating on `backend`
+    async fn server_report(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        root: Option<&str>,
+        client: Option<&ClientIdentity>,
+    ) -> diagnostics::ServerReport {
+        let privileges = match backend.capabilities().privilege_check {
+            true => backend.check_privileges().await.ok(),
+            false => None,
+        };
+        if let Some(report) = &privileges
+            && Arc::ptr_eq(backend, &self.backend)
+        {
+            self.set_privileges(report.clone());
+        }
+        let capabilities = self.capabilities();
+        let system = backend.system_info().await.unwrap_or_else(|err| {
+            tracing::warn!("Failed to describe the {} system: {err}", backend.name());
+            SystemInfo::default()
+        });
+        let repositories = match capabilities.repository_listing {
+            true => backend.list_repositories().await.ok(),
+            false => None,
+        };
+        let can_mutate = match &privileges {
+            Some(report) => report.can_mutate && backend.capabilities().mutating,
+            None => capabilities.mutating,
+        };
+        let gate = self.vulnerability_gate.as_ref();
+        diagnostics::ServerReport {
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            backend: backend.name().to_string(),
+            os: backend.os_name().to_string(),
+            system,
+            root: root.map(str::to_string),
+            default_arch: self.default_arch.clone(),
+            repositories,
+            privileges,
+            can_mutate,
+            capabilities,
+            policies: diagnostics::PolicyReport {
+                untrusted_sources: self.allow_untrusted_sources,
+                no_scripts: self.allow_no_scripts,
+                allowed_repositories: self.repository_allowlist(client).entries().to_vec(),
+                offline: self.offline.is_some(),
+                signed_repositories_only: self.signed_repositories_only,
+                blocked_vulnerabilities: gate.map(|gate| gate.min_severity()),
+                vulnerability_override: gate.is_some_and(|gate| gate.allows_override()),
+                approval_required: self.approval.is_some(),
+                package_pins: !self.package_pins.is_empty(),
+                quotas: self.quota.is_some(),
+                audit_log: self.audit.is_some(),
+                idempotency_keys: self.idempotency.is_some(),
+                auto_refresh: self.auto_refresh.is_some(),
+            },
+        }
+    }
+
+    /// Follow or cancel a background job of `owner`
+    fn execute_job(
+        &self,
+        operation: &Operation,
+        owner: &idempotency::Owner,
+    ) -> Result<CallToolResult, McpError> {
+        match operation {
+            Operation::GetJobStatus { job_id } => {
+                let (report, result) = self.job_store()?.status(job_id, owner)?;
+                let state = match (report.status, report.queue_position) {
+                    (jobs::JobStatus::Queued, Some(position)) => {
+                        format!("is queued behind {position} earlier jobs")
+                    }
+                    (jobs::JobStatus::Queued, None) => "is queued".to_string(),
+                    (jobs::JobStatus::Running, _) => "is running".to_string(),
+                    (status, _) => status.to_string(),
+                };
+                let mut content = vec![
+                    Content::text(format!("Job '{job_id}' ({}) {state}.", report.tool)),
+                    Content::json(&report)?,
+                ];
+                if let Some(result) = result {
+                    content.extend(result.content);
+                }
+                Ok(CallToolResult::success(content))
+            }
+            Operation::GetJobOutput {
+                job_id,
+                offset,
+                limit,
+            } => {
+                let limit = limit
+                    .unwrap_or(jobs::MAX_OUTPUT_PAGE)
+                    .min(jobs::MAX_OUTPUT_PAGE);
+                let output = self.job_store()?.output(job_id, owner, *offset, limit)?;
+                let heading = match output.lines.len() {
+                    0 => format!(
+                        "Job '{job_id}' ({}) has no output from line {}.",
+                        output.status, output.offset
+                    ),
+                    count => format!(
+                        "{count} lines of the output of job '{job_id}' ({}) from line {}:\n\n{}",
+                        output.status,
+                        output.offset,
+                        output.lines.join("\n")
+                    ),
+                };
+                Ok(CallToolResult::success(vec![
+                    Content::text(heading),
+                    Content::json(&output)?,
+                ]))
+            }
+            Operation::CancelJob { job_id } => {
+                let report = self.job_store()?.cancel(job_id, owner)?;
+                let message = match report.status {
+                    jobs::JobStatus::Cancelled => format!("Job '{job_id}' was cancelled."),
+                    status => format!("Job '{job_id}' already {status}."),
+                };
+                Ok(CallToolResult::success(vec![
+                    Content::text(message),
+                    Content::json(&report)?,
+                ]))
+            }
+            _ => unreachable!("execute_job only runs job operations"),
+        }
+    }
+
+    /// Whether an install on targets runs on a target of the server's own
+    /// host
+    fn selects_local_target(
+        &self,
+        options: &TargetInstallOptions,
+    ) -> Result<bool, PackageManagerError> {
+        Ok(self
+            .select_targets(&options.targets)?
+            .iter()
+            .any(|(name, _)| self.local_targets.contains(name)))
+    }
+
+    /// Store of the background jobs, unless they are disabled
+    fn job_store(&self) -> Result<&Arc<jobs::JobStore>, PackageManagerError> {
+        self.jobs
+            .as_ref()
+            .ok_or(PackageManagerError::Unsupported { operation: "jobs" })
+    }
+
+    /// Results of the backend's health checks and of the checks built on
+    /// its privilege check, repositories and signatures
+    async fn doctor_report(&self, backend: &Arc<dyn PackageManager>) -> doctor::DoctorReport {
+        let capabilities = backend.capabilities();
+        let mut checks = backend.health_checks().await.unwrap_or_else(|err| {
+            vec![HealthCheck::warn(
+                "package_manager",
+                format!("the {} checks could not run: {err}", backend.name()),
+            )]
+        });
+
+        if capabilities.privilege_check {
+            checks.push(match backend.check_privileges().await {
+                Ok(report) => {
+                    if Arc::ptr_eq(backend, &self.backend) {
+                        self.set_privileges(report.clone());
+                    }
+                    if report.can_mutate {
+                        HealthCheck::pass("privileges", "installs can modify the system")
+                    } else {
+                        HealthCheck::fail("privileges", report.problems.join("; "))
+                            .with_remediation(
+                                "Run the server as root, or with --escalate and passwordless sudo or doas.",
+                            )
+                    }
+                }
+                Err(err) => HealthCheck::warn(
+                    "privileges",
+                    format!("the privileges could not be checked: {err}"),
+                ),
+            });
+        }
+
+        if capabilities.repository_listing {
+            match backend.list_repositories().await {
+                Ok(repositories) if repositories.is_empty() => checks.push(
+                    HealthCheck::fail("repositories", "no repositories are configured")
+                        .with_remediation(
+                            "Configure at least one repository, or call refresh_repositories after adding one.",
+                        ),
+                ),
+                Ok(repositories) => {
+                    checks.extend(doctor::check_reachability(&repositories).await)
+                }
+                Err(err) => checks.push(HealthCheck::fail(
+                    "repositories",
+                    format!("the repositories could not be listed: {err}"),
+                )),
+            }
+        }
+
+        if capabilities.signature_check {
+            match backend.unsigned_repositories(None).await {
+                Ok(unsigned) if unsigned.is_empty() => checks.push(HealthCheck::pass(
+                    "signing",
+                    "every repository is signed by a trusted key",
+                )),
+                Ok(unsigned) => checks.extend(unsigned.into_iter().map(|unsigned| {
+                    HealthCheck::fail(
+                        "signing",
+                        format!("{}: {}", unsigned.repository, unsigned.reason),
+                    )
+                    .with_remediation(
+                        "Install the repository's signing key (Alpine: /etc/apk/keys; APT: a keyring referenced \
+                        with signed-by), or remove the repository.",
+                    )
+                })),
+                Err(err) => checks.push(HealthCheck::warn(
+                    "signing",
+                    format!("the repository signatures could not be checked: {err}"),
+                )),
+            }
+        }
+
+        checks.push(doctor::check_proxy());
+        doctor::DoctorReport::new(checks)
+    }
+
+    async fn execute(
+        &self,
+        backend: &Arc<dyn PackageManager>,
+        root: Option<&str>,
+        operation: &Operation,
+        client: Option<&ClientIdentity>,
+        verbose: bool,
+    ) -> Result<CallToolResult, McpError> {
+        let pm_name = backend.name();
+        // Selections are set right before installing, once every check
+        // passed, and after pinned packages are verified
+        let preseed = operation.preseed();
+
+        match operation {
+            Operation::Install(install_options) => {
+                let package = &install_options.package;
+                let pinned = self.package_pins.is_pinned(package);
+                if !pinned {
+                    set_selections(backend.as_ref(), &preseed).await?;
+                }
+                let package_installation = match self.batcher_for(backend, install_options) {
+                    _ if pinned => {
+                        self.install_pinned(
+                            backend,
+                            package,
+                            None,
+                            install_options.repository.clone(),
+                            install_options.arch.clone(),
+                            &preseed,
+                        )
+                        .await
+                    }
+                    Some(batcher) => batcher.install(backend, install_options).await,
+                    None => backend.install_package(install_options).await,
+                };
+
+                match package_installation {
+                    Ok(outcome) => {
+                        self.events
+                            .publish_install(pm_name, backend.version_scheme(), &outcome);
+                        self.charge_quota(&outcome);
+                        self.track_changes(backend, &outcome);
+                        // Repositories replacing the configured ones are
+                        // added on replay, as the versions are pinned
+                        let mut repositories = install_options.added_repositories();
+                        repositories
+                            .extend(install_options.only_repositories.iter().map(String::as_str));
+                        self.record_install(
+                            backend,
+                            &outcome,
+                            install_options.arch.as_deref(),
+                            &repositories,
+                        );
+
+                        let success_message = match &outcome.version {
+                            Some(version) => format!(
+                                "Package '{package}' version '{version}' was installed successfully."
+                            ),
+                            None => format!("Package '{package}' was installed successfully."),
+                        };
+                        let mut content =
+                            vec![Content::text(success_message), Content::json(&outcome)?];
+                        if verbose {
+                            content.push(Content::text(command_report(&outcome.diagnostics)));
+                        }
+                        Ok(CallToolResult::success(content))
+                    }
+                    Err(PackageManagerError::CommandFailed(exec_result)) => {
+                        let error_message = format!(
+                            "Failed to install package '{package}' (exit code: {})",
+                            exec_result.status
+                        );
+                        let mut error_details = serde_json::json!({
+                            "package_name": package,
+                            "exit_code": exec_result.status,
+                            "package_manager": pm_name
+                        });
+
+                        if let Some(stdout) = exec_result.stdout {
+                            error_details["stdout"] = serde_json::Value::String(stdout);
+                        }
+                        if let Some(stderr) = exec_result.stderr {
+                            error_details["stderr"] = serde_json::Value::String(stderr);
+                        }
+
+                        Err(McpError::internal_error(error_message, Some(error_details)))
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            }
+            Operation::InstallVersion(install_version_options) => {
+                let package = &install_version_options.package;
+                let version = &install_version_options.version;
+                let pinned = self.package_pins.is_pinned(package);
+                if !pinned {
+                    set_selections(backend.as_ref(), &preseed).await?;
+                }
+                let package_installation = if pinned {
+                    self.install_pinned(
+                        backend,
+                        package,
+                        Some(version),
+                        None,
+                        install_version_options.arch.clone(),
+                        &preseed,
+                    )
+                    .await
+                } else {
+                    backend
+                        .install_package_with_version(install_version_options)
+                        .await
+                };
+
+                match package_installation {
+                    Ok(outcome) => {
+                        self.events
+                            .publish_install(pm_name, backend.version_scheme(), &outcome);
+                        self.charge_quota(&outcome);
+                        self.track_changes(backend, &outcome);
+                        self.record_install(
+                            backend,
+                            &outcome,
+                            install_version_options.arch.as_deref(),
+                            &outcome
+                                .repository
+                                .as_deref()
+                                .into_iter()
+                                .collect::<Vec<_>>(),
+                        );
+
+                        let success_message = match outcome.version.as_deref() {
+                            Some(installed) if installed != version => format!(
+                                "Package '{package}' version '{installed}' was installed successfully, the newest version matching '{version}'."
+                            ),
+                            _ => format!(
+                                "Package '{package}' version '{version}' was installed successfully."
+                            ),
+                        };
+                        let mut content =
+                            vec![Content::text(success_message), Content::json(&outcome)?];
+                        if verbose {
+                            content.push(Content::text(command_report(&outcome.diagnostics)));
+                        }
+                        Ok(CallToolResult::success(content))
+                    }
+                    Err(PackageManagerError::CommandFailed(exec_result)) => {
+                        let error_message = format!(
+                            "Failed to install package '{package}' version '{version}' (exit code: {})",
+                            exec_result.status
+                        );
+                        let mut error_details = serde_json::json!({
+                            "package_name": package,
+                            "version": version,
+                            "exit_code": exec_result.status,
+                            "package_manager": pm_name
+                        });
+
+                        if let Some(stdout) = exec_result.stdout {
+                            error_details["stdout"] = serde_json::Value::String(stdout);
+                        }
+                        if let Some(stderr) = exec_result.stderr {
+                            error_details["stderr"] = serde_json::Value::String(stderr);
+                        }
+
+                        Err(McpError::internal_error(error_message, Some(error_details)))
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            }
+            Operation::InstallVersions { packages } => {
+                set_selections(backend.as_ref(), &preseed).await?;
+                let outcomes = backend.install_packages_with_versions(packages).await?;
+                let mut lines = Vec::new();
+                for (outcome, options) in outcomes.iter().zip(packages.iter()) {
+                    self.events
+                        .publish_install(pm_name, backend.version_scheme(), outcome);
+                    self.charge_quota(outcome);
+                    self.track_changes(backend, outcome);
+                    self.record_install(
+                        backend,
+                        outcome,
+                        options.arch.as_deref(),
+                        &outcome
+                            .repository
+                            .as_deref()
+                            .into_iter()
+                            .collect::<Vec<_>>(),
+                    );
+                    lines.push(format!(
+                        "- {} {}",
+                        outcome.package,
+                        outcome.version.as_deref().unwrap_or(&options.version)
+                    ));
+                }
+                let mut content = vec![
+                    Content::text(format!(
+                        "Installed {} packages in one transaction:\n{}",
+                        outcomes.len(),
+                        lines.join("\n")
+                    )),
+                    Content::json(&outcomes)?,
+                ];
+                // The outcomes share the output of their one command
+                if let Some(outcome) = outcomes.first().filter(|_| verbose) {
+                    content.push(Content::text(command_report(&outcome.diagnostics)));
+                }
+                Ok(CallToolResult::success(content))
+            }
+            Operation::RefreshRepositories => {
+                let repository_refresh = backend.refresh_repositories().await;
+
+                match repository_refresh {
+                    Ok(_) => {
+                        self.index_freshness.record(root);
+                        let success_message =
+                            "All repositories were refreshed successfully.".to_string();
+                        Ok(CallToolResult::success(vec![Content::text(
+                            success_message,
+                        )]))
+                    }
+                    Err(PackageManagerError::CommandFailed(exec_result)) => {
+                        let error_message = format!(
+                            "Failed to refresh repositories (exit code: {})",
+                            exec_result.status
+                        );
+                        let mut error_details = serde_json::json!({
+                            "exit_code": exec_result.status,
+                            "package_manager": pm_name
+                        });
+
+                        if let Some(stdout) = exec_result.stdout {
+                            error_details["stdout"] = serde_json::Value::String(stdout);
+                        }
+                        if let Some(stderr) = exec_result.stderr {
+                            error_details["stderr"] = serde_json::Value::String(stderr);
+                        }
+
+                        Err(McpError::internal_error(error_message, Some(error_details)))
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            }
+            Operation::ListInstalled(page) => {
+                // Later pages read the listing the first page took
+                let position = page.position()?;
+                let package_list = match position {
+                    Some((snapshot, _)) => Ok(self.listings.get(snapshot).ok_or_else(|| {
+                        McpError::invalid_params(
+                            format!(
+                                "the listing of cursor '{}' expired, list the packages again without a cursor",
+                                page.cursor.as_deref().unwrap_or_default()
+                            ),
+                            None,
+                        )
+                    })?),
+                    None => self.installed_packages(backend, root).await,
+                };
+
+                match package_list {
+                    Ok(packages) => {
+                        let start = position.map_or(0, |(_, start)| start);
+                        let (listed, next) = page.slice(&packages, start)?;
+                        let next_cursor = next.map(|end| {
+                            let snapshot = match position {
+                                Some((snapshot, _)) => snapshot.to_string(),
+                                None => self.listings.store(packages.clone()),
+                            };
+                            format!("{snapshot}.{end}")
+                        });
+                        let lines = listed
+                            .iter()
+                            .map(format_installed_package)
+                            .collect::<Vec<String>>()
+                            .join("\n");
+
+                        if page.limit.is_none() {
+                            return Ok(CallToolResult::success(vec![
+                                Content::text(format!("Installed packages:\n{lines}")),
+                                Content::json(listed)?,
+                            ]));
+                        }
+
+                        let mut text = format!(
+                            "Installed packages {}-{} of {}:\n{lines}",
+                            start + 1,
+                            start + listed.len(),
+                            packages.len()
+                        );
+                        if let Some(cursor) = &next_cursor {
+                            text.push_str(&format!(
+                                "\n\nMore packages are installed, call again with cursor '{cursor}' for the next page."
+                            ));
+                        }
+                        Ok(CallToolResult::success(vec![
+                            Content::text(text),
+                            Content::json(listed)?,
+                            Content::json(serde_json::json!({
+                                "total": packages.len(),
+                                "next_cursor": next_cursor,
+                            }))?,
+                        ]))
+                    }
+                    Err(PackageManagerError::CommandFailed(exec_result)) => {
+                        let error_message = format!(
+                            "Failed to list installed packages (exit code: {})",
+                            exec_result.status
+                        );
+                        let mut error_details = serde_json::json!({
+                            "exit_code": exec_result.status,
+                            "package_manager": pm_name
+                        });
+
+                        if let Some(stderr) = exec_result.stderr {
+                            error_details["stderr"] = serde_json::Value::String(stderr);
+                        }
+
+                        Err(McpError::internal_error(error_message, Some(error_details)))
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            }
+            Operation::ListRepositories => {
+                let repositories = backend.list_repositories().await?;
+                let lines = repositories
+                    .iter()
+                    .map(format_repository)
+                    .collect::<Vec<String>>()
+                    .join("\n");
+
+                Ok(CallToolResult::success(vec![
+                    Content::text(format!("Configured repositories:\n{lines}")),
+                    Content::json(&repositories)?,
+                ]))
+            }
+            Operation::ResolveVersion(options) => {
+                let resolved = backend.resolve_version(options).await?;
+                let source = match resolved.repositories.as_slice() {
+                    [] => String::new(),
+                    repositories => format!(" from {}", repositories.join(", ")),
+                };
+                Ok(CallToolResult::success(vec![
+                    Content::text(format!(
+                        "Version '{}' of package '{}' would be installed for '{}'{source}.",
+                        resolved.version, resolved.package, options.version
+                    )),
+                    Content::json(&resolved)?,
+                ]))
+            }
+            Operation::ListSearchRepositories => {
+                let repositories = backend.list_search_repositories().await?;
+                let lines = repositories
+                    .iter()
+                    .map(format_repository)
+                    .collect::<Vec<String>>()
+                    .join("\n");
+
+                Ok(CallToolResult::success(vec![
+                    Content::text(format!("Searched repositories:\n{lines}")),
+                    Content::json(&repositories)?,
+                ]))
+            }
+            Operation::CheckPrivileges => {
+                let report = backend.check_privileges().await?;
+                if Arc::ptr_eq(backend, &self.backend) {
+                    self.set_privileges(report.clone());
+                }
+
+                let summary = if report.can_mutate {
+                    "The server can install packages and refresh repositories.".to_string()
+                } else {
+                    format!(
+                        "The server cannot install packages or refresh repositories:\n{}",
+                        report
+                            .problems
+                            .iter()
+                            .map(|problem| format!("- {problem}"))
+                            .collect::<Vec<String>>()
+                            .join("\n")
+                    )
+                };
+                Ok(CallToolResult::success(vec![
+                    Content::text(summary),
+                    Content::json(&report)?,
+                ]))
+            }
+            Operation::ServerInfo => {
+                let report = self.server_report(backend, root, client).await;
+                Ok(CallToolResult::success(vec![
+                    Content::text(report.summary()),
+                    Content::json(&report)?,
+                ]))
+            }
+            Operation::Doctor => {
+                let report = self.doctor_report(backend).await;
+                Ok(CallToolResult::success(vec![
+                    Content::text(report.summary()),
+                    Content::json(&report)?,
+                ]))
+            }
+            Operation::GetJobStatus { .. }
+            | Operation::GetJobOutput { .. }
+            | Operation::CancelJob { .. } => {
+                unreachable!("job operations are run by execute_job")
+            }
+            Operation::GetOperationLog {
+                operation_id,
+                offset,
+                limit,
+            } => {
+                let Some(logs) = &self.operation_logs else {
+                    return Err(PackageManagerError::Unsupported {
+                        operation: "get_operation_log",
+                    }
+                    .into());
+                };
+                let owner = client.map(|client| client.subject.as_str());
+                let limit = limit
+                    .unwrap_or(operation_log::MAX_LOG_PAGE)
+                    .min(operation_log::MAX_LOG_PAGE);
+                let page = logs.read(operation_id, owner).await?.page(*offset, limit);
+                let heading = match page.lines.len() {
+                    0 => format!(
+                        "Operation '{operation_id}' ({}, {}) has no output from line {}.",
+                        page.tool, page.outcome, page.offset
+                    ),
+                    count => format!(
+                        "{count} of the {} lines of the output of operation '{operation_id}' ({}, {}) from line {}:\n\n{}",
+                        page.total_lines,
+                        page.tool,
+                        page.outcome,
+                        page.offset,
+                        page.lines
+                            .iter()
+                            .map(|line| line.line.as_str())
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    ),
+                };
+                Ok(CallToolResult::success(vec![
+                    Content::text(heading),
+                    Content::json(&page)?,
+                ]))
+            }
+            Operation::InstallOnTargets(options) => {
+                let package = &options.package;
+                let targets = self.select_targets(&options.targets)?;
+                let schemes: Vec<_> = targets
+                    .iter()
+                    .map(|(_, backend)| (backend.name(), backend.version_scheme()))
+                    .collect();
+
+                let mut lines = Vec::new();
+                let mut results = Vec::new();
+                for ((target, result), (target_pm, scheme)) in targets::install_on(targets, options)
+                    .await
+                    .into_iter()
+                    .zip(schemes)
+                {
+                    match result {
+                        Ok(outcome) => {
+                            self.events.publish_install_on(
+                                Some(&target),
+                                target_pm,
+                                scheme,
+                                &outcome,
+                            );
+                            self.charge_quota(&outcome);
+                            lines.push(match &outcome.version {
+                                Some(version) => {
+                                    format!("- {target}: installed version '{version}'")
+                                }
+                                None => format!("- {target}: installed"),
+                            });
+                            results.push(targets::TargetResult {
+                                target,
+                                success: true,
+                                outcome: Some(outcome),
+                                error: None,
+                            });
+                        }
+                        Err(err) => {
+                            lines.push(format!("- {target}: failed: {err}"));
+                            let err = McpError::from(err);
+                            let mut error = err.data.unwrap_or_else(|| serde_json::json!({}));
+                            error["message"] = serde_json::json!(err.message);
+                            results.push(targets::TargetResult {
+                                target,
+                                success: false,
+                                outcome: None,
+                                error: Some(error),
+                            });
+                        }
+                    }
+                }
+
+                let succeeded = results.iter().filter(|result| result.success).count();
+                let content = vec![
+                    Content::text(format!(
+                        "Package '{package}' was installed on {succeeded} of {} targets:\n{}",
+                        results.len(),
+                        lines.join("\n")
+                    )),
+                    Content::json(&results)?,
+                ];
+                if succeeded == results.len() {
+                    Ok(CallToolResult::success(content))
+                } else {
+                    Ok(CallToolResult::error(content))
+                }
+            }
+            Operation::ExportSession { format } => {
+                let packages = self
+                    .session
+                    .as_ref()
+                    .map(|session| session.packages())
+                    .unwrap_or_default();
+                if packages.is_empty() {
+                    return Ok(CallToolResult::success(vec![Content::text(
+                        "No packages were installed in this session.",
+                    )]));
+                }
+
+                if *format == ExportFormat::Spdx {
+                    let document = crate::sbom::spdx_document(&packages, backend.version_scheme());
+                    return Ok(CallToolResult::success(vec![
+                        Content::text(
+                            serde_json::to_string_pretty(&document)
+                                .map_err(|err| McpError::internal_error(err.to_string(), None))?,
+                        ),
+                        Content::json(serde_json::json!({
+                            "format": format,
+                            "packages": packages,
+                            "document": document,
+                        }))?,
+                    ]));
+                }
+
+                let script = if *format == ExportFormat::Ansible {
+                    crate::ansible::render_tasks(&backend.ansible_tasks(&packages).await?)
+                } else {
+                    let commands = backend.install_commands(&packages).await?;
+                    format.render(&commands).unwrap_or_default()
+                };
+                Ok(CallToolResult::success(vec![
+                    Content::text(script.clone()),
+                    Content::json(serde_json::json!({
+                        "format": format,
+                        "packages": packages,
+                        "script": script,
+                    }))?,
+                ]))
+            }
+            Operation::ExportDockerfileSnippet => {
+                let packages = self
+                    .session
+                    .as_ref()
+                    .map(|session| session.packages())
+                    .unwrap_or_default();
+                if packages.is_empty() {
+                    return Ok(CallToolResult::success(vec![Content::text(
+                        "No packages were installed in this session.",
+                    )]));
+                }
+
+                let instructions = backend.dockerfile_instructions(&packages).await?;
+                let snippet = format!("{}\n", instructions.join("\n"));
+                Ok(CallToolResult::success(vec![
+                    Content::text(snippet.clone()),
+                    Content::json(serde_json::json!({
+                        "packages": packages,
+                        "instructions": instructions,
+                        "snippet": snippet,
+                    }))?,
+                ]))
+            }
+            Operation::ExportCloudInit => {
+                let packages = self
+                    .session
+                    .as_ref()
+                    .map(|session| session.packages())
+                    .unwrap_or_default();
+                if packages.is_empty() {
+                    return Ok(CallToolResult::success(vec![Content::text(
+                        "No packages were installed in this session.",
+                    )]));
+                }
+
+                let config = backend.cloud_config(&packages).await?;
+                let yaml = config.to_yaml();
+                Ok(CallToolResult::success(vec![
+                    Content::text(yaml.clone()),
+                    Content::json(serde_json::json!({
+                        "packages": packages,
+                        "config": config,
+                        "yaml": yaml,
+                    }))?,
+                ]))
+            }
+            Operation::ExportApkoConfig { scope } => {
+                let scope = scope.unwrap_or(if self.session.is_some() {
+                    ExportScope::Session
+                } else {
+                    ExportScope::Installed
+                });
+                let packages = match scope {
+                    ExportScope::Session => {
+                        let Some(session) = &self.session else {
+                            return Err(PackageManagerError::Validation {
+                                field: "scope",
+                                value: "session".to_string(),
+                                reason: "sessions are not recorded, export the installed packages instead".to_string(),
+                            }
+                            .into());
+                        };
+                        session.packages()
+                    }
+                    ExportScope::Installed => self
+                        .installed_packages(backend, root)
+                        .await?
+                        .iter()
+                        .map(|package| PinnedPackage {
+                            name: package.name.clone(),
+                            version: package.version.clone(),
+                            arch: None,
+                            repository: None,
+                            repositories: Vec::new(),
+                        })
+                        .collect(),
+                };
+                if packages.is_empty() {
+                    return Ok(CallToolResult::success(vec![Content::text(match scope {
+                        ExportScope::Session => "No packages were installed in this session.",
+                        ExportScope::Installed => "No packages are installed.",
+                    })]));
+                }
+
+                let config = backend.apko_config(&packages).await?;
+                let yaml = config.to_yaml();
+                Ok(CallToolResult::success(vec![
+                    Content::text(yaml.clone()),
+                    Content::json(serde_json::json!({
+                        "scope": scope,
+                        "config": config,
+                        "yaml": yaml,
+                    }))?,
+                ]))
+            }
+            Operation::ExportAuditLog { from_sequence } => {
+                let Some(audit) = &self.audit else {
+                    return Err(PackageManagerError::Unsupported {
+                        operation: "export_audit_log",
+                    }
+                    .into());
+                };
+                let export = audit.export(*from_sequence).await.map_err(|err| {
+                    PackageManagerError::io(
+                        format!(
+                            "there was an error reading audit log {}",
+                            audit.path().display()
+                        ),
+                        err,
+                    )
+                })?;
+                let verification = &export.verification;
+                let mut text = match &verification.broken_at {
+                    None => format!(
+                        "The audit log's chain of {} entries is intact, its head is {}.",
+                        verification.entries,
+                        verification.head.as_deref().unwrap_or("empty")
+                    ),
+                    Some(broken) => format!(
+                        "The audit log was tampered with: line {} breaks the chain ({}) after {} valid entries.",
+                        broken.line, broken.problem, verification.entries
+                    ),
+                };
+                if let Some(key) = &verification.public_key {
+                    text.push_str(&format!(" Signatures were verified with public key {key}."));
+                }
+                text.push_str(&format!(" Returning {} entries.", export.entries.len()));
+                Ok(CallToolResult::success(vec![
+                    Content::text(text),
+                    Content::json(&export)?,
+                ]))
+            }
+            Operation::ExportHistory { from_sequence } => {
+                let Some(audit) = &self.audit else {
+                    return Err(PackageManagerError::Unsupported {
+                        operation: "export_history",
                     }
-                    Err(err) => Err(McpError::internal_error(
+                    .into());
+                };
+                let export = audit.export(*from_sequence).await.map_err(|err| {
+                    PackageManagerError::io(
                         format!(
-                            "System error while searching for packages with query '{query}': {err:?}. This may indicate {pm_name} is not available or there are permission issues."
+                            "there was an error reading audit log {}",
+                            audit.path().display()
+                        ),
+                        err,
+                    )
+                })?;
+                let records: Vec<_> = export
+                    .entries
+                    .iter()
+                    .flat_map(crate::history::history_records)
+                    .collect();
+                let jsonl = crate::history::to_jsonl(&records);
+                Ok(CallToolResult::success(vec![
+                    Content::text(jsonl.clone()),
+                    Content::json(serde_json::json!({
+                        "records": records.len(),
+                        "jsonl": jsonl,
+                    }))?,
+                ]))
+            }
+            Operation::GenerateLockfile => {
+                let (packages, dependencies) = self
+                    .session
+                    .as_ref()
+                    .map(|session| (session.packages(), session.dependencies()))
+                    .unwrap_or_default();
+                if packages.is_empty() {
+                    return Ok(CallToolResult::success(vec![Content::text(
+                        "No packages were installed in this session.",
+                    )]));
+                }
+
+                let requested = packages.len();
+                let mut locked = backend
+                    .lock_packages(&[packages, dependencies].concat())
+                    .await?;
+                for (index, package) in locked.iter_mut().enumerate() {
+                    package.dependency = index >= requested;
+                }
+                let lockfile = Lockfile::new(backend.as_ref(), locked);
+                Ok(CallToolResult::success(vec![
+                    Content::text(
+                        serde_json::to_string_pretty(&lockfile)
+                            .map_err(|err| McpError::internal_error(err.to_string(), None))?,
+                    ),
+                    Content::json(&lockfile)?,
+                ]))
+            }
+            Operation::InstallFromLockfile(lockfile) => {
+                if lockfile.backend != pm_name {
+                    return Err(PackageManagerError::Validation {
+                        field: "lockfile",
+                        value: lockfile.backend.clone(),
+                        reason: format!(
+                            "the lockfile was written by the {} backend, this server uses {pm_name}",
+                            lockfile.backend
+                        ),
+                    }
+                    .into());
+                }
+
+                // Checksums and sources are only known to backends that can
+                // lock packages
+                let verify = backend.capabilities().lockfile;
+                let pinned = lockfile.pinned();
+                if verify {
+                    let found = backend.lock_packages(&pinned).await?;
+                    let installed = self.installed_packages(backend, root).await?;
+                    let differences = lockfile.differences(&found, &installed);
+                    if !differences.is_empty() {
+                        return Err(PackageManagerError::LockfileMismatch { differences }.into());
+                    }
+                }
+
+                let mut outcomes = backend.install_locked(&lockfile.packages).await?;
+                if let Some(installed) = &self.installed {
+                    installed.clear();
+                }
+                for (outcome, locked) in outcomes.iter_mut().zip(&lockfile.packages) {
+                    // Packages already at their pinned version are unchanged
+                    outcome
+                        .version
+                        .get_or_insert_with(|| locked.package.version.clone());
+                    self.events
+                        .publish_install(pm_name, backend.version_scheme(), outcome);
+                    self.charge_quota(outcome);
+                    self.track_changes(backend, outcome);
+                    self.record_locked_install(backend, outcome, locked);
+                }
+
+                let mut verified = Vec::new();
+                let mut unverified = Vec::new();
+                if verify {
+                    let found = backend.lock_packages(&pinned).await?;
+                    let mismatches: Vec<_> = lockfile
+                        .differences(&found, &[])
+                        .into_iter()
+                        .filter(|difference| {
+                            matches!(difference.problem, LockProblem::ChecksumMismatch { .. })
+                        })
+                        .collect();
+                    if !mismatches.is_empty() {
+                        return Err(PackageManagerError::LockfileMismatch {
+                            differences: mismatches,
+                        }
+                        .into());
+                    }
+                    for (locked, found) in lockfile.packages.iter().zip(&found) {
+                        if locked.checksum.is_some() && found.checksum == locked.checksum {
+                            verified.push(locked.package.name.clone());
+                        } else {
+                            unverified.push(locked.package.name.clone());
+                        }
+                    }
+                } else {
+                    unverified = pinned.iter().map(|package| package.name.clone()).collect();
+                }
+
+                let mut text = format!(
+                    "Installed the {} packages of the lockfile at their pinned versions, {} with a verified checksum.",
+                    lockfile.packages.len(),
+                    verified.len()
+                );
+                if !unverified.is_empty() {
+                    text.push_str(&format!(
+                        " No checksum could be verified for: {}.",
+                        unverified.join(", ")
+                    ));
+                }
+                Ok(CallToolResult::success(vec![
+                    Content::text(text),
+                    Content::json(serde_json::json!({
+                        "installed": outcomes,
+                        "verified": verified,
+                        "unverified": unverified,
+                    }))?,
+                ]))
+            }
+            Operation::SearchAllDistros { project, distros } => {
+                let Some(repology) = &self.repology else {
+                    return Err(PackageManagerError::Unsupported {
+                        operation: "search_all_distros",
+                    }
+                    .into());
+                };
+                let families: Vec<String> = if distros.is_empty() {
+                    repology::MAJOR_DISTRIBUTIONS
+                        .iter()
+                        .map(|family| family.to_string())
+                        .collect()
+                } else {
+                    distros.clone()
+                };
+                let packages = repology.project(project, &families).await.map_err(|err| {
+                    PackageManagerError::NetworkFailure {
+                        message: format!("the Repology database could not be queried: {err}"),
+                    }
+                })?;
+
+                let text = if packages.is_empty() {
+                    format!(
+                        "Repology knows no package of project '{project}' in {}.",
+                        families.join(", ")
+                    )
+                } else {
+                    let lines: Vec<String> = packages
+                        .iter()
+                        .map(|package| {
+                            format!(
+                                "- {}: {} {} ({})",
+                                package.repository,
+                                package.names.join(", "),
+                                package.version,
+                                package.status
+                            )
+                        })
+                        .collect();
+                    format!(
+                        "Packages of project '{project}' by repository:\n{}",
+                        lines.join("\n")
+                    )
+                };
+                Ok(CallToolResult::success(vec![
+                    Content::text(text),
+                    Content::json(serde_json::json!({
+                        "project": project,
+                        "packages": packages,
+                    }))?,
+                ]))
+            }
+            Operation::CheckVulnerabilities { packages } => {
+                let Some(osv) = &self.osv else {
+                    return Err(PackageManagerError::Unsupported {
+                        operation: "check_vulnerabilities",
+                    }
+                    .into());
+                };
+                let ecosystem = backend.osv_ecosystem().await?;
+                let installed = self.installed_packages(backend, root).await?;
+                let checked: Vec<(String, String)> = if packages.is_empty() {
+                    installed
+                        .iter()
+                        .map(|package| (package.name.clone(), package.version.clone()))
+                        .collect()
+                } else {
+                    packages
+                        .iter()
+                        .map(|entry| match entry.split_once('=') {
+                            Some((name, version)) => Ok((name.to_string(), version.to_string())),
+                            None => installed
+                                .iter()
+                                .find(|package| package.name == *entry)
+                                .map(|package| (package.name.clone(), package.version.clone()))
+                                .ok_or_else(|| PackageManagerError::Validation {
+                                    field: "packages",
+                                    value: entry.clone(),
+                                    reason: "the package is not installed, pass 'name=version' to check a version".to_string(),
+                                }),
+                        })
+                        .collect::<Result<_, _>>()?
+                };
+                if checked.is_empty() {
+                    return Ok(CallToolResult::success(vec![Content::text(
+                        "No packages are installed, so none were checked.",
+                    )]));
+                }
+
+                let sources = backend.source_packages().await?;
+                let queries: Vec<(String, String)> = checked
+                    .iter()
+                    .map(|(name, version)| {
+                        (sources.get(name).unwrap_or(name).clone(), version.clone())
+                    })
+                    .collect();
+                let found = osv
+                    .vulnerabilities(&ecosystem, &queries)
+                    .await
+                    .map_err(|err| PackageManagerError::NetworkFailure {
+                        message: format!(
+                            "the OSV vulnerability database could not be queried: {err}"
                         ),
-                        Some(serde_json::json!({
+                    })?;
+
+                let affected: Vec<osv::PackageVulnerabilities> = checked
+                    .iter()
+                    .zip(&queries)
+                    .zip(found)
+                    .filter(|(_, vulnerabilities)| !vulnerabilities.is_empty())
+                    .map(|(((package, version), (source, _)), vulnerabilities)| {
+                        osv::PackageVulnerabilities {
+                            package: package.clone(),
+                            version: version.clone(),
+                            source: (source != package).then(|| source.clone()),
+                            vulnerabilities,
+                        }
+                    })
+                    .collect();
+
+                let text = if affected.is_empty() {
+                    format!(
+                        "No known vulnerabilities affect the {} checked packages ({ecosystem}).",
+                        checked.len()
+                    )
+                } else {
+                    let lines = affected
+                        .iter()
+                        .flat_map(|package| {
+                            package.vulnerabilities.iter().map(move |vulnerability| {
+                                let mut line = format!(
+                                    "- {} {}: {}",
+                                    package.package, package.version, vulnerability.id
+                                );
+                                if let Some(severity) = &vulnerability.severity {
+                                    line.push_str(&format!(" ({severity})"));
+                                }
+                                if !vulnerability.fixed_versions.is_empty() {
+                                    line.push_str(&format!(
+                                        ", fixed in {}",
+                                        vulnerability.fixed_versions.join(", ")
+                                    ));
+                                }
+                                if let Some(summary) = &vulnerability.summary {
+                                    line.push_str(&format!(": {summary}"));
+                                }
+                                line
+                            })
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    format!(
+                        "Known vulnerabilities affect {} of the {} checked packages ({ecosystem}):\n{lines}",
+                        affected.len(),
+                        checked.len()
+                    )
+                };
+                Ok(CallToolResult::success(vec![
+                    Content::text(text),
+                    Content::json(serde_json::json!({
+                        "ecosystem": ecosystem,
+                        "checked": checked.len(),
+                        "packages": affected,
+                    }))?,
+                ]))
+            }
+            Operation::LicenseReport { deny } => {
+                let deny_list: Vec<String> =
+                    self.denied_licenses.iter().chain(deny).cloned().collect();
+                let report =
+                    licenses::LicenseReport::new(backend.package_licenses().await?, &deny_list);
+
+                let mut counts: Vec<(&String, &usize)> = report.licenses.iter().collect();
+                counts.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+                let mut text = format!(
+                    "License report for {} installed packages: {} distinct licenses, {} packages without license information.\n\nLicenses:\n{}",
+                    report.packages.len(),
+                    report.licenses.len(),
+                    report.unknown,
+                    counts
+                        .iter()
+                        .map(|(license, count)| format!("- {license}: {count}"))
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                );
+                if deny_list.is_empty() {
+                    text.push_str("\n\nNo deny-list is configured, so no package was flagged.");
+                } else if report.flagged == 0 {
+                    text.push_str(&format!(
+                        "\n\nNo package has a license matching the deny-list ({}).",
+                        deny_list.join(", ")
+                    ));
+                } else {
+                    text.push_str(&format!(
+                        "\n\n{} packages have a license matching the deny-list and need compliance review:\n{}",
+                        report.flagged,
+                        report
+                            .flagged_packages()
+                            .map(|finding| format!(
+                                "- {} {}: {} (matches {})",
+                                finding.package.name,
+                                finding.package.version,
+                                finding.package.licenses.join("; "),
+                                finding.denied.join(", ")
+                            ))
+                            .collect::<Vec<String>>()
+                            .join("\n")
+                    ));
+                }
+
+                Ok(CallToolResult::success(vec![
+                    Content::text(text),
+                    Content::json(&report)?,
+                ]))
+            }
+            Operation::Search {
+                options: search_options,
+                listing,
+            } => {
+                let query = &search_options.query;
+                let package_search = match search_normalized(backend.as_ref(), search_options).await
+                {
+                    Ok((results, matched_query)) if listing.needs_locations() => backend
+                        .locate_search_results(results)
+                        .await
+                        .map(|results| (results, matched_query)),
+                    search => search,
+                };
+
+                match package_search {
+                    Ok((results, matched_query)) => {
+                        let (results, found) = listing.apply(backend.version_scheme(), results);
+                        if results.is_empty() {
+                            let within = match &listing.branch {
+                                Some(branch) => format!(" in '{branch}'"),
+                                None => String::new(),
+                            };
+                            return Ok(CallToolResult::success(vec![Content::text(format!(
+                                "Search completed for query '{query}' but no packages were found{within}."
+                            ))]));
+                        }
+
+                        let lines = results
+                            .iter()
+                            .map(format_search_result)
+                            .collect::<Vec<String>>()
+                            .join("\n");
+
+                        let mut heading = match matched_query {
+                            Some(matched) => format!(
+                                "No packages matched query '{query}'. Search results for query '{matched}'"
+                            ),
+                            None => format!("Search results for query '{query}'"),
+                        };
+                        if results.len() < found {
+                            heading.push_str(&format!(
+                                " (the first {} of {found}; raise limit to see more)",
+                                results.len()
+                            ));
+                        }
+                        heading.push(':');
+                        Ok(CallToolResult::success(vec![
+                            Content::text(format!("{heading}\n\n{lines}")),
+                            Content::json(&results)?,
+                        ]))
+                    }
+                    Err(PackageManagerError::CommandFailed(exec_result)) => {
+                        let error_message = format!(
+                            "Failed to search for packages with query '{query}' (exit code: {})",
+                            exec_result.status
+                        );
+                        let mut error_details = serde_json::json!({
                             "query": query,
-                            "error_type": "system_error",
-                            "suggestion": format!("Ensure {} package manager is installed and you have sufficient privileges", pm_name)
-                        })),
-                    )),
+                            "exit_code": exec_result.status,
+                            "package_manager": pm_name
+                        });
+
+                        if let Some(stdout) = exec_result.stdout {
+                            error_details["stdout"] = serde_json::Value::String(stdout);
+                        }
+                        if let Some(stderr) = exec_result.stderr {
+                            error_details["stderr"] = serde_json::Value::String(stderr);
+                        }
+
+                        Err(McpError::internal_error(error_message, Some(error_details)))
+                    }
+                    Err(err) => Err(err.into()),
                 }
             }
-            _ => Ok(CallToolResult::error(vec![Content::text(format!(
-                "Unknown tool '{}'. Available tools: install_package, install_package_with_version, list_installed_packages, refresh_repositories, search_package",
-                request.name
-            ))])),
         }
     }
 }
+
+/// Fail installs needing more disk space than is free, before the package
+/// manager starts unpacking them
+///
+/// Installs whose space cannot be estimated, e.g. from package files or
+/// custom repositories, are left to the package manager.
+async fn check_disk_space(
+    backend: &Arc<dyn PackageManager>,
+    operation: &Operation,
+) -> Result<(), PackageManagerError> {
+    if !backend.capabilities().disk_space_check {
+        return Ok(());
+    }
+    let versions = match operation {
+        Operation::Install(options)
+            if options.added_repositories().is_empty()
+                && options.only_repositories.is_empty()
+                && operation.untrusted_source() != Some(options.package.as_str()) =>
+        {
+            let packages = [options.package.clone()];
+            return require_space(
+                backend,
+                &packages,
+                options.arch.as_deref(),
+                options.install_recommends,
+            )
+            .await;
+        }
+        Operation::InstallVersion(options) => std::slice::from_ref(options),
+        Operation::InstallVersions { packages } => packages.as_slice(),
+        _ => return Ok(()),
+    };
+    // One estimate covers a single architecture
+    let arch = versions.first().and_then(|options| options.arch.as_deref());
+    if versions
+        .iter()
+        .any(|options| options.arch.as_deref() != arch)
+    {
+        return Ok(());
+    }
+    let mut packages = Vec::new();
+    for options in versions {
+        match backend.resolve_version(options).await {
+            Ok(resolved) => packages.push(format!("{}={}", resolved.package, resolved.version)),
+            // The install reports versions that do not resolve
+            Err(_) => return Ok(()),
+        }
+    }
+    // Installed together, the packages take the first selection like the
+    // install does
+    let recommends = versions
+        .iter()
+        .find_map(|options| options.install_recommends);
+    require_space(backend, &packages, arch, recommends).await
+}
+
+/// Compare the space installing `packages` needs with the free space
+async fn require_space(
+    backend: &Arc<dyn PackageManager>,
+    packages: &[String],
+    arch: Option<&str>,
+    recommends: Option<Recommends>,
+) -> Result<(), PackageManagerError> {
+    let requirements = match backend.required_space(packages, arch, recommends).await {
+        Ok(requirements) => requirements,
+        Err(err) => {
+            tracing::debug!(
+                "Could not estimate the space of installing {}: {err}",
+                packages.join(" ")
+            );
+            return Ok(());
+        }
+    };
+    match requirements
+        .into_iter()
+        .find(|requirement| requirement.needed > requirement.available)
+    {
+        Some(requirement) => Err(PackageManagerError::InsufficientDiskSpace {
+            mount: requirement.mount,
+            needed: requirement.needed,
+            available: requirement.available,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The newest of the `found` versions of `options.package` matching its
+/// version, with the repositories offering it
+///
+/// Without a version scheme, only an equal version matches. Fails with
+/// [`PackageManagerError::NotFound`] when nothing offers the package, with
+/// no searched repositories, and with
+/// [`PackageManagerError::VersionNotFound`] when no version matches.
+pub(crate) fn resolve_among(
+    scheme: Option<VersionScheme>,
+    found: &[SearchResult],
+    options: &InstallVersionOptions,
+) -> Result<ResolvedVersion, PackageManagerError> {
+    let mut versions = parse::versions_of(found, &options.package);
+    let constraint = VersionConstraint::parse(&options.version);
+    let resolved = match scheme {
+        Some(scheme) => scheme.latest_matching(versions.iter().map(String::as_str), &constraint),
+        None => versions
+            .iter()
+            .map(String::as_str)
+            .find(|version| *version == constraint.version),
+    };
+
+    if let Some(version) = resolved {
+        let mut repositories: Vec<String> = Vec::new();
+        for repository in found
+            .iter()
+            .filter(|result| {
+                result.name == options.package && result.version.as_deref() == Some(version)
+            })
+            .filter_map(|result| result.repository.clone())
+        {
+            if !repositories.contains(&repository) {
+                repositories.push(repository);
+            }
+        }
+        return Ok(ResolvedVersion {
+            package: options.package.clone(),
+            version: version.to_string(),
+            repositories,
+        });
+    }
+
+    if versions.is_empty() {
+        return Err(PackageManagerError::NotFound {
+            package: options.package.clone(),
+            searched_repositories: Vec::new(),
+        });
+    }
+    if let Some(scheme) = scheme {
+        scheme.sort(&mut versions);
+    }
+    Err(PackageManagerError::VersionNotFound {
+        package: options.package.clone(),
+        version: options.version.clone(),
+        available_versions: versions,
+    })
+}
+
+/// Search results of `options`, or of the first normalized form of its
+/// query finding packages, with that query
+///
+/// Exact searches are not normalized.
+async fn search_normalized(
+    backend: &dyn PackageManager,
+    options: &SearchOptions,
+) -> Result<(Vec<SearchResult>, Option<String>), PackageManagerError> {
+    let results = backend.search_package(options).await?;
+    if !results.is_empty() || options.mode == SearchMode::Exact {
+        return Ok((results, None));
+    }
+    for query in normalize::query_variants(&options.query) {
+        let results = backend
+            .search_package(&SearchOptions {
+                query: query.clone(),
+                ..options.clone()
+            })
+            .await?;
+        if !results.is_empty() {
+            return Ok((results, Some(query)));
+        }
+    }
+    Ok((Vec::new(), None))
+}
+
+/// Versions of exactly the package `search` queries that its repositories
+/// offer
+async fn available_versions(
+    backend: &dyn PackageManager,
+    search: &SearchOptions,
+) -> Result<Vec<String>, PackageManagerError> {
+    let found = backend.search_package(search).await?;
+    Ok(parse::versions_of(&found, &search.query))
+}
+
+/// Reject the options of an install of the pinned `package` that the
+/// install of its downloaded file would not apply
+///
+/// Pinned packages are fetched from the configured repositories or the
+/// call's `repository`, then installed as a file with the backend's
+/// defaults.
+fn check_pinned_options(
+    package: &str,
+    mut parameters: Vec<&'static str>,
+    install_recommends: Option<Recommends>,
+    target_release: Option<&str>,
+    flags: InstallFlags,
+) -> Result<(), PackageManagerError> {
+    if install_recommends.is_some() {
+        parameters.push("install_recommends");
+    }
+    if target_release.is_some() {
+        parameters.push("target_release");
+    }
+    parameters.extend(flags.parameters());
+    match parameters.first().copied() {
+        None => Ok(()),
+        Some(parameter) => Err(PackageManagerError::Validation {
+            field: parameter,
+            value: package.to_string(),
+            reason: "pinned packages are installed from their verified download, which does not apply this option"
+                .to_string(),
+        }),
+    }
+}
+
+/// Set the debconf `selections` of an install, if any
+async fn set_selections(
+    backend: &dyn PackageManager,
+    selections: &[String],
+) -> Result<(), PackageManagerError> {
+    if selections.is_empty() {
+        return Ok(());
+    }
+    backend.preseed(selections).await
+}
+
+/// Search for the candidates of an APK install, which may name its package
+/// `package@tag`: the plain name, in the requested repositories tagged `tag`
+/// if there are any
+fn untagged_search(mut search: SearchOptions) -> SearchOptions {
+    let (name, Some(tag)) = parse::apk::split_package_tag(&search.query) else {
+        return search;
+    };
+    let tagged: Vec<String> = search
+        .repository
+        .iter()
+        .chain(&search.repositories)
+        .chain(&search.only_repositories)
+        .filter(|repository| parse::apk::split_repository_tag(repository).0 == Some(tag))
+        .cloned()
+        .collect();
+    search.query = name.to_string();
+    if !tagged.is_empty() {
+        search.repository = None;
+        search.repositories = Vec::new();
+        search.only_repositories = tagged;
+    }
+    search
+}
+
+/// Version an install picks among `versions`: the latest one, or the latest
+/// matching the `version` constraint
+fn candidate_version<'a>(
+    scheme: Option<VersionScheme>,
+    versions: &'a [String],
+    version: Option<&'a str>,
+) -> Option<&'a str> {
+    let versions = versions.iter().map(String::as_str);
+    match (version, scheme) {
+        (None, Some(scheme)) => scheme.latest(versions),
+        (None, None) => versions.into_iter().next(),
+        (Some(version), Some(scheme)) => {
+            let constraint = VersionConstraint::parse(version);
+            scheme
+                .latest_matching(versions, &constraint)
+                .or_else(|| constraint.is_exact().then_some(version))
+        }
+        (Some(version), None) => Some(version),
+    }
+}
+
+/// Command line and output of a successful command, for verbose results
+///
+/// Only the end of long streams is kept, where package managers print their
+/// warnings and summaries.
+fn command_report(result: &ExecResult) -> String {
+    let tail = |output: &str| match output.len().checked_sub(VERBOSE_OUTPUT_LIMIT) {
+        Some(start) if start > 0 => {
+            let start = (start..output.len())
+                .find(|index| output.is_char_boundary(*index))
+                .unwrap_or(output.len());
+            format!("[{start} bytes truncated]\n{}", &output[start..])
+        }
+        _ => output.to_string(),
+    };
+    let mut report = match &result.command {
+        Some(command) => format!("Command: {command}\nExit code: {}", result.status),
+        None => format!("Exit code: {}", result.status),
+    };
+    for (name, output) in [("stdout", &result.stdout), ("stderr", &result.stderr)] {
+        if let Some(output) = output.as_deref().filter(|output| !output.trim().is_empty()) {
+            report.push_str(&format!("\n\n{name}:\n{}", tail(output.trim_end())));
+        }
+    }
+    report
+}
+
+fn format_installed_package(package: &InstalledPackage) -> String {
+    let mut line = match &package.architecture {
+        Some(architecture) => format!("{} {} ({architecture})", package.name, package.version),
+        None => format!("{} {}", package.name, package.version),
+    };
+    if let Some(repository) = &package.repository {
+        line.push_str(&format!(" [{repository}]"));
+    }
+    if package.reason == Some(InstallReason::Dependency) {
+        line.push_str(" - dependency");
+    }
+    line
+}
+
+fn format_repository(repository: &Repository) -> String {
+    let mut line = match &repository.tag {
+        Some(tag) => format!("@{tag} {}", repository.url),
+        None => repository.url.clone(),
+    };
+    for field in [&repository.suites, &repository.components] {
+        if !field.is_empty() {
+            line.push(' ');
+            line.push_str(&field.join(" "));
+        }
+    }
+    if !repository.enabled {
+        line.push_str(" (disabled)");
+    }
+    line
+}
+
+fn format_search_result(result: &SearchResult) -> String {
+    let mut line = match &result.version {
+        Some(version) => format!("{} {version}", result.name),
+        None => result.name.clone(),
+    };
+    if let Some(repository) = &result.repository {
+        line.push_str(&format!(" [{repository}]"));
+    }
+    if let Some(description) = &result.description {
+        line.push_str(&format!(" - {description}"));
+    }
+    line
+}
@@ -0,0 +1,91 @@
+//! Names the tools are exposed under
+//!
+//! MCP gateways aggregating several servers put every tool in one list, where
+//! an `install_package` of this server clashes with the tools of others. The
+//! handler can expose its tools with a prefix (`alpine_install_package`) or
+//! under aliases chosen per tool. Calls are mapped back to the built-in names
+//! before they are dispatched, so audit entries, hooks and notifications keep
+//! using the built-in names.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::operation::Operation;
+
+/// Longest tool name MCP clients accept
+const MAX_TOOL_NAME_LENGTH: usize = 64;
+
+/// Prefix and aliases applied to the built-in tool names
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ToolNames {
+    prefix: String,
+    aliases: BTreeMap<String, String>,
+}
+
+impl ToolNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expose every tool without an alias as `prefix` followed by its name
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Expose the built-in tool `name` as `alias`, without the prefix
+    pub fn with_alias(mut self, name: impl Into<String>, alias: impl Into<String>) -> Self {
+        self.aliases.insert(name.into(), alias.into());
+        self
+    }
+
+    /// Whether every tool keeps its built-in name
+    pub fn is_default(&self) -> bool {
+        self.prefix.is_empty() && self.aliases.is_empty()
+    }
+
+    /// Name the built-in tool `name` is exposed as
+    pub fn exposed(&self, name: &str) -> String {
+        match self.aliases.get(name) {
+            Some(alias) => alias.clone(),
+            None => format!("{}{name}", self.prefix),
+        }
+    }
+
+    /// Built-in tool exposed as `exposed`, if any
+    pub fn internal(&self, exposed: &str) -> Option<&'static str> {
+        Operation::TOOL_NAMES
+            .iter()
+            .copied()
+            .find(|name| self.exposed(name) == exposed)
+    }
+
+    /// Check that aliases name built-in tools and every exposed name is a
+    /// valid, unique tool name
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(name) = self
+            .aliases
+            .keys()
+            .find(|name| !Operation::TOOL_NAMES.contains(&name.as_str()))
+        {
+            return Err(format!("'{name}' is not a tool of this server"));
+        }
+        let mut exposed = HashSet::new();
+        for name in Operation::TOOL_NAMES {
+            let exposed_name = self.exposed(name);
+            if exposed_name.is_empty()
+                || exposed_name.len() > MAX_TOOL_NAME_LENGTH
+                || !exposed_name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(format!(
+                    "'{exposed_name}' is not a valid tool name: use 1 to {MAX_TOOL_NAME_LENGTH} letters, digits, '_' or '-'"
+                ));
+            }
+            if !exposed.insert(exposed_name.clone()) {
+                return Err(format!("more than one tool is named '{exposed_name}'"));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+//! Normalized forms of search queries
+//!
+//! Package names are lowercase and their separators differ between
+//! ecosystems: agents search for `PostgreSQL`, `python_dev` or `golang` and
+//! find nothing, then go looking for repositories to add. When a search
+//! returns no packages, the handler searches again with the
+//! [`query_variants`] of the query: case-folded, with `_` and `-`
+//! exchanged, and through a table of common aliases.
+
+/// Names agents commonly search for, with the package name distributions
+/// use
+const ALIASES: &[(&str, &str)] = &[
+    ("golang", "go"),
+    ("node", "nodejs"),
+    ("node.js", "nodejs"),
+    ("pgsql", "postgresql"),
+    ("postgres", "postgresql"),
+    ("psql", "postgresql-client"),
+    ("python", "python3"),
+    ("python-dev", "python3-dev"),
+    ("python-pip", "python3-pip"),
+    ("pip", "python3-pip"),
+    ("pip3", "python3-pip"),
+    ("build-essential", "build-base"),
+    ("build-base", "build-essential"),
+    ("openssl-dev", "libssl-dev"),
+    ("libssl-dev", "openssl-dev"),
+    ("vim-enhanced", "vim"),
+    ("httpd", "apache2"),
+];
+
+/// Queries to search for when `query` finds nothing, most faithful first
+///
+/// The query itself is not included. Aliases are looked up for the query
+/// and each of its normalized forms.
+pub fn query_variants(query: &str) -> Vec<String> {
+    let folded = query.to_lowercase();
+    let mut forms = vec![
+        folded.clone(),
+        folded.replace('_', "-"),
+        folded.replace('-', "_"),
+    ];
+    for form in forms.clone() {
+        if let Some((_, alias)) = ALIASES.iter().find(|(name, _)| *name == form) {
+            forms.push(alias.to_string());
+        }
+    }
+
+    let mut variants: Vec<String> = Vec::new();
+    for form in forms {
+        if form != query && !variants.contains(&form) {
+            variants.push(form);
+        }
+    }
+    variants
+}
@@ -0,0 +1,214 @@
+//! Chat-ops notifications of installs
+//!
+//! Platform teams want to see what agents install without tailing logs. A
+//! [`NotificationSink`] receives a [`Notification`] after every install call,
+//! whether it succeeded, failed or was rejected, naming the packages, the
+//! MCP session and the client that asked for it. Sinks take either the
+//! notification as JSON, for generic webhooks, or a Slack-compatible
+//! `{"text": ...}` message, which Slack, Mattermost and Rocket.Chat
+//! incoming webhooks accept.
+//!
+//! Notifications are delivered in the background: a slow or failing sink
+//! never delays or fails the tool call.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::audit::AuditOutcome;
+use crate::identity::ClientIdentity;
+use crate::operation::Operation;
+
+/// How long a delivery may take
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Payload a sink receives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotificationFormat {
+    /// The [`Notification`] as JSON
+    Json,
+    /// A Slack incoming webhook message
+    Slack,
+}
+
+/// Webhook notified of installs
+#[derive(Clone, Debug)]
+pub struct NotificationSink {
+    url: String,
+    format: NotificationFormat,
+    client: reqwest::Client,
+}
+
+/// A package named by a notified call
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct NotifiedPackage {
+    pub name: String,
+    /// Installed version, or the requested one when the call failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// An install or removal made or attempted through the server
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    pub tool: &'static str,
+    pub outcome: AuditOutcome,
+    /// Requested packages
+    pub packages: Vec<NotifiedPackage>,
+    /// Packages the install removed, e.g. conflicting ones
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<NotifiedPackage>,
+    /// MCP session of the call, over streamable HTTP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// Client that made the call, when identified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initiator: Option<ClientIdentity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl NotificationSink {
+    /// Sink receiving notifications as JSON
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_format(url, NotificationFormat::Json)
+    }
+
+    /// Sink receiving Slack-compatible messages
+    pub fn slack(url: impl Into<String>) -> Self {
+        Self::with_format(url, NotificationFormat::Slack)
+    }
+
+    pub fn with_format(url: impl Into<String>, format: NotificationFormat) -> Self {
+        Self {
+            url: url.into(),
+            format,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Deliver `notification`, logging failures
+    pub(crate) async fn deliver(&self, notification: &Notification) {
+        let request = self.client.post(&self.url).timeout(DELIVERY_TIMEOUT);
+        let request = match self.format {
+            NotificationFormat::Json => request.json(notification),
+            NotificationFormat::Slack => {
+                request.json(&serde_json::json!({ "text": notification.summary() }))
+            }
+        };
+        let delivery = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = delivery {
+            tracing::warn!("Failed to deliver notification to {}: {err}", self.url);
+        }
+    }
+}
+
+impl Notification {
+    /// Notification of a call of `operation`, if it installs packages
+    ///
+    /// `result` is the JSON content of a successful call, giving the
+    /// installed version and removed packages of single installs.
+    pub fn new(
+        operation: &Operation,
+        outcome: AuditOutcome,
+        result: Option<&Value>,
+        error: Option<String>,
+    ) -> Option<Self> {
+        let requested = |name: &str, version: Option<&str>| NotifiedPackage {
+            name: name.to_string(),
+            version: version.map(str::to_string),
+        };
+        let mut packages = match operation {
+            Operation::Install(options) => vec![requested(&options.package, None)],
+            Operation::InstallVersion(options) => {
+                vec![requested(&options.package, Some(&options.version))]
+            }
+            Operation::InstallVersions { packages } => packages
+                .iter()
+                .map(|options| requested(&options.package, Some(&options.version)))
+                .collect(),
+            Operation::InstallOnTargets(options) => {
+                vec![requested(&options.package, options.version.as_deref())]
+            }
+            Operation::InstallFromLockfile(lockfile) => lockfile
+                .packages
+                .iter()
+                .filter(|locked| !locked.dependency)
+                .map(|locked| requested(&locked.package.name, Some(&locked.package.version)))
+                .collect(),
+            _ => return None,
+        };
+
+        let mut removed = Vec::new();
+        if let (Some(result), [package]) = (result, packages.as_mut_slice()) {
+            if let Some(version) = result["version"].as_str() {
+                package.version = Some(version.to_string());
+            }
+            removed = result["removed"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|removed| {
+                    Some(requested(
+                        removed["name"].as_str()?,
+                        removed["version"].as_str(),
+                    ))
+                })
+                .collect();
+        }
+
+        Some(Self {
+            tool: operation.tool_name(),
+            outcome,
+            packages,
+            removed,
+            session: None,
+            initiator: None,
+            error,
+            timestamp: Utc::now(),
+        })
+    }
+
+    /// One-line description of the notification for chat messages
+    pub fn summary(&self) -> String {
+        let list = |packages: &[NotifiedPackage]| {
+            packages
+                .iter()
+                .map(|package| match &package.version {
+                    Some(version) => format!("`{}` {version}", package.name),
+                    None => format!("`{}`", package.name),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut summary = match self.outcome {
+            AuditOutcome::Succeeded => format!("Installed {}", list(&self.packages)),
+            AuditOutcome::Failed => format!("Failed to install {}", list(&self.packages)),
+            AuditOutcome::Rejected => format!("Rejected install of {}", list(&self.packages)),
+        };
+        if !self.removed.is_empty() {
+            summary.push_str(&format!(", removing {}", list(&self.removed)));
+        }
+        summary.push_str(&format!(" with `{}`", self.tool));
+        if let Some(initiator) = &self.initiator {
+            summary.push_str(&format!(" for {}", initiator.subject));
+        }
+        if let Some(session) = &self.session {
+            summary.push_str(&format!(" (session {session})"));
+        }
+        if let Some(error) = &self.error {
+            summary.push_str(&format!(": {error}"));
+        }
+        summary
+    }
+}
@@ -0,0 +1,134 @@
+//! Offline mode for air-gapped environments
+//!
+//! Disconnected networks only reach internal mirrors of the public
+//! repositories. [`OfflineMirrors`] maps public repository URLs to those
+//! mirrors (`https://dl-cdn.alpinelinux.org/alpine` to
+//! `https://mirror.example.com/alpine`), and may list internal repositories
+//! that mirror nothing. In offline mode:
+//!
+//! - the backends move the system's repositories and their default mirrors
+//!   onto the internal mirrors, dropping the ones without a mirror, so
+//!   `refresh_repositories` and installs only contact internal hosts;
+//! - `repository` arguments naming any other host are rejected with a
+//!   policy error before anything runs.
+
+use super::allowlist::repository_urls;
+use crate::error::PackageManagerError;
+
+/// A public URL prefix and the internal mirror serving it
+#[derive(Clone, Debug)]
+struct Mirror {
+    public: Option<String>,
+    internal: String,
+}
+
+/// Internal mirrors replacing public repositories
+#[derive(Clone, Debug, Default)]
+pub struct OfflineMirrors {
+    mirrors: Vec<Mirror>,
+}
+
+impl OfflineMirrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve repositories below `public` from `internal` instead
+    pub fn mirror(mut self, public: impl Into<String>, internal: impl Into<String>) -> Self {
+        self.mirrors.push(Mirror {
+            public: Some(trim(public.into())),
+            internal: trim(internal.into()),
+        });
+        self
+    }
+
+    /// Reach the internal repository below `internal`, which mirrors nothing
+    pub fn internal(mut self, internal: impl Into<String>) -> Self {
+        self.mirrors.push(Mirror {
+            public: None,
+            internal: trim(internal.into()),
+        });
+        self
+    }
+
+    /// Add a mirror given as `PUBLIC=INTERNAL`, or an internal repository
+    /// given as its URL
+    pub fn push_spec(&mut self, spec: &str) {
+        let mirror = match spec.split_once('=') {
+            Some((public, internal)) if public.contains("://") && internal.contains("://") => {
+                Mirror {
+                    public: Some(trim(public.to_string())),
+                    internal: trim(internal.to_string()),
+                }
+            }
+            _ => Mirror {
+                public: None,
+                internal: trim(spec.to_string()),
+            },
+        };
+        self.mirrors.push(mirror);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mirrors.is_empty()
+    }
+
+    /// Base URLs of the internal mirrors, in configured order
+    pub fn internal_urls(&self) -> impl Iterator<Item = &str> {
+        self.mirrors.iter().map(|mirror| mirror.internal.as_str())
+    }
+
+    /// Whether `url` is below an internal mirror
+    pub fn allows(&self, url: &str) -> bool {
+        self.mirrors
+            .iter()
+            .any(|mirror| below(url, &mirror.internal).is_some())
+    }
+
+    /// `url` as reached in offline mode
+    ///
+    /// URLs below an internal mirror and local paths are kept, URLs below a
+    /// mirrored public prefix are moved onto its mirror. Other URLs cannot be
+    /// reached and give `None`.
+    pub fn rewrite(&self, url: &str) -> Option<String> {
+        if !url.contains("://") || url.starts_with("file://") || self.allows(url) {
+            return Some(url.to_string());
+        }
+        self.mirrors.iter().find_map(|mirror| {
+            let rest = below(url, mirror.public.as_deref()?)?;
+            Some(format!("{}{rest}", mirror.internal))
+        })
+    }
+
+    /// Reject `repository` unless every URL it names can be reached offline
+    ///
+    /// Paths of APT sources files are rejected too, since the hosts they
+    /// name cannot be checked.
+    pub fn check(&self, repository: &str) -> Result<(), PackageManagerError> {
+        let definition = repository.trim();
+        let unreachable = repository_urls(definition)
+            .into_iter()
+            .find(|url| self.rewrite(url).is_none());
+        let message = match unreachable {
+            Some(url) => format!(
+                "the server is offline and {url} is not on one of its internal mirrors ({})",
+                self.internal_urls().collect::<Vec<_>>().join(", ")
+            ),
+            None if definition.ends_with(".list") || definition.ends_with(".sources") => format!(
+                "the server is offline and the hosts of sources file {definition} cannot be checked, pass the repository as a URL or source entry"
+            ),
+            None => return Ok(()),
+        };
+        Err(PackageManagerError::PermissionDenied { message })
+    }
+}
+
+/// The part of `url` after `prefix`, when `url` is below it
+fn below<'a>(url: &'a str, prefix: &str) -> Option<&'a str> {
+    url.strip_prefix(prefix)
+        .filter(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+}
+
+fn trim(url: String) -> String {
+    url.trim_end_matches('/').to_string()
+}
@@ -0,0 +1,361 @@
+//! Complete output of mutating operations, kept on disk
+//!
+//! Tool results carry the end of what the package manager printed, progress
+//! notifications and job output vanish with the session, and the audit log
+//! records what was asked, not what happened. When an install failed, the
+//! person reviewing it later needs every line the package manager printed.
+//! With an [`OperationLogStore`], the handler writes the stdout and stderr
+//! lines of every mutating operation to a JSON file named after its
+//! `operation_id`, returns that id with the result, and serves the log
+//! through the `get_operation_log` tool and as an MCP resource.
+//!
+//! The store keeps the most recent logs up to a count and an age, deleting
+//! older ones as new ones are written. Logs hold whatever the package
+//! manager printed, so the directory is only accessible by the server's
+//! user and each log is written to a temporary file renamed into place.
+
+use std::future::Future;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rmcp::ErrorData as McpError;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use super::exec::{self, OutputStream};
+use crate::audit::AuditOutcome;
+use crate::error::ErrorCode;
+
+/// Prefix of the URIs of operation logs read as resources
+pub const OPERATION_LOG_URI_PREFIX: &str = "operation-log://";
+
+/// Logs kept by default
+pub const DEFAULT_MAX_LOGS: usize = 1000;
+
+/// Age after which logs are deleted by default
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Lines kept per log, the first ones are dropped beyond
+const MAX_LOG_LINES: usize = 100_000;
+
+/// Most lines `get_operation_log` returns per call
+pub const MAX_LOG_PAGE: usize = 1000;
+
+/// Logs listed as resources, the most recent first
+const MAX_LISTED_LOGS: usize = 100;
+
+/// A line printed by a command of the operation
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLine {
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+/// Output of one mutating operation
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OperationLog {
+    pub operation_id: String,
+    pub tool: String,
+    /// Subject of the client that made the call, when identified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// MCP session the call was made in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub outcome: AuditOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Lines dropped from the start of a longer output
+    #[serde(default)]
+    pub dropped_lines: usize,
+    pub lines: Vec<LogLine>,
+}
+
+/// Page of the lines of a log, as returned by `get_operation_log`
+#[derive(Clone, Debug, Serialize)]
+pub struct OperationLogPage {
+    pub operation_id: String,
+    pub tool: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub outcome: AuditOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Lines in the log
+    pub total_lines: usize,
+    pub offset: usize,
+    /// Offset of the line after the last one returned
+    pub next_offset: usize,
+    pub lines: Vec<LogLine>,
+}
+
+/// Directory of operation logs
+#[derive(Debug)]
+pub struct OperationLogStore {
+    dir: PathBuf,
+    max_logs: usize,
+    max_age: Option<Duration>,
+    next_id: AtomicU64,
+    /// Held while a log is written and old ones deleted
+    writing: tokio::sync::Mutex<()>,
+}
+
+impl OperationLogStore {
+    /// Keep logs in `dir`, creating it if needed, only accessible by the
+    /// server's user
+    pub fn open(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+        // The directory may have existed with looser permissions
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        Ok(Self {
+            dir,
+            max_logs: DEFAULT_MAX_LOGS,
+            max_age: Some(DEFAULT_MAX_AGE),
+            next_id: AtomicU64::new(0),
+            writing: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Keep at most `max_logs` logs, at least one
+    pub fn with_max_logs(mut self, max_logs: usize) -> Self {
+        self.max_logs = max_logs.max(1);
+        self
+    }
+
+    /// Delete logs older than `max_age`, or never with `None`
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Id of a new operation, ordered by the time it started
+    pub(crate) fn new_id(&self) -> String {
+        format!(
+            "op-{}-{}",
+            Utc::now().format("%Y%m%dT%H%M%S%6f"),
+            self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+        )
+    }
+
+    /// Write `log`, then delete the logs beyond the retention limits
+    ///
+    /// Failures are logged rather than failing the operation, which already
+    /// ran.
+    pub(crate) async fn write(&self, log: &OperationLog) {
+        let _writing = self.writing.lock().await;
+        let path = self.path(&log.operation_id);
+        let written = match serde_json::to_vec(log) {
+            Ok(contents) => self.write_file(&log.operation_id, &contents).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = written {
+            tracing::warn!("Failed to write operation log {}: {err}", path.display());
+        }
+        if let Err(err) = self.prune().await {
+            tracing::warn!(
+                "Failed to delete old operation logs in {}: {err}",
+                self.dir.display()
+            );
+        }
+    }
+
+    /// Write the log file of `operation_id` with mode 600, through a
+    /// temporary file so readers never see a partial log
+    async fn write_file(&self, operation_id: &str, contents: &[u8]) -> std::io::Result<()> {
+        // Not listed: ids start with `op-`
+        let temporary = self.dir.join(format!(".{operation_id}.json.tmp"));
+        let written = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&temporary)
+                .await?;
+            file.write_all(contents).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temporary, self.path(operation_id)).await
+        }
+        .await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&temporary).await;
+        }
+        written
+    }
+
+    /// Log `operation_id`, if it was made by `client`
+    pub(crate) async fn read(
+        &self,
+        operation_id: &str,
+        client: Option<&str>,
+    ) -> Result<OperationLog, McpError> {
+        // Ids are file names, never paths
+        let valid = operation_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+        let contents = match valid {
+            true => tokio::fs::read(self.path(operation_id)).await.ok(),
+            false => None,
+        };
+        contents
+            .and_then(|contents| serde_json::from_slice::<OperationLog>(&contents).ok())
+            .filter(|log| log.client.as_deref() == client)
+            .ok_or_else(|| {
+                McpError::invalid_params(
+                    format!("Operation log '{operation_id}' not found"),
+                    Some(serde_json::json!({
+                        "error_type": "operation_log_not_found",
+                        "error_code": ErrorCode::NotFound,
+                        "operation_id": operation_id,
+                    })),
+                )
+            })
+    }
+
+    /// Ids of the most recent logs made by `client`, the most recent first
+    pub(crate) async fn recent(&self, client: Option<&str>) -> std::io::Result<Vec<String>> {
+        /// The part of a log naming who made it
+        #[derive(Deserialize)]
+        struct Owner {
+            #[serde(default)]
+            client: Option<String>,
+        }
+
+        let mut ids = Vec::new();
+        for (id, _) in self.entries().await?.into_iter().rev() {
+            if ids.len() == MAX_LISTED_LOGS {
+                break;
+            }
+            // Logs deleted or unreadable meanwhile are skipped
+            let Ok(contents) = tokio::fs::read(self.path(&id)).await else {
+                continue;
+            };
+            if serde_json::from_slice::<Owner>(&contents)
+                .is_ok_and(|owner| owner.client.as_deref() == client)
+            {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Delete the logs beyond `max_logs` and older than `max_age`
+    async fn prune(&self) -> std::io::Result<()> {
+        let entries = self.entries().await?;
+        let excess = entries.len().saturating_sub(self.max_logs);
+        for (position, (id, modified)) in entries.iter().enumerate() {
+            let expired = self.max_age.is_some_and(|max_age| {
+                modified
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age > max_age)
+            });
+            if position < excess || expired {
+                tokio::fs::remove_file(self.path(id)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ids of the logs with their modification time, the oldest first
+    async fn entries(&self) -> std::io::Result<Vec<(String, Option<std::time::SystemTime>)>> {
+        let mut entries = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name();
+            let Some(id) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+                .filter(|id| id.starts_with("op-"))
+            else {
+                continue;
+            };
+            let modified = entry
+                .metadata()
+                .await
+                .ok()
+                .and_then(|metadata| metadata.modified().ok());
+            entries.push((id.to_string(), modified));
+        }
+        // Ids start with the time the operation started
+        entries.sort();
+        Ok(entries)
+    }
+
+    fn path(&self, operation_id: &str) -> PathBuf {
+        self.dir.join(format!("{operation_id}.json"))
+    }
+}
+
+impl OperationLog {
+    /// Lines from `offset`, at most `limit`
+    pub fn page(&self, offset: usize, limit: usize) -> OperationLogPage {
+        let offset = offset.min(self.lines.len());
+        let lines: Vec<LogLine> = self
+            .lines
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        OperationLogPage {
+            operation_id: self.operation_id.clone(),
+            tool: self.tool.clone(),
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            outcome: self.outcome,
+            error: self.error.clone(),
+            total_lines: self.lines.len(),
+            offset,
+            next_offset: offset + lines.len(),
+            lines,
+        }
+    }
+}
+
+/// Run `call`, collecting the lines its commands print while still
+/// forwarding them to the current output sink
+///
+/// Returns the lines dropped beyond [`MAX_LOG_LINES`] with the others.
+pub(crate) async fn capture<F: Future>(call: F) -> (F::Output, usize, Vec<LogLine>) {
+    let outer = exec::current_sink();
+    let (sender, mut receiver) = mpsc::unbounded_channel::<exec::OutputLine>();
+    let mut lines = std::collections::VecDeque::new();
+    let mut dropped = 0;
+    let mut keep = |line: exec::OutputLine| {
+        if let Some(outer) = &outer {
+            let _ = outer.send(line.clone());
+        }
+        if lines.len() >= MAX_LOG_LINES {
+            lines.pop_front();
+            dropped += 1;
+        }
+        lines.push_back(LogLine {
+            stream: line.stream,
+            line: line.line,
+        });
+    };
+    let mut call = std::pin::pin!(exec::stream_output(sender, call));
+    let output = loop {
+        tokio::select! {
+            output = &mut call => break output,
+            Some(line) = receiver.recv() => keep(line),
+        }
+    };
+    while let Ok(line) = receiver.try_recv() {
+        keep(line);
+    }
+    (output, dropped, lines.into())
+}
@@ -0,0 +1,468 @@
+//! Vulnerability lookups in the OSV.dev database
+//!
+//! Hosts rarely have a scanner matching their distribution's security
+//! tracker, and agents want to know whether a package they installed or are
+//! about to install has known advisories. OSV.dev aggregates the Alpine
+//! secdb and the Debian security tracker under ecosystems such as
+//! `Alpine:v3.22` and `Debian:12`: [`OsvClient`] asks its batch API which
+//! advisories affect each package version, then fetches every advisory once
+//! for its summary, severity and fixed versions.
+//!
+//! Advisories are filed against source packages (`openssl` rather than
+//! `libssl3`), so callers query with the source package names.
+//!
+//! A [`VulnerabilityGate`] checks the version an install is about to pull in
+//! and blocks it while advisories at or above a [`Severity`] have no fix.
+//! Severities come from the advisory's rating, or from the base score of its
+//! CVSS v3 vector; advisories without either are not gated.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// OSV API used by default
+pub const DEFAULT_OSV_API: &str = "https://api.osv.dev";
+
+/// How long a query to the API may take
+const QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Most queries the batch endpoint accepts in one request
+const MAX_BATCH: usize = 1000;
+
+/// Advisories fetched at the same time
+const CONCURRENT_FETCHES: usize = 16;
+
+/// OSV.dev client looking up advisories of package versions
+#[derive(Clone, Debug)]
+pub struct OsvClient {
+    url: String,
+    client: reqwest::Client,
+}
+
+/// A known vulnerability affecting a package version
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vulnerability {
+    /// OSV identifier, e.g. `CVE-2025-0725` or `DSA-5621-1`
+    pub id: String,
+    /// Other identifiers of the same vulnerability
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    /// Severity rating (`HIGH`) or CVSS vector, when the advisory has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<String>,
+    /// Versions of the package fixing the vulnerability
+    #[serde(default)]
+    pub fixed_versions: Vec<String>,
+}
+
+/// Advisories affecting one checked package
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PackageVulnerabilities {
+    pub package: String,
+    pub version: String,
+    /// Source package the advisories were looked up for, when its name
+    /// differs from the package's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub vulnerabilities: Vec<Vulnerability>,
+}
+
+#[derive(Deserialize)]
+struct BatchResponse {
+    results: Vec<BatchResult>,
+}
+
+#[derive(Deserialize)]
+struct BatchResult {
+    #[serde(default)]
+    vulns: Vec<VulnId>,
+    #[serde(default)]
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VulnId {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Advisory {
+    id: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    summary: Option<String>,
+    #[serde(default)]
+    severity: Vec<AdvisorySeverity>,
+    #[serde(default)]
+    affected: Vec<Affected>,
+    #[serde(default)]
+    database_specific: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct AdvisorySeverity {
+    score: String,
+}
+
+#[derive(Deserialize)]
+struct Affected {
+    package: AffectedPackage,
+    #[serde(default)]
+    ranges: Vec<AffectedRange>,
+}
+
+#[derive(Deserialize)]
+struct AffectedPackage {
+    name: String,
+    ecosystem: String,
+}
+
+#[derive(Deserialize)]
+struct AffectedRange {
+    #[serde(default)]
+    events: Vec<serde_json::Value>,
+}
+
+impl OsvClient {
+    pub fn new() -> Self {
+        Self::with_url(DEFAULT_OSV_API)
+    }
+
+    /// Query the OSV API at `url`, e.g. a self-hosted mirror
+    pub fn with_url(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into().trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Advisories affecting each `(name, version)` of `ecosystem`, in order
+    pub(crate) async fn vulnerabilities(
+        &self,
+        ecosystem: &str,
+        packages: &[(String, String)],
+    ) -> Result<Vec<Vec<Vulnerability>>, reqwest::Error> {
+        let mut ids: Vec<Vec<String>> = vec![Vec::new(); packages.len()];
+        for (chunk, queried) in packages.chunks(MAX_BATCH).enumerate() {
+            let offset = chunk * MAX_BATCH;
+            let mut pending: Vec<(usize, Option<String>)> = (0..queried.len())
+                .map(|index| (offset + index, None))
+                .collect();
+            // Packages with more advisories than fit a response are queried
+            // again with their page token
+            while !pending.is_empty() {
+                let queries: Vec<_> = pending
+                    .iter()
+                    .map(|(index, page_token)| {
+                        let (name, version) = &packages[*index];
+                        let mut query = json!({
+                            "package": { "name": name, "ecosystem": ecosystem },
+                            "version": version,
+                        });
+                        if let Some(page_token) = page_token {
+                            query["page_token"] = json!(page_token);
+                        }
+                        query
+                    })
+                    .collect();
+                let response: BatchResponse = self
+                    .client
+                    .post(format!("{}/v1/querybatch", self.url))
+                    .json(&json!({ "queries": queries }))
+                    .timeout(QUERY_TIMEOUT)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+
+                let mut next = Vec::new();
+                for ((index, _), result) in pending.into_iter().zip(response.results) {
+                    ids[index].extend(result.vulns.into_iter().map(|vuln| vuln.id));
+                    if let Some(page_token) = result.next_page_token {
+                        next.push((index, Some(page_token)));
+                    }
+                }
+                pending = next;
+            }
+        }
+
+        let mut unique: Vec<&String> = ids.iter().flatten().collect();
+        unique.sort();
+        unique.dedup();
+        let mut advisories = HashMap::new();
+        for group in unique.chunks(CONCURRENT_FETCHES) {
+            let mut fetches = tokio::task::JoinSet::new();
+            for id in group {
+                let request = self
+                    .client
+                    .get(format!("{}/v1/vulns/{id}", self.url))
+                    .timeout(QUERY_TIMEOUT);
+                fetches.spawn(async move {
+                    request
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json::<Advisory>()
+                        .await
+                });
+            }
+            while let Some(fetched) = fetches.join_next().await {
+                // The tasks only end early when the runtime shuts down
+                if let Ok(advisory) = fetched {
+                    let advisory = advisory?;
+                    advisories.insert(advisory.id.clone(), advisory);
+                }
+            }
+        }
+
+        Ok(packages
+            .iter()
+            .zip(&ids)
+            .map(|((name, _), ids)| {
+                ids.iter()
+                    .filter_map(|id| advisories.get(id))
+                    .map(|advisory| vulnerability(advisory, ecosystem, name))
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+impl Default for OsvClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What `advisory` says about the package `name` of `ecosystem`
+fn vulnerability(advisory: &Advisory, ecosystem: &str, name: &str) -> Vulnerability {
+    let severity = advisory
+        .database_specific
+        .as_ref()
+        .and_then(|specific| specific.get("severity"))
+        .and_then(|severity| severity.as_str())
+        .map(str::to_string)
+        .or_else(|| {
+            advisory
+                .severity
+                .first()
+                .map(|severity| severity.score.clone())
+        });
+
+    let mut fixed_versions: Vec<String> = advisory
+        .affected
+        .iter()
+        .filter(|affected| affected.package.name == name && affected.package.ecosystem == ecosystem)
+        .flat_map(|affected| &affected.ranges)
+        .flat_map(|range| &range.events)
+        .filter_map(|event| event.get("fixed")?.as_str())
+        .map(str::to_string)
+        .collect();
+    fixed_versions.dedup();
+
+    Vulnerability {
+        id: advisory.id.clone(),
+        aliases: advisory.aliases.clone(),
+        summary: advisory.summary.clone(),
+        severity,
+        fixed_versions,
+    }
+}
+
+/// Qualitative severity of a vulnerability
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+            Self::Critical => "critical",
+        })
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(severity: &str) -> Result<Self, Self::Err> {
+        match severity.trim().to_ascii_lowercase().as_str() {
+            "low" | "negligible" | "unimportant" => Ok(Self::Low),
+            "medium" | "moderate" => Ok(Self::Medium),
+            "high" | "important" => Ok(Self::High),
+            "critical" => Ok(Self::Critical),
+            _ => Err(format!(
+                "unknown severity '{severity}', expected low, medium, high or critical"
+            )),
+        }
+    }
+}
+
+impl Severity {
+    /// Severity of a CVSS base score, `None` for scores of 0
+    fn of_score(score: f64) -> Option<Self> {
+        match score {
+            score if score >= 9.0 => Some(Self::Critical),
+            score if score >= 7.0 => Some(Self::High),
+            score if score >= 4.0 => Some(Self::Medium),
+            score if score > 0.0 => Some(Self::Low),
+            _ => None,
+        }
+    }
+}
+
+impl Vulnerability {
+    /// Severity of the advisory's rating or CVSS v3 vector, if known
+    pub fn severity_level(&self) -> Option<Severity> {
+        let severity = self.severity.as_deref()?;
+        if severity.starts_with("CVSS:3") {
+            cvss3_base_score(severity).and_then(Severity::of_score)
+        } else {
+            severity.parse().ok()
+        }
+    }
+}
+
+/// Base score of a CVSS v3.0 or v3.1 vector, e.g.
+/// `CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H`
+fn cvss3_base_score(vector: &str) -> Option<f64> {
+    let metrics: HashMap<&str, &str> = vector
+        .split('/')
+        .skip(1)
+        .filter_map(|metric| metric.split_once(':'))
+        .collect();
+    let changed = match *metrics.get("S")? {
+        "U" => false,
+        "C" => true,
+        _ => return None,
+    };
+    let impact = |metric| match *metrics.get(metric)? {
+        "H" => Some(0.56),
+        "L" => Some(0.22),
+        "N" => Some(0.0),
+        _ => None,
+    };
+    let attack_vector = match *metrics.get("AV")? {
+        "N" => 0.85,
+        "A" => 0.62,
+        "L" => 0.55,
+        "P" => 0.2,
+        _ => return None,
+    };
+    let attack_complexity = match *metrics.get("AC")? {
+        "L" => 0.77,
+        "H" => 0.44,
+        _ => return None,
+    };
+    let privileges = match (*metrics.get("PR")?, changed) {
+        ("N", _) => 0.85,
+        ("L", false) => 0.62,
+        ("L", true) => 0.68,
+        ("H", false) => 0.27,
+        ("H", true) => 0.5,
+        _ => return None,
+    };
+    let interaction = match *metrics.get("UI")? {
+        "N" => 0.85,
+        "R" => 0.62,
+        _ => return None,
+    };
+
+    let base: f64 = 1.0 - (1.0 - impact("C")?) * (1.0 - impact("I")?) * (1.0 - impact("A")?);
+    let impact = if changed {
+        7.52 * (base - 0.029) - 3.25 * (base - 0.02).powi(15)
+    } else {
+        6.42 * base
+    };
+    if impact <= 0.0 {
+        return Some(0.0);
+    }
+    let exploitability = 8.22 * attack_vector * attack_complexity * privileges * interaction;
+    let score: f64 = if changed {
+        1.08 * (impact + exploitability)
+    } else {
+        impact + exploitability
+    };
+    Some(round_up(score.min(10.0)))
+}
+
+/// Round up to one decimal as the CVSS v3.1 specification does, avoiding
+/// floating-point artifacts
+fn round_up(value: f64) -> f64 {
+    let scaled = (value * 100_000.0).round() as u64;
+    if scaled.is_multiple_of(10_000) {
+        scaled as f64 / 100_000.0
+    } else {
+        (scaled / 10_000 + 1) as f64 / 10.0
+    }
+}
+
+/// Policy blocking installs of versions with severe known vulnerabilities
+#[derive(Clone, Copy, Debug)]
+pub struct VulnerabilityGate {
+    min_severity: Severity,
+    block_fixed: bool,
+    allow_override: bool,
+}
+
+impl VulnerabilityGate {
+    /// Block installs affected by unfixed vulnerabilities of `min_severity`
+    /// or above
+    pub fn new(min_severity: Severity) -> Self {
+        Self {
+            min_severity,
+            block_fixed: false,
+            allow_override: false,
+        }
+    }
+
+    /// Also block vulnerabilities fixed in another version, e.g. for pinned
+    /// installs of an old version
+    pub fn block_fixed(mut self) -> Self {
+        self.block_fixed = true;
+        self
+    }
+
+    /// Let installs passing `override_vulnerabilities` through
+    pub fn allow_override(mut self) -> Self {
+        self.allow_override = true;
+        self
+    }
+
+    pub fn allows_override(&self) -> bool {
+        self.allow_override
+    }
+
+    pub fn min_severity(&self) -> Severity {
+        self.min_severity
+    }
+
+    /// Vulnerabilities among `vulnerabilities` that block an install
+    pub fn blocking(&self, vulnerabilities: Vec<Vulnerability>) -> Vec<Vulnerability> {
+        vulnerabilities
+            .into_iter()
+            .filter(|vulnerability| {
+                (self.block_fixed || vulnerability.fixed_versions.is_empty())
+                    && vulnerability
+                        .severity_level()
+                        .is_some_and(|severity| severity >= self.min_severity)
+            })
+            .collect()
+    }
+}
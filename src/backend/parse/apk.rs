@@ -0,0 +1,595 @@
+//! Parsers for `apk` output
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use super::search_entry;
+use crate::backend::{
+    InstallReason, InstalledPackage, LockedPackage, PackageLicense, PinnedPackage, Repository,
+    SearchResult,
+};
+
+/// Split an APK package string (`name-version-rN`) into name and version
+///
+/// Package names may contain hyphens and digits (`py3-pip`, `font-noto-cjk`,
+/// `llvm19-libs`), so the version is always taken from the last two
+/// hyphen-separated fields.
+pub fn split_package_version(package: &str) -> Option<(&str, &str)> {
+    let (rest, release) = package.rsplit_once('-')?;
+    let (name, version) = rest.rsplit_once('-')?;
+
+    let is_release = release
+        .strip_prefix('r')
+        .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()));
+    let is_version = version.starts_with(|c: char| c.is_ascii_digit());
+
+    if name.is_empty() || !is_release || !is_version {
+        return None;
+    }
+
+    Some((name, &package[name.len() + 1..]))
+}
+
+/// Whether the line is a diagnostic rather than command output
+fn is_diagnostic(line: &str) -> bool {
+    line.starts_with("fetch ") || line.starts_with("WARNING:") || line.starts_with("ERROR:")
+}
+
+/// Parse `apk search` output
+///
+/// Lines are `name-version` on their own, or followed by ` - description`
+/// when descriptions are requested (and by default with apk-tools 3).
+pub fn parse_search(stdout: &str) -> Vec<SearchResult> {
+    stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !is_diagnostic(line))
+        .filter_map(|line| {
+            let (package, description) = match line.split_once(" - ") {
+                Some((package, description)) => (package.trim(), Some(description)),
+                None => (line, None),
+            };
+            let (name, version) = match split_package_version(package) {
+                Some((name, version)) => (name, Some(version)),
+                None => (package, None),
+            };
+            search_entry(name, version, None, description)
+        })
+        .collect()
+}
+
+/// Parse `apk policy` output
+///
+/// Each package starts with a `name policy:` line, followed by its versions
+/// (`  version:`) and, below each version, the repositories holding it, one
+/// per line. Every repository of a version becomes its own entry, so callers
+/// know where each version can be installed from. The installed database
+/// (`lib/apk/db/installed`) is not a repository; versions only found there
+/// are listed without one. Tagged repositories (`@edge URL`) are reported by
+/// their URL.
+pub fn parse_policy(stdout: &str) -> Vec<SearchResult> {
+    let mut results = Vec::new();
+    let mut name = None;
+    let mut version: Option<(&str, bool)> = None;
+    let flush = |name: Option<&str>, version: Option<(&str, bool)>, results: &mut Vec<_>| {
+        if let (Some(name), Some((version, false))) = (name, version) {
+            results.extend(search_entry(name, Some(version), None, None));
+        }
+    };
+
+    for line in stdout.lines().filter(|line| !is_diagnostic(line.trim())) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if let Some(package) = trimmed.strip_suffix(" policy:") {
+            flush(name, version.take(), &mut results);
+            name = Some(package);
+        } else if !line.starts_with("    ")
+            && let Some(listed) = trimmed.strip_suffix(':')
+        {
+            flush(name, version.take(), &mut results);
+            version = Some((listed, false));
+        } else if let (Some(name), Some((version, located))) = (name, version.as_mut()) {
+            if trimmed.ends_with("lib/apk/db/installed") {
+                continue;
+            }
+            let repository = match trimmed.split_once(' ') {
+                Some((tag, url)) if tag.starts_with('@') => url.trim(),
+                _ => trimmed,
+            };
+            results.extend(search_entry(name, Some(version), Some(repository), None));
+            *located = true;
+        }
+    }
+    flush(name, version, &mut results);
+    results
+}
+
+/// Parse the `APKINDEX` file of a repository index
+///
+/// Packages are blocks of `K:value` lines separated by blank lines: `P` is
+/// the name, `V` the version, `A` the architecture, `T` the description, `L`
+/// the license, `m` the maintainer and `t` the build time in seconds since
+/// the epoch. Other fields are ignored, as are blocks without a name.
+pub fn parse_apkindex(content: &str) -> Vec<SearchResult> {
+    let mut results = Vec::new();
+    let mut fields = HashMap::new();
+    // The trailing empty line ends the last block
+    for line in content.lines().chain(std::iter::once("")) {
+        let line = line.trim();
+        if !line.is_empty() {
+            if let Some((key, value)) = line.split_once(':') {
+                fields.insert(key, value.trim());
+            }
+            continue;
+        }
+
+        let field = |key| fields.get(key).copied().filter(|value| !value.is_empty());
+        if let Some(entry) =
+            field("P").and_then(|name| search_entry(name, field("V"), None, field("T")))
+        {
+            results.push(SearchResult {
+                arch: field("A").map(str::to_string),
+                license: field("L").map(str::to_string),
+                maintainer: field("m").map(str::to_string),
+                build_date: field("t")
+                    .and_then(|time| time.parse().ok())
+                    .and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+                    .map(|time| time.to_rfc3339()),
+                ..entry
+            });
+        }
+        fields.clear();
+    }
+    results
+}
+
+/// Parse the checksums of the installed database (`/lib/apk/db/installed`)
+///
+/// The database has the block format of `APKINDEX`; `C` is the checksum of
+/// the package's control data, `Q1` followed by its base64 SHA-1, which is
+/// what `apk` verifies packages against. Packages without one are returned
+/// without a checksum.
+pub fn parse_installed_checksums(content: &str) -> Vec<LockedPackage> {
+    let mut packages = Vec::new();
+    let mut fields = HashMap::new();
+    // The trailing empty line ends the last block
+    for line in content.lines().chain(std::iter::once("")) {
+        let line = line.trim();
+        if !line.is_empty() {
+            if let Some((key, value)) = line.split_once(':') {
+                fields.entry(key).or_insert(value.trim());
+            }
+            continue;
+        }
+
+        let field = |key| fields.get(key).copied().filter(|value| !value.is_empty());
+        if let (Some(name), Some(version)) = (field("P"), field("V")) {
+            packages.push(LockedPackage {
+                checksum: field("C").map(str::to_string),
+                ..LockedPackage::new(PinnedPackage {
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    arch: field("A").map(str::to_string),
+                    repository: None,
+                    repositories: Vec::new(),
+                })
+            });
+        }
+        fields.clear();
+    }
+    packages
+}
+
+/// Parse a package listing page of the Alpine packages site
+/// (`https://pkgs.alpinelinux.org/packages?name=...`)
+///
+/// Each table row is a package, with cells classed by the field they hold.
+/// The description is the hint of the package link. The repository is
+/// reported as `branch/repo`, e.g. `v3.22/main`, the path of the repository
+/// on the mirrors, and the build date, given in UTC, as RFC 3339.
+pub fn parse_packages_page(html: &str) -> Vec<SearchResult> {
+    static ROW: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<tr[^>]*>(.*?)</tr>").unwrap());
+    static CELL: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"(?s)<td[^>]*class="([^"]*)"[^>]*>(.*?)</td>"#).unwrap());
+    static TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+    static HINT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"data-hint="([^"]*)""#).unwrap());
+
+    ROW.captures_iter(html)
+        .filter_map(|row| {
+            let description = CELL
+                .captures_iter(&row[1])
+                .find(|cell| &cell[1] == "package")
+                .and_then(|cell| Some(unescape_html(&HINT.captures(&cell[2])?[1])));
+            let cells: HashMap<&str, String> = CELL
+                .captures_iter(&row[1])
+                .map(|cell| {
+                    let class = cell.get(1).map_or("", |class| class.as_str());
+                    (class, unescape_html(TAG.replace_all(&cell[2], "").trim()))
+                })
+                .collect();
+            let field = |class| {
+                cells
+                    .get(class)
+                    .map(String::as_str)
+                    .filter(|value| !value.is_empty())
+            };
+            let repository = match (field("branch"), field("repo")) {
+                (Some(branch), Some(repo)) => Some(format!("{branch}/{repo}")),
+                _ => None,
+            };
+            let entry = search_entry(
+                field("package")?,
+                field("version"),
+                repository.as_deref(),
+                description.as_deref(),
+            )?;
+            Some(SearchResult {
+                arch: field("arch").map(str::to_string),
+                license: field("license").map(str::to_string),
+                maintainer: field("maintainer").map(str::to_string),
+                build_date: field("bdate").map(|date| {
+                    chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")
+                        .map_or_else(|_| date.to_string(), |date| date.and_utc().to_rfc3339())
+                }),
+                ..entry
+            })
+        })
+        .collect()
+}
+
+/// Decode the character references the packages site uses
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Parse `apk list -I` output
+///
+/// Format: `curl-8.14.1-r1 x86_64 {curl} (curl) [installed]`. With the
+/// content of `/etc/apk/world`, packages it names are explicitly installed,
+/// from the repository tag they are pinned to, and the others are
+/// dependencies.
+pub fn parse_installed_list(stdout: &str, world: Option<&str>) -> Vec<InstalledPackage> {
+    let world = world.map(parse_world);
+    stdout
+        .lines()
+        .filter(|line| !is_diagnostic(line))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (name, version) = split_package_version(fields.next()?)?;
+            let requested = world.as_ref().map(|world| world.get(name));
+            Some(InstalledPackage {
+                name: name.to_string(),
+                version: version.to_string(),
+                architecture: fields.next().map(|arch| arch.to_string()),
+                previous_version: None,
+                repository: requested.flatten().cloned().flatten(),
+                reason: requested.map(|requested| match requested {
+                    Some(_) => InstallReason::Explicit,
+                    None => InstallReason::Dependency,
+                }),
+            })
+        })
+        .collect()
+}
+
+/// Parse `/etc/apk/world` into the requested packages and the repository
+/// tag each is pinned to
+///
+/// Entries look like `curl`, `curl@edge`, `curl=8.14.1-r1` or
+/// `curl@edge>=8`; `!name` entries forbid a package and are skipped.
+pub fn parse_world(content: &str) -> BTreeMap<String, Option<String>> {
+    content
+        .split_whitespace()
+        .filter(|entry| !entry.starts_with('!'))
+        .filter_map(|entry| {
+            let entry = entry
+                .split(['=', '<', '>', '~'])
+                .next()
+                .filter(|entry| !entry.is_empty())?;
+            Some(match entry.split_once('@') {
+                Some((name, tag)) => (name.to_string(), Some(tag.to_string())),
+                None => (entry.to_string(), None),
+            })
+        })
+        .collect()
+}
+
+/// Parse the origin packages of `apk list -I` output
+///
+/// Lines look like `libcrypto3-3.5.1-r0 x86_64 {openssl} (Apache-2.0)
+/// [installed]`. Only packages whose origin has another name are returned.
+pub fn parse_installed_origins(stdout: &str) -> HashMap<String, String> {
+    stdout
+        .lines()
+        .filter(|line| !is_diagnostic(line))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (name, _) = split_package_version(fields.next()?)?;
+            let origin = fields
+                .find_map(|field| field.strip_prefix('{')?.strip_suffix('}'))
+                .filter(|origin| *origin != name)?;
+            Some((name.to_string(), origin.to_string()))
+        })
+        .collect()
+}
+
+/// Parse the licenses of `apk list -I` output
+///
+/// The license is the parenthesized SPDX expression after the origin, e.g.
+/// `ca-certificates-bundle-20250619-r0 x86_64 {ca-certificates} (MPL-2.0 AND
+/// MIT) [installed]`.
+pub fn parse_installed_licenses(stdout: &str) -> Vec<PackageLicense> {
+    stdout
+        .lines()
+        .filter(|line| !is_diagnostic(line))
+        .filter_map(|line| {
+            let (package, rest) = line.split_once(' ')?;
+            let (name, version) = split_package_version(package)?;
+            let license = rest
+                .split_once("} (")
+                .map(|(_, license)| license)
+                .and_then(|license| license.trim_end().strip_suffix(" [installed]"))
+                .and_then(|license| license.strip_suffix(')'))
+                .map(str::trim)
+                .filter(|license| !license.is_empty());
+            Some(PackageLicense {
+                name: name.to_string(),
+                version: version.to_string(),
+                licenses: license.map(str::to_string).into_iter().collect(),
+            })
+        })
+        .collect()
+}
+
+/// Parse the packages changed by `apk add`
+///
+/// Handles `(1/3) Installing curl (8.14.1-r1)` as well as
+/// `(2/3) Upgrading musl (1.2.5-r9 -> 1.2.5-r10)`, `Downgrading` and
+/// `Replacing` lines.
+pub fn parse_install_output(stdout: &str) -> Vec<InstalledPackage> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim_start().strip_prefix('(')?;
+            let (_, rest) = rest.split_once(") ")?;
+            let (action, rest) = rest.split_once(' ')?;
+            let (name, versions) = rest.split_once(" (")?;
+            let versions = versions.trim_end().strip_suffix(')')?;
+
+            let (previous_version, version) = match (action, versions.split_once(" -> ")) {
+                ("Installing" | "Replacing", None) => (None, versions),
+                ("Upgrading" | "Downgrading" | "Replacing", Some((previous, current))) => {
+                    (Some(previous.to_string()), current)
+                }
+                _ => return None,
+            };
+
+            Some(InstalledPackage {
+                name: name.to_string(),
+                version: version.to_string(),
+                architecture: None,
+                previous_version,
+                repository: None,
+                reason: None,
+            })
+        })
+        .collect()
+}
+
+/// Parse the bytes `apk add --simulate --verbose` would add to the installed
+/// packages, from its `After this operation, 12 MiB of additional disk space
+/// will be used.` line
+///
+/// Installs freeing space add nothing; `None` when apk printed no summary.
+pub fn parse_required_space(stdout: &str) -> Option<u64> {
+    let line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("After this operation, "))?;
+    if !line.ends_with("of additional disk space will be used.") {
+        return Some(0);
+    }
+    let mut words = line.split_whitespace();
+    let size: u64 = words.next()?.parse().ok()?;
+    let unit: u64 = match words.next()? {
+        "B" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return None,
+    };
+    Some(size * unit)
+}
+
+/// Parse the packages removed by `apk add`, from `(1/4) Purging py3-packaging
+/// (25.0-r0)` lines
+///
+/// Dependencies nothing needs any more are purged when an upgrade drops
+/// them.
+pub fn parse_removed_packages(stdout: &str) -> Vec<InstalledPackage> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim_start().strip_prefix('(')?;
+            let (_, rest) = rest.split_once(") ")?;
+            let rest = rest.strip_prefix("Purging ")?;
+            let (name, version) = rest.split_once(" (")?;
+            Some(InstalledPackage {
+                name: name.to_string(),
+                version: version.trim_end().strip_suffix(')')?.to_string(),
+                architecture: None,
+                previous_version: None,
+                repository: None,
+                reason: None,
+            })
+        })
+        .collect()
+}
+
+/// Parse the name of the key an `.apk` file is signed with from the listing
+/// of its contents (`tar -tzf`), whose first entry is the signature:
+/// `.SIGN.RSA.<key>` or `.SIGN.RSA256.<key>`
+pub fn parse_signing_key(listing: &str) -> Option<String> {
+    listing.lines().find_map(|line| {
+        let key = line.trim().strip_prefix(".SIGN.")?;
+        let (_, key) = key.split_once('.')?;
+        (!key.is_empty()).then(|| key.to_string())
+    })
+}
+
+/// Parse `/etc/apk/repositories`
+///
+/// Each line is a repository URL or local path, optionally prefixed with an
+/// `@tag` for pinned repositories. Commented-out repositories (common for
+/// `community` after `setup-apkrepos`) are reported as disabled.
+pub fn parse_repositories(content: &str) -> Vec<Repository> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (line, enabled) = match line.strip_prefix('#') {
+                Some(commented) => (commented.trim(), false),
+                None => (line, true),
+            };
+
+            let (tag, url) = split_repository_tag(line);
+            let url = url.split_whitespace().next()?;
+            if !url.contains("://") && !url.starts_with('/') {
+                return None;
+            }
+
+            Some(Repository {
+                tag: tag.map(str::to_string),
+                enabled,
+                ..Repository::new(url)
+            })
+        })
+        .collect()
+}
+
+/// Split the `@tag` off a repository, e.g. `@testing
+/// https://dl-cdn.alpinelinux.org/alpine/edge/testing`
+pub fn split_repository_tag(repository: &str) -> (Option<&str>, &str) {
+    let repository = repository.trim();
+    match repository
+        .strip_prefix('@')
+        .and_then(|tagged| tagged.split_once(char::is_whitespace))
+    {
+        Some((tag, url)) => (Some(tag), url.trim_start()),
+        None => (None, repository),
+    }
+}
+
+/// Split the `@tag` off a package name, e.g. `neovim@testing`
+pub fn split_package_tag(package: &str) -> (&str, Option<&str>) {
+    match package.split_once('@') {
+        Some((name, tag)) => (name, Some(tag)),
+        None => (package, None),
+    }
+}
+
+/// Repository URLs `apk` reported as unreachable in its diagnostics
+///
+/// apk keeps going when a repository index cannot be fetched and only warns,
+/// e.g. `WARNING: updating and opening https://...: temporary error (try
+/// again later)`, so unreachable repositories are recognised from the
+/// messages rather than the exit status. Only connection failures and
+/// server errors (`HTTP 503: ...`) are reported: a missing file, e.g. a 404
+/// for a package the index is newer than, is not the mirror's fault, and
+/// neither are cache misses and other local errors.
+pub fn parse_unreachable_repositories(output: &str) -> Vec<String> {
+    const NETWORK_ERRORS: &[&str] = &[
+        "temporary error",
+        "network error",
+        "DNS lookup error",
+        "could not connect",
+        "timed out",
+        "TLS",
+    ];
+    let server_error = |reason: &str| {
+        reason.match_indices("HTTP 5").any(|(at, _)| {
+            let code = &reason.as_bytes()[at + "HTTP 5".len()..];
+            code.len() >= 2 && code[..2].iter().all(u8::is_ascii_digit)
+        })
+    };
+
+    let mut urls: Vec<String> = Vec::new();
+    for line in output.lines().map(str::trim) {
+        let Some(message) = line
+            .strip_prefix("WARNING:")
+            .or_else(|| line.strip_prefix("ERROR:"))
+        else {
+            continue;
+        };
+        let Some(url) = message.split_whitespace().find(|word| word.contains("://")) else {
+            continue;
+        };
+        let reason = &message[message.find(url).unwrap_or_default() + url.len()..];
+        if !NETWORK_ERRORS.iter().any(|error| reason.contains(error)) && !server_error(reason) {
+            continue;
+        }
+
+        let url = url.trim_end_matches(':').to_string();
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+/// Alpine release read from `/etc/alpine-release`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlpineRelease {
+    pub major: u32,
+    pub minor: u32,
+    /// Development snapshot of the next release, e.g. `3.23_alpha20250612`
+    pub edge: bool,
+}
+
+impl AlpineRelease {
+    /// Repository branch of the release: `edge` or `v<major>.<minor>`
+    pub fn branch(&self) -> String {
+        if self.edge {
+            "edge".to_string()
+        } else {
+            format!("v{}.{}", self.major, self.minor)
+        }
+    }
+
+    /// Up to `count` stable branches released before this one, newest first
+    ///
+    /// Edge snapshots carry the version of the upcoming release, so the
+    /// branches start with the latest stable release.
+    pub fn older_branches(&self, count: usize) -> Vec<String> {
+        let Some(newest) = self.minor.checked_sub(1) else {
+            return Vec::new();
+        };
+        (0..=newest)
+            .rev()
+            .take(count)
+            .map(|minor| format!("v{}.{minor}", self.major))
+            .collect()
+    }
+}
+
+/// Parse `/etc/alpine-release`, e.g. `3.22.1` or `3.23_alpha20250612`
+pub fn parse_alpine_release(content: &str) -> Option<AlpineRelease> {
+    let release = content.lines().next()?.trim();
+    let (version, edge) = match release.split_once('_') {
+        Some((version, _)) => (version, true),
+        None => (release, false),
+    };
+    let mut numbers = version.split('.');
+    let major = numbers.next()?.parse().ok()?;
+    let minor = numbers.next()?.parse().ok()?;
+    Some(AlpineRelease { major, minor, edge })
+}
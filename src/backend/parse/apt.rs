@@ -0,0 +1,427 @@
+//! Parsers for `apt`, `apt-get` and `apt-cache` output
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::search_entry;
+use crate::backend::{
+    InstallReason, InstalledPackage, LockedPackage, PinnedPackage, Repository, SearchResult,
+};
+
+/// Parse `apt-cache madison` output
+///
+/// Format: `      curl | 7.88.1-10+deb12u12 | http://deb.debian.org/debian bookworm/main amd64 Packages`.
+/// The archive line becomes the entry's repository. Source package entries
+/// (`... Sources`) are skipped since only binary packages can be installed.
+pub fn parse_madison(stdout: &str) -> Vec<SearchResult> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('|');
+            let package = fields.next()?;
+            let version = fields.next()?;
+            let source = fields.next();
+
+            if source.is_some_and(|source| source.trim().ends_with(" Sources")) {
+                return None;
+            }
+
+            search_entry(package, Some(version), source, None)
+                .filter(|entry| entry.version.is_some())
+        })
+        .collect()
+}
+
+/// Parse the checksums of `apt-cache show` output
+///
+/// Records are stanzas of `Field: value` lines separated by blank lines.
+/// The `SHA256` field of each record becomes a `sha256:<hex>` checksum;
+/// records read from the dpkg status file have none. Continuation lines of
+/// multi-line fields start with a space and are skipped.
+pub fn parse_show_checksums(stdout: &str) -> Vec<LockedPackage> {
+    let mut packages = Vec::new();
+    let mut fields = HashMap::new();
+    // The trailing empty line ends the last record
+    for line in stdout.lines().chain(std::iter::once("")) {
+        if line.starts_with([' ', '\t']) {
+            continue;
+        }
+        let line = line.trim();
+        if !line.is_empty() {
+            if let Some((key, value)) = line.split_once(':') {
+                fields.insert(key, value.trim());
+            }
+            continue;
+        }
+
+        let field = |key| fields.get(key).copied().filter(|value| !value.is_empty());
+        if let (Some(name), Some(version)) = (field("Package"), field("Version")) {
+            packages.push(LockedPackage {
+                checksum: field("SHA256").map(|sha256| format!("sha256:{sha256}")),
+                ..LockedPackage::new(PinnedPackage {
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    arch: field("Architecture").map(str::to_string),
+                    repository: None,
+                    repositories: Vec::new(),
+                })
+            });
+        }
+        fields.clear();
+    }
+    packages
+}
+
+/// Parse `apt-cache search` output
+///
+/// Format: `curl - command line tool for transferring data with URL syntax`
+pub fn parse_search(stdout: &str) -> Vec<SearchResult> {
+    stdout
+        .lines()
+        .filter_map(|line| match line.split_once(" - ") {
+            Some((name, description)) => search_entry(name, None, None, Some(description)),
+            None => search_entry(line, None, None, None),
+        })
+        .collect()
+}
+
+/// Parse `apt list --installed` output
+///
+/// Format: `curl/stable,now 7.88.1-10+deb12u12 amd64 [installed]`. The
+/// `Listing...` header has no `/` and is skipped. The archives other than
+/// `now`, the local status, are the repository; packages marked
+/// `automatic` were installed as dependencies.
+pub fn parse_installed_list(stdout: &str) -> Vec<InstalledPackage> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (name, archives) = fields.next()?.split_once('/')?;
+            let version = fields.next()?;
+            let architecture = fields.next().map(|arch| arch.to_string());
+            let status = fields.collect::<Vec<_>>().join(" ");
+            let archives: Vec<&str> = archives
+                .split(',')
+                .filter(|archive| !archive.is_empty() && *archive != "now")
+                .collect();
+            Some(InstalledPackage {
+                name: name.to_string(),
+                version: version.to_string(),
+                architecture,
+                previous_version: None,
+                repository: (!archives.is_empty()).then(|| archives.join(",")),
+                reason: status.starts_with("[installed").then(|| {
+                    if status.contains("automatic") {
+                        InstallReason::Dependency
+                    } else {
+                        InstallReason::Explicit
+                    }
+                }),
+            })
+        })
+        .collect()
+}
+
+/// Parse `dpkg-query -W -f '${Package}\t${source:Package}\n'` output
+///
+/// Only packages whose source package has another name are returned.
+pub fn parse_source_packages(stdout: &str) -> HashMap<String, String> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, source) = line.split_once('\t')?;
+            let source = source.trim();
+            (!source.is_empty() && source != name).then(|| (name.to_string(), source.to_string()))
+        })
+        .collect()
+}
+
+/// Parse `grep -R -H --include=copyright ^License: /usr/share/doc` output
+///
+/// Lines look like `/usr/share/doc/curl/copyright:License: curl`, one per
+/// license paragraph of the package's machine-readable copyright file.
+/// Returns the distinct licenses of each package, in file order. Packages
+/// with free-form copyright files have no `License:` lines.
+pub fn parse_copyright_licenses(stdout: &str) -> BTreeMap<String, Vec<String>> {
+    let mut licenses: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for line in stdout.lines() {
+        let Some((path, license)) = line.split_once(":License:") else {
+            continue;
+        };
+        let Some(package) = path
+            .strip_suffix("/copyright")
+            .and_then(|dir| dir.rsplit('/').next())
+            .filter(|package| !package.is_empty())
+        else {
+            continue;
+        };
+        let license = license.trim();
+        let known = licenses.entry(package.to_string()).or_default();
+        if !license.is_empty() && !known.iter().any(|known| known == license) {
+            known.push(license.to_string());
+        }
+    }
+    licenses
+}
+
+/// OSV ecosystem of the release described by `/etc/os-release`
+///
+/// Debian releases are `Debian:<VERSION_ID>` and Ubuntu releases
+/// `Ubuntu:<VERSION_ID>`, with a `:LTS` suffix for long-term support
+/// releases. Testing, unstable and other derivatives have none.
+pub fn parse_osv_ecosystem(os_release: &str) -> Option<String> {
+    let field = |key: &str| {
+        os_release.lines().find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix('=')?;
+            Some(value.trim().trim_matches('"'))
+        })
+    };
+    let version_id = field("VERSION_ID").filter(|version| !version.is_empty())?;
+    match field("ID")? {
+        "debian" => Some(format!("Debian:{version_id}")),
+        "ubuntu" if field("VERSION").is_some_and(|version| version.contains("LTS")) => {
+            Some(format!("Ubuntu:{version_id}:LTS"))
+        }
+        "ubuntu" => Some(format!("Ubuntu:{version_id}")),
+        _ => None,
+    }
+}
+
+/// Parse `Setting up curl:amd64 (7.88.1-10) ...` lines from `apt-get install` output
+///
+/// Upgrades are detected from the preceding `Unpacking curl (new) over (old) ...` lines.
+pub fn parse_install_output(stdout: &str) -> Vec<InstalledPackage> {
+    let previous_versions: HashMap<&str, &str> = stdout
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("Unpacking ")?;
+            let (package, rest) = rest.split_once(" (")?;
+            let (_, rest) = rest.split_once(") over (")?;
+            let (previous_version, _) = rest.split_once(')')?;
+            Some((package, previous_version))
+        })
+        .collect();
+
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("Setting up ")?;
+            let (package, rest) = rest.split_once(" (")?;
+            let (version, _) = rest.split_once(')')?;
+            let (name, architecture) = match package.split_once(':') {
+                Some((name, architecture)) => (name, Some(architecture.to_string())),
+                None => (package, None),
+            };
+            Some(InstalledPackage {
+                name: name.to_string(),
+                version: version.to_string(),
+                architecture,
+                previous_version: previous_versions
+                    .get(package)
+                    .map(|previous_version| previous_version.to_string()),
+                repository: None,
+                reason: None,
+            })
+        })
+        .collect()
+}
+
+/// Parse `Removing netcat-traditional (1.10-47) ...` lines from `apt-get
+/// install` output, for packages removed because they conflict with the
+/// installed ones
+pub fn parse_removed_packages(stdout: &str) -> Vec<InstalledPackage> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("Removing ")?;
+            let (package, rest) = rest.split_once(" (")?;
+            let (version, _) = rest.split_once(')')?;
+            let (name, architecture) = match package.split_once(':') {
+                Some((name, architecture)) => (name, Some(architecture.to_string())),
+                None => (package, None),
+            };
+            Some(InstalledPackage {
+                name: name.to_string(),
+                version: version.to_string(),
+                architecture,
+                previous_version: None,
+                repository: None,
+                reason: None,
+            })
+        })
+        .collect()
+}
+
+/// Parse the size of the archives downloaded by `apt-get`, from the
+/// `Fetched 41.9 kB in 0s (1,012 kB/s)` line
+///
+/// APT reports sizes in SI units with thousands separators.
+pub fn parse_fetched_bytes(stdout: &str) -> Option<u64> {
+    let line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Fetched "))?;
+    parse_size(line)
+}
+
+/// Parse the bytes `apt-get install --assume-no` would download and add to
+/// the installed packages, from its `Need to get 0 B/2,345 kB of archives.`
+/// and `After this operation, 7,001 kB of additional disk space will be
+/// used.` lines
+///
+/// APT leaves out the lines of sizes that are zero. Installs freeing space
+/// add nothing.
+pub fn parse_required_space(stdout: &str) -> (u64, u64) {
+    let mut download = 0;
+    let mut installed = 0;
+    for line in stdout.lines() {
+        if let Some(sizes) = line
+            .strip_prefix("Need to get ")
+            .and_then(|line| line.strip_suffix(" of archives."))
+        {
+            // Archives already in the cache are not downloaded again
+            let missing = sizes.split('/').next().unwrap_or(sizes);
+            download = parse_size(missing).unwrap_or_default();
+        } else if let Some(line) = line
+            .strip_prefix("After this operation, ")
+            .filter(|line| line.ends_with("of additional disk space will be used."))
+        {
+            installed = parse_size(line).unwrap_or_default();
+        }
+    }
+    (download, installed)
+}
+
+/// Parse a size APT prints, e.g. `1,361 kB`, in SI units with thousands
+/// separators
+fn parse_size(text: &str) -> Option<u64> {
+    let mut words = text.split_whitespace();
+    let size: f64 = words.next()?.replace(',', "").parse().ok()?;
+    let unit = match words.next()? {
+        "B" => 1.0,
+        "kB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        _ => return None,
+    };
+    Some((size * unit).round() as u64)
+}
+
+/// Parse a one-line style `sources.list` file
+///
+/// Format: `deb [arch=amd64 signed-by=/etc/apt/keyrings/docker.gpg] https://download.docker.com/linux/debian bookworm stable`.
+/// Commented-out entries (`# deb-src ...`) are reported as disabled.
+pub fn parse_sources_list(content: &str) -> Vec<Repository> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            match line.strip_prefix('#') {
+                Some(commented) => parse_source_line(commented).map(|repository| Repository {
+                    enabled: false,
+                    ..repository
+                }),
+                None => parse_source_line(line),
+            }
+        })
+        .collect()
+}
+
+/// Parse a single one-line style source entry
+pub fn parse_source_line(line: &str) -> Option<Repository> {
+    let line = line.trim();
+    let (kind, rest) = line.split_once(char::is_whitespace)?;
+    if kind != "deb" && kind != "deb-src" {
+        return None;
+    }
+
+    let rest = rest.trim_start();
+    let (options, rest) = match rest.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?,
+        None => ("", rest),
+    };
+
+    let mut fields = rest.split_whitespace();
+    let mut repository = Repository::new(fields.next()?);
+    repository.types = vec![kind.to_string()];
+    repository.suites = vec![fields.next()?.to_string()];
+    repository.components = fields.map(str::to_string).collect();
+    let option = |name: &str| {
+        options
+            .split_whitespace()
+            .filter_map(|option| option.split_once('='))
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    };
+    repository.signed_by = option("signed-by").map(str::to_string);
+    repository.trusted = option("trusted").is_some_and(|trusted| trusted == "yes");
+
+    Some(repository)
+}
+
+/// Parse a deb822 style `.sources` file
+///
+/// Stanzas are separated by blank lines and hold `Types`, `URIs`, `Suites`,
+/// `Components`, `Signed-By`, `Trusted` and `Enabled` fields. A stanza with
+/// several URIs is reported as one repository per URI. Multi-line values (an
+/// inline `Signed-By` key) are joined with newlines, with ` .` lines standing
+/// for empty lines as in the file format.
+pub fn parse_deb822_sources(content: &str) -> Vec<Repository> {
+    let mut repositories = Vec::new();
+    let mut stanza: Vec<(String, String)> = Vec::new();
+
+    for line in content.lines().chain(std::iter::once("")) {
+        if line.starts_with('#') {
+            continue;
+        }
+
+        if line.trim().is_empty() {
+            repositories.extend(stanza_repositories(&stanza));
+            stanza.clear();
+        } else if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = stanza.last_mut() {
+                let continuation = line.trim();
+                if !value.is_empty() {
+                    value.push('\n');
+                }
+                if continuation != "." {
+                    value.push_str(continuation);
+                }
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            stanza.push((key.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    repositories
+}
+
+fn stanza_repositories(stanza: &[(String, String)]) -> Vec<Repository> {
+    let field = |name: &str| {
+        stanza
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let words = |name: &str| -> Vec<String> {
+        field(name)
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect()
+    };
+
+    let enabled = !field("enabled").is_some_and(|enabled| enabled.eq_ignore_ascii_case("no"));
+    words("uris")
+        .into_iter()
+        .map(|url| Repository {
+            types: words("types"),
+            suites: words("suites"),
+            components: words("components"),
+            signed_by: field("signed-by")
+                .filter(|signed_by| !signed_by.is_empty())
+                .map(str::to_string),
+            trusted: field("trusted").is_some_and(|trusted| trusted.eq_ignore_ascii_case("yes")),
+            enabled,
+            ..Repository::new(url)
+        })
+        .collect()
+}
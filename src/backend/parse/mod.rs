@@ -0,0 +1,230 @@
+//! Parsers for package manager command output
+//!
+//! Parsers take the captured stdout of a command and never run anything
+//! themselves, so they can be tested against the fixtures captured from
+//! different distribution releases in `tests/fixtures`.
+//!
+//! Every listing of available packages (search results, version lookups) is
+//! turned into [`SearchResult`] entries through [`search_entry`], so tools
+//! consume the same `{name, version, repository, description}` shape no
+//! matter which command produced it.
+
+pub mod apk;
+pub mod apt;
+
+use crate::backend::SearchResult;
+use crate::error::ErrorCode;
+
+/// Build a search entry from raw output fields
+///
+/// Fields are trimmed and empty ones dropped. Returns `None` when the
+/// package name is empty.
+pub fn search_entry(
+    name: &str,
+    version: Option<&str>,
+    repository: Option<&str>,
+    description: Option<&str>,
+) -> Option<SearchResult> {
+    let field = |value: Option<&str>| {
+        value
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+
+    Some(SearchResult {
+        name: field(Some(name))?,
+        version: field(version),
+        repository: field(repository),
+        description: field(description),
+        arch: None,
+        license: None,
+        maintainer: None,
+        build_date: None,
+    })
+}
+
+/// Versions of a package listed in search results, without duplicates
+///
+/// Entries for other packages (e.g. `curl-doc` when searching for `curl`)
+/// and entries without a version are ignored.
+pub fn versions_of(results: &[SearchResult], package: &str) -> Vec<String> {
+    let mut versions: Vec<String> = Vec::new();
+    for version in results
+        .iter()
+        .filter(|result| result.name == package)
+        .filter_map(|result| result.version.as_ref())
+    {
+        if !versions.contains(version) {
+            versions.push(version.clone());
+        }
+    }
+    versions
+}
+
+/// Name of the release described by `/etc/os-release`
+///
+/// Uses `PRETTY_NAME`, falling back to `NAME` and `VERSION_ID`.
+pub fn parse_os_release_name(content: &str) -> Option<String> {
+    let field = |key: &str| {
+        content.lines().find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix('=')?;
+            Some(value.trim().trim_matches('"')).filter(|value| !value.is_empty())
+        })
+    };
+    if let Some(name) = field("PRETTY_NAME") {
+        return Some(name.to_string());
+    }
+    match (field("NAME")?, field("VERSION_ID")) {
+        (name, Some(version)) => Some(format!("{name} {version}")),
+        (name, None) => Some(name.to_string()),
+    }
+}
+
+/// Mount point and available bytes of each file system `df -Pk` lists
+pub fn parse_df(stdout: &str) -> Vec<(String, u64)> {
+    stdout
+        .lines()
+        .filter(|line| !line.starts_with("Filesystem"))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let available: u64 = fields.get(3)?.parse().ok()?;
+            let mount = fields.get(5..).filter(|mount| !mount.is_empty())?.join(" ");
+            Some((mount, available * 1024))
+        })
+        .collect()
+}
+
+/// PID, command and path of each lock `lslocks --noheadings --raw --output
+/// PID,COMMAND,PATH` lists
+///
+/// Raw output escapes the spaces of commands and paths as `\x20`. Locks
+/// whose path `lslocks` could not resolve are left out.
+pub fn parse_lslocks(stdout: &str) -> Vec<(u32, String, String)> {
+    let unescape = |field: &str| field.replace("\\x20", " ");
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse().ok()?;
+            let command = unescape(fields.next()?);
+            let path = unescape(fields.next()?);
+            Some((pid, command, path))
+        })
+        .collect()
+}
+
+/// First non-empty line of a `--version` output
+pub fn parse_version_line(stdout: &str) -> Option<String> {
+    stdout
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+/// Parse the digest `sha256sum` prints for a single file
+pub fn parse_sha256sum(stdout: &str) -> Option<String> {
+    let digest = stdout.split_whitespace().next()?;
+    (digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| digest.to_ascii_lowercase())
+}
+
+/// Signatures of failures in package manager output, by class, in the
+/// order they are checked
+///
+/// Earlier classes win: a download failing because the disk is full also
+/// prints a fetch error, and an unprivileged apt-get fails to take the dpkg
+/// lock because of a denied permission, not because it is held.
+const FAILURE_SIGNATURES: &[(ErrorCode, &[&str])] = &[
+    (
+        ErrorCode::DiskFull,
+        &[
+            "No space left on device",
+            // apt-get: `E: You don't have enough free space in /var/cache/apt/archives/.`
+            "You don't have enough free space",
+        ],
+    ),
+    (
+        ErrorCode::PermissionDenied,
+        &[
+            "Permission denied",
+            // apt-get: `E: ... (13: Permission denied), are you root?`
+            "are you root?",
+            "Operation not permitted",
+        ],
+    ),
+    (
+        ErrorCode::LockHeld,
+        &[
+            // apt-get: `E: Could not get lock /var/lib/dpkg/lock-frontend. It is held by process 1234`
+            "Could not get lock",
+            "Unable to acquire the dpkg frontend lock",
+            // apk: `ERROR: Unable to lock database: Resource temporarily unavailable`
+            "Unable to lock database: Resource temporarily unavailable",
+        ],
+    ),
+    (
+        ErrorCode::BrokenState,
+        &[
+            // apt-get: `E: dpkg was interrupted, you must manually run 'dpkg --configure -a' ...`
+            "dpkg was interrupted",
+            // apt-get: `You might want to run 'apt --fix-broken install' to correct these.`
+            "--fix-broken install",
+        ],
+    ),
+    (
+        ErrorCode::NetworkFailure,
+        &[
+            "Temporary failure resolving",
+            "Could not resolve",
+            "Failed to fetch",
+            "Could not connect",
+            "Connection timed out",
+            "Network is unreachable",
+            // apk: `WARNING: fetching https://...: DNS lookup error`
+            "DNS lookup error",
+            "network error",
+            "temporary error (try again later)",
+        ],
+    ),
+    (
+        ErrorCode::NotFound,
+        &[
+            // apk: `ERROR: unable to select packages:\n  foo (no such package):`
+            "(no such package)",
+            // apt-get: `E: Unable to locate package foo`
+            "Unable to locate package",
+            // apt-get: `E: Package 'foo' has no installation candidate`
+            "has no installation candidate",
+            // apt-get: `E: Version '1.0' for 'foo' was not found`
+            "was not found",
+        ],
+    ),
+    (
+        ErrorCode::UnsatisfiableDependency,
+        &[
+            "Unmet dependencies",
+            "unmet dependencies",
+            "held broken packages",
+            "but it is not going to be installed",
+            // apk: `ERROR: unable to select packages:\n  foo-1.0:\n    breaks: world[foo<1]`
+            "unable to select packages",
+            "breaks:",
+            "conflicts:",
+        ],
+    ),
+];
+
+/// Class of the failure a package manager printed in `output`, if any of
+/// the known signatures appears in it
+pub fn classify_failure(output: &str) -> Option<ErrorCode> {
+    FAILURE_SIGNATURES
+        .iter()
+        .find(|(_, signatures)| {
+            signatures
+                .iter()
+                .any(|signature| output.contains(signature))
+        })
+        .map(|(code, _)| *code)
+}
@@ -0,0 +1,293 @@
+//! Pinned artifacts of critical packages
+//!
+//! Repository signatures say a package comes from a trusted repository, not
+//! that it is the build a team vetted. For critical packages, such as internal
+//! tooling, a [`PackagePins`] file names the SHA-256 of the expected package
+//! file and/or the key expected to have signed it:
+//!
+//! ```toml
+//! [[package]]
+//! name = "internal-agent"
+//! version = "2.1.0-r0"
+//! sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! signing_key = "builds@example.com-5f3e1a2b.rsa.pub"
+//! ```
+//!
+//! Installs of a pinned package download its file first, compare it with the
+//! pins of the package and install that file only when one of them matches,
+//! every field it sets included. Pins without a `version` apply to every
+//! version. Anything else fails closed: a mismatch, a download that cannot be
+//! checked, or an install path that cannot download the file beforehand.
+//!
+//! Signing keys are the names APK packages carry their signature under
+//! (`.SIGN.RSA.<key>`); Debian packages are not signed themselves, so APT
+//! pins use `sha256`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use super::parse::apk::split_package_tag;
+use super::parse::parse_sha256sum;
+use super::runner::CommandRunner;
+use super::{FetchedPackage, command};
+use crate::error::PackageManagerError;
+
+/// Suffix of the next directory packages are downloaded into
+static NEXT_FETCH_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Errors loading a pins file
+#[derive(Debug, thiserror::Error)]
+pub enum PinsError {
+    #[error("failed to read package pins file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid package pins file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("pin of {name}: {reason}")]
+    Invalid { name: String, reason: String },
+}
+
+/// Expected artifact of a package
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackagePin {
+    pub name: String,
+    /// Version the pin applies to, every version when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Lowercase hex SHA-256 of the package file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Name of the key the package file must be signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+}
+
+impl PackagePin {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            version: None,
+            sha256: None,
+            signing_key: None,
+        }
+    }
+
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn sha256(mut self, digest: impl Into<String>) -> Self {
+        self.sha256 = Some(digest.into().to_ascii_lowercase());
+        self
+    }
+
+    pub fn signing_key(mut self, key: impl Into<String>) -> Self {
+        self.signing_key = Some(key.into());
+        self
+    }
+
+    fn applies_to(&self, version: &str) -> bool {
+        self.version
+            .as_deref()
+            .is_none_or(|pinned| pinned == version)
+    }
+
+    fn matches(&self, artifact: &FetchedPackage) -> bool {
+        self.applies_to(&artifact.package.version)
+            && self
+                .sha256
+                .as_deref()
+                .is_none_or(|digest| digest == artifact.sha256)
+            && self
+                .signing_key
+                .as_deref()
+                .is_none_or(|key| artifact.signing_key.as_deref() == Some(key))
+    }
+}
+
+impl fmt::Display for PackagePin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(version) = &self.version {
+            write!(f, " {version}")?;
+        }
+        if let Some(digest) = &self.sha256 {
+            write!(f, " sha256 {digest}")?;
+        }
+        if let Some(key) = &self.signing_key {
+            write!(f, " signed by {key}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Pins of critical packages, by package name
+#[derive(Clone, Debug, Default)]
+pub struct PackagePins {
+    pins: Vec<PackagePin>,
+}
+
+#[derive(Deserialize)]
+struct PinsFile {
+    #[serde(default)]
+    package: Vec<PackagePin>,
+}
+
+impl PackagePins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a pin; several pins of a package are alternatives
+    pub fn pin(mut self, pin: PackagePin) -> Self {
+        self.pins.push(pin);
+        self
+    }
+
+    /// Load pins from the TOML file at `path`
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, PinsError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|source| PinsError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_toml(&contents)
+    }
+
+    /// Parse pins from TOML
+    pub fn from_toml(contents: &str) -> Result<Self, PinsError> {
+        let file: PinsFile = toml::from_str(contents)?;
+        let mut pins = Self::new();
+        for pin in file.package {
+            let invalid = |reason: &str| PinsError::Invalid {
+                name: pin.name.clone(),
+                reason: reason.to_string(),
+            };
+            if pin.sha256.is_none() && pin.signing_key.is_none() {
+                return Err(invalid("set a sha256 or a signing_key"));
+            }
+            if let Some(digest) = &pin.sha256
+                && (digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()))
+            {
+                return Err(invalid("the sha256 must be 64 hexadecimal digits"));
+            }
+            pins.pins.push(PackagePin {
+                sha256: pin.sha256.map(|digest| digest.to_ascii_lowercase()),
+                ..pin
+            });
+        }
+        Ok(pins)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+
+    /// Whether installs of `package` must verify its artifact
+    ///
+    /// APK packages named with a repository tag (`openssl@testing`) are
+    /// pinned like the plain name.
+    pub fn is_pinned(&self, package: &str) -> bool {
+        let (name, _) = split_package_tag(package);
+        self.pins.iter().any(|pin| pin.name == name)
+    }
+
+    /// Refuse a downloaded artifact that no pin of its package matches
+    ///
+    /// Packages without pins are accepted.
+    pub fn verify(&self, artifact: &FetchedPackage) -> Result<(), PackageManagerError> {
+        let name = &artifact.package.name;
+        let pins: Vec<&PackagePin> = self.pins.iter().filter(|pin| pin.name == *name).collect();
+        if pins.is_empty() || pins.iter().any(|pin| pin.matches(artifact)) {
+            return Ok(());
+        }
+        let version = &artifact.package.version;
+        Err(PackageManagerError::ArtifactMismatch {
+            package: name.clone(),
+            version: version.clone(),
+            sha256: artifact.sha256.clone(),
+            signing_key: artifact.signing_key.clone(),
+            expected: pins
+                .into_iter()
+                .filter(|pin| pin.applies_to(version))
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+/// Create an empty directory, only accessible by the server's user, to
+/// download a package into
+pub(crate) async fn create_fetch_directory(
+    runner: &dyn CommandRunner,
+) -> Result<String, PackageManagerError> {
+    let dir = format!(
+        "/tmp/package-manager-mcp-{}-fetch-{}",
+        std::process::id(),
+        NEXT_FETCH_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let output = runner
+        .run(command("mkdir").args(["-m", "700", "--", &dir]))
+        .await
+        .map_err(|err| PackageManagerError::io(format!("failed to create {dir}"), err))?;
+    output.success()?;
+    Ok(dir)
+}
+
+/// Path of the file with `extension` downloaded into `dir`
+pub(crate) async fn fetched_file(
+    runner: &dyn CommandRunner,
+    dir: &str,
+    extension: &str,
+) -> Result<String, PackageManagerError> {
+    let entries = runner
+        .list_dir(dir)
+        .await
+        .map_err(|err| PackageManagerError::io(format!("failed to list {dir}"), err))?;
+    let file = entries
+        .into_iter()
+        .find(|entry| entry.ends_with(extension))
+        .ok_or_else(|| PackageManagerError::Io {
+            context: format!("no {extension} file was downloaded into {dir}"),
+            source: std::io::ErrorKind::NotFound.into(),
+        })?;
+    Ok(format!("{dir}/{file}"))
+}
+
+/// SHA-256 of a file on the managed system
+pub(crate) async fn file_sha256(
+    runner: &dyn CommandRunner,
+    path: &str,
+) -> Result<String, PackageManagerError> {
+    let output = runner
+        .run(command("sha256sum").args(["--", path]))
+        .await
+        .map_err(|err| PackageManagerError::io(format!("failed to checksum {path}"), err))?;
+    let exec_result = output.success()?;
+    parse_sha256sum(exec_result.stdout.as_deref().unwrap_or_default()).ok_or_else(|| {
+        PackageManagerError::Io {
+            context: format!("sha256sum printed no digest for {path}"),
+            source: std::io::ErrorKind::InvalidData.into(),
+        }
+    })
+}
+
+/// Remove the directory a package was downloaded into
+pub(crate) async fn remove_fetch_directory(
+    runner: &dyn CommandRunner,
+    dir: &str,
+) -> Result<(), PackageManagerError> {
+    let output = runner
+        .run(command("rm").args(["-rf", "--", dir]))
+        .await
+        .map_err(|err| PackageManagerError::io(format!("failed to remove {dir}"), err))?;
+    output.success()?;
+    Ok(())
+}
@@ -0,0 +1,111 @@
+//! Package search through the Alpine packages site
+//!
+//! Discovery-style searches do not need the full index of every repository:
+//! the packages site (`https://pkgs.alpinelinux.org`) answers a query with the
+//! matching packages of a branch, filtered by repository and architecture,
+//! and reports their license, maintainer and build date as well. The APK
+//! backend can use [`PackagesSite`] as its search provider instead of
+//! repository indexes.
+
+use std::time::Duration;
+
+use super::parse::apk::parse_packages_page;
+use super::{SearchMode, SearchResult};
+
+/// Alpine packages site used by default
+pub const DEFAULT_PACKAGES_SITE: &str = "https://pkgs.alpinelinux.org";
+
+/// How long a query to the site may take
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Search provider querying the Alpine packages site
+#[derive(Clone, Debug)]
+pub struct PackagesSite {
+    url: String,
+    branch: Option<String>,
+    repository: Option<String>,
+    client: reqwest::Client,
+}
+
+impl PackagesSite {
+    pub fn new() -> Self {
+        Self::with_url(DEFAULT_PACKAGES_SITE)
+    }
+
+    /// Query the site at `url`, e.g. a mirror of the packages site
+    pub fn with_url(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into().trim_end_matches('/').to_string(),
+            branch: None,
+            repository: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Search this branch (`edge`, `v3.22`) instead of the system's
+    pub fn with_branch(mut self, branch: impl Into<String>) -> Self {
+        self.branch = Some(branch.into());
+        self
+    }
+
+    /// Only search this repository (`main`, `community`, `testing`)
+    pub fn with_repository(mut self, repository: impl Into<String>) -> Self {
+        self.repository = Some(repository.into());
+        self
+    }
+
+    /// Branch searched when the search does not select one
+    pub(crate) fn branch(&self) -> Option<&str> {
+        self.branch.as_deref()
+    }
+
+    /// Packages whose name matches `name` in `mode` on `branch`, for `arch`
+    ///
+    /// The site only searches names, so description searches match names
+    /// containing `name`. `repository` overrides the configured repository
+    /// filter. Results have their repository set to its path on the mirrors,
+    /// e.g. `v3.22/main`.
+    pub(crate) async fn search(
+        &self,
+        name: &str,
+        mode: SearchMode,
+        branch: Option<&str>,
+        repository: Option<&str>,
+        arch: &str,
+    ) -> Result<Vec<SearchResult>, reqwest::Error> {
+        let pattern = mode.name_glob(name);
+        let mut query = vec![("name", pattern.as_str()), ("arch", arch)];
+        if let Some(branch) = branch {
+            query.push(("branch", branch));
+        }
+        if let Some(repository) = repository.or(self.repository.as_deref()) {
+            query.push(("repo", repository));
+        }
+
+        let page = self
+            .client
+            .get(format!("{}/packages", self.url))
+            .query(&query)
+            .timeout(QUERY_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let matches = SearchMode::Wildcard.matcher(&pattern);
+        Ok(parse_packages_page(&page)
+            .into_iter()
+            .filter(|package| match mode {
+                SearchMode::Exact => package.name == name,
+                _ => matches(&package.name, None),
+            })
+            .collect())
+    }
+}
+
+impl Default for PackagesSite {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,451 @@
+//! Backend delegating to an external plugin executable
+//!
+//! The plugin is spawned once and speaks newline-delimited JSON-RPC 2.0 over
+//! its stdin and stdout. Every [`PackageManager`] method maps to a request of
+//! the same name whose `params` are the serialized options:
+//!
+//! | Method                         | Params                    | Result                      |
+//! |--------------------------------|---------------------------|-----------------------------|
+//! | `initialize`                   | `{}`                      | [`PluginInfo`]              |
+//! | `install_package`              | [`InstallOptions`]        | [`InstallOutcome`]          |
+//! | `install_package_with_version` | [`InstallVersionOptions`] | [`InstallOutcome`]          |
+//! | `search_package`               | [`SearchOptions`]         | array of [`SearchResult`]   |
+//! | `list_installed_packages`      | `{}`                      | array of [`InstalledPackage`] |
+//! | `refresh_repositories`         | `{}`                      | [`ExecResult`]              |
+//! | `list_repositories`            | `{}`                      | array of [`Repository`]     |
+//! | `list_search_repositories`     | `{}`                      | array of [`Repository`]     |
+//! | `check_privileges`             | `{}`                      | [`PrivilegeReport`]         |
+//! | `install_commands`             | `{"packages": [...]}` of [`PinnedPackage`] | array of strings |
+//! | `package_licenses`             | `{}`                      | array of [`PackageLicense`] |
+//! | `osv_ecosystem`                | `{}`                      | string, e.g. `"Alpine:v3.22"` |
+//! | `source_packages`              | `{}`                      | object mapping package names to source names |
+//! | `system_info`                  | `{}`                      | [`SystemInfo`]              |
+//! | `required_space`               | `{"packages": [...], "arch": ..., "install_recommends": ...}` | array of [`SpaceRequirement`] |
+//! | `preseed`                      | `{"selections": [...]}`   | `null`                      |
+//!
+//! Failures are reported as JSON-RPC errors. An `error_type` in the error
+//! `data` (one of the codes returned by [`PackageManagerError::error_type`])
+//! is mapped back to the matching error variant so clients see the same
+//! failure classes as with the built-in backends.
+//!
+//! While a request is running the plugin may send `output` notifications with
+//! `{"stream": "stdout" | "stderr", "line": "..."}` params; they are forwarded
+//! as progress like the output of built-in backends. Anything the plugin
+//! writes to stderr is passed through to the server's stderr.
+
+use std::process::Stdio;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::Mutex;
+
+use super::exec::{self, OutputLine, OutputStream};
+use super::privileges::PrivilegeReport;
+use super::signing::UnsignedRepository;
+use super::{
+    Capabilities, ExecResult, InstallOptions, InstallOutcome, InstallVersionOptions,
+    InstalledPackage, LockedPackage, PackageLicense, PackageManager, PinnedPackage, Recommends,
+    Repository, SearchOptions, SearchResult, SpaceRequirement, SystemInfo,
+};
+use crate::error::PackageManagerError;
+
+/// Package manager backend implemented by an external executable
+pub struct PluginBackend {
+    name: &'static str,
+    os_name: &'static str,
+    capabilities: Capabilities,
+    process: Mutex<PluginProcess>,
+}
+
+struct PluginProcess {
+    // Kept so the plugin is killed when the backend is dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: u64,
+}
+
+/// Result of the `initialize` request
+#[derive(Deserialize)]
+pub struct PluginInfo {
+    /// Name of the package manager
+    pub name: String,
+    /// Name of the operating system
+    pub os_name: String,
+    /// Optional features supported by the plugin, all enabled when omitted
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    id: Option<u64>,
+    method: Option<String>,
+    #[serde(default)]
+    params: Value,
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    message: String,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Deserialize)]
+struct OutputParams {
+    stream: String,
+    line: String,
+}
+
+impl PluginBackend {
+    /// Spawn the plugin executable and perform the `initialize` handshake
+    pub async fn spawn(program: &str, args: &[String]) -> Result<Self, PackageManagerError> {
+        // The plugin is a long-lived local process rather than a command run
+        // to completion, so it is spawned directly instead of by a runner
+        let mut child = tokio::process::Command::new(program)
+            .kill_on_drop(true)
+            .args(args)
+            .envs(super::runner::COMMAND_LOCALE)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|err| {
+                PackageManagerError::io(format!("Failed to start plugin '{program}'"), err)
+            })?;
+
+        let stdin = child.stdin.take().ok_or_else(|| {
+            PackageManagerError::io(
+                "Failed to start plugin",
+                std::io::Error::other("plugin stdin was not captured"),
+            )
+        })?;
+        let stdout = child.stdout.take().ok_or_else(|| {
+            PackageManagerError::io(
+                "Failed to start plugin",
+                std::io::Error::other("plugin stdout was not captured"),
+            )
+        })?;
+
+        let process = PluginProcess {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            next_id: 1,
+        };
+        let mut backend = Self {
+            name: "plugin",
+            os_name: "plugin",
+            capabilities: Capabilities::default(),
+            process: Mutex::new(process),
+        };
+
+        let info: PluginInfo = backend.call("initialize", &serde_json::json!({})).await?;
+        // The names are requested once per plugin for the lifetime of the
+        // server, so leaking them is the simplest way to satisfy the trait.
+        backend.name = Box::leak(info.name.into_boxed_str());
+        backend.os_name = Box::leak(info.os_name.into_boxed_str());
+        backend.capabilities = info.capabilities;
+
+        Ok(backend)
+    }
+
+    /// Send a request and wait for its response
+    ///
+    /// Requests are serialized: the plugin only ever sees one request at a time.
+    async fn call<P: Serialize, R: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &P,
+    ) -> Result<R, PackageManagerError> {
+        let mut process = self.process.lock().await;
+        let id = process.next_id;
+        process.next_id += 1;
+
+        let mut request = serde_json::to_vec(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))
+        .map_err(|err| protocol_error(method, err))?;
+        request.push(b'\n');
+
+        process
+            .stdin
+            .write_all(&request)
+            .await
+            .map_err(|err| PackageManagerError::io("Failed to write to plugin", err))?;
+        process
+            .stdin
+            .flush()
+            .await
+            .map_err(|err| PackageManagerError::io("Failed to write to plugin", err))?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = process
+                .stdout
+                .read_line(&mut line)
+                .await
+                .map_err(|err| PackageManagerError::io("Failed to read from plugin", err))?;
+            if read == 0 {
+                return Err(PackageManagerError::Plugin {
+                    message: format!("plugin exited while handling '{method}'"),
+                });
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let message: Message =
+                serde_json::from_str(&line).map_err(|err| protocol_error(method, err))?;
+
+            if message.id.is_none() {
+                if message.method.as_deref() == Some("output") {
+                    forward_output(message.params);
+                }
+                continue;
+            }
+            if message.id != Some(id) {
+                tracing::warn!(
+                    "Ignoring plugin response with unexpected id {:?}",
+                    message.id
+                );
+                continue;
+            }
+
+            if let Some(error) = message.error {
+                return Err(error.into());
+            }
+            return serde_json::from_value(message.result.unwrap_or(Value::Null))
+                .map_err(|err| protocol_error(method, err));
+        }
+    }
+}
+
+#[async_trait]
+impl PackageManager for PluginBackend {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn os_name(&self) -> &'static str {
+        self.os_name
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    async fn install_package(
+        &self,
+        options: &InstallOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        self.call("install_package", options).await
+    }
+
+    async fn install_package_with_version(
+        &self,
+        options: &InstallVersionOptions,
+    ) -> Result<InstallOutcome, PackageManagerError> {
+        self.call("install_package_with_version", options).await
+    }
+
+    async fn search_package(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchResult>, PackageManagerError> {
+        self.call("search_package", options).await
+    }
+
+    async fn list_installed_packages(&self) -> Result<Vec<InstalledPackage>, PackageManagerError> {
+        self.call("list_installed_packages", &serde_json::json!({}))
+            .await
+    }
+
+    async fn refresh_repositories(&self) -> Result<ExecResult, PackageManagerError> {
+        self.call("refresh_repositories", &serde_json::json!({}))
+            .await
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        self.call("list_repositories", &serde_json::json!({})).await
+    }
+
+    async fn list_search_repositories(&self) -> Result<Vec<Repository>, PackageManagerError> {
+        self.call("list_search_repositories", &serde_json::json!({}))
+            .await
+    }
+
+    async fn check_privileges(&self) -> Result<PrivilegeReport, PackageManagerError> {
+        self.call("check_privileges", &serde_json::json!({})).await
+    }
+
+    async fn install_commands(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<String>, PackageManagerError> {
+        self.call(
+            "install_commands",
+            &serde_json::json!({ "packages": packages }),
+        )
+        .await
+    }
+
+    async fn lock_packages(
+        &self,
+        packages: &[PinnedPackage],
+    ) -> Result<Vec<LockedPackage>, PackageManagerError> {
+        self.call(
+            "lock_packages",
+            &serde_json::json!({ "packages": packages }),
+        )
+        .await
+    }
+
+    async fn install_locked(
+        &self,
+        packages: &[LockedPackage],
+    ) -> Result<Vec<InstallOutcome>, PackageManagerError> {
+        self.call(
+            "install_locked",
+            &serde_json::json!({ "packages": packages }),
+        )
+        .await
+    }
+
+    async fn unsigned_repositories(
+        &self,
+        repository: Option<&str>,
+    ) -> Result<Vec<UnsignedRepository>, PackageManagerError> {
+        self.call(
+            "unsigned_repositories",
+            &serde_json::json!({ "repository": repository }),
+        )
+        .await
+    }
+
+    async fn package_licenses(&self) -> Result<Vec<PackageLicense>, PackageManagerError> {
+        self.call("package_licenses", &serde_json::json!({})).await
+    }
+
+    async fn osv_ecosystem(&self) -> Result<String, PackageManagerError> {
+        self.call("osv_ecosystem", &serde_json::json!({})).await
+    }
+
+    async fn system_info(&self) -> Result<SystemInfo, PackageManagerError> {
+        self.call("system_info", &serde_json::json!({})).await
+    }
+
+    async fn required_space(
+        &self,
+        packages: &[String],
+        arch: Option<&str>,
+        recommends: Option<Recommends>,
+    ) -> Result<Vec<SpaceRequirement>, PackageManagerError> {
+        self.call(
+            "required_space",
+            &serde_json::json!({
+                "packages": packages,
+                "arch": arch,
+                "install_recommends": recommends,
+            }),
+        )
+        .await
+    }
+
+    async fn source_packages(
+        &self,
+    ) -> Result<std::collections::HashMap<String, String>, PackageManagerError> {
+        self.call("source_packages", &serde_json::json!({})).await
+    }
+
+    async fn preseed(&self, selections: &[String]) -> Result<(), PackageManagerError> {
+        self.call("preseed", &serde_json::json!({ "selections": selections }))
+            .await
+    }
+}
+
+fn forward_output(params: Value) {
+    let Ok(params) = serde_json::from_value::<OutputParams>(params) else {
+        return;
+    };
+    let stream = match params.stream.as_str() {
+        "stderr" => OutputStream::Stderr,
+        _ => OutputStream::Stdout,
+    };
+    exec::emit(OutputLine {
+        stream,
+        line: params.line,
+    });
+}
+
+fn protocol_error(method: &str, err: serde_json::Error) -> PackageManagerError {
+    PackageManagerError::Plugin {
+        message: format!("invalid message for '{method}': {err}"),
+    }
+}
+
+impl From<RpcError> for PackageManagerError {
+    fn from(error: RpcError) -> Self {
+        let data = &error.data;
+        let string = |key: &str| {
+            data.get(key)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let strings = |key: &str| -> Vec<String> {
+            data.get(key)
+                .and_then(|value| serde_json::from_value(value.clone()).ok())
+                .unwrap_or_default()
+        };
+
+        match data.get("error_type").and_then(Value::as_str) {
+            Some("package_not_found") => Self::NotFound {
+                package: string("package_name"),
+                searched_repositories: strings("searched_repositories"),
+            },
+            Some("version_not_found") => Self::VersionNotFound {
+                package: string("package_name"),
+                version: string("requested_version"),
+                available_versions: strings("available_versions"),
+            },
+            Some("permission_denied") => Self::PermissionDenied {
+                message: error.message,
+            },
+            Some("network_failure") => Self::NetworkFailure {
+                message: error.message,
+            },
+            Some("lock_held") => Self::LockHeld {
+                message: error.message,
+            },
+            Some("command_failed") => Self::CommandFailed(ExecResult {
+                stdout: data.get("stdout").and_then(Value::as_str).map(Into::into),
+                stderr: data
+                    .get("stderr")
+                    .and_then(Value::as_str)
+                    .map(Into::into)
+                    .or(Some(error.message)),
+                status: data
+                    .get("exit_code")
+                    .and_then(Value::as_i64)
+                    .and_then(|code| i32::try_from(code).ok())
+                    .unwrap_or(-1),
+                truncated: false,
+                command: None,
+            }),
+            _ => Self::Plugin {
+                message: error.message,
+            },
+        }
+    }
+}
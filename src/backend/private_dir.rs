@@ -0,0 +1,83 @@
+//! Private directories for the files package managers read as root
+//!
+//! APT reads repository credentials and generated sources from files the
+//! server writes. At a path other users can guess, a local user could
+//! create the file first, or a symlink in its place, and read the secrets
+//! or inject a source apt-get trusts. A [`PrivateDir`] is created on the
+//! managed system under a random name with `mkdir -m 700`, which fails
+//! rather than reuse an existing directory, so only the server's user can
+//! create files in it. It is removed with its files when dropped.
+
+use std::sync::Arc;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::sync::OnceCell;
+
+use super::command;
+use super::runner::CommandRunner;
+use crate::error::PackageManagerError;
+
+/// Directory only accessible by the server's user, created on first use
+pub(crate) struct PrivateDir {
+    path: String,
+    /// Runner the directory was created with, to remove it
+    created: OnceCell<Arc<dyn CommandRunner>>,
+}
+
+impl PrivateDir {
+    /// A directory in `/tmp` named after `purpose` and a random suffix
+    pub(crate) fn new(purpose: &str) -> Self {
+        let mut suffix = [0u8; 8];
+        // Without randomness, the name is still unique: `mkdir` fails when
+        // another user created it first
+        let _ = SystemRandom::new().fill(&mut suffix);
+        let suffix: String = suffix.iter().map(|byte| format!("{byte:02x}")).collect();
+        Self {
+            path: format!(
+                "/tmp/package-manager-mcp-{}-{purpose}-{suffix}",
+                std::process::id()
+            ),
+            created: OnceCell::new(),
+        }
+    }
+
+    /// Path of the file `name` in the directory
+    pub(crate) fn file(&self, name: &str) -> String {
+        format!("{}/{name}", self.path)
+    }
+
+    /// Create the directory through `runner` unless it was created already
+    pub(crate) async fn create(
+        &self,
+        runner: &Arc<dyn CommandRunner>,
+    ) -> Result<(), PackageManagerError> {
+        self.created
+            .get_or_try_init(|| async {
+                let output = runner
+                    .run(command("mkdir").args(["-m", "700", "--", &self.path]))
+                    .await
+                    .map_err(|err| {
+                        PackageManagerError::io(format!("failed to create {}", self.path), err)
+                    })?;
+                output.success()?;
+                Ok::<_, PackageManagerError>(runner.clone())
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let Some(runner) = self.created.take() else {
+            return;
+        };
+        // The directory lives on the runner's target, so removing it is async
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let path = std::mem::take(&mut self.path);
+            runtime.spawn(async move {
+                let _ = runner.run(command("rm").args(["-rf", "--", &path])).await;
+            });
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Checks of whether the server can modify the system
+//!
+//! Installs and refreshes need write access to the package database, which
+//! usually means running as root, directly or through an
+//! [`EscalatingRunner`](super::runner::EscalatingRunner). The check runs at
+//! startup and through the `check_privileges` tool, and its
+//! [`PrivilegeReport`] lets the handler hide mutating tools from clients
+//! when they would only fail.
+
+use serde::{Deserialize, Serialize};
+
+use super::command;
+use super::runner::CommandRunner;
+
+/// Privilege escalation used by a runner, as found by its preflight check
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EscalationReport {
+    /// `sudo` or `doas`
+    pub program: String,
+    /// Whether privileged commands can run, because the user is root or
+    /// passwordless escalation works
+    pub available: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+}
+
+/// Whether the server can perform mutating operations
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrivilegeReport {
+    /// Effective user ID commands run as, when it could be determined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_uid: Option<u32>,
+    /// Escalation of privileged commands, when configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalation: Option<EscalationReport>,
+    /// Package database directory that installs lock and write
+    pub database: String,
+    /// Whether privileged commands can write to `database`
+    pub database_writable: bool,
+    /// Whether installs and repository refreshes can succeed
+    pub can_mutate: bool,
+    /// Why they cannot, in a form suitable for users
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+}
+
+impl PrivilegeReport {
+    /// Report for a server that can perform every operation
+    pub fn unrestricted(database: impl Into<String>) -> Self {
+        Self {
+            effective_uid: None,
+            escalation: None,
+            database: database.into(),
+            database_writable: true,
+            can_mutate: true,
+            problems: Vec::new(),
+        }
+    }
+}
+
+/// Check whether privileged commands run through `runner` can write to the
+/// package database directory
+///
+/// Writability is tested with a privileged `test -w`, so it goes through the
+/// same escalation as installs and also catches read-only file systems.
+pub(crate) async fn check(runner: &dyn CommandRunner, database: &str) -> PrivilegeReport {
+    let mut problems = Vec::new();
+
+    let effective_uid = match runner.run(command("id").arg("-u")).await {
+        Ok(result) if result.status == 0 => result
+            .stdout
+            .as_deref()
+            .and_then(|stdout| stdout.trim().parse().ok()),
+        _ => None,
+    };
+
+    let escalation = runner.escalation().await;
+    if let Some(problem) = escalation
+        .as_ref()
+        .and_then(|escalation| escalation.problem.clone())
+    {
+        problems.push(problem);
+    }
+
+    let database_writable = match runner
+        .run(command("test").privileged().arg("-w").arg(database))
+        .await
+    {
+        Ok(result) => result.status == 0,
+        Err(_) => false,
+    };
+    if !database_writable && problems.is_empty() {
+        problems.push(match effective_uid {
+            Some(uid) if uid != 0 => format!(
+                "the server runs as user {uid} and cannot write to {database}; run it as root or with --escalate"
+            ),
+            _ => format!("{database} is not writable, the file system may be read-only"),
+        });
+    }
+
+    PrivilegeReport {
+        effective_uid,
+        escalation,
+        database: database.to_string(),
+        database_writable,
+        can_mutate: database_writable,
+        problems,
+    }
+}
@@ -0,0 +1,343 @@
+//! Registry of backends selectable by name
+//!
+//! Built-in backends are registered by [`BackendRegistry::with_builtins`].
+//! Embedders can register their own backends (for example a preconfigured
+//! [`PluginBackend`] or [`GenericBackend`]) under a new name and let the same
+//! CLI or configuration value select between all of them.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::apk::{Apk, DEFAULT_INDEX_CACHE_MAX_AGE};
+use super::apt::Apt;
+use super::credentials::RepositoryCredentials;
+use super::generic::{BackendConfigError, GenericBackend};
+use super::mirrors::MirrorList;
+use super::mock::MockBackend;
+use super::offline::OfflineMirrors;
+use super::pkgs::PackagesSite;
+use super::plugin::PluginBackend;
+use super::runner::{CommandRunner, LocalRunner};
+use super::snapshot::SnapshotArchive;
+use super::{BackendKind, PackageManager, Recommends};
+use crate::error::PackageManagerError;
+
+/// Name selecting the backend detected from the host OS
+pub const AUTO: &str = "auto";
+
+/// Errors raised while creating a backend from the registry
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("Unknown backend '{name}'. Available backends: {}", available.join(", "))]
+    UnknownBackend {
+        name: String,
+        available: Vec<String>,
+    },
+    #[error("Unsupported OS: neither Alpine nor Debian detected")]
+    DetectionFailed,
+    #[error("Backend '{backend}' requires the {option} option")]
+    MissingOption {
+        backend: String,
+        option: &'static str,
+    },
+    #[error(transparent)]
+    Config(#[from] BackendConfigError),
+    #[error(transparent)]
+    Backend(#[from] PackageManagerError),
+}
+
+/// Options passed to backend factories
+///
+/// Backends only read the options relevant to them.
+#[derive(Clone)]
+pub struct BackendOptions {
+    /// Configuration file, used by the `generic` and `mock` backends
+    pub config: Option<PathBuf>,
+    /// Executable, used by the `plugin` backend
+    pub program: Option<String>,
+    /// Arguments passed to `program`
+    pub args: Vec<String>,
+    /// Where the `apk`, `apt` and `generic` backends run their commands
+    pub runner: Arc<dyn CommandRunner>,
+    /// Alpine mirror base URLs the `apk` backend fails over between, in
+    /// order of preference (default: [`super::apk::DEFAULT_MIRRORS`])
+    pub mirrors: Vec<String>,
+    /// Repositories the `apk` backend searches when no repository is given
+    /// (default: derived from the target's Alpine release)
+    pub search_repositories: Vec<String>,
+    /// Stable branches older than the target's that the `apk` backend
+    /// searches (default: [`super::apk::DEFAULT_OLDER_BRANCHES`])
+    pub older_branches: Option<usize>,
+    /// Directory where the `apk` backend keeps the indexes of searched
+    /// repositories (default: none, indexes are downloaded for every search)
+    pub index_cache: Option<String>,
+    /// How long the `apk` backend uses cached indexes (default:
+    /// [`super::apk::DEFAULT_INDEX_CACHE_MAX_AGE`])
+    pub index_cache_max_age: Option<Duration>,
+    /// Whether the `apk` backend searches by downloading and parsing the
+    /// repository indexes itself instead of running `apk search`
+    pub native_index: bool,
+    /// Whether the `apk` backend runs every install without maintainer
+    /// scripts
+    pub no_scripts: bool,
+    /// Alpine packages site the `apk` backend searches through instead of
+    /// running `apk search`, e.g. [`super::pkgs::DEFAULT_PACKAGES_SITE`]
+    pub packages_site: Option<String>,
+    /// Branch searched on the packages site (default: the target's branch)
+    pub packages_branch: Option<String>,
+    /// Snapshot archive the `apt` backend installs historical versions from,
+    /// e.g. [`super::snapshot::DEFAULT_SNAPSHOT_ARCHIVE`]
+    pub snapshot_archive: Option<String>,
+    /// Suite installed from the snapshot archive (default: the target's
+    /// release codename)
+    pub snapshot_suite: Option<String>,
+    /// Weaker dependencies the `apt` backend installs when calls do not
+    /// choose (default: APT's configuration)
+    pub install_recommends: Option<Recommends>,
+    /// Credentials the `apk` and `apt` backends authenticate to private
+    /// repositories with
+    pub credentials: Option<RepositoryCredentials>,
+    /// Internal mirrors the `apk` and `apt` backends reach instead of the
+    /// public repositories (default: none, online)
+    pub offline: Option<OfflineMirrors>,
+    /// Root filesystem managed instead of `/`, for backends supporting
+    /// [`PackageManager::with_root`]
+    pub root: Option<String>,
+}
+
+impl Default for BackendOptions {
+    fn default() -> Self {
+        Self {
+            config: None,
+            program: None,
+            args: Vec::new(),
+            runner: Arc::new(LocalRunner),
+            mirrors: Vec::new(),
+            search_repositories: Vec::new(),
+            older_branches: None,
+            index_cache: None,
+            index_cache_max_age: None,
+            native_index: false,
+            no_scripts: false,
+            packages_site: None,
+            packages_branch: None,
+            snapshot_archive: None,
+            snapshot_suite: None,
+            install_recommends: None,
+            credentials: None,
+            offline: None,
+            root: None,
+        }
+    }
+}
+
+type BackendFuture =
+    Pin<Box<dyn Future<Output = Result<Arc<dyn PackageManager>, RegistryError>> + Send>>;
+type BackendFactory = Arc<dyn Fn(BackendOptions) -> BackendFuture + Send + Sync>;
+
+#[derive(Clone)]
+struct Registration {
+    name: String,
+    description: String,
+    factory: BackendFactory,
+}
+
+/// Backends available for selection by name
+#[derive(Clone, Default)]
+pub struct BackendRegistry {
+    registrations: Vec<Registration>,
+}
+
+impl BackendRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry containing the backends shipped with this crate
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        for kind in [BackendKind::Apk, BackendKind::Apt] {
+            registry.register(
+                kind.name(),
+                kind.create().os_name(),
+                move |options: BackendOptions| async move {
+                    Ok(match kind {
+                        BackendKind::Apk => {
+                            let mut apk = Apk::with_runner(options.runner);
+                            if !options.mirrors.is_empty() {
+                                apk = apk.with_mirrors(MirrorList::new(options.mirrors));
+                            }
+                            if let Some(count) = options.older_branches {
+                                apk = apk.with_older_branches(count);
+                            }
+                            if !options.search_repositories.is_empty() {
+                                apk = apk.with_search_repositories(options.search_repositories);
+                            }
+                            let max_age = options
+                                .index_cache_max_age
+                                .unwrap_or(DEFAULT_INDEX_CACHE_MAX_AGE);
+                            if let Some(dir) = options.index_cache {
+                                apk = apk.with_index_cache(dir, max_age);
+                            }
+                            if options.native_index {
+                                apk = apk.with_native_index(max_age);
+                            }
+                            if let Some(url) = options.packages_site {
+                                let mut site = PackagesSite::with_url(url);
+                                if let Some(branch) = options.packages_branch {
+                                    site = site.with_branch(branch);
+                                }
+                                apk = apk.with_packages_site(site);
+                            }
+                            if let Some(credentials) = options.credentials {
+                                apk = apk.with_credentials(credentials);
+                            }
+                            if let Some(offline) = options.offline {
+                                apk = apk.with_offline_mirrors(offline);
+                            }
+                            if options.no_scripts {
+                                apk = apk.with_no_scripts();
+                            }
+                            Arc::new(apk) as Arc<dyn PackageManager>
+                        }
+                        BackendKind::Apt => {
+                            let mut apt = Apt::with_runner(options.runner);
+                            if let Some(url) = options.snapshot_archive {
+                                let mut archive = SnapshotArchive::with_url(url);
+                                if let Some(suite) = options.snapshot_suite {
+                                    archive = archive.with_suite(suite);
+                                }
+                                apt = apt.with_snapshot_archive(archive);
+                            }
+                            if let Some(recommends) = options.install_recommends {
+                                apt = apt.with_install_recommends(recommends);
+                            }
+                            if let Some(credentials) = options.credentials {
+                                apt = apt.with_credentials(credentials);
+                            }
+                            if let Some(offline) = options.offline {
+                                apt = apt.with_offline_mirrors(offline);
+                            }
+                            Arc::new(apt)
+                        }
+                    })
+                },
+            );
+        }
+        registry.register(
+            "generic",
+            "Command templates loaded from a TOML configuration",
+            |options: BackendOptions| async move {
+                let config = options.config.ok_or_else(|| RegistryError::MissingOption {
+                    backend: "generic".to_string(),
+                    option: "config",
+                })?;
+                Ok(
+                    Arc::new(GenericBackend::from_file(config)?.with_runner(options.runner))
+                        as Arc<dyn PackageManager>,
+                )
+            },
+        );
+        registry.register(
+            "mock",
+            "In-memory package database for tests and demos",
+            |options: BackendOptions| async move {
+                let backend = match options.config {
+                    Some(config) => MockBackend::from_file(config)?,
+                    None => MockBackend::new(),
+                };
+                Ok(Arc::new(backend) as Arc<dyn PackageManager>)
+            },
+        );
+        registry.register(
+            "plugin",
+            "External executable speaking JSON-RPC over stdio",
+            |options: BackendOptions| async move {
+                let program = options
+                    .program
+                    .ok_or_else(|| RegistryError::MissingOption {
+                        backend: "plugin".to_string(),
+                        option: "program",
+                    })?;
+                Ok(
+                    Arc::new(PluginBackend::spawn(&program, &options.args).await?)
+                        as Arc<dyn PackageManager>,
+                )
+            },
+        );
+        registry
+    }
+
+    /// Register a backend under the given name, replacing any previous one
+    pub fn register<F, Fut>(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        factory: F,
+    ) -> &mut Self
+    where
+        F: Fn(BackendOptions) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Arc<dyn PackageManager>, RegistryError>> + Send + 'static,
+    {
+        let name = name.into();
+        self.registrations
+            .retain(|registration| registration.name != name);
+        self.registrations.push(Registration {
+            name,
+            description: description.into(),
+            factory: Arc::new(move |options| Box::pin(factory(options))),
+        });
+        self
+    }
+
+    /// Names and descriptions of the registered backends
+    pub fn backends(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.registrations.iter().map(|registration| {
+            (
+                registration.name.as_str(),
+                registration.description.as_str(),
+            )
+        })
+    }
+
+    /// Create the backend registered under `name`
+    ///
+    /// [`AUTO`] selects the built-in backend for the system targeted by the
+    /// options' runner and root. The backend is moved to the options' root
+    /// once created.
+    pub async fn create(
+        &self,
+        name: &str,
+        options: BackendOptions,
+    ) -> Result<Arc<dyn PackageManager>, RegistryError> {
+        let name = if name == AUTO {
+            BackendKind::detect_on(&*options.runner, options.root.as_deref())
+                .await
+                .map_err(|err| PackageManagerError::io("there was an error detecting the OS", err))?
+                .ok_or(RegistryError::DetectionFailed)?
+                .name()
+        } else {
+            name
+        };
+
+        let registration = self
+            .registrations
+            .iter()
+            .find(|registration| registration.name == name)
+            .ok_or_else(|| RegistryError::UnknownBackend {
+                name: name.to_string(),
+                available: self.backends().map(|(name, _)| name.to_string()).collect(),
+            })?;
+
+        let root = options.root.clone();
+        let backend = (registration.factory)(options).await?;
+        match root {
+            Some(root) => Ok(backend.with_root(&root)?),
+            None => Ok(backend),
+        }
+    }
+}
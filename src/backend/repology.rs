@@ -0,0 +1,184 @@
+//! Cross-distribution package lookups in the Repology database
+//!
+//! The same project is often packaged under different names: `libcurl` is
+//! `curl-dev` on Alpine and `libcurl4-openssl-dev` on Debian. Repology tracks
+//! the packages of hundreds of repositories by project, so
+//! [`RepologyClient`] can tell what a project is called in each distribution
+//! and its newest version there, without searching every package manager.
+//!
+//! Repology asks API clients to identify themselves, so requests carry the
+//! server's name and version as their user agent.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Repology API used by default
+pub const DEFAULT_REPOLOGY_API: &str = "https://repology.org/api/v1";
+
+/// Repository families reported when the caller does not pick any
+pub const MAJOR_DISTRIBUTIONS: &[&str] = &[
+    "alpine",
+    "wolfi",
+    "debian",
+    "ubuntu",
+    "fedora",
+    "centos_stream",
+    "opensuse",
+    "arch",
+];
+
+/// How long a query to the API may take
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Repology client looking up the packages of a project
+#[derive(Clone, Debug)]
+pub struct RepologyClient {
+    url: String,
+    client: reqwest::Client,
+}
+
+/// A project's package in one repository
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DistroPackage {
+    /// Repology repository, e.g. `alpine_3_22` or `debian_12`
+    pub repository: String,
+    /// Binary package names to install
+    pub names: Vec<String>,
+    /// Source package name, when the repository reports one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_name: Option<String>,
+    /// Newest version of the project in the repository
+    pub version: String,
+    /// Repology's status of the version, e.g. `newest` or `outdated`
+    pub status: String,
+}
+
+#[derive(Deserialize)]
+struct RepologyPackage {
+    repo: String,
+    #[serde(default)]
+    srcname: Option<String>,
+    #[serde(default)]
+    binname: Option<String>,
+    #[serde(default)]
+    visiblename: Option<String>,
+    version: String,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+impl RepologyClient {
+    pub fn new() -> Self {
+        Self::with_url(DEFAULT_REPOLOGY_API)
+    }
+
+    /// Query the Repology API at `url`, e.g. a self-hosted instance
+    pub fn with_url(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into().trim_end_matches('/').to_string(),
+            client: reqwest::Client::builder()
+                .user_agent(concat!(
+                    "package-manager-mcp/",
+                    env!("CARGO_PKG_VERSION"),
+                    " (+https://github.com/endorhq/package-manager-mcp)"
+                ))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Packages of `project` in the repositories of the `families`, one per
+    /// repository in the order Repology lists them
+    ///
+    /// A family matches the repository of its name and its releases
+    /// (`debian` matches `debian_12` and `debian_unstable`).
+    pub(crate) async fn project(
+        &self,
+        project: &str,
+        families: &[String],
+    ) -> Result<Vec<DistroPackage>, reqwest::Error> {
+        let packages: Vec<RepologyPackage> = self
+            .client
+            .get(format!("{}/project/{project}", self.url))
+            .timeout(QUERY_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut found: Vec<(DistroPackage, usize)> = Vec::new();
+        for package in packages {
+            if !families.iter().any(|family| {
+                package.repo == *family
+                    || package
+                        .repo
+                        .strip_prefix(family.as_str())
+                        .is_some_and(|release| release.starts_with('_'))
+            }) {
+                continue;
+            }
+            let status = package.status.unwrap_or_else(|| "unknown".to_string());
+            let rank = status_rank(&status);
+            let name = package.binname.or(package.visiblename);
+            match found
+                .iter_mut()
+                .find(|(found, _)| found.repository == package.repo)
+            {
+                // Another binary package of the current version
+                Some((found, found_rank)) if found.version == package.version => {
+                    if let Some(name) = name
+                        && !found.names.contains(&name)
+                    {
+                        found.names.push(name);
+                    }
+                    if rank < *found_rank {
+                        found.status = status;
+                        *found_rank = rank;
+                    }
+                }
+                Some((found, found_rank)) => {
+                    if rank < *found_rank {
+                        *found = DistroPackage {
+                            repository: package.repo,
+                            names: name.into_iter().collect(),
+                            source_name: package.srcname,
+                            version: package.version,
+                            status,
+                        };
+                        *found_rank = rank;
+                    }
+                }
+                None => found.push((
+                    DistroPackage {
+                        repository: package.repo,
+                        names: name.into_iter().collect(),
+                        source_name: package.srcname,
+                        version: package.version,
+                        status,
+                    },
+                    rank,
+                )),
+            }
+        }
+        Ok(found.into_iter().map(|(package, _)| package).collect())
+    }
+}
+
+impl Default for RepologyClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Order of Repology statuses, the current version of a repository first
+fn status_rank(status: &str) -> usize {
+    match status {
+        "newest" | "unique" => 0,
+        "devel" => 1,
+        "outdated" => 2,
+        "legacy" => 4,
+        _ => 3,
+    }
+}
@@ -0,0 +1,880 @@
+//! Where backend commands run
+//!
+//! Backends describe the commands they need as [`Command`] values and hand
+//! them to a [`CommandRunner`], which decides where and how they execute. This
+//! keeps backend logic independent of the execution target:
+//!
+//! - [`LocalRunner`] spawns processes on the host (the default)
+//! - [`ChrootRunner`] runs them inside a root filesystem with `chroot`
+//! - [`ContainerRunner`] runs them in a running container with `docker exec`,
+//!   `podman exec` or `nerdctl exec`
+//! - [`KubernetesRunner`] runs them in a pod's container with `kubectl exec`
+//! - [`SshRunner`] runs them on a remote host over `ssh`
+//! - [`EscalatingRunner`] runs privileged commands through `sudo` or `doas`
+//! - [`RecordingRunner`] records commands and replays canned output in tests
+//!
+//! Runners also read and write the few configuration files backends need
+//! (repository lists, temporary source files), so those operations target the
+//! same system as the commands.
+//!
+//! Every command runs in the `C` locale ([`COMMAND_LOCALE`]): the output
+//! parsers expect English messages and `1,361 kB`-style numbers, which
+//! package managers only print when the target's locale is not a translated
+//! one.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use super::ExecResult;
+use super::exec::{self, OutputLine, OutputStream};
+use super::privileges::EscalationReport;
+
+/// Locale variables every command runs with, unless it sets them itself
+pub const COMMAND_LOCALE: [(&str, &str); 2] = [("LC_ALL", "C"), ("LANG", "C")];
+
+/// A program invocation, independent of where it runs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Command {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Environment variables set for the program
+    pub env: Vec<(String, String)>,
+    /// Data written to the program's standard input
+    pub stdin: Option<String>,
+    /// Whether the command modifies the system and needs root privileges
+    pub privileged: bool,
+}
+
+impl Command {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn arg(&mut self, arg: impl AsRef<str>) -> &mut Self {
+        self.args.push(arg.as_ref().to_string());
+        self
+    }
+
+    pub fn args<I>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_string()));
+        self
+    }
+
+    pub fn env(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn stdin(&mut self, input: impl Into<String>) -> &mut Self {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    /// Mark the command as modifying the system, e.g. installs and index
+    /// refreshes
+    pub fn privileged(&mut self) -> &mut Self {
+        self.privileged = true;
+        self
+    }
+
+    /// The program followed by its arguments
+    pub fn argv(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.program.as_str()).chain(self.args.iter().map(String::as_str))
+    }
+
+    /// The command as a POSIX shell would run it: its own environment
+    /// variables, then the quoted program and arguments
+    pub fn command_line(&self) -> String {
+        self.env
+            .iter()
+            .map(|(key, value)| shell_quote(&format!("{key}={value}")))
+            .chain(self.argv().map(shell_quote))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Environment variables the program runs with: the [`COMMAND_LOCALE`]
+    /// variables it does not set, then its own
+    pub fn environment(&self) -> impl Iterator<Item = (&str, &str)> {
+        COMMAND_LOCALE
+            .into_iter()
+            .filter(|(key, _)| !self.env.iter().any(|(set, _)| set == key))
+            .chain(
+                self.env
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_str())),
+            )
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in &self.env {
+            write!(f, "{key}={value} ")?;
+        }
+        let argv: Vec<&str> = self.argv().collect();
+        write!(f, "{}", argv.join(" "))
+    }
+}
+
+/// Executes commands and file operations on the target system
+///
+/// Only [`run`](Self::run) is required. The file operations default to
+/// running `cat`, `ls`, `sh` and `rm` through it, which works for any target
+/// with a POSIX userland; runners with direct file access override them.
+#[async_trait]
+pub trait CommandRunner: Send + Sync + 'static {
+    /// Run the command to completion, capturing its output
+    ///
+    /// Output lines are forwarded to the current output sink as they are
+    /// produced, like [`exec::stream_output`] documents.
+    async fn run(&self, command: &Command) -> std::io::Result<ExecResult>;
+
+    /// Read a file, returning `None` when it does not exist
+    async fn read_file(&self, path: &str) -> std::io::Result<Option<String>> {
+        let result = self.run(Command::new("cat").args(["--", path])).await?;
+        match result.status {
+            0 => Ok(Some(result.stdout.unwrap_or_default())),
+            _ if is_not_found(&result) => Ok(None),
+            _ => Err(command_error("cat", &result)),
+        }
+    }
+
+    /// Names of the entries of a directory, empty when it does not exist
+    async fn list_dir(&self, path: &str) -> std::io::Result<Vec<String>> {
+        let result = self
+            .run(Command::new("ls").args(["-1A", "--", path]))
+            .await?;
+        match result.status {
+            0 => Ok(result
+                .stdout
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_string)
+                .collect()),
+            _ if is_not_found(&result) => Ok(Vec::new()),
+            _ => Err(command_error("ls", &result)),
+        }
+    }
+
+    /// Create or replace a file
+    async fn write_file(&self, path: &str, contents: &str) -> std::io::Result<()> {
+        let result = self
+            .run(
+                Command::new("sh")
+                    .args(["-c", "cat > \"$1\"", "sh", path])
+                    .stdin(contents),
+            )
+            .await?;
+        match result.status {
+            0 => Ok(()),
+            _ => Err(command_error("sh", &result)),
+        }
+    }
+
+    /// How the runner escalates privileged commands, if it does
+    async fn escalation(&self) -> Option<EscalationReport> {
+        None
+    }
+
+    /// Remove a file, ignoring files that do not exist
+    async fn remove_file(&self, path: &str) -> std::io::Result<()> {
+        let result = self
+            .run(Command::new("rm").args(["-f", "--", path]))
+            .await?;
+        match result.status {
+            0 => Ok(()),
+            _ => Err(command_error("rm", &result)),
+        }
+    }
+}
+
+fn is_not_found(result: &ExecResult) -> bool {
+    result
+        .stderr
+        .as_deref()
+        .is_some_and(|stderr| stderr.contains("No such file"))
+}
+
+fn command_error(program: &str, result: &ExecResult) -> std::io::Error {
+    let stderr = result.stderr.as_deref().unwrap_or_default().trim();
+    if stderr.contains("Permission denied") {
+        std::io::Error::new(std::io::ErrorKind::PermissionDenied, stderr.to_string())
+    } else {
+        std::io::Error::other(format!(
+            "{program} exited with status {}: {stderr}",
+            result.status
+        ))
+    }
+}
+
+/// Runs commands as processes on the host
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalRunner;
+
+#[async_trait]
+impl CommandRunner for LocalRunner {
+    async fn run(&self, command: &Command) -> std::io::Result<ExecResult> {
+        let mut process = tokio::process::Command::new(&command.program);
+        // Cancelled tool calls must not leave package manager processes behind
+        process
+            .kill_on_drop(true)
+            .args(&command.args)
+            .envs(command.environment());
+        let result = exec::output(&mut process, command.stdin.as_deref()).await?;
+        Ok(ExecResult {
+            command: Some(command.command_line()),
+            ..result
+        })
+    }
+
+    async fn read_file(&self, path: &str) -> std::io::Result<Option<String>> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list_dir(&self, path: &str) -> std::io::Result<Vec<String>> {
+        let mut entries = match tokio::fs::read_dir(path).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    async fn write_file(&self, path: &str, contents: &str) -> std::io::Result<()> {
+        tokio::fs::write(path, contents).await
+    }
+
+    async fn remove_file(&self, path: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Runs commands inside a root filesystem with `chroot`
+///
+/// Files are accessed through the root directory from the outside, so the
+/// root does not need a shell or coreutils for file operations.
+pub struct ChrootRunner {
+    root: PathBuf,
+    inner: Arc<dyn CommandRunner>,
+}
+
+impl ChrootRunner {
+    /// Chroot into `root` from the host
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self::with_runner(root, Arc::new(LocalRunner))
+    }
+
+    /// Chroot into `root` using another runner to run `chroot`
+    pub fn with_runner(root: impl Into<PathBuf>, inner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            root: root.into(),
+            inner,
+        }
+    }
+
+    fn host_path(&self, path: &str) -> String {
+        self.root
+            .join(path.trim_start_matches('/'))
+            .display()
+            .to_string()
+    }
+}
+
+#[async_trait]
+impl CommandRunner for ChrootRunner {
+    async fn run(&self, command: &Command) -> std::io::Result<ExecResult> {
+        let mut chroot = Command::new("chroot");
+        chroot
+            .arg(self.root.display().to_string())
+            .args(command.argv());
+        chroot.env = command.env.clone();
+        chroot.stdin = command.stdin.clone();
+        chroot.privileged = command.privileged;
+        self.inner.run(&chroot).await
+    }
+
+    async fn read_file(&self, path: &str) -> std::io::Result<Option<String>> {
+        self.inner.read_file(&self.host_path(path)).await
+    }
+
+    async fn list_dir(&self, path: &str) -> std::io::Result<Vec<String>> {
+        self.inner.list_dir(&self.host_path(path)).await
+    }
+
+    async fn write_file(&self, path: &str, contents: &str) -> std::io::Result<()> {
+        self.inner.write_file(&self.host_path(path), contents).await
+    }
+
+    async fn remove_file(&self, path: &str) -> std::io::Result<()> {
+        self.inner.remove_file(&self.host_path(path)).await
+    }
+
+    async fn escalation(&self) -> Option<EscalationReport> {
+        self.inner.escalation().await
+    }
+}
+
+/// Runs commands in a running container through the engine's `exec`
+///
+/// Environment variables are passed with `--env`, since the engine does not
+/// forward its own environment into the container.
+///
+/// The Podman and nerdctl constructors run commands as the container's `root`
+/// rather than the image's `USER`; the README's configuration section
+/// explains why, and what that user maps to with rootless engines.
+pub struct ContainerRunner {
+    engine: String,
+    container: String,
+    user: Option<String>,
+    namespace: Option<String>,
+    inner: Arc<dyn CommandRunner>,
+}
+
+impl ContainerRunner {
+    /// Run commands in a Docker container
+    pub fn docker(container: impl Into<String>) -> Self {
+        Self::new("docker", container)
+    }
+
+    /// Run commands as `root` in a Podman container, rootless or not
+    pub fn podman(container: impl Into<String>) -> Self {
+        Self::new("podman", container).with_user("root")
+    }
+
+    /// Run commands as `root` in a containerd container through nerdctl
+    ///
+    /// Containers started by nerdctl live in the `default` containerd
+    /// namespace; use [`Self::with_namespace`] for others, e.g. `k8s.io`.
+    pub fn nerdctl(container: impl Into<String>) -> Self {
+        Self::new("nerdctl", container).with_user("root")
+    }
+
+    /// Run commands in a container using an engine with a Docker compatible CLI
+    pub fn new(engine: impl Into<String>, container: impl Into<String>) -> Self {
+        Self::with_runner(engine, container, Arc::new(LocalRunner))
+    }
+
+    /// Run the engine CLI through another runner
+    pub fn with_runner(
+        engine: impl Into<String>,
+        container: impl Into<String>,
+        inner: Arc<dyn CommandRunner>,
+    ) -> Self {
+        Self {
+            engine: engine.into(),
+            container: container.into(),
+            user: None,
+            namespace: None,
+            inner,
+        }
+    }
+
+    /// Run the engine CLI through another runner, as `root` with Podman and
+    /// nerdctl like [`Self::podman`] and [`Self::nerdctl`]
+    pub fn for_engine(
+        engine: impl Into<String>,
+        container: impl Into<String>,
+        inner: Arc<dyn CommandRunner>,
+    ) -> Self {
+        let runner = Self::with_runner(engine, container, inner);
+        match runner.engine.as_str() {
+            "podman" | "nerdctl" => runner.with_user("root"),
+            _ => runner,
+        }
+    }
+
+    /// User commands run as, unless it is the image's
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Run commands as this user (`exec --user`) instead of the image's
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Look the container up in this containerd namespace (`nerdctl
+    /// --namespace`)
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+}
+
+#[async_trait]
+impl CommandRunner for ContainerRunner {
+    async fn run(&self, command: &Command) -> std::io::Result<ExecResult> {
+        let mut exec = Command::new(&self.engine);
+        if let Some(namespace) = &self.namespace {
+            exec.arg("--namespace").arg(namespace);
+        }
+        exec.arg("exec");
+        if let Some(user) = &self.user {
+            exec.arg("--user").arg(user);
+        }
+        if command.stdin.is_some() {
+            exec.arg("--interactive");
+        }
+        for (key, value) in command.environment() {
+            exec.arg("--env").arg(format!("{key}={value}"));
+        }
+        exec.arg(&self.container).args(command.argv());
+        exec.stdin = command.stdin.clone();
+        exec.privileged = command.privileged;
+        self.inner.run(&exec).await
+    }
+
+    async fn escalation(&self) -> Option<EscalationReport> {
+        self.inner.escalation().await
+    }
+}
+
+/// Runs commands in a container of a Kubernetes pod through `kubectl exec`
+///
+/// `kubectl` goes through the API server's `pods/exec` subresource, so it
+/// needs that permission on the pod. It authenticates with the kubeconfig
+/// (`--kubeconfig`, `KUBECONFIG` or `~/.kube/config`) or, when the server
+/// runs in a pod without one, with the pod's service account.
+///
+/// Commands run as the container's user, since `kubectl exec` cannot switch
+/// users as [`ContainerRunner`] does.
+/// `exec` does not forward environment variables either, so they are set with
+/// `env`.
+pub struct KubernetesRunner {
+    pod: String,
+    namespace: Option<String>,
+    container: Option<String>,
+    kubeconfig: Option<PathBuf>,
+    context: Option<String>,
+    inner: Arc<dyn CommandRunner>,
+}
+
+impl KubernetesRunner {
+    /// Run commands in the pod's default container, from the host
+    pub fn new(pod: impl Into<String>) -> Self {
+        Self::with_runner(pod, Arc::new(LocalRunner))
+    }
+
+    /// Run `kubectl` through another runner
+    pub fn with_runner(pod: impl Into<String>, inner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            pod: pod.into(),
+            namespace: None,
+            container: None,
+            kubeconfig: None,
+            context: None,
+            inner,
+        }
+    }
+
+    /// Look the pod up in this namespace instead of the context's
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Run commands in this container of the pod instead of its default one
+    pub fn with_container(mut self, container: impl Into<String>) -> Self {
+        self.container = Some(container.into());
+        self
+    }
+
+    /// Authenticate with this kubeconfig file
+    pub fn with_kubeconfig(mut self, kubeconfig: impl Into<PathBuf>) -> Self {
+        self.kubeconfig = Some(kubeconfig.into());
+        self
+    }
+
+    /// Use this context of the kubeconfig instead of its current one
+    pub fn with_context(mut self, context: impl Into<String>) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+}
+
+#[async_trait]
+impl CommandRunner for KubernetesRunner {
+    async fn run(&self, command: &Command) -> std::io::Result<ExecResult> {
+        let mut exec = Command::new("kubectl");
+        if let Some(kubeconfig) = &self.kubeconfig {
+            exec.arg("--kubeconfig")
+                .arg(kubeconfig.display().to_string());
+        }
+        if let Some(context) = &self.context {
+            exec.arg("--context").arg(context);
+        }
+        if let Some(namespace) = &self.namespace {
+            exec.arg("--namespace").arg(namespace);
+        }
+        exec.arg("exec");
+        if command.stdin.is_some() {
+            exec.arg("--stdin");
+        }
+        exec.arg(&self.pod);
+        if let Some(container) = &self.container {
+            exec.arg("--container").arg(container);
+        }
+        exec.arg("--").arg("env");
+        for (key, value) in command.environment() {
+            exec.arg(format!("{key}={value}"));
+        }
+        exec.args(command.argv());
+        exec.stdin = command.stdin.clone();
+        // Access to the pod is granted by the API server, not by host privileges
+        self.inner.run(&exec).await
+    }
+}
+
+/// Runs commands on a remote host over `ssh`
+///
+/// The remote side runs commands through a shell, so every argument is
+/// quoted. `BatchMode` makes missing keys or unknown hosts fail instead of
+/// prompting.
+pub struct SshRunner {
+    destination: String,
+    inner: Arc<dyn CommandRunner>,
+}
+
+impl SshRunner {
+    /// Run commands on `destination` (`host` or `user@host`) from the host
+    pub fn new(destination: impl Into<String>) -> Self {
+        Self::with_runner(destination, Arc::new(LocalRunner))
+    }
+
+    /// Run `ssh` through another runner
+    pub fn with_runner(destination: impl Into<String>, inner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            destination: destination.into(),
+            inner,
+        }
+    }
+}
+
+#[async_trait]
+impl CommandRunner for SshRunner {
+    async fn run(&self, command: &Command) -> std::io::Result<ExecResult> {
+        let mut remote = vec!["env".to_string()];
+        remote.extend(
+            command
+                .environment()
+                .map(|(key, value)| shell_quote(&format!("{key}={value}"))),
+        );
+        remote.extend(command.argv().map(shell_quote));
+
+        let mut ssh = Command::new("ssh");
+        ssh.args(["-o", "BatchMode=yes", "--", &self.destination])
+            .arg(remote.join(" "));
+        ssh.stdin = command.stdin.clone();
+        ssh.privileged = command.privileged;
+        self.inner.run(&ssh).await
+    }
+}
+
+/// Quote an argument for a POSIX shell
+pub(crate) fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:+@,".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Tool used to run privileged commands as root
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Escalation {
+    Sudo,
+    Doas,
+}
+
+impl Escalation {
+    pub fn program(self) -> &'static str {
+        match self {
+            Self::Sudo => "sudo",
+            Self::Doas => "doas",
+        }
+    }
+}
+
+/// Whether privileged commands can run, as found by the preflight check
+#[derive(Clone, Debug)]
+enum EscalationStatus {
+    /// The runner already runs commands as root
+    NotNeeded,
+    Available,
+    Unavailable(String),
+}
+
+/// Runs [privileged](Command::privileged) commands as root through
+/// `sudo -n` or `doas -n`, for servers running as an unprivileged user
+///
+/// Other commands run unchanged. Before the first privileged command, a
+/// preflight check finds out whether escalation is needed (the user is not
+/// root) and possible without a password. When it is not, privileged
+/// commands fail with a permission error explaining why instead of hanging
+/// on a password prompt. Environment variables are passed through `env`,
+/// since `sudo` and `doas` reset the environment.
+pub struct EscalatingRunner {
+    escalation: Escalation,
+    inner: Arc<dyn CommandRunner>,
+    status: tokio::sync::OnceCell<EscalationStatus>,
+}
+
+impl EscalatingRunner {
+    /// Escalate privileged commands run on the host
+    pub fn new(escalation: Escalation) -> Self {
+        Self::with_runner(escalation, Arc::new(LocalRunner))
+    }
+
+    /// Escalate privileged commands run through another runner
+    pub fn with_runner(escalation: Escalation, inner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            escalation,
+            inner,
+            status: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    /// Check that privileged commands can run, once
+    ///
+    /// Succeeds when the user is root or passwordless escalation works, and
+    /// otherwise returns a permission error with the reason.
+    pub async fn preflight(&self) -> std::io::Result<()> {
+        match self.status.get_or_try_init(|| self.detect()).await? {
+            EscalationStatus::NotNeeded | EscalationStatus::Available => Ok(()),
+            EscalationStatus::Unavailable(reason) => Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                reason.clone(),
+            )),
+        }
+    }
+
+    /// Whether privileged commands are escalated, once the preflight ran
+    pub fn escalates(&self) -> bool {
+        matches!(self.status.get(), Some(EscalationStatus::Available))
+    }
+
+    async fn detect(&self) -> std::io::Result<EscalationStatus> {
+        let uid = self.inner.run(Command::new("id").arg("-u")).await?;
+        if uid.status == 0 && uid.stdout.as_deref().map(str::trim) == Some("0") {
+            return Ok(EscalationStatus::NotNeeded);
+        }
+
+        let program = self.escalation.program();
+        let check = match self
+            .inner
+            .run(Command::new(program).args(["-n", "true"]))
+            .await
+        {
+            Ok(check) => check,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(EscalationStatus::Unavailable(format!(
+                    "the server is not running as root and {program} is not installed"
+                )));
+            }
+            Err(err) => return Err(err),
+        };
+        if check.status == 0 {
+            return Ok(EscalationStatus::Available);
+        }
+
+        let stderr = check.stderr.as_deref().unwrap_or_default().trim();
+        Ok(EscalationStatus::Unavailable(format!(
+            "the server is not running as root and passwordless {program} is not available \
+            ({stderr}); allow the package manager without a password, e.g. with a NOPASSWD \
+            sudoers rule or a 'permit nopass' doas rule"
+        )))
+    }
+}
+
+#[async_trait]
+impl CommandRunner for EscalatingRunner {
+    async fn run(&self, command: &Command) -> std::io::Result<ExecResult> {
+        if !command.privileged {
+            return self.inner.run(command).await;
+        }
+        self.preflight().await?;
+        if !self.escalates() {
+            return self.inner.run(command).await;
+        }
+
+        let mut escalated = Command::new(self.escalation.program());
+        escalated.arg("-n").arg("env");
+        for (key, value) in command.environment() {
+            escalated.arg(format!("{key}={value}"));
+        }
+        escalated.args(command.argv());
+        escalated.stdin = command.stdin.clone();
+        escalated.privileged = true;
+        self.inner.run(&escalated).await
+    }
+
+    async fn read_file(&self, path: &str) -> std::io::Result<Option<String>> {
+        self.inner.read_file(path).await
+    }
+
+    async fn list_dir(&self, path: &str) -> std::io::Result<Vec<String>> {
+        self.inner.list_dir(path).await
+    }
+
+    async fn write_file(&self, path: &str, contents: &str) -> std::io::Result<()> {
+        self.inner.write_file(path, contents).await
+    }
+
+    async fn remove_file(&self, path: &str) -> std::io::Result<()> {
+        self.inner.remove_file(path).await
+    }
+
+    async fn escalation(&self) -> Option<EscalationReport> {
+        let problem = self.preflight().await.err().map(|err| err.to_string());
+        Some(EscalationReport {
+            program: self.escalation.program().to_string(),
+            available: problem.is_none(),
+            problem,
+        })
+    }
+}
+
+/// Records commands and replays canned results, for tests
+///
+/// Commands are answered by the first response whose words prefix the
+/// command line, or with an empty successful result. Files live in memory.
+#[derive(Default)]
+pub struct RecordingRunner {
+    responses: Vec<(Vec<String>, ExecResult)>,
+    files: Mutex<BTreeMap<String, String>>,
+    calls: Mutex<Vec<Command>>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer commands starting with `prefix` (program and leading arguments)
+    pub fn with_response(mut self, prefix: &[&str], result: ExecResult) -> Self {
+        self.responses
+            .push((prefix.iter().map(|word| word.to_string()).collect(), result));
+        self
+    }
+
+    /// Answer commands starting with `prefix` with the given stdout
+    pub fn with_stdout(self, prefix: &[&str], stdout: &str) -> Self {
+        self.with_response(prefix, ExecResult::new(stdout.as_bytes(), b"", 0))
+    }
+
+    /// Add a file to the in-memory file system
+    pub fn with_file(self, path: impl Into<String>, contents: impl Into<String>) -> Self {
+        self.lock_files().insert(path.into(), contents.into());
+        self
+    }
+
+    /// Commands run so far, oldest first
+    pub fn calls(&self) -> Vec<Command> {
+        self.calls
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Current contents of a file in the in-memory file system
+    pub fn file(&self, path: &str) -> Option<String> {
+        self.lock_files().get(path).cloned()
+    }
+
+    fn lock_files(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, String>> {
+        self.files.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[async_trait]
+impl CommandRunner for RecordingRunner {
+    async fn run(&self, command: &Command) -> std::io::Result<ExecResult> {
+        self.calls
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(command.clone());
+
+        let argv: Vec<&str> = command.argv().collect();
+        let result = self
+            .responses
+            .iter()
+            .find(|(prefix, _)| {
+                argv.starts_with(&prefix.iter().map(String::as_str).collect::<Vec<_>>())
+            })
+            .map(|(_, result)| result.clone())
+            .unwrap_or_default();
+        let result = ExecResult {
+            command: Some(command.command_line()),
+            ..result
+        };
+
+        for (stream, output) in [
+            (OutputStream::Stdout, &result.stdout),
+            (OutputStream::Stderr, &result.stderr),
+        ] {
+            for line in output.as_deref().unwrap_or_default().lines() {
+                exec::emit(OutputLine {
+                    stream,
+                    line: line.to_string(),
+                });
+            }
+        }
+
+        Ok(result)
+    }
+
+    async fn read_file(&self, path: &str) -> std::io::Result<Option<String>> {
+        Ok(self.file(path))
+    }
+
+    async fn list_dir(&self, path: &str) -> std::io::Result<Vec<String>> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        Ok(self
+            .lock_files()
+            .keys()
+            .filter_map(|file| file.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .map(str::to_string)
+            .collect())
+    }
+
+    async fn write_file(&self, path: &str, contents: &str) -> std::io::Result<()> {
+        self.lock_files()
+            .insert(path.to_string(), contents.to_string());
+        Ok(())
+    }
+
+    async fn remove_file(&self, path: &str) -> std::io::Result<()> {
+        self.lock_files().remove(path);
+        Ok(())
+    }
+}
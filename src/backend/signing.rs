@@ -0,0 +1,75 @@
+//! Signed-repositories-only hardening mode
+//!
+//! Package managers only protect against tampered packages when they verify
+//! the signatures of the repositories they install from, which a single
+//! misconfigured source defeats. In hardening mode, the server checks the
+//! signing configuration of the repositories at startup and before every
+//! operation using them, and refuses to run while one of them cannot be
+//! verified:
+//!
+//! - APK needs signing keys in `/etc/apk/keys`, and rejects local
+//!   directories of packages, whose generated indexes are not signed;
+//! - APT needs a `Signed-By` keyring that exists on every enabled source,
+//!   and rejects sources marked `trusted=yes` as well as local directories.
+//!
+//! Custom `repository` arguments are checked the same way as the configured
+//! repositories.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::PackageManagerError;
+
+/// A repository whose packages the package manager would not verify
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnsignedRepository {
+    /// URL or path of the repository
+    pub repository: String,
+    /// File the repository is configured in, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub reason: String,
+}
+
+impl UnsignedRepository {
+    pub fn new(repository: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            repository: repository.into(),
+            file: None,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn in_file(mut self, file: Option<String>) -> Self {
+        self.file = file;
+        self
+    }
+}
+
+impl fmt::Display for UnsignedRepository {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.repository)?;
+        if let Some(file) = &self.file {
+            write!(f, " (in {file})")?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+/// Refuse to operate while any repository is unsigned
+pub(crate) fn require_signed(problems: &[UnsignedRepository]) -> Result<(), PackageManagerError> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(PackageManagerError::PermissionDenied {
+        message: format!(
+            "the server only uses signed repositories: {}",
+            problems
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ")
+        ),
+    })
+}
@@ -0,0 +1,172 @@
+//! Historical Debian versions from snapshot.debian.org
+//!
+//! The configured suites only carry the current versions of a package. The
+//! snapshot archive keeps every version ever uploaded, so when
+//! `install_package_with_version` cannot find a version in the suites, the
+//! APT backend can look it up with the archive's machine-readable API, add
+//! the archive as it was when the version was first seen as a temporary
+//! source, and install the version from there.
+
+use serde::Deserialize;
+
+/// Snapshot archive used by default
+pub const DEFAULT_SNAPSHOT_ARCHIVE: &str = "https://snapshot.debian.org";
+
+/// How long a query to the archive may take
+const QUERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// snapshot.debian.org client resolving historical package versions
+#[derive(Clone, Debug)]
+pub struct SnapshotArchive {
+    url: String,
+    suite: Option<String>,
+    client: reqwest::Client,
+}
+
+/// Snapshot of the archive holding a package version
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SnapshotLocation {
+    /// Archive the version was uploaded to, e.g. `debian` or `debian-security`
+    pub archive: String,
+    /// When the version was first seen, e.g. `20230320T091003Z`
+    pub timestamp: String,
+    /// Component of the pool the version is in, e.g. `main`
+    pub component: String,
+}
+
+#[derive(Deserialize)]
+struct BinaryVersions {
+    result: Vec<BinaryVersion>,
+}
+
+#[derive(Deserialize)]
+struct BinaryVersion {
+    binary_version: String,
+}
+
+#[derive(Deserialize)]
+struct BinaryFiles {
+    result: Vec<BinaryFile>,
+    #[serde(default)]
+    fileinfo: std::collections::HashMap<String, Vec<FileInfo>>,
+}
+
+#[derive(Deserialize)]
+struct BinaryFile {
+    hash: String,
+    architecture: String,
+}
+
+#[derive(Deserialize)]
+struct FileInfo {
+    archive_name: String,
+    first_seen: String,
+    path: String,
+}
+
+impl SnapshotArchive {
+    pub fn new() -> Self {
+        Self::with_url(DEFAULT_SNAPSHOT_ARCHIVE)
+    }
+
+    /// Query the snapshot archive at `url`
+    pub fn with_url(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into().trim_end_matches('/').to_string(),
+            suite: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Install from this suite of the snapshot (e.g. `bookworm`) instead of
+    /// the system's codename
+    pub fn with_suite(mut self, suite: impl Into<String>) -> Self {
+        self.suite = Some(suite.into());
+        self
+    }
+
+    /// Configured suite, if any
+    pub(crate) fn suite(&self) -> Option<&str> {
+        self.suite.as_deref()
+    }
+
+    /// Every version of the binary package `package` the archive has seen
+    pub(crate) async fn versions(&self, package: &str) -> std::io::Result<Vec<String>> {
+        let versions: BinaryVersions = self.query(&["mr", "binary", package, ""]).await?;
+        Ok(versions
+            .result
+            .into_iter()
+            .map(|version| version.binary_version)
+            .collect())
+    }
+
+    /// Snapshot first holding `version` of `package`, for `arch` when given
+    ///
+    /// Architecture-independent (`all`) packages match any architecture.
+    pub(crate) async fn locate(
+        &self,
+        package: &str,
+        version: &str,
+        arch: Option<&str>,
+    ) -> std::io::Result<Option<SnapshotLocation>> {
+        let mut files: BinaryFiles = self
+            .query(&["mr", "binary", package, version, "binfiles"])
+            .await?;
+        let location = files
+            .result
+            .iter()
+            .filter(|file| {
+                arch.is_none_or(|arch| file.architecture == arch || file.architecture == "all")
+            })
+            .find_map(|file| {
+                let info = files.fileinfo.remove(&file.hash)?;
+                // The earliest sighting is the one most likely to still be
+                // in the suite with its original dependencies
+                let info = info
+                    .into_iter()
+                    .min_by(|a, b| a.first_seen.cmp(&b.first_seen))?;
+                let component = info.path.strip_prefix("/pool/")?.split('/').next()?;
+                Some(SnapshotLocation {
+                    archive: info.archive_name,
+                    timestamp: info.first_seen,
+                    component: component.to_string(),
+                })
+            });
+        Ok(location)
+    }
+
+    /// One-line APT source for the suite of the snapshot at `location`
+    pub(crate) fn source_entry(&self, location: &SnapshotLocation, suite: &str) -> String {
+        format!(
+            "deb {}/archive/{}/{}/ {suite} {}",
+            self.url, location.archive, location.timestamp, location.component
+        )
+    }
+
+    async fn query<T: serde::de::DeserializeOwned>(&self, segments: &[&str]) -> std::io::Result<T> {
+        let mut url = reqwest::Url::parse(&self.url).map_err(std::io::Error::other)?;
+        url.path_segments_mut()
+            .map_err(|()| std::io::Error::other(format!("{} cannot have a path", self.url)))?
+            .pop_if_empty()
+            .extend(segments);
+        if segments.last() == Some(&"binfiles") {
+            url.set_query(Some("fileinfo=1"));
+        }
+
+        let response = self
+            .client
+            .get(url)
+            .timeout(QUERY_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(std::io::Error::other)?;
+        response.json().await.map_err(std::io::Error::other)
+    }
+}
+
+impl Default for SnapshotArchive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
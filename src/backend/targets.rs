@@ -0,0 +1,284 @@
+//! Named execution targets for fan-out installs
+//!
+//! Besides its own backend, a server can manage a fleet of other systems,
+//! e.g. identical agent sandboxes. Each [`Target`] names one of them and how
+//! to reach it. The `install_package_on_targets` tool applies an install to
+//! several targets in parallel and reports a result per target, so one failing
+//! sandbox does not hide the others.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::task::JoinSet;
+
+use super::exec::{self, OutputLine};
+use super::runner::{
+    ChrootRunner, CommandRunner, ContainerRunner, EscalatingRunner, Escalation, KubernetesRunner,
+    LocalRunner, SshRunner,
+};
+use super::{
+    InstallFlags, InstallOptions, InstallOutcome, InstallVersionOptions, PackageManager,
+    TargetInstallOptions,
+};
+use crate::error::PackageManagerError;
+use crate::operation::Operation;
+
+/// A target's name and the backend managing it
+pub(crate) type NamedBackend = (String, Arc<dyn PackageManager>);
+
+/// How to reach an execution target
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TargetSpec {
+    /// The host the server runs on
+    Local,
+    /// A running container, through the engine's `exec`
+    Container { engine: String, container: String },
+    /// A root filesystem on the host, through `chroot`
+    Chroot(PathBuf),
+    /// A container of a Kubernetes pod, through `kubectl exec`
+    Kubernetes {
+        namespace: Option<String>,
+        pod: String,
+        container: Option<String>,
+    },
+    /// A remote host (`host` or `user@host`), through `ssh`
+    Ssh(String),
+}
+
+impl TargetSpec {
+    /// Runner executing commands on the target
+    ///
+    /// Privileged commands are escalated where they run: on the host for
+    /// containers and root filesystems, on the remote host for `ssh`.
+    pub fn runner(&self, escalation: Option<Escalation>) -> Arc<dyn CommandRunner> {
+        let escalate = |runner: Arc<dyn CommandRunner>| -> Arc<dyn CommandRunner> {
+            match escalation {
+                Some(escalation) => Arc::new(EscalatingRunner::with_runner(escalation, runner)),
+                None => runner,
+            }
+        };
+
+        match self {
+            Self::Local => escalate(Arc::new(LocalRunner)),
+            Self::Container { engine, container } => {
+                let host = escalate(Arc::new(LocalRunner));
+                Arc::new(ContainerRunner::with_runner(
+                    engine.clone(),
+                    container.clone(),
+                    host,
+                ))
+            }
+            Self::Chroot(root) => {
+                let host = escalate(Arc::new(LocalRunner));
+                Arc::new(ChrootRunner::with_runner(root.clone(), host))
+            }
+            // The API server grants access to the pod, escalation does not apply
+            Self::Kubernetes {
+                namespace,
+                pod,
+                container,
+            } => {
+                let mut runner = KubernetesRunner::new(pod.clone());
+                if let Some(namespace) = namespace {
+                    runner = runner.with_namespace(namespace.clone());
+                }
+                if let Some(container) = container {
+                    runner = runner.with_container(container.clone());
+                }
+                Arc::new(runner)
+            }
+            Self::Ssh(destination) => escalate(Arc::new(SshRunner::new(destination.clone()))),
+        }
+    }
+}
+
+impl FromStr for TargetSpec {
+    type Err = String;
+
+    /// Parse `local`, `docker:CONTAINER`, `podman:CONTAINER`,
+    /// `nerdctl:CONTAINER`, `chroot:PATH`, `k8s:[NAMESPACE/]POD[:CONTAINER]`
+    /// or `ssh:HOST`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        if spec == "local" {
+            return Ok(Self::Local);
+        }
+        let (kind, location) = spec
+            .split_once(':')
+            .filter(|(_, location)| !location.is_empty())
+            .ok_or_else(|| format!("invalid target '{spec}': expected KIND:LOCATION or local"))?;
+        match kind {
+            "docker" | "podman" | "nerdctl" => Ok(Self::Container {
+                engine: kind.to_string(),
+                container: location.to_string(),
+            }),
+            "chroot" => Ok(Self::Chroot(PathBuf::from(location))),
+            "k8s" => {
+                let (pod, container) = match location.split_once(':') {
+                    Some((pod, container)) => (pod, Some(container.to_string())),
+                    None => (location, None),
+                };
+                let (namespace, pod) = match pod.split_once('/') {
+                    Some((namespace, pod)) => (Some(namespace.to_string()), pod),
+                    None => (None, pod),
+                };
+                Ok(Self::Kubernetes {
+                    namespace,
+                    pod: pod.to_string(),
+                    container,
+                })
+            }
+            "ssh" => Ok(Self::Ssh(location.to_string())),
+            _ => Err(format!(
+                "invalid target '{spec}': unknown kind '{kind}', expected docker, podman, nerdctl, chroot, k8s or ssh"
+            )),
+        }
+    }
+}
+
+/// A named execution target, parsed from `NAME=SPEC`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub name: String,
+    pub spec: TargetSpec,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        let (name, spec) = target
+            .split_once('=')
+            .ok_or_else(|| format!("invalid target '{target}': expected NAME=SPEC"))?;
+        validate_target_name(name).map_err(|err| err.to_string())?;
+        Ok(Self {
+            name: name.to_string(),
+            spec: spec.parse()?,
+        })
+    }
+}
+
+/// Reject target names that are not plain identifiers like `sandbox-1`
+pub(crate) fn validate_target_name(name: &str) -> Result<(), PackageManagerError> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        return Err(PackageManagerError::Validation {
+            field: "targets",
+            value: name.to_string(),
+            reason: "only alphanumeric characters, dots, hyphens and underscores are allowed"
+                .to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Result of an install on one target
+#[derive(Clone, Debug, Serialize)]
+pub struct TargetResult {
+    pub target: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<InstallOutcome>,
+    /// Error data, shaped like the data of a failed single-target call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+/// Install on every target in parallel, returning results in target order
+///
+/// Output lines are forwarded to the current output sink prefixed with the
+/// target name. Dropping the future aborts the installs still running, like
+/// it kills the command of a single-target call.
+pub(crate) async fn install_on(
+    targets: Vec<NamedBackend>,
+    options: &TargetInstallOptions,
+) -> Vec<(String, Result<InstallOutcome, PackageManagerError>)> {
+    let sink = exec::current_sink();
+    let mut tasks = JoinSet::new();
+    let mut names = Vec::new();
+
+    for (name, backend) in targets {
+        let options = options.clone();
+        let sink = sink.clone();
+        let prefix = name.clone();
+        let task = tasks.spawn(async move {
+            let install = install(&*backend, &options);
+            match sink {
+                Some(sink) => {
+                    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+                    tokio::spawn(async move {
+                        while let Some(OutputLine { stream, line }) = receiver.recv().await {
+                            let _ = sink.send(OutputLine {
+                                stream,
+                                line: format!("[{prefix}] {line}"),
+                            });
+                        }
+                    });
+                    exec::stream_output(sender, install).await
+                }
+                None => install.await,
+            }
+        });
+        names.push((task.id(), name));
+    }
+
+    let mut results = HashMap::new();
+    while let Some(joined) = tasks.join_next_with_id().await {
+        match joined {
+            Ok((id, result)) => results.insert(id, result),
+            Err(err) => results.insert(
+                err.id(),
+                Err(PackageManagerError::io(
+                    "install task did not complete",
+                    std::io::Error::other(err),
+                )),
+            ),
+        };
+    }
+    names
+        .into_iter()
+        .filter_map(|(id, name)| Some((name, results.remove(&id)?)))
+        .collect()
+}
+
+async fn install(
+    backend: &dyn PackageManager,
+    options: &TargetInstallOptions,
+) -> Result<InstallOutcome, PackageManagerError> {
+    let capabilities = backend.capabilities();
+    match &options.version {
+        Some(version) => {
+            let options = InstallVersionOptions {
+                package: options.package.clone(),
+                version: version.clone(),
+                arch: options.arch.clone(),
+                preseed: Vec::new(),
+                install_recommends: None,
+                target_release: None,
+                flags: InstallFlags::default(),
+            };
+            capabilities.check(&Operation::InstallVersion(options.clone()))?;
+            backend.install_package_with_version(&options).await
+        }
+        None => {
+            let options = InstallOptions {
+                package: options.package.clone(),
+                repository: None,
+                repositories: Vec::new(),
+                only_repositories: Vec::new(),
+                arch: options.arch.clone(),
+                preseed: Vec::new(),
+                install_recommends: None,
+                target_release: None,
+                flags: InstallFlags::default(),
+            };
+            capabilities.check(&Operation::Install(options.clone()))?;
+            backend.install_package(&options).await
+        }
+    }
+}
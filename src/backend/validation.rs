@@ -0,0 +1,595 @@
+//! Validation of tool call arguments
+//!
+//! Arguments end up as package manager arguments, configuration files and
+//! URLs the package manager fetches. The handler checks every operation with
+//! [`validate_operation`] before running it, so backends receive package
+//! names following their distribution's grammar, repositories that are
+//! well-formed URLs or paths, and strings of bounded length. Failures are
+//! reported as [`PackageManagerError::Validation`] naming the field, the
+//! rejected value and the rule it broke.
+
+use super::allowlist::repository_urls;
+use super::local::local_directory;
+use super::parse::apk::{split_package_tag, split_repository_tag};
+use crate::error::PackageManagerError;
+use crate::operation::Operation;
+use crate::version::{VersionConstraint, VersionScheme};
+
+/// Longest package name, version, search query or pattern accepted
+pub const MAX_NAME_LENGTH: usize = 128;
+
+/// Types of debconf questions, as `debconf-set-selections` accepts them
+const DEBCONF_TYPES: &[&str] = &[
+    "boolean",
+    "error",
+    "multiselect",
+    "note",
+    "password",
+    "select",
+    "string",
+    "text",
+    "title",
+];
+
+/// Longest repository definition, path or URL accepted
+pub const MAX_REPOSITORY_LENGTH: usize = 4096;
+
+/// Check every argument of `operation` for a backend using `scheme`
+///
+/// Package files and URLs passed as package names are only checked for
+/// length and control characters, since the handler only lets them through
+/// when untrusted sources are allowed. APK installs may pin a package to a
+/// tagged repository (`neovim@testing`). Installs on targets use the grammar
+/// shared by every backend, as targets may run other distributions.
+pub fn validate_operation(
+    operation: &Operation,
+    scheme: Option<VersionScheme>,
+) -> Result<(), PackageManagerError> {
+    match operation {
+        Operation::Install(options) => {
+            if operation.untrusted_source() == Some(options.package.as_str()) {
+                validate_package_file(&options.package)?;
+            } else if scheme == Some(VersionScheme::Apk) {
+                let (name, tag) = split_package_tag(&options.package);
+                validate_package_name(name, scheme)?;
+                if let Some(tag) = tag {
+                    validate_tag("package_name", &options.package, tag)?;
+                }
+            } else {
+                validate_package_name(&options.package, scheme)?;
+            }
+            for repository in operation.repositories() {
+                validate_repository(repository, scheme)?;
+            }
+            validate_preseed(&options.preseed)?;
+            validate_target_release(options.target_release.as_deref())?;
+            validate_arch(options.arch.as_deref())
+        }
+        Operation::InstallVersion(options) | Operation::ResolveVersion(options) => {
+            validate_package_name(&options.package, scheme)?;
+            validate_version(&options.version, scheme)?;
+            validate_preseed(&options.preseed)?;
+            validate_target_release(options.target_release.as_deref())?;
+            validate_arch(options.arch.as_deref())
+        }
+        Operation::InstallVersions { packages } => {
+            for (index, options) in packages.iter().enumerate() {
+                validate_package_name(&options.package, scheme)?;
+                validate_version(&options.version, scheme)?;
+                validate_preseed(&options.preseed)?;
+                validate_target_release(options.target_release.as_deref())?;
+                validate_arch(options.arch.as_deref())?;
+                if packages[..index]
+                    .iter()
+                    .any(|listed| listed.package == options.package)
+                {
+                    return Err(invalid(
+                        "packages",
+                        &options.package,
+                        "the package is listed more than once",
+                    ));
+                }
+            }
+            Ok(())
+        }
+        Operation::Search { options, listing } => {
+            validate_query(&options.query)?;
+            for repository in operation.repositories() {
+                validate_repository(repository, scheme)?;
+            }
+            if listing.limit == Some(0) {
+                return Err(invalid("limit", "0", "must be at least 1"));
+            }
+            if let Some(branch) = &listing.branch {
+                validate_text("branch", branch, MAX_NAME_LENGTH)?;
+            }
+            validate_arch(options.arch.as_deref())
+        }
+        Operation::InstallOnTargets(options) => {
+            if operation.untrusted_source() == Some(options.package.as_str()) {
+                validate_package_file(&options.package)?;
+            } else {
+                validate_package_name(&options.package, None)?;
+            }
+            if let Some(version) = &options.version {
+                validate_version(version, None)?;
+            }
+            validate_arch(options.arch.as_deref())?;
+            for target in &options.targets {
+                validate_text("targets", target, MAX_NAME_LENGTH)?;
+            }
+            Ok(())
+        }
+        Operation::InstallFromLockfile(lockfile) => {
+            if lockfile.packages.is_empty() {
+                return Err(invalid("lockfile", "", "the lockfile has no packages"));
+            }
+            for locked in &lockfile.packages {
+                let package = &locked.package;
+                validate_package_name(&package.name, scheme)?;
+                validate_version(&package.version, scheme)?;
+                validate_arch(package.arch.as_deref())?;
+                for repository in package.custom_repositories() {
+                    validate_repository(repository, scheme)?;
+                }
+            }
+            Ok(())
+        }
+        Operation::CheckVulnerabilities { packages } => {
+            for entry in packages {
+                match entry.split_once('=') {
+                    Some((name, version)) => {
+                        validate_package_name(name, scheme)?;
+                        validate_version(version, scheme)?;
+                    }
+                    None => validate_package_name(entry, scheme)?,
+                }
+            }
+            Ok(())
+        }
+        Operation::SearchAllDistros { project, distros } => {
+            validate_project(project)?;
+            for family in distros {
+                validate_text("distros", family, MAX_NAME_LENGTH)?;
+                if !family
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+                {
+                    return Err(invalid(
+                        "distros",
+                        family,
+                        "only lowercase letters, digits and underscores are allowed",
+                    ));
+                }
+            }
+            Ok(())
+        }
+        Operation::LicenseReport { deny } => {
+            for pattern in deny {
+                validate_text("deny", pattern, MAX_NAME_LENGTH)?;
+            }
+            Ok(())
+        }
+        Operation::ListInstalled(_)
+        | Operation::RefreshRepositories
+        | Operation::ListRepositories
+        | Operation::ListSearchRepositories
+        | Operation::CheckPrivileges
+        | Operation::ServerInfo
+        | Operation::Doctor
+        | Operation::GetJobStatus { .. }
+        | Operation::GetJobOutput { .. }
+        | Operation::CancelJob { .. }
+        | Operation::GetOperationLog { .. }
+        | Operation::ExportSession { .. }
+        | Operation::ExportDockerfileSnippet
+        | Operation::ExportCloudInit
+        | Operation::ExportApkoConfig { .. }
+        | Operation::GenerateLockfile
+        | Operation::ExportAuditLog { .. }
+        | Operation::ExportHistory { .. } => Ok(()),
+    }
+}
+
+/// Reject package names outside the grammar of `scheme`
+///
+/// - APK: letters, digits and `+ _ . -`, with `:` for provided names such as
+///   `so:libz.so.1` or `cmd:curl`
+/// - Debian: lowercase letters, digits and `+ . -`, at least two characters
+///   (Debian policy), optionally qualified with an architecture (`:arm64`)
+/// - Other backends: letters, digits and `+ _ . : @ -`
+///
+/// Every name starts with a letter or digit, so it cannot be mistaken for an
+/// option.
+pub fn validate_package_name(
+    name: &str,
+    scheme: Option<VersionScheme>,
+) -> Result<(), PackageManagerError> {
+    let invalid = |reason: &str| invalid("package_name", name, reason);
+    check_length("package_name", name, MAX_NAME_LENGTH)?;
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(invalid("must start with a letter or digit"));
+    }
+
+    match scheme {
+        Some(VersionScheme::Apk) => {
+            if !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+_.-:".contains(c))
+            {
+                return Err(invalid(
+                    "only letters, digits, plus signs, underscores, dots, hyphens and colons are allowed",
+                ));
+            }
+        }
+        Some(VersionScheme::Deb) => {
+            let (package, arch) = match name.split_once(':') {
+                Some((package, arch)) => (package, Some(arch)),
+                None => (name, None),
+            };
+            if package.len() < 2 {
+                return Err(invalid("Debian package names have at least two characters"));
+            }
+            if !package.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || c == '+' || c == '.' || c == '-'
+            }) {
+                return Err(invalid(
+                    "only lowercase letters, digits, plus signs, dots and hyphens are allowed, with an optional ':arch' suffix",
+                ));
+            }
+            if let Some(arch) = arch {
+                validate_arch(Some(arch)).map_err(|_| invalid("invalid architecture qualifier"))?;
+            }
+        }
+        None => {
+            if !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+_.:@-".contains(c))
+            {
+                return Err(invalid(
+                    "only letters, digits, plus signs, underscores, dots, colons, at signs and hyphens are allowed",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reject versions and constraints (`>=1.2`, `~3.12`) with characters
+/// outside the version grammar of `scheme`
+///
+/// Debian versions may carry an epoch (`1:2.3`) and tildes (`1.0~rc1`).
+pub fn validate_version(
+    version: &str,
+    scheme: Option<VersionScheme>,
+) -> Result<(), PackageManagerError> {
+    check_length("version", version, MAX_NAME_LENGTH)?;
+    let constraint = VersionConstraint::parse(version);
+    let allowed = |c: char| match scheme {
+        Some(VersionScheme::Apk) => c.is_ascii_alphanumeric() || "._+-".contains(c),
+        Some(VersionScheme::Deb) | None => c.is_ascii_alphanumeric() || "._+-:~".contains(c),
+    };
+    if constraint.version.is_empty() {
+        return Err(invalid("version", version, "a version is required"));
+    }
+    if !constraint.version.chars().all(allowed) {
+        return Err(invalid(
+            "version",
+            version,
+            match scheme {
+                Some(VersionScheme::Apk) => {
+                    "only letters, digits, dots, underscores, plus signs and hyphens are allowed"
+                }
+                _ => {
+                    "only letters, digits, dots, underscores, plus signs, hyphens, colons and tildes are allowed"
+                }
+            },
+        ));
+    }
+    Ok(())
+}
+
+/// Reject search queries that are empty, too long, contain control
+/// characters or could be mistaken for an option
+pub fn validate_query(query: &str) -> Result<(), PackageManagerError> {
+    validate_text("query", query, MAX_NAME_LENGTH)?;
+    if query.trim().is_empty() {
+        return Err(invalid("query", query, "must not be empty"));
+    }
+    if query.starts_with('-') {
+        return Err(invalid("query", query, "must not start with a hyphen"));
+    }
+    Ok(())
+}
+
+/// Reject Repology project names, which end up in the path of an API URL
+pub fn validate_project(project: &str) -> Result<(), PackageManagerError> {
+    validate_text("project", project, MAX_NAME_LENGTH)?;
+    if !project.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(invalid(
+            "project",
+            project,
+            "must start with a letter or digit",
+        ));
+    }
+    if !project
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "+_.-:".contains(c))
+    {
+        return Err(invalid(
+            "project",
+            project,
+            "only letters, digits, plus signs, underscores, dots, hyphens and colons are allowed",
+        ));
+    }
+    Ok(())
+}
+
+/// Reject repositories that are not well-formed for a backend using `scheme`
+///
+/// APK repositories are URLs or absolute paths of local repositories, and
+/// URLs may be prefixed with an `@tag` (`@testing https://…`). APT
+/// repositories are absolute paths of `.list` or `.sources` files, or
+/// one-line entries and deb822 stanzas whose URIs must be well-formed.
+/// Other backends take a URL or a plain value.
+pub fn validate_repository(
+    repository: &str,
+    scheme: Option<VersionScheme>,
+) -> Result<(), PackageManagerError> {
+    let invalid = |reason: &str| invalid("repository", repository, reason);
+    check_length("repository", repository, MAX_REPOSITORY_LENGTH)?;
+    let definition = repository.trim();
+    if definition.is_empty() {
+        return Err(invalid("must not be empty"));
+    }
+    // Only deb822 stanzas span several lines
+    let multiline = scheme == Some(VersionScheme::Deb) && definition.contains('\n');
+    if definition
+        .chars()
+        .any(|c| c.is_control() && !(multiline && (c == '\n' || c == '\t')))
+    {
+        return Err(invalid("must not contain control characters"));
+    }
+
+    match scheme {
+        Some(VersionScheme::Apk) => {
+            let (tag, definition) = split_repository_tag(definition);
+            if let Some(tag) = tag {
+                validate_tag("repository", repository, tag)?;
+                if !definition.contains("://") || local_directory(definition).is_some() {
+                    return Err(invalid("only repository URLs can be tagged"));
+                }
+            }
+            if definition.contains("://") {
+                validate_url(definition).map_err(&invalid)
+            } else if definition.starts_with('/') {
+                Ok(())
+            } else {
+                Err(invalid(
+                    "must be a URL or the absolute path of a local directory of packages",
+                ))
+            }
+        }
+        Some(VersionScheme::Deb) => {
+            let urls = repository_urls(definition);
+            if urls.is_empty() {
+                return if definition.starts_with('/') && !multiline {
+                    Ok(())
+                } else {
+                    Err(invalid(
+                        "must be a one-line entry, a deb822 stanza or the absolute path of a .list or .sources file or of a directory of packages",
+                    ))
+                };
+            }
+            urls.into_iter()
+                .try_for_each(validate_url)
+                .map_err(&invalid)
+        }
+        None => {
+            if definition.contains("://") {
+                validate_url(definition).map_err(&invalid)
+            } else if definition.starts_with('-') {
+                Err(invalid("must not start with a hyphen"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Reject repository tags that are not plain identifiers like `testing`
+fn validate_tag(field: &'static str, value: &str, tag: &str) -> Result<(), PackageManagerError> {
+    if !tag.starts_with(|c: char| c.is_ascii_alphanumeric())
+        || !tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+    {
+        return Err(invalid(
+            field,
+            value,
+            "repository tags start with a letter or digit and only contain letters, digits, underscores, dots and hyphens",
+        ));
+    }
+    Ok(())
+}
+
+/// Reject architecture names that are not plain identifiers like `aarch64`
+/// or `arm64`
+pub fn validate_arch(arch: Option<&str>) -> Result<(), PackageManagerError> {
+    match arch {
+        Some(arch)
+            if arch.is_empty()
+                || arch.len() > MAX_NAME_LENGTH
+                || !arch
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') =>
+        {
+            Err(invalid(
+                "arch",
+                arch,
+                "only alphanumeric characters, hyphens and underscores are allowed",
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reject release names that are not a suite, codename or archive name
+/// (`bookworm-backports`, `stable`, `22.04`)
+pub fn validate_target_release(release: Option<&str>) -> Result<(), PackageManagerError> {
+    let Some(release) = release else {
+        return Ok(());
+    };
+    if release.is_empty()
+        || release.len() > MAX_NAME_LENGTH
+        || release.starts_with('-')
+        || !release
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+' | '~'))
+    {
+        return Err(invalid(
+            "target_release",
+            release,
+            "only alphanumeric characters, hyphens, underscores, dots, plus signs and tildes are allowed, not starting with a hyphen",
+        ));
+    }
+    Ok(())
+}
+
+/// `selection` with its value masked when its question is a `password`
+pub fn masked_selection(selection: &str) -> String {
+    match selection.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [owner, question, "password", _, ..] => format!("{owner} {question} password ********"),
+        _ => selection.to_string(),
+    }
+}
+
+/// Reject debconf selections that are not single `package question type
+/// value` lines with a known question type
+///
+/// The value may be empty or contain spaces.
+pub fn validate_preseed(selections: &[String]) -> Result<(), PackageManagerError> {
+    for selection in selections {
+        validate_text("preseed", selection, MAX_REPOSITORY_LENGTH)?;
+        let mut fields = selection.split_whitespace();
+        let (Some(owner), Some(question), Some(kind)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid(
+                "preseed",
+                selection,
+                "must be a 'package question type value' line",
+            ));
+        };
+        let name = |field: &str| {
+            field
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-._/:".contains(c))
+                && !field.starts_with('-')
+        };
+        if !name(owner) || !name(question) || !question.contains('/') {
+            return Err(invalid(
+                "preseed",
+                &masked_selection(selection),
+                "the package and question must be names such as 'tzdata tzdata/Areas'",
+            ));
+        }
+        if !DEBCONF_TYPES.contains(&kind) {
+            return Err(invalid(
+                "preseed",
+                selection,
+                &format!("the type must be one of {}", DEBCONF_TYPES.join(", ")),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reject alternate roots that are not absolute paths without `..`
+/// components
+pub fn validate_root(root: &str) -> Result<(), PackageManagerError> {
+    let path = std::path::Path::new(root);
+    let reason = if !path.is_absolute() {
+        "must be an absolute path"
+    } else if path
+        .components()
+        .any(|component| component == std::path::Component::ParentDir)
+    {
+        "must not contain '..' components"
+    } else if root.chars().any(char::is_control) {
+        "must not contain control characters"
+    } else {
+        return Ok(());
+    };
+
+    Err(invalid("root", root, reason))
+}
+
+/// Reject package files and URLs that are too long, contain control
+/// characters or could be mistaken for an option
+fn validate_package_file(package: &str) -> Result<(), PackageManagerError> {
+    validate_text("package_name", package, MAX_REPOSITORY_LENGTH)?;
+    if package.starts_with('-') {
+        return Err(invalid(
+            "package_name",
+            package,
+            "must not start with a hyphen",
+        ));
+    }
+    if package.contains("://") {
+        validate_url(package).map_err(|reason| invalid("package_name", package, reason))?;
+    }
+    Ok(())
+}
+
+/// Check that `url` has a scheme (`https`, `mirror+http`), a host unless it
+/// is a `file` URL, and no whitespace
+fn validate_url(url: &str) -> Result<(), &'static str> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or("URLs must start with a scheme such as https://")?;
+    if !scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+.-".contains(c))
+    {
+        return Err("invalid URL scheme");
+    }
+    if rest.chars().any(char::is_whitespace) {
+        return Err("URLs must not contain whitespace");
+    }
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    if host.is_empty() && !scheme.ends_with("file") {
+        return Err("URLs must name a host");
+    }
+    Ok(())
+}
+
+/// Reject strings longer than `max` bytes or with control characters
+fn validate_text(field: &'static str, value: &str, max: usize) -> Result<(), PackageManagerError> {
+    check_length(field, value, max)?;
+    if value.chars().any(char::is_control) {
+        return Err(invalid(field, value, "must not contain control characters"));
+    }
+    Ok(())
+}
+
+fn check_length(field: &'static str, value: &str, max: usize) -> Result<(), PackageManagerError> {
+    if value.len() > max {
+        return Err(invalid(
+            field,
+            // Long values are cut so the error stays readable
+            &value.chars().take(64).collect::<String>(),
+            &format!("must be at most {max} bytes long"),
+        ));
+    }
+    Ok(())
+}
+
+fn invalid(field: &'static str, value: &str, reason: &str) -> PackageManagerError {
+    PackageManagerError::Validation {
+        field,
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
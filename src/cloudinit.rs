@@ -0,0 +1,218 @@
+//! cloud-init configurations for the packages installed in a session
+//!
+//! Teams provisioning VMs rather than containers describe their machines as
+//! cloud-init user data. The `export_cloud_init` tool turns the packages a
+//! session installed into its `packages` module, pinned to the installed
+//! versions, together with the repositories and keys they came from:
+//!
+//! - APT sources become entries of the `apt` module's `sources`, with the
+//!   ASCII-armored key of their keyring when the server can read one;
+//! - APK repositories are appended to `/etc/apk/repositories` with
+//!   `write_files`, which also installs the keys from `/etc/apk/keys` that
+//!   are not the Alpine project's own.
+
+use serde::Serialize;
+
+use crate::backend::{PinnedPackage, Repository};
+
+/// Placeholder cloud-init replaces with the path it writes a source's key to
+const KEY_FILE: &str = "$KEY_FILE";
+
+/// An APT source of the `apt` module
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AptSource {
+    /// Key of the source in `sources`, also naming its files
+    pub name: String,
+    /// One-line entry of the source
+    pub source: String,
+    /// ASCII-armored key verifying the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+/// A file of the `write_files` module
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct WrittenFile {
+    pub path: String,
+    pub content: String,
+    /// Whether the content is appended to an existing file
+    pub append: bool,
+}
+
+/// The parts of a cloud-config installing pinned packages
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CloudConfig {
+    /// Packages as `(name, version)`
+    pub packages: Vec<(String, String)>,
+    pub apt_sources: Vec<AptSource>,
+    pub write_files: Vec<WrittenFile>,
+}
+
+impl CloudConfig {
+    /// Configuration installing `packages` at their pinned versions, without
+    /// any repository
+    pub fn new(packages: &[PinnedPackage]) -> Self {
+        let mut config = Self::default();
+        for package in packages {
+            let pinned = (package.name.clone(), package.version.clone());
+            if !config.packages.contains(&pinned) {
+                config.packages.push(pinned);
+            }
+        }
+        config
+    }
+
+    /// Add an APT source, one per suite
+    ///
+    /// `key` replaces the keyring named by the source's `Signed-By`.
+    pub fn with_apt_source(mut self, repository: &Repository, key: Option<String>) -> Self {
+        let repository = Repository {
+            signed_by: match &key {
+                Some(_) => Some(KEY_FILE.to_string()),
+                None => repository.signed_by.clone(),
+            },
+            ..repository.clone()
+        };
+        for source in repository.source_lines() {
+            if self.apt_sources.iter().any(|added| added.source == source) {
+                continue;
+            }
+            let name = self.source_name(&repository.url);
+            self.apt_sources.push(AptSource {
+                name,
+                source,
+                key: key.clone(),
+            });
+        }
+        self
+    }
+
+    /// Append `repository` to `/etc/apk/repositories`
+    pub fn with_apk_repository(mut self, repository: &str) -> Self {
+        let line = format!("{}\n", repository.trim());
+        match self
+            .write_files
+            .iter_mut()
+            .find(|file| file.path == "/etc/apk/repositories")
+        {
+            Some(file) if file.content.lines().any(|added| added == line.trim_end()) => {}
+            Some(file) => file.content.push_str(&line),
+            None => self.write_files.push(WrittenFile {
+                path: "/etc/apk/repositories".to_string(),
+                content: line,
+                append: true,
+            }),
+        }
+        self
+    }
+
+    /// Write the APK key `name` with `content` into `/etc/apk/keys`
+    pub fn with_apk_key(mut self, name: &str, content: &str) -> Self {
+        // Keys must be in place before the repositories are read
+        self.write_files.insert(
+            0,
+            WrittenFile {
+                path: format!("/etc/apk/keys/{name}"),
+                content: content.to_string(),
+                append: false,
+            },
+        );
+        self
+    }
+
+    /// The configuration as cloud-config YAML
+    pub fn to_yaml(&self) -> String {
+        let mut yaml = "#cloud-config\npackage_update: true\npackages:\n".to_string();
+        for (name, version) in &self.packages {
+            yaml.push_str(&format!(
+                "  - [{}, {}]\n",
+                yaml_scalar(name),
+                yaml_scalar(version)
+            ));
+        }
+
+        if !self.apt_sources.is_empty() {
+            yaml.push_str("apt:\n  sources:\n");
+            for source in &self.apt_sources {
+                yaml.push_str(&format!("    {}:\n", yaml_scalar(&source.name)));
+                yaml.push_str(&format!("      source: {}\n", yaml_scalar(&source.source)));
+                if let Some(key) = &source.key {
+                    push_block(&mut yaml, "      ", "key", key);
+                }
+            }
+        }
+
+        if !self.write_files.is_empty() {
+            yaml.push_str("write_files:\n");
+            for file in &self.write_files {
+                yaml.push_str(&format!("  - path: {}\n", yaml_scalar(&file.path)));
+                if file.append {
+                    yaml.push_str("    append: true\n");
+                }
+                push_block(&mut yaml, "    ", "content", &file.content);
+            }
+        }
+        yaml
+    }
+
+    /// Name of a new source of `url`, from its host
+    fn source_name(&self, url: &str) -> String {
+        let base = host_name(url);
+        let mut name = base.clone();
+        let mut suffix = 1;
+        while self.apt_sources.iter().any(|source| source.name == name) {
+            suffix += 1;
+            name = format!("{base}-{suffix}");
+        }
+        name
+    }
+}
+
+/// Host of `url` with other characters than letters and digits replaced by
+/// dashes, e.g. `apt-example-com`
+pub(crate) fn host_name(url: &str) -> String {
+    let authority = url
+        .split_once("://")
+        .map_or(url, |(_, rest)| rest)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let host = authority
+        .rsplit('@')
+        .next()
+        .and_then(|host| host.split(':').next())
+        .filter(|host| !host.is_empty())
+        .unwrap_or("source");
+    host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// Append `key` with `content` as a literal block scalar indented by `indent`
+pub(crate) fn push_block(yaml: &mut String, indent: &str, key: &str, content: &str) {
+    yaml.push_str(&format!("{indent}{key}: |\n"));
+    for line in content.lines() {
+        if line.is_empty() {
+            yaml.push('\n');
+        } else {
+            yaml.push_str(&format!("{indent}  {line}\n"));
+        }
+    }
+}
+
+/// `value` as a YAML scalar, double-quoted unless it is a plain word
+pub(crate) fn yaml_scalar(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._+~-/=@".contains(c))
+        && !value.starts_with(['-', '.', '~', '@'])
+        && value.parse::<f64>().is_err()
+        && !["true", "false", "yes", "no", "on", "off", "null"]
+            .contains(&value.to_ascii_lowercase().as_str());
+    if plain {
+        value.to_string()
+    } else {
+        serde_json::Value::from(value).to_string()
+    }
+}
@@ -0,0 +1,416 @@
+use rmcp::ErrorData as McpError;
+use rmcp::model::ErrorCode as McpErrorCode;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::ExecResult;
+use crate::backend::osv::Vulnerability;
+use crate::backend::parse::classify_failure;
+use crate::backend::pins::PackagePin;
+use crate::lockfile::LockDifference;
+use crate::quota::QuotaKind;
+
+/// Class of a failure, the same on every backend
+///
+/// Set as `error_code` in the data of every tool call error. Unlike
+/// `error_type`, which names the error raised, it is also derived from the
+/// output of failed package manager commands, so an `apk add` and an
+/// `apt-get install` failing because the network is down both give
+/// `network_failure`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// A package, version or other named object does not exist
+    NotFound,
+    /// The requested packages cannot be installed together with the
+    /// installed ones
+    UnsatisfiableDependency,
+    /// A repository or other remote service is unreachable
+    NetworkFailure,
+    /// The server lacks the privileges, or a policy refused the call
+    PermissionDenied,
+    /// Another process holds the package database lock
+    LockHeld,
+    /// The file system is out of space
+    DiskFull,
+    /// An interrupted or failed operation left packages half-installed
+    BrokenState,
+    /// An argument of the call is invalid
+    InvalidArgument,
+    /// The backend does not support the operation
+    Unsupported,
+    /// The call conflicts with a lockfile, pin, quota or vulnerability policy
+    PolicyViolation,
+    /// Any other failure
+    Unknown,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::UnsatisfiableDependency => "unsatisfiable_dependency",
+            Self::NetworkFailure => "network_failure",
+            Self::PermissionDenied => "permission_denied",
+            Self::LockHeld => "lock_held",
+            Self::DiskFull => "disk_full",
+            Self::BrokenState => "broken_state",
+            Self::InvalidArgument => "invalid_argument",
+            Self::Unsupported => "unsupported",
+            Self::PolicyViolation => "policy_violation",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Class of an error raised outside [`PackageManagerError`], from the
+    /// command output in its data or its JSON-RPC code
+    pub fn of(err: &McpError) -> Self {
+        let output = err.data.as_ref().map(|data| {
+            ["stderr", "stdout"]
+                .into_iter()
+                .filter_map(|stream| data[stream].as_str())
+                .collect::<Vec<_>>()
+                .join("\n")
+        });
+        if let Some(code) = output.as_deref().and_then(classify_failure) {
+            return code;
+        }
+        match err.code {
+            McpErrorCode::INVALID_PARAMS => Self::InvalidArgument,
+            McpErrorCode::RESOURCE_NOT_FOUND => Self::NotFound,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Set `error_code` in the data of `err` unless it has one
+pub(crate) fn with_error_code(mut err: McpError) -> McpError {
+    if err
+        .data
+        .as_ref()
+        .is_some_and(|data| data.get("error_code").is_some())
+    {
+        return err;
+    }
+    let code = ErrorCode::of(&err);
+    match &mut err.data {
+        Some(serde_json::Value::Object(data)) => {
+            data.insert("error_code".to_string(), serde_json::json!(code));
+        }
+        data => {
+            *data = Some(serde_json::json!({ "error_code": code }));
+        }
+    }
+    err
+}
+
+/// Errors returned by package manager backends
+///
+/// Every variant maps to a stable `error_type` code in the MCP error data so
+/// clients can branch on the failure class without parsing messages.
+#[derive(Debug, thiserror::Error)]
+pub enum PackageManagerError {
+    #[error("Package '{package}' not found in any searched repository")]
+    NotFound {
+        package: String,
+        searched_repositories: Vec<String>,
+    },
+    #[error(
+        "Version '{version}' of package '{package}' not found. Available versions: {}",
+        available_versions.join(", ")
+    )]
+    VersionNotFound {
+        package: String,
+        version: String,
+        available_versions: Vec<String>,
+    },
+    #[error("Permission denied: {message}")]
+    PermissionDenied { message: String },
+    #[error("Network failure: {message}")]
+    NetworkFailure { message: String },
+    #[error("The package database is locked by another process: {message}")]
+    LockHeld { message: String },
+    #[error("Invalid value '{value}' for {field}: {reason}")]
+    Validation {
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+    #[error("Command exited with status {}", .0.status)]
+    CommandFailed(ExecResult),
+    #[error(
+        "The output of {} exceeded {limit} bytes and was truncated, so its result cannot be read; the command itself succeeded",
+        command.as_deref().map(|command| format!("'{command}'")).unwrap_or_else(|| "the command".to_string())
+    )]
+    OutputTruncated {
+        command: Option<String>,
+        /// Bytes of each stream kept, see `--max-output-bytes`
+        limit: usize,
+    },
+    #[error("Operation '{operation}' is not supported by this backend")]
+    Unsupported { operation: &'static str },
+    #[error(
+        "The packages do not match the lockfile: {}",
+        differences.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+    )]
+    LockfileMismatch { differences: Vec<LockDifference> },
+    #[error(
+        "Version '{version}' of package '{package}' has known vulnerabilities: {}{}",
+        vulnerabilities.iter().map(|vulnerability| vulnerability.id.as_str()).collect::<Vec<_>>().join(", "),
+        if *overridable { ". Pass override_vulnerabilities to install it anyway" } else { "" }
+    )]
+    VulnerablePackage {
+        package: String,
+        version: String,
+        vulnerabilities: Vec<Vulnerability>,
+        /// Whether the call may pass `override_vulnerabilities`
+        overridable: bool,
+    },
+    #[error(
+        "The downloaded artifact of package '{package}' version '{version}' does not match its pins (sha256 {sha256}, {})",
+        signing_key.as_deref().map(|key| format!("signed by {key}")).unwrap_or_else(|| "no signature".to_string())
+    )]
+    ArtifactMismatch {
+        package: String,
+        version: String,
+        sha256: String,
+        signing_key: Option<String>,
+        /// Pins of the package applying to the version, empty when none does
+        expected: Vec<PackagePin>,
+    },
+    #[error(
+        "The session's {quota} quota is exceeded ({used} of {limit} used){}",
+        reset_at.map(|at| format!(", it resets at {}", at.to_rfc3339())).unwrap_or_default()
+    )]
+    QuotaExceeded {
+        quota: QuotaKind,
+        limit: u64,
+        used: u64,
+        reset_at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    #[error(
+        "Not enough disk space on {mount}: the install needs {needed} bytes, {available} bytes are free"
+    )]
+    InsufficientDiskSpace {
+        /// Mount point of the file system
+        mount: String,
+        needed: u64,
+        available: u64,
+    },
+    #[error("Plugin error: {message}")]
+    Plugin { message: String },
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl PackageManagerError {
+    /// Wrap an I/O error raised while running a package manager command
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        let context = context.into();
+        if source.kind() == std::io::ErrorKind::PermissionDenied {
+            Self::PermissionDenied {
+                message: format!("{context}: {source}"),
+            }
+        } else {
+            Self::Io { context, source }
+        }
+    }
+
+    /// Stable machine-readable code for the failure class
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            Self::NotFound { .. } => "package_not_found",
+            Self::VersionNotFound { .. } => "version_not_found",
+            Self::PermissionDenied { .. } => "permission_denied",
+            Self::NetworkFailure { .. } => "network_failure",
+            Self::LockHeld { .. } => "lock_held",
+            Self::Validation { .. } => "validation_error",
+            Self::CommandFailed(_) => "command_failed",
+            Self::OutputTruncated { .. } => "output_truncated",
+            Self::Unsupported { .. } => "unsupported_operation",
+            Self::LockfileMismatch { .. } => "lockfile_mismatch",
+            Self::VulnerablePackage { .. } => "vulnerable_package",
+            Self::ArtifactMismatch { .. } => "artifact_mismatch",
+            Self::QuotaExceeded { .. } => "quota_exceeded",
+            Self::InsufficientDiskSpace { .. } => "insufficient_disk_space",
+            Self::Plugin { .. } => "plugin_error",
+            Self::Io { .. } => "system_error",
+        }
+    }
+
+    /// Class of the failure, from the output of the command for failed
+    /// commands and plugin calls
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::NotFound { .. } | Self::VersionNotFound { .. } => ErrorCode::NotFound,
+            Self::PermissionDenied { .. } => ErrorCode::PermissionDenied,
+            Self::NetworkFailure { .. } => ErrorCode::NetworkFailure,
+            Self::LockHeld { .. } => ErrorCode::LockHeld,
+            Self::Validation { .. } => ErrorCode::InvalidArgument,
+            Self::CommandFailed(result) => {
+                let output = [result.stderr.as_deref(), result.stdout.as_deref()]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                classify_failure(&output).unwrap_or(ErrorCode::Unknown)
+            }
+            Self::OutputTruncated { .. } => ErrorCode::Unknown,
+            Self::Unsupported { .. } => ErrorCode::Unsupported,
+            Self::LockfileMismatch { .. }
+            | Self::VulnerablePackage { .. }
+            | Self::ArtifactMismatch { .. }
+            | Self::QuotaExceeded { .. } => ErrorCode::PolicyViolation,
+            Self::InsufficientDiskSpace { .. } => ErrorCode::DiskFull,
+            Self::Plugin { message } => classify_failure(message).unwrap_or(ErrorCode::Unknown),
+            Self::Io { source, .. } if source.kind() == std::io::ErrorKind::StorageFull => {
+                ErrorCode::DiskFull
+            }
+            Self::Io { .. } => ErrorCode::Unknown,
+        }
+    }
+}
+
+impl From<PackageManagerError> for McpError {
+    fn from(err: PackageManagerError) -> Self {
+        let message = err.to_string();
+        let mut data = serde_json::json!({
+            "error_type": err.error_type(),
+            "error_code": err.error_code(),
+        });
+
+        match &err {
+            PackageManagerError::NotFound {
+                package,
+                searched_repositories,
+            } => {
+                data["package_name"] = serde_json::json!(package);
+                data["searched_repositories"] = serde_json::json!(searched_repositories);
+            }
+            PackageManagerError::VersionNotFound {
+                package,
+                version,
+                available_versions,
+            } => {
+                data["package_name"] = serde_json::json!(package);
+                data["requested_version"] = serde_json::json!(version);
+                data["available_versions"] = serde_json::json!(available_versions);
+            }
+            PackageManagerError::PermissionDenied { .. } => {
+                data["suggestion"] = serde_json::json!(
+                    "Run the server as root or grant it the privileges required by the package manager"
+                );
+            }
+            PackageManagerError::NetworkFailure { .. } => {
+                data["suggestion"] =
+                    serde_json::json!("Check network connectivity and repository availability");
+            }
+            PackageManagerError::LockHeld { .. } => {
+                data["suggestion"] = serde_json::json!(
+                    "Wait for the other package manager process to finish and retry"
+                );
+            }
+            PackageManagerError::Validation {
+                field,
+                value,
+                reason,
+            } => {
+                data[*field] = serde_json::json!(value);
+                data["field"] = serde_json::json!(field);
+                data["reason"] = serde_json::json!(reason);
+            }
+            PackageManagerError::CommandFailed(exec_result) => {
+                data["exit_code"] = serde_json::json!(exec_result.status);
+                if let Some(stdout) = &exec_result.stdout {
+                    data["stdout"] = serde_json::json!(stdout);
+                }
+                if let Some(stderr) = &exec_result.stderr {
+                    data["stderr"] = serde_json::json!(stderr);
+                }
+            }
+            PackageManagerError::OutputTruncated { command, limit } => {
+                data["command"] = serde_json::json!(command);
+                data["output_limit"] = serde_json::json!(limit);
+                data["suggestion"] = serde_json::json!(
+                    "Check the system's state before retrying, then raise --max-output-bytes or split the operation"
+                );
+            }
+            PackageManagerError::Unsupported { operation } => {
+                data["operation"] = serde_json::json!(operation);
+            }
+            PackageManagerError::LockfileMismatch { differences } => {
+                data["differences"] = serde_json::json!(differences);
+            }
+            PackageManagerError::VulnerablePackage {
+                package,
+                version,
+                vulnerabilities,
+                overridable,
+            } => {
+                data["package_name"] = serde_json::json!(package);
+                data["version"] = serde_json::json!(version);
+                data["vulnerabilities"] = serde_json::json!(vulnerabilities);
+                data["overridable"] = serde_json::json!(overridable);
+            }
+            PackageManagerError::ArtifactMismatch {
+                package,
+                version,
+                sha256,
+                signing_key,
+                expected,
+            } => {
+                data["package_name"] = serde_json::json!(package);
+                data["version"] = serde_json::json!(version);
+                data["sha256"] = serde_json::json!(sha256);
+                data["signing_key"] = serde_json::json!(signing_key);
+                data["expected"] = serde_json::json!(expected);
+            }
+            PackageManagerError::QuotaExceeded {
+                quota,
+                limit,
+                used,
+                reset_at,
+            } => {
+                data["quota"] = serde_json::json!(quota);
+                data["limit"] = serde_json::json!(limit);
+                data["used"] = serde_json::json!(used);
+                data["reset_at"] = serde_json::json!(reset_at.map(|at| at.to_rfc3339()));
+            }
+            PackageManagerError::InsufficientDiskSpace {
+                mount,
+                needed,
+                available,
+            } => {
+                data["mount"] = serde_json::json!(mount);
+                data["needed_bytes"] = serde_json::json!(needed);
+                data["available_bytes"] = serde_json::json!(available);
+                data["suggestion"] = serde_json::json!(
+                    "Free space on the file system, e.g. by cleaning the package cache, or install fewer packages at once"
+                );
+            }
+            PackageManagerError::Plugin { .. } => {
+                data["suggestion"] =
+                    serde_json::json!("Check the plugin executable and its stderr output");
+            }
+            PackageManagerError::Io { .. } => {
+                data["suggestion"] = serde_json::json!(
+                    "Ensure the package manager is installed and you have sufficient privileges"
+                );
+            }
+        }
+
+        match err {
+            PackageManagerError::Validation { .. } => McpError::invalid_params(message, Some(data)),
+            _ => McpError::internal_error(message, Some(data)),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::backend::InstallOutcome;
+use crate::version::VersionScheme;
+
+/// Number of events buffered for slow subscribers before they start lagging
+const EVENT_BUS_CAPACITY: usize = 256;
+
+/// Timeout for a single webhook delivery attempt
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Kind of change applied to a package
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageEventKind {
+    Installed,
+    Upgraded,
+    Downgraded,
+    Removed,
+}
+
+/// A change to the installed package set made through the server
+#[derive(Clone, Debug, Serialize)]
+pub struct PackageEvent {
+    pub kind: PackageEventKind,
+    pub package: String,
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_version: Option<String>,
+    pub package_manager: &'static str,
+    /// Execution target the change was made on, for fan-out installs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Broadcast channel of package events
+///
+/// Cloning the bus shares the underlying channel, so every handler clone
+/// publishes to the same subscribers.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<PackageEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { sender }
+    }
+
+    /// Subscribe to events published after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<PackageEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publish an event to every current subscriber
+    pub fn publish(&self, event: PackageEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+
+    /// Publish one event per package changed by an installation
+    ///
+    /// Replaced versions are reported as upgrades unless the version scheme
+    /// shows the new version is older.
+    pub fn publish_install(
+        &self,
+        package_manager: &'static str,
+        scheme: Option<VersionScheme>,
+        outcome: &InstallOutcome,
+    ) {
+        self.publish_install_on(None, package_manager, scheme, outcome);
+    }
+
+    /// Publish the changes of an install made on a named execution target
+    pub fn publish_install_on(
+        &self,
+        target: Option<&str>,
+        package_manager: &'static str,
+        scheme: Option<VersionScheme>,
+        outcome: &InstallOutcome,
+    ) {
+        let timestamp = Utc::now();
+        for installed in &outcome.installed {
+            let kind = match (&installed.previous_version, scheme) {
+                (None, _) => PackageEventKind::Installed,
+                (Some(previous), Some(scheme))
+                    if scheme.compare(&installed.version, previous).is_lt() =>
+                {
+                    PackageEventKind::Downgraded
+                }
+                (Some(_), _) => PackageEventKind::Upgraded,
+            };
+            self.publish(PackageEvent {
+                kind,
+                package: installed.name.clone(),
+                version: installed.version.clone(),
+                previous_version: installed.previous_version.clone(),
+                package_manager,
+                target: target.map(str::to_string),
+                timestamp,
+            });
+        }
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Deliver every event from the bus as a JSON `POST` to each webhook URL
+pub fn spawn_webhook_delivery(events: &EventBus, urls: Vec<String>) -> tokio::task::JoinHandle<()> {
+    let mut receiver = events.subscribe();
+    let client = reqwest::Client::new();
+
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Webhook delivery lagged, {skipped} events were dropped");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            for url in &urls {
+                let delivery = client
+                    .post(url)
+                    .timeout(WEBHOOK_TIMEOUT)
+                    .json(&event)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+
+                if let Err(err) = delivery {
+                    tracing::warn!("Failed to deliver package event to webhook {url}: {err}");
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,95 @@
+//! Operation history for analytics, derived from the audit log
+//!
+//! The audit log records every tool call with its full operation, whose shape
+//! differs per tool and may change between releases. Analytics pipelines
+//! asking "what do agents install most often across our fleet" want flat
+//! rows instead. The `export_history` tool turns each audit entry into
+//! [`HistoryRecord`]s with a fixed set of fields, one per package the call
+//! named, and renders them as JSON Lines.
+//!
+//! Every field is always present, `null` when it does not apply, so each
+//! line has the same columns. New fields may be added; existing ones keep
+//! their name and meaning.
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// One package named by one tool call
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct HistoryRecord {
+    /// Sequence number of the audit entry, shared by the records of a call
+    pub sequence: Option<u64>,
+    /// When the call ran, as RFC 3339
+    pub timestamp: Option<String>,
+    pub tool: Option<String>,
+    /// `succeeded`, `failed` or `rejected`
+    pub outcome: Option<String>,
+    /// Requested package, for calls installing packages
+    pub package: Option<String>,
+    /// Requested version or constraint, when the call gave one
+    pub version: Option<String>,
+    pub arch: Option<String>,
+    /// Custom repository the package was requested from
+    pub repository: Option<String>,
+    /// Root filesystem the call operated on, when not `/`
+    pub root: Option<String>,
+    /// Subject of the client that made the call, when identified
+    pub client: Option<String>,
+    /// Local file, URL or custom repository the call installed from
+    pub untrusted_source: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Records of one line of the audit log
+///
+/// Calls installing several packages give one record per requested package,
+/// leaving out lockfile dependencies; calls not naming a package give one record without one.
+pub fn history_records(entry: &Value) -> Vec<HistoryRecord> {
+    let text = |value: &Value| value.as_str().map(str::to_string);
+    let call = HistoryRecord {
+        sequence: entry["sequence"].as_u64(),
+        timestamp: text(&entry["timestamp"]),
+        tool: text(&entry["tool"]),
+        outcome: text(&entry["outcome"]),
+        package: None,
+        version: None,
+        arch: None,
+        repository: None,
+        root: text(&entry["root"]),
+        client: text(&entry["client"]["subject"]),
+        untrusted_source: text(&entry["untrusted_source"]),
+        error: text(&entry["error"]),
+    };
+
+    let operation = &entry["operation"];
+    let packages: Vec<&Value> = match operation["packages"].as_array() {
+        Some(packages) => packages
+            .iter()
+            .filter(|package| package["dependency"].as_bool() != Some(true))
+            .collect(),
+        None if operation["package"].is_string() => vec![operation],
+        None => Vec::new(),
+    };
+    if packages.is_empty() {
+        return vec![call];
+    }
+    packages
+        .into_iter()
+        .map(|package| HistoryRecord {
+            package: text(package.get("package").unwrap_or(&package["name"])),
+            version: text(&package["version"]),
+            arch: text(&package["arch"]),
+            repository: text(&package["repository"]),
+            ..call.clone()
+        })
+        .collect()
+}
+
+/// The records as JSON Lines, each line ending with a newline
+pub fn to_jsonl(records: &[HistoryRecord]) -> String {
+    records
+        .iter()
+        .filter_map(|record| serde_json::to_string(record).ok())
+        .map(|line| line + "\n")
+        .collect()
+}
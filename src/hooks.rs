@@ -0,0 +1,52 @@
+use async_trait::async_trait;
+use rmcp::{ErrorData as McpError, model::CallToolResult};
+
+use crate::identity::ClientIdentity;
+use crate::operation::Operation;
+
+/// Hooks invoked around every package operation
+///
+/// Embedders can use hooks to plug in custom policy checks, notifications or
+/// cache invalidation without forking the handler. Hooks run in the order
+/// they were registered.
+#[async_trait]
+pub trait Hooks: Send + Sync + 'static {
+    /// Called before the operation runs. Returning an error rejects the
+    /// operation and the error is returned to the client.
+    async fn before(&self, _operation: &Operation) -> Result<(), McpError> {
+        Ok(())
+    }
+
+    /// Called before the operation runs with the client requesting it, when
+    /// the server identified one. Defaults to [`Hooks::before`]; implement
+    /// it instead for per-user policies.
+    async fn before_call(
+        &self,
+        operation: &Operation,
+        _client: Option<&ClientIdentity>,
+    ) -> Result<(), McpError> {
+        self.before(operation).await
+    }
+
+    /// Called after the operation ran with the result returned to the client
+    async fn after(&self, _operation: &Operation, _result: &Result<CallToolResult, McpError>) {}
+}
+
+#[async_trait]
+impl<H: Hooks + ?Sized> Hooks for std::sync::Arc<H> {
+    async fn before(&self, operation: &Operation) -> Result<(), McpError> {
+        (**self).before(operation).await
+    }
+
+    async fn before_call(
+        &self,
+        operation: &Operation,
+        client: Option<&ClientIdentity>,
+    ) -> Result<(), McpError> {
+        (**self).before_call(operation, client).await
+    }
+
+    async fn after(&self, operation: &Operation, result: &Result<CallToolResult, McpError>) {
+        (**self).after(operation, result).await
+    }
+}
@@ -0,0 +1,128 @@
+//! Identity of the client behind a tool call
+//!
+//! Audit trails need to say which person or agent asked for an install, and
+//! policies may treat them differently. The server identifies the client of
+//! each HTTP request, in this order, from:
+//!
+//! - the subject of the bearer token it authenticated with, for tokens
+//!   registered with a subject;
+//! - the common name of its TLS client certificate, taken from a header set
+//!   by the proxy terminating mutual TLS in front of the server;
+//! - the `X-Forwarded-User` header set by an authenticating proxy.
+//!
+//! Headers are only trusted when configured with [`IdentityHeaders`]: the
+//! proxy must overwrite them, since clients can send them too. The resulting
+//! [`ClientIdentity`] is recorded on audit entries, sent to the approval
+//! webhook, passed to hooks and selects per-user repository allowlists.
+
+use std::fmt;
+
+use axum::http::HeaderMap;
+use serde::Serialize;
+
+/// Header set by authenticating proxies with the name of the user
+pub const FORWARDED_USER_HEADER: &str = "x-forwarded-user";
+
+/// How the client was identified
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentitySource {
+    AuthToken,
+    ClientCertificate,
+    ForwardedUser,
+}
+
+/// Person or agent behind a request
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ClientIdentity {
+    pub subject: String,
+    pub source: IdentitySource,
+}
+
+impl ClientIdentity {
+    pub fn new(subject: impl Into<String>, source: IdentitySource) -> Self {
+        Self {
+            subject: subject.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.subject)
+    }
+}
+
+/// Request headers trusted to identify clients
+#[derive(Clone, Debug, Default)]
+pub struct IdentityHeaders {
+    client_certificate: Option<String>,
+    forwarded_user: bool,
+}
+
+impl IdentityHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Header holding the subject of the client's TLS certificate, either a
+    /// distinguished name (`CN=alice,O=Example`) or the bare common name
+    pub fn client_certificate(mut self, header: impl Into<String>) -> Self {
+        self.client_certificate = Some(header.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Trust the `X-Forwarded-User` header
+    pub fn forwarded_user(mut self) -> Self {
+        self.forwarded_user = true;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.client_certificate.is_none() && !self.forwarded_user
+    }
+
+    /// Identity given by the trusted headers of a request
+    pub fn identify(&self, headers: &HeaderMap) -> Option<ClientIdentity> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        if let Some(subject) = self
+            .client_certificate
+            .as_deref()
+            .and_then(header)
+            .and_then(common_name)
+        {
+            return Some(ClientIdentity::new(
+                subject,
+                IdentitySource::ClientCertificate,
+            ));
+        }
+        if self.forwarded_user
+            && let Some(user) = header(FORWARDED_USER_HEADER)
+        {
+            return Some(ClientIdentity::new(user, IdentitySource::ForwardedUser));
+        }
+        None
+    }
+}
+
+/// Common name of a certificate subject, in RFC 4514 (`CN=alice,O=Example`)
+/// or OpenSSL (`/O=Example/CN=alice`) form, or the subject itself when it
+/// has no attributes
+fn common_name(subject: &str) -> Option<&str> {
+    if !subject.contains('=') {
+        return Some(subject);
+    }
+    subject
+        .split([',', '/'])
+        .filter_map(|attribute| attribute.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("CN"))
+        .map(|(_, value)| value.trim())
+        .filter(|value| !value.is_empty())
+}
@@ -0,0 +1,131 @@
+//! Model Context Protocol (MCP) server exposing OS package manager operations.
+//!
+//! The crate can be used as a standalone binary or embedded into other Rust
+//! services. Embedders pick a [`PackageManager`] backend (either a concrete
+//! type or one chosen at runtime through [`BackendKind`]), wrap it in a
+//! [`PackageManagerHandler`] and mount the resulting service in their own
+//! axum router:
+//!
+//! ```no_run
+//! use package_manager_mcp::{Apk, service};
+//!
+//! let app: axum::Router = axum::Router::new().nest_service("/packages/mcp", service(Apk::new()));
+//! ```
+//!
+//! Programs that run the server themselves can use [`ServerBuilder`], which
+//! also wires hooks, webhooks, authentication and extra routes.
+
+pub mod ansible;
+pub mod apko;
+pub mod audit;
+pub mod backend;
+pub mod cloudinit;
+pub mod error;
+pub mod events;
+pub mod history;
+pub mod hooks;
+pub mod identity;
+pub mod lockfile;
+pub mod operation;
+pub mod quota;
+pub mod sbom;
+pub mod server;
+pub mod session;
+pub mod version;
+
+use std::sync::Arc;
+
+use rmcp::transport::streamable_http_server::{
+    StreamableHttpService, session::local::LocalSessionManager,
+};
+
+pub use ansible::{AnsibleTask, TaskArgument};
+pub use apko::ApkoConfig;
+pub use audit::{AuditEntry, AuditLog, AuditOutcome, AuditSigner, AuditVerification};
+pub use backend::{
+    BackendKind, Capabilities, ExecResult, FetchedPackage, INSTALLED_MANIFEST_URI, InstallFlags,
+    InstallOptions, InstallOutcome, InstallReason, InstallVersionOptions, InstalledPackage,
+    LockedPackage, PackageLicense, PackageManager, PackageManagerHandler, PinnedPackage,
+    Recommends, Repository, SESSION_DELTA_URI, SearchListing, SearchMode, SearchOptions,
+    SearchResult, SearchSort, SpaceRequirement, SystemInfo, TargetInstallOptions,
+    apk::Apk,
+    approval::ApprovalWebhook,
+    apt::Apt,
+    cache::{CachingBackend, SearchCache},
+    credentials::{CredentialsError, RepositoryCredentials},
+    descriptions::{DescriptionOverrides, DescriptionsError, TextOverride},
+    diagnostics::{PolicyReport, ServerReport},
+    doctor::{CheckStatus, DoctorReport, HealthCheck},
+    exit_status::ExitMeaning,
+    freshness::{AutoRefresh, RefreshReason},
+    generic::GenericBackend,
+    mirrors::MirrorList,
+    mock::{MockBackend, MockFailure, MockPackage},
+    naming::ToolNames,
+    notifications::{Notification, NotificationFormat, NotificationSink, NotifiedPackage},
+    offline::OfflineMirrors,
+    operation_log::{LogLine, OperationLog, OperationLogStore},
+    osv::{OsvClient, Severity, VulnerabilityGate},
+    pins::{PackagePin, PackagePins, PinsError},
+    pkgs::PackagesSite,
+    plugin::PluginBackend,
+    privileges::PrivilegeReport,
+    registry::{BackendOptions, BackendRegistry},
+    repology::{DistroPackage, RepologyClient},
+    runner::{
+        ChrootRunner, CommandRunner, ContainerRunner, EscalatingRunner, Escalation,
+        KubernetesRunner, LocalRunner, RecordingRunner, SshRunner,
+    },
+    snapshot::SnapshotArchive,
+    targets::{Target, TargetResult, TargetSpec},
+};
+pub use cloudinit::CloudConfig;
+pub use error::{ErrorCode, PackageManagerError};
+pub use events::{EventBus, PackageEvent, PackageEventKind};
+pub use hooks::Hooks;
+pub use identity::{ClientIdentity, IdentityHeaders, IdentitySource};
+pub use lockfile::Lockfile;
+pub use operation::{Operation, OutputFormat, Page};
+pub use quota::{QuotaKind, QuotaLimits};
+pub use server::{Server, ServerBuilder, ServerError};
+pub use session::{ExportFormat, ExportScope, SessionDelta, SessionLog};
+pub use version::{VersionConstraint, VersionScheme};
+
+/// Build the streamable HTTP MCP service for the given backend
+pub fn service(
+    backend: impl PackageManager,
+) -> StreamableHttpService<PackageManagerHandler, LocalSessionManager> {
+    shared_service(Arc::new(backend))
+}
+
+/// Build the streamable HTTP MCP service for a backend selected at runtime
+pub fn shared_service(
+    backend: Arc<dyn PackageManager>,
+) -> StreamableHttpService<PackageManagerHandler, LocalSessionManager> {
+    handler_service(PackageManagerHandler::from_arc(backend))
+}
+
+/// Build the streamable HTTP MCP service for a fully configured handler
+pub fn handler_service(
+    handler: PackageManagerHandler,
+) -> StreamableHttpService<PackageManagerHandler, LocalSessionManager> {
+    StreamableHttpService::new(
+        move || Ok(handler.for_new_session()),
+        LocalSessionManager::default().into(),
+        Default::default(),
+    )
+}
+
+/// Build an axum router exposing the MCP service at `/mcp`
+pub fn router(backend: Arc<dyn PackageManager>) -> axum::Router {
+    axum::Router::new().nest_service("/mcp", shared_service(backend))
+}
+
+/// Serve the given router on the listener until Ctrl+C is received
+pub async fn serve(listener: tokio::net::TcpListener, router: axum::Router) -> std::io::Result<()> {
+    axum::serve(listener, router)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+}
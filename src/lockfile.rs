@@ -0,0 +1,194 @@
+//! Lockfiles pinning the packages installed during an MCP session
+//!
+//! An exported session reinstalls the requested packages at their versions,
+//! but lets the package manager pick their dependencies again. The
+//! `generate_lockfile` tool pins the whole set instead: every package the
+//! session installed, including the dependencies its installs pulled in, with
+//! its exact version, the checksum the package manager knows it by and the
+//! repository holding it. Lockfiles are JSON documents:
+//!
+//! ```json
+//! {
+//!   "lockfile_version": 1,
+//!   "backend": "APK",
+//!   "os": "Alpine Linux",
+//!   "generated_at": "2025-07-14T09:30:00Z",
+//!   "packages": [
+//!     {
+//!       "name": "curl",
+//!       "version": "8.14.1-r1",
+//!       "source": "https://dl-cdn.alpinelinux.org/alpine/v3.22/main",
+//!       "checksum": "Q1hEw9wdKBn2zrEYGA+kHYdeyTOxY="
+//!     },
+//!     {
+//!       "name": "libcurl",
+//!       "version": "8.14.1-r1",
+//!       "arch": "x86_64",
+//!       "source": "https://dl-cdn.alpinelinux.org/alpine/v3.22/main",
+//!       "checksum": "Q1nS3S1R5yvvFEivQZqU5SwbC7IKA=",
+//!       "dependency": true
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Checksums are in the package manager's own notation (see
+//! [`LockedPackage::checksum`]), so they can only be compared between
+//! systems of the same backend.
+//!
+//! `install_from_lockfile` installs the packages of a lockfile at exactly
+//! their pinned versions. Before installing, it compares the lockfile with
+//! what the package manager knows of each version ([`Lockfile::differences`])
+//! and fails with the list of [`LockDifference`]s if a version is no longer
+//! available or has another checksum; the checksums are compared again once
+//! the packages are installed.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{InstalledPackage, LockedPackage, PackageManager, PinnedPackage};
+
+/// Version of the lockfile format written by this crate
+pub const LOCKFILE_VERSION: u32 = 1;
+
+/// Packages pinned for a reproducible install
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub lockfile_version: u32,
+    /// Name of the backend that wrote the lockfile, e.g. `APK`
+    pub backend: String,
+    /// Operating system the packages were installed on
+    pub os: String,
+    pub generated_at: DateTime<Utc>,
+    /// Requested packages in installation order, then their dependencies
+    pub packages: Vec<LockedPackage>,
+}
+
+impl Lockfile {
+    /// Lockfile of `packages`, written now by `backend`
+    pub fn new(backend: &dyn PackageManager, packages: Vec<LockedPackage>) -> Self {
+        Self {
+            lockfile_version: LOCKFILE_VERSION,
+            backend: backend.name().to_string(),
+            os: backend.os_name().to_string(),
+            generated_at: Utc::now(),
+            packages,
+        }
+    }
+
+    /// Parse a lockfile given as a JSON document or as its text
+    ///
+    /// Lockfiles written by a newer version of the format are rejected.
+    pub fn from_value(value: &serde_json::Value) -> Result<Self, String> {
+        let lockfile: Self = match value {
+            serde_json::Value::String(text) => serde_json::from_str(text),
+            value => serde_json::from_value(value.clone()),
+        }
+        .map_err(|err| format!("invalid lockfile: {err}"))?;
+        if lockfile.lockfile_version > LOCKFILE_VERSION {
+            return Err(format!(
+                "lockfile version {} is not supported, the newest supported version is {LOCKFILE_VERSION}",
+                lockfile.lockfile_version
+            ));
+        }
+        Ok(lockfile)
+    }
+
+    /// The locked packages, pinned to their versions
+    pub fn pinned(&self) -> Vec<PinnedPackage> {
+        self.packages
+            .iter()
+            .map(|package| package.package.clone())
+            .collect()
+    }
+
+    /// Locked packages that cannot be installed as pinned
+    ///
+    /// `found` is what the package manager knows of the locked packages now,
+    /// in the same order (see [`PackageManager::lock_packages`]). A version
+    /// is unavailable when no repository holds it and it is not the
+    /// installed one; packages locked to a custom repository are not
+    /// checked, since it is only added by the install. Checksums are only
+    /// compared when both sides have one.
+    pub fn differences(
+        &self,
+        found: &[LockedPackage],
+        installed: &[InstalledPackage],
+    ) -> Vec<LockDifference> {
+        self.packages
+            .iter()
+            .zip(found)
+            .filter_map(|(locked, found)| {
+                let installed_version = installed
+                    .iter()
+                    .find(|package| package.name == locked.package.name)
+                    .map(|package| package.version.clone());
+                let problem = match (&locked.checksum, &found.checksum) {
+                    (Some(locked), Some(found)) if locked != found => {
+                        LockProblem::ChecksumMismatch {
+                            locked_checksum: locked.clone(),
+                            found_checksum: found.clone(),
+                        }
+                    }
+                    _ if found.source.is_none()
+                        && locked.package.custom_repositories().next().is_none()
+                        && installed_version.as_deref() != Some(&locked.package.version) =>
+                    {
+                        LockProblem::Unavailable
+                    }
+                    _ => return None,
+                };
+                Some(LockDifference {
+                    name: locked.package.name.clone(),
+                    locked_version: locked.package.version.clone(),
+                    installed_version,
+                    problem,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A locked package that cannot be installed as pinned
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LockDifference {
+    pub name: String,
+    /// Version pinned by the lockfile
+    pub locked_version: String,
+    /// Version installed on the system, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installed_version: Option<String>,
+    #[serde(flatten)]
+    pub problem: LockProblem,
+}
+
+impl fmt::Display for LockDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: ", self.name, self.locked_version)?;
+        match &self.problem {
+            LockProblem::Unavailable => write!(f, "no longer available"),
+            LockProblem::ChecksumMismatch {
+                locked_checksum,
+                found_checksum,
+            } => write!(
+                f,
+                "checksum {found_checksum} does not match the locked {locked_checksum}"
+            ),
+        }
+    }
+}
+
+/// Why a locked package cannot be installed as pinned
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum LockProblem {
+    /// No repository holds the pinned version
+    Unavailable,
+    /// The package manager knows the pinned version by another checksum
+    ChecksumMismatch {
+        locked_checksum: String,
+        found_checksum: String,
+    },
+}
@@ -1,17 +1,23 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use rmcp::transport::streamable_http_server::{
-    StreamableHttpService, session::local::LocalSessionManager,
-};
 use tracing_subscriber::{
     layer::SubscriberExt,
     util::SubscriberInitExt,
     {self},
 };
 
-mod backend;
+use std::sync::Arc;
 
-use backend::{PackageManagerHandler, apk::Apk, apt::Apt};
+use package_manager_mcp::audit::verify_chain;
+use package_manager_mcp::backend::exec;
+use package_manager_mcp::{
+    ApprovalWebhook, AuditLog, AuditSigner, AutoRefresh, BackendOptions, BackendRegistry,
+    ChrootRunner, CommandRunner, ContainerRunner, DescriptionOverrides, EscalatingRunner,
+    Escalation, IdentityHeaders, KubernetesRunner, LocalRunner, NotificationSink, OfflineMirrors,
+    OperationLogStore, OsvClient, PackagePins, QuotaLimits, Recommends, RepologyClient,
+    RepositoryCredentials, SearchCache, ServerBuilder, Severity, Target, TargetSpec, ToolNames,
+    VulnerabilityGate,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -20,6 +26,365 @@ struct Args {
     port: u32,
     #[arg(default_value = "0.0.0.0")]
     host: String,
+    /// Package manager backend to use: auto, apk, apt, generic, mock or plugin
+    #[arg(long, default_value = "auto")]
+    backend: String,
+    /// Plugin executable used by `--backend plugin`
+    #[arg(long)]
+    plugin: Option<String>,
+    /// Argument passed to the plugin executable (repeatable)
+    #[arg(long = "plugin-arg", allow_hyphen_values = true)]
+    plugin_args: Vec<String>,
+    /// TOML configuration used by `--backend generic` and `--backend mock`
+    #[arg(long = "backend-config")]
+    backend_config: Option<std::path::PathBuf>,
+    /// Alpine mirror base URL the apk backend fails over between, in order
+    /// of preference (repeatable)
+    #[arg(long = "apk-mirror")]
+    apk_mirrors: Vec<String>,
+    /// Repository searched by the apk backend when no repository is given:
+    /// a path below the mirrors such as `v3.22/main`, or a full URL
+    /// (repeatable, or comma-separated in the environment variable)
+    #[arg(
+        long = "apk-search-repository",
+        env = "APK_SEARCH_REPOSITORIES",
+        value_delimiter = ','
+    )]
+    apk_search_repositories: Vec<String>,
+    /// Number of stable Alpine branches older than the system's to search
+    /// when the search repositories are derived from the system
+    #[arg(long = "apk-older-branches")]
+    apk_older_branches: Option<usize>,
+    /// Directory where the apk backend keeps the indexes of searched
+    /// repositories between searches, instead of downloading them every time
+    #[arg(long = "apk-index-cache")]
+    apk_index_cache: Option<String>,
+    /// Minutes cached apk indexes are used before being downloaded again
+    #[arg(long = "apk-index-cache-max-age")]
+    apk_index_cache_max_age: Option<u64>,
+    /// Search by downloading and parsing the apk repository indexes instead
+    /// of running `apk search`
+    #[arg(long = "apk-native-index")]
+    apk_native_index: bool,
+    /// Run every apk install with --no-scripts, for environments forbidding
+    /// maintainer scripts
+    #[arg(long = "apk-no-scripts")]
+    apk_no_scripts: bool,
+    /// Search through the Alpine packages site instead of running
+    /// `apk search` (default site: https://pkgs.alpinelinux.org)
+    #[arg(
+        long = "apk-packages-site",
+        num_args = 0..=1,
+        default_missing_value = package_manager_mcp::backend::pkgs::DEFAULT_PACKAGES_SITE,
+        conflicts_with = "apk_native_index"
+    )]
+    apk_packages_site: Option<String>,
+    /// Branch searched on the packages site, e.g. edge or v3.22 (default: the
+    /// system's branch)
+    #[arg(long = "apk-packages-branch", requires = "apk_packages_site")]
+    apk_packages_branch: Option<String>,
+    /// Install versions missing from the APT suites from the snapshot
+    /// archive (default archive: https://snapshot.debian.org)
+    #[arg(
+        long = "apt-snapshot-archive",
+        num_args = 0..=1,
+        default_missing_value = package_manager_mcp::backend::snapshot::DEFAULT_SNAPSHOT_ARCHIVE
+    )]
+    apt_snapshot_archive: Option<String>,
+    /// Suite installed from the snapshot archive, e.g. bookworm (default: the
+    /// system's release codename)
+    #[arg(long = "apt-snapshot-suite", requires = "apt_snapshot_archive")]
+    apt_snapshot_suite: Option<String>,
+    /// Packages APT installs besides the dependencies when calls do not
+    /// choose: none, recommends or suggests (default: APT's configuration)
+    #[arg(long = "install-recommends")]
+    install_recommends: Option<Recommends>,
+    /// TOML file mapping private repository URLs to their credentials
+    /// (basic authentication, token or netrc entry)
+    #[arg(long = "repository-credentials")]
+    repository_credentials: Option<std::path::PathBuf>,
+    /// Seconds search results are reused for identical searches (0 disables
+    /// the cache)
+    #[arg(long = "search-cache-ttl", default_value_t = 0)]
+    search_cache_ttl: u64,
+    /// JSON file the search cache is loaded from and saved to
+    #[arg(long = "search-cache-file")]
+    search_cache_file: Option<std::path::PathBuf>,
+    /// Seconds a listing of installed packages is reused until a mutating
+    /// tool succeeds (0 disables the cache)
+    #[arg(long = "installed-cache-ttl", default_value_t = 0)]
+    installed_cache_ttl: u64,
+    /// Refresh the repository indexes and retry once when an install or
+    /// search misses a package
+    #[arg(long = "auto-refresh")]
+    auto_refresh: bool,
+    /// Also refresh the indexes before installs and searches when they are
+    /// older than this many seconds (implies --auto-refresh)
+    #[arg(long = "index-max-age-secs")]
+    index_max_age_secs: Option<u64>,
+    /// Seconds since the last refresh before a missing package refreshes
+    /// the indexes again
+    #[arg(long = "auto-refresh-min-interval-secs", default_value_t = 5 * 60)]
+    auto_refresh_min_interval_secs: u64,
+    /// Seconds the result of a mutating call is replayed to retries with the
+    /// same idempotency key (0 disables idempotency keys)
+    #[arg(long = "idempotency-ttl", default_value_t = 24 * 60 * 60)]
+    idempotency_ttl: u64,
+    /// Do not let mutating calls run as background jobs with `async`, and
+    /// hide the job tools
+    #[arg(long = "disable-jobs")]
+    disable_jobs: bool,
+    /// Prefix of every exposed tool name, e.g. `alpine_` to tell the tools
+    /// apart from those of other servers behind the same MCP gateway
+    #[arg(long = "tool-prefix", default_value = "")]
+    tool_prefix: String,
+    /// Name a tool is exposed as instead of its prefixed name, as
+    /// TOOL=ALIAS (e.g. install_package=apk_add) (repeatable)
+    #[arg(long = "tool-alias", value_parser = parse_assignment)]
+    tool_aliases: Vec<(String, String)>,
+    /// TOML file replacing or extending tool descriptions and the server
+    /// instructions
+    #[arg(long)]
+    descriptions: Option<std::path::PathBuf>,
+    /// Architecture used by installs and searches that do not pass `arch`
+    #[arg(long)]
+    arch: Option<String>,
+    /// Packages per page of `list_installed_packages` when the call does not
+    /// pass a `limit` (default: the whole listing)
+    #[arg(long = "page-size")]
+    page_size: Option<usize>,
+    /// Manage packages of the root filesystem at this path with the package
+    /// manager's own options (`apk --root`, `apt-get -o Dir=`), running it
+    /// from the host
+    #[arg(long)]
+    root: Option<String>,
+    /// Manage packages of the root filesystem at this path through `chroot`
+    #[arg(long, conflicts_with_all = ["container", "pod"])]
+    chroot: Option<std::path::PathBuf>,
+    /// Manage packages of this running container through the engine's `exec`
+    #[arg(long, conflicts_with = "pod")]
+    container: Option<String>,
+    /// Container engine used by `--container`: docker, podman or nerdctl
+    #[arg(
+        long = "container-engine",
+        default_value = "docker",
+        value_parser = ["docker", "podman", "nerdctl"],
+        requires = "container"
+    )]
+    container_engine: String,
+    /// User running the package manager in the container (default: the
+    /// image's user for docker, root for podman and nerdctl)
+    #[arg(long = "container-user", requires = "container")]
+    container_user: Option<String>,
+    /// Manage packages of this Kubernetes pod through `kubectl exec`
+    #[arg(long)]
+    pod: Option<String>,
+    /// Namespace of the `--pod` (default: the kubeconfig context's)
+    #[arg(long = "pod-namespace", requires = "pod")]
+    pod_namespace: Option<String>,
+    /// Container of the `--pod` (default: the pod's default container)
+    #[arg(long = "pod-container", requires = "pod")]
+    pod_container: Option<String>,
+    /// Kubeconfig used by `--pod` (default: `KUBECONFIG`, `~/.kube/config` or
+    /// the in-cluster service account)
+    #[arg(long, requires = "pod")]
+    kubeconfig: Option<std::path::PathBuf>,
+    /// Kubeconfig context used by `--pod`
+    #[arg(long = "kube-context", requires = "pod")]
+    kube_context: Option<String>,
+    /// Run installs and repository refreshes through `sudo -n` or `doas -n`
+    /// when the server is not running as root
+    #[arg(long, value_parser = ["sudo", "doas"])]
+    escalate: Option<String>,
+    /// containerd namespace of the container, for nerdctl (e.g. `k8s.io`)
+    #[arg(long = "container-namespace", requires = "container")]
+    container_namespace: Option<String>,
+    /// Execution target for fan-out installs, as NAME=SPEC where SPEC is
+    /// `local`, `docker:CONTAINER`, `podman:CONTAINER`, `nerdctl:CONTAINER`,
+    /// `chroot:PATH`, `k8s:[NAMESPACE/]POD[:CONTAINER]` or `ssh:HOST`
+    /// (repeatable)
+    #[arg(long = "target")]
+    targets: Vec<Target>,
+    /// Record the packages each session installs and offer the
+    /// `export_session` tool
+    #[arg(long = "record-sessions")]
+    record_sessions: bool,
+    /// Offer the `check_vulnerabilities` tool, querying this OSV API
+    /// (default API: https://api.osv.dev)
+    #[arg(
+        long = "osv-api",
+        num_args = 0..=1,
+        default_missing_value = package_manager_mcp::backend::osv::DEFAULT_OSV_API
+    )]
+    osv_api: Option<String>,
+    /// Offer the `search_all_distros` tool, querying this Repology API
+    /// (default API: https://repology.org/api/v1)
+    #[arg(
+        long = "repology-api",
+        num_args = 0..=1,
+        default_missing_value = package_manager_mcp::backend::repology::DEFAULT_REPOLOGY_API
+    )]
+    repology_api: Option<String>,
+    /// Block installs of versions affected by unfixed vulnerabilities of this
+    /// severity or above (low, medium, high or critical), checked with the
+    /// OSV API
+    #[arg(long = "block-vulnerabilities", requires = "osv_api")]
+    block_vulnerabilities: Option<Severity>,
+    /// Also block vulnerabilities that are fixed in another version
+    #[arg(
+        long = "block-fixed-vulnerabilities",
+        requires = "block_vulnerabilities"
+    )]
+    block_fixed_vulnerabilities: bool,
+    /// Let installs passing `override_vulnerabilities` through the
+    /// vulnerability gate
+    #[arg(
+        long = "allow-vulnerability-override",
+        requires = "block_vulnerabilities"
+    )]
+    allow_vulnerability_override: bool,
+    /// TOML file pinning the SHA-256 or signing key of critical packages,
+    /// whose downloaded file is verified before they are installed
+    #[arg(long = "package-pins")]
+    package_pins: Option<std::path::PathBuf>,
+    /// License pattern flagged by the `license_report` tool, e.g. AGPL
+    /// (repeatable)
+    #[arg(long = "deny-license")]
+    denied_licenses: Vec<String>,
+    /// Allow installs from package files, URLs and custom repositories
+    #[arg(long = "allow-untrusted-sources")]
+    allow_untrusted_sources: bool,
+    /// Allow installs skipping maintainer scripts with `no_scripts`
+    #[arg(long = "allow-no-scripts")]
+    allow_no_scripts: bool,
+    /// URL prefix or domain the `repository` argument must be on (repeatable)
+    #[arg(long = "allowed-repository")]
+    allowed_repositories: Vec<String>,
+    /// URL prefix or domain the `repository` argument of one client must be
+    /// on, as SUBJECT=ENTRY, replacing `--allowed-repository` for that
+    /// client (repeatable)
+    #[arg(long = "user-allowed-repository", value_parser = parse_assignment)]
+    user_allowed_repositories: Vec<(String, String)>,
+    /// Offline mode: internal mirror replacing a public repository, as
+    /// PUBLIC=INTERNAL (e.g.
+    /// https://dl-cdn.alpinelinux.org/alpine=https://mirror.example.com/alpine),
+    /// or an internal repository URL (repeatable)
+    #[arg(long = "offline-mirror")]
+    offline_mirrors: Vec<String>,
+    /// Refuse to start, and to install, search or refresh, while a repository
+    /// lacks a valid signing configuration
+    #[arg(long = "signed-repositories-only")]
+    signed_repositories_only: bool,
+    /// Run installs without first checking, from a simulation of the
+    /// install, that the disk has the space they need
+    #[arg(long = "skip-disk-space-check")]
+    skip_disk_space_check: bool,
+    /// JSON Lines file recording every tool call
+    #[arg(long = "audit-log")]
+    audit_log: Option<std::path::PathBuf>,
+    /// File holding the Ed25519 key signing the audit log, as a 32-byte hex
+    /// seed (e.g. generated with `openssl rand -hex 32`)
+    #[arg(long = "audit-signing-key")]
+    audit_signing_key: Option<std::path::PathBuf>,
+    /// Verify the hash chain of an audit log file and exit, instead of
+    /// serving
+    #[arg(long = "verify-audit-log")]
+    verify_audit_log: Option<std::path::PathBuf>,
+    /// Public key, in hex, the signatures of `--verify-audit-log` must be
+    /// made with
+    #[arg(long = "audit-public-key", requires = "verify_audit_log")]
+    audit_public_key: Option<String>,
+    /// Directory keeping the complete output of every install and
+    /// repository refresh, served by `get_operation_log`
+    #[arg(long = "operation-log-dir")]
+    operation_log_dir: Option<std::path::PathBuf>,
+    /// Operation logs kept, the oldest are deleted beyond
+    #[arg(
+        long = "operation-log-max",
+        default_value_t = 1000,
+        requires = "operation_log_dir"
+    )]
+    operation_log_max: usize,
+    /// Seconds operation logs are kept (0 keeps them regardless of age)
+    #[arg(
+        long = "operation-log-max-age-secs",
+        default_value_t = 30 * 24 * 60 * 60,
+        requires = "operation_log_dir"
+    )]
+    operation_log_max_age_secs: u64,
+    /// Packages each MCP session may install, upgrade or downgrade
+    #[arg(long = "max-installs")]
+    max_installs: Option<u64>,
+    /// Packages the installs of each MCP session may remove, e.g. because
+    /// they conflict
+    #[arg(long = "max-removed-packages")]
+    max_removed_packages: Option<u64>,
+    /// Bytes the installs of each MCP session may download (APT only, APK
+    /// does not report downloads)
+    #[arg(long = "max-downloaded-bytes")]
+    max_downloaded_bytes: Option<u64>,
+    /// Apply the session quotas to the last N seconds instead of the whole
+    /// session
+    #[arg(long = "quota-window-secs")]
+    quota_window_secs: Option<u64>,
+    /// URL asked to approve every mutating operation before it runs
+    #[arg(long = "approval-webhook")]
+    approval_webhook: Option<String>,
+    /// Seconds the approval webhook may take to decide
+    #[arg(
+        long = "approval-timeout-secs",
+        default_value_t = 30,
+        requires = "approval_webhook"
+    )]
+    approval_timeout_secs: u64,
+    /// Run operations when the approval webhook fails or times out, instead
+    /// of rejecting them
+    #[arg(long = "approval-fail-open", requires = "approval_webhook")]
+    approval_fail_open: bool,
+    /// Webhook URL notified with a JSON event for every package change (repeatable)
+    #[arg(long = "webhook-url")]
+    webhook_urls: Vec<String>,
+    /// Webhook URL receiving a JSON notification after every install, with
+    /// its outcome, session and initiator (repeatable)
+    #[arg(long = "notify-webhook")]
+    notify_webhooks: Vec<String>,
+    /// Slack-compatible incoming webhook URL receiving a message after every
+    /// install (repeatable)
+    #[arg(long = "notify-slack")]
+    notify_slack: Vec<String>,
+    /// Bearer token clients must send in the `Authorization` header
+    #[arg(long = "auth-token")]
+    auth_token: Option<String>,
+    /// Bearer token identifying its clients in audit entries and policy
+    /// decisions, as SUBJECT=TOKEN (repeatable)
+    #[arg(long = "auth-token-subject", value_parser = parse_assignment)]
+    auth_token_subjects: Vec<(String, String)>,
+    /// Header in which the proxy terminating mutual TLS passes the subject
+    /// of the client certificate, e.g. `X-SSL-Client-S-DN`
+    #[arg(long = "client-cert-header")]
+    client_cert_header: Option<String>,
+    /// Identify clients by the `X-Forwarded-User` header of an
+    /// authenticating proxy
+    #[arg(long = "trust-forwarded-user")]
+    trust_forwarded_user: bool,
+    /// Bytes of the stdout and of the stderr of a command kept in memory;
+    /// longer output keeps its start and end
+    #[arg(long = "max-output-bytes", default_value_t = exec::DEFAULT_OUTPUT_LIMIT)]
+    max_output_bytes: usize,
+    /// Milliseconds an install waits for other installs of the session to
+    /// run them as one command (0 disables batching)
+    #[arg(long = "batch-window-ms", default_value_t = 0)]
+    batch_window_ms: u64,
+    /// Milliseconds between checks of the installed packages for clients
+    /// subscribed to the installed manifest
+    #[arg(
+        long = "watch-interval-ms",
+        default_value_t = package_manager_mcp::backend::drift::DEFAULT_WATCH_INTERVAL.as_millis() as u64
+    )]
+    watch_interval_ms: u64,
+    /// Compress MCP responses with gzip or brotli for clients that accept it
+    #[arg(long = "compress-responses")]
+    compress_responses: bool,
 }
 
 #[tokio::main]
@@ -34,36 +399,339 @@ async fn main() -> Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // Auto-detect OS and create appropriate backend
-    let router = if std::path::Path::new("/etc/alpine-release").exists() {
-        tracing::info!("Detected Alpine Linux, using APK backend");
-        let handler = PackageManagerHandler::new(Apk::new());
-        let service = StreamableHttpService::new(
-            move || Ok(handler.clone()),
-            LocalSessionManager::default().into(),
-            Default::default(),
-        );
-        axum::Router::new().nest_service("/mcp", service)
-    } else if std::path::Path::new("/etc/debian_version").exists() {
-        tracing::info!("Detected Debian/Debian-derivative, using APT backend");
-        let handler = PackageManagerHandler::new(Apt::new());
-        let service = StreamableHttpService::new(
-            move || Ok(handler.clone()),
-            LocalSessionManager::default().into(),
-            Default::default(),
-        );
-        axum::Router::new().nest_service("/mcp", service)
-    } else {
-        anyhow::bail!("Unsupported OS: neither Alpine nor Debian detected");
-    };
+    if let Some(path) = &args.verify_audit_log {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read audit log {}", path.display()))?;
+        let verification = verify_chain(&contents, args.audit_public_key.as_deref());
+        println!("{}", serde_json::to_string_pretty(&verification)?);
+        if !verification.valid {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    exec::set_output_limit(args.max_output_bytes);
 
-    let tcp_listener =
-        tokio::net::TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
-    let _ = axum::serve(tcp_listener, router)
-        .with_graceful_shutdown(async {
-            let _ = tokio::signal::ctrl_c().await;
+    let escalation = args.escalate.as_deref().map(|program| {
+        if program == "doas" {
+            Escalation::Doas
+        } else {
+            Escalation::Sudo
+        }
+    });
+    // Privileges are escalated on the host, around `chroot` and the container engine
+    let host: Arc<dyn CommandRunner> = match escalation {
+        Some(escalation) => {
+            let program = escalation.program();
+            let runner = EscalatingRunner::new(escalation);
+            match runner.preflight().await {
+                Ok(()) if runner.escalates() => {
+                    tracing::info!("Running privileged commands through {program}")
+                }
+                Ok(()) => tracing::info!("Running as root, {program} is not needed"),
+                Err(err) => tracing::warn!("Installs and refreshes will fail: {err}"),
+            }
+            Arc::new(runner)
+        }
+        None => Arc::new(LocalRunner),
+    };
+    let runner: Arc<dyn CommandRunner> = match (args.chroot, args.container, args.pod) {
+        (Some(root), _, _) => Arc::new(ChrootRunner::with_runner(root, host)),
+        (_, Some(container), _) => {
+            let mut runner =
+                ContainerRunner::for_engine(args.container_engine.clone(), container, host);
+            if let Some(user) = args.container_user {
+                runner = runner.with_user(user);
+            }
+            if let Some(namespace) = args.container_namespace {
+                if args.container_engine != "nerdctl" {
+                    anyhow::bail!(
+                        "--container-namespace only applies to --container-engine nerdctl"
+                    );
+                }
+                runner = runner.with_namespace(namespace);
+            }
+            Arc::new(runner)
+        }
+        (_, _, Some(pod)) => {
+            let mut runner = KubernetesRunner::new(pod);
+            if let Some(namespace) = args.pod_namespace {
+                runner = runner.with_namespace(namespace);
+            }
+            if let Some(container) = args.pod_container {
+                runner = runner.with_container(container);
+            }
+            if let Some(kubeconfig) = args.kubeconfig {
+                runner = runner.with_kubeconfig(kubeconfig);
+            }
+            if let Some(context) = args.kube_context {
+                runner = runner.with_context(context);
+            }
+            Arc::new(runner)
+        }
+        _ => host,
+    };
+    let credentials = args
+        .repository_credentials
+        .map(|path| {
+            RepositoryCredentials::from_file(&path).with_context(|| {
+                format!("failed to load repository credentials {}", path.display())
+            })
         })
-        .await;
+        .transpose()?;
+    let offline = (!args.offline_mirrors.is_empty()).then(|| {
+        let mut offline = OfflineMirrors::new();
+        for spec in &args.offline_mirrors {
+            offline.push_spec(spec);
+        }
+        offline
+    });
+    if let Some(offline) = &offline {
+        // Services the server reaches itself must be internal too
+        for (flag, url) in [
+            ("--apk-packages-site", &args.apk_packages_site),
+            ("--apt-snapshot-archive", &args.apt_snapshot_archive),
+            ("--osv-api", &args.osv_api),
+            ("--repology-api", &args.repology_api),
+        ] {
+            if let Some(url) = url
+                && !offline.allows(url)
+            {
+                anyhow::bail!(
+                    "the server is offline and {flag} {url} is not on one of its internal mirrors"
+                );
+            }
+        }
+    }
+    let options = BackendOptions {
+        config: args.backend_config,
+        program: args.plugin,
+        args: args.plugin_args,
+        runner,
+        mirrors: args.apk_mirrors,
+        search_repositories: args.apk_search_repositories,
+        older_branches: args.apk_older_branches,
+        index_cache: args.apk_index_cache,
+        native_index: args.apk_native_index,
+        no_scripts: args.apk_no_scripts,
+        packages_site: args.apk_packages_site,
+        packages_branch: args.apk_packages_branch,
+        snapshot_archive: args.apt_snapshot_archive,
+        snapshot_suite: args.apt_snapshot_suite,
+        install_recommends: args.install_recommends,
+        credentials,
+        offline: offline.clone(),
+        index_cache_max_age: args
+            .apk_index_cache_max_age
+            .map(|minutes| std::time::Duration::from_secs(minutes * 60)),
+        root: args.root,
+    };
+    let registry = BackendRegistry::with_builtins();
+    let backend = registry.create(&args.backend, options.clone()).await?;
+    tracing::info!("Using {} backend for {}", backend.name(), backend.os_name());
+
+    let mut builder = ServerBuilder::new()
+        .shared_backend(backend)
+        .check_privileges();
+    // Targets use the same backend selection, detected on each target for `auto`
+    for target in args.targets {
+        let options = BackendOptions {
+            runner: target.spec.runner(escalation),
+            root: None,
+            ..options.clone()
+        };
+        let backend = registry.create(&args.backend, options).await?;
+        tracing::info!(
+            "Using {} backend for target {}",
+            backend.name(),
+            target.name
+        );
+        builder = match target.spec {
+            TargetSpec::Local => builder.local_target(target.name, backend),
+            _ => builder.target(target.name, backend),
+        };
+    }
+    for url in args.webhook_urls {
+        builder = builder.with_webhook(url);
+    }
+    for url in args.notify_webhooks {
+        builder = builder.with_notification_sink(NotificationSink::new(url));
+    }
+    for url in args.notify_slack {
+        builder = builder.with_notification_sink(NotificationSink::slack(url));
+    }
+    if let Some(token) = args.auth_token {
+        builder = builder.with_auth(token);
+    }
+    for (subject, token) in args.auth_token_subjects {
+        builder = builder.with_auth_subject(subject, token);
+    }
+    let mut identity_headers = IdentityHeaders::new();
+    if let Some(header) = args.client_cert_header {
+        identity_headers = identity_headers.client_certificate(header);
+    }
+    if args.trust_forwarded_user {
+        identity_headers = identity_headers.forwarded_user();
+    }
+    builder = builder.identity_headers(identity_headers);
+    if let Some(arch) = args.arch {
+        builder = builder.default_arch(arch);
+    }
+    if let Some(size) = args.page_size {
+        builder = builder.page_size(size);
+    }
+    if args.record_sessions {
+        builder = builder.record_sessions();
+    }
+    if let Some(url) = args.osv_api {
+        builder = builder.vulnerability_database(OsvClient::with_url(url));
+    }
+    if let Some(url) = args.repology_api {
+        builder = builder.repology(RepologyClient::with_url(url));
+    }
+    if let Some(severity) = args.block_vulnerabilities {
+        let mut gate = VulnerabilityGate::new(severity);
+        if args.block_fixed_vulnerabilities {
+            gate = gate.block_fixed();
+        }
+        if args.allow_vulnerability_override {
+            gate = gate.allow_override();
+        }
+        builder = builder.vulnerability_gate(gate);
+    }
+    if let Some(path) = &args.package_pins {
+        let pins = PackagePins::from_file(path)
+            .with_context(|| format!("failed to load package pins {}", path.display()))?;
+        builder = builder.package_pins(pins);
+    }
+    for pattern in args.denied_licenses {
+        builder = builder.deny_license(pattern);
+    }
+    if args.allow_untrusted_sources {
+        builder = builder.allow_untrusted_sources();
+    }
+    if args.allow_no_scripts {
+        builder = builder.allow_no_scripts();
+    }
+    for entry in args.allowed_repositories {
+        builder = builder.allow_repository(entry);
+    }
+    for (subject, entry) in args.user_allowed_repositories {
+        builder = builder.allow_repository_for(subject, entry);
+    }
+    if let Some(offline) = offline {
+        builder = builder.offline_mirrors(offline);
+    }
+    if args.signed_repositories_only {
+        builder = builder.signed_repositories_only();
+    }
+    if args.skip_disk_space_check {
+        builder = builder.skip_disk_space_check();
+    }
+    if let Some(path) = args.audit_log {
+        let mut log = AuditLog::open(&path)
+            .with_context(|| format!("failed to open audit log {}", path.display()))?;
+        if let Some(key) = args.audit_signing_key {
+            let signer = AuditSigner::load(&key)
+                .with_context(|| format!("failed to load audit signing key {}", key.display()))?;
+            tracing::info!(
+                "Signing the audit log with public key {}",
+                signer.public_key()
+            );
+            log = log.with_signer(signer);
+        }
+        builder = builder.audit_log(log);
+    }
+    if let Some(dir) = args.operation_log_dir {
+        let logs = OperationLogStore::open(&dir)
+            .with_context(|| format!("failed to open operation log directory {}", dir.display()))?
+            .with_max_logs(args.operation_log_max)
+            .with_max_age(
+                (args.operation_log_max_age_secs > 0)
+                    .then(|| std::time::Duration::from_secs(args.operation_log_max_age_secs)),
+            );
+        builder = builder.operation_logs(logs);
+    }
+    let mut quotas = QuotaLimits::new();
+    if let Some(installs) = args.max_installs {
+        quotas = quotas.max_installs(installs);
+    }
+    if let Some(packages) = args.max_removed_packages {
+        quotas = quotas.max_removed_packages(packages);
+    }
+    if let Some(bytes) = args.max_downloaded_bytes {
+        quotas = quotas.max_downloaded_bytes(bytes);
+    }
+    if let Some(secs) = args.quota_window_secs {
+        quotas = quotas.per_window(std::time::Duration::from_secs(secs));
+    }
+    builder = builder.quotas(quotas);
+    if let Some(url) = args.approval_webhook {
+        let mut webhook = ApprovalWebhook::new(url)
+            .with_timeout(std::time::Duration::from_secs(args.approval_timeout_secs));
+        if args.approval_fail_open {
+            webhook = webhook.fail_open();
+        }
+        builder = builder.approval_webhook(webhook);
+    }
+    if args.batch_window_ms > 0 {
+        builder = builder.batch_installs(std::time::Duration::from_millis(args.batch_window_ms));
+    }
+    builder = builder.watch_interval(std::time::Duration::from_millis(
+        args.watch_interval_ms.max(1),
+    ));
+    if args.compress_responses {
+        builder = builder.compress_responses();
+    }
+    if args.search_cache_ttl > 0 {
+        let mut cache = SearchCache::new(std::time::Duration::from_secs(args.search_cache_ttl));
+        if let Some(path) = args.search_cache_file {
+            cache = cache.persist_to(path);
+        }
+        builder = builder.search_cache(cache);
+    }
+    if args.installed_cache_ttl > 0 {
+        builder = builder.installed_cache(std::time::Duration::from_secs(args.installed_cache_ttl));
+    }
+    let auto_refresh = AutoRefresh::new().with_min_interval(std::time::Duration::from_secs(
+        args.auto_refresh_min_interval_secs,
+    ));
+    if let Some(max_age) = args.index_max_age_secs {
+        builder = builder
+            .auto_refresh(auto_refresh.with_max_age(std::time::Duration::from_secs(max_age)));
+    } else if args.auto_refresh {
+        builder = builder.auto_refresh(auto_refresh);
+    }
+    builder = builder.idempotency_ttl(std::time::Duration::from_secs(args.idempotency_ttl));
+    if args.disable_jobs {
+        builder = builder.disable_jobs();
+    }
+    let mut tool_names = ToolNames::new().with_prefix(args.tool_prefix);
+    for (name, alias) in args.tool_aliases {
+        tool_names = tool_names.with_alias(name, alias);
+    }
+    builder = builder.tool_names(tool_names);
+    if let Some(path) = &args.descriptions {
+        let overrides = DescriptionOverrides::from_file(path)
+            .with_context(|| format!("failed to load descriptions {}", path.display()))?;
+        builder = builder.descriptions(overrides);
+    }
+
+    builder
+        .bind(&format!("{}:{}", args.host, args.port))
+        .await?
+        .serve()
+        .await?;
 
     Ok(())
 }
+
+/// Split a `SUBJECT=VALUE` argument
+fn parse_assignment(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((subject, value)) if !subject.is_empty() && !value.is_empty() => {
+            Ok((subject.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected SUBJECT=VALUE, got '{arg}'")),
+    }
+}
@@ -0,0 +1,874 @@
+use std::str::FromStr;
+
+use rmcp::{
+    ErrorData as McpError,
+    model::{CallToolRequestParam, CallToolResult, Content, JsonObject},
+};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::backend::{
+    InstallFlags, InstallOptions, InstallVersionOptions, Recommends, SearchListing, SearchMode,
+    SearchOptions, TargetInstallOptions,
+};
+use crate::lockfile::Lockfile;
+use crate::session::{ExportFormat, ExportScope};
+
+/// Slice of a listing requested by a tool call
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Page {
+    /// Opaque position returned as `next_cursor` by the previous page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    /// Maximum number of entries in the page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl Page {
+    /// Snapshot of the listing and offset in it the cursor points at, as
+    /// `{snapshot}.{offset}`
+    pub fn position(&self) -> Result<Option<(&str, usize)>, McpError> {
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+        cursor
+            .split_once('.')
+            .and_then(|(snapshot, offset)| Some((snapshot, offset.parse().ok()?)))
+            .filter(|(snapshot, _)| !snapshot.is_empty())
+            .map(Some)
+            .ok_or_else(|| invalid_cursor(cursor))
+    }
+
+    /// Entries of `items` in this page, starting at `start`, and the offset
+    /// of the next page if any entries remain
+    pub fn slice<'a, T>(
+        &self,
+        items: &'a [T],
+        start: usize,
+    ) -> Result<(&'a [T], Option<usize>), McpError> {
+        if start > items.len() {
+            return Err(invalid_cursor(self.cursor.as_deref().unwrap_or_default()));
+        }
+        let end = match self.limit {
+            Some(0) => {
+                return Err(McpError::invalid_params("limit must be at least 1", None));
+            }
+            Some(limit) => start.saturating_add(limit).min(items.len()),
+            None => items.len(),
+        };
+        Ok((&items[start..end], (end < items.len()).then_some(end)))
+    }
+}
+
+fn invalid_cursor(cursor: &str) -> McpError {
+    McpError::invalid_params(
+        format!("invalid cursor '{cursor}', pass a next_cursor from a previous page"),
+        None,
+    )
+}
+
+/// Longest summary returned by the `summary` output format
+const MAX_SUMMARY_LENGTH: usize = 200;
+
+/// Shape of a tool result, chosen with the `output_format` parameter every
+/// tool takes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// The human-readable text and the JSON content, as each tool renders them
+    #[default]
+    Text,
+    /// Only the JSON content
+    Json,
+    /// One short sentence
+    Summary,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "summary" => Ok(Self::Summary),
+            _ => Err(format!(
+                "unknown output_format '{format}', expected text, json or summary"
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Format requested by a tool call, `text` when not given
+    pub fn from_request(request: &CallToolRequestParam) -> Result<Self, McpError> {
+        match request
+            .arguments
+            .as_ref()
+            .and_then(|arguments| arguments.get("output_format"))
+        {
+            Some(Value::String(format)) => format
+                .parse()
+                .map_err(|err: String| McpError::invalid_params(err, None)),
+            Some(_) => Err(McpError::invalid_params(
+                "output_format must be a string",
+                None,
+            )),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Reshape a tool's result into this format
+    ///
+    /// Results without JSON content are returned as `{"message": ...}` in the
+    /// `json` format.
+    pub fn apply(self, mut result: CallToolResult) -> CallToolResult {
+        let (json, texts) = split_content(&result);
+        result.content = match self {
+            Self::Text => return result,
+            Self::Json if json.is_empty() => vec![Content::text(
+                serde_json::json!({ "message": texts.join("\n") }).to_string(),
+            )],
+            Self::Json => json
+                .iter()
+                .map(|value| Content::text(value.to_string()))
+                .collect(),
+            Self::Summary => vec![Content::text(summarize(&json, &texts))],
+        };
+        result
+    }
+}
+
+/// JSON values and plain texts of a result's text content
+fn split_content(result: &CallToolResult) -> (Vec<Value>, Vec<&str>) {
+    let mut json = Vec::new();
+    let mut texts = Vec::new();
+    for text in result
+        .content
+        .iter()
+        .filter_map(|content| content.as_text())
+    {
+        match serde_json::from_str::<Value>(&text.text) {
+            Ok(value @ (Value::Object(_) | Value::Array(_))) => json.push(value),
+            _ => texts.push(text.text.as_str()),
+        }
+    }
+    (json, texts)
+}
+
+/// One sentence describing a result: the first line of its text, with the
+/// number of entries for listings, or the size of documents
+fn summarize(json: &[Value], texts: &[&str]) -> String {
+    let entries = json.iter().find_map(Value::as_array).map(Vec::len);
+    let summary = match texts.first().map(|text| text.trim()) {
+        Some(text) if !text.contains('\n') => text.to_string(),
+        Some(text) => {
+            let first_line = text.lines().next().unwrap_or_default().trim_end();
+            match (first_line.strip_suffix(':'), entries) {
+                (Some(heading), Some(entries)) => format!("{heading}: {entries} entries"),
+                _ => format!(
+                    "Returned a document of {} lines, use the text output_format to read it",
+                    text.lines().count()
+                ),
+            }
+        }
+        None => match entries {
+            Some(entries) => format!("Returned {entries} entries"),
+            None => "Returned a JSON result, use the json output_format to read it".to_string(),
+        },
+    };
+    match summary.char_indices().nth(MAX_SUMMARY_LENGTH) {
+        Some((end, _)) => format!("{}…", &summary[..end]),
+        None => summary,
+    }
+}
+
+/// Package operation requested by a tool call
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Operation {
+    Install(InstallOptions),
+    InstallVersion(InstallVersionOptions),
+    /// Install several packages at the given versions in one transaction
+    InstallVersions {
+        packages: Vec<InstallVersionOptions>,
+    },
+    /// Find the version an install of `package` at `version` would pick
+    ResolveVersion(InstallVersionOptions),
+    Search {
+        #[serde(flatten)]
+        options: SearchOptions,
+        #[serde(flatten)]
+        listing: SearchListing,
+    },
+    ListInstalled(Page),
+    RefreshRepositories,
+    ListRepositories,
+    ListSearchRepositories,
+    CheckPrivileges,
+    /// Describe the backend, system, repositories, privileges and policies
+    ServerInfo,
+    /// Check the environment installs depend on
+    Doctor,
+    InstallOnTargets(TargetInstallOptions),
+    ExportSession {
+        format: ExportFormat,
+    },
+    /// Render the session's installs as Dockerfile instructions
+    ExportDockerfileSnippet,
+    /// Render the session's installs as cloud-init user data
+    ExportCloudInit,
+    /// Describe the packages of `scope` as an apko configuration, the
+    /// session's when recording and the installed ones otherwise if not given
+    ExportApkoConfig {
+        scope: Option<ExportScope>,
+    },
+    /// Look up advisories of the given `name` or `name=version` entries,
+    /// every installed package when empty
+    CheckVulnerabilities {
+        packages: Vec<String>,
+    },
+    /// Look up the packages of a project in the repositories of `distros`,
+    /// the major distributions when empty
+    SearchAllDistros {
+        project: String,
+        distros: Vec<String>,
+    },
+    /// Report the licenses of the installed packages, flagging those
+    /// matching `deny` as well as the handler's deny-list
+    LicenseReport {
+        deny: Vec<String>,
+    },
+    GenerateLockfile,
+    InstallFromLockfile(Lockfile),
+    /// Export the audit log, from the entry with sequence number
+    /// `from_sequence` if given, after verifying its chain
+    ExportAuditLog {
+        from_sequence: Option<u64>,
+    },
+    /// Export the audit log as flat history records in JSON Lines, from the
+    /// entry with sequence number `from_sequence` if given
+    ExportHistory {
+        from_sequence: Option<u64>,
+    },
+    /// Report the state of a background job, with its result once it
+    /// succeeded
+    GetJobStatus {
+        job_id: String,
+    },
+    /// Read the output of a background job from the line `offset`
+    GetJobOutput {
+        job_id: String,
+        offset: usize,
+        limit: Option<usize>,
+    },
+    /// Stop a running background job
+    CancelJob {
+        job_id: String,
+    },
+    /// Read the kept output of a mutating operation from the line `offset`
+    GetOperationLog {
+        operation_id: String,
+        offset: usize,
+        limit: Option<usize>,
+    },
+}
+
+impl Operation {
+    /// Parse the operation from a tool call, returning `None` for unknown tools
+    pub fn from_request(request: &CallToolRequestParam) -> Result<Option<Self>, McpError> {
+        let arguments = request.arguments.as_ref();
+
+        let operation = match request.name.as_ref() {
+            "install_package" => Self::Install(InstallOptions {
+                package: required_str(arguments, "package_name")?,
+                repository: optional_str(arguments, "repository"),
+                repositories: optional_str_array(arguments, "repositories")?,
+                only_repositories: only_repositories(arguments)?,
+                arch: optional_str(arguments, "arch"),
+                preseed: optional_str_array(arguments, "preseed")?,
+                install_recommends: install_recommends(arguments)?,
+                target_release: optional_str(arguments, "target_release"),
+                flags: install_flags(arguments)?,
+            }),
+            "install_package_with_version" => match arguments.and_then(|args| args.get("packages"))
+            {
+                Some(packages) => {
+                    if ["package_name", "version"]
+                        .iter()
+                        .any(|name| arguments.is_some_and(|args| args.contains_key(*name)))
+                    {
+                        return Err(McpError::invalid_params(
+                            "pass either packages or package_name and version, not both",
+                            None,
+                        ));
+                    }
+                    Self::InstallVersions {
+                        packages: version_pairs(
+                            packages,
+                            optional_str(arguments, "arch"),
+                            optional_str_array(arguments, "preseed")?,
+                            install_recommends(arguments)?,
+                            optional_str(arguments, "target_release"),
+                            install_flags(arguments)?,
+                        )?,
+                    }
+                }
+                None => Self::InstallVersion(InstallVersionOptions {
+                    package: required_str(arguments, "package_name")?,
+                    version: required_str(arguments, "version")?,
+                    arch: optional_str(arguments, "arch"),
+                    preseed: optional_str_array(arguments, "preseed")?,
+                    install_recommends: install_recommends(arguments)?,
+                    target_release: optional_str(arguments, "target_release"),
+                    flags: install_flags(arguments)?,
+                }),
+            },
+            "resolve_version" => Self::ResolveVersion(InstallVersionOptions {
+                package: required_str(arguments, "package_name")?,
+                version: required_str(arguments, "version")?,
+                arch: optional_str(arguments, "arch"),
+                preseed: Vec::new(),
+                install_recommends: None,
+                target_release: optional_str(arguments, "target_release"),
+                flags: InstallFlags::default(),
+            }),
+            "search_package" => Self::Search {
+                options: SearchOptions {
+                    query: required_str(arguments, "query")?,
+                    repository: optional_str(arguments, "repository"),
+                    repositories: optional_str_array(arguments, "repositories")?,
+                    only_repositories: only_repositories(arguments)?,
+                    arch: optional_str(arguments, "arch"),
+                    mode: search_mode(arguments)?,
+                },
+                listing: SearchListing {
+                    limit: optional_usize(arguments, "limit")?,
+                    sort: optional_str(arguments, "sort")
+                        .map(|sort| sort.parse())
+                        .transpose()
+                        .map_err(|err: String| McpError::invalid_params(err, None))?,
+                    branch: optional_str(arguments, "branch")
+                        .or_else(|| optional_str(arguments, "suite")),
+                },
+            },
+            "list_installed_packages" => Self::ListInstalled(Page {
+                cursor: optional_str(arguments, "cursor"),
+                limit: optional_usize(arguments, "limit")?,
+            }),
+            "refresh_repositories" => Self::RefreshRepositories,
+            "list_repositories" => Self::ListRepositories,
+            "list_search_repositories" => Self::ListSearchRepositories,
+            "check_privileges" => Self::CheckPrivileges,
+            "server_info" => Self::ServerInfo,
+            "doctor" => Self::Doctor,
+            "install_package_on_targets" => Self::InstallOnTargets(TargetInstallOptions {
+                package: required_str(arguments, "package_name")?,
+                version: optional_str(arguments, "version"),
+                arch: optional_str(arguments, "arch"),
+                targets: optional_str_array(arguments, "targets")?,
+            }),
+            "export_session" => Self::ExportSession {
+                format: match optional_str(arguments, "format") {
+                    Some(format) => format
+                        .parse()
+                        .map_err(|err: String| McpError::invalid_params(err, None))?,
+                    None => ExportFormat::default(),
+                },
+            },
+            "export_dockerfile_snippet" => Self::ExportDockerfileSnippet,
+            "export_cloud_init" => Self::ExportCloudInit,
+            "export_apko_config" => Self::ExportApkoConfig {
+                scope: optional_str(arguments, "scope")
+                    .map(|scope| scope.parse())
+                    .transpose()
+                    .map_err(|err: String| McpError::invalid_params(err, None))?,
+            },
+            "check_vulnerabilities" => Self::CheckVulnerabilities {
+                packages: optional_str_array(arguments, "packages")?,
+            },
+            "search_all_distros" => Self::SearchAllDistros {
+                project: required_str(arguments, "project")?,
+                distros: optional_str_array(arguments, "distros")?,
+            },
+            "license_report" => Self::LicenseReport {
+                deny: optional_str_array(arguments, "deny")?,
+            },
+            "generate_lockfile" => Self::GenerateLockfile,
+            "install_from_lockfile" => {
+                let lockfile =
+                    arguments
+                        .and_then(|args| args.get("lockfile"))
+                        .ok_or_else(|| {
+                            McpError::invalid_params("missing required parameter: lockfile", None)
+                        })?;
+                Self::InstallFromLockfile(
+                    Lockfile::from_value(lockfile)
+                        .map_err(|err| McpError::invalid_params(err, None))?,
+                )
+            }
+            "get_job_status" => Self::GetJobStatus {
+                job_id: required_str(arguments, "job_id")?,
+            },
+            "get_job_output" => Self::GetJobOutput {
+                job_id: required_str(arguments, "job_id")?,
+                offset: optional_usize(arguments, "offset")?.unwrap_or_default(),
+                limit: optional_usize(arguments, "limit")?,
+            },
+            "cancel_job" => Self::CancelJob {
+                job_id: required_str(arguments, "job_id")?,
+            },
+            "get_operation_log" => Self::GetOperationLog {
+                operation_id: required_str(arguments, "operation_id")?,
+                offset: optional_usize(arguments, "offset")?.unwrap_or_default(),
+                limit: optional_usize(arguments, "limit")?,
+            },
+            "export_audit_log" => Self::ExportAuditLog {
+                from_sequence: optional_usize(arguments, "from_sequence")?.map(|from| from as u64),
+            },
+            "export_history" => Self::ExportHistory {
+                from_sequence: optional_usize(arguments, "from_sequence")?.map(|from| from as u64),
+            },
+            _ => return Ok(None),
+        };
+
+        Ok(Some(operation))
+    }
+
+    /// Name of the tool performing the operation
+    pub fn tool_name(&self) -> &'static str {
+        match self {
+            Self::Install(_) => "install_package",
+            Self::InstallVersion(_) | Self::InstallVersions { .. } => {
+                "install_package_with_version"
+            }
+            Self::ResolveVersion(_) => "resolve_version",
+            Self::Search { .. } => "search_package",
+            Self::ListInstalled(_) => "list_installed_packages",
+            Self::RefreshRepositories => "refresh_repositories",
+            Self::ListRepositories => "list_repositories",
+            Self::ListSearchRepositories => "list_search_repositories",
+            Self::CheckPrivileges => "check_privileges",
+            Self::ServerInfo => "server_info",
+            Self::Doctor => "doctor",
+            Self::InstallOnTargets(_) => "install_package_on_targets",
+            Self::ExportSession { .. } => "export_session",
+            Self::ExportDockerfileSnippet => "export_dockerfile_snippet",
+            Self::ExportCloudInit => "export_cloud_init",
+            Self::ExportApkoConfig { .. } => "export_apko_config",
+            Self::CheckVulnerabilities { .. } => "check_vulnerabilities",
+            Self::SearchAllDistros { .. } => "search_all_distros",
+            Self::LicenseReport { .. } => "license_report",
+            Self::GenerateLockfile => "generate_lockfile",
+            Self::InstallFromLockfile(_) => "install_from_lockfile",
+            Self::ExportAuditLog { .. } => "export_audit_log",
+            Self::ExportHistory { .. } => "export_history",
+            Self::GetJobStatus { .. } => "get_job_status",
+            Self::GetJobOutput { .. } => "get_job_output",
+            Self::CancelJob { .. } => "cancel_job",
+            Self::GetOperationLog { .. } => "get_operation_log",
+        }
+    }
+
+    /// Architecture the operation targets, when it selects one
+    pub fn arch(&self) -> Option<&str> {
+        match self {
+            Self::Install(options) => options.arch.as_deref(),
+            Self::InstallVersion(options) => options.arch.as_deref(),
+            Self::InstallVersions { packages } => {
+                packages.iter().find_map(|options| options.arch.as_deref())
+            }
+            Self::ResolveVersion(options) => options.arch.as_deref(),
+            Self::Search { options, .. } => options.arch.as_deref(),
+            Self::InstallOnTargets(options) => options.arch.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Target the architecture unless the call selected one
+    pub(crate) fn set_default_arch(&mut self, arch: &str) {
+        let options_arch = match self {
+            Self::Install(options) => &mut options.arch,
+            Self::InstallVersion(options) => &mut options.arch,
+            Self::ResolveVersion(options) => &mut options.arch,
+            Self::Search { options, .. } => &mut options.arch,
+            Self::InstallVersions { packages } => {
+                for options in packages {
+                    options.arch.get_or_insert_with(|| arch.to_string());
+                }
+                return;
+            }
+            _ => return,
+        };
+        options_arch.get_or_insert_with(|| arch.to_string());
+    }
+
+    /// Page every listing returns unless the call passes a `limit`
+    pub(crate) fn set_default_page_size(&mut self, size: usize) {
+        if let Self::ListInstalled(page) = self {
+            page.limit.get_or_insert(size);
+        }
+    }
+
+    /// Every tool of the server, in alphabetical order
+    pub const TOOL_NAMES: &[&str] = &[
+        "cancel_job",
+        "check_privileges",
+        "check_vulnerabilities",
+        "doctor",
+        "export_apko_config",
+        "export_audit_log",
+        "export_cloud_init",
+        "export_dockerfile_snippet",
+        "export_history",
+        "export_session",
+        "generate_lockfile",
+        "get_job_output",
+        "get_job_status",
+        "get_operation_log",
+        "install_from_lockfile",
+        "install_package",
+        "install_package_on_targets",
+        "install_package_with_version",
+        "license_report",
+        "list_installed_packages",
+        "list_repositories",
+        "list_search_repositories",
+        "refresh_repositories",
+        "resolve_version",
+        "search_all_distros",
+        "search_package",
+        "server_info",
+    ];
+
+    /// Whether the operation modifies the system
+    pub fn is_mutating(&self) -> bool {
+        Self::is_mutating_tool(self.tool_name())
+    }
+
+    /// Whether the operations of the tool named `tool_name` modify the
+    /// system, the [`Self::is_mutating`] of each of them
+    pub fn is_mutating_tool(tool_name: &str) -> bool {
+        matches!(
+            tool_name,
+            "install_package"
+                | "install_package_with_version"
+                | "refresh_repositories"
+                | "install_package_on_targets"
+                | "install_from_lockfile"
+        )
+    }
+
+    /// Whether the operation downloads from repositories
+    pub fn uses_repositories(&self) -> bool {
+        matches!(
+            self,
+            Self::Install(_)
+                | Self::InstallVersion(_)
+                | Self::InstallVersions { .. }
+                | Self::ResolveVersion(_)
+                | Self::Search { .. }
+                | Self::RefreshRepositories
+                | Self::InstallOnTargets(_)
+                | Self::InstallFromLockfile(_)
+        )
+    }
+
+    /// Custom repository the operation uses, the first one for operations
+    /// using several
+    pub fn repository(&self) -> Option<&str> {
+        self.repositories().into_iter().next()
+    }
+
+    /// Custom repositories the operation uses
+    pub fn repositories(&self) -> Vec<&str> {
+        match self {
+            Self::Install(options) => options
+                .added_repositories()
+                .into_iter()
+                .chain(options.only_repositories.iter().map(String::as_str))
+                .collect(),
+            Self::Search { options, .. } => options
+                .added_repositories()
+                .into_iter()
+                .chain(options.only_repositories.iter().map(String::as_str))
+                .collect(),
+            Self::InstallFromLockfile(lockfile) => {
+                let mut repositories = Vec::new();
+                for repository in lockfile
+                    .packages
+                    .iter()
+                    .flat_map(|package| package.package.custom_repositories())
+                {
+                    if !repositories.contains(&repository) {
+                        repositories.push(repository);
+                    }
+                }
+                repositories
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether the operation adds a list of repositories to the configured
+    /// ones
+    pub fn lists_repositories(&self) -> bool {
+        match self {
+            Self::Install(options) => !options.repositories.is_empty(),
+            Self::Search { options, .. } => !options.repositories.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Whether the operation replaces the configured repositories with its
+    /// own
+    pub fn overrides_repositories(&self) -> bool {
+        match self {
+            Self::Install(options) => !options.only_repositories.is_empty(),
+            Self::Search { options, .. } => !options.only_repositories.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// debconf selections the operation sets before installing, each once
+    pub fn preseed(&self) -> Vec<String> {
+        let entries: Vec<&String> = match self {
+            Self::Install(options) => options.preseed.iter().collect(),
+            Self::InstallVersion(options) => options.preseed.iter().collect(),
+            Self::InstallVersions { packages } => packages
+                .iter()
+                .flat_map(|options| &options.preseed)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut selections: Vec<String> = Vec::new();
+        for entry in entries {
+            if !selections.contains(entry) {
+                selections.push(entry.clone());
+            }
+        }
+        selections
+    }
+
+    /// Weaker dependencies the operation installs, when the call chose
+    pub fn install_recommends(&self) -> Option<Recommends> {
+        match self {
+            Self::Install(options) => options.install_recommends,
+            Self::InstallVersion(options) => options.install_recommends,
+            Self::InstallVersions { packages } => packages
+                .iter()
+                .find_map(|options| options.install_recommends),
+            _ => None,
+        }
+    }
+
+    /// Release the operation's installs prefer, when the call chose one
+    pub fn target_release(&self) -> Option<&str> {
+        match self {
+            Self::Install(options) => options.target_release.as_deref(),
+            Self::InstallVersion(options) | Self::ResolveVersion(options) => {
+                options.target_release.as_deref()
+            }
+            Self::InstallVersions { packages } => packages
+                .iter()
+                .find_map(|options| options.target_release.as_deref()),
+            _ => None,
+        }
+    }
+
+    /// Script and cache options of the operation's installs
+    pub fn install_flags(&self) -> InstallFlags {
+        match self {
+            Self::Install(options) => options.flags,
+            Self::InstallVersion(options) => options.flags,
+            Self::InstallVersions { packages } => packages
+                .first()
+                .map(|options| options.flags)
+                .unwrap_or_default(),
+            _ => InstallFlags::default(),
+        }
+    }
+
+    /// Package file, URL or custom repository the operation installs from,
+    /// bypassing the configured repositories and their signatures
+    pub fn untrusted_source(&self) -> Option<&str> {
+        self.untrusted_sources().first().copied()
+    }
+
+    /// Package file or URL the operation installs, or else every custom
+    /// repository it installs from
+    pub fn untrusted_sources(&self) -> Vec<&str> {
+        let package = match self {
+            Self::Install(options) => &options.package,
+            Self::InstallOnTargets(options) => &options.package,
+            Self::InstallFromLockfile(_) => return self.repositories(),
+            _ => return Vec::new(),
+        };
+        if is_package_file(package) {
+            vec![package]
+        } else if matches!(self, Self::Install(_)) {
+            self.repositories()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Whether `package` names a package file or URL rather than a package
+/// (`./tool.apk`, `/tmp/tool_1.0_amd64.deb`, `https://example.com/tool.apk`)
+///
+/// APT's `package/release` selection is not mistaken for a path.
+fn is_package_file(package: &str) -> bool {
+    package.starts_with('/')
+        || package.starts_with("./")
+        || package.starts_with("../")
+        || package.starts_with('~')
+        || package.contains("://")
+        || package.ends_with(".apk")
+        || package.ends_with(".deb")
+}
+
+fn required_str(arguments: Option<&JsonObject>, name: &str) -> Result<String, McpError> {
+    optional_str(arguments, name).ok_or_else(|| {
+        McpError::invalid_params(format!("missing required parameter: {name}"), None)
+    })
+}
+
+fn optional_str_array(arguments: Option<&JsonObject>, name: &str) -> Result<Vec<String>, McpError> {
+    let Some(value) = arguments.and_then(|args| args.get(name)) else {
+        return Ok(Vec::new());
+    };
+    value
+        .as_array()
+        .and_then(|values| {
+            values
+                .iter()
+                .map(|value| value.as_str().map(str::to_string))
+                .collect()
+        })
+        .ok_or_else(|| {
+            McpError::invalid_params(format!("{name} must be an array of strings"), None)
+        })
+}
+
+/// `{package, version}` pairs of a versioned install, installed for `arch`
+/// unless they name their own architecture, each with the call's `preseed`,
+/// `install_recommends`, `target_release` and flags
+fn version_pairs(
+    packages: &Value,
+    arch: Option<String>,
+    preseed: Vec<String>,
+    install_recommends: Option<Recommends>,
+    target_release: Option<String>,
+    flags: InstallFlags,
+) -> Result<Vec<InstallVersionOptions>, McpError> {
+    let invalid = || {
+        McpError::invalid_params(
+            "packages must be a non-empty array of {package, version} objects",
+            None,
+        )
+    };
+    let packages = packages
+        .as_array()
+        .filter(|packages| !packages.is_empty())
+        .ok_or_else(invalid)?;
+    packages
+        .iter()
+        .map(|pair| {
+            let text = |name: &str| pair.get(name).and_then(Value::as_str).map(str::to_string);
+            Ok(InstallVersionOptions {
+                package: text("package").ok_or_else(invalid)?,
+                version: text("version").ok_or_else(invalid)?,
+                arch: text("arch").or_else(|| arch.clone()),
+                preseed: preseed.clone(),
+                install_recommends,
+                target_release: target_release.clone(),
+                flags,
+            })
+        })
+        .collect()
+}
+
+/// Repositories replacing the configured ones, which the added
+/// `repository` and `repositories` cannot be combined with
+fn only_repositories(arguments: Option<&JsonObject>) -> Result<Vec<String>, McpError> {
+    let repositories = optional_str_array(arguments, "only_repositories")?;
+    if !repositories.is_empty()
+        && (optional_str(arguments, "repository").is_some()
+            || !optional_str_array(arguments, "repositories")?.is_empty())
+    {
+        return Err(McpError::invalid_params(
+            "pass either repository and repositories or only_repositories, not both",
+            None,
+        ));
+    }
+    Ok(repositories)
+}
+
+/// Weaker dependencies an install adds, given by `install_recommends`
+fn install_recommends(arguments: Option<&JsonObject>) -> Result<Option<Recommends>, McpError> {
+    optional_str(arguments, "install_recommends")
+        .map(|recommends| recommends.parse::<Recommends>())
+        .transpose()
+        .map_err(|err| McpError::invalid_params(err, None))
+}
+
+/// Script and cache options of an install
+fn install_flags(arguments: Option<&JsonObject>) -> Result<InstallFlags, McpError> {
+    Ok(InstallFlags {
+        no_scripts: optional_bool(arguments, "no_scripts")?.unwrap_or(false),
+        no_cache: optional_bool(arguments, "no_cache")?.unwrap_or(false),
+        update_cache: optional_bool(arguments, "update_cache")?.unwrap_or(false),
+    })
+}
+
+/// Mode of a search, given by `mode` or by `search_descriptions`
+fn search_mode(arguments: Option<&JsonObject>) -> Result<SearchMode, McpError> {
+    let mode = optional_str(arguments, "mode")
+        .map(|mode| mode.parse::<SearchMode>())
+        .transpose()
+        .map_err(|err| McpError::invalid_params(err, None))?;
+    let descriptions = optional_bool(arguments, "search_descriptions")?;
+    match (mode, descriptions) {
+        (Some(mode), Some(descriptions)) if descriptions != (mode == SearchMode::Description) => {
+            Err(McpError::invalid_params(
+                "search_descriptions contradicts mode; give only one of them",
+                None,
+            ))
+        }
+        (Some(mode), _) => Ok(mode),
+        (None, Some(true)) => Ok(SearchMode::Description),
+        (None, _) => Ok(SearchMode::default()),
+    }
+}
+
+fn optional_bool(arguments: Option<&JsonObject>, name: &str) -> Result<Option<bool>, McpError> {
+    let Some(value) = arguments.and_then(|args| args.get(name)) else {
+        return Ok(None);
+    };
+    value
+        .as_bool()
+        .map(Some)
+        .ok_or_else(|| McpError::invalid_params(format!("{name} must be a boolean"), None))
+}
+
+fn optional_usize(arguments: Option<&JsonObject>, name: &str) -> Result<Option<usize>, McpError> {
+    let Some(value) = arguments.and_then(|args| args.get(name)) else {
+        return Ok(None);
+    };
+    value
+        .as_u64()
+        .and_then(|value| usize::try_from(value).ok())
+        .map(Some)
+        .ok_or_else(|| {
+            McpError::invalid_params(format!("{name} must be a non-negative integer"), None)
+        })
+}
+
+fn optional_str(arguments: Option<&JsonObject>, name: &str) -> Option<String> {
+    arguments
+        .and_then(|args| args.get(name))
+        .and_then(|value| value.as_str())
+        .map(|value| value.to_string())
+}
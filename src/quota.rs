@@ -0,0 +1,301 @@
+//! Per-session quotas on mutating operations
+//!
+//! An agent stuck in a loop can install, upgrade and replace packages far
+//! faster than anyone notices. [`QuotaLimits`] bound what each MCP session
+//! may do: how many packages it installs, how many packages its installs
+//! remove and how many bytes they download. Limits apply to the whole session,
+//! or to a sliding time window when one is set.
+//!
+//! Usage is charged once an install succeeds, from the outcome reported by the
+//! package manager, so a single install may go over a limit; the calls after
+//! it are refused with a `quota_exceeded` error telling when the window frees
+//! up. Installs requesting more packages than the installs quota has left are
+//! refused before running.
+//!
+//! Checking an operation reserves what it needs under the same lock, so
+//! concurrent operations of a session cannot all pass on the last unit of a
+//! quota. Charges use up the reservations, and what is left of one is
+//! released when its operation ends.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::backend::InstallOutcome;
+use crate::error::PackageManagerError;
+
+/// Limits on what a session may change, unlimited by default
+#[derive(Clone, Debug, Default)]
+pub struct QuotaLimits {
+    installs: Option<u64>,
+    removed_packages: Option<u64>,
+    downloaded_bytes: Option<u64>,
+    window: Option<Duration>,
+}
+
+impl QuotaLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Packages a session may install, upgrade or downgrade: one per
+    /// install and per target, and every package of an installed lockfile
+    pub fn max_installs(mut self, installs: u64) -> Self {
+        self.installs = Some(installs);
+        self
+    }
+
+    /// Packages a session's installs may remove, e.g. conflicting packages
+    pub fn max_removed_packages(mut self, packages: u64) -> Self {
+        self.removed_packages = Some(packages);
+        self
+    }
+
+    /// Bytes a session's installs may download, as reported by the package
+    /// manager
+    pub fn max_downloaded_bytes(mut self, bytes: u64) -> Self {
+        self.downloaded_bytes = Some(bytes);
+        self
+    }
+
+    /// Apply the limits to the usage of the last `window` instead of the
+    /// whole session
+    pub fn per_window(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.installs.is_none()
+            && self.removed_packages.is_none()
+            && self.downloaded_bytes.is_none()
+    }
+}
+
+/// Quota a session ran out of
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKind {
+    Installs,
+    RemovedPackages,
+    DownloadedBytes,
+}
+
+impl fmt::Display for QuotaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Installs => "installs",
+            Self::RemovedPackages => "removed packages",
+            Self::DownloadedBytes => "downloaded bytes",
+        })
+    }
+}
+
+/// Usage charged by one successful operation
+#[derive(Clone, Copy, Debug)]
+struct Charge {
+    at: DateTime<Utc>,
+    installs: u64,
+    removed_packages: u64,
+    downloaded_bytes: u64,
+}
+
+impl Charge {
+    fn get(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::Installs => self.installs,
+            QuotaKind::RemovedPackages => self.removed_packages,
+            QuotaKind::DownloadedBytes => self.downloaded_bytes,
+        }
+    }
+}
+
+/// Usage set aside for a running operation
+#[derive(Clone, Copy, Debug)]
+struct Reserved {
+    id: u64,
+    installs: u64,
+    /// Whether the unit of the other quotas every operation needs is still
+    /// reserved, until the operation's first charge
+    minimum: bool,
+}
+
+impl Reserved {
+    fn get(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::Installs => self.installs,
+            _ => u64::from(self.minimum),
+        }
+    }
+}
+
+/// Charges and reservations of a session
+#[derive(Debug, Default)]
+struct Usage {
+    charges: VecDeque<Charge>,
+    reserved: Vec<Reserved>,
+    next_id: u64,
+}
+
+/// Usage of one session against its limits
+#[derive(Debug)]
+pub(crate) struct QuotaUsage {
+    limits: QuotaLimits,
+    usage: Mutex<Usage>,
+}
+
+/// Usage reserved by [`QuotaUsage::reserve`], released when dropped
+#[derive(Debug)]
+pub(crate) struct QuotaReservation {
+    usage: Arc<QuotaUsage>,
+    id: u64,
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        self.usage
+            .lock_usage()
+            .reserved
+            .retain(|reserved| reserved.id != self.id);
+    }
+}
+
+impl QuotaUsage {
+    pub(crate) fn new(limits: QuotaLimits) -> Self {
+        Self {
+            limits,
+            usage: Mutex::default(),
+        }
+    }
+
+    /// The same limits with no usage, for a new session
+    pub(crate) fn fresh(&self) -> Self {
+        Self::new(self.limits.clone())
+    }
+
+    /// Refuse an operation installing `installs` packages once a quota is
+    /// used up, or when it would go over the installs quota, and reserve
+    /// what it needs otherwise
+    pub(crate) fn reserve(
+        self: &Arc<Self>,
+        installs: u64,
+    ) -> Result<QuotaReservation, PackageManagerError> {
+        let mut usage = self.lock_usage();
+        let charges = self.current_charges(&mut usage);
+        let used = |kind| {
+            charges
+                .iter()
+                .map(|charge: &Charge| charge.get(kind))
+                .chain(usage.reserved.iter().map(|reserved| reserved.get(kind)))
+                .sum::<u64>()
+        };
+        for (kind, limit) in [
+            (QuotaKind::Installs, self.limits.installs),
+            (QuotaKind::RemovedPackages, self.limits.removed_packages),
+            (QuotaKind::DownloadedBytes, self.limits.downloaded_bytes),
+        ] {
+            let Some(limit) = limit else {
+                continue;
+            };
+            let used = used(kind);
+            // Every operation needs one more unit of each quota left, and
+            // installs one per package
+            let requested = if kind == QuotaKind::Installs {
+                installs.max(1)
+            } else {
+                1
+            };
+            if used + requested > limit {
+                return Err(PackageManagerError::QuotaExceeded {
+                    quota: kind,
+                    limit,
+                    used,
+                    reset_at: limit
+                        .checked_sub(requested)
+                        .and_then(|allowed| self.reset_at(&charges, kind, used, allowed)),
+                });
+            }
+        }
+
+        let id = usage.next_id;
+        usage.next_id += 1;
+        usage.reserved.push(Reserved {
+            id,
+            installs: installs.max(1),
+            minimum: true,
+        });
+        Ok(QuotaReservation {
+            usage: self.clone(),
+            id,
+        })
+    }
+
+    /// Charge the changes of a successful install, using up a reservation
+    pub(crate) fn charge(&self, outcome: &InstallOutcome) {
+        let charge = Charge {
+            at: Utc::now(),
+            installs: 1,
+            removed_packages: outcome.removed.len() as u64,
+            downloaded_bytes: outcome.downloaded_bytes.unwrap_or_default(),
+        };
+        let mut usage = self.lock_usage();
+        // Charges do not name their operation: taking from the oldest
+        // reservation left can only count an install twice until the
+        // operations end, never let one through
+        if let Some(reserved) = usage
+            .reserved
+            .iter_mut()
+            .find(|reserved| reserved.installs > 0)
+        {
+            reserved.installs -= 1;
+            reserved.minimum = false;
+        }
+        usage.charges.push_back(charge);
+    }
+
+    /// Charges within the window, dropping older ones
+    fn current_charges(&self, usage: &mut Usage) -> Vec<Charge> {
+        if let Some(start) = self.window_start() {
+            while usage
+                .charges
+                .front()
+                .is_some_and(|charge| charge.at <= start)
+            {
+                usage.charges.pop_front();
+            }
+        }
+        usage.charges.iter().copied().collect()
+    }
+
+    /// When enough charges leave the window for the `used` units of `kind` to
+    /// fall to `allowed`, `None` for quotas lasting the whole session
+    fn reset_at(
+        &self,
+        charges: &[Charge],
+        kind: QuotaKind,
+        mut used: u64,
+        allowed: u64,
+    ) -> Option<DateTime<Utc>> {
+        let window = chrono::Duration::from_std(self.limits.window?).ok()?;
+        for charge in charges {
+            used -= charge.get(kind);
+            if used <= allowed {
+                return Some(charge.at + window);
+            }
+        }
+        None
+    }
+
+    fn window_start(&self) -> Option<DateTime<Utc>> {
+        let window = chrono::Duration::from_std(self.limits.window?).ok()?;
+        Some(Utc::now() - window)
+    }
+
+    fn lock_usage(&self) -> std::sync::MutexGuard<'_, Usage> {
+        self.usage.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
@@ -0,0 +1,119 @@
+//! Software bills of materials for the packages installed in a session
+//!
+//! Compliance tooling often only accepts an SBOM, not a script. The
+//! `export_session` tool can describe the recorded packages as an SPDX 2.3
+//! JSON document instead, with a package URL (purl) per package so scanners
+//! can match them against advisories of their distribution.
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Value, json};
+
+use crate::backend::PinnedPackage;
+use crate::version::VersionScheme;
+
+/// SPDX version of the generated documents
+pub const SPDX_VERSION: &str = "SPDX-2.3";
+
+/// SPDX 2.3 JSON document describing `packages`
+///
+/// `scheme` selects the purl type and namespace of the packages (`apk/alpine`
+/// or `deb/debian`); packages of backends without a version scheme get no
+/// purl. Licenses are not recorded with the packages, so they are reported
+/// as `NOASSERTION`.
+pub fn spdx_document(packages: &[PinnedPackage], scheme: Option<VersionScheme>) -> Value {
+    let created = Utc::now();
+    let name = format!(
+        "package-manager-mcp-session-{}",
+        created.format("%Y%m%dT%H%M%SZ")
+    );
+
+    let mut described = Vec::new();
+    let mut relationships = Vec::new();
+    for (index, package) in packages.iter().enumerate() {
+        let id = format!("SPDXRef-Package-{}-{}", index + 1, spdx_id(&package.name));
+        let mut entry = json!({
+            "name": package.name,
+            "SPDXID": id,
+            "versionInfo": package.version,
+            "downloadLocation": package.repository.as_deref().unwrap_or("NOASSERTION"),
+            "filesAnalyzed": false,
+            "licenseConcluded": "NOASSERTION",
+            "licenseDeclared": "NOASSERTION",
+            "copyrightText": "NOASSERTION",
+        });
+        if let Some(purl) = scheme.map(|scheme| purl(scheme, package)) {
+            entry["externalRefs"] = json!([{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": purl,
+            }]);
+        }
+        described.push(entry);
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": id,
+        }));
+    }
+
+    json!({
+        "spdxVersion": SPDX_VERSION,
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!(
+            "https://spdx.org/spdxdocs/{name}-{}-{}",
+            std::process::id(),
+            created.timestamp_subsec_nanos()
+        ),
+        "creationInfo": {
+            "created": created.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "creators": [format!("Tool: package-manager-mcp-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": described,
+        "relationships": relationships,
+    })
+}
+
+/// Package URL of `package`, e.g. `pkg:apk/alpine/curl@8.14.1-r1?arch=x86_64`
+pub fn purl(scheme: VersionScheme, package: &PinnedPackage) -> String {
+    let (kind, namespace) = match scheme {
+        VersionScheme::Apk => ("apk", "alpine"),
+        VersionScheme::Deb => ("deb", "debian"),
+    };
+    let mut purl = format!(
+        "pkg:{kind}/{namespace}/{}@{}",
+        percent_encode(&package.name),
+        percent_encode(&package.version)
+    );
+    if let Some(arch) = &package.arch {
+        purl.push_str(&format!("?arch={}", percent_encode(arch)));
+    }
+    purl
+}
+
+/// `name` with the characters SPDX identifiers cannot contain replaced by `-`
+fn spdx_id(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Percent-encode everything but unreserved characters, as purls require
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b".-_~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
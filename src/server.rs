@@ -0,0 +1,792 @@
+//! Builder wiring a backend, hooks, events and HTTP routes into a server
+//!
+//! [`ServerBuilder`] is the entry point for running the MCP server as part of
+//! another program. It composes the pieces the binary otherwise wires by hand:
+//!
+//! ```no_run
+//! use package_manager_mcp::{Apk, ServerBuilder};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! ServerBuilder::new()
+//!     .backend(Apk::new())
+//!     .with_auth("secret-token")
+//!     .bind("0.0.0.0:8090")
+//!     .await?
+//!     .serve()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Policy checks and auditing plug in through [`Hooks`], change notifications
+//! through the [`EventBus`] and webhooks, and extra HTTP endpoints (health
+//! checks, metrics) through [`ServerBuilder::route`] and
+//! [`ServerBuilder::merge`].
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+use axum::extract::{Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::MethodRouter;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use tower_http::compression::CompressionLayer;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+
+use crate::audit::AuditLog;
+use crate::backend::approval::ApprovalWebhook;
+use crate::backend::cache::{CachingBackend, SearchCache};
+use crate::backend::descriptions::DescriptionOverrides;
+use crate::backend::freshness::AutoRefresh;
+use crate::backend::naming::ToolNames;
+use crate::backend::notifications::NotificationSink;
+use crate::backend::offline::OfflineMirrors;
+use crate::backend::operation_log::OperationLogStore;
+use crate::backend::osv::{OsvClient, VulnerabilityGate};
+use crate::backend::pins::PackagePins;
+use crate::backend::privileges::PrivilegeReport;
+use crate::backend::repology::RepologyClient;
+use crate::backend::{PackageManager, PackageManagerHandler};
+use crate::events::{self, EventBus};
+use crate::hooks::Hooks;
+use crate::identity::{ClientIdentity, IdentityHeaders, IdentitySource};
+use crate::quota::QuotaLimits;
+
+/// Default path of the MCP endpoint
+pub const DEFAULT_MCP_PATH: &str = "/mcp";
+
+/// Identify clients the authentication did not from the trusted headers
+async fn identify_client(
+    State(headers): State<Arc<IdentityHeaders>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if request.extensions().get::<ClientIdentity>().is_none()
+        && let Some(identity) = headers.identify(request.headers())
+    {
+        request.extensions_mut().insert(identity);
+    }
+    next.run(request).await
+}
+
+/// Errors raised while building or binding a server
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("no package manager backend was configured")]
+    MissingBackend,
+    #[error("invalid tool schema: {0}")]
+    ToolSchema(String),
+    #[error("failed to bind {address}: {source}")]
+    Bind {
+        address: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("invalid tool names: {0}")]
+    ToolNames(String),
+    #[error("refusing to serve with unsigned repositories: {0}")]
+    UnsignedRepositories(String),
+}
+
+/// Accepted bearer tokens and the subjects they identify
+type AuthTokens = Vec<(String, Option<String>)>;
+
+/// Builder composing an MCP package manager server
+pub struct ServerBuilder {
+    backend: Option<Arc<dyn PackageManager>>,
+    hooks: Vec<Arc<dyn Hooks>>,
+    events: Option<EventBus>,
+    webhook_urls: Vec<String>,
+    auth_tokens: AuthTokens,
+    identity_headers: IdentityHeaders,
+    default_arch: Option<String>,
+    page_size: Option<usize>,
+    check_privileges: bool,
+    privileges: Option<PrivilegeReport>,
+    targets: Vec<(String, Arc<dyn PackageManager>)>,
+    local_targets: Vec<String>,
+    record_sessions: bool,
+    compress_responses: bool,
+    search_cache: Option<SearchCache>,
+    installed_cache_ttl: Option<Duration>,
+    auto_refresh: Option<AutoRefresh>,
+    idempotency_ttl: Option<Duration>,
+    jobs: bool,
+    tool_names: ToolNames,
+    descriptions: DescriptionOverrides,
+    batch_window: Option<Duration>,
+    watch_interval: Option<Duration>,
+    osv: Option<OsvClient>,
+    repology: Option<RepologyClient>,
+    denied_licenses: Vec<String>,
+    allow_untrusted_sources: bool,
+    allow_no_scripts: bool,
+    allowed_repositories: Vec<String>,
+    user_allowed_repositories: Vec<(String, String)>,
+    offline: Option<OfflineMirrors>,
+    signed_repositories_only: bool,
+    disk_space_check: bool,
+    audit_log: Option<AuditLog>,
+    operation_logs: Option<OperationLogStore>,
+    quotas: QuotaLimits,
+    approval: Option<ApprovalWebhook>,
+    notification_sinks: Vec<NotificationSink>,
+    vulnerability_gate: Option<VulnerabilityGate>,
+    package_pins: PackagePins,
+    mcp_path: String,
+    routes: Router,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            backend: None,
+            hooks: Vec::new(),
+            events: None,
+            webhook_urls: Vec::new(),
+            auth_tokens: Vec::new(),
+            identity_headers: IdentityHeaders::new(),
+            default_arch: None,
+            page_size: None,
+            check_privileges: false,
+            privileges: None,
+            targets: Vec::new(),
+            local_targets: Vec::new(),
+            record_sessions: false,
+            compress_responses: false,
+            search_cache: None,
+            installed_cache_ttl: None,
+            auto_refresh: None,
+            idempotency_ttl: None,
+            jobs: true,
+            tool_names: ToolNames::new(),
+            descriptions: DescriptionOverrides::new(),
+            batch_window: None,
+            watch_interval: None,
+            osv: None,
+            repology: None,
+            denied_licenses: Vec::new(),
+            allow_untrusted_sources: false,
+            allow_no_scripts: false,
+            allowed_repositories: Vec::new(),
+            user_allowed_repositories: Vec::new(),
+            offline: None,
+            signed_repositories_only: false,
+            disk_space_check: true,
+            audit_log: None,
+            operation_logs: None,
+            quotas: QuotaLimits::new(),
+            approval: None,
+            notification_sinks: Vec::new(),
+            vulnerability_gate: None,
+            package_pins: PackagePins::new(),
+            mcp_path: DEFAULT_MCP_PATH.to_string(),
+            routes: Router::new(),
+        }
+    }
+
+    /// Serve the given backend
+    pub fn backend(self, backend: impl PackageManager) -> Self {
+        self.shared_backend(Arc::new(backend))
+    }
+
+    /// Serve a backend selected at runtime, e.g. from a [`crate::BackendRegistry`]
+    pub fn shared_backend(mut self, backend: Arc<dyn PackageManager>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Register hooks (policy checks, auditing) invoked around every operation
+    pub fn with_hooks(mut self, hooks: impl Hooks) -> Self {
+        self.hooks.push(Arc::new(hooks));
+        self
+    }
+
+    /// Publish package events to an existing bus instead of a private one
+    pub fn with_event_bus(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Deliver every package event to the webhook URL
+    pub fn with_webhook(mut self, url: impl Into<String>) -> Self {
+        self.webhook_urls.push(url.into());
+        self
+    }
+
+    /// Require `Authorization: Bearer <token>` on the MCP endpoint
+    ///
+    /// Routes added with [`Self::route`] and [`Self::merge`] are not
+    /// protected, so health checks keep working without credentials.
+    pub fn with_auth(mut self, token: impl Into<String>) -> Self {
+        self.auth_tokens.push((token.into(), None));
+        self
+    }
+
+    /// Also accept `token`, identifying its clients as `subject` in audit
+    /// entries and policy decisions
+    pub fn with_auth_subject(
+        mut self,
+        subject: impl Into<String>,
+        token: impl Into<String>,
+    ) -> Self {
+        self.auth_tokens.push((token.into(), Some(subject.into())));
+        self
+    }
+
+    /// Identify clients without a token subject from trusted request headers
+    /// set by a proxy
+    pub fn identity_headers(mut self, headers: IdentityHeaders) -> Self {
+        self.identity_headers = headers;
+        self
+    }
+
+    /// Architecture used by installs and searches that do not pass `arch`
+    pub fn default_arch(mut self, arch: impl Into<String>) -> Self {
+        self.default_arch = Some(arch.into());
+        self
+    }
+
+    /// Split listings into pages of at most `size` entries
+    pub fn page_size(mut self, size: usize) -> Self {
+        self.page_size = Some(size);
+        self
+    }
+
+    /// Check whether the backend can modify the system when binding
+    ///
+    /// Problems are logged, and mutating tools are hidden until a
+    /// `check_privileges` call finds them solved. Backends without the
+    /// `privilege_check` capability are not checked.
+    pub fn check_privileges(mut self) -> Self {
+        self.check_privileges = true;
+        self
+    }
+
+    /// Start from the result of a privilege check run by the caller
+    pub fn privileges(mut self, report: PrivilegeReport) -> Self {
+        self.privileges = Some(report);
+        self
+    }
+
+    /// Add a named execution target for fan-out installs
+    pub fn target(mut self, name: impl Into<String>, backend: Arc<dyn PackageManager>) -> Self {
+        let name = name.into();
+        self.local_targets.retain(|existing| *existing != name);
+        self.targets.push((name, backend));
+        self
+    }
+
+    /// Add a named execution target on the server's own host, whose installs
+    /// wait for the server's other mutating calls
+    pub fn local_target(
+        mut self,
+        name: impl Into<String>,
+        backend: Arc<dyn PackageManager>,
+    ) -> Self {
+        let name = name.into();
+        self = self.target(name.clone(), backend);
+        self.local_targets.push(name);
+        self
+    }
+
+    /// Answer repeated searches from the cache until its entries expire
+    pub fn search_cache(mut self, cache: SearchCache) -> Self {
+        self.search_cache = Some(cache);
+        self
+    }
+
+    /// Reuse listings of installed packages for `ttl`, or until a mutating
+    /// tool succeeds
+    pub fn installed_cache(mut self, ttl: Duration) -> Self {
+        self.installed_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Refresh the repository indexes on the agents' behalf, as `policy`
+    /// allows
+    pub fn auto_refresh(mut self, policy: AutoRefresh) -> Self {
+        self.auto_refresh = Some(policy);
+        self
+    }
+
+    /// Replay results of mutating calls retried with the same idempotency key
+    /// for `ttl` instead of a day, or never when `ttl` is zero
+    pub fn idempotency_ttl(mut self, ttl: Duration) -> Self {
+        self.idempotency_ttl = Some(ttl);
+        self
+    }
+
+    /// Run mutating calls to completion only, without background jobs
+    pub fn disable_jobs(mut self) -> Self {
+        self.jobs = false;
+        self
+    }
+
+    /// Expose the tools under `names`, e.g. with a prefix telling them apart
+    /// from the tools of other servers behind the same gateway
+    pub fn tool_names(mut self, names: ToolNames) -> Self {
+        self.tool_names = names;
+        self
+    }
+
+    /// Replace or extend the generated tool descriptions and server
+    /// instructions, e.g. to document site-specific repositories and policies
+    pub fn descriptions(mut self, overrides: DescriptionOverrides) -> Self {
+        self.descriptions = overrides;
+        self
+    }
+
+    /// Run the installs a session requests within `window` of each other as
+    /// one command
+    pub fn batch_installs(mut self, window: Duration) -> Self {
+        self.batch_window = Some(window);
+        self
+    }
+
+    /// Check the installed packages of sessions subscribed to the installed
+    /// manifest for changes every `interval`
+    pub fn watch_interval(mut self, interval: Duration) -> Self {
+        self.watch_interval = Some(interval);
+        self
+    }
+
+    /// Offer `check_vulnerabilities`, looking up advisories with `client`
+    pub fn vulnerability_database(mut self, client: OsvClient) -> Self {
+        self.osv = Some(client);
+        self
+    }
+
+    /// Offer `search_all_distros`, looking up projects with `client`
+    pub fn repology(mut self, client: RepologyClient) -> Self {
+        self.repology = Some(client);
+        self
+    }
+
+    /// Block installs of versions with vulnerabilities as `gate` says,
+    /// checked with the [`Self::vulnerability_database`]
+    pub fn vulnerability_gate(mut self, gate: VulnerabilityGate) -> Self {
+        self.vulnerability_gate = Some(gate);
+        self
+    }
+
+    /// Verify the downloaded file of pinned packages before installing them
+    pub fn package_pins(mut self, pins: PackagePins) -> Self {
+        self.package_pins = pins;
+        self
+    }
+
+    /// Flag packages with a license containing `pattern` in license reports
+    /// (repeatable)
+    pub fn deny_license(mut self, pattern: impl Into<String>) -> Self {
+        self.denied_licenses.push(pattern.into());
+        self
+    }
+
+    /// Allow installs from package files, URLs and custom repositories,
+    /// which are rejected by default
+    pub fn allow_untrusted_sources(mut self) -> Self {
+        self.allow_untrusted_sources = true;
+        self
+    }
+
+    /// Allow installs skipping maintainer scripts with `no_scripts`, which
+    /// is rejected by default
+    pub fn allow_no_scripts(mut self) -> Self {
+        self.allow_no_scripts = true;
+        self
+    }
+
+    /// Only accept repositories below the URL prefix or on the domain
+    /// `entry` (repeatable)
+    pub fn allow_repository(mut self, entry: impl Into<String>) -> Self {
+        self.allowed_repositories.push(entry.into());
+        self
+    }
+
+    /// Accept repositories below the URL prefix or on the domain `entry`
+    /// from the client identified as `subject`, replacing the server-wide
+    /// allowlist for it (repeatable)
+    pub fn allow_repository_for(
+        mut self,
+        subject: impl Into<String>,
+        entry: impl Into<String>,
+    ) -> Self {
+        self.user_allowed_repositories
+            .push((subject.into(), entry.into()));
+        self
+    }
+
+    /// Reject repositories that are not on the internal mirrors of
+    /// `offline`
+    ///
+    /// Backends are put in offline mode separately, through
+    /// [`crate::BackendOptions::offline`].
+    pub fn offline_mirrors(mut self, offline: OfflineMirrors) -> Self {
+        self.offline = Some(offline);
+        self
+    }
+
+    /// Refuse to operate while a repository lacks a valid signing
+    /// configuration
+    ///
+    /// The repositories of the backend and the targets are checked when
+    /// binding, which fails if one is unsigned, and again before every
+    /// operation using them.
+    pub fn signed_repositories_only(mut self) -> Self {
+        self.signed_repositories_only = true;
+        self
+    }
+
+    /// Let installs run without checking first that the disk can hold them
+    pub fn skip_disk_space_check(mut self) -> Self {
+        self.disk_space_check = false;
+        self
+    }
+
+    /// Record every tool call in `log`
+    pub fn audit_log(mut self, log: AuditLog) -> Self {
+        self.audit_log = Some(log);
+        self
+    }
+
+    /// Keep the complete output of every mutating operation in `logs`
+    pub fn operation_logs(mut self, logs: OperationLogStore) -> Self {
+        self.operation_logs = Some(logs);
+        self
+    }
+
+    /// Limit the installs, removed packages and downloads of each MCP session
+    pub fn quotas(mut self, limits: QuotaLimits) -> Self {
+        self.quotas = limits;
+        self
+    }
+
+    /// Run mutating operations only once `webhook` approves them
+    pub fn approval_webhook(mut self, webhook: ApprovalWebhook) -> Self {
+        self.approval = Some(webhook);
+        self
+    }
+
+    /// Notify `sink` after every install, successful or not
+    pub fn with_notification_sink(mut self, sink: NotificationSink) -> Self {
+        self.notification_sinks.push(sink);
+        self
+    }
+
+    /// Record the packages each session installs, for `export_session`
+    pub fn record_sessions(mut self) -> Self {
+        self.record_sessions = true;
+        self
+    }
+
+    /// Compress MCP responses with gzip or brotli when the client accepts it
+    ///
+    /// Listings and search results are repetitive text that shrinks several
+    /// times over. Event streams are compressed as well; every event is
+    /// flushed as soon as it is sent, so progress notifications still arrive
+    /// as they happen.
+    pub fn compress_responses(mut self) -> Self {
+        self.compress_responses = true;
+        self
+    }
+
+    /// Serve the MCP endpoint at another path than [`DEFAULT_MCP_PATH`]
+    pub fn mcp_path(mut self, path: impl Into<String>) -> Self {
+        self.mcp_path = path.into();
+        self
+    }
+
+    /// Add an HTTP route next to the MCP endpoint
+    pub fn route(mut self, path: &str, method_router: MethodRouter) -> Self {
+        self.routes = self.routes.route(path, method_router);
+        self
+    }
+
+    /// Merge another router next to the MCP endpoint
+    pub fn merge(mut self, router: Router) -> Self {
+        self.routes = self.routes.merge(router);
+        self
+    }
+
+    /// Build the handler and the axum router serving it
+    ///
+    /// Webhook delivery is spawned on the current Tokio runtime, so this must
+    /// be called from within one when webhooks are configured.
+    pub fn build(self) -> Result<Router, ServerError> {
+        let mut backend = self.backend.ok_or(ServerError::MissingBackend)?;
+        if let Some(cache) = self.search_cache {
+            backend = Arc::new(CachingBackend::new(backend, cache));
+        }
+
+        let mut handler = PackageManagerHandler::from_arc(backend);
+        if let Some(events) = self.events {
+            handler = handler.with_event_bus(events);
+        }
+        for hooks in self.hooks {
+            handler = handler.with_hooks(hooks);
+        }
+        if let Some(arch) = self.default_arch {
+            handler = handler.with_default_arch(arch);
+        }
+        if let Some(size) = self.page_size {
+            handler = handler.with_page_size(size);
+        }
+        if let Some(ttl) = self.installed_cache_ttl {
+            handler = handler.with_installed_cache(ttl);
+        }
+        if let Some(policy) = self.auto_refresh {
+            handler = handler.with_auto_refresh(policy);
+        }
+        if let Some(ttl) = self.idempotency_ttl {
+            handler = handler.with_idempotency_ttl(ttl);
+        }
+        if !self.jobs {
+            handler = handler.without_jobs();
+        }
+        self.tool_names.validate().map_err(ServerError::ToolNames)?;
+        handler = handler.with_tool_names(self.tool_names);
+        handler = handler.with_description_overrides(self.descriptions);
+        if let Some(window) = self.batch_window {
+            handler = handler.with_install_batching(window);
+        }
+        if let Some(interval) = self.watch_interval {
+            handler = handler.with_watch_interval(interval);
+        }
+        if let Some(report) = self.privileges {
+            handler = handler.with_privileges(report);
+        }
+        if self.record_sessions {
+            handler = handler.with_session_recording();
+        }
+        if let Some(client) = self.osv {
+            handler = handler.with_vulnerability_database(client);
+        }
+        if let Some(client) = self.repology {
+            handler = handler.with_repology(client);
+        }
+        for pattern in self.denied_licenses {
+            handler = handler.with_denied_license(pattern);
+        }
+        if self.allow_untrusted_sources {
+            handler = handler.with_untrusted_sources();
+        }
+        if self.allow_no_scripts {
+            handler = handler.with_no_scripts_allowed();
+        }
+        for entry in self.allowed_repositories {
+            handler = handler.with_allowed_repository(entry);
+        }
+        for (subject, entry) in self.user_allowed_repositories {
+            handler = handler.with_allowed_repository_for(subject, entry);
+        }
+        if let Some(offline) = self.offline {
+            handler = handler.with_offline_mirrors(offline);
+        }
+        if self.signed_repositories_only {
+            handler = handler.with_signed_repositories_only();
+        }
+        if !self.disk_space_check {
+            handler = handler.without_disk_space_check();
+        }
+        if let Some(log) = self.audit_log {
+            handler = handler.with_audit_log(log);
+        }
+        if let Some(logs) = self.operation_logs {
+            handler = handler.with_operation_logs(logs);
+        }
+        handler = handler.with_quotas(self.quotas);
+        handler = handler.with_package_pins(self.package_pins);
+        if let Some(gate) = self.vulnerability_gate {
+            handler = handler.with_vulnerability_gate(gate);
+        }
+        if let Some(webhook) = self.approval {
+            handler = handler.with_approval_webhook(webhook);
+        }
+        for sink in self.notification_sinks {
+            handler = handler.with_notification_sink(sink);
+        }
+        for (name, backend) in self.targets {
+            handler = if self.local_targets.contains(&name) {
+                handler.with_local_target(name, backend)
+            } else {
+                handler.with_target(name, backend)
+            };
+        }
+        handler
+            .validate_tools()
+            .map_err(|err| ServerError::ToolSchema(err.message.to_string()))?;
+        if !self.webhook_urls.is_empty() {
+            events::spawn_webhook_delivery(handler.events(), self.webhook_urls);
+        }
+
+        let mut mcp = Router::new().nest_service(&self.mcp_path, crate::handler_service(handler));
+        // Token subjects take precedence, so the identity layer runs after
+        // the authentication one
+        if !self.identity_headers.is_empty() {
+            mcp = mcp.layer(middleware::from_fn_with_state(
+                Arc::new(self.identity_headers),
+                identify_client,
+            ));
+        }
+        if !self.auth_tokens.is_empty() {
+            let tokens = self
+                .auth_tokens
+                .into_iter()
+                .map(|(token, subject)| (format!("Bearer {token}"), subject))
+                .collect();
+            mcp = mcp.layer(middleware::from_fn_with_state(
+                Arc::new(tokens),
+                require_bearer_token,
+            ));
+        }
+        if self.compress_responses {
+            // The default predicate skips event streams, which is where the
+            // results of tool calls are
+            let predicate = SizeAbove::default()
+                .and(NotForContentType::GRPC)
+                .and(NotForContentType::IMAGES);
+            mcp = mcp.layer(CompressionLayer::new().compress_when(predicate));
+        }
+
+        Ok(mcp.merge(self.routes))
+    }
+
+    /// Build the server and bind it to the address, e.g. `0.0.0.0:8090`
+    pub async fn bind(mut self, address: &str) -> Result<Server, ServerError> {
+        if self.check_privileges
+            && let Some(backend) = &self.backend
+            && backend.capabilities().privilege_check
+        {
+            match backend.check_privileges().await {
+                Ok(report) => {
+                    for problem in &report.problems {
+                        tracing::warn!("Installs and refreshes are disabled: {problem}");
+                    }
+                    self.privileges = Some(report);
+                }
+                Err(err) => tracing::warn!("Failed to check privileges: {err}"),
+            }
+        }
+
+        if self.signed_repositories_only {
+            let targets = self.targets.iter().map(|(_, backend)| backend);
+            for backend in self.backend.iter().chain(targets) {
+                check_signed_repositories(backend.as_ref()).await?;
+            }
+        }
+
+        let router = self.build()?;
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|source| ServerError::Bind {
+                address: address.to_string(),
+                source,
+            })?;
+        Ok(Server { listener, router })
+    }
+}
+
+/// Fail unless every repository of `backend` is signed
+async fn check_signed_repositories(backend: &dyn PackageManager) -> Result<(), ServerError> {
+    if !backend.capabilities().signature_check {
+        return Err(ServerError::UnsignedRepositories(format!(
+            "the {} backend cannot check repository signatures",
+            backend.name()
+        )));
+    }
+    let problems = backend
+        .unsigned_repositories(None)
+        .await
+        .map_err(|err| ServerError::UnsignedRepositories(err.to_string()))?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(ServerError::UnsignedRepositories(
+        problems
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; "),
+    ))
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A server bound to its listening socket
+pub struct Server {
+    listener: tokio::net::TcpListener,
+    router: Router,
+}
+
+impl Server {
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve requests until Ctrl+C is received
+    pub async fn serve(self) -> std::io::Result<()> {
+        crate::serve(self.listener, self.router).await
+    }
+}
+
+/// Whether `presented` is the `expected` authorization, compared in constant
+/// time
+///
+/// Both are MACed with a random key and `hmac::verify` compares the tags
+/// without stopping at the first differing byte, so response times do not
+/// reveal how much of a token a guess got right.
+fn token_matches(presented: &[u8], expected: &str) -> bool {
+    let Ok(key) = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()) else {
+        return false;
+    };
+    let tag = hmac::sign(&key, expected.as_bytes());
+    hmac::verify(&key, presented, tag.as_ref()).is_ok()
+}
+
+async fn require_bearer_token(
+    State(tokens): State<Arc<AuthTokens>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| {
+            // Every token is compared, so the time taken does not tell which
+            // one matched either
+            tokens.iter().fold(None, |authorized, token| {
+                if token_matches(value.as_bytes(), &token.0) {
+                    Some(token)
+                } else {
+                    authorized
+                }
+            })
+        });
+
+    if let Some((_, subject)) = authorized {
+        if let Some(subject) = subject {
+            request.extensions_mut().insert(ClientIdentity::new(
+                subject.clone(),
+                IdentitySource::AuthToken,
+            ));
+        }
+        next.run(request).await
+    } else {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response()
+    }
+}
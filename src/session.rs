@@ -0,0 +1,290 @@
+//! Recording of the packages installed during an MCP session
+//!
+//! When recording is enabled, every successful install a session makes on the
+//! server's own system is kept as a [`PinnedPackage`]. The `export_session`
+//! tool turns them into the commands reinstalling the same versions, as a shell
+//! script or a Dockerfile `RUN` instruction, so what an agent set up
+//! interactively can be replayed into an image build. It can also describe
+//! them as an SPDX bill of materials (see [`crate::sbom`]) or as Ansible
+//! tasks (see [`crate::ansible`]).
+//!
+//! The packages the installs pulled in as dependencies are recorded
+//! separately, so `generate_lockfile` can pin the whole set (see
+//! [`crate::lockfile`]).
+//!
+//! Independently of recording, every session keeps a [`SessionDelta`] of the
+//! packages its installs added, removed and changed, for supervisors
+//! snapshotting what a session changed through the `state://session-delta`
+//! resource.
+
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::backend::{InstallOutcome, InstalledPackage, PinnedPackage};
+
+/// Shape of an exported session
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// A POSIX shell script stopping at the first failing command
+    #[default]
+    Script,
+    /// A single Dockerfile `RUN` instruction
+    Dockerfile,
+    /// An SPDX 2.3 JSON document listing the packages
+    Spdx,
+    /// A list of Ansible tasks adding the repositories and installing the
+    /// packages
+    Ansible,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "script" => Ok(Self::Script),
+            "dockerfile" => Ok(Self::Dockerfile),
+            "spdx" => Ok(Self::Spdx),
+            "ansible" => Ok(Self::Ansible),
+            _ => Err(format!(
+                "unknown format '{format}', expected script, dockerfile, spdx or ansible"
+            )),
+        }
+    }
+}
+
+impl ExportFormat {
+    /// Render the commands in this format
+    ///
+    /// Returns `None` for formats describing the packages rather than the
+    /// commands installing them.
+    pub fn render(self, commands: &[String]) -> Option<String> {
+        match self {
+            Self::Script => {
+                let mut script = "#!/bin/sh\nset -eu\n".to_string();
+                for command in commands {
+                    script.push_str(command);
+                    script.push('\n');
+                }
+                Some(script)
+            }
+            Self::Dockerfile => Some(format!("RUN {}\n", commands.join(" \\\n    && "))),
+            Self::Spdx | Self::Ansible => None,
+        }
+    }
+}
+
+/// Packages an export describes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportScope {
+    /// The packages the session installed
+    Session,
+    /// Every package installed on the system
+    Installed,
+}
+
+impl FromStr for ExportScope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        match scope {
+            "session" => Ok(Self::Session),
+            "installed" => Ok(Self::Installed),
+            _ => Err(format!(
+                "unknown scope '{scope}', expected session or installed"
+            )),
+        }
+    }
+}
+
+/// Packages installed by one session, in installation order
+#[derive(Debug, Default)]
+pub struct SessionLog {
+    packages: Mutex<Vec<PinnedPackage>>,
+    /// Other packages the installs added or upgraded
+    dependencies: Mutex<Vec<PinnedPackage>>,
+}
+
+impl SessionLog {
+    /// Record a successful install of the requested package and the
+    /// dependencies it changed
+    ///
+    /// Installing a package again replaces its earlier entry, so each package
+    /// appears once, pinned to the last version installed. A dependency that
+    /// is later requested moves to the requested packages, and a requested
+    /// package upgraded as a dependency keeps its place with the new version.
+    /// `repositories` are the custom repositories the install used.
+    pub fn record(&self, outcome: &InstallOutcome, arch: Option<&str>, repositories: &[&str]) {
+        let Some(version) = &outcome.version else {
+            tracing::warn!(
+                "Not recording '{}': the installed version is unknown",
+                outcome.package
+            );
+            return;
+        };
+
+        let mut packages = self.packages.lock().unwrap_or_else(|err| err.into_inner());
+        let mut dependencies = self
+            .dependencies
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        packages
+            .retain(|package| package.name != outcome.package || package.arch.as_deref() != arch);
+        dependencies.retain(|package| package.name != outcome.package);
+        let (repository, repositories) = match repositories {
+            [] => (None, Vec::new()),
+            [first, rest @ ..] => (
+                Some(first.to_string()),
+                rest.iter()
+                    .map(|repository| repository.to_string())
+                    .collect(),
+            ),
+        };
+        packages.push(PinnedPackage {
+            name: outcome.package.clone(),
+            version: version.clone(),
+            arch: arch.map(str::to_string),
+            repository: repository.clone(),
+            repositories: repositories.clone(),
+        });
+
+        for installed in &outcome.installed {
+            if installed.name == outcome.package {
+                continue;
+            }
+            add_dependency(
+                &mut packages,
+                &mut dependencies,
+                PinnedPackage {
+                    name: installed.name.clone(),
+                    version: installed.version.clone(),
+                    arch: installed.architecture.clone(),
+                    repository: repository.clone(),
+                    repositories: repositories.clone(),
+                },
+            );
+        }
+    }
+
+    /// Record a package installed as a dependency, e.g. from a lockfile
+    pub fn record_dependency(&self, package: PinnedPackage) {
+        let mut packages = self.packages.lock().unwrap_or_else(|err| err.into_inner());
+        let mut dependencies = self
+            .dependencies
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        add_dependency(&mut packages, &mut dependencies, package);
+    }
+
+    /// Recorded packages, in installation order
+    pub fn packages(&self) -> Vec<PinnedPackage> {
+        self.packages
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Packages the recorded installs pulled in, in installation order
+    pub fn dependencies(&self) -> Vec<PinnedPackage> {
+        self.dependencies
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+}
+
+/// Add `package` to the dependencies, or update the version of the requested
+/// package with its name
+fn add_dependency(
+    packages: &mut [PinnedPackage],
+    dependencies: &mut Vec<PinnedPackage>,
+    package: PinnedPackage,
+) {
+    if let Some(requested) = packages
+        .iter_mut()
+        .find(|requested| requested.name == package.name)
+    {
+        requested.version = package.version;
+        return;
+    }
+    dependencies.retain(|dependency| dependency.name != package.name);
+    dependencies.push(package);
+}
+
+/// Packages added, removed and changed on a system since a session began
+///
+/// Built from the outcomes of the session's installs, so changes made by
+/// other sessions or outside the server are not included.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SessionDelta {
+    pub added: Vec<InstalledPackage>,
+    pub removed: Vec<InstalledPackage>,
+    /// Packages at another version, with the version at the session's start
+    /// as their `previous_version`
+    pub changed: Vec<InstalledPackage>,
+}
+
+impl SessionDelta {
+    /// Apply the packages an install added, upgraded and removed
+    ///
+    /// A package changed back to its version at the session's start, or
+    /// added and then removed, leaves the delta.
+    pub fn record(&mut self, outcome: &InstallOutcome) {
+        for package in &outcome.installed {
+            if let Some(index) = position(&self.removed, package) {
+                let removed = self.removed.remove(index);
+                if removed.version != package.version {
+                    self.changed.push(InstalledPackage {
+                        previous_version: Some(removed.version),
+                        ..package.clone()
+                    });
+                }
+            } else if let Some(index) = position(&self.added, package) {
+                self.added[index].version = package.version.clone();
+            } else if let Some(index) = position(&self.changed, package) {
+                if self.changed[index].previous_version.as_ref() == Some(&package.version) {
+                    self.changed.remove(index);
+                } else {
+                    self.changed[index].version = package.version.clone();
+                }
+            } else if package.previous_version.is_some() {
+                self.changed.push(package.clone());
+            } else {
+                self.added.push(package.clone());
+            }
+        }
+
+        for package in &outcome.removed {
+            if let Some(index) = position(&self.added, package) {
+                self.added.remove(index);
+            } else if let Some(index) = position(&self.changed, package) {
+                let changed = self.changed.remove(index);
+                self.removed.push(InstalledPackage {
+                    version: changed.previous_version.unwrap_or(changed.version),
+                    previous_version: None,
+                    ..changed
+                });
+            } else if position(&self.removed, package).is_none() {
+                self.removed.push(InstalledPackage {
+                    previous_version: None,
+                    ..package.clone()
+                });
+            }
+        }
+    }
+}
+
+/// Index of the entry for `package`, by name and, when both report one,
+/// architecture
+fn position(packages: &[InstalledPackage], package: &InstalledPackage) -> Option<usize> {
+    packages.iter().position(|entry| {
+        entry.name == package.name
+            && (entry.architecture.is_none()
+                || package.architecture.is_none()
+                || entry.architecture == package.architecture)
+    })
+}
@@ -0,0 +1,132 @@
+//! Alpine `apk` version ordering
+//!
+//! A version is made of dot-separated numbers, an optional letter, any number
+//! of `_suffix[N]` parts and an optional `-rN` package release:
+//! `1.2.3a_rc1_p2-r4`. Pre-release suffixes (`alpha`, `beta`, `pre`, `rc`)
+//! sort before the plain version, post-release suffixes (`cvs`, `svn`, `git`,
+//! `hg`, `p`) after it. Versions that do not follow the format are compared
+//! as strings.
+
+use std::cmp::Ordering;
+
+/// Suffixes in ascending order, with the plain version between `rc` and `cvs`
+const SUFFIXES: &[&str] = &[
+    "alpha", "beta", "pre", "rc", "", "cvs", "svn", "git", "hg", "p",
+];
+
+/// Rank of a version without any (further) suffix
+const NO_SUFFIX: usize = 4;
+
+struct Version<'a> {
+    numbers: Vec<&'a str>,
+    letter: Option<char>,
+    suffixes: Vec<(usize, u64)>,
+    release: u64,
+}
+
+impl<'a> Version<'a> {
+    fn parse(version: &'a str) -> Option<Self> {
+        let (version, release) = match version.rsplit_once("-r") {
+            Some((version, release)) => (version, release.parse().ok()?),
+            None => (version, 0),
+        };
+
+        let (base, suffixes) = match version.split_once('_') {
+            Some((base, suffixes)) => (base, Some(suffixes)),
+            None => (version, None),
+        };
+
+        let (numbers, letter) = match base.chars().last() {
+            Some(letter) if letter.is_ascii_lowercase() => (&base[..base.len() - 1], Some(letter)),
+            _ => (base, None),
+        };
+        let numbers: Vec<&str> = numbers.split('.').collect();
+        if numbers
+            .iter()
+            .any(|number| number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()))
+        {
+            return None;
+        }
+
+        let suffixes = match suffixes {
+            Some(suffixes) => suffixes
+                .split('_')
+                .map(parse_suffix)
+                .collect::<Option<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+
+        Some(Self {
+            numbers,
+            letter,
+            suffixes,
+            release,
+        })
+    }
+}
+
+/// Parse a `_suffix[N]` part into its rank and number
+fn parse_suffix(suffix: &str) -> Option<(usize, u64)> {
+    let digits = suffix
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or(suffix.len());
+    let (name, number) = suffix.split_at(digits);
+    if name.is_empty() {
+        return None;
+    }
+    let rank = SUFFIXES.iter().position(|known| *known == name)?;
+    let number = if number.is_empty() {
+        0
+    } else {
+        number.parse().ok()?
+    };
+    Some((rank, number))
+}
+
+/// Compare two `apk` versions
+pub fn compare(a: &str, b: &str) -> Ordering {
+    match (Version::parse(a), Version::parse(b)) {
+        (Some(a), Some(b)) => compare_parsed(&a, &b),
+        _ => a.cmp(b),
+    }
+}
+
+fn compare_parsed(a: &Version, b: &Version) -> Ordering {
+    for (index, (x, y)) in a.numbers.iter().zip(&b.numbers).enumerate() {
+        let ordering = if index > 0 && (x.starts_with('0') || y.starts_with('0')) {
+            // Components with leading zeros compare as decimal fractions
+            x.trim_end_matches('0').cmp(y.trim_end_matches('0'))
+        } else {
+            compare_numbers(x, y)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    a.numbers
+        .len()
+        .cmp(&b.numbers.len())
+        .then_with(|| a.letter.cmp(&b.letter))
+        .then_with(|| compare_suffixes(&a.suffixes, &b.suffixes))
+        .then_with(|| a.release.cmp(&b.release))
+}
+
+fn compare_suffixes(a: &[(usize, u64)], b: &[(usize, u64)]) -> Ordering {
+    let length = a.len().max(b.len());
+    (0..length)
+        .map(|index| {
+            let x = a.get(index).copied().unwrap_or((NO_SUFFIX, 0));
+            let y = b.get(index).copied().unwrap_or((NO_SUFFIX, 0));
+            x.cmp(&y)
+        })
+        .find(|ordering| *ordering != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Compare digit strings numerically without overflowing
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
@@ -0,0 +1,87 @@
+//! Debian package version ordering
+//!
+//! Implements the algorithm from Debian Policy §5.6.12: versions are
+//! `[epoch:]upstream[-revision]`, compared by epoch, then upstream version,
+//! then revision. Within each part, non-digit runs are compared character by
+//! character with `~` sorting before everything (even the end of the string)
+//! and letters before other characters, and digit runs are compared
+//! numerically.
+
+use std::cmp::Ordering;
+
+/// Compare two Debian package versions
+pub fn compare(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split(a);
+    let (b_epoch, b_upstream, b_revision) = split(b);
+
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| compare_part(a_upstream, b_upstream))
+        .then_with(|| compare_part(a_revision, b_revision))
+}
+
+/// Split a version into epoch, upstream version and revision
+fn split(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => match epoch.parse() {
+            Ok(epoch) => (epoch, rest),
+            Err(_) => (0, version),
+        },
+        None => (0, version),
+    };
+    match rest.rsplit_once('-') {
+        Some((upstream, revision)) => (epoch, upstream, revision),
+        None => (epoch, rest, ""),
+    }
+}
+
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let mut a = a.as_bytes();
+    let mut b = b.as_bytes();
+
+    while !a.is_empty() || !b.is_empty() {
+        // Non-digit prefix
+        loop {
+            let x = a.first().filter(|c| !c.is_ascii_digit());
+            let y = b.first().filter(|c| !c.is_ascii_digit());
+            if x.is_none() && y.is_none() {
+                break;
+            }
+            let ordering = order(x.copied()).cmp(&order(y.copied()));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = &a[1..];
+            b = &b[1..];
+        }
+
+        // Digit run
+        let x = take_digits(&mut a);
+        let y = take_digits(&mut b);
+        let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    Ordering::Equal
+}
+
+/// Sort weight of a character in a non-digit run
+fn order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_alphabetic() => i32::from(c),
+        Some(c) => i32::from(c) + 256,
+    }
+}
+
+/// Take the leading digits, without leading zeros
+fn take_digits<'a>(input: &mut &'a [u8]) -> &'a [u8] {
+    let length = input.iter().take_while(|c| c.is_ascii_digit()).count();
+    let (digits, rest) = input.split_at(length);
+    *input = rest;
+    let zeros = digits.iter().take_while(|c| **c == b'0').count();
+    &digits[zeros..]
+}
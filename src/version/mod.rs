@@ -0,0 +1,160 @@
+//! Version ordering for the package formats supported by the built-in backends
+//!
+//! Package versions cannot be ordered as plain strings: `1.10` is newer than
+//! `1.9`, `1.0~rc1` is older than `1.0` in Debian and `1.0_rc1` is older than
+//! `1.0` in Alpine. [`VersionScheme`] implements each format's own rules.
+
+pub mod apk;
+pub mod deb;
+
+use std::cmp::Ordering;
+
+use serde::Serialize;
+
+/// Versioning rules of a package format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionScheme {
+    /// Alpine `apk` versions: `1.2.3a_rc1-r4`
+    Apk,
+    /// Debian package versions: `1:2.3~beta1-4`
+    Deb,
+}
+
+impl VersionScheme {
+    /// Compare two versions
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            Self::Apk => apk::compare(a, b),
+            Self::Deb => deb::compare(a, b),
+        }
+    }
+
+    /// Sort versions from oldest to newest and remove duplicates
+    pub fn sort(self, versions: &mut Vec<String>) {
+        versions.sort_by(|a, b| self.compare(a, b));
+        versions.dedup();
+    }
+
+    /// The newest of the given versions
+    pub fn latest<'a>(self, versions: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+        versions.into_iter().max_by(|a, b| self.compare(a, b))
+    }
+
+    /// The newest of the given versions satisfying the constraint
+    ///
+    /// An exact version none of them equals falls back to the newest version
+    /// starting with its components, as with `~`: `7.88` picks `7.88.1-r1`
+    /// and `3.11` the newest `3.11.x`.
+    pub fn latest_matching<'a>(
+        self,
+        versions: impl IntoIterator<Item = &'a str>,
+        constraint: &VersionConstraint,
+    ) -> Option<&'a str> {
+        let versions: Vec<&str> = versions.into_iter().collect();
+        let latest = |constraint: &VersionConstraint| {
+            self.latest(
+                versions
+                    .iter()
+                    .copied()
+                    .filter(|version| constraint.matches(self, version)),
+            )
+        };
+        latest(constraint).or_else(|| {
+            constraint.is_exact().then(|| {
+                latest(&VersionConstraint {
+                    comparison: Comparison::Fuzzy,
+                    version: constraint.version.clone(),
+                })
+            })?
+        })
+    }
+}
+
+/// Comparison operator of a [`VersionConstraint`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    /// `=` or no operator
+    Equal,
+    /// `<` or `<<`
+    Less,
+    /// `<=`
+    LessOrEqual,
+    /// `>` or `>>`
+    Greater,
+    /// `>=`
+    GreaterOrEqual,
+    /// `~`: versions starting with the given version components, e.g. `~1.2`
+    /// matches `1.2`, `1.2.5`, `1.2-r3` and, without an epoch, `1:1.2.5` but
+    /// not `1.20`
+    Fuzzy,
+}
+
+/// A version requirement such as `>=1.2` or `~3.12`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionConstraint {
+    pub comparison: Comparison,
+    pub version: String,
+}
+
+impl VersionConstraint {
+    /// Parse a constraint, treating a bare version as an exact match
+    pub fn parse(constraint: &str) -> Self {
+        const OPERATORS: &[(&str, Comparison)] = &[
+            (">=", Comparison::GreaterOrEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">>", Comparison::Greater),
+            ("<<", Comparison::Less),
+            ("==", Comparison::Equal),
+            (">", Comparison::Greater),
+            ("<", Comparison::Less),
+            ("=", Comparison::Equal),
+            ("~", Comparison::Fuzzy),
+        ];
+
+        let constraint = constraint.trim();
+        OPERATORS
+            .iter()
+            .find_map(|(operator, comparison)| {
+                constraint.strip_prefix(operator).map(|version| Self {
+                    comparison: *comparison,
+                    version: version.trim().to_string(),
+                })
+            })
+            .unwrap_or_else(|| Self {
+                comparison: Comparison::Equal,
+                version: constraint.to_string(),
+            })
+    }
+
+    /// Whether the constraint only accepts a single exact version
+    pub fn is_exact(&self) -> bool {
+        self.comparison == Comparison::Equal
+    }
+
+    /// Whether the candidate version satisfies the constraint
+    pub fn matches(&self, scheme: VersionScheme, candidate: &str) -> bool {
+        let ordering = scheme.compare(candidate, &self.version);
+        match self.comparison {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessOrEqual => ordering != Ordering::Greater,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterOrEqual => ordering != Ordering::Less,
+            Comparison::Fuzzy => {
+                let without_epoch = match candidate.split_once(':') {
+                    Some((epoch, rest))
+                        if !self.version.contains(':')
+                            && epoch.chars().all(|c| c.is_ascii_digit()) =>
+                    {
+                        rest
+                    }
+                    _ => candidate,
+                };
+                without_epoch
+                    .strip_prefix(self.version.as_str())
+                    .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_digit()))
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! Commands run on the host through the local runner
+//!
+//! The output limit is process-wide, so these tests live in their own binary.
+
+use package_manager_mcp::backend::exec;
+use package_manager_mcp::backend::runner::Command;
+use package_manager_mcp::{CommandRunner, LocalRunner};
+
+#[tokio::test]
+async fn keeps_the_start_and_end_of_runaway_output() {
+    exec::set_output_limit(1000);
+
+    let mut command = Command::new("sh");
+    command.args(["-c", "yes line | head -c 100000; echo end; echo done >&2"]);
+    let result = LocalRunner.run(&command).await.unwrap();
+
+    assert!(result.truncated);
+    let stdout = result.stdout.clone().unwrap();
+    assert!(stdout.starts_with("line\nline\n"));
+    assert!(stdout.ends_with("line\nline\nend\n"));
+    assert!(stdout.contains("\n[... 99004 bytes of output truncated ...]\n"));
+    assert!(stdout.len() < 1100);
+    assert_eq!(result.stderr.as_deref(), Some("done\n"));
+
+    // Parsing the rest would silently miss what was dropped
+    let err = result.success().unwrap_err();
+    assert_eq!(err.error_type(), "output_truncated");
+}
@@ -0,0 +1,3887 @@
+mod support;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use package_manager_mcp::audit::{ChainBreak, ChainProblem, verify_chain};
+use package_manager_mcp::{
+    Apk, ApprovalWebhook, Apt, AuditLog, AuditSigner, AutoRefresh, CachingBackend,
+    DescriptionOverrides, GenericBackend, IdentityHeaders, MockBackend, MockFailure, MockPackage,
+    NotificationSink, OfflineMirrors, OperationLogStore, OsvClient, PackageEventKind,
+    PackageManager, PackageManagerHandler, PackagePin, PackagePins, QuotaLimits, RecordingRunner,
+    RepologyClient, SearchCache, ServerBuilder, Severity, ToolNames, VulnerabilityGate,
+};
+use rmcp::model::ErrorCode;
+use serde_json::json;
+
+use support::{TestServer, error_type, json_content};
+
+#[tokio::test]
+async fn lists_every_tool_with_its_schema() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let mut names: Vec<_> = server
+        .tools()
+        .await
+        .into_iter()
+        .map(|tool| tool.name.to_string())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "cancel_job",
+            "check_privileges",
+            "doctor",
+            "export_apko_config",
+            "get_job_output",
+            "get_job_status",
+            "install_from_lockfile",
+            "install_package",
+            "install_package_with_version",
+            "license_report",
+            "list_installed_packages",
+            "list_repositories",
+            "list_search_repositories",
+            "refresh_repositories",
+            "resolve_version",
+            "search_package",
+            "server_info",
+        ]
+    );
+
+    let install = server.tool("install_package").await;
+    assert_eq!(install.input_schema["required"], json!(["package_name"]));
+    assert!(install.input_schema["properties"]["repository"].is_object());
+    assert!(install.input_schema["properties"]["async"].is_object());
+
+    let install_version = server.tool("install_package_with_version").await;
+    assert_eq!(install_version.input_schema["required"], json!([]));
+    assert!(install_version.input_schema["properties"]["packages"].is_object());
+}
+
+#[tokio::test]
+async fn hides_tools_the_backend_does_not_support() {
+    let backend = GenericBackend::from_toml(
+        r#"
+        name = "TEST"
+        os_name = "Test OS"
+
+        [search]
+        command = ["true"]
+
+        [list]
+        command = ["true"]
+        "#,
+    )
+    .unwrap();
+    let server = TestServer::start(backend).await;
+
+    let mut names: Vec<_> = server
+        .tools()
+        .await
+        .into_iter()
+        .map(|tool| tool.name.to_string())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "doctor",
+            "list_installed_packages",
+            "search_package",
+            "server_info"
+        ]
+    );
+
+    let search = server.tool("search_package").await;
+    assert!(
+        search.input_schema["properties"]
+            .get("repository")
+            .is_none()
+    );
+    assert!(search.input_schema["properties"].get("arch").is_none());
+
+    let err = server
+        .call_err("install_package", json!({ "package_name": "curl" }))
+        .await;
+    assert_eq!(error_type(&err), "unsupported_operation");
+}
+
+#[tokio::test]
+async fn preseed_needs_a_backend_with_debconf() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let install = server.tool("install_package").await;
+    assert!(install.input_schema["properties"].get("preseed").is_none());
+
+    let err = server
+        .call_err(
+            "install_package",
+            json!({
+                "package_name": "tzdata",
+                "preseed": ["tzdata tzdata/Areas select Europe"],
+            }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "unsupported_operation");
+}
+
+#[tokio::test]
+async fn install_recommends_needs_a_backend_choosing_them() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let install = server.tool("install_package_with_version").await;
+    assert!(
+        install.input_schema["properties"]
+            .get("install_recommends")
+            .is_none()
+    );
+
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "curl", "install_recommends": "all" }),
+        )
+        .await;
+    assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "curl", "install_recommends": "none" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "unsupported_operation");
+}
+
+#[tokio::test]
+async fn only_repositories_needs_a_backend_replacing_them() {
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_untrusted_sources();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let search = server.tool("search_package").await;
+    assert!(
+        search.input_schema["properties"]
+            .get("only_repositories")
+            .is_none()
+    );
+
+    for repositories in [
+        json!({ "repository": "mock://main" }),
+        json!({ "repositories": ["mock://main"] }),
+    ] {
+        let mut arguments = json!({
+            "package_name": "curl",
+            "only_repositories": ["mock://main"],
+        });
+        arguments
+            .as_object_mut()
+            .unwrap()
+            .extend(repositories.as_object().unwrap().clone());
+        let err = server.call_err("install_package", arguments).await;
+        assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+    }
+    for (tool, arguments) in [
+        ("install_package", json!({ "package_name": "curl" })),
+        ("search_package", json!({ "query": "curl" })),
+    ] {
+        let mut arguments = arguments;
+        arguments["only_repositories"] = json!(["mock://main", "mock://community"]);
+        let err = server.call_err(tool, arguments).await;
+        assert_eq!(error_type(&err), "unsupported_operation");
+    }
+}
+
+#[tokio::test]
+async fn repository_lists_need_a_backend_adding_several() {
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_untrusted_sources();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let install = server.tool("install_package").await;
+    assert!(
+        install.input_schema["properties"]
+            .get("repository")
+            .is_some()
+    );
+    assert!(
+        install.input_schema["properties"]
+            .get("repositories")
+            .is_none()
+    );
+
+    let err = server
+        .call_err(
+            "install_package",
+            json!({
+                "package_name": "curl",
+                "repository": "mock://main",
+                "repositories": ["mock://community"],
+            }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "unsupported_operation");
+
+    // APT searches several repositories as it installs from them, through
+    // one source added to the configured ones
+    let runner = Arc::new(RecordingRunner::new());
+    let handler = PackageManagerHandler::new(Apt::with_runner(runner.clone()))
+        .with_untrusted_sources()
+        .without_disk_space_check();
+    let server = TestServer::start_with_handler(handler).await;
+    let search = server.tool("search_package").await;
+    assert!(
+        search.input_schema["properties"]
+            .get("repositories")
+            .is_some()
+    );
+    server
+        .call(
+            "search_package",
+            json!({
+                "query": "curl",
+                "repositories": [
+                    "deb https://apt.example.com/extra bookworm main",
+                    "deb https://apt.example.com/tools bookworm main",
+                ],
+            }),
+        )
+        .await
+        .unwrap();
+    let calls = runner.calls();
+    let search = calls
+        .iter()
+        .find(|command| command.args.contains(&"search".to_string()))
+        .unwrap();
+    let option = search
+        .args
+        .iter()
+        .find(|arg| arg.starts_with("Dir::Etc::sourcelist="))
+        .unwrap();
+    // sources.list.d is still read
+    assert!(!search.args.contains(&"Dir::Etc::sourceparts=-".to_string()));
+    assert!(
+        calls
+            .iter()
+            .any(|command| command.args.contains(&"update".to_string())
+                && command.args.contains(option)),
+        "{calls:?}"
+    );
+}
+
+#[tokio::test]
+async fn apk_package_names_may_name_a_repository_tag() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    // Past validation, the mock backend has no such package
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "neovim@testing" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "package_not_found");
+
+    for (tool, arguments) in [
+        ("install_package", json!({ "package_name": "neovim@" })),
+        ("install_package", json!({ "package_name": "neovim@-x" })),
+        (
+            "install_package_with_version",
+            json!({ "package_name": "neovim@testing", "version": "0.11" }),
+        ),
+    ] {
+        let err = server.call_err(tool, arguments).await;
+        assert_eq!(error_type(&err), "validation_error");
+    }
+}
+
+#[tokio::test]
+async fn target_release_needs_a_backend_preferring_releases() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    for tool in ["install_package", "resolve_version"] {
+        let schema = server.tool(tool).await;
+        assert!(
+            schema.input_schema["properties"]
+                .get("target_release")
+                .is_none()
+        );
+    }
+
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "8", "target_release": "-oDir=/tmp" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "validation_error");
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "curl", "target_release": "bookworm-backports" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "unsupported_operation");
+}
+
+#[tokio::test]
+async fn installs_and_lists_packages() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let outcome = server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+    assert_eq!(outcome["package"], "curl");
+    assert_eq!(outcome["version"], "8.14.1-r1");
+
+    let installed = server.call_json("list_installed_packages", json!({})).await;
+    let curl = installed
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|package| package["name"] == "curl")
+        .expect("curl is not listed as installed");
+    assert_eq!(curl["version"], "8.14.1-r1");
+}
+
+#[tokio::test]
+async fn includes_the_command_output_of_verbose_installs() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let result = server
+        .call("install_package", json!({ "package_name": "curl" }))
+        .await
+        .unwrap();
+    assert_eq!(result.content.len(), 2);
+
+    let result = server
+        .call(
+            "install_package_with_version",
+            json!({ "package_name": "git", "version": "2.49.1-r0", "verbose": true }),
+        )
+        .await
+        .unwrap();
+    let report = &result.content[2].as_text().unwrap().text;
+    assert!(report.starts_with("Exit code: 0"), "{report}");
+    assert!(
+        report.contains("stdout:\n(1/1) Installing git (2.49.1-r0)"),
+        "{report}"
+    );
+}
+
+#[tokio::test]
+async fn lists_why_and_where_from_packages_are_installed() {
+    let server = TestServer::start(
+        MockBackend::empty()
+            .with_package(
+                MockPackage::new("app", "1.0-r0", "Application").with_dependency("libapp"),
+            )
+            .with_package(MockPackage::new("libapp", "1.0-r0", "Application library")),
+    )
+    .await;
+    server
+        .call_json("install_package", json!({ "package_name": "app" }))
+        .await;
+
+    let result = server
+        .call("list_installed_packages", json!({}))
+        .await
+        .unwrap();
+    let mut installed: Vec<_> = json_content(&result)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|package| {
+            (
+                package["name"].as_str().unwrap().to_string(),
+                package["reason"].as_str().unwrap().to_string(),
+                package["repository"].is_string(),
+            )
+        })
+        .collect();
+    installed.sort();
+    assert_eq!(
+        installed,
+        [
+            ("app".to_string(), "explicit".to_string(), true),
+            ("libapp".to_string(), "dependency".to_string(), true),
+        ]
+    );
+    assert!(
+        result.content[0]
+            .as_text()
+            .unwrap()
+            .text
+            .contains("libapp 1.0-r0")
+    );
+    assert!(
+        result.content[0]
+            .as_text()
+            .unwrap()
+            .text
+            .contains("- dependency")
+    );
+}
+
+#[tokio::test]
+async fn shapes_results_with_the_output_format() {
+    let server = TestServer::start(MockBackend::new()).await;
+    let texts = |result: &rmcp::model::CallToolResult| -> Vec<String> {
+        result
+            .content
+            .iter()
+            .filter_map(|content| content.as_text())
+            .map(|text| text.text.clone())
+            .collect()
+    };
+    assert_eq!(
+        server.tool("search_package").await.input_schema["properties"]["output_format"]["enum"],
+        json!(["text", "json", "summary"])
+    );
+
+    let result = server
+        .call(
+            "install_package",
+            json!({ "package_name": "curl", "output_format": "summary" }),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        texts(&result),
+        ["Package 'curl' version '8.14.1-r1' was installed successfully."]
+    );
+
+    let result = server
+        .call(
+            "install_package",
+            json!({ "package_name": "git", "output_format": "json" }),
+        )
+        .await
+        .unwrap();
+    let content = texts(&result);
+    assert_eq!(content.len(), 1);
+    let outcome: serde_json::Value = serde_json::from_str(&content[0]).unwrap();
+    assert_eq!(outcome["package"], "git");
+
+    let result = server
+        .call("list_repositories", json!({ "output_format": "summary" }))
+        .await
+        .unwrap();
+    let summary = &texts(&result)[0];
+    assert!(
+        summary.starts_with("Configured repositories: ") && summary.ends_with(" entries"),
+        "{summary}"
+    );
+
+    // Results without JSON are wrapped
+    let result = server
+        .call(
+            "search_package",
+            json!({ "query": "no-such-package", "output_format": "json" }),
+        )
+        .await
+        .unwrap();
+    let message: serde_json::Value = serde_json::from_str(&texts(&result)[0]).unwrap();
+    assert!(
+        message["message"]
+            .as_str()
+            .unwrap()
+            .contains("no packages were found")
+    );
+
+    let err = server
+        .call_err(
+            "list_installed_packages",
+            json!({ "output_format": "yaml" }),
+        )
+        .await;
+    assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+    assert_eq!(
+        err.message,
+        "unknown output_format 'yaml', expected text, json or summary"
+    );
+}
+
+#[tokio::test]
+async fn pages_through_installed_packages() {
+    let backend = MockBackend::new()
+        .with_installed(MockPackage::new(
+            "curl",
+            "8.14.1-r1",
+            "URL retrieval utility",
+        ))
+        .with_installed(MockPackage::new("jq", "1.8.0-r0", "JSON processor"));
+    let handler = PackageManagerHandler::new(backend).with_page_size(2);
+    let server = TestServer::start_with_handler(handler).await;
+
+    let mut names = Vec::new();
+    let mut cursor = serde_json::Value::Null;
+    loop {
+        let arguments = match &cursor {
+            serde_json::Value::Null => json!({}),
+            cursor => json!({ "cursor": cursor }),
+        };
+        let result = server
+            .call("list_installed_packages", arguments)
+            .await
+            .unwrap();
+        let blocks: Vec<serde_json::Value> = result
+            .content
+            .iter()
+            .filter_map(|content| serde_json::from_str(&content.as_text()?.text).ok())
+            .collect();
+        let page = blocks[0].as_array().unwrap();
+        assert!(page.len() <= 2);
+        if names.is_empty() {
+            // The next pages come from the listing the first page took
+            server
+                .call_json("install_package", json!({ "package_name": "git" }))
+                .await;
+        }
+        names.extend(page.iter().map(|package| package["name"].clone()));
+        cursor = blocks[1]["next_cursor"].clone();
+        if cursor.is_null() {
+            assert_eq!(blocks[1]["total"], names.len());
+            break;
+        }
+    }
+    assert!(names.len() > 2);
+    assert!(names.contains(&json!("curl")) && names.contains(&json!("jq")));
+    assert!(!names.contains(&json!("git")));
+
+    let all = server
+        .call_json("list_installed_packages", json!({ "limit": 100 }))
+        .await;
+    let all = all.as_array().unwrap();
+    assert!(all.len() > names.len());
+    assert!(all.iter().any(|package| package["name"] == "git"));
+
+    let err = server
+        .call_err("list_installed_packages", json!({ "cursor": "oops" }))
+        .await;
+    assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn exports_every_repository_an_install_used() {
+    let installed = "(1/1) Installing jq (1.8.0-r0)\nOK: 12 MiB in 30 packages\n";
+    let runner = Arc::new(
+        RecordingRunner::new()
+            .with_stdout(&["apk", "add"], installed)
+            .with_stdout(&["apk", "--repositories-file"], installed),
+    );
+    let handler = PackageManagerHandler::new(Apk::with_runner(runner.clone()))
+        .with_untrusted_sources()
+        .with_session_recording()
+        .without_disk_space_check();
+    let server = TestServer::start_with_handler(handler).await;
+
+    server
+        .call_json(
+            "install_package",
+            json!({
+                "package_name": "jq",
+                "repositories": ["https://one.example.com/main", "https://two.example.com/main"],
+            }),
+        )
+        .await;
+    let export = server.call_json("export_session", json!({})).await;
+    assert_eq!(
+        export["script"],
+        "#!/bin/sh\nset -eu\napk add --no-cache --repository https://one.example.com/main --repository https://two.example.com/main jq=1.8.0-r0\n"
+    );
+    assert_eq!(
+        export["packages"][0]["repository"],
+        "https://one.example.com/main"
+    );
+    assert_eq!(
+        export["packages"][0]["repositories"],
+        json!(["https://two.example.com/main"])
+    );
+
+    server
+        .call_json(
+            "install_package",
+            json!({
+                "package_name": "jq",
+                "only_repositories": ["https://three.example.com/main"],
+            }),
+        )
+        .await;
+    let export = server.call_json("export_session", json!({})).await;
+    assert_eq!(
+        export["script"],
+        "#!/bin/sh\nset -eu\napk add --no-cache --repository https://three.example.com/main jq=1.8.0-r0\n"
+    );
+}
+
+#[tokio::test]
+async fn reuses_installed_listings_until_a_mutation_succeeds() {
+    let runner = Arc::new(RecordingRunner::new().with_stdout(
+        &["apk", "list"],
+        "curl-8.14.1-r1 x86_64 {curl} (curl) [installed]\n",
+    ));
+    let handler = PackageManagerHandler::new(Apk::with_runner(runner.clone()))
+        .with_installed_cache(Duration::from_secs(60));
+    let server = TestServer::start_with_handler(handler).await;
+
+    for _ in 0..2 {
+        let packages = server.call_json("list_installed_packages", json!({})).await;
+        assert_eq!(packages[0]["name"], "curl");
+    }
+    server
+        .call_json("install_package", json!({ "package_name": "jq" }))
+        .await;
+    server.call_json("list_installed_packages", json!({})).await;
+
+    let listings = runner
+        .calls()
+        .iter()
+        .filter(|command| command.to_string() == "apk list -I")
+        .count();
+    assert_eq!(listings, 2);
+}
+
+#[tokio::test]
+async fn skipping_maintainer_scripts_must_be_allowed() {
+    let runner = Arc::new(RecordingRunner::new());
+    let handler =
+        PackageManagerHandler::new(Apk::with_runner(runner.clone())).without_disk_space_check();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let install = server.tool("install_package").await;
+    assert!(
+        install.input_schema["properties"]
+            .get("no_scripts")
+            .is_none()
+    );
+    assert!(install.input_schema["properties"].get("no_cache").is_some());
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "jq", "no_scripts": true }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "jq", "no_cache": true, "update_cache": true }),
+        )
+        .await;
+
+    let handler = PackageManagerHandler::new(Apk::with_runner(runner.clone()))
+        .without_disk_space_check()
+        .with_no_scripts_allowed();
+    let server = TestServer::start_with_handler(handler).await;
+    let install = server.tool("install_package").await;
+    assert!(
+        install.input_schema["properties"]
+            .get("no_scripts")
+            .is_some()
+    );
+    server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "jq", "no_scripts": true }),
+        )
+        .await;
+
+    let installs: Vec<String> = runner
+        .calls()
+        .iter()
+        .map(|command| command.to_string())
+        .filter(|command| command.starts_with("apk add"))
+        .collect();
+    assert_eq!(
+        installs,
+        [
+            "apk add --no-cache --update-cache jq",
+            "apk add --no-scripts jq"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn batches_installs_requested_together() {
+    let runner = Arc::new(RecordingRunner::new().with_stdout(
+        &["apk", "add"],
+        "(1/3) Installing oniguruma (6.9.10-r0)\n(2/3) Installing jq (1.8.0-r0)\n(3/3) Installing curl (8.14.1-r1)\nOK: 12 MiB in 30 packages\n",
+    ));
+    let handler = PackageManagerHandler::new(Apk::with_runner(runner.clone()))
+        .with_install_batching(Duration::from_millis(200))
+        .without_disk_space_check();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let (jq, curl) = tokio::join!(
+        server.call_json("install_package", json!({ "package_name": "jq" })),
+        server.call_json("install_package", json!({ "package_name": "curl" })),
+    );
+
+    let commands: Vec<_> = runner
+        .calls()
+        .iter()
+        .map(|command| command.to_string())
+        .collect();
+    assert_eq!(commands.len(), 1, "{commands:?}");
+    assert!(commands[0] == "apk add jq curl" || commands[0] == "apk add curl jq");
+    assert_eq!(jq["version"], "1.8.0-r0");
+    assert_eq!(curl["version"], "8.14.1-r1");
+    // Dependencies are reported once, by the first install of the batch
+    let installed =
+        jq["installed"].as_array().unwrap().len() + curl["installed"].as_array().unwrap().len();
+    assert_eq!(installed, 3);
+}
+
+#[tokio::test]
+async fn batching_still_runs_other_mutating_calls_one_at_a_time() {
+    let backend = MockBackend::new()
+        .with_operation_latency("install_package_with_version", Duration::from_millis(200));
+    let handler =
+        PackageManagerHandler::new(backend).with_install_batching(Duration::from_millis(50));
+    let server = TestServer::start_with_handler(handler).await;
+
+    let started = std::time::Instant::now();
+    tokio::join!(
+        server.call_json(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "8.14.1-r1" }),
+        ),
+        server.call_json(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "8.12.1-r0" }),
+        ),
+    );
+    assert!(started.elapsed() >= Duration::from_millis(400));
+}
+
+#[tokio::test]
+async fn installs_on_local_targets_wait_for_other_mutating_calls() {
+    let backend =
+        MockBackend::new().with_operation_latency("install_package", Duration::from_millis(300));
+    let handler = PackageManagerHandler::new(backend)
+        .with_local_target("local", Arc::new(MockBackend::new()))
+        .with_target("sandbox", Arc::new(MockBackend::new()));
+    let server = TestServer::start_with_handler(handler).await;
+
+    for (target, waits) in [("local", true), ("sandbox", false)] {
+        let started = std::time::Instant::now();
+        let (_, elapsed) = tokio::join!(
+            server.call_json("install_package", json!({ "package_name": "curl" })),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                server
+                    .call_json(
+                        "install_package_on_targets",
+                        json!({ "package_name": "git", "targets": [target] }),
+                    )
+                    .await;
+                started.elapsed()
+            },
+        );
+        assert_eq!(elapsed >= Duration::from_millis(300), waits, "{target}");
+    }
+}
+
+#[tokio::test]
+async fn checks_packages_for_known_vulnerabilities() {
+    let queries = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let router = axum::Router::new()
+        .route(
+            "/v1/querybatch",
+            axum::routing::post({
+                let queries = queries.clone();
+                move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    let results: Vec<_> = body["queries"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|query| {
+                            queries.lock().unwrap().push(query.clone());
+                            match query["package"]["name"].as_str() {
+                                Some("busybox") => json!({ "vulns": [{ "id": "ALPINE-CVE-2025-0001" }] }),
+                                _ => json!({}),
+                            }
+                        })
+                        .collect();
+                    axum::Json(json!({ "results": results }))
+                }
+            }),
+        )
+        .route(
+            "/v1/vulns/ALPINE-CVE-2025-0001",
+            axum::routing::get(|| async {
+                axum::Json(json!({
+                    "id": "ALPINE-CVE-2025-0001",
+                    "aliases": ["CVE-2025-0001"],
+                    "summary": "Heap overflow in the busybox shell",
+                    "database_specific": { "severity": "HIGH" },
+                    "affected": [{
+                        "package": { "name": "busybox", "ecosystem": "Alpine:v3.22" },
+                        "ranges": [{ "type": "ECOSYSTEM", "events": [{ "introduced": "0" }, { "fixed": "1.37.0-r19" }] }]
+                    }, {
+                        "package": { "name": "busybox", "ecosystem": "Alpine:v3.21" },
+                        "ranges": [{ "type": "ECOSYSTEM", "events": [{ "introduced": "0" }, { "fixed": "1.37.0-r13" }] }]
+                    }]
+                }))
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let unconfigured = TestServer::start(MockBackend::new()).await;
+    assert!(
+        !unconfigured
+            .tools()
+            .await
+            .iter()
+            .any(|tool| tool.name == "check_vulnerabilities")
+    );
+
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_vulnerability_database(OsvClient::with_url(api));
+    let server = TestServer::start_with_handler(handler).await;
+
+    let report = server.call_json("check_vulnerabilities", json!({})).await;
+    assert_eq!(report["ecosystem"], "Alpine:v3.22");
+    assert_eq!(report["checked"], 2);
+    assert_eq!(report["packages"][0]["package"], "busybox");
+    assert_eq!(report["packages"][0]["version"], "1.37.0-r18");
+    let vulnerability = &report["packages"][0]["vulnerabilities"][0];
+    assert_eq!(vulnerability["id"], "ALPINE-CVE-2025-0001");
+    assert_eq!(vulnerability["severity"], "HIGH");
+    assert_eq!(vulnerability["fixed_versions"], json!(["1.37.0-r19"]));
+    assert_eq!(
+        queries.lock().unwrap()[0]["package"]["ecosystem"],
+        "Alpine:v3.22"
+    );
+
+    let report = server
+        .call_json(
+            "check_vulnerabilities",
+            json!({ "packages": ["musl", "curl=8.12.1-r0"] }),
+        )
+        .await;
+    assert_eq!(report["checked"], 2);
+    assert_eq!(report["packages"], json!([]));
+    assert_eq!(queries.lock().unwrap()[3]["version"], "8.12.1-r0");
+
+    let err = server
+        .call_err("check_vulnerabilities", json!({ "packages": ["curl"] }))
+        .await;
+    assert_eq!(error_type(&err), "validation_error");
+}
+
+#[tokio::test]
+async fn finds_the_packages_of_a_project_in_other_distributions() {
+    let user_agents = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let router = axum::Router::new().route(
+        "/project/curl",
+        axum::routing::get({
+            let user_agents = user_agents.clone();
+            move |headers: axum::http::HeaderMap| async move {
+                user_agents
+                    .lock()
+                    .unwrap()
+                    .push(headers["user-agent"].to_str().unwrap().to_string());
+                axum::Json(json!([
+                    { "repo": "alpine_3_22", "srcname": "curl", "binname": "curl", "version": "8.14.1", "status": "newest" },
+                    { "repo": "alpine_3_22", "srcname": "curl", "binname": "curl-dev", "version": "8.14.1", "status": "newest" },
+                    { "repo": "debian_12", "srcname": "curl", "binname": "libcurl4-openssl-dev", "version": "7.88.1", "status": "outdated" },
+                    { "repo": "debian_12", "srcname": "curl", "binname": "curl", "version": "7.88.1", "status": "outdated" },
+                    { "repo": "debian_12", "srcname": "curl", "binname": "curl", "version": "7.74.0", "status": "legacy" },
+                    { "repo": "homebrew", "srcname": "curl", "binname": "curl", "version": "8.14.1", "status": "newest" }
+                ]))
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let unconfigured = TestServer::start(MockBackend::new()).await;
+    assert!(
+        !unconfigured
+            .tools()
+            .await
+            .iter()
+            .any(|tool| tool.name == "search_all_distros")
+    );
+
+    let handler =
+        PackageManagerHandler::new(MockBackend::new()).with_repology(RepologyClient::with_url(api));
+    let server = TestServer::start_with_handler(handler).await;
+
+    let found = server
+        .call_json("search_all_distros", json!({ "project": "curl" }))
+        .await;
+    assert_eq!(
+        found["packages"],
+        json!([
+            {
+                "repository": "alpine_3_22",
+                "names": ["curl", "curl-dev"],
+                "source_name": "curl",
+                "version": "8.14.1",
+                "status": "newest"
+            },
+            {
+                "repository": "debian_12",
+                "names": ["libcurl4-openssl-dev", "curl"],
+                "source_name": "curl",
+                "version": "7.88.1",
+                "status": "outdated"
+            }
+        ])
+    );
+    assert!(user_agents.lock().unwrap()[0].starts_with("package-manager-mcp/"));
+
+    let found = server
+        .call_json(
+            "search_all_distros",
+            json!({ "project": "curl", "distros": ["homebrew"] }),
+        )
+        .await;
+    assert_eq!(found["packages"][0]["repository"], "homebrew");
+
+    let err = server
+        .call_err("search_all_distros", json!({ "project": "../curl" }))
+        .await;
+    assert_eq!(error_type(&err), "validation_error");
+}
+
+#[tokio::test]
+async fn blocks_installs_of_versions_with_severe_vulnerabilities() {
+    let router = axum::Router::new()
+        .route(
+            "/v1/querybatch",
+            axum::routing::post(
+                |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    let results: Vec<_> = body["queries"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|query| match query["version"].as_str() {
+                            Some("8.14.1-r1") => json!({ "vulns": [{ "id": "CVE-2025-1000" }] }),
+                            Some("8.12.1-r0") => json!({ "vulns": [{ "id": "CVE-2025-0001" }] }),
+                            _ => json!({}),
+                        })
+                        .collect();
+                    axum::Json(json!({ "results": results }))
+                },
+            ),
+        )
+        .route(
+            "/v1/vulns/CVE-2025-1000",
+            axum::routing::get(|| async {
+                axum::Json(json!({
+                    "id": "CVE-2025-1000",
+                    "summary": "Remote code execution in curl",
+                    "severity": [{ "type": "CVSS_V3", "score": "CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H" }],
+                    "affected": [{
+                        "package": { "name": "curl", "ecosystem": "Alpine:v3.22" },
+                        "ranges": [{ "type": "ECOSYSTEM", "events": [{ "introduced": "0" }] }]
+                    }]
+                }))
+            }),
+        )
+        .route(
+            "/v1/vulns/CVE-2025-0001",
+            axum::routing::get(|| async {
+                axum::Json(json!({
+                    "id": "CVE-2025-0001",
+                    "database_specific": { "severity": "HIGH" },
+                    "affected": [{
+                        "package": { "name": "curl", "ecosystem": "Alpine:v3.22" },
+                        "ranges": [{ "type": "ECOSYSTEM", "events": [{ "introduced": "0" }, { "fixed": "8.14.1-r0" }] }]
+                    }]
+                }))
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_target("sandbox", Arc::new(MockBackend::new()))
+        .with_vulnerability_database(OsvClient::with_url(api.clone()))
+        .with_vulnerability_gate(VulnerabilityGate::new(Severity::Critical).allow_override());
+    let server = TestServer::start_with_handler(handler).await;
+    let tool = server.tool("install_package").await;
+    assert!(tool.input_schema["properties"]["override_vulnerabilities"].is_object());
+
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    let err = server
+        .call_err("install_package", json!({ "package_name": "curl" }))
+        .await;
+    assert_eq!(error_type(&err), "vulnerable_package");
+    assert!(err.message.contains("Pass override_vulnerabilities"));
+    let data = err.data.unwrap();
+    assert_eq!(data["version"], "8.14.1-r1");
+    assert_eq!(data["vulnerabilities"][0]["id"], "CVE-2025-1000");
+    assert_eq!(data["overridable"], true);
+    // A repository tag does not hide the package from the gate
+    let err = server
+        .call_err("install_package", json!({ "package_name": "curl@edge" }))
+        .await;
+    assert_eq!(error_type(&err), "vulnerable_package");
+    // So do installs on targets and from lockfiles
+    let err = server
+        .call_err(
+            "install_package_on_targets",
+            json!({ "package_name": "curl" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "vulnerable_package");
+    let lockfile = json!({
+        "lockfile_version": 1,
+        "backend": "MOCK",
+        "os": "Mock OS",
+        "generated_at": "2026-10-01T00:00:00Z",
+        "packages": [{ "name": "curl", "version": "8.14.1-r1" }]
+    });
+    let err = server
+        .call_err("install_from_lockfile", json!({ "lockfile": lockfile }))
+        .await;
+    assert_eq!(error_type(&err), "vulnerable_package");
+    // High and fixed vulnerabilities are below this gate
+    server
+        .call_json(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "8.12.1-r0" }),
+        )
+        .await;
+    server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "curl", "override_vulnerabilities": true }),
+        )
+        .await;
+
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_vulnerability_database(OsvClient::with_url(api))
+        .with_vulnerability_gate(VulnerabilityGate::new(Severity::High).block_fixed());
+    let server = TestServer::start_with_handler(handler).await;
+    let tool = server.tool("install_package").await;
+    assert!(
+        tool.input_schema["properties"]
+            .get("override_vulnerabilities")
+            .is_none()
+    );
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "<8.14", "override_vulnerabilities": true }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "vulnerable_package");
+    let data = err.data.unwrap();
+    assert_eq!(data["version"], "8.12.1-r0");
+    assert_eq!(
+        data["vulnerabilities"][0]["fixed_versions"],
+        json!(["8.14.1-r0"])
+    );
+    assert_eq!(data["overridable"], false);
+}
+
+#[tokio::test]
+async fn reports_licenses_and_flags_denied_ones() {
+    let backend = MockBackend::new()
+        .with_installed(
+            MockPackage::new("ghostscript", "10.05.1-r0", "PostScript interpreter")
+                .with_license("AGPL-3.0-or-later"),
+        )
+        .with_installed(MockPackage::new("tzdata", "2025b-r0", "Timezone data"));
+    let handler = PackageManagerHandler::new(backend).with_denied_license("agpl");
+    let server = TestServer::start_with_handler(handler).await;
+
+    let result = server.call("license_report", json!({})).await.unwrap();
+    let text = &result.content[0].as_text().unwrap().text;
+    assert!(text.contains("- GPL-2.0-only: 1"), "{text}");
+    assert!(
+        text.contains("- ghostscript 10.05.1-r0: AGPL-3.0-or-later (matches agpl)"),
+        "{text}"
+    );
+    let report = json_content(&result);
+    assert_eq!(report["flagged"], 1);
+    assert_eq!(report["unknown"], 1);
+    assert_eq!(report["licenses"]["MIT"], 1);
+    assert_eq!(report["packages"][0]["name"], "busybox");
+    assert!(report["packages"][0].get("denied").is_none());
+
+    let report = server
+        .call_json("license_report", json!({ "deny": ["GPL-2.0"] }))
+        .await;
+    assert_eq!(report["flagged"], 2);
+    assert_eq!(report["packages"][0]["denied"], json!(["GPL-2.0"]));
+}
+
+#[tokio::test]
+async fn rejects_untrusted_sources_unless_allowed_and_audits_them() {
+    let path = std::env::temp_dir().join(format!(
+        "package-manager-mcp-audit-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_audit_log(AuditLog::open(&path).unwrap());
+    let server = TestServer::start_with_handler(handler).await;
+
+    let err = server
+        .call_err("install_package", json!({ "package_name": "./tool.apk" }))
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "curl", "repository": "mock://main" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_untrusted_sources()
+        .with_audit_log(AuditLog::open(&path).unwrap());
+    let server = TestServer::start_with_handler(handler).await;
+    let outcome = server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "curl", "repository": "mock://main" }),
+        )
+        .await;
+    assert_eq!(outcome["package"], "curl");
+
+    let entries: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0]["tool"], "install_package");
+    assert_eq!(entries[0]["operation"]["package"], "./tool.apk");
+    assert_eq!(entries[0]["untrusted_source"], "./tool.apk");
+    assert_eq!(entries[0]["outcome"], "rejected");
+    assert_eq!(entries[1]["untrusted_source"], "mock://main");
+    assert_eq!(entries[1]["outcome"], "rejected");
+    assert!(entries[2].get("untrusted_source").is_none());
+    assert_eq!(entries[2]["outcome"], "succeeded");
+    assert_eq!(entries[3]["untrusted_source"], "mock://main");
+    assert_eq!(entries[3]["outcome"], "succeeded");
+}
+
+#[tokio::test]
+async fn exports_the_operation_history_as_json_lines() {
+    let path = std::env::temp_dir().join(format!(
+        "package-manager-mcp-history-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_session_recording()
+        .with_audit_log(AuditLog::open(&path).unwrap());
+    let server = TestServer::start_with_handler(handler).await;
+    server
+        .call_json(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "8.14.1-r1" }),
+        )
+        .await;
+    server
+        .call_err("install_package", json!({ "package_name": "./tool.apk" }))
+        .await;
+    server
+        .call_json("search_package", json!({ "query": "git" }))
+        .await;
+
+    let result = server.call("export_history", json!({})).await.unwrap();
+    let export = json_content(&result);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(export["records"], 3);
+    let records: Vec<serde_json::Value> = export["jsonl"]
+        .as_str()
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["sequence"], 1);
+    assert_eq!(records[0]["tool"], "install_package_with_version");
+    assert_eq!(records[0]["outcome"], "succeeded");
+    assert_eq!(records[0]["package"], "curl");
+    assert_eq!(records[0]["version"], "8.14.1-r1");
+    assert_eq!(records[1]["package"], "./tool.apk");
+    assert_eq!(records[1]["outcome"], "rejected");
+    assert_eq!(records[1]["untrusted_source"], "./tool.apk");
+    assert_eq!(records[2]["tool"], "search_package");
+    assert_eq!(records[2]["package"], json!(null));
+    // Every line has the same fields
+    for record in &records {
+        assert_eq!(record.as_object().unwrap().len(), 12, "{record}");
+    }
+}
+
+#[tokio::test]
+async fn chains_and_signs_the_audit_log_and_detects_tampering() {
+    let path = std::env::temp_dir().join(format!(
+        "package-manager-mcp-chained-audit-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let seed = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    let signer = AuditSigner::from_seed_hex(seed).unwrap();
+    let public_key = signer.public_key();
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_audit_log(AuditLog::open(&path).unwrap().with_signer(signer));
+    let server = TestServer::start_with_handler(handler).await;
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    server
+        .call_err("install_package", json!({ "package_name": "./tool.apk" }))
+        .await;
+
+    let export = server.call_json("export_audit_log", json!({})).await;
+    assert_eq!(export["verification"]["valid"], true);
+    assert_eq!(export["verification"]["entries"], 2);
+    assert_eq!(export["verification"]["public_key"], public_key);
+    let entries = export["entries"].as_array().unwrap();
+    assert_eq!(entries[0]["sequence"], 1);
+    assert_eq!(entries[0]["previous_hash"], json!(null));
+    assert_eq!(entries[1]["previous_hash"], entries[0]["hash"]);
+    assert_eq!(export["verification"]["head"], entries[1]["hash"]);
+    assert!(entries[1]["signature"].is_string());
+
+    // Reopening the log continues its chain
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_audit_log(
+        AuditLog::open(&path)
+            .unwrap()
+            .with_signer(AuditSigner::from_seed_hex(seed).unwrap()),
+    );
+    let server = TestServer::start_with_handler(handler).await;
+    let export = server
+        .call_json("export_audit_log", json!({ "from_sequence": 3 }))
+        .await;
+    assert_eq!(export["verification"]["entries"], 3);
+    assert_eq!(export["entries"][0]["tool"], "export_audit_log");
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(verify_chain(&contents, Some(&public_key)).valid);
+    let other_key = AuditSigner::from_seed_hex(&"01".repeat(32))
+        .unwrap()
+        .public_key();
+    let forged = verify_chain(&contents, Some(&other_key));
+    assert!(!forged.valid);
+    assert_eq!(
+        forged.broken_at,
+        Some(ChainBreak {
+            line: 1,
+            problem: ChainProblem::InvalidSignature
+        })
+    );
+
+    std::fs::write(&path, contents.replacen("\"succeeded\"", "\"rejected\"", 1)).unwrap();
+    let export = server.call_json("export_audit_log", json!({})).await;
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(export["verification"]["valid"], false);
+    assert_eq!(export["verification"]["entries"], 0);
+    assert_eq!(
+        export["verification"]["broken_at"],
+        json!({ "line": 1, "problem": "hash_mismatch" })
+    );
+}
+
+#[tokio::test]
+async fn refuses_unsigned_repositories_in_hardening_mode() {
+    let backend = MockBackend::new()
+        .with_package(MockPackage {
+            repository: Some("mock://unsigned".to_string()),
+            ..MockPackage::new("tool", "1.0-r0", "Internal tool")
+        })
+        .with_unsigned_repository("mock://unsigned")
+        .with_unsigned_repository("mock://scratch");
+    let handler = PackageManagerHandler::new(backend).with_signed_repositories_only();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let err = server
+        .call_err("install_package", json!({ "package_name": "git" }))
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    assert!(err.message.contains("mock://unsigned"));
+    let err = server
+        .call_err("search_package", json!({ "query": "curl" }))
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    // Operations not using the repositories still run
+    server.call_json("list_installed_packages", json!({})).await;
+
+    let handler =
+        PackageManagerHandler::new(MockBackend::new().with_unsigned_repository("mock://scratch"))
+            .with_untrusted_sources()
+            .with_signed_repositories_only();
+    let server = TestServer::start_with_handler(handler).await;
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "curl", "repository": "mock://scratch" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    assert!(
+        err.message
+            .contains("mock://scratch: the repository is not signed")
+    );
+
+    let err = ServerBuilder::new()
+        .backend(MockBackend::new().with_unsigned_repository("mock://main"))
+        .signed_repositories_only()
+        .bind("127.0.0.1:0")
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("mock://main"));
+}
+
+#[tokio::test]
+async fn enforces_per_session_quotas() {
+    let backend = MockBackend::new()
+        .with_installed(MockPackage::new(
+            "netcat-openbsd",
+            "1.229.1-r0",
+            "OpenBSD netcat",
+        ))
+        .with_package(
+            MockPackage::new("netcat-traditional", "1.10-r0", "Traditional netcat")
+                .with_conflict("netcat-openbsd")
+                .with_size(120_000),
+        );
+    let handler = PackageManagerHandler::new(backend).with_quotas(
+        QuotaLimits::new()
+            .max_removed_packages(1)
+            .max_downloaded_bytes(1_000_000),
+    );
+    let server = TestServer::start_with_handler(handler).await;
+
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    let outcome = server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "netcat-traditional" }),
+        )
+        .await;
+    assert_eq!(outcome["removed"][0]["name"], "netcat-openbsd");
+    assert_eq!(outcome["downloaded_bytes"], 120_000);
+
+    let err = server
+        .call_err("install_package", json!({ "package_name": "curl" }))
+        .await;
+    assert_eq!(error_type(&err), "quota_exceeded");
+    let data = err.data.unwrap();
+    assert_eq!(data["quota"], "removed_packages");
+    assert_eq!(data["limit"], 1);
+    assert_eq!(data["used"], 1);
+    // Quotas without a window last the whole session
+    assert!(data["reset_at"].is_null());
+
+    // Each session has its own usage
+    let other = server.connect(&[]).await;
+    other
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+
+    // Concurrent calls cannot both take the last install
+    let handler =
+        PackageManagerHandler::new(MockBackend::new().with_latency(Duration::from_millis(200)))
+            .with_quotas(QuotaLimits::new().max_installs(1));
+    let server = TestServer::start_with_handler(handler).await;
+    let (git, curl) = tokio::join!(
+        server.call("install_package", json!({ "package_name": "git" })),
+        server.call("install_package", json!({ "package_name": "curl" })),
+    );
+    let errors: Vec<_> = [git, curl].into_iter().filter_map(Result::err).collect();
+    assert_eq!(errors.len(), 1, "{errors:?}");
+    assert_eq!(error_type(&errors[0]), "quota_exceeded");
+
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_quotas(
+        QuotaLimits::new()
+            .max_installs(1)
+            .per_window(Duration::from_secs(3600)),
+    );
+    let server = TestServer::start_with_handler(handler).await;
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "8.12.1-r0" }),
+        )
+        .await;
+    assert!(
+        err.message
+            .contains("installs quota is exceeded (1 of 1 used)")
+    );
+    let data = err.data.unwrap();
+    assert_eq!(data["quota"], "installs");
+    let reset_at =
+        chrono::DateTime::parse_from_rfc3339(data["reset_at"].as_str().unwrap()).unwrap();
+    assert!(reset_at > chrono::Utc::now() + chrono::Duration::minutes(59));
+    // Operations that do not install are not limited
+    server.call_json("list_installed_packages", json!({})).await;
+}
+
+#[tokio::test]
+async fn asks_the_approval_webhook_before_mutating() {
+    let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let router = axum::Router::new()
+        .route(
+            "/approve",
+            axum::routing::post({
+                let requests = requests.clone();
+                move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    requests.lock().unwrap().push(body.clone());
+                    match body["operation"]["package"].as_str() {
+                        Some("curl") => axum::Json(
+                            json!({ "decision": "deny", "reason": "outside the change window" }),
+                        ),
+                        _ => axum::Json(json!({ "decision": "approve" })),
+                    }
+                }
+            }),
+        )
+        .route(
+            "/slow",
+            axum::routing::post(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                axum::Json(json!({ "decision": "approve" }))
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_approval_webhook(ApprovalWebhook::new(format!("{url}/approve")));
+    let server = TestServer::start_with_handler(handler).await;
+
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    let err = server
+        .call_err("install_package", json!({ "package_name": "curl" }))
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    assert_eq!(
+        err.message,
+        "Permission denied: the approval webhook denied install_package: outside the change window"
+    );
+    // Read-only operations are not sent
+    server
+        .call_json("search_package", json!({ "query": "curl" }))
+        .await;
+    let requests = requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0]["tool"], "install_package");
+    assert_eq!(requests[0]["operation"]["operation"], "install");
+    assert_eq!(requests[0]["operation"]["package"], "git");
+    assert!(requests[0]["requested_at"].is_string());
+
+    let slow = ApprovalWebhook::new(format!("{url}/slow")).with_timeout(Duration::from_millis(100));
+    let server = TestServer::start_with_handler(
+        PackageManagerHandler::new(MockBackend::new()).with_approval_webhook(slow.clone()),
+    )
+    .await;
+    let err = server.call_err("refresh_repositories", json!({})).await;
+    assert!(
+        err.message
+            .contains("the approval webhook could not approve refresh_repositories")
+    );
+
+    let server = TestServer::start_with_handler(
+        PackageManagerHandler::new(MockBackend::new()).with_approval_webhook(slow.fail_open()),
+    )
+    .await;
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+}
+
+#[tokio::test]
+async fn notifies_chat_ops_sinks_of_installs() {
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let router = axum::Router::new().route(
+        "/{sink}",
+        axum::routing::post({
+            let received = received.clone();
+            move |axum::extract::Path(sink): axum::extract::Path<String>,
+                  axum::Json(body): axum::Json<serde_json::Value>| async move {
+                received.lock().unwrap().push((sink, body));
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let backend = Arc::new(MockBackend::new());
+    backend.inject_failure("install_package_with_version", MockFailure::NetworkFailure);
+    let handler = PackageManagerHandler::from_arc(backend)
+        .with_notification_sink(NotificationSink::new(format!("{url}/json")))
+        .with_notification_sink(NotificationSink::slack(format!("{url}/slack")));
+    let server = TestServer::start_with_handler(handler).await;
+
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    server
+        .call_err(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "8.14.1-r1" }),
+        )
+        .await;
+    server
+        .call_err("install_package", json!({ "package_name": "./tool.apk" }))
+        .await;
+    // Read-only operations are not notified
+    server
+        .call_json("search_package", json!({ "query": "curl" }))
+        .await;
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while received.lock().unwrap().len() < 6 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 6);
+    let json: Vec<_> = received
+        .iter()
+        .filter(|(sink, _)| sink == "json")
+        .map(|(_, body)| body.clone())
+        .collect();
+    let slack: Vec<_> = received
+        .iter()
+        .filter(|(sink, _)| sink == "slack")
+        .map(|(_, body)| body["text"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(json.len(), 3);
+
+    let installed = json
+        .iter()
+        .find(|body| body["outcome"] == "succeeded")
+        .unwrap();
+    assert_eq!(installed["tool"], "install_package");
+    assert_eq!(installed["packages"][0]["name"], "git");
+    assert!(installed["packages"][0]["version"].is_string());
+    assert!(installed["session"].is_string());
+    assert!(installed["timestamp"].is_string());
+
+    let failed = json
+        .iter()
+        .find(|body| body["outcome"] == "failed")
+        .unwrap();
+    assert_eq!(
+        failed["packages"],
+        json!([{ "name": "curl", "version": "8.14.1-r1" }])
+    );
+    assert!(failed["error"].is_string());
+    assert_eq!(failed["session"], installed["session"]);
+
+    let rejected = json
+        .iter()
+        .find(|body| body["outcome"] == "rejected")
+        .unwrap();
+    assert_eq!(rejected["packages"][0]["name"], "./tool.apk");
+
+    assert!(
+        slack.iter().any(|text| text.starts_with(
+            "Failed to install `curl` 8.14.1-r1 with `install_package_with_version` (session "
+        )),
+        "{slack:?}"
+    );
+}
+
+#[tokio::test]
+async fn validates_the_arguments_of_every_tool() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let err = server
+        .call_err("install_package", json!({ "package_name": "-oDir=/tmp" }))
+        .await;
+    assert_eq!(error_type(&err), "validation_error");
+    let data = err.data.unwrap();
+    assert_eq!(data["field"], "package_name");
+    assert_eq!(data["package_name"], "-oDir=/tmp");
+    assert_eq!(data["reason"], "must start with a letter or digit");
+
+    let err = server
+        .call_err("search_package", json!({ "query": "x".repeat(200) }))
+        .await;
+    assert_eq!(err.data.unwrap()["field"], "query");
+    let err = server
+        .call_err(
+            "search_package",
+            json!({ "query": "curl", "repository": "https://exa mple.org/alpine" }),
+        )
+        .await;
+    assert_eq!(err.data.unwrap()["field"], "repository");
+    let err = server
+        .call_err("license_report", json!({ "deny": ["AGPL\n"] }))
+        .await;
+    assert_eq!(err.data.unwrap()["field"], "deny");
+}
+
+#[tokio::test]
+async fn restricts_repositories_to_the_allowlist() {
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_allowed_repository("mock://main")
+        .with_allowed_repository("example.com");
+    let server = TestServer::start_with_handler(handler).await;
+
+    let install = server.tool("install_package").await;
+    let description = install.input_schema["properties"]["repository"]["description"]
+        .as_str()
+        .unwrap();
+    assert!(
+        description.contains("mock://main, example.com"),
+        "{description}"
+    );
+
+    // Approved repositories do not need untrusted sources to be allowed
+    let outcome = server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "curl", "repository": "mock://main" }),
+        )
+        .await;
+    assert_eq!(outcome["package"], "curl");
+    server
+        .call(
+            "search_package",
+            json!({ "query": "curl", "repository": "https://mirror.example.com/alpine/edge/main" }),
+        )
+        .await
+        .unwrap();
+
+    for repository in [
+        "https://example.com.evil.net/alpine",
+        "mock://main-evil",
+        "/srv/repo",
+    ] {
+        let err = server
+            .call_err(
+                "search_package",
+                json!({ "query": "curl", "repository": repository }),
+            )
+            .await;
+        assert_eq!(error_type(&err), "permission_denied", "{repository}");
+    }
+}
+
+#[tokio::test]
+async fn rejects_repositories_off_the_internal_mirrors_when_offline() {
+    let offline = OfflineMirrors::new()
+        .mirror(
+            "https://dl-cdn.alpinelinux.org/alpine",
+            "https://mirror.example.com/alpine",
+        )
+        .internal("https://packages.example.com");
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_untrusted_sources()
+        .with_offline_mirrors(offline);
+    let server = TestServer::start_with_handler(handler).await;
+
+    let search = server.tool("search_package").await;
+    let description = search.input_schema["properties"]["repository"]["description"]
+        .as_str()
+        .unwrap();
+    assert!(
+        description.contains("https://mirror.example.com/alpine, https://packages.example.com"),
+        "{description}"
+    );
+
+    // Mirrored public repositories are moved onto their mirror by the backend
+    for repository in [
+        "https://mirror.example.com/alpine/v3.22/main",
+        "https://dl-cdn.alpinelinux.org/alpine/v3.22/community",
+        "https://packages.example.com/tools",
+        "/srv/repo",
+    ] {
+        server
+            .call(
+                "search_package",
+                json!({ "query": "curl", "repository": repository }),
+            )
+            .await
+            .unwrap();
+    }
+
+    for repository in [
+        "https://example.org/alpine/v3.22/main",
+        "https://packages.example.com.evil.net/tools",
+        "deb https://example.org/debian bookworm main",
+        "/etc/apt/sources.list.d/extra.list",
+    ] {
+        let err = server
+            .call_err(
+                "search_package",
+                json!({ "query": "curl", "repository": repository }),
+            )
+            .await;
+        assert_eq!(error_type(&err), "permission_denied", "{repository}");
+        assert!(err.message.contains("offline"), "{}", err.message);
+    }
+}
+
+#[tokio::test]
+async fn generates_a_lockfile_of_the_session_with_its_dependencies() {
+    let backend = MockBackend::empty()
+        .with_package(
+            MockPackage::new("curl", "8.14.1-r1", "URL retrieval utility and library")
+                .with_dependency("libcurl")
+                .with_checksum("Q1hEw9wdKBn2zrEYGA+kHYdeyTOxY="),
+        )
+        .with_package(
+            MockPackage::new(
+                "libcurl",
+                "8.14.1-r1",
+                "The multiprotocol file transfer library",
+            )
+            .with_checksum("Q1nS3S1R5yvvFEivQZqU5SwbC7IKA="),
+        )
+        .with_package(MockPackage::new("libcurl", "8.12.1-r0", "Older libcurl"));
+    let handler = PackageManagerHandler::new(backend).with_session_recording();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let empty = server.call("generate_lockfile", json!({})).await.unwrap();
+    assert_eq!(
+        empty.content[0].as_text().unwrap().text,
+        "No packages were installed in this session."
+    );
+
+    server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+
+    let lockfile = server.call_json("generate_lockfile", json!({})).await;
+    assert_eq!(lockfile["lockfile_version"], 1);
+    assert_eq!(lockfile["backend"], "MOCK");
+    assert_eq!(
+        lockfile["packages"],
+        json!([
+            {
+                "name": "curl",
+                "version": "8.14.1-r1",
+                "source": "mock://main",
+                "checksum": "Q1hEw9wdKBn2zrEYGA+kHYdeyTOxY="
+            },
+            {
+                "name": "libcurl",
+                "version": "8.14.1-r1",
+                "arch": std::env::consts::ARCH,
+                "source": "mock://main",
+                "checksum": "Q1nS3S1R5yvvFEivQZqU5SwbC7IKA=",
+                "dependency": true
+            }
+        ])
+    );
+
+    // Without session recording there is nothing to lock or export
+    let plain = TestServer::start(MockBackend::new()).await;
+    assert!(!plain.tools().await.iter().any(|tool| {
+        [
+            "generate_lockfile",
+            "export_session",
+            "export_dockerfile_snippet",
+            "export_cloud_init",
+        ]
+        .contains(&tool.name.as_ref())
+    }));
+}
+
+#[tokio::test]
+async fn installs_a_lockfile_after_checking_its_versions_and_checksums() {
+    let backend = || {
+        MockBackend::empty()
+            .with_package(
+                MockPackage::new("curl", "8.14.1-r1", "URL retrieval utility and library")
+                    .with_dependency("libcurl")
+                    .with_checksum("Q1hEw9wdKBn2zrEYGA+kHYdeyTOxY="),
+            )
+            .with_package(
+                MockPackage::new(
+                    "libcurl",
+                    "8.14.1-r1",
+                    "The multiprotocol file transfer library",
+                )
+                .with_checksum("Q1nS3S1R5yvvFEivQZqU5SwbC7IKA="),
+            )
+            .with_package(MockPackage::new("libcurl", "8.12.1-r0", "Older libcurl"))
+    };
+    let source = TestServer::start_with_handler(
+        PackageManagerHandler::new(backend()).with_session_recording(),
+    )
+    .await;
+    source
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+    let lockfile = source.call_json("generate_lockfile", json!({})).await;
+
+    let target = TestServer::start(backend()).await;
+    let mut tampered = lockfile.clone();
+    tampered["packages"][0]["checksum"] = json!("Q1tampered");
+    let err = target
+        .call_err("install_from_lockfile", json!({ "lockfile": tampered }))
+        .await;
+    assert_eq!(error_type(&err), "lockfile_mismatch");
+    assert_eq!(
+        err.data.unwrap()["differences"],
+        json!([{
+            "name": "curl",
+            "locked_version": "8.14.1-r1",
+            "problem": "checksum_mismatch",
+            "locked_checksum": "Q1tampered",
+            "found_checksum": "Q1hEw9wdKBn2zrEYGA+kHYdeyTOxY="
+        }])
+    );
+    // Nothing was installed
+    let installed = target.call_json("list_installed_packages", json!({})).await;
+    assert_eq!(installed, json!([]));
+
+    let result = target
+        .call_json(
+            "install_from_lockfile",
+            json!({ "lockfile": lockfile.to_string() }),
+        )
+        .await;
+    assert_eq!(result["verified"], json!(["curl", "libcurl"]));
+    assert_eq!(result["installed"][1]["version"], "8.14.1-r1");
+
+    let mut stale = lockfile.clone();
+    stale["packages"][1]["version"] = json!("8.13.0-r0");
+    let err = target
+        .call_err("install_from_lockfile", json!({ "lockfile": stale }))
+        .await;
+    assert_eq!(error_type(&err), "lockfile_mismatch");
+    assert_eq!(
+        err.data.unwrap()["differences"],
+        json!([{
+            "name": "libcurl",
+            "locked_version": "8.13.0-r0",
+            "installed_version": "8.14.1-r1",
+            "problem": "unavailable"
+        }])
+    );
+
+    let mut foreign = lockfile;
+    foreign["backend"] = json!("APT");
+    let err = target
+        .call_err("install_from_lockfile", json!({ "lockfile": foreign }))
+        .await;
+    assert_eq!(error_type(&err), "validation_error");
+}
+
+#[tokio::test]
+async fn verifies_the_artifact_of_pinned_packages_before_installing() {
+    let old = MockPackage::new("agent", "1.0-r0", "Internal agent").with_signing_key("old.rsa.pub");
+    let current = MockPackage::new("agent", "2.0-r0", "Internal agent")
+        .with_signing_key("builds@example.com.rsa.pub");
+    let pins = PackagePins::new()
+        .pin(PackagePin::new("agent").signing_key("builds@example.com.rsa.pub"))
+        .pin(
+            PackagePin::new("agent")
+                .version("1.0-r0")
+                .sha256("0".repeat(64)),
+        );
+    let backend = MockBackend::new()
+        .with_package(old.clone())
+        .with_package(current.clone());
+    let handler = PackageManagerHandler::new(backend).with_package_pins(pins);
+    let server = TestServer::start_with_handler(handler).await;
+
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({ "package_name": "agent", "version": "1.0-r0" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "artifact_mismatch");
+    let data = err.data.unwrap();
+    assert_eq!(data["version"], "1.0-r0");
+    assert_eq!(data["sha256"], old.sha256());
+    assert_eq!(data["signing_key"], "old.rsa.pub");
+    assert_eq!(data["expected"].as_array().unwrap().len(), 2);
+    let installed = server.call_json("list_installed_packages", json!({})).await;
+    assert!(!installed.to_string().contains("agent"));
+
+    // A repository tag does not get around the pins
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "agent@testing" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    assert!(err.message.contains("agent"));
+    let installed = server.call_json("list_installed_packages", json!({})).await;
+    assert!(!installed.to_string().contains("agent"));
+
+    let result = server
+        .call_json("install_package", json!({ "package_name": "agent" }))
+        .await;
+    assert_eq!(result["version"], "2.0-r0");
+    // Other packages are installed as usual
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+
+    let lockfile = json!({
+        "lockfile_version": 1,
+        "backend": "Mock",
+        "os": "Mock OS",
+        "generated_at": "2026-01-01T00:00:00Z",
+        "packages": [{ "name": "agent", "version": "2.0-r0" }]
+    });
+    let err = server
+        .call_err("install_from_lockfile", json!({ "lockfile": lockfile }))
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    assert!(err.message.contains("agent"));
+
+    // The search cache passes downloads through to the backend
+    let backend = CachingBackend::new(
+        Arc::new(MockBackend::new().with_package(current.clone())),
+        SearchCache::new(Duration::from_secs(60)),
+    );
+    let pins =
+        PackagePins::new().pin(PackagePin::new("agent").signing_key("builds@example.com.rsa.pub"));
+    let handler = PackageManagerHandler::new(backend).with_package_pins(pins);
+    let server = TestServer::start_with_handler(handler).await;
+    let result = server
+        .call_json("install_package", json!({ "package_name": "agent" }))
+        .await;
+    assert_eq!(result["version"], "2.0-r0");
+
+    // Backends that cannot download packages first fail closed
+    let runner = Arc::new(RecordingRunner::new());
+    let pins = PackagePins::new().pin(PackagePin::new("tool").sha256("0".repeat(64)));
+    let backend = GenericBackend::from_toml(
+        r#"
+        name = "TEST"
+        os_name = "Test OS"
+
+        [install]
+        command = ["install-tool", "{package}"]
+        "#,
+    )
+    .unwrap()
+    .with_runner(runner.clone());
+    let handler = PackageManagerHandler::new(backend).with_package_pins(pins);
+    let server = TestServer::start_with_handler(handler).await;
+    let err = server
+        .call_err("install_package", json!({ "package_name": "tool" }))
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    assert!(runner.calls().is_empty());
+
+    // Options the install of the downloaded file would drop are refused
+    let runner = Arc::new(RecordingRunner::new());
+    let pins = PackagePins::new().pin(PackagePin::new("tool").sha256("0".repeat(64)));
+    let handler = PackageManagerHandler::new(Apk::with_runner(runner.clone()))
+        .with_untrusted_sources()
+        .with_package_pins(pins);
+    let server = TestServer::start_with_handler(handler).await;
+    for arguments in [
+        json!({ "package_name": "tool", "only_repositories": ["https://apk.example.com/private"] }),
+        json!({ "package_name": "tool", "repositories": ["https://apk.example.com/private"] }),
+        json!({ "package_name": "tool", "update_cache": true }),
+    ] {
+        let err = server.call_err("install_package", arguments).await;
+        assert_eq!(error_type(&err), "validation_error", "{err:?}");
+    }
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({ "package_name": "tool", "version": "1.0-r0", "no_cache": true }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "validation_error", "{err:?}");
+    assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+}
+
+#[tokio::test]
+async fn reports_the_packages_the_session_changed() {
+    let backend = MockBackend::new()
+        .with_installed(MockPackage::new(
+            "curl",
+            "8.0.0-r0",
+            "URL retrieval utility",
+        ))
+        .with_installed(MockPackage::new("wget", "1.25.0-r0", "Network utility"))
+        .with_package(
+            MockPackage::new("busybox-extras", "1.37.0-r1", "Additional applets")
+                .with_conflict("wget"),
+        );
+    let handler = PackageManagerHandler::new(backend);
+    let server = TestServer::start_with_handler(handler.clone()).await;
+
+    let resources: Vec<_> = server
+        .resources()
+        .await
+        .into_iter()
+        .map(|resource| resource.uri.clone())
+        .collect();
+    assert_eq!(
+        resources,
+        ["state://installed-manifest", "state://session-delta"]
+    );
+    assert_eq!(
+        server.read_json("state://session-delta").await,
+        json!({ "added": [], "removed": [], "changed": [] })
+    );
+
+    server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+    server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "busybox-extras" }),
+        )
+        .await;
+
+    let delta = server.read_json("state://session-delta").await;
+    assert_eq!(delta["added"][0]["name"], "busybox-extras");
+    assert_eq!(delta["added"].as_array().unwrap().len(), 1);
+    assert_eq!(delta["removed"][0]["name"], "wget");
+    assert_eq!(delta["removed"][0]["version"], "1.25.0-r0");
+    assert_eq!(delta["changed"][0]["name"], "curl");
+    assert_eq!(delta["changed"][0]["version"], "8.14.1-r1");
+    assert_eq!(delta["changed"][0]["previous_version"], "8.0.0-r0");
+
+    // A new session starts from the system as it finds it
+    let other = TestServer::start_with_handler(handler).await;
+    assert_eq!(
+        other.read_json("state://session-delta").await,
+        json!({ "added": [], "removed": [], "changed": [] })
+    );
+}
+
+#[tokio::test]
+async fn notifies_subscribers_when_the_installed_packages_drift() {
+    let backend = Arc::new(MockBackend::new());
+    let handler = PackageManagerHandler::from_arc(backend.clone())
+        .with_installed_cache(Duration::from_secs(60))
+        .with_watch_interval(Duration::from_millis(20));
+    let server = TestServer::start_with_handler(handler).await;
+
+    let err = server.subscribe("state://unknown").await.unwrap_err();
+    assert_eq!(err.code, ErrorCode::RESOURCE_NOT_FOUND);
+    server
+        .subscribe("state://installed-manifest")
+        .await
+        .unwrap();
+    assert_eq!(server.next_update(Duration::from_millis(200)).await, None);
+
+    server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+    assert_eq!(
+        server.next_update(Duration::from_secs(5)).await.as_deref(),
+        Some("state://installed-manifest")
+    );
+
+    // Changes made outside the server are reported too, and replace the
+    // cached listing
+    server.read_json("state://installed-manifest").await;
+    backend.mark_installed(MockPackage::new("wget", "1.25.0-r0", "Network utility"));
+    assert_eq!(
+        server.next_update(Duration::from_secs(5)).await.as_deref(),
+        Some("state://installed-manifest")
+    );
+    let manifest = server.read_json("state://installed-manifest").await;
+    assert!(
+        manifest
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|package| package["name"] == "wget")
+    );
+}
+
+#[tokio::test]
+async fn installs_a_specific_version() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let outcome = server
+        .call_json(
+            "install_package_with_version",
+            json!({ "package_name": "python3", "version": "3.12.11-r0" }),
+        )
+        .await;
+    assert_eq!(outcome["version"], "3.12.11-r0");
+}
+
+#[tokio::test]
+async fn installs_for_the_requested_or_default_architecture() {
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_default_arch("riscv64");
+    let server = TestServer::start_with_handler(handler).await;
+
+    let outcome = server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "curl", "arch": "aarch64" }),
+        )
+        .await;
+    assert_eq!(outcome["installed"][0]["architecture"], "aarch64");
+
+    let outcome = server
+        .call_json("install_package", json!({ "package_name": "python3" }))
+        .await;
+    assert_eq!(outcome["installed"][0]["architecture"], "riscv64");
+}
+
+#[tokio::test]
+async fn describes_the_environment_and_policies() {
+    let server = TestServer::start_with_handler(
+        PackageManagerHandler::new(MockBackend::new())
+            .with_allowed_repository("https://mirror.example.com/")
+            .with_signed_repositories_only(),
+    )
+    .await;
+
+    let result = server.call("server_info", json!({})).await.unwrap();
+    let report = json_content(&result);
+    assert_eq!(report["backend"], "MOCK");
+    assert_eq!(report["distro_release"], "Alpine Linux v3.22");
+    assert!(report["package_manager_version"].is_string());
+    assert_eq!(report["can_mutate"], true);
+    assert_eq!(report["privileges"]["can_mutate"], true);
+    assert!(
+        report["repositories"]
+            .as_array()
+            .is_some_and(|r| !r.is_empty())
+    );
+    assert_eq!(
+        report["policies"]["allowed_repositories"],
+        json!(["https://mirror.example.com/"])
+    );
+    assert_eq!(report["policies"]["signed_repositories_only"], true);
+    assert_eq!(report["policies"]["idempotency_keys"], true);
+
+    let summary = &result.content[0].as_text().unwrap().text;
+    assert!(summary.contains("(Alpine Linux v3.22)"), "{summary}");
+    assert!(summary.contains("signed repositories only"), "{summary}");
+
+    let server = TestServer::start(MockBackend::new().read_only()).await;
+    let report = server.call_json("server_info", json!({})).await;
+    assert_eq!(report["can_mutate"], false);
+    assert_eq!(
+        report["privileges"]["problems"],
+        json!(["the mock database is read-only"])
+    );
+}
+
+#[tokio::test]
+async fn diagnoses_the_environment() {
+    let server = TestServer::start(MockBackend::new()).await;
+    let result = server.call("doctor", json!({})).await.unwrap();
+    let report = json_content(&result);
+    let checks: Vec<_> = report["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|check| {
+            (
+                check["name"].as_str().unwrap(),
+                check["status"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    for name in ["package_manager", "lock", "privileges", "signing", "proxy"] {
+        assert!(
+            checks.iter().any(|(check, _)| *check == name),
+            "{name} is missing from {checks:?}"
+        );
+    }
+    assert!(
+        checks
+            .iter()
+            .filter(|(name, _)| *name != "proxy")
+            .all(|(_, status)| *status == "pass"),
+        "{checks:?}"
+    );
+
+    let server = TestServer::start(MockBackend::new().read_only()).await;
+    let result = server.call("doctor", json!({})).await.unwrap();
+    let report = json_content(&result);
+    assert_eq!(report["status"], "fail");
+    let privileges = report["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|check| check["name"] == "privileges")
+        .unwrap();
+    assert_eq!(privileges["status"], "fail");
+    assert!(privileges["remediation"].is_string());
+    let summary = &result.content[0].as_text().unwrap().text;
+    assert!(summary.starts_with("Some checks failed:"), "{summary}");
+    assert!(
+        summary.contains("[FAIL] privileges: the mock database is read-only"),
+        "{summary}"
+    );
+}
+
+#[tokio::test]
+async fn hides_mutating_tools_when_the_server_cannot_modify_the_system() {
+    let server = TestServer::start(MockBackend::new().read_only()).await;
+    assert!(
+        server
+            .tools()
+            .await
+            .iter()
+            .any(|tool| tool.name == "install_package")
+    );
+
+    let report = server.call_json("check_privileges", json!({})).await;
+    assert_eq!(report["can_mutate"], false);
+    assert_eq!(
+        report["problems"],
+        json!(["the mock database is read-only"])
+    );
+
+    let mut names: Vec<_> = server
+        .tools()
+        .await
+        .into_iter()
+        .map(|tool| tool.name.to_string())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "check_privileges",
+            "doctor",
+            "export_apko_config",
+            "license_report",
+            "list_installed_packages",
+            "list_repositories",
+            "list_search_repositories",
+            "resolve_version",
+            "search_package",
+            "server_info",
+        ]
+    );
+    let err = server
+        .call_err("install_package", json!({ "package_name": "curl" }))
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+    assert!(err.message.contains("the mock database is read-only"));
+}
+
+#[tokio::test]
+async fn operates_on_the_root_passed_by_the_call() {
+    let runner = Arc::new(RecordingRunner::new());
+    let server = TestServer::start(Apk::with_runner(runner.clone())).await;
+
+    let install = server.tool("install_package").await;
+    assert!(install.input_schema["properties"]["root"].is_object());
+
+    server
+        .call_json("list_installed_packages", json!({ "root": "/srv/rootfs" }))
+        .await;
+    server.call_json("list_installed_packages", json!({})).await;
+    let commands: Vec<_> = runner
+        .calls()
+        .iter()
+        .map(|command| command.to_string())
+        .collect();
+    assert_eq!(commands, ["apk --root /srv/rootfs list -I", "apk list -I"]);
+
+    let err = server
+        .call_err("list_installed_packages", json!({ "root": "rootfs" }))
+        .await;
+    assert_eq!(error_type(&err), "validation_error");
+
+    let server = TestServer::start(MockBackend::new()).await;
+    let install = server.tool("install_package").await;
+    assert!(install.input_schema["properties"].get("root").is_none());
+    let err = server
+        .call_err("list_installed_packages", json!({ "root": "/srv/rootfs" }))
+        .await;
+    assert_eq!(error_type(&err), "unsupported_operation");
+}
+
+#[tokio::test]
+async fn installs_on_every_target_and_reports_each_result() {
+    let failing = Arc::new(MockBackend::new());
+    failing.inject_failure("install_package", MockFailure::NetworkFailure);
+    let handler = PackageManagerHandler::new(MockBackend::new().read_only())
+        .with_privileges(
+            MockBackend::new()
+                .read_only()
+                .check_privileges()
+                .await
+                .unwrap(),
+        )
+        .with_target("sandbox-1", Arc::new(MockBackend::new()))
+        .with_target("sandbox-2", failing)
+        .with_target("sandbox-3", Arc::new(MockBackend::new()));
+    let mut events = handler.events().subscribe();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let tool = server.tool("install_package_on_targets").await;
+    assert_eq!(
+        tool.input_schema["properties"]["targets"]["items"]["enum"],
+        json!(["sandbox-1", "sandbox-2", "sandbox-3"])
+    );
+
+    let result = server
+        .call(
+            "install_package_on_targets",
+            json!({ "package_name": "curl" }),
+        )
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+    let results = json_content(&result);
+    assert_eq!(results[0]["target"], "sandbox-1");
+    assert_eq!(results[0]["success"], true);
+    assert_eq!(results[0]["outcome"]["version"], "8.14.1-r1");
+    assert_eq!(results[1]["target"], "sandbox-2");
+    assert_eq!(results[1]["error"]["error_type"], "network_failure");
+    assert_eq!(results[2]["success"], true);
+
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("no event was published")
+        .unwrap();
+    assert_eq!(event.target.as_deref(), Some("sandbox-1"));
+
+    let results = server
+        .call_json(
+            "install_package_on_targets",
+            json!({ "package_name": "curl", "version": "8.12.1-r0", "targets": ["sandbox-3"] }),
+        )
+        .await;
+    assert_eq!(results.as_array().unwrap().len(), 1);
+    assert_eq!(results[0]["outcome"]["version"], "8.12.1-r0");
+
+    let err = server
+        .call_err(
+            "install_package_on_targets",
+            json!({ "package_name": "curl", "targets": ["sandbox-4"] }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "validation_error");
+}
+
+#[tokio::test]
+async fn exports_the_packages_installed_in_the_session() {
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_session_recording();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let empty = server.call("export_session", json!({})).await.unwrap();
+    assert_eq!(
+        empty.content[0].as_text().unwrap().text,
+        "No packages were installed in this session."
+    );
+
+    server
+        .call_json(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "8.12.1-r0" }),
+        )
+        .await;
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+
+    let export = server.call_json("export_session", json!({})).await;
+    assert_eq!(
+        export["script"],
+        "#!/bin/sh\nset -eu\napk add --no-cache git=2.49.1-r0 curl=8.14.1-r1\n"
+    );
+    assert_eq!(export["packages"][1]["version"], "8.14.1-r1");
+
+    let export = server
+        .call_json("export_session", json!({ "format": "dockerfile" }))
+        .await;
+    assert_eq!(
+        export["script"],
+        "RUN apk add --no-cache git=2.49.1-r0 curl=8.14.1-r1\n"
+    );
+
+    let export = server
+        .call_json("export_session", json!({ "format": "ansible" }))
+        .await;
+    assert!(
+        export["script"]
+            .as_str()
+            .unwrap()
+            .ends_with("  community.general.apk:\n    name:\n      - git=2.49.1-r0\n      - curl=8.14.1-r1\n    update_cache: true\n")
+    );
+
+    let document = server
+        .call_json("export_session", json!({ "format": "spdx" }))
+        .await;
+    assert_eq!(document["spdxVersion"], "SPDX-2.3");
+    assert_eq!(document["packages"][1]["name"], "curl");
+    assert_eq!(document["packages"][1]["versionInfo"], "8.14.1-r1");
+    assert_eq!(
+        document["packages"][1]["externalRefs"][0]["referenceLocator"],
+        "pkg:apk/alpine/curl@8.14.1-r1"
+    );
+    assert_eq!(
+        document["relationships"][1]["relatedSpdxElement"],
+        document["packages"][1]["SPDXID"]
+    );
+
+    // Another client of the same server has its own session
+    let other = server.connect(&[]).await;
+    let result = other.call("export_session", json!({})).await.unwrap();
+    assert!(
+        result.content[0]
+            .as_text()
+            .unwrap()
+            .text
+            .starts_with("No packages")
+    );
+}
+
+#[tokio::test]
+async fn exports_the_session_as_a_dockerfile_snippet() {
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_session_recording();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let empty = server
+        .call("export_dockerfile_snippet", json!({}))
+        .await
+        .unwrap();
+    assert_eq!(
+        empty.content[0].as_text().unwrap().text,
+        "No packages were installed in this session."
+    );
+
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+
+    let export = server
+        .call_json("export_dockerfile_snippet", json!({}))
+        .await;
+    assert_eq!(
+        export["snippet"],
+        "RUN apk add --no-cache \\\n    git=2.49.1-r0 \\\n    curl=8.14.1-r1\n"
+    );
+    assert_eq!(export["instructions"].as_array().unwrap().len(), 1);
+    assert_eq!(export["packages"][0]["name"], "git");
+}
+
+#[tokio::test]
+async fn exports_the_session_as_cloud_init_user_data() {
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_session_recording();
+    let server = TestServer::start_with_handler(handler).await;
+
+    server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+    let export = server.call_json("export_cloud_init", json!({})).await;
+    assert_eq!(
+        export["yaml"],
+        "#cloud-config\npackage_update: true\npackages:\n  - [curl, 8.14.1-r1]\n"
+    );
+    assert_eq!(export["config"]["packages"], json!([["curl", "8.14.1-r1"]]));
+}
+
+#[tokio::test]
+async fn exports_packages_as_an_apko_configuration() {
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_session_recording();
+    let server = TestServer::start_with_handler(handler).await;
+
+    let empty = server.call("export_apko_config", json!({})).await.unwrap();
+    assert_eq!(
+        empty.content[0].as_text().unwrap().text,
+        "No packages were installed in this session."
+    );
+
+    server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+    let export = server.call_json("export_apko_config", json!({})).await;
+    assert_eq!(export["scope"], "session");
+    assert_eq!(export["config"]["repositories"], json!(["mock://main"]));
+    assert_eq!(export["config"]["packages"], json!(["curl=8.14.1-r1"]));
+    assert!(
+        export["yaml"]
+            .as_str()
+            .unwrap()
+            .contains("  packages:\n    - curl=8.14.1-r1\n")
+    );
+
+    // Without session recording, the installed packages are exported
+    let plain = TestServer::start(MockBackend::new()).await;
+    plain
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    let export = plain.call_json("export_apko_config", json!({})).await;
+    assert_eq!(export["scope"], "installed");
+    assert!(
+        export["config"]["packages"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("git=2.49.1-r0"))
+    );
+    let err = plain
+        .call("export_apko_config", json!({ "scope": "session" }))
+        .await
+        .unwrap_err();
+    assert_eq!(error_type(&err), "validation_error");
+}
+
+#[tokio::test]
+async fn installs_the_newest_version_matching_a_constraint() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let outcome = server
+        .call_json(
+            "install_package_with_version",
+            json!({ "package_name": "python3", "version": "<3.13" }),
+        )
+        .await;
+    assert_eq!(outcome["version"], "3.12.11-r0");
+}
+
+#[tokio::test]
+async fn installs_the_newest_version_starting_with_a_partial_version() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let result = server
+        .call(
+            "install_package_with_version",
+            json!({ "package_name": "python3", "version": "3.12" }),
+        )
+        .await
+        .unwrap();
+    assert!(result.content[0].as_text().unwrap().text.contains(
+        "version '3.12.11-r0' was installed successfully, the newest version matching '3.12'"
+    ));
+    assert_eq!(json_content(&result)["version"], "3.12.11-r0");
+}
+
+#[tokio::test]
+async fn resolves_versions_without_installing_them() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let resolved = server
+        .call_json(
+            "resolve_version",
+            json!({ "package_name": "python3", "version": "~3.12" }),
+        )
+        .await;
+    assert_eq!(
+        resolved,
+        json!({
+            "package": "python3",
+            "version": "3.12.11-r0",
+            "repositories": ["mock://main"]
+        })
+    );
+    let installed = server.call_json("list_installed_packages", json!({})).await;
+    assert!(!installed.to_string().contains("python3"), "{installed}");
+
+    let err = server
+        .call_err(
+            "resolve_version",
+            json!({ "package_name": "python3", "version": ">=4" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "version_not_found");
+    assert_eq!(
+        err.data.unwrap()["available_versions"],
+        json!(["3.12.11-r0", "3.13.5-r0"])
+    );
+}
+
+#[tokio::test]
+async fn installs_several_versions_in_one_transaction() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let outcomes = server
+        .call_json(
+            "install_package_with_version",
+            json!({
+                "packages": [
+                    { "package": "python3", "version": "<3.13" },
+                    { "package": "curl", "version": "8.12.1-r0" }
+                ]
+            }),
+        )
+        .await;
+    let versions: Vec<_> = outcomes
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|outcome| {
+            (
+                outcome["package"].as_str().unwrap(),
+                outcome["version"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(versions, [("python3", "3.12.11-r0"), ("curl", "8.12.1-r0")]);
+
+    // Nothing is installed when a version cannot be found
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({
+                "packages": [
+                    { "package": "git", "version": "2.49.1-r0" },
+                    { "package": "curl", "version": "1.0" }
+                ]
+            }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "version_not_found");
+    let installed = server.call_json("list_installed_packages", json!({})).await;
+    assert!(!installed.to_string().contains("\"git\""), "{installed}");
+
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({
+                "package_name": "git",
+                "version": "2.49.1-r0",
+                "packages": [{ "package": "curl", "version": "8.12.1-r0" }]
+            }),
+        )
+        .await;
+    assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({
+                "packages": [
+                    { "package": "curl", "version": "8.12.1-r0" },
+                    { "package": "curl", "version": "8.14.1-r1" }
+                ]
+            }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "validation_error");
+}
+
+#[tokio::test]
+async fn lists_repositories() {
+    let backend = MockBackend::empty().with_package(MockPackage {
+        repository: Some("mock://testing".to_string()),
+        ..MockPackage::new("hello", "1.0-r0", "Hello world")
+    });
+    let server = TestServer::start(backend).await;
+
+    let repositories = server.call_json("list_repositories", json!({})).await;
+    assert_eq!(
+        repositories,
+        json!([
+            { "url": "mock://main", "enabled": true },
+            { "url": "mock://testing", "enabled": true }
+        ])
+    );
+}
+
+#[tokio::test]
+async fn lists_searched_repositories() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let repositories = server
+        .call_json("list_search_repositories", json!({}))
+        .await;
+    assert_eq!(repositories[0]["url"], "mock://main");
+}
+
+#[tokio::test]
+async fn searches_packages() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let results = server
+        .call_json("search_package", json!({ "query": "python" }))
+        .await;
+    let versions: Vec<_> = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["version"].as_str().unwrap())
+        .collect();
+    assert_eq!(versions, ["3.12.11-r0", "3.13.5-r0"]);
+}
+
+#[tokio::test]
+async fn searches_packages_in_each_mode() {
+    let server = TestServer::start(MockBackend::new()).await;
+    let names = |results: serde_json::Value| -> Vec<String> {
+        let mut names: Vec<String> = results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["name"].as_str().unwrap().to_string())
+            .collect();
+        names.dedup();
+        names
+    };
+
+    for (arguments, expected) in [
+        (json!({ "query": "python" }), vec!["python3"]),
+        (
+            json!({ "query": "python3", "mode": "exact" }),
+            vec!["python3"],
+        ),
+        (json!({ "query": "*t", "mode": "prefix" }), vec!["git"]),
+        (json!({ "query": "ur", "mode": "wildcard" }), vec!["curl"]),
+        (json!({ "query": "g?t", "mode": "wildcard" }), vec!["git"]),
+        (
+            json!({ "query": "Version Control", "mode": "description" }),
+            vec!["git"],
+        ),
+        (
+            json!({ "query": "Version Control", "search_descriptions": true }),
+            vec!["git"],
+        ),
+        (
+            json!({ "query": "git", "search_descriptions": false }),
+            vec!["git"],
+        ),
+    ] {
+        let results = server.call_json("search_package", arguments.clone()).await;
+        assert_eq!(names(results), expected, "{arguments}");
+    }
+
+    let result = server
+        .call(
+            "search_package",
+            json!({ "query": "python", "mode": "exact" }),
+        )
+        .await
+        .unwrap();
+    assert!(
+        result.content[0]
+            .as_text()
+            .unwrap()
+            .text
+            .contains("no packages were found")
+    );
+
+    let err = server
+        .call_err(
+            "search_package",
+            json!({ "query": "curl", "mode": "fuzzy" }),
+        )
+        .await;
+    assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+
+    let err = server
+        .call_err(
+            "search_package",
+            json!({ "query": "curl", "mode": "exact", "search_descriptions": true }),
+        )
+        .await;
+    assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn limits_sorts_and_filters_search_results_by_branch() {
+    let package = |name: &str, version: &str, branch: &str| MockPackage {
+        repository: Some(format!(
+            "https://dl-cdn.alpinelinux.org/alpine/{branch}/community"
+        )),
+        ..MockPackage::new(name, version, "Python module")
+    };
+    let server = TestServer::start(
+        MockBackend::empty()
+            .with_package(package("py3-a", "1.0-r0", "v3.21"))
+            .with_package(package("py3-c", "0.9-r0", "edge"))
+            .with_package(package("py3-a", "1.10-r0", "v3.22"))
+            .with_package(package("py3-b", "2.0-r0", "v3.22")),
+    )
+    .await;
+    let listed = |results: serde_json::Value| -> Vec<String> {
+        results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| format!("{} {}", result["name"], result["version"]).replace('"', ""))
+            .collect()
+    };
+
+    for (arguments, expected) in [
+        (
+            json!({ "query": "py3-", "sort": "name" }),
+            vec![
+                "py3-a 1.10-r0",
+                "py3-a 1.0-r0",
+                "py3-b 2.0-r0",
+                "py3-c 0.9-r0",
+            ],
+        ),
+        (
+            json!({ "query": "py3-", "branch": "v3.22" }),
+            vec!["py3-a 1.10-r0", "py3-b 2.0-r0"],
+        ),
+        (
+            json!({ "query": "py3-", "suite": "3.21" }),
+            vec!["py3-a 1.0-r0"],
+        ),
+    ] {
+        let results = server.call_json("search_package", arguments.clone()).await;
+        assert_eq!(listed(results), expected, "{arguments}");
+    }
+
+    let result = server
+        .call(
+            "search_package",
+            json!({ "query": "py3-", "sort": "version", "limit": 2 }),
+        )
+        .await
+        .unwrap();
+    assert!(result.content[0].as_text().unwrap().text.starts_with(
+        "Search results for query 'py3-' (the first 2 of 4; raise limit to see more):"
+    ));
+    assert_eq!(
+        listed(json_content(&result)),
+        ["py3-b 2.0-r0", "py3-a 1.10-r0"]
+    );
+
+    let result = server
+        .call(
+            "search_package",
+            json!({ "query": "py3-a", "branch": "edge" }),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        result.content[0].as_text().unwrap().text,
+        "Search completed for query 'py3-a' but no packages were found in 'edge'."
+    );
+
+    for arguments in [
+        json!({ "query": "py3-", "limit": 0 }),
+        json!({ "query": "py3-", "sort": "popularity" }),
+    ] {
+        let err = server.call_err("search_package", arguments).await;
+        assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+    }
+}
+
+#[tokio::test]
+async fn retries_empty_searches_with_normalized_queries() {
+    let server = TestServer::start(
+        MockBackend::new()
+            .with_package(MockPackage::new(
+                "go",
+                "1.24.4-r0",
+                "Go programming language",
+            ))
+            .with_package(MockPackage::new(
+                "py3-setuptools",
+                "80.9.0-r0",
+                "Easily build and distribute Python 3 packages",
+            )),
+    )
+    .await;
+
+    for (query, matched, expected) in [
+        ("CURL", "curl", "curl"),
+        ("py3_setuptools", "py3-setuptools", "py3-setuptools"),
+        ("golang", "go", "go"),
+    ] {
+        let result = server
+            .call("search_package", json!({ "query": query }))
+            .await
+            .unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        assert!(
+            text.starts_with(&format!(
+                "No packages matched query '{query}'. Search results for query '{matched}':"
+            )),
+            "{text}"
+        );
+        assert_eq!(json_content(&result)[0]["name"], expected);
+    }
+
+    let result = server
+        .call(
+            "search_package",
+            json!({ "query": "CURL", "mode": "exact" }),
+        )
+        .await
+        .unwrap();
+    assert!(
+        result.content[0]
+            .as_text()
+            .unwrap()
+            .text
+            .contains("no packages were found")
+    );
+}
+
+#[tokio::test]
+async fn reports_missing_packages() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let err = server
+        .call_err("install_package", json!({ "package_name": "nonexistent" }))
+        .await;
+    assert_eq!(error_type(&err), "package_not_found");
+}
+
+#[tokio::test]
+async fn reports_available_versions_when_version_is_missing() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let err = server
+        .call_err(
+            "install_package_with_version",
+            json!({ "package_name": "curl", "version": "1.0" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "version_not_found");
+    let data = err.data.unwrap();
+    assert_eq!(data["requested_version"], "1.0");
+    assert_eq!(
+        data["available_versions"],
+        json!(["8.12.1-r0", "8.14.1-r1"])
+    );
+}
+
+#[tokio::test]
+async fn reports_injected_failures() {
+    let backend = Arc::new(MockBackend::new());
+    let server =
+        TestServer::start_with_handler(PackageManagerHandler::from_arc(backend.clone())).await;
+
+    backend.inject_failure("refresh_repositories", MockFailure::LockHeld);
+    let err = server.call_err("refresh_repositories", json!({})).await;
+    assert_eq!(error_type(&err), "lock_held");
+
+    backend.inject_failure(
+        "install_package",
+        MockFailure::CommandFailed { exit_code: 100 },
+    );
+    let err = server
+        .call_err("install_package", json!({ "package_name": "git" }))
+        .await;
+    assert_eq!(err.data.unwrap()["exit_code"], 100);
+
+    backend.clear_failures();
+    let result = server
+        .call("refresh_repositories", json!({}))
+        .await
+        .unwrap();
+    assert_ne!(result.is_error, Some(true));
+}
+
+#[tokio::test]
+async fn runs_mutating_calls_as_background_jobs() {
+    let backend =
+        MockBackend::new().with_operation_latency("install_package", Duration::from_millis(200));
+    let server = TestServer::start(backend).await;
+
+    let started = server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "curl", "async": true }),
+        )
+        .await;
+    assert_eq!(started["status"], "running");
+    let job_id = started["job_id"].as_str().unwrap();
+    let status = server
+        .call_json("get_job_status", json!({ "job_id": job_id }))
+        .await;
+    assert_eq!(status["status"], "running");
+    assert_eq!(status["tool"], "install_package");
+
+    let result = loop {
+        let result = server
+            .call("get_job_status", json!({ "job_id": job_id }))
+            .await
+            .unwrap();
+        if json_content(&result)["status"] != "running" {
+            break result;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    let status = json_content(&result);
+    assert_eq!(status["status"], "succeeded");
+    assert!(status["output_lines"].as_u64().unwrap() > 0);
+    // The result of the install follows the status
+    let outcome: serde_json::Value = result.content[2..]
+        .iter()
+        .find_map(|content| serde_json::from_str(&content.as_text()?.text).ok())
+        .unwrap();
+    assert_eq!(outcome["version"], "8.14.1-r1");
+
+    let output = server
+        .call_json("get_job_output", json!({ "job_id": job_id }))
+        .await;
+    let lines = output["lines"].as_array().unwrap();
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.as_str().unwrap().contains("Installing curl")),
+        "{lines:?}"
+    );
+    assert_eq!(output["next_offset"], lines.len());
+    let rest = server
+        .call_json(
+            "get_job_output",
+            json!({ "job_id": job_id, "offset": lines.len() }),
+        )
+        .await;
+    assert_eq!(rest["lines"], json!([]));
+}
+
+#[tokio::test]
+async fn cancels_background_jobs() {
+    let backend =
+        MockBackend::new().with_operation_latency("install_package", Duration::from_secs(30));
+    let server = TestServer::start(backend).await;
+
+    let started = server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "curl", "async": true }),
+        )
+        .await;
+    let running = started["job_id"].as_str().unwrap();
+    // Stopping the local process would not stop a remote package manager
+    let err = server
+        .call_err("cancel_job", json!({ "job_id": running }))
+        .await;
+    assert_eq!(error_type(&err), "job_running");
+
+    let queued = server
+        .call_json(
+            "install_package",
+            json!({ "package_name": "git", "async": true }),
+        )
+        .await;
+    assert_eq!(queued["status"], "queued");
+    let job_id = queued["job_id"].as_str().unwrap();
+    // Jobs of anonymous calls belong to their session
+    let other = server.connect(&[]).await;
+    for tool in ["get_job_status", "get_job_output", "cancel_job"] {
+        let err = other.call_err(tool, json!({ "job_id": job_id })).await;
+        assert_eq!(error_type(&err), "job_not_found");
+    }
+    let cancelled = server
+        .call_json("cancel_job", json!({ "job_id": job_id }))
+        .await;
+    assert_eq!(cancelled["status"], "cancelled");
+    let status = server
+        .call_json("get_job_status", json!({ "job_id": job_id }))
+        .await;
+    assert_eq!(status["status"], "cancelled");
+    let status = server
+        .call_json("get_job_status", json!({ "job_id": running }))
+        .await;
+    assert_eq!(status["status"], "running");
+
+    let err = server
+        .call_err("get_job_status", json!({ "job_id": "job-999" }))
+        .await;
+    assert_eq!(error_type(&err), "job_not_found");
+    assert_eq!(err.data.unwrap()["error_code"], "not_found");
+}
+
+#[tokio::test]
+async fn queues_the_jobs_of_a_session_in_order() {
+    let backend =
+        MockBackend::new().with_operation_latency("install_package", Duration::from_millis(200));
+    let server = TestServer::start(backend).await;
+
+    let mut job_ids = Vec::new();
+    for (position, package) in ["curl", "git", "python3"].into_iter().enumerate() {
+        let started = server
+            .call_json(
+                "install_package",
+                json!({ "package_name": package, "async": true }),
+            )
+            .await;
+        if position == 0 {
+            assert_eq!(started["status"], "running");
+        } else {
+            assert_eq!(started["status"], "queued");
+            assert_eq!(started["queue_position"], position);
+        }
+        job_ids.push(started["job_id"].as_str().unwrap().to_string());
+    }
+    let status = server
+        .call_json("get_job_status", json!({ "job_id": job_ids[2] }))
+        .await;
+    assert_eq!(status["status"], "queued");
+    assert_eq!(status["queue_position"], 2);
+
+    let mut finished = Vec::new();
+    for job_id in &job_ids {
+        let status = loop {
+            let status = server
+                .call_json("get_job_status", json!({ "job_id": job_id }))
+                .await;
+            if !matches!(status["status"].as_str(), Some("queued" | "running")) {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(status["status"], "succeeded");
+        assert!(status.get("queue_position").is_none());
+        let finished_at: chrono::DateTime<chrono::Utc> =
+            serde_json::from_value(status["finished_at"].clone()).unwrap();
+        finished.push(finished_at);
+    }
+    // Each install started once the previous one finished
+    assert!(
+        finished
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] >= chrono::Duration::milliseconds(150)),
+        "{finished:?}"
+    );
+}
+
+#[tokio::test]
+async fn keeps_the_output_of_mutating_operations_in_logs() {
+    let dir = std::env::temp_dir().join(format!(
+        "package-manager-mcp-operation-logs-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let logs = OperationLogStore::open(&dir).unwrap().with_max_logs(2);
+    let handler = PackageManagerHandler::new(MockBackend::new()).with_operation_logs(logs);
+    let server = TestServer::start_with_handler(handler).await;
+    let operation_id = |result: &rmcp::model::CallToolResult| {
+        let note = &result.content.last().unwrap().as_text().unwrap().text;
+        note.split('\'').nth(1).unwrap().to_string()
+    };
+
+    let installed = server
+        .call("install_package", json!({ "package_name": "git" }))
+        .await
+        .unwrap();
+    let first = operation_id(&installed);
+    let log = server
+        .call_json("get_operation_log", json!({ "operation_id": first }))
+        .await;
+    assert_eq!(log["tool"], "install_package");
+    assert_eq!(log["outcome"], "succeeded");
+    assert!(
+        log["lines"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|line| line["stream"] == "stdout"
+                && line["line"].as_str().unwrap().contains("Installing git")),
+        "{log}"
+    );
+    // Logs hold what the package manager printed, for the server's user only
+    let mode = |path: &std::path::Path| {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    };
+    assert_eq!(mode(&dir), 0o700);
+    assert_eq!(mode(&dir.join(format!("{first}.json"))), 0o600);
+    // Read-only calls are not logged
+    server
+        .call_json("search_package", json!({ "query": "curl" }))
+        .await;
+
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "no-such-package" }),
+        )
+        .await;
+    let second = err.data.unwrap()["operation_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let uri = format!("operation-log://{second}");
+    let log = server.read_json(&uri).await;
+    assert_eq!(log["outcome"], "failed");
+    assert!(log["error"].as_str().unwrap().contains("no-such-package"));
+    let resources: Vec<String> = server
+        .resources()
+        .await
+        .into_iter()
+        .map(|resource| resource.uri.clone())
+        .collect();
+    assert!(resources.contains(&uri), "{resources:?}");
+
+    // Only the last two logs are kept
+    server
+        .call_json("install_package", json!({ "package_name": "curl" }))
+        .await;
+    let err = server
+        .call_err("get_operation_log", json!({ "operation_id": first }))
+        .await;
+    assert_eq!(error_type(&err), "operation_log_not_found");
+    let err = server
+        .call_err(
+            "get_operation_log",
+            json!({ "operation_id": "../operation-logs" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "operation_log_not_found");
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn lists_only_the_operation_logs_of_the_client() {
+    let dir = std::env::temp_dir().join(format!(
+        "package-manager-mcp-client-operation-logs-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let server = ServerBuilder::new()
+        .backend(MockBackend::new())
+        .with_auth_subject("alice", "alice-token")
+        .with_auth_subject("bob", "bob-token")
+        .operation_logs(OperationLogStore::open(&dir).unwrap())
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let alice = TestServer::serve(server, &[("Authorization", "Bearer alice-token")]).await;
+    let bob = alice
+        .connect(&[("Authorization", "Bearer bob-token")])
+        .await;
+    let logs = |resources: Vec<rmcp::model::Resource>| -> Vec<String> {
+        resources
+            .into_iter()
+            .map(|resource| resource.uri.clone())
+            .filter(|uri| uri.starts_with("operation-log://"))
+            .collect()
+    };
+
+    alice
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+    assert_eq!(logs(alice.resources().await).len(), 1);
+    assert_eq!(logs(bob.resources().await), Vec::<String>::new());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn classifies_failures_with_an_error_code() {
+    let backend = Arc::new(MockBackend::new());
+    let server =
+        TestServer::start_with_handler(PackageManagerHandler::from_arc(backend.clone())).await;
+
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "no-such-package" }),
+        )
+        .await;
+    assert_eq!(err.data.unwrap()["error_code"], "not_found");
+
+    backend.inject_failure("refresh_repositories", MockFailure::NetworkFailure);
+    let err = server.call_err("refresh_repositories", json!({})).await;
+    assert_eq!(err.data.unwrap()["error_code"], "network_failure");
+
+    backend.inject_failure(
+        "install_package",
+        MockFailure::CommandFailed { exit_code: 1 },
+    );
+    let err = server
+        .call_err("install_package", json!({ "package_name": "git" }))
+        .await;
+    assert_eq!(err.data.unwrap()["error_code"], "unknown");
+
+    backend.inject_failure(
+        "install_package",
+        MockFailure::CommandFailed { exit_code: 126 },
+    );
+    let err = server
+        .call_err("install_package", json!({ "package_name": "git" }))
+        .await;
+    let data = err.data.unwrap();
+    assert_eq!(data["exit_code"], 126);
+    assert_eq!(data["exit_meaning"], "the command could not be executed");
+    assert_eq!(data["error_code"], "permission_denied");
+    assert!(data["suggestion"].is_string());
+
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "Not A Package" }),
+        )
+        .await;
+    assert_eq!(err.data.unwrap()["error_code"], "invalid_argument");
+}
+
+#[tokio::test]
+async fn replays_mutating_calls_retried_with_the_same_idempotency_key() {
+    let backend = Arc::new(MockBackend::new());
+    let server =
+        TestServer::start_with_handler(PackageManagerHandler::from_arc(backend.clone())).await;
+    let install = json!({ "package_name": "git", "idempotency_key": "install-git-1" });
+
+    backend.inject_failure("install_package", MockFailure::NetworkFailure);
+    let err = server.call_err("install_package", install.clone()).await;
+    assert_eq!(error_type(&err), "network_failure");
+
+    // Failures are not stored, so the retry runs
+    backend.clear_failures();
+    let installed = server
+        .call("install_package", install.clone())
+        .await
+        .unwrap();
+    assert_ne!(installed.is_error, Some(true));
+
+    // Successes are replayed without running the package manager again
+    backend.inject_failure("install_package", MockFailure::NetworkFailure);
+    let replayed = server.call("install_package", install).await.unwrap();
+    assert_eq!(replayed, installed);
+
+    let err = server
+        .call_err(
+            "install_package",
+            json!({ "package_name": "curl", "idempotency_key": "install-git-1" }),
+        )
+        .await;
+    assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+    assert!(err.message.contains("idempotency_key"), "{}", err.message);
+
+    let err = server
+        .call_err("install_package", json!({ "package_name": "git" }))
+        .await;
+    assert_eq!(error_type(&err), "network_failure");
+
+    // Anonymous keys belong to their session: another client runs its call
+    let other = server.connect(&[]).await;
+    let err = other
+        .call_err(
+            "install_package",
+            json!({ "package_name": "git", "idempotency_key": "install-git-1" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "network_failure");
+}
+
+#[tokio::test]
+async fn rejects_missing_parameters() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let err = server.call_err("install_package", json!({})).await;
+    assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn reports_unknown_tools_as_tool_errors() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let result = server.call("remove_package", json!({})).await.unwrap();
+    assert_eq!(result.is_error, Some(true));
+}
+
+#[tokio::test]
+async fn exposes_tools_under_a_prefix_and_aliases() {
+    let handler = PackageManagerHandler::new(MockBackend::new());
+    // The tool list built with the built-in names is not reused
+    handler.validate_tools().unwrap();
+    let server = TestServer::start_with_handler(
+        handler.with_tool_names(
+            ToolNames::new()
+                .with_prefix("alpine_")
+                .with_alias("search_package", "apk_search"),
+        ),
+    )
+    .await;
+
+    let names: Vec<_> = server
+        .tools()
+        .await
+        .into_iter()
+        .map(|tool| tool.name.to_string())
+        .collect();
+    assert!(names.contains(&"alpine_install_package".to_string()));
+    assert!(names.contains(&"apk_search".to_string()));
+    assert!(!names.iter().any(|name| name == "install_package"));
+
+    let installed = server
+        .call_json("alpine_install_package", json!({ "package_name": "git" }))
+        .await;
+    assert_eq!(installed["package"], "git");
+    server
+        .call_json("apk_search", json!({ "query": "curl" }))
+        .await;
+
+    let result = server
+        .call("install_package", json!({ "package_name": "git" }))
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+    let text = &result.content[0].as_text().unwrap().text;
+    assert!(text.contains("alpine_install_package"), "{text}");
+
+    let err = ServerBuilder::new()
+        .backend(MockBackend::new())
+        .tool_names(ToolNames::new().with_alias("remove_package", "apk_del"))
+        .build()
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("remove_package"), "{err}");
+}
+
+#[tokio::test]
+async fn applies_description_overrides() {
+    let overrides = DescriptionOverrides::from_toml(
+        r#"
+        [instructions]
+        append = "Packages are mirrored at https://mirror.example.com."
+
+        [tools.install_package]
+        append = "Installs are reviewed weekly."
+
+        [tools.search_package]
+        replace = "Search the internal mirror."
+        "#,
+    )
+    .unwrap();
+    let handler = PackageManagerHandler::new(MockBackend::new())
+        .with_tool_names(ToolNames::new().with_prefix("alpine_"));
+    // The tool list built with the generated descriptions is not reused
+    handler.validate_tools().unwrap();
+    let server =
+        TestServer::start_with_handler(handler.with_description_overrides(overrides)).await;
+
+    let instructions = server.instructions().unwrap();
+    assert!(instructions.starts_with("This MCP server provides"));
+    assert!(instructions.ends_with("\n\nPackages are mirrored at https://mirror.example.com."));
+
+    let install = server.tool("alpine_install_package").await;
+    let description = install.description.unwrap();
+    assert!(description.starts_with("Install "), "{description}");
+    assert!(description.ends_with("\n\nInstalls are reviewed weekly."));
+    let search = server.tool("alpine_search_package").await;
+    assert_eq!(search.description.unwrap(), "Search the internal mirror.");
+
+    let err = DescriptionOverrides::from_toml("[tools.remove_package]\nappend = \"No.\"")
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("remove_package"), "{err}");
+}
+
+#[tokio::test]
+async fn publishes_events_for_installs() {
+    let handler = PackageManagerHandler::new(MockBackend::new());
+    let mut events = handler.events().subscribe();
+    let server = TestServer::start_with_handler(handler).await;
+
+    server
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("no event was published")
+        .unwrap();
+    assert_eq!(event.kind, PackageEventKind::Installed);
+    assert_eq!(event.package, "git");
+    assert_eq!(event.package_manager, "MOCK");
+}
+
+#[tokio::test]
+async fn returns_text_summary_alongside_json() {
+    let server = TestServer::start(MockBackend::new()).await;
+
+    let result = server
+        .call("list_installed_packages", json!({}))
+        .await
+        .unwrap();
+    let summary = result.content[0].as_text().unwrap();
+    assert!(summary.text.starts_with("Installed packages:"));
+    assert!(json_content(&result).is_array());
+}
+
+#[tokio::test]
+async fn builder_compresses_responses_for_clients_that_accept_it() {
+    let server = ServerBuilder::new()
+        .backend(MockBackend::new())
+        .compress_responses()
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    tokio::spawn(server.serve());
+
+    let client = reqwest::Client::new();
+    let initialize = |encoding: Option<&str>| {
+        let mut request = client
+            .post(format!("http://{address}/mcp"))
+            .header("Accept", "application/json, text/event-stream")
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "initialize",
+                "params": {
+                    "protocolVersion": "2025-03-26",
+                    "capabilities": {},
+                    "clientInfo": { "name": "test", "version": "0.0.0" }
+                }
+            }));
+        if let Some(encoding) = encoding {
+            request = request.header("Accept-Encoding", encoding);
+        }
+        request.send()
+    };
+
+    let plain = initialize(None).await.unwrap();
+    assert!(plain.headers().get("content-encoding").is_none());
+
+    let compressed = initialize(Some("gzip")).await.unwrap();
+    assert_eq!(compressed.headers()["content-encoding"], "gzip");
+    let body = compressed.bytes().await.unwrap();
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+        .unwrap();
+    assert!(decoded.contains("\"serverInfo\""));
+}
+
+#[tokio::test]
+async fn identifies_clients_in_the_audit_log_and_per_user_allowlists() {
+    let path = std::env::temp_dir().join(format!(
+        "package-manager-mcp-client-audit-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let server = || async {
+        let backend = MockBackend::new()
+            .with_package(MockPackage {
+                repository: Some("https://bob.example.org/alpine".to_string()),
+                ..MockPackage::new("bob-tool", "1.0-r0", "Bob's tool")
+            })
+            .with_package(MockPackage {
+                repository: Some("https://mirror.example.com/alpine".to_string()),
+                ..MockPackage::new("mirrored-tool", "1.0-r0", "Mirrored tool")
+            });
+        ServerBuilder::new()
+            .backend(backend)
+            .with_auth("shared")
+            .with_auth_subject("alice", "alice-token")
+            .identity_headers(
+                IdentityHeaders::new()
+                    .client_certificate("X-SSL-Client-S-DN")
+                    .forwarded_user(),
+            )
+            .allow_repository("https://mirror.example.com/")
+            .allow_repository_for("bob", "https://bob.example.org/")
+            .audit_log(AuditLog::open(&path).unwrap())
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap()
+    };
+
+    // Token subjects win over headers
+    let alice = TestServer::serve(
+        server().await,
+        &[
+            ("Authorization", "Bearer alice-token"),
+            ("X-Forwarded-User", "mallory"),
+        ],
+    )
+    .await;
+    alice
+        .call_json("install_package", json!({ "package_name": "git" }))
+        .await;
+
+    let bob = TestServer::serve(
+        server().await,
+        &[
+            ("Authorization", "Bearer shared"),
+            ("X-SSL-Client-S-DN", "CN=bob,O=Example"),
+        ],
+    )
+    .await;
+    bob.call_json(
+        "install_package",
+        json!({ "package_name": "bob-tool", "repository": "https://bob.example.org/alpine" }),
+    )
+    .await;
+    // Bob's allowlist replaces the server-wide one
+    let err = bob
+        .call_err(
+            "install_package",
+            json!({ "package_name": "mirrored-tool", "repository": "https://mirror.example.com/alpine" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+
+    let carol = TestServer::serve(
+        server().await,
+        &[
+            ("Authorization", "Bearer shared"),
+            ("X-Forwarded-User", "carol"),
+        ],
+    )
+    .await;
+    carol
+        .call_json(
+            "install_package",
+            json!({ "package_name": "mirrored-tool", "repository": "https://mirror.example.com/alpine" }),
+        )
+        .await;
+    let err = carol
+        .call_err(
+            "install_package",
+            json!({ "package_name": "bob-tool", "repository": "https://bob.example.org/alpine" }),
+        )
+        .await;
+    assert_eq!(error_type(&err), "permission_denied");
+
+    let anonymous = TestServer::serve(server().await, &[("Authorization", "Bearer shared")]).await;
+    let export = anonymous.call_json("export_audit_log", json!({})).await;
+    let entries = export["entries"].as_array().unwrap();
+    assert_eq!(
+        entries[0]["client"],
+        json!({ "subject": "alice", "source": "auth_token" })
+    );
+    assert_eq!(
+        entries[1]["client"],
+        json!({ "subject": "bob", "source": "client_certificate" })
+    );
+    assert_eq!(entries[2]["outcome"], "rejected");
+    assert_eq!(entries[2]["client"]["subject"], "bob");
+    assert_eq!(
+        entries[3]["client"],
+        json!({ "subject": "carol", "source": "forwarded_user" })
+    );
+    assert_eq!(entries[4]["outcome"], "rejected");
+    assert_eq!(entries.len(), 5);
+}
+
+#[tokio::test]
+async fn builder_protects_the_mcp_endpoint_with_the_auth_token() {
+    let server = ServerBuilder::new()
+        .backend(MockBackend::new())
+        .with_auth("secret")
+        .route("/health", axum::routing::get(|| async { "ok" }))
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    tokio::spawn(server.serve());
+
+    let client = reqwest::Client::new();
+    let initialize = |token: Option<&str>| {
+        let mut request = client
+            .post(format!("http://{address}/mcp"))
+            .header("Accept", "application/json, text/event-stream")
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "initialize",
+                "params": {
+                    "protocolVersion": "2025-03-26",
+                    "capabilities": {},
+                    "clientInfo": { "name": "test", "version": "0.0.0" }
+                }
+            }));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request.send()
+    };
+
+    assert_eq!(initialize(None).await.unwrap().status(), 401);
+    assert_eq!(initialize(Some("wrong")).await.unwrap().status(), 401);
+    assert_eq!(initialize(Some("secret")).await.unwrap().status(), 200);
+
+    let health = client
+        .get(format!("http://{address}/health"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(health.status(), 200);
+}
+
+#[tokio::test]
+async fn fails_installs_the_disk_cannot_hold_before_running_them() {
+    let backend = MockBackend::empty()
+        .with_package(MockPackage::new("zlib", "1.3.1-r2", "Compression library").with_size(100))
+        .with_package(
+            MockPackage::new("libpng", "1.6.50-r0", "PNG library")
+                .with_dependency("zlib")
+                .with_size(300),
+        )
+        .with_free_space(350);
+    let server = TestServer::start(backend).await;
+
+    let err = server
+        .call("install_package", json!({"package_name": "libpng"}))
+        .await
+        .unwrap_err();
+    assert_eq!(error_type(&err), "insufficient_disk_space");
+    let data = err.data.unwrap();
+    assert_eq!(data["mount"], "/");
+    assert_eq!(data["needed_bytes"], 400);
+    assert_eq!(data["available_bytes"], 350);
+    let installed = server
+        .call("list_installed_packages", json!({}))
+        .await
+        .unwrap();
+    assert!(!json_content(&installed).to_string().contains("libpng"));
+
+    let err = server
+        .call(
+            "install_package_with_version",
+            json!({"package_name": "libpng", "version": "1.6.50-r0"}),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(error_type(&err), "insufficient_disk_space");
+
+    server
+        .call("install_package", json!({"package_name": "zlib"}))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn refreshes_indexes_missing_a_package_and_retries() {
+    let backend = || {
+        MockBackend::empty()
+            .with_package(MockPackage::new("jq", "1.8.0-r0", "JSON processor").unindexed())
+    };
+    let server = TestServer::start(backend()).await;
+    let err = server
+        .call("install_package", json!({"package_name": "jq"}))
+        .await
+        .unwrap_err();
+    assert_eq!(error_type(&err), "package_not_found");
+
+    let path = std::env::temp_dir().join(format!(
+        "package-manager-mcp-refresh-audit-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let handler = PackageManagerHandler::new(backend().with_index_age(Duration::from_secs(600)))
+        .with_auto_refresh(AutoRefresh::new())
+        .with_audit_log(AuditLog::open(&path).unwrap());
+    let server = TestServer::start_with_handler(handler).await;
+    let result = server
+        .call("install_package", json!({"package_name": "jq"}))
+        .await
+        .unwrap();
+    assert_eq!(json_content(&result)["version"], "1.8.0-r0");
+    let note = &result.content.last().unwrap().as_text().unwrap().text;
+    assert!(note.contains("refreshed and the call retried"), "{note}");
+
+    // The indexes were just refreshed, so another miss does not refresh them
+    let err = server
+        .call("install_package", json!({"package_name": "missing"}))
+        .await
+        .unwrap_err();
+    assert_eq!(error_type(&err), "package_not_found");
+    assert!(err.data.unwrap().get("index_refresh").is_none());
+
+    let entries: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&path).unwrap();
+    let tools: Vec<_> = entries.iter().map(|entry| &entry["tool"]).collect();
+    assert_eq!(
+        tools,
+        ["refresh_repositories", "install_package", "install_package"]
+    );
+    assert_eq!(entries[0]["outcome"], "succeeded");
+
+    let handler = PackageManagerHandler::new(backend())
+        .with_auto_refresh(AutoRefresh::new().with_min_interval(Duration::ZERO));
+    let server = TestServer::start_with_handler(handler).await;
+    let err = server
+        .call("install_package", json!({"package_name": "missing"}))
+        .await
+        .unwrap_err();
+    assert_eq!(error_type(&err), "package_not_found");
+    assert_eq!(err.data.unwrap()["index_refresh"]["reason"], "not_found");
+}
+
+#[tokio::test]
+async fn refreshes_stale_indexes_before_searching() {
+    let backend = MockBackend::new().with_index_age(Duration::from_secs(2 * 24 * 60 * 60));
+    let handler = PackageManagerHandler::new(backend)
+        .with_auto_refresh(AutoRefresh::new().with_max_age(Duration::from_secs(24 * 60 * 60)));
+    let server = TestServer::start_with_handler(handler).await;
+
+    let result = server
+        .call("search_package", json!({"query": "curl"}))
+        .await
+        .unwrap();
+    let note = &result.content.last().unwrap().as_text().unwrap().text;
+    assert!(note.contains("seconds old"), "{note}");
+
+    let result = server
+        .call("search_package", json!({"query": "curl"}))
+        .await
+        .unwrap();
+    assert!(
+        !result
+            .content
+            .iter()
+            .any(|content| content.as_text().unwrap().text.contains("refreshed")),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn refreshes_wait_for_running_installs() {
+    let backend = MockBackend::new()
+        .with_index_age(Duration::from_secs(2 * 24 * 60 * 60))
+        .with_operation_latency("install_from_lockfile", Duration::from_millis(300));
+    let handler = PackageManagerHandler::new(backend)
+        .with_auto_refresh(AutoRefresh::new().with_max_age(Duration::from_secs(24 * 60 * 60)));
+    let server = TestServer::start_with_handler(handler).await;
+    let lockfile = json!({
+        "lockfile_version": 1,
+        "backend": "MOCK",
+        "os": "Mock OS",
+        "generated_at": "2026-01-01T00:00:00Z",
+        "packages": [{ "name": "curl", "version": "8.14.1-r1" }]
+    });
+
+    // The lockfile install does not refresh, and holds the mutation lock
+    // while the search's refresh of the stale indexes waits for it
+    let install = server.call("install_from_lockfile", json!({ "lockfile": lockfile }));
+    let search = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let started = std::time::Instant::now();
+        let result = server
+            .call("search_package", json!({"query": "curl"}))
+            .await
+            .unwrap();
+        (started.elapsed(), result)
+    };
+    let (installed, (elapsed, result)) = tokio::join!(install, search);
+    installed.unwrap();
+    assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+    let note = &result.content.last().unwrap().as_text().unwrap().text;
+    assert!(note.contains("seconds old"), "{note}");
+}
@@ -0,0 +1,417 @@
+//! Golden tests for the command output parsers
+//!
+//! Every `*.txt` fixture under `tests/fixtures/<manager>/<command>/` is the
+//! captured stdout of a command on a given distribution release. It is parsed
+//! and compared against the `*.json` golden file next to it. Run the tests
+//! with `UPDATE_GOLDEN=1` to regenerate the golden files after an intended
+//! parser change, and review the diff.
+
+use std::path::{Path, PathBuf};
+
+use package_manager_mcp::ErrorCode;
+use package_manager_mcp::backend::parse::{
+    apk, apt, classify_failure, parse_df, parse_lslocks, parse_os_release_name, parse_sha256sum,
+    parse_version_line, versions_of,
+};
+use serde::Serialize;
+
+fn fixtures(dir: &str) -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(dir);
+    let mut fixtures: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("failed to read {}: {err}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty(), "no fixtures in {}", dir.display());
+    fixtures
+}
+
+fn check_golden<T: Serialize>(dir: &str, parse: impl Fn(&str) -> T) {
+    for fixture in fixtures(dir) {
+        let input = std::fs::read_to_string(&fixture).unwrap();
+        let actual = serde_json::to_string_pretty(&parse(&input)).unwrap() + "\n";
+        let golden = fixture.with_extension("json");
+
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&golden, &actual).unwrap();
+            continue;
+        }
+
+        let expected = std::fs::read_to_string(&golden).unwrap_or_else(|err| {
+            panic!(
+                "missing golden file {} ({err}), run with UPDATE_GOLDEN=1",
+                golden.display()
+            )
+        });
+        assert_eq!(
+            actual,
+            expected,
+            "parsed output of {} does not match its golden file",
+            fixture.display()
+        );
+    }
+}
+
+#[test]
+fn apk_search() {
+    check_golden("apk/search", apk::parse_search);
+}
+
+#[test]
+fn apk_policy() {
+    check_golden("apk/policy", apk::parse_policy);
+}
+
+#[test]
+fn apk_index() {
+    check_golden("apk/apkindex", apk::parse_apkindex);
+}
+
+#[test]
+fn apk_packages_page() {
+    check_golden("apk/packages-page", apk::parse_packages_page);
+}
+
+#[test]
+fn apk_list_installed() {
+    check_golden("apk/list-installed", |stdout| {
+        apk::parse_installed_list(stdout, None)
+    });
+}
+
+#[test]
+fn apk_world() {
+    check_golden("apk/world", apk::parse_world);
+}
+
+#[test]
+fn apk_installed_licenses() {
+    check_golden("apk/list-installed-licenses", apk::parse_installed_licenses);
+}
+
+#[test]
+fn apk_installed_checksums() {
+    check_golden("apk/installed-db", apk::parse_installed_checksums);
+}
+
+#[test]
+fn apk_add() {
+    check_golden("apk/add", apk::parse_install_output);
+}
+
+#[test]
+fn apk_add_removals() {
+    check_golden("apk/add-removals", apk::parse_removed_packages);
+}
+
+#[test]
+fn apk_package_signing_keys() {
+    check_golden("apk/package-contents", apk::parse_signing_key);
+}
+
+#[test]
+fn sha256sum_digests() {
+    assert_eq!(
+        parse_sha256sum(
+            "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08  /tmp/tool.apk\n"
+        )
+        .as_deref(),
+        Some("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08")
+    );
+    assert_eq!(
+        parse_sha256sum("sha256sum: /tmp/tool.apk: No such file\n"),
+        None
+    );
+}
+
+#[test]
+fn apk_repositories() {
+    check_golden("apk/repositories", apk::parse_repositories);
+}
+
+#[test]
+fn apk_unreachable_repositories() {
+    check_golden("apk/unreachable", apk::parse_unreachable_repositories);
+}
+
+#[test]
+fn apt_madison() {
+    check_golden("apt/madison", apt::parse_madison);
+}
+
+#[test]
+fn apt_show_checksums() {
+    check_golden("apt/show", apt::parse_show_checksums);
+}
+
+#[test]
+fn apt_search() {
+    check_golden("apt/search", apt::parse_search);
+}
+
+#[test]
+fn apt_list_installed() {
+    check_golden("apt/list-installed", apt::parse_installed_list);
+}
+
+#[test]
+fn apt_copyright_licenses() {
+    check_golden("apt/copyright-licenses", apt::parse_copyright_licenses);
+}
+
+#[test]
+fn apt_install() {
+    check_golden("apt/install", apt::parse_install_output);
+}
+
+#[test]
+fn apt_install_removals() {
+    check_golden("apt/install-removals", apt::parse_removed_packages);
+}
+
+#[test]
+fn apt_fetched_sizes_are_in_si_units() {
+    let fetched = |line: &str| apt::parse_fetched_bytes(&format!("Get:1 ...\n{line}\n"));
+    assert_eq!(fetched("Fetched 707 kB in 0s (6,329 kB/s)"), Some(707_000));
+    assert_eq!(
+        fetched("Fetched 1,361 kB in 1s (1,361 kB/s)"),
+        Some(1_361_000)
+    );
+    assert_eq!(
+        fetched("Fetched 41.9 MB in 3s (14.0 MB/s)"),
+        Some(41_900_000)
+    );
+    assert_eq!(fetched("Fetched 512 B in 0s (5,120 B/s)"), Some(512));
+    assert_eq!(
+        apt::parse_fetched_bytes("0 upgraded, 0 newly installed"),
+        None
+    );
+}
+
+#[test]
+fn apt_required_space() {
+    let output = "The following NEW packages will be installed:\n  jq libjq1 libonig5\n0 upgraded, 3 newly installed, 0 to remove and 0 not upgraded.\nNeed to get 0 B/389 kB of archives.\nAfter this operation, 1,156 kB of additional disk space will be used.\nAbort.\n";
+    assert_eq!(apt::parse_required_space(output), (0, 1_156_000));
+    let output = "Need to get 41.9 MB of archives.\nAfter this operation, 12.5 MB disk space will be freed.\n";
+    assert_eq!(apt::parse_required_space(output), (41_900_000, 0));
+    assert_eq!(
+        apt::parse_required_space("jq is already the newest version (1.6-2.1).\n"),
+        (0, 0)
+    );
+}
+
+#[test]
+fn apk_required_space() {
+    let output = "(1/2) Installing oniguruma (6.9.10-r0)\n(2/2) Installing jq (1.8.0-r0)\nAfter this operation, 1 MiB of additional disk space will be used.\nOK: 12 MiB in 30 packages\n";
+    assert_eq!(apk::parse_required_space(output), Some(1 << 20));
+    assert_eq!(
+        apk::parse_required_space("After this operation, 300 KiB of disk space will be freed.\n"),
+        Some(0)
+    );
+    assert_eq!(
+        apk::parse_required_space("OK: 12 MiB in 30 packages\n"),
+        None
+    );
+}
+
+#[test]
+fn apt_localized_sizes_are_not_misread() {
+    // Commands run in the C locale; translated output must not be mistaken
+    // for the English one, e.g. a decimal comma for a thousands separator
+    assert_eq!(
+        apt::parse_fetched_bytes("Es wurden 41,9 kB in 0 s geholt (1.012 kB/s).\n"),
+        None
+    );
+    assert_eq!(
+        apt::parse_fetched_bytes("41,9 ko réceptionnés en 0s (1 012 ko/s)\n"),
+        None
+    );
+}
+
+#[test]
+fn apt_sources_list() {
+    check_golden("apt/sources-list", apt::parse_sources_list);
+}
+
+#[test]
+fn apt_deb822_sources() {
+    check_golden("apt/deb822", apt::parse_deb822_sources);
+}
+
+#[test]
+fn apk_package_versions_split_on_the_last_two_fields() {
+    assert_eq!(
+        apk::split_package_version("py3-pip-25.1.1-r0"),
+        Some(("py3-pip", "25.1.1-r0"))
+    );
+    assert_eq!(
+        apk::split_package_version("llvm19-libs-19.1.7-r1"),
+        Some(("llvm19-libs", "19.1.7-r1"))
+    );
+    assert_eq!(apk::split_package_version("curl"), None);
+    assert_eq!(apk::split_package_version("font-noto-cjk"), None);
+    assert_eq!(apk::split_package_version("-1.0-r0"), None);
+}
+
+#[test]
+fn versions_are_collected_from_matching_entries_only() {
+    let results = apk::parse_search(
+        "curl-8.14.1-r1\ncurl-doc-8.14.1-r1\ncurl-8.12.1-r0\ncurl-8.14.1-r1\ncurl\n",
+    );
+    assert_eq!(versions_of(&results, "curl"), ["8.14.1-r1", "8.12.1-r0"]);
+}
+
+#[test]
+fn alpine_releases_give_their_branch_and_older_branches() {
+    let stable = apk::parse_alpine_release("3.22.1\n").unwrap();
+    assert_eq!(stable.branch(), "v3.22");
+    assert_eq!(stable.older_branches(2), ["v3.21", "v3.20"]);
+
+    let edge = apk::parse_alpine_release("3.23_alpha20250612\n").unwrap();
+    assert_eq!(edge.branch(), "edge");
+    assert_eq!(edge.older_branches(1), ["v3.22"]);
+
+    let first = apk::parse_alpine_release("4.0.0").unwrap();
+    assert!(first.older_branches(3).is_empty());
+    assert_eq!(apk::parse_alpine_release("not a release"), None);
+}
+
+#[test]
+fn df_free_space() {
+    let df = "Filesystem     1024-blocks     Used Available Capacity Mounted on\n\
+        overlay         61202244 40117112  17944420      70% /\n\
+        /dev/sdb1         999320   870048     60460      94% /var/cache/my apt\n";
+    assert_eq!(
+        parse_df(df),
+        [
+            ("/".to_string(), 17944420 * 1024),
+            ("/var/cache/my apt".to_string(), 60460 * 1024),
+        ]
+    );
+    assert_eq!(parse_df("df: /missing: No such file or directory\n"), []);
+}
+
+#[test]
+fn lslocks_holders() {
+    let stdout = "4242 apt-get /var/lib/dpkg/lock-frontend\n\
+        815 unattended-upgr /var/lib/my\\x20dir/lock\n\
+        77 containerd\n";
+    assert_eq!(
+        parse_lslocks(stdout),
+        [
+            (
+                4242,
+                "apt-get".to_string(),
+                "/var/lib/dpkg/lock-frontend".to_string()
+            ),
+            (
+                815,
+                "unattended-upgr".to_string(),
+                "/var/lib/my dir/lock".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn failure_signatures() {
+    let cases = [
+        (
+            "E: Could not get lock /var/lib/dpkg/lock-frontend. It is held by process 4242 (apt-get)\n\
+            E: Unable to acquire the dpkg frontend lock (/var/lib/dpkg/lock-frontend), is another process using it?\n",
+            ErrorCode::LockHeld,
+        ),
+        (
+            "E: Could not open lock file /var/lib/dpkg/lock-frontend - open (13: Permission denied)\n\
+            E: Unable to acquire the dpkg frontend lock (/var/lib/dpkg/lock-frontend), are you root?\n",
+            ErrorCode::PermissionDenied,
+        ),
+        (
+            "ERROR: Unable to lock database: Resource temporarily unavailable\n\
+            ERROR: Failed to open apk database: Resource temporarily unavailable\n",
+            ErrorCode::LockHeld,
+        ),
+        (
+            "Err:1 http://deb.debian.org/debian bookworm InRelease\n  \
+            Temporary failure resolving 'deb.debian.org'\n",
+            ErrorCode::NetworkFailure,
+        ),
+        (
+            "WARNING: fetching https://dl-cdn.alpinelinux.org/alpine/v3.22/main: DNS lookup error\n",
+            ErrorCode::NetworkFailure,
+        ),
+        (
+            "ERROR: unable to select packages:\n  nosuch (no such package):\n    required by: world[nosuch]\n",
+            ErrorCode::NotFound,
+        ),
+        ("E: Unable to locate package nosuch\n", ErrorCode::NotFound),
+        (
+            "The following packages have unmet dependencies:\n \
+            foo : Depends: libbar (>= 2.0) but it is not going to be installed\n\
+            E: Unable to correct problems, you have held broken packages.\n",
+            ErrorCode::UnsatisfiableDependency,
+        ),
+        (
+            "ERROR: unable to select packages:\n  foo-2.0-r0:\n    breaks: world[foo<2]\n",
+            ErrorCode::UnsatisfiableDependency,
+        ),
+        (
+            "E: You don't have enough free space in /var/cache/apt/archives/.\n",
+            ErrorCode::DiskFull,
+        ),
+        (
+            "ERROR: curl-8.14.1-r1: failed to extract usr/bin/curl: No space left on device\n",
+            ErrorCode::DiskFull,
+        ),
+    ];
+    for (output, code) in cases {
+        assert_eq!(classify_failure(output), Some(code), "{output}");
+    }
+    assert_eq!(
+        classify_failure("E: Sub-process /usr/bin/dpkg returned an error code (1)\n"),
+        None
+    );
+}
+
+#[test]
+fn os_release_names() {
+    assert_eq!(
+        parse_os_release_name("NAME=\"Alpine Linux\"\nPRETTY_NAME=\"Alpine Linux v3.22\"\n")
+            .as_deref(),
+        Some("Alpine Linux v3.22")
+    );
+    assert_eq!(
+        parse_os_release_name("NAME=\"Debian GNU/Linux\"\nVERSION_ID=\"12\"\n").as_deref(),
+        Some("Debian GNU/Linux 12")
+    );
+    assert_eq!(parse_os_release_name("ID=debian\n"), None);
+    assert_eq!(
+        parse_version_line("\napt 2.6.1 (amd64)\nSupported modules:\n").as_deref(),
+        Some("apt 2.6.1 (amd64)")
+    );
+}
+
+#[test]
+fn os_releases_map_to_osv_ecosystems() {
+    let ecosystem = |os_release: &str| apt::parse_osv_ecosystem(os_release);
+    assert_eq!(
+        ecosystem("ID=debian\nVERSION_ID=\"12\"\nVERSION=\"12 (bookworm)\"\n").as_deref(),
+        Some("Debian:12")
+    );
+    assert_eq!(
+        ecosystem("ID=ubuntu\nVERSION_ID=\"24.04\"\nVERSION=\"24.04.2 LTS (Noble Numbat)\"\n")
+            .as_deref(),
+        Some("Ubuntu:24.04:LTS")
+    );
+    assert_eq!(
+        ecosystem("ID=ubuntu\nVERSION_ID=\"25.04\"\nVERSION=\"25.04 (Plucky Puffin)\"\n")
+            .as_deref(),
+        Some("Ubuntu:25.04")
+    );
+    // Testing and unstable have no version
+    assert_eq!(ecosystem("ID=debian\nVERSION_CODENAME=forky\n"), None);
+    assert_eq!(ecosystem("ID=linuxmint\nVERSION_ID=\"22\"\n"), None);
+}
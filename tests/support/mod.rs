@@ -0,0 +1,278 @@
+//! Shared helpers for integration tests
+//!
+//! [`TestServer`] serves a handler over streamable HTTP on an ephemeral port
+//! and connects a real MCP client to it, so tests exercise the same path as
+//! external clients.
+
+#![allow(dead_code)]
+
+use package_manager_mcp::{PackageManager, PackageManagerHandler, Server};
+use reqwest::header::{HeaderName, HeaderValue};
+use rmcp::{
+    ClientHandler, ErrorData as McpError, RoleClient, ServiceExt,
+    model::{
+        CallToolRequestParam, CallToolResult, ReadResourceRequestParam, Resource, ResourceContents,
+        ResourceUpdatedNotificationParam, SubscribeRequestParam, Tool,
+    },
+    service::{NotificationContext, RunningService, ServiceError},
+    transport::{
+        StreamableHttpClientTransport, streamable_http_client::StreamableHttpClientTransportConfig,
+    },
+};
+use std::net::SocketAddr;
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+
+/// MCP server running in the background with a connected client
+pub struct TestServer {
+    client: RunningService<RoleClient, TestClient>,
+    address: SocketAddr,
+    /// The server task, `None` for the clients added with [`connect`]
+    ///
+    /// [`connect`]: TestServer::connect
+    server: Option<JoinHandle<()>>,
+    updates: Mutex<mpsc::UnboundedReceiver<String>>,
+}
+
+/// Client forwarding the URIs of updated resources to the test
+struct TestClient {
+    updates: mpsc::UnboundedSender<String>,
+}
+
+impl ClientHandler for TestClient {
+    async fn on_resource_updated(
+        &self,
+        params: ResourceUpdatedNotificationParam,
+        _: NotificationContext<RoleClient>,
+    ) {
+        let _ = self.updates.send(params.uri);
+    }
+}
+
+impl TestServer {
+    /// Start a server for the given backend
+    pub async fn start(backend: impl PackageManager) -> Self {
+        Self::start_with_handler(PackageManagerHandler::new(backend)).await
+    }
+
+    /// Start a server for a fully configured handler
+    pub async fn start_with_handler(handler: PackageManagerHandler) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind test listener");
+        let address = listener.local_addr().expect("listener has no address");
+
+        let router =
+            axum::Router::new().nest_service("/mcp", package_manager_mcp::handler_service(handler));
+        let server = tokio::spawn(async move {
+            axum::serve(listener, router)
+                .await
+                .expect("test server failed");
+        });
+
+        let mut test = Self::connect_to(address, &[]).await;
+        test.server = Some(server);
+        test
+    }
+
+    /// Serve a server built with `ServerBuilder` and connect a client
+    /// sending `headers` with every request
+    pub async fn serve(server: Server, headers: &[(&str, &str)]) -> Self {
+        let address = server.local_addr().expect("server has no address");
+        let server = tokio::spawn(async move {
+            server.serve().await.expect("test server failed");
+        });
+
+        let mut test = Self::connect_to(address, headers).await;
+        test.server = Some(server);
+        test
+    }
+
+    /// Connect another client to the same server, as another MCP session
+    /// sending `headers` with every request
+    ///
+    /// The server stops when the first client is dropped.
+    pub async fn connect(&self, headers: &[(&str, &str)]) -> Self {
+        Self::connect_to(self.address, headers).await
+    }
+
+    async fn connect_to(address: SocketAddr, headers: &[(&str, &str)]) -> Self {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).expect("invalid header name"),
+                    HeaderValue::from_str(value).expect("invalid header value"),
+                )
+            })
+            .collect();
+        let http = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .expect("failed to build HTTP client");
+        let transport = StreamableHttpClientTransport::with_client(
+            http,
+            StreamableHttpClientTransportConfig::with_uri(format!("http://{address}/mcp")),
+        );
+        let (sender, updates) = mpsc::unbounded_channel();
+        let client = TestClient { updates: sender }
+            .serve(transport)
+            .await
+            .expect("failed to connect MCP client");
+
+        Self {
+            client,
+            address,
+            server: None,
+            updates: Mutex::new(updates),
+        }
+    }
+
+    /// Instructions the server gave when the client connected
+    pub fn instructions(&self) -> Option<String> {
+        self.client
+            .peer_info()
+            .and_then(|info| info.instructions.clone())
+    }
+
+    /// Every tool advertised by the server
+    pub async fn tools(&self) -> Vec<Tool> {
+        self.client
+            .list_all_tools()
+            .await
+            .expect("failed to list tools")
+    }
+
+    /// The advertised tool with the given name
+    pub async fn tool(&self, name: &str) -> Tool {
+        self.tools()
+            .await
+            .into_iter()
+            .find(|tool| tool.name == name)
+            .unwrap_or_else(|| panic!("tool '{name}' is not advertised"))
+    }
+
+    /// Every resource advertised by the server
+    pub async fn resources(&self) -> Vec<Resource> {
+        self.client
+            .list_all_resources()
+            .await
+            .expect("failed to list resources")
+    }
+
+    /// Read a JSON resource
+    pub async fn read_json(&self, uri: &str) -> serde_json::Value {
+        let result = self
+            .client
+            .read_resource(ReadResourceRequestParam {
+                uri: uri.to_string(),
+            })
+            .await
+            .unwrap_or_else(|err| panic!("failed to read resource '{uri}': {err}"));
+        match result.contents.first() {
+            Some(ResourceContents::TextResourceContents { text, .. }) => {
+                serde_json::from_str(text).expect("resource is not JSON")
+            }
+            other => panic!("resource '{uri}' has no text content: {other:?}"),
+        }
+    }
+
+    /// Subscribe to updates of a resource
+    pub async fn subscribe(&self, uri: &str) -> Result<(), McpError> {
+        match self
+            .client
+            .subscribe(SubscribeRequestParam {
+                uri: uri.to_string(),
+            })
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(ServiceError::McpError(err)) => Err(err),
+            Err(err) => panic!("subscription to '{uri}' failed outside MCP: {err}"),
+        }
+    }
+
+    /// URI of the next resource the server reports as updated, `None` when
+    /// it reports none within `timeout`
+    pub async fn next_update(&self, timeout: std::time::Duration) -> Option<String> {
+        let mut updates = self.updates.lock().await;
+        tokio::time::timeout(timeout, updates.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Call a tool, returning the result or the MCP error
+    pub async fn call(
+        &self,
+        name: &str,
+        arguments: serde_json::Value,
+    ) -> Result<CallToolResult, McpError> {
+        let arguments = match arguments {
+            serde_json::Value::Object(arguments) => Some(arguments),
+            serde_json::Value::Null => None,
+            other => panic!("tool arguments must be an object, got {other}"),
+        };
+
+        match self
+            .client
+            .call_tool(CallToolRequestParam {
+                name: name.to_string().into(),
+                arguments,
+            })
+            .await
+        {
+            Ok(result) => Ok(result),
+            Err(ServiceError::McpError(err)) => Err(err),
+            Err(err) => panic!("tool call '{name}' failed outside MCP: {err}"),
+        }
+    }
+
+    /// Call a tool that is expected to succeed and return its JSON content
+    pub async fn call_json(&self, name: &str, arguments: serde_json::Value) -> serde_json::Value {
+        let result = self
+            .call(name, arguments)
+            .await
+            .unwrap_or_else(|err| panic!("tool call '{name}' failed: {err:?}"));
+        assert_ne!(
+            result.is_error,
+            Some(true),
+            "tool call '{name}' reported an error"
+        );
+        json_content(&result)
+    }
+
+    /// Call a tool that is expected to fail and return the MCP error
+    pub async fn call_err(&self, name: &str, arguments: serde_json::Value) -> McpError {
+        match self.call(name, arguments).await {
+            Ok(result) => panic!("tool call '{name}' unexpectedly succeeded: {result:?}"),
+            Err(err) => err,
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(server) = &self.server {
+            server.abort();
+        }
+    }
+}
+
+/// The structured JSON block attached to a tool result
+pub fn json_content(result: &CallToolResult) -> serde_json::Value {
+    result
+        .content
+        .iter()
+        .filter_map(|content| content.as_text())
+        .find_map(|text| serde_json::from_str(&text.text).ok())
+        .expect("tool result has no JSON content")
+}
+
+/// The `error_type` code attached to an MCP error
+pub fn error_type(err: &McpError) -> &str {
+    err.data
+        .as_ref()
+        .and_then(|data| data["error_type"].as_str())
+        .expect("error has no error_type")
+}
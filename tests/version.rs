@@ -0,0 +1,145 @@
+//! Version ordering and constraint matching
+
+use std::cmp::Ordering;
+
+use package_manager_mcp::{VersionConstraint, VersionScheme};
+
+fn assert_ascending(scheme: VersionScheme, versions: &[&str]) {
+    for pair in versions.windows(2) {
+        assert_eq!(
+            scheme.compare(pair[0], pair[1]),
+            Ordering::Less,
+            "{} should sort before {}",
+            pair[0],
+            pair[1]
+        );
+        assert_eq!(scheme.compare(pair[1], pair[0]), Ordering::Greater);
+    }
+}
+
+#[test]
+fn apk_versions_follow_apk_tools_ordering() {
+    assert_ascending(
+        VersionScheme::Apk,
+        &[
+            "1.0_alpha1-r0",
+            "1.0_beta2-r0",
+            "1.0_pre1-r0",
+            "1.0_rc1-r0",
+            "1.0-r0",
+            "1.0-r1",
+            "1.0-r10",
+            "1.0_p1-r0",
+            "1.0a-r0",
+            "1.0b-r0",
+            "1.0.1-r0",
+            "1.01-r0",
+            "1.2-r0",
+            "1.9-r0",
+            "1.10-r0",
+            "10.0-r0",
+        ],
+    );
+    assert_eq!(
+        VersionScheme::Apk.compare("1.0-r0", "1.0-r0"),
+        Ordering::Equal
+    );
+}
+
+#[test]
+fn deb_versions_follow_debian_policy() {
+    assert_ascending(
+        VersionScheme::Deb,
+        &[
+            "1.0~~",
+            "1.0~~a",
+            "1.0~a",
+            "1.0~rc1-1",
+            "1.0",
+            "1.0-1",
+            "1.0-1+deb12u1",
+            "1.0-2",
+            "1.0-10",
+            "1.0a",
+            "1.0+dfsg-1",
+            "1.9",
+            "1.10",
+            "1:0.1",
+            "2:0.0.1",
+        ],
+    );
+    assert_eq!(
+        VersionScheme::Deb.compare("1.0-01", "1.0-1"),
+        Ordering::Equal
+    );
+}
+
+#[test]
+fn sorting_is_numeric_and_removes_duplicates() {
+    let mut versions = vec![
+        "8.9.0-r0".to_string(),
+        "8.14.1-r1".to_string(),
+        "8.12.1-r0".to_string(),
+        "8.14.1-r1".to_string(),
+    ];
+    VersionScheme::Apk.sort(&mut versions);
+    assert_eq!(versions, ["8.9.0-r0", "8.12.1-r0", "8.14.1-r1"]);
+}
+
+#[test]
+fn constraints_pick_the_newest_matching_version() {
+    let versions = [
+        "3.9.18-r0",
+        "3.11.9-r0",
+        "3.12.1-r0",
+        "3.12.11-r0",
+        "3.13.5-r0",
+    ];
+    let latest = |constraint: &str| {
+        VersionScheme::Apk.latest_matching(versions, &VersionConstraint::parse(constraint))
+    };
+
+    assert_eq!(latest("3.11.9-r0"), Some("3.11.9-r0"));
+    assert_eq!(latest("=3.11.9-r0"), Some("3.11.9-r0"));
+    assert_eq!(latest("<3.12"), Some("3.11.9-r0"));
+    assert_eq!(latest("<=3.12.11-r0"), Some("3.12.11-r0"));
+    assert_eq!(latest(">=3.10"), Some("3.13.5-r0"));
+    assert_eq!(latest("~3.12"), Some("3.12.11-r0"));
+    assert_eq!(latest("~3.1"), None);
+    assert_eq!(latest(">4"), None);
+}
+
+#[test]
+fn exact_versions_fall_back_to_the_newest_version_with_their_components() {
+    let versions = [
+        "7.88.1-r0",
+        "7.88.1-r1",
+        "7.880-r0",
+        "3.11.2-r0",
+        "3.11.9-r0",
+    ];
+    let latest = |constraint: &str| {
+        VersionScheme::Apk.latest_matching(versions, &VersionConstraint::parse(constraint))
+    };
+
+    assert_eq!(latest("7.88"), Some("7.88.1-r1"));
+    // Without a release, apk versions compare equal to release r0
+    assert_eq!(latest("7.88.1"), Some("7.88.1-r0"));
+    assert_eq!(latest("3.11"), Some("3.11.9-r0"));
+    assert_eq!(latest("7.8"), None);
+    assert_eq!(latest(">7.88.1-r1"), Some("7.880-r0"));
+
+    let debian = ["1:2.39.2-1.1", "1:2.39.5-0+deb12u2", "1:2.47.2-0.2"];
+    assert_eq!(
+        VersionScheme::Deb.latest_matching(debian, &VersionConstraint::parse("2.39")),
+        Some("1:2.39.5-0+deb12u2")
+    );
+}
+
+#[test]
+fn bare_versions_are_exact_constraints() {
+    let constraint = VersionConstraint::parse(" 1.2.3-r0 ");
+    assert!(constraint.is_exact());
+    assert_eq!(constraint.version, "1.2.3-r0");
+    assert!(!VersionConstraint::parse(">=1.2").is_exact());
+}